@@ -0,0 +1,255 @@
+mod workload;
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use clap::Parser;
+use hdrhistogram::Histogram;
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+use pluto_client::{ClientError, ClusterClient, Command, Connection, Encoding, Pipeline, PoolConfig, Response};
+use workload::{KeyDistribution, ValueSize, Workload};
+
+// Command line arguments
+#[derive(Parser, Debug)]
+#[command(name = "pluto-bench")]
+#[command(author = "thebyteslayer")]
+#[command(version = "1.0.0")]
+#[command(about = "Load generator and benchmark for flux-cache")]
+struct Args {
+    /// Server host
+    #[arg(long, default_value = "127.0.0.1")]
+    host: String,
+    /// Server port
+    #[arg(long, default_value_t = 6124)]
+    port: u16,
+    /// Password sent with AUTH on every connection
+    #[arg(long)]
+    password: Option<String>,
+    /// Wire encoding: json, msgpack or bincode
+    #[arg(long, default_value = "json")]
+    encoding: String,
+    /// Concurrent connections
+    #[arg(short, long, default_value_t = 50)]
+    clients: usize,
+    /// Total requests to send
+    #[arg(short = 'n', long, default_value_t = 100_000)]
+    requests: usize,
+    /// Commands sent per round trip on each connection
+    #[arg(short = 'P', long, default_value_t = 1)]
+    pipeline: usize,
+    /// Number of distinct keys
+    #[arg(long, default_value_t = 10_000)]
+    keyspace: usize,
+    /// How keys are picked from the key space
+    #[arg(long, value_enum, default_value_t = KeyDistribution::Uniform)]
+    key_distribution: KeyDistribution,
+    /// Skew of the zipf key distribution
+    #[arg(long, default_value_t = 0.99)]
+    zipf_exponent: f64,
+    /// Value size in bytes, or a MIN-MAX range picked uniformly
+    #[arg(long, default_value = "100")]
+    value_size: ValueSize,
+    /// Fraction of requests that are GETs; the rest are SETs
+    #[arg(long, default_value_t = 0.8)]
+    read_ratio: f64,
+    /// Prefix of every generated key
+    #[arg(long, default_value = "bench:")]
+    key_prefix: String,
+    /// Write every key once before the run so reads hit
+    #[arg(long)]
+    populate: bool,
+    /// Route requests by key slot across the cluster
+    #[arg(long)]
+    cluster: bool,
+    /// Seed for the request generator, for reproducible runs
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+}
+
+// What one worker measured
+struct WorkerReport {
+    latency: Histogram<u64>, // microseconds per round trip
+    hits: u64,
+    misses: u64,
+    errors: u64,
+}
+
+// Where a worker sends its requests
+enum Target {
+    Node(Connection),
+    Cluster(ClusterClient),
+}
+
+#[tokio::main]
+async fn main() {
+    let args = Args::parse();
+    if let Err(e) = run(args).await {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+async fn run(args: Args) -> Result<(), ClientError> {
+    let encoding = match args.encoding.as_str() {
+        "json" => Encoding::Json,
+        "msgpack" => Encoding::Msgpack,
+        "bincode" => Encoding::Bincode,
+        other => return Err(ClientError::Protocol(format!("unknown encoding {}", other))),
+    };
+    if !(0.0..=1.0).contains(&args.read_ratio) {
+        return Err(ClientError::Protocol("read ratio must be between 0 and 1".to_string()));
+    }
+    let addr = format!("{}:{}", args.host, args.port);
+    let workload = Arc::new(Workload::new(
+        args.keyspace, args.key_distribution, args.zipf_exponent,
+        args.value_size, args.read_ratio, args.key_prefix.clone(),
+    ));
+    let cluster = if args.cluster {
+        let config = PoolConfig {
+            max_size: args.clients.max(1),
+            encoding,
+            password: args.password.clone(),
+            ..PoolConfig::default()
+        };
+        Some(ClusterClient::connect(&[addr.as_str()], config).await?)
+    } else {
+        None
+    };
+    if args.cluster && args.pipeline > 1 {
+        eprintln!("Pipelining is not used in cluster mode; requests are routed one at a time");
+    }
+
+    if args.populate {
+        let mut target = connect(&addr, encoding, &args.password, &cluster).await?;
+        let mut rng = StdRng::seed_from_u64(args.seed);
+        let started = Instant::now();
+        let keys: Vec<usize> = (0..workload.key_space()).collect();
+        for chunk in keys.chunks(1000) {
+            let commands = chunk.iter()
+                .map(|&i| Command::SET { key: workload.key(i), value: workload.value(&mut rng), visible_at: None })
+                .collect();
+            send(&mut target, commands).await?;
+        }
+        println!("Populated {} keys in {:.2}s", workload.key_space(), started.elapsed().as_secs_f64());
+    }
+
+    let issued = Arc::new(AtomicUsize::new(0));
+    let started = Instant::now();
+    let mut workers = tokio::task::JoinSet::new();
+    for worker in 0..args.clients.max(1) {
+        let target = connect(&addr, encoding, &args.password, &cluster).await?;
+        let workload = workload.clone();
+        let issued = issued.clone();
+        let depth = if cluster.is_some() { 1 } else { args.pipeline.max(1) };
+        let seed = args.seed.wrapping_add(worker as u64 + 1);
+        let total = args.requests;
+        workers.spawn(run_worker(target, workload, issued, total, depth, seed));
+    }
+
+    let mut latency = Histogram::<u64>::new(3).expect("valid histogram precision");
+    let (mut hits, mut misses, mut errors) = (0, 0, 0);
+    while let Some(report) = workers.join_next().await {
+        let report = report.map_err(|e| ClientError::Protocol(e.to_string()))??;
+        latency.add(&report.latency).ok();
+        hits += report.hits;
+        misses += report.misses;
+        errors += report.errors;
+    }
+    let elapsed = started.elapsed();
+    print_report(&args, elapsed, &latency, hits, misses, errors);
+    Ok(())
+}
+
+async fn connect(addr: &str, encoding: Encoding, password: &Option<String>, cluster: &Option<ClusterClient>) -> Result<Target, ClientError> {
+    if let Some(cluster) = cluster {
+        return Ok(Target::Cluster(cluster.clone()));
+    }
+    let mut conn = Connection::connect(addr).await?;
+    if let Some(password) = password {
+        conn.auth(password).await?;
+    }
+    if encoding != Encoding::Json {
+        conn.hello(None, Some(encoding)).await?;
+    }
+    Ok(Target::Node(conn))
+}
+
+async fn send(target: &mut Target, commands: Vec<Command>) -> Result<Vec<Response>, ClientError> {
+    match target {
+        Target::Node(conn) => {
+            let mut pipeline = Pipeline::new();
+            for cmd in commands {
+                pipeline.command(cmd);
+            }
+            conn.execute(pipeline).await
+        }
+        Target::Cluster(cluster) => {
+            let mut responses = Vec::with_capacity(commands.len());
+            for cmd in commands {
+                responses.push(cluster.send(cmd).await?);
+            }
+            Ok(responses)
+        }
+    }
+}
+
+// Send batches of `depth` commands until the shared request budget runs out
+async fn run_worker(
+    mut target: Target,
+    workload: Arc<Workload>,
+    issued: Arc<AtomicUsize>,
+    total: usize,
+    depth: usize,
+    seed: u64,
+) -> Result<WorkerReport, ClientError> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut report = WorkerReport {
+        latency: Histogram::new(3).expect("valid histogram precision"),
+        hits: 0,
+        misses: 0,
+        errors: 0,
+    };
+    loop {
+        let start = issued.fetch_add(depth, Ordering::Relaxed);
+        if start >= total {
+            break;
+        }
+        let count = depth.min(total - start);
+        let commands: Vec<Command> = (0..count).map(|i| workload.next(&mut rng, start + i)).collect();
+        let reads: Vec<bool> = commands.iter().map(|cmd| matches!(cmd, Command::GET { .. })).collect();
+        let sent = Instant::now();
+        let responses = send(&mut target, commands).await?;
+        let micros = sent.elapsed().as_micros() as u64;
+        for (response, read) in responses.iter().zip(reads) {
+            report.latency.record(micros.max(1)).ok();
+            match response {
+                Response::Nil | Response::Missing if read => report.misses += 1,
+                Response::Error(_) => report.errors += 1,
+                _ if read => report.hits += 1,
+                _ => {}
+            }
+        }
+    }
+    Ok(report)
+}
+
+fn print_report(args: &Args, elapsed: Duration, latency: &Histogram<u64>, hits: u64, misses: u64, errors: u64) {
+    let requests = latency.len();
+    let seconds = elapsed.as_secs_f64().max(f64::EPSILON);
+    let ms = |micros: u64| micros as f64 / 1000.0;
+    println!("====== {} requests ======", requests);
+    println!("  {} clients, pipeline {}, {} keys ({:?}), value size {:?}, read ratio {}",
+        args.clients, args.pipeline, args.keyspace, args.key_distribution, args.value_size, args.read_ratio);
+    println!("  completed in {:.2}s", seconds);
+    println!("  throughput: {:.0} requests/s", requests as f64 / seconds);
+    println!("  reads: {} hits, {} misses; errors: {}", hits, misses, errors);
+    println!("  latency (ms): min {:.3} p50 {:.3} p90 {:.3} p99 {:.3} p99.9 {:.3} max {:.3}",
+        ms(latency.min()),
+        ms(latency.value_at_quantile(0.50)),
+        ms(latency.value_at_quantile(0.90)),
+        ms(latency.value_at_quantile(0.99)),
+        ms(latency.value_at_quantile(0.999)),
+        ms(latency.max()),
+    );
+}
@@ -0,0 +1,105 @@
+use std::str::FromStr;
+use rand::Rng;
+use pluto_client::Command;
+
+// Sizes of the values written
+#[derive(Debug, Clone, Copy)]
+pub enum ValueSize {
+    Fixed(usize),
+    Uniform(usize, usize), // inclusive range
+}
+
+impl FromStr for ValueSize {
+    type Err = String;
+
+    // `100` for a fixed size, `10-1000` for sizes picked uniformly in a range
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |v: &str| v.trim().parse::<usize>().map_err(|_| format!("invalid value size {}", s));
+        match s.split_once('-') {
+            Some((min, max)) => {
+                let (min, max) = (parse(min)?, parse(max)?);
+                if min > max {
+                    return Err(format!("invalid value size range {}", s));
+                }
+                Ok(ValueSize::Uniform(min, max))
+            }
+            None => Ok(ValueSize::Fixed(parse(s)?)),
+        }
+    }
+}
+
+impl ValueSize {
+    fn pick(&self, rng: &mut impl Rng) -> usize {
+        match *self {
+            ValueSize::Fixed(size) => size,
+            ValueSize::Uniform(min, max) => rng.gen_range(min..=max),
+        }
+    }
+}
+
+// How keys are picked from the key space
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum KeyDistribution {
+    Uniform,
+    Zipf,    // a few keys get most of the traffic
+    Sequential,
+}
+
+// Generates the commands a worker sends
+pub struct Workload {
+    key_space: usize,
+    distribution: KeyDistribution,
+    zipf_cdf: Vec<f64>, // cumulative key probabilities, only for Zipf
+    value_size: ValueSize,
+    read_ratio: f64,
+    key_prefix: String,
+}
+
+impl Workload {
+    pub fn new(key_space: usize, distribution: KeyDistribution, zipf_exponent: f64, value_size: ValueSize, read_ratio: f64, key_prefix: String) -> Self {
+        let key_space = key_space.max(1);
+        let zipf_cdf = if distribution == KeyDistribution::Zipf {
+            let weights: Vec<f64> = (1..=key_space).map(|rank| 1.0 / (rank as f64).powf(zipf_exponent)).collect();
+            let total: f64 = weights.iter().sum();
+            let mut sum = 0.0;
+            weights.iter().map(|w| { sum += w / total; sum }).collect()
+        } else {
+            Vec::new()
+        };
+        Workload { key_space, distribution, zipf_cdf, value_size, read_ratio, key_prefix }
+    }
+
+    pub fn key_space(&self) -> usize {
+        self.key_space
+    }
+
+    pub fn key(&self, index: usize) -> String {
+        format!("{}{}", self.key_prefix, index)
+    }
+
+    pub fn value(&self, rng: &mut impl Rng) -> Vec<u8> {
+        vec![b'x'; self.value_size.pick(rng)]
+    }
+
+    fn pick_key(&self, rng: &mut impl Rng, sequence: usize) -> String {
+        let index = match self.distribution {
+            KeyDistribution::Uniform => rng.gen_range(0..self.key_space),
+            KeyDistribution::Sequential => sequence % self.key_space,
+            KeyDistribution::Zipf => {
+                let p: f64 = rng.gen_range(0.0..1.0);
+                self.zipf_cdf.partition_point(|&c| c < p).min(self.key_space - 1)
+            }
+        };
+        self.key(index)
+    }
+
+    // Next command: a GET with probability `read_ratio`, otherwise a SET
+    pub fn next(&self, rng: &mut impl Rng, sequence: usize) -> Command {
+        let key = self.pick_key(rng, sequence);
+        if rng.gen_bool(self.read_ratio) {
+            Command::GET { key }
+        } else {
+            Command::SET { key, value: self.value(rng), visible_at: None }
+        }
+    }
+}
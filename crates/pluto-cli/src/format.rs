@@ -0,0 +1,108 @@
+use pluto_client::{KeyEvent, Response};
+
+// Show bytes as text when they are printable UTF-8, escaping them otherwise
+pub fn format_bytes(data: &[u8]) -> String {
+    match std::str::from_utf8(data) {
+        Ok(text) if !text.chars().any(|c| c.is_control() && c != '\n' && c != '\t') => format!("{:?}", text),
+        _ => {
+            let escaped: String = data.iter()
+                .map(|&b| match b {
+                    0x20..=0x7e if b != b'"' && b != b'\\' => (b as char).to_string(),
+                    _ => format!("\\x{:02x}", b),
+                })
+                .collect();
+            format!("\"{}\"", escaped)
+        }
+    }
+}
+
+// Render a response the way the REPL shows it
+pub fn format_response(response: &Response) -> String {
+    match response {
+        Response::Success => "OK".to_string(),
+        Response::Pong => "PONG".to_string(),
+        Response::Error(error) => format!("(error) {} {}", error.code, error),
+        Response::Data(data) => format_bytes(data),
+        Response::Stale(data) => format!("(stale) {}", format_bytes(data)),
+        Response::Locked => "(locked) compute and set the key".to_string(),
+        Response::InProgress => "(in progress) another client is computing the key".to_string(),
+        Response::Missing => "(missing)".to_string(),
+        Response::Nil => "(nil)".to_string(),
+        Response::Exists(exists) => format!("(boolean) {}", exists),
+        Response::Integer(value) => format!("(integer) {}", value),
+        Response::Info(info) => info.trim_end().to_string(),
+        Response::Slots(json) => json.clone(),
+        Response::Config(values) => values.iter()
+            .enumerate()
+            .map(|(i, (key, value))| format!("{}) {} = {}", i + 1, key, value))
+            .collect::<Vec<_>>()
+            .join("\n"),
+        Response::NodeInfo { node_id, address, weight, hashing } => format!(
+            "node_id: {}\naddress: {}\nweight: {}\nhashing: {}",
+            node_id, address, weight, format!("{:?}", hashing).to_lowercase()
+        ),
+        Response::Message { channel, message } => format!("message on {}: {}", channel, format_bytes(message)),
+        Response::KeyEvent(event) => format_key_event(event),
+        Response::Commands(commands) => commands.iter()
+            .enumerate()
+            .map(|(i, info)| format!("{}) {} arity {} [{}] since {}", i + 1, info.name, info.arity, info.flags.join(", "), info.since))
+            .collect::<Vec<_>>()
+            .join("\n"),
+        Response::CommandDocs(docs) => docs.iter()
+            .map(|doc| format!("{}\n  {} (since {})", format!("{} {}", doc.name, doc.usage).trim_end(), doc.summary, doc.since))
+            .collect::<Vec<_>>()
+            .join("\n"),
+        Response::Hello { server, version, protocol, encodings, compression, auth_required } => {
+            let encodings: Vec<String> = encodings.iter().map(|e| format!("{:?}", e).to_lowercase()).collect();
+            format!(
+                "server: {}\nversion: {}\nprotocol: {}\nencodings: {}\ncompression: {}\nauth_required: {}",
+                server, version, protocol, encodings.join(", "), compression.join(", "), auth_required
+            )
+        }
+        Response::Moved { slot, address } => format!("(moved) slot {} is served by {}", slot, address),
+        Response::Ask { slot, address } => format!("(ask) slot {} is moving to {}", slot, address),
+        Response::Replicate { offset, command, .. } => format!("(replicate) {} at offset {}", command.name(), offset),
+        Response::Digests(hashes) => hashes.iter()
+            .enumerate()
+            .map(|(i, hash)| format!("{}) {:016x}", i + 1, hash))
+            .collect::<Vec<_>>()
+            .join("\n"),
+        Response::Keys(keys) if keys.is_empty() => "(empty list)".to_string(),
+        Response::Keys(keys) | Response::KeysChunk(keys) => keys.iter()
+            .enumerate()
+            .map(|(i, key)| format!("{}) {}", i + 1, format_bytes(key.as_bytes())))
+            .collect::<Vec<_>>()
+            .join("\n"),
+        Response::Members(members) if members.is_empty() => "(empty set)".to_string(),
+        Response::Members(members) => members.iter()
+            .enumerate()
+            .map(|(i, member)| format!("{}) {}", i + 1, format_bytes(member.as_bytes())))
+            .collect::<Vec<_>>()
+            .join("\n"),
+        Response::Aggregate { result, failures } => {
+            let mut out = format_response(result);
+            for (node, error) in failures {
+                out.push_str(&format!("\n(failed) {}: {}", node, error));
+            }
+            out
+        }
+        Response::KeyDigests(digests) => digests.iter()
+            .enumerate()
+            .map(|(i, (key, hash))| format!("{}) {} {:016x}", i + 1, key, hash))
+            .collect::<Vec<_>>()
+            .join("\n"),
+        Response::Invalidate { keys } if keys.is_empty() => "(invalidate) every key".to_string(),
+        Response::Invalidate { keys } => format!("(invalidate) {}", keys.join(" ")),
+        Response::Object(info) => format!(
+            "created_at: {}\nmodified_at: {}\naccessed_at: {}\naccesses: {}\nversion: {}\nstored_bytes: {}\nmemory: {}\ncodec: {}\ninline: {}\nvisible_at: {}",
+            info.created_at, info.modified_at, info.accessed_at, info.accesses, info.version,
+            info.stored_bytes, info.memory, info.codec, info.inline,
+            info.visible_at.map_or("-".to_string(), |at| at.to_string())
+        ),
+    }
+}
+
+// A key event as `key reason size`
+pub fn format_key_event(event: &KeyEvent) -> String {
+    format!("{} {} {}", event.key, format!("{:?}", event.reason).to_lowercase(), event.size)
+}
@@ -0,0 +1,257 @@
+mod format;
+mod parse;
+
+use std::path::PathBuf;
+use clap::Parser;
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::history::DefaultHistory;
+use rustyline::{Context, Editor, Helper, Highlighter, Hinter, Validator};
+use tokio::io::{AsyncBufReadExt, BufReader};
+use pluto_client::{ClientError, ClusterClient, Command, Connection, Pipeline, PoolConfig, Response};
+use format::{format_bytes, format_key_event, format_response};
+use parse::{parse_line, COMMAND_NAMES};
+
+// Commands sent per round trip in --pipe mode
+const PIPE_BATCH: usize = 1000;
+
+// Command line arguments
+#[derive(Parser, Debug)]
+#[command(name = "pluto-cli")]
+#[command(author = "thebyteslayer")]
+#[command(version = "1.0.0")]
+#[command(about = "Interactive command line client for flux-cache")]
+struct Args {
+    /// Server host
+    #[arg(long, default_value = "127.0.0.1")]
+    host: String,
+    /// Server port
+    #[arg(long, default_value_t = 6124)]
+    port: u16,
+    /// Password sent with AUTH after connecting
+    #[arg(long)]
+    password: Option<String>,
+    /// Route key commands to the node serving their slot
+    #[arg(long)]
+    cluster: bool,
+    /// Read commands from stdin, one per line, and print one response per line
+    #[arg(long)]
+    pipe: bool,
+}
+
+// Where commands go: one node, or the whole cluster
+enum Target {
+    Node(Connection),
+    Cluster(ClusterClient),
+}
+
+impl Target {
+    async fn send(&mut self, cmd: Command) -> Result<Response, ClientError> {
+        match self {
+            Target::Node(conn) => conn.send(cmd).await,
+            Target::Cluster(cluster) => cluster.send(cmd).await,
+        }
+    }
+}
+
+// Completes command names at the start of the line: those the server
+// reports through COMMAND, or the ones the CLI knows of when it can't
+#[derive(Helper, Hinter, Highlighter, Validator)]
+struct CliHelper {
+    names: Vec<String>,
+}
+
+impl Completer for CliHelper {
+    type Candidate = String;
+
+    fn complete(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> rustyline::Result<(usize, Vec<String>)> {
+        let prefix = &line[..pos];
+        if prefix.contains(char::is_whitespace) {
+            return Ok((pos, Vec::new()));
+        }
+        let prefix = prefix.to_uppercase();
+        let names = self.names.iter()
+            .filter(|name| name.starts_with(&prefix))
+            .cloned()
+            .collect();
+        Ok((0, names))
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let args = Args::parse();
+    let addr = format!("{}:{}", args.host, args.port);
+    let target = match connect(&args, &addr).await {
+        Ok(target) => target,
+        Err(e) => {
+            eprintln!("Could not connect to {} - {}", addr, e);
+            std::process::exit(1);
+        }
+    };
+    let result = if args.pipe {
+        run_pipe(target).await
+    } else {
+        run_repl(target, &addr).await
+    };
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+async fn connect(args: &Args, addr: &str) -> Result<Target, ClientError> {
+    if args.cluster {
+        let config = PoolConfig { password: args.password.clone(), ..PoolConfig::default() };
+        return Ok(Target::Cluster(ClusterClient::connect(&[addr], config).await?));
+    }
+    let mut conn = Connection::connect(addr).await?;
+    if let Some(password) = &args.password {
+        conn.auth(password).await?;
+    }
+    Ok(Target::Node(conn))
+}
+
+async fn run_repl(mut target: Target, addr: &str) -> Result<(), ClientError> {
+    let mut editor: Editor<CliHelper, DefaultHistory> = Editor::new()
+        .map_err(|e| ClientError::Protocol(e.to_string()))?;
+    let names = match target.send(Command::COMMAND).await {
+        // Commands nodes send each other aren't typed in
+        Ok(Response::Commands(commands)) => commands.into_iter()
+            .filter(|info| !info.flags.iter().any(|flag| flag == "internal"))
+            .map(|info| info.name)
+            .collect(),
+        _ => COMMAND_NAMES.iter().map(|name| name.to_string()).collect(),
+    };
+    editor.set_helper(Some(CliHelper { names }));
+    let history = history_file();
+    if let Some(path) = &history {
+        let _ = editor.load_history(path);
+    }
+    let prompt = format!("{}> ", addr);
+    loop {
+        let line = match editor.readline(&prompt) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted | ReadlineError::Eof) => break,
+            Err(e) => return Err(ClientError::Protocol(e.to_string())),
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let _ = editor.add_history_entry(line);
+        if line.eq_ignore_ascii_case("quit") || line.eq_ignore_ascii_case("exit") {
+            break;
+        }
+        let cmd = match parse_line(line) {
+            Ok(cmd) => cmd,
+            Err(e) => {
+                println!("(error) {}", e);
+                continue;
+            }
+        };
+        if let Command::SUBSCRIBE { channels } = cmd {
+            // Subscriber mode takes over the session until the process is stopped
+            return subscribe(target, addr, &channels).await;
+        }
+        if let Command::KEYEVENTS_SUBSCRIBE { patterns } = cmd {
+            return watch_keys(target, addr, &patterns).await;
+        }
+        match target.send(cmd).await {
+            Ok(response) => println!("{}", format_response(&response)),
+            Err(e @ (ClientError::Io(_) | ClientError::ConnectionClosed)) => return Err(e),
+            Err(e) => println!("(error) {}", e),
+        }
+    }
+    if let Some(path) = &history {
+        let _ = editor.save_history(path);
+    }
+    Ok(())
+}
+
+async fn subscribe(target: Target, addr: &str, channels: &[String]) -> Result<(), ClientError> {
+    let conn = match target {
+        Target::Node(conn) => conn,
+        Target::Cluster(cluster) => cluster.node(addr).await?,
+    };
+    let channels: Vec<&str> = channels.iter().map(String::as_str).collect();
+    let mut subscription = conn.subscribe(&channels).await?;
+    println!("Subscribed to {} channel(s), press Ctrl-C to stop", subscription.channel_count());
+    loop {
+        let (channel, message) = subscription.next_message().await?;
+        println!("{}: {}", channel, format_bytes(&message));
+    }
+}
+
+async fn watch_keys(target: Target, addr: &str, patterns: &[String]) -> Result<(), ClientError> {
+    let conn = match target {
+        Target::Node(conn) => conn,
+        Target::Cluster(cluster) => cluster.node(addr).await?,
+    };
+    let patterns: Vec<&str> = patterns.iter().map(String::as_str).collect();
+    let mut events = conn.watch_keys(&patterns).await?;
+    println!("Watching {} pattern(s), press Ctrl-C to stop", events.pattern_count());
+    loop {
+        println!("{}", format_key_event(&events.next_event().await?));
+    }
+}
+
+// Send stdin line by line. On a single node the lines are pipelined in
+// batches; in cluster mode each command is routed on its own.
+async fn run_pipe(mut target: Target) -> Result<(), ClientError> {
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    let mut batch = Vec::new();
+    let mut failed = false;
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match parse_line(&line) {
+            Ok(cmd) => batch.push(cmd),
+            Err(e) => {
+                eprintln!("(error) {}: {}", e, line.trim());
+                failed = true;
+                continue;
+            }
+        }
+        if batch.len() >= PIPE_BATCH {
+            failed |= send_batch(&mut target, std::mem::take(&mut batch)).await?;
+        }
+    }
+    failed |= send_batch(&mut target, batch).await?;
+    if failed {
+        std::process::exit(2);
+    }
+    Ok(())
+}
+
+// Print one line per response, returning whether any of them was an error
+async fn send_batch(target: &mut Target, commands: Vec<Command>) -> Result<bool, ClientError> {
+    let responses = match target {
+        Target::Node(conn) => {
+            let mut pipeline = Pipeline::new();
+            for cmd in commands {
+                pipeline.command(cmd);
+            }
+            conn.execute(pipeline).await?
+        }
+        Target::Cluster(cluster) => {
+            let mut responses = Vec::with_capacity(commands.len());
+            for cmd in commands {
+                responses.push(cluster.send(cmd).await?);
+            }
+            responses
+        }
+    };
+    let mut failed = false;
+    for response in &responses {
+        failed |= matches!(response, Response::Error(_));
+        // Multi-line responses are kept on one line so output lines match input lines
+        println!("{}", format_response(response).replace('\n', "\\n"));
+    }
+    Ok(failed)
+}
+
+fn history_file() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".pluto_cli_history"))
+}
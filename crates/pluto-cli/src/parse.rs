@@ -0,0 +1,444 @@
+use pluto_client::{Command, ResetMode, ShutdownMode, SlotState};
+
+// Command names offered by tab completion, in the order they are listed
+pub const COMMAND_NAMES: &[&str] = &[
+    "SET", "GET", "DEL", "EXISTS", "EXPIRE", "TTL", "DUMP", "MIGRATE", "PING", "ECHO", "INFO", "CONFIG_GET", "PUBLISH",
+    "SUBSCRIBE", "AUTH", "HELLO", "MEMORY_USAGE", "MEMORY_DEFRAG", "CLUSTER_SLOTS",
+    "NODE_INFO", "CLUSTER_JOIN", "CLUSTER_REMOVE", "CLUSTER_ISOLATE", "CLUSTER_RESET", "DBSIZE", "KEYS", "FLUSHALL",
+    "CLUSTER_DBSIZE", "CLUSTER_KEYS", "CLUSTER_INFO", "CLUSTER_FLUSHALL", "CLUSTER_SETSLOT", "MIGRATE_SLOTS",
+    "MIGRATE_SLOTS_ABORT", "ASKING", "READONLY", "READWRITE", "WAIT", "QUORUM", "SHUTDOWN",
+    "GEO_PROMOTE", "COUNTER_INCRBY", "COUNTER_GET", "ORSET_ADD", "ORSET_REM", "ORSET_MEMBERS", "UNLINK",
+    "EXPIREAT", "PEXPIRE", "PEXPIREAT", "PTTL", "PERSIST", "OBJECT_IDLETIME", "OBJECT_FREQ", "CLIENT_TRACKING",
+    "MSETNX", "DEBUG_OBJECT", "SOFT_EXPIRE", "SOFT_TTL",
+    "GET_OR_LOCK", "UNLOCK", "SET_MISSING",
+    "KEYEVENTS_SUBSCRIBE", "GETSET", "COMMAND", "COMMAND_DOCS", "VERSION",
+    "PERSISTENCE_STATUS",
+];
+
+// Split a line into words. Double or single quotes group words with spaces,
+// and a backslash escapes the next character inside double quotes.
+pub fn split_words(line: &str) -> Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut chars = line.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+        let mut word = String::new();
+        while let Some(&c) = chars.peek() {
+            match c {
+                '"' | '\'' => {
+                    chars.next();
+                    let mut closed = false;
+                    while let Some(inner) = chars.next() {
+                        if inner == c {
+                            closed = true;
+                            break;
+                        }
+                        if inner == '\\' && c == '"' {
+                            match chars.next() {
+                                Some('n') => word.push('\n'),
+                                Some('t') => word.push('\t'),
+                                Some(escaped) => word.push(escaped),
+                                None => break,
+                            }
+                        } else {
+                            word.push(inner);
+                        }
+                    }
+                    if !closed {
+                        return Err("unbalanced quotes".to_string());
+                    }
+                }
+                c if c.is_whitespace() => break,
+                c => {
+                    word.push(c);
+                    chars.next();
+                }
+            }
+        }
+        words.push(word);
+    }
+    Ok(words)
+}
+
+// Turn a line into a command. Lines starting with `{` or `"` are taken as the
+// command's JSON form; anything else is a command name followed by arguments.
+pub fn parse_line(line: &str) -> Result<Command, String> {
+    let trimmed = line.trim();
+    if trimmed.starts_with('{') || trimmed.starts_with('"') {
+        return serde_json::from_str(trimmed).map_err(|e| e.to_string());
+    }
+    parse_words(&split_words(trimmed)?)
+}
+
+// Turn a command name and its arguments into a command
+fn parse_words(words: &[String]) -> Result<Command, String> {
+    let Some((name, args)) = words.split_first() else {
+        return Err("empty command".to_string());
+    };
+    let name = name.to_uppercase();
+    let arity = |min: usize, max: usize| -> Result<(), String> {
+        if args.len() < min || args.len() > max {
+            Err(format!("wrong number of arguments for {}", name))
+        } else {
+            Ok(())
+        }
+    };
+    let arg = |i: usize| args[i].clone();
+    let cmd = match name.as_str() {
+        "SET" => {
+            arity(2, 4)?;
+            Command::SET { key: arg(0), value: arg(1).into_bytes(), visible_at: visible_at(&args[2..])? }
+        }
+        "GETSET" => {
+            arity(2, 2)?;
+            Command::GETSET { key: arg(0), value: arg(1).into_bytes() }
+        }
+        "GET" => {
+            arity(1, 1)?;
+            Command::GET { key: arg(0) }
+        }
+        "GET_OR_LOCK" => {
+            arity(2, 3)?;
+            let lock_ms = args[1].parse().map_err(|_| format!("invalid number of milliseconds {}", args[1]))?;
+            let wait_ms = match args.get(2) {
+                Some(wait) => wait.parse().map_err(|_| format!("invalid number of milliseconds {}", wait))?,
+                None => 0,
+            };
+            Command::GET_OR_LOCK { key: arg(0), lock_ms, wait_ms }
+        }
+        "UNLOCK" => {
+            arity(1, 1)?;
+            Command::UNLOCK { key: arg(0) }
+        }
+        "DEL" => {
+            arity(1, usize::MAX)?;
+            Command::DEL { keys: args.to_vec() }
+        }
+        "UNLINK" => {
+            arity(1, usize::MAX)?;
+            Command::UNLINK { keys: args.to_vec() }
+        }
+        "EXISTS" if args.len() > 1 => Command::EXISTS_MANY { keys: args.to_vec() },
+        "EXISTS" => {
+            arity(1, 1)?;
+            Command::EXISTS { key: arg(0) }
+        }
+        "MSETNX" => {
+            arity(2, usize::MAX)?;
+            if args.len() % 2 != 0 {
+                return Err(format!("wrong number of arguments for {}", name));
+            }
+            let entries = args.chunks(2).map(|pair| (pair[0].clone(), pair[1].clone().into())).collect();
+            Command::MSETNX { entries }
+        }
+        "EXPIRE" => {
+            arity(2, 4)?;
+            let seconds = args[1].parse().map_err(|_| format!("invalid number of seconds {}", args[1]))?;
+            Command::EXPIRE { key: arg(0), seconds, jitter_pct: jitter(&args[2..])? }
+        }
+        "TTL" => {
+            arity(1, 1)?;
+            Command::TTL { key: arg(0) }
+        }
+        "SOFT_EXPIRE" => {
+            arity(2, 2)?;
+            let seconds = args[1].parse().map_err(|_| format!("invalid number of seconds {}", args[1]))?;
+            Command::SOFT_EXPIRE { key: arg(0), seconds }
+        }
+        "SOFT_TTL" => {
+            arity(1, 1)?;
+            Command::SOFT_TTL { key: arg(0) }
+        }
+        "SET_MISSING" => {
+            arity(2, 2)?;
+            let milliseconds = args[1].parse().map_err(|_| format!("invalid number of milliseconds {}", args[1]))?;
+            Command::SET_MISSING { key: arg(0), milliseconds }
+        }
+        "EXPIREAT" => {
+            arity(2, 2)?;
+            let timestamp = args[1].parse().map_err(|_| format!("invalid timestamp {}", args[1]))?;
+            Command::EXPIREAT { key: arg(0), timestamp }
+        }
+        "PEXPIRE" => {
+            arity(2, 4)?;
+            let milliseconds = args[1].parse().map_err(|_| format!("invalid number of milliseconds {}", args[1]))?;
+            Command::PEXPIRE { key: arg(0), milliseconds, jitter_pct: jitter(&args[2..])? }
+        }
+        "PEXPIREAT" => {
+            arity(2, 2)?;
+            let timestamp_ms = args[1].parse().map_err(|_| format!("invalid timestamp {}", args[1]))?;
+            Command::PEXPIREAT { key: arg(0), timestamp_ms }
+        }
+        "PTTL" => {
+            arity(1, 1)?;
+            Command::PTTL { key: arg(0) }
+        }
+        "PERSIST" => {
+            arity(1, 1)?;
+            Command::PERSIST { key: arg(0) }
+        }
+        "DUMP" => {
+            arity(1, 1)?;
+            Command::DUMP { key: arg(0) }
+        }
+        "MIGRATE" => {
+            arity(2, 4)?;
+            let mut copy = false;
+            let mut replace = false;
+            for option in &args[2..] {
+                match option.to_uppercase().as_str() {
+                    "COPY" => copy = true,
+                    "REPLACE" => replace = true,
+                    _ => return Err(format!("unknown MIGRATE option {}", option)),
+                }
+            }
+            Command::MIGRATE { key: arg(0), address: arg(1), copy, replace }
+        }
+        "PING" => {
+            arity(0, 1)?;
+            Command::PING { message: args.first().map(|m| m.clone().into_bytes()) }
+        }
+        "ECHO" => {
+            arity(1, 1)?;
+            Command::ECHO { message: arg(0).into_bytes() }
+        }
+        "INFO" => {
+            arity(0, 1)?;
+            Command::INFO { section: args.first().cloned() }
+        }
+        "CONFIG_GET" => {
+            arity(1, 1)?;
+            Command::CONFIG_GET { key: arg(0) }
+        }
+        "PUBLISH" => {
+            arity(2, 2)?;
+            Command::PUBLISH { channel: arg(0), message: arg(1).into_bytes() }
+        }
+        "SUBSCRIBE" => {
+            arity(1, usize::MAX)?;
+            Command::SUBSCRIBE { channels: args.to_vec() }
+        }
+        "KEYEVENTS_SUBSCRIBE" => {
+            arity(1, usize::MAX)?;
+            Command::KEYEVENTS_SUBSCRIBE { patterns: args.to_vec() }
+        }
+        "AUTH" => {
+            arity(1, 2)?;
+            match args {
+                [password] => Command::AUTH { username: None, password: password.clone() },
+                _ => Command::AUTH { username: Some(arg(0)), password: arg(1) },
+            }
+        }
+        "HELLO" => {
+            arity(0, 1)?;
+            let protocol = args.first()
+                .map(|p| p.parse().map_err(|_| format!("invalid protocol version {}", p)))
+                .transpose()?;
+            Command::HELLO { protocol, encoding: None }
+        }
+        "MEMORY_USAGE" => {
+            arity(1, 1)?;
+            Command::MEMORY_USAGE { key: arg(0) }
+        }
+        "OBJECT_IDLETIME" => {
+            arity(1, 1)?;
+            Command::OBJECT_IDLETIME { key: arg(0) }
+        }
+        "OBJECT_FREQ" => {
+            arity(1, 1)?;
+            Command::OBJECT_FREQ { key: arg(0) }
+        }
+        "DEBUG_OBJECT" => {
+            arity(1, 1)?;
+            Command::DEBUG_OBJECT { key: arg(0) }
+        }
+        "MEMORY_DEFRAG" => Command::MEMORY_DEFRAG,
+        "CLUSTER_SLOTS" => Command::CLUSTER_SLOTS,
+        "NODE_INFO" => Command::NODE_INFO,
+        "CLUSTER_JOIN" => {
+            arity(1, 1)?;
+            Command::CLUSTER_JOIN { address: arg(0) }
+        }
+        "CLUSTER_REMOVE" => {
+            arity(1, 1)?;
+            Command::CLUSTER_REMOVE { address: arg(0) }
+        }
+        "CLUSTER_ISOLATE" => Command::CLUSTER_ISOLATE,
+        "CLUSTER_RESET" => {
+            arity(0, 1)?;
+            let mode = match args.first().map(|m| m.to_uppercase()).as_deref() {
+                None => None,
+                Some("SOFT") => Some(ResetMode::SOFT),
+                Some("HARD") => Some(ResetMode::HARD),
+                Some(other) => return Err(format!("unknown reset mode {}", other)),
+            };
+            Command::CLUSTER_RESET { mode }
+        }
+        "DBSIZE" => Command::DBSIZE,
+        "FLUSHALL" => Command::FLUSHALL,
+        "KEYS" => {
+            arity(0, 3)?;
+            let pattern = args.first().cloned().unwrap_or_else(|| "*".to_string());
+            Command::KEYS { pattern, chunk: chunk(args.get(1..).unwrap_or_default())? }
+        }
+        "CLUSTER_DBSIZE" => Command::CLUSTER_DBSIZE,
+        "CLUSTER_FLUSHALL" => Command::CLUSTER_FLUSHALL,
+        "CLUSTER_INFO" => {
+            arity(0, 1)?;
+            Command::CLUSTER_INFO { section: args.first().cloned() }
+        }
+        "CLUSTER_KEYS" => {
+            arity(0, 3)?;
+            let pattern = args.first().cloned().unwrap_or_else(|| "*".to_string());
+            Command::CLUSTER_KEYS { pattern, chunk: chunk(args.get(1..).unwrap_or_default())? }
+        }
+        "MIGRATE_SLOTS" => {
+            arity(2, usize::MAX)?;
+            let mut slots = Vec::new();
+            for word in &args[1..] {
+                slots.extend(parse_slots(word)?);
+            }
+            Command::MIGRATE_SLOTS { slots, address: arg(0) }
+        }
+        "CLUSTER_SETSLOT" => {
+            arity(2, 3)?;
+            let slots = parse_slots(&args[0])?;
+            let address = args.get(2).cloned();
+            let needs_address = |state: fn(String) -> SlotState| {
+                address.clone().map(state).ok_or_else(|| format!("{} needs a node address", args[1].to_uppercase()))
+            };
+            let state = match args[1].to_uppercase().as_str() {
+                "NODE" => needs_address(|address| SlotState::NODE { address })?,
+                "MIGRATING" => needs_address(|address| SlotState::MIGRATING { address })?,
+                "IMPORTING" => needs_address(|address| SlotState::IMPORTING { address })?,
+                "UNASSIGNED" => SlotState::UNASSIGNED,
+                "STABLE" => SlotState::STABLE,
+                other => return Err(format!("unknown slot state {}", other)),
+            };
+            Command::CLUSTER_SETSLOT { slots, state }
+        }
+        "MIGRATE_SLOTS_ABORT" => Command::MIGRATE_SLOTS_ABORT,
+        "COMMAND" => Command::COMMAND,
+        "VERSION" => Command::VERSION,
+        "PERSISTENCE_STATUS" => Command::PERSISTENCE_STATUS,
+        "COMMAND_DOCS" => Command::COMMAND_DOCS { names: args.iter().map(|name| name.to_uppercase()).collect() },
+        "GEO_PROMOTE" => {
+            arity(0, 1)?;
+            let local = match args.first().map(|m| m.to_uppercase()).as_deref() {
+                None => false,
+                Some("LOCAL") => true,
+                Some(other) => return Err(format!("unknown GEO_PROMOTE option {}", other)),
+            };
+            Command::GEO_PROMOTE { local }
+        }
+        "COUNTER_INCRBY" => {
+            arity(1, 2)?;
+            let delta = args.get(1)
+                .map(|d| d.parse().map_err(|_| format!("invalid increment {}", d)))
+                .transpose()?
+                .unwrap_or(1);
+            Command::COUNTER_INCRBY { key: arg(0), delta }
+        }
+        "COUNTER_GET" => {
+            arity(1, 1)?;
+            Command::COUNTER_GET { key: arg(0) }
+        }
+        "ORSET_ADD" => {
+            arity(2, usize::MAX)?;
+            Command::ORSET_ADD { key: arg(0), members: args[1..].to_vec() }
+        }
+        "ORSET_REM" => {
+            arity(2, usize::MAX)?;
+            Command::ORSET_REM { key: arg(0), members: args[1..].to_vec() }
+        }
+        "ORSET_MEMBERS" => {
+            arity(1, 1)?;
+            Command::ORSET_MEMBERS { key: arg(0) }
+        }
+        "CLIENT_TRACKING" => {
+            arity(1, 1)?;
+            let on = match args[0].to_uppercase().as_str() {
+                "ON" => true,
+                "OFF" => false,
+                other => return Err(format!("CLIENT_TRACKING takes ON or OFF, not {}", other)),
+            };
+            Command::CLIENT_TRACKING { on }
+        }
+        "ASKING" => Command::ASKING,
+        "READONLY" => Command::READONLY,
+        "READWRITE" => Command::READWRITE,
+        "QUORUM" => {
+            arity(2, usize::MAX)?;
+            let replicas = args[0].parse().map_err(|_| format!("invalid number of replicas {}", args[0]))?;
+            Command::QUORUM { replicas, command: Box::new(parse_words(&args[1..])?) }
+        }
+        "WAIT" => {
+            arity(2, 2)?;
+            let replicas = args[0].parse().map_err(|_| format!("invalid number of replicas {}", args[0]))?;
+            let timeout = args[1].parse().map_err(|_| format!("invalid timeout {}", args[1]))?;
+            Command::WAIT { replicas, timeout }
+        }
+        "SHUTDOWN" => {
+            arity(0, 1)?;
+            let mode = match args.first().map(|m| m.to_uppercase()).as_deref() {
+                None => None,
+                Some("SAVE") => Some(ShutdownMode::SAVE),
+                Some("NOSAVE") => Some(ShutdownMode::NOSAVE),
+                Some(other) => return Err(format!("unknown shutdown mode {}", other)),
+            };
+            Command::SHUTDOWN { mode }
+        }
+        _ => return Err(format!("unknown command {}", name)),
+    };
+    Ok(cmd)
+}
+
+// Slots written as a comma separated list of slots or inclusive ranges of
+// them, as in 0-99,200
+fn parse_slots(spec: &str) -> Result<Vec<usize>, String> {
+    let mut slots = Vec::new();
+    for part in spec.split(',') {
+        let (start, end) = part.split_once('-').unwrap_or((part, part));
+        let start: usize = start.parse().map_err(|_| format!("invalid slot {}", part))?;
+        let end: usize = end.parse().map_err(|_| format!("invalid slot {}", part))?;
+        slots.extend(start..=end);
+    }
+    Ok(slots)
+}
+
+// An optional `JITTER <percent>` after a TTL
+fn jitter(args: &[String]) -> Result<Option<u8>, String> {
+    match args {
+        [] => Ok(None),
+        [flag, pct] if flag.eq_ignore_ascii_case("JITTER") => {
+            pct.parse().map(Some).map_err(|_| format!("invalid jitter percentage {}", pct))
+        }
+        _ => Err(format!("expected JITTER <percent>, got {}", args.join(" "))),
+    }
+}
+
+// An optional `CHUNK <keys>` after a pattern, to stream the keys in frames of that many
+fn chunk(args: &[String]) -> Result<Option<usize>, String> {
+    match args {
+        [] => Ok(None),
+        [flag, keys] if flag.eq_ignore_ascii_case("CHUNK") => {
+            keys.parse().map(Some).map_err(|_| format!("invalid chunk size {}", keys))
+        }
+        _ => Err(format!("expected CHUNK <keys>, got {}", args.join(" "))),
+    }
+}
+
+// An optional `VISIBLE_AT <unix seconds>` or `PVISIBLE_AT <unix milliseconds>`
+// after a value, as milliseconds
+fn visible_at(args: &[String]) -> Result<Option<u64>, String> {
+    let parse = |time: &str| time.parse::<u64>().map_err(|_| format!("invalid unix time {}", time));
+    match args {
+        [] => Ok(None),
+        [flag, time] if flag.eq_ignore_ascii_case("VISIBLE_AT") => Ok(Some(parse(time)?.saturating_mul(1000))),
+        [flag, time] if flag.eq_ignore_ascii_case("PVISIBLE_AT") => Ok(Some(parse(time)?)),
+        _ => Err(format!("expected VISIBLE_AT <unix seconds> or PVISIBLE_AT <unix ms>, got {}", args.join(" "))),
+    }
+}
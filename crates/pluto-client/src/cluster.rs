@@ -0,0 +1,451 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, Mutex, RwLock};
+use bytes::Bytes;
+use pluto_core::cluster::{key_slot, ClusterData, NodeHealth, TOTAL_SLOTS};
+use pluto_core::protocol::{Command, ObjectInfo, Response};
+use crate::connection::{expect_lookup, Connection, Lookup};
+use crate::error::ClientError;
+use crate::pool::{Pool, PoolConfig};
+
+// Redirects followed for one command before giving up
+const MAX_REDIRECTS: usize = 5;
+
+// A client for a whole cluster. Keys are hashed to slots locally and each
+// command goes straight to the node serving its slot; Moved redirects update
+// the slot map and trigger a refresh, Ask redirects are followed once.
+// Nodes the cluster marked failed are avoided until their slots move.
+#[derive(Clone)]
+pub struct ClusterClient {
+    seeds: Vec<String>,
+    config: PoolConfig,
+    slots: Arc<RwLock<Vec<String>>>, // owner address of every slot; empty until the first refresh
+    failed: Arc<RwLock<HashSet<String>>>, // nodes marked failed in the last slot map
+    pools: Arc<Mutex<HashMap<String, Pool>>>,
+}
+
+impl ClusterClient {
+    // Connect to a cluster through any of its nodes and load the slot map
+    pub async fn connect(seeds: &[&str], config: PoolConfig) -> Result<Self, ClientError> {
+        let client = ClusterClient {
+            seeds: seeds.iter().map(|seed| seed.to_string()).collect(),
+            config,
+            slots: Arc::new(RwLock::new(Vec::new())),
+            failed: Arc::new(RwLock::new(HashSet::new())),
+            pools: Arc::new(Mutex::new(HashMap::new())),
+        };
+        client.refresh_topology().await?;
+        Ok(client)
+    }
+
+    // Reload the slot map from the first node that answers, trying the known
+    // nodes before the seeds and failed nodes last
+    pub async fn refresh_topology(&self) -> Result<(), ClientError> {
+        let mut candidates = self.nodes();
+        for seed in &self.seeds {
+            if !candidates.contains(seed) {
+                candidates.push(seed.clone());
+            }
+        }
+        let (healthy, failed): (Vec<String>, Vec<String>) = candidates.into_iter().partition(|addr| !self.is_failed(addr));
+        let candidates = healthy.into_iter().chain(failed);
+        let mut last_error = ClientError::Protocol("no cluster nodes to ask for the slot map".to_string());
+        for addr in candidates {
+            let json = match self.pool(&addr).get().await {
+                Ok(mut conn) => conn.cluster_slots().await,
+                Err(e) => Err(e),
+            };
+            match json {
+                Ok(json) => return self.load_slots(&json),
+                // A standalone node serves every slot itself
+                Err(ClientError::Server(error)) if error.message.contains("Clustering is disabled") => {
+                    *self.slots.write().unwrap() = vec![addr; TOTAL_SLOTS];
+                    return Ok(());
+                }
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+
+    fn load_slots(&self, json: &str) -> Result<(), ClientError> {
+        let data: ClusterData = serde_json::from_str(json)
+            .map_err(|e| ClientError::Protocol(format!("invalid slot map: {}", e)))?;
+        let mut slots = vec![String::new(); TOTAL_SLOTS];
+        for node in &data.nodes {
+            let (start, end) = node.slot_range;
+            for slot in slots.iter_mut().take(end.min(TOTAL_SLOTS - 1) + 1).skip(start) {
+                *slot = node.address.clone();
+            }
+        }
+        *self.slots.write().unwrap() = slots;
+        *self.failed.write().unwrap() = data.health.into_iter()
+            .filter(|(_, health)| *health == NodeHealth::Failed)
+            .map(|(addr, _)| addr)
+            .collect();
+        Ok(())
+    }
+
+    // Whether the cluster marked the node failed as of the last refresh
+    pub fn is_failed(&self, addr: &str) -> bool {
+        self.failed.read().unwrap().contains(addr)
+    }
+
+    // Addresses of every node in the slot map
+    pub fn nodes(&self) -> Vec<String> {
+        let mut nodes: Vec<String> = Vec::new();
+        for addr in self.slots.read().unwrap().iter() {
+            if !addr.is_empty() && nodes.last() != Some(addr) && !nodes.contains(addr) {
+                nodes.push(addr.clone());
+            }
+        }
+        nodes
+    }
+
+    // Address of the node serving a key
+    pub fn node_for_key(&self, key: &str) -> Option<String> {
+        let slots = self.slots.read().unwrap();
+        slots.get(key_slot(key)).filter(|addr| !addr.is_empty()).cloned()
+    }
+
+    fn pool(&self, addr: &str) -> Pool {
+        self.pools.lock().unwrap()
+            .entry(addr.to_string())
+            .or_insert_with(|| Pool::new(addr, self.config.clone()))
+            .clone()
+    }
+
+    // Send a command to the node serving its keys, following redirects.
+    // Commands without keys go to any node.
+    pub async fn send(&self, cmd: Command) -> Result<Response, ClientError> {
+        let mut addr = match cmd.keys().first() {
+            Some(key) => self.node_for_key(key),
+            None => self.nodes().into_iter().find(|addr| !self.is_failed(addr)),
+        }
+        .or_else(|| self.seeds.first().cloned())
+        .ok_or_else(|| ClientError::Protocol("no cluster nodes known".to_string()))?;
+        // Fail fast rather than wait out a connect timeout, and look for the
+        // node the slot moved to in the meantime
+        if self.is_failed(&addr) {
+            let client = self.clone();
+            tokio::spawn(async move {
+                let _ = client.refresh_topology().await;
+            });
+            return Err(ClientError::Protocol(format!("{} serving {} is marked failed", addr, cmd.name())));
+        }
+        let mut asking = false;
+
+        for _ in 0..=MAX_REDIRECTS {
+            let mut conn = self.pool(&addr).get().await?;
+            if asking {
+                conn.asking().await?;
+            }
+            match conn.send(cmd.clone()).await? {
+                Response::Moved { slot, address } => {
+                    if let Some(owner) = self.slots.write().unwrap().get_mut(slot) {
+                        *owner = address.clone();
+                    }
+                    // The map is stale; reload it without holding up this command
+                    let client = self.clone();
+                    tokio::spawn(async move {
+                        let _ = client.refresh_topology().await;
+                    });
+                    addr = address;
+                    asking = false;
+                }
+                Response::Ask { address, .. } => {
+                    addr = address;
+                    asking = true;
+                }
+                response => return Ok(response),
+            }
+        }
+        Err(ClientError::Protocol(format!("too many redirects for {}", cmd.name())))
+    }
+
+    // Send a command, turning `Response::Error` into `ClientError::Server`
+    pub async fn query(&self, cmd: Command) -> Result<Response, ClientError> {
+        match self.send(cmd).await? {
+            Response::Error(message) => Err(ClientError::Server(message)),
+            response => Ok(response),
+        }
+    }
+
+    pub async fn set(&self, key: &str, value: impl Into<Vec<u8>>) -> Result<(), ClientError> {
+        match self.query(Command::SET { key: key.to_string(), value: value.into(), visible_at: None }).await? {
+            Response::Success => Ok(()),
+            response => Err(ClientError::UnexpectedResponse(response)),
+        }
+    }
+
+    // Replace a value, returning the one it replaced, or None when the key had none
+    pub async fn getset(&self, key: &str, value: impl Into<Vec<u8>>) -> Result<Option<Bytes>, ClientError> {
+        match self.query(Command::GETSET { key: key.to_string(), value: value.into() }).await? {
+            Response::Data(old) => Ok(Some(old)),
+            Response::Nil => Ok(None),
+            response => Err(ClientError::UnexpectedResponse(response)),
+        }
+    }
+
+    // Set a value that reads miss until `visible_at`, a unix time in milliseconds
+    pub async fn set_visible_at(&self, key: &str, value: impl Into<Vec<u8>>, visible_at: u64) -> Result<(), ClientError> {
+        match self.query(Command::SET { key: key.to_string(), value: value.into(), visible_at: Some(visible_at) }).await? {
+            Response::Success => Ok(()),
+            response => Err(ClientError::UnexpectedResponse(response)),
+        }
+    }
+
+    // Get a value, or None when the key doesn't exist or is cached as missing
+    pub async fn get(&self, key: &str) -> Result<Option<Bytes>, ClientError> {
+        match self.query(Command::GET { key: key.to_string() }).await {
+            Ok(Response::Data(data) | Response::Stale(data)) => Ok(Some(data)),
+            Ok(Response::Missing | Response::Nil) => Ok(None),
+            Ok(response) => Err(ClientError::UnexpectedResponse(response)),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Cache that a key is missing for `milliseconds`
+    pub async fn set_missing(&self, key: &str, milliseconds: u64) -> Result<(), ClientError> {
+        match self.query(Command::SET_MISSING { key: key.to_string(), milliseconds }).await? {
+            Response::Success => Ok(()),
+            response => Err(ClientError::UnexpectedResponse(response)),
+        }
+    }
+
+    // Get a value, or take the lock of the missing key to compute it, waiting
+    // up to `wait_ms` for another client that holds it
+    pub async fn get_or_lock(&self, key: &str, lock_ms: u64, wait_ms: u64) -> Result<Lookup, ClientError> {
+        expect_lookup(self.query(Command::GET_OR_LOCK { key: key.to_string(), lock_ms, wait_ms }).await?)
+    }
+
+    // Release a key's lock without setting it, returning whether it was held
+    pub async fn unlock(&self, key: &str) -> Result<bool, ClientError> {
+        match self.query(Command::UNLOCK { key: key.to_string() }).await? {
+            Response::Integer(released) => Ok(released == 1),
+            response => Err(ClientError::UnexpectedResponse(response)),
+        }
+    }
+
+    // Get a value and whether its soft TTL passed, or None when the key doesn't exist
+    pub async fn get_with_staleness(&self, key: &str) -> Result<Option<(Bytes, bool)>, ClientError> {
+        match self.query(Command::GET { key: key.to_string() }).await {
+            Ok(Response::Data(data)) => Ok(Some((data, false))),
+            Ok(Response::Stale(data)) => Ok(Some((data, true))),
+            Ok(Response::Missing | Response::Nil) => Ok(None),
+            Ok(response) => Err(ClientError::UnexpectedResponse(response)),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Delete keys, returning whether any of them existed. Keys are grouped by
+    // node so each node gets a single DEL.
+    pub async fn del(&self, keys: &[&str]) -> Result<bool, ClientError> {
+        let mut by_node: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for key in keys {
+            let addr = self.node_for_key(key).unwrap_or_default();
+            by_node.entry(addr).or_default().push(key.to_string());
+        }
+        let mut found = false;
+        for keys in by_node.into_values() {
+            match self.query(Command::DEL { keys }).await {
+                Ok(Response::Success) => found = true,
+                Ok(response) => return Err(ClientError::UnexpectedResponse(response)),
+                Err(e) if e.is_key_not_found() => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(found)
+    }
+
+    pub async fn exists(&self, key: &str) -> Result<bool, ClientError> {
+        match self.query(Command::EXISTS { key: key.to_string() }).await? {
+            Response::Exists(exists) => Ok(exists),
+            response => Err(ClientError::UnexpectedResponse(response)),
+        }
+    }
+
+    // Number of the keys that exist, a key listed twice counting twice. Keys
+    // are grouped by node like `del`.
+    pub async fn exists_many(&self, keys: &[&str]) -> Result<i64, ClientError> {
+        let mut by_node: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for key in keys {
+            let addr = self.node_for_key(key).unwrap_or_default();
+            by_node.entry(addr).or_default().push(key.to_string());
+        }
+        let mut count = 0;
+        for keys in by_node.into_values() {
+            match self.query(Command::EXISTS_MANY { keys }).await? {
+                Response::Integer(found) => count += found,
+                response => return Err(ClientError::UnexpectedResponse(response)),
+            }
+        }
+        Ok(count)
+    }
+
+    // Set every key only when none of them exists, returning whether they
+    // were set. The keys must be served by the same node.
+    pub async fn msetnx(&self, entries: &[(&str, &[u8])]) -> Result<bool, ClientError> {
+        let entries = entries.iter().map(|(key, value)| (key.to_string(), Bytes::copy_from_slice(value))).collect();
+        match self.query(Command::MSETNX { entries }).await? {
+            Response::Integer(set) => Ok(set == 1),
+            response => Err(ClientError::UnexpectedResponse(response)),
+        }
+    }
+
+    // Expire a key after `seconds`, returning whether it exists
+    pub async fn expire(&self, key: &str, seconds: u64) -> Result<bool, ClientError> {
+        match self.query(Command::EXPIRE { key: key.to_string(), seconds, jitter_pct: None }).await? {
+            Response::Integer(found) => Ok(found == 1),
+            response => Err(ClientError::UnexpectedResponse(response)),
+        }
+    }
+
+    // Expire a key after `seconds` spread by up to `jitter_pct` percent either
+    // way, returning whether it exists
+    pub async fn expire_with_jitter(&self, key: &str, seconds: u64, jitter_pct: u8) -> Result<bool, ClientError> {
+        match self.query(Command::EXPIRE { key: key.to_string(), seconds, jitter_pct: Some(jitter_pct) }).await? {
+            Response::Integer(found) => Ok(found == 1),
+            response => Err(ClientError::UnexpectedResponse(response)),
+        }
+    }
+
+    // Seconds before a key expires: -1 without an expiry, -2 when missing
+    pub async fn ttl(&self, key: &str) -> Result<i64, ClientError> {
+        match self.query(Command::TTL { key: key.to_string() }).await? {
+            Response::Integer(ttl) => Ok(ttl),
+            response => Err(ClientError::UnexpectedResponse(response)),
+        }
+    }
+
+    // Have GET flag a key stale after `seconds`, returning whether it exists
+    pub async fn soft_expire(&self, key: &str, seconds: u64) -> Result<bool, ClientError> {
+        match self.query(Command::SOFT_EXPIRE { key: key.to_string(), seconds }).await? {
+            Response::Integer(found) => Ok(found == 1),
+            response => Err(ClientError::UnexpectedResponse(response)),
+        }
+    }
+
+    // Seconds before a key goes stale: -1 without a soft TTL, -2 when missing
+    pub async fn soft_ttl(&self, key: &str) -> Result<i64, ClientError> {
+        match self.query(Command::SOFT_TTL { key: key.to_string() }).await? {
+            Response::Integer(ttl) => Ok(ttl),
+            response => Err(ClientError::UnexpectedResponse(response)),
+        }
+    }
+
+    // Expire a key at a unix time in seconds, returning whether it exists
+    pub async fn expire_at(&self, key: &str, timestamp: u64) -> Result<bool, ClientError> {
+        match self.query(Command::EXPIREAT { key: key.to_string(), timestamp }).await? {
+            Response::Integer(found) => Ok(found == 1),
+            response => Err(ClientError::UnexpectedResponse(response)),
+        }
+    }
+
+    // Expire a key after `milliseconds`, returning whether it exists
+    pub async fn pexpire(&self, key: &str, milliseconds: u64) -> Result<bool, ClientError> {
+        match self.query(Command::PEXPIRE { key: key.to_string(), milliseconds, jitter_pct: None }).await? {
+            Response::Integer(found) => Ok(found == 1),
+            response => Err(ClientError::UnexpectedResponse(response)),
+        }
+    }
+
+    // Expire a key at a unix time in milliseconds, returning whether it exists
+    pub async fn pexpire_at(&self, key: &str, timestamp_ms: u64) -> Result<bool, ClientError> {
+        match self.query(Command::PEXPIREAT { key: key.to_string(), timestamp_ms }).await? {
+            Response::Integer(found) => Ok(found == 1),
+            response => Err(ClientError::UnexpectedResponse(response)),
+        }
+    }
+
+    // Milliseconds before a key expires: -1 without an expiry, -2 when missing
+    pub async fn pttl(&self, key: &str) -> Result<i64, ClientError> {
+        match self.query(Command::PTTL { key: key.to_string() }).await? {
+            Response::Integer(ttl) => Ok(ttl),
+            response => Err(ClientError::UnexpectedResponse(response)),
+        }
+    }
+
+    // Drop a key's expiry, returning whether it had one
+    pub async fn persist(&self, key: &str) -> Result<bool, ClientError> {
+        match self.query(Command::PERSIST { key: key.to_string() }).await? {
+            Response::Integer(found) => Ok(found == 1),
+            response => Err(ClientError::UnexpectedResponse(response)),
+        }
+    }
+
+    // Serialize a key for RESTORE, or None when the key doesn't exist
+    pub async fn dump(&self, key: &str) -> Result<Option<Bytes>, ClientError> {
+        match self.query(Command::DUMP { key: key.to_string() }).await {
+            Ok(Response::Data(payload)) => Ok(Some(payload)),
+            Ok(response) => Err(ClientError::UnexpectedResponse(response)),
+            Err(e) if e.is_key_not_found() => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Re-create a key from a DUMP payload
+    pub async fn restore(&self, key: &str, payload: impl Into<Vec<u8>>, replace: bool) -> Result<(), ClientError> {
+        match self.query(Command::RESTORE { key: key.to_string(), payload: payload.into(), replace }).await? {
+            Response::Success => Ok(()),
+            response => Err(ClientError::UnexpectedResponse(response)),
+        }
+    }
+
+    // Move a key from the node serving its slot to the node at `address`
+    pub async fn migrate(&self, key: &str, address: &str, copy: bool, replace: bool) -> Result<(), ClientError> {
+        let cmd = Command::MIGRATE { key: key.to_string(), address: address.to_string(), copy, replace };
+        match self.query(cmd).await? {
+            Response::Success => Ok(()),
+            response => Err(ClientError::UnexpectedResponse(response)),
+        }
+    }
+
+    // Bytes a key's node accounts to it, or None when it doesn't exist
+    pub async fn memory_usage(&self, key: &str) -> Result<Option<i64>, ClientError> {
+        match self.query(Command::MEMORY_USAGE { key: key.to_string() }).await {
+            Ok(Response::Integer(bytes)) => Ok(Some(bytes)),
+            Ok(response) => Err(ClientError::UnexpectedResponse(response)),
+            Err(e) if e.is_key_not_found() => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Seconds since a key was last read or written, or None when it doesn't exist
+    pub async fn object_idletime(&self, key: &str) -> Result<Option<i64>, ClientError> {
+        match self.query(Command::OBJECT_IDLETIME { key: key.to_string() }).await {
+            Ok(Response::Integer(seconds)) => Ok(Some(seconds)),
+            Ok(response) => Err(ClientError::UnexpectedResponse(response)),
+            Err(e) if e.is_key_not_found() => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    // A key's access counter, or None when it doesn't exist
+    pub async fn object_freq(&self, key: &str) -> Result<Option<i64>, ClientError> {
+        match self.query(Command::OBJECT_FREQ { key: key.to_string() }).await {
+            Ok(Response::Integer(counter)) => Ok(Some(counter)),
+            Ok(response) => Err(ClientError::UnexpectedResponse(response)),
+            Err(e) if e.is_key_not_found() => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    // A key's bookkeeping, or None when it doesn't exist
+    pub async fn debug_object(&self, key: &str) -> Result<Option<ObjectInfo>, ClientError> {
+        match self.query(Command::DEBUG_OBJECT { key: key.to_string() }).await {
+            Ok(Response::Object(info)) => Ok(Some(info)),
+            Ok(response) => Err(ClientError::UnexpectedResponse(response)),
+            Err(e) if e.is_key_not_found() => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    // A connection to one node, for commands that aren't about keys
+    pub async fn node(&self, addr: &str) -> Result<Connection, ClientError> {
+        let mut conn = Connection::connect(addr).await?;
+        if let Some(password) = &self.config.password {
+            conn.auth(password).await?;
+        }
+        Ok(conn)
+    }
+}
@@ -0,0 +1,806 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::time::Duration;
+use bytes::{Buf, Bytes, BytesMut};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use pluto_core::codec::{encode_command, parse_response, Encoding};
+use pluto_core::protocol::{Command, CommandDoc, CommandInfo, KeyEvent, ObjectInfo, ResetMode, Response, ShutdownMode, SlotState};
+use crate::error::ClientError;
+use crate::pipeline::Pipeline;
+
+// Initial size of a connection's read buffer
+const READ_BUFFER_SIZE: usize = 16 * 1024;
+
+// A single connection to a server, speaking the native protocol
+pub struct Connection {
+    stream: TcpStream,
+    addr: String,
+    buf: BytesMut,
+    encoding: Encoding,
+    broken: bool, // an IO or framing error left the stream in an unknown state
+    invalidations: VecDeque<Vec<String>>, // pushed by CLIENT_TRACKING and not taken yet
+}
+
+impl Connection {
+    // Connect using JSON, the encoding every connection starts with
+    pub async fn connect(addr: &str) -> Result<Self, ClientError> {
+        let stream = TcpStream::connect(addr).await?;
+        stream.set_nodelay(true)?;
+        Ok(Connection {
+            stream,
+            addr: addr.to_string(),
+            buf: BytesMut::with_capacity(READ_BUFFER_SIZE),
+            encoding: Encoding::Json,
+            broken: false,
+            invalidations: VecDeque::new(),
+        })
+    }
+
+    // Connect and switch to `encoding` with a HELLO handshake
+    pub async fn connect_with(addr: &str, encoding: Encoding) -> Result<Self, ClientError> {
+        let mut conn = Connection::connect(addr).await?;
+        if encoding != Encoding::Json {
+            conn.hello(None, Some(encoding)).await?;
+        }
+        Ok(conn)
+    }
+
+    pub fn addr(&self) -> &str {
+        &self.addr
+    }
+
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    // Whether the connection can no longer be used
+    pub fn is_broken(&self) -> bool {
+        self.broken
+    }
+
+    // Send one command and return the server's response as is
+    pub async fn send(&mut self, cmd: Command) -> Result<Response, ClientError> {
+        if cmd.switches_encoding() {
+            return Err(ClientError::Protocol("use hello or set_encoding to switch encodings".to_string()));
+        }
+        self.write_commands(std::slice::from_ref(&cmd)).await?;
+        self.read_response().await
+    }
+
+    // Send one command, turning `Response::Error` into `ClientError::Server`
+    pub async fn query(&mut self, cmd: Command) -> Result<Response, ClientError> {
+        match self.send(cmd).await? {
+            Response::Error(message) => Err(ClientError::Server(message)),
+            response => Ok(response),
+        }
+    }
+
+    // Write every command in the pipeline before reading any response, so
+    // the whole batch costs a single round trip. Responses come back in
+    // command order; errors stay in place as `Response::Error`.
+    pub async fn execute(&mut self, pipeline: Pipeline) -> Result<Vec<Response>, ClientError> {
+        let commands = pipeline.into_commands();
+        if commands.iter().any(Command::switches_encoding) {
+            return Err(ClientError::Protocol("pipelines can't switch encodings".to_string()));
+        }
+        self.write_commands(&commands).await?;
+        let mut responses = Vec::with_capacity(commands.len());
+        for _ in 0..commands.len() {
+            responses.push(self.read_response().await?);
+        }
+        Ok(responses)
+    }
+
+    async fn write_commands(&mut self, commands: &[Command]) -> Result<(), ClientError> {
+        let mut out = Vec::new();
+        for cmd in commands {
+            let body = encode_command(cmd, self.encoding)
+                .map_err(|e| ClientError::Protocol(e.to_string()))?;
+            if self.encoding != Encoding::Json {
+                out.extend_from_slice(&(body.len() as u32).to_be_bytes());
+            }
+            out.extend_from_slice(&body);
+        }
+        if let Err(e) = self.stream.write_all(&out).await {
+            self.broken = true;
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    // Read the next response, keeping invalidations pushed in between for
+    // take_invalidations and joining a reply streamed in chunks back together
+    async fn read_response(&mut self) -> Result<Response, ClientError> {
+        let mut leading = Vec::new();
+        loop {
+            match self.read_part().await? {
+                Response::KeysChunk(keys) => leading.extend(keys),
+                response => return Ok(response.with_leading_keys(leading)),
+            }
+        }
+    }
+
+    // Read the next frame of a response, which may be one chunk of it
+    async fn read_part(&mut self) -> Result<Response, ClientError> {
+        loop {
+            match self.read_frame().await? {
+                Response::Invalidate { keys } => self.invalidations.push_back(keys),
+                response => return Ok(response),
+            }
+        }
+    }
+
+    async fn read_frame(&mut self) -> Result<Response, ClientError> {
+        loop {
+            match parse_response(&self.buf, self.encoding) {
+                Ok(Some((response, used))) => {
+                    self.buf.advance(used);
+                    return Ok(response);
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    self.broken = true;
+                    return Err(ClientError::Protocol(e));
+                }
+            }
+            self.buf.reserve(READ_BUFFER_SIZE);
+            match self.stream.read_buf(&mut self.buf).await {
+                Ok(0) => {
+                    self.broken = true;
+                    return Err(ClientError::ConnectionClosed);
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    self.broken = true;
+                    return Err(e.into());
+                }
+            }
+        }
+    }
+
+    // Send a command that switches the encoding once its response is read
+    async fn switch_encoding(&mut self, cmd: Command, encoding: Encoding) -> Result<Response, ClientError> {
+        self.write_commands(std::slice::from_ref(&cmd)).await?;
+        match self.read_response().await? {
+            Response::Error(message) => Err(ClientError::Server(message)),
+            response => {
+                self.encoding = encoding;
+                Ok(response)
+            }
+        }
+    }
+
+    pub async fn set(&mut self, key: &str, value: impl Into<Vec<u8>>) -> Result<(), ClientError> {
+        let cmd = Command::SET { key: key.to_string(), value: value.into(), visible_at: None };
+        expect_success(self.query(cmd).await?)
+    }
+
+    // Replace a value, returning the one it replaced, or None when the key had none
+    pub async fn getset(&mut self, key: &str, value: impl Into<Vec<u8>>) -> Result<Option<Bytes>, ClientError> {
+        match self.query(Command::GETSET { key: key.to_string(), value: value.into() }).await? {
+            Response::Data(old) => Ok(Some(old)),
+            Response::Nil => Ok(None),
+            Response::Error(message) => Err(ClientError::Server(message)),
+            response => Err(ClientError::UnexpectedResponse(response)),
+        }
+    }
+
+    // Set a value that reads miss until `visible_at`, a unix time in milliseconds
+    pub async fn set_visible_at(&mut self, key: &str, value: impl Into<Vec<u8>>, visible_at: u64) -> Result<(), ClientError> {
+        let cmd = Command::SET { key: key.to_string(), value: value.into(), visible_at: Some(visible_at) };
+        expect_success(self.query(cmd).await?)
+    }
+
+    // Get a value, or None when the key doesn't exist or is cached as missing
+    pub async fn get(&mut self, key: &str) -> Result<Option<Bytes>, ClientError> {
+        match self.query(Command::GET { key: key.to_string() }).await {
+            Ok(Response::Data(data) | Response::Stale(data)) => Ok(Some(data)),
+            Ok(Response::Missing | Response::Nil) => Ok(None),
+            Ok(response) => Err(ClientError::UnexpectedResponse(response)),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Get a value, or take the lock of the missing key to compute it, waiting
+    // up to `wait_ms` for another client that holds it
+    pub async fn get_or_lock(&mut self, key: &str, lock_ms: u64, wait_ms: u64) -> Result<Lookup, ClientError> {
+        expect_lookup(self.query(Command::GET_OR_LOCK { key: key.to_string(), lock_ms, wait_ms }).await?)
+    }
+
+    // Cache that a key is missing for `milliseconds`, so lookups of it stop
+    // short of whatever it would be computed from
+    pub async fn set_missing(&mut self, key: &str, milliseconds: u64) -> Result<(), ClientError> {
+        expect_success(self.query(Command::SET_MISSING { key: key.to_string(), milliseconds }).await?)
+    }
+
+    // Release a key's lock without setting it, returning whether it was held
+    pub async fn unlock(&mut self, key: &str) -> Result<bool, ClientError> {
+        Ok(expect_integer(self.query(Command::UNLOCK { key: key.to_string() }).await?)? == 1)
+    }
+
+    // Get a value and whether its soft TTL passed, or None when the key doesn't exist
+    pub async fn get_with_staleness(&mut self, key: &str) -> Result<Option<(Bytes, bool)>, ClientError> {
+        match self.query(Command::GET { key: key.to_string() }).await {
+            Ok(Response::Data(data)) => Ok(Some((data, false))),
+            Ok(Response::Stale(data)) => Ok(Some((data, true))),
+            Ok(Response::Missing | Response::Nil) => Ok(None),
+            Ok(response) => Err(ClientError::UnexpectedResponse(response)),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Delete keys, returning whether any of them existed
+    pub async fn del(&mut self, keys: &[&str]) -> Result<bool, ClientError> {
+        let keys = keys.iter().map(|key| key.to_string()).collect();
+        match self.query(Command::DEL { keys }).await {
+            Ok(Response::Success) => Ok(true),
+            Ok(response) => Err(ClientError::UnexpectedResponse(response)),
+            Err(e) if e.is_key_not_found() => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Delete keys, freeing their memory in the background, and return how
+    // many existed
+    pub async fn unlink(&mut self, keys: &[&str]) -> Result<i64, ClientError> {
+        let keys = keys.iter().map(|key| key.to_string()).collect();
+        expect_integer(self.query(Command::UNLINK { keys }).await?)
+    }
+
+    pub async fn exists(&mut self, key: &str) -> Result<bool, ClientError> {
+        match self.query(Command::EXISTS { key: key.to_string() }).await? {
+            Response::Exists(exists) => Ok(exists),
+            response => Err(ClientError::UnexpectedResponse(response)),
+        }
+    }
+
+    // Number of the keys that exist, a key listed twice counting twice
+    pub async fn exists_many(&mut self, keys: &[&str]) -> Result<i64, ClientError> {
+        let keys = keys.iter().map(|key| key.to_string()).collect();
+        expect_integer(self.query(Command::EXISTS_MANY { keys }).await?)
+    }
+
+    // Set every key only when none of them exists, returning whether they were set
+    pub async fn msetnx(&mut self, entries: &[(&str, &[u8])]) -> Result<bool, ClientError> {
+        let entries = entries.iter().map(|(key, value)| (key.to_string(), Bytes::copy_from_slice(value))).collect();
+        Ok(expect_integer(self.query(Command::MSETNX { entries }).await?)? == 1)
+    }
+
+    // Expire a key after `seconds`, returning whether it exists
+    pub async fn expire(&mut self, key: &str, seconds: u64) -> Result<bool, ClientError> {
+        Ok(expect_integer(self.query(Command::EXPIRE { key: key.to_string(), seconds, jitter_pct: None }).await?)? == 1)
+    }
+
+    // Expire a key after `seconds` spread by up to `jitter_pct` percent either
+    // way, returning whether it exists
+    pub async fn expire_with_jitter(&mut self, key: &str, seconds: u64, jitter_pct: u8) -> Result<bool, ClientError> {
+        let cmd = Command::EXPIRE { key: key.to_string(), seconds, jitter_pct: Some(jitter_pct) };
+        Ok(expect_integer(self.query(cmd).await?)? == 1)
+    }
+
+    // Seconds before a key expires: -1 without an expiry, -2 when missing
+    pub async fn ttl(&mut self, key: &str) -> Result<i64, ClientError> {
+        expect_integer(self.query(Command::TTL { key: key.to_string() }).await?)
+    }
+
+    // Have GET flag a key stale after `seconds`, returning whether it exists
+    pub async fn soft_expire(&mut self, key: &str, seconds: u64) -> Result<bool, ClientError> {
+        Ok(expect_integer(self.query(Command::SOFT_EXPIRE { key: key.to_string(), seconds }).await?)? == 1)
+    }
+
+    // Seconds before a key goes stale: -1 without a soft TTL, -2 when missing
+    pub async fn soft_ttl(&mut self, key: &str) -> Result<i64, ClientError> {
+        expect_integer(self.query(Command::SOFT_TTL { key: key.to_string() }).await?)
+    }
+
+    // Expire a key at a unix time in seconds, returning whether it exists
+    pub async fn expire_at(&mut self, key: &str, timestamp: u64) -> Result<bool, ClientError> {
+        Ok(expect_integer(self.query(Command::EXPIREAT { key: key.to_string(), timestamp }).await?)? == 1)
+    }
+
+    // Expire a key after `milliseconds`, returning whether it exists
+    pub async fn pexpire(&mut self, key: &str, milliseconds: u64) -> Result<bool, ClientError> {
+        Ok(expect_integer(self.query(Command::PEXPIRE { key: key.to_string(), milliseconds, jitter_pct: None }).await?)? == 1)
+    }
+
+    // Expire a key at a unix time in milliseconds, returning whether it exists
+    pub async fn pexpire_at(&mut self, key: &str, timestamp_ms: u64) -> Result<bool, ClientError> {
+        Ok(expect_integer(self.query(Command::PEXPIREAT { key: key.to_string(), timestamp_ms }).await?)? == 1)
+    }
+
+    // Milliseconds before a key expires: -1 without an expiry, -2 when missing
+    pub async fn pttl(&mut self, key: &str) -> Result<i64, ClientError> {
+        expect_integer(self.query(Command::PTTL { key: key.to_string() }).await?)
+    }
+
+    // Drop a key's expiry, returning whether it had one
+    pub async fn persist(&mut self, key: &str) -> Result<bool, ClientError> {
+        Ok(expect_integer(self.query(Command::PERSIST { key: key.to_string() }).await?)? == 1)
+    }
+
+    // Serialize a key for RESTORE, or None when the key doesn't exist
+    pub async fn dump(&mut self, key: &str) -> Result<Option<Bytes>, ClientError> {
+        match self.query(Command::DUMP { key: key.to_string() }).await {
+            Ok(Response::Data(payload)) => Ok(Some(payload)),
+            Ok(response) => Err(ClientError::UnexpectedResponse(response)),
+            Err(e) if e.is_key_not_found() => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Re-create a key from a DUMP payload
+    pub async fn restore(&mut self, key: &str, payload: impl Into<Vec<u8>>, replace: bool) -> Result<(), ClientError> {
+        let cmd = Command::RESTORE { key: key.to_string(), payload: payload.into(), replace };
+        expect_success(self.query(cmd).await?)
+    }
+
+    // Move a key to the node at `address`, keeping the local copy when `copy` is set
+    pub async fn migrate(&mut self, key: &str, address: &str, copy: bool, replace: bool) -> Result<(), ClientError> {
+        let cmd = Command::MIGRATE { key: key.to_string(), address: address.to_string(), copy, replace };
+        expect_success(self.query(cmd).await?)
+    }
+
+    pub async fn ping(&mut self) -> Result<(), ClientError> {
+        match self.query(Command::PING { message: None }).await? {
+            Response::Pong => Ok(()),
+            response => Err(ClientError::UnexpectedResponse(response)),
+        }
+    }
+
+    pub async fn echo(&mut self, message: impl Into<Vec<u8>>) -> Result<Bytes, ClientError> {
+        expect_data(self.query(Command::ECHO { message: message.into() }).await?)
+    }
+
+    pub async fn info(&mut self, section: Option<&str>) -> Result<String, ClientError> {
+        match self.query(Command::INFO { section: section.map(str::to_string) }).await? {
+            Response::Info(info) => Ok(info),
+            response => Err(ClientError::UnexpectedResponse(response)),
+        }
+    }
+
+    pub async fn config_get(&mut self, key: &str) -> Result<BTreeMap<String, String>, ClientError> {
+        match self.query(Command::CONFIG_GET { key: key.to_string() }).await? {
+            Response::Config(values) => Ok(values),
+            response => Err(ClientError::UnexpectedResponse(response)),
+        }
+    }
+
+    // The server's version and build, as `key:value` lines
+    pub async fn version(&mut self) -> Result<String, ClientError> {
+        match self.query(Command::VERSION).await? {
+            Response::Info(version) => Ok(version),
+            response => Err(ClientError::UnexpectedResponse(response)),
+        }
+    }
+
+    // Whether snapshots, the write-ahead log and backups are succeeding, as
+    // `key:value` lines
+    pub async fn persistence_status(&mut self) -> Result<String, ClientError> {
+        match self.query(Command::PERSISTENCE_STATUS).await? {
+            Response::Info(status) => Ok(status),
+            response => Err(ClientError::UnexpectedResponse(response)),
+        }
+    }
+
+    // Every command the server takes, with its arity and flags
+    pub async fn command(&mut self) -> Result<Vec<CommandInfo>, ClientError> {
+        match self.query(Command::COMMAND).await? {
+            Response::Commands(commands) => Ok(commands),
+            response => Err(ClientError::UnexpectedResponse(response)),
+        }
+    }
+
+    // Usage and summary of the named commands, of every command when none is named
+    pub async fn command_docs(&mut self, names: &[&str]) -> Result<Vec<CommandDoc>, ClientError> {
+        let names = names.iter().map(|name| name.to_string()).collect();
+        match self.query(Command::COMMAND_DOCS { names }).await? {
+            Response::CommandDocs(docs) => Ok(docs),
+            response => Err(ClientError::UnexpectedResponse(response)),
+        }
+    }
+
+    // Publish a message, returning the number of subscribers that received it
+    pub async fn publish(&mut self, channel: &str, message: impl Into<Vec<u8>>) -> Result<i64, ClientError> {
+        let cmd = Command::PUBLISH { channel: channel.to_string(), message: message.into() };
+        expect_integer(self.query(cmd).await?)
+    }
+
+    pub async fn auth(&mut self, password: &str) -> Result<(), ClientError> {
+        expect_success(self.query(Command::AUTH { username: None, password: password.to_string() }).await?)
+    }
+
+    // Authenticate as one of the users the server is configured with
+    pub async fn auth_user(&mut self, username: &str, password: &str) -> Result<(), ClientError> {
+        let cmd = Command::AUTH { username: Some(username.to_string()), password: password.to_string() };
+        expect_success(self.query(cmd).await?)
+    }
+
+    // Handshake, optionally requiring a protocol version and switching encodings
+    pub async fn hello(&mut self, protocol: Option<u32>, encoding: Option<Encoding>) -> Result<Response, ClientError> {
+        let cmd = Command::HELLO { protocol, encoding };
+        match encoding {
+            Some(encoding) => self.switch_encoding(cmd, encoding).await,
+            None => self.query(cmd).await,
+        }
+    }
+
+    pub async fn set_encoding(&mut self, encoding: Encoding) -> Result<(), ClientError> {
+        let response = self.switch_encoding(Command::ENCODING { name: encoding }, encoding).await?;
+        expect_success(response)
+    }
+
+    // Bytes the server accounts to a key, or None when it doesn't exist
+    pub async fn memory_usage(&mut self, key: &str) -> Result<Option<i64>, ClientError> {
+        match self.query(Command::MEMORY_USAGE { key: key.to_string() }).await {
+            Ok(Response::Integer(bytes)) => Ok(Some(bytes)),
+            Ok(response) => Err(ClientError::UnexpectedResponse(response)),
+            Err(e) if e.is_key_not_found() => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Seconds since a key was last read or written, or None when it doesn't exist
+    pub async fn object_idletime(&mut self, key: &str) -> Result<Option<i64>, ClientError> {
+        match self.query(Command::OBJECT_IDLETIME { key: key.to_string() }).await {
+            Ok(Response::Integer(seconds)) => Ok(Some(seconds)),
+            Ok(response) => Err(ClientError::UnexpectedResponse(response)),
+            Err(e) if e.is_key_not_found() => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    // A key's access counter, or None when it doesn't exist
+    pub async fn object_freq(&mut self, key: &str) -> Result<Option<i64>, ClientError> {
+        match self.query(Command::OBJECT_FREQ { key: key.to_string() }).await {
+            Ok(Response::Integer(counter)) => Ok(Some(counter)),
+            Ok(response) => Err(ClientError::UnexpectedResponse(response)),
+            Err(e) if e.is_key_not_found() => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    // A key's bookkeeping, or None when it doesn't exist
+    pub async fn debug_object(&mut self, key: &str) -> Result<Option<ObjectInfo>, ClientError> {
+        match self.query(Command::DEBUG_OBJECT { key: key.to_string() }).await {
+            Ok(Response::Object(info)) => Ok(Some(info)),
+            Ok(response) => Err(ClientError::UnexpectedResponse(response)),
+            Err(e) if e.is_key_not_found() => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub async fn memory_defrag(&mut self) -> Result<(), ClientError> {
+        expect_success(self.query(Command::MEMORY_DEFRAG).await?)
+    }
+
+    // Cluster topology as the JSON document the server reports
+    pub async fn cluster_slots(&mut self) -> Result<String, ClientError> {
+        match self.query(Command::CLUSTER_SLOTS).await? {
+            Response::Slots(slots) => Ok(slots),
+            response => Err(ClientError::UnexpectedResponse(response)),
+        }
+    }
+
+    // Node id and address of the server
+    pub async fn node_info(&mut self) -> Result<(String, String), ClientError> {
+        match self.query(Command::NODE_INFO).await? {
+            Response::NodeInfo { node_id, address, .. } => Ok((node_id, address)),
+            response => Err(ClientError::UnexpectedResponse(response)),
+        }
+    }
+
+    pub async fn cluster_join(&mut self, address: &str) -> Result<(), ClientError> {
+        expect_success(self.query(Command::CLUSTER_JOIN { address: address.to_string() }).await?)
+    }
+
+    pub async fn cluster_remove(&mut self, address: &str) -> Result<(), ClientError> {
+        expect_success(self.query(Command::CLUSTER_REMOVE { address: address.to_string() }).await?)
+    }
+
+    pub async fn cluster_isolate(&mut self) -> Result<(), ClientError> {
+        expect_success(self.query(Command::CLUSTER_ISOLATE).await?)
+    }
+
+    // Forget the cluster this node is in; HARD also drops every key and the node id
+    pub async fn cluster_reset(&mut self, mode: Option<ResetMode>) -> Result<(), ClientError> {
+        expect_success(self.query(Command::CLUSTER_RESET { mode }).await?)
+    }
+
+    // Start moving the keys of `slots` to the node at `address` in the background
+    pub async fn migrate_slots(&mut self, slots: Vec<usize>, address: &str) -> Result<(), ClientError> {
+        expect_success(self.query(Command::MIGRATE_SLOTS { slots, address: address.to_string() }).await?)
+    }
+
+    // Assign or unassign slots, or mark them as migrating or importing on this node
+    pub async fn cluster_setslot(&mut self, slots: Vec<usize>, state: SlotState) -> Result<(), ClientError> {
+        expect_success(self.query(Command::CLUSTER_SETSLOT { slots, state }).await?)
+    }
+
+    pub async fn migrate_slots_abort(&mut self) -> Result<(), ClientError> {
+        expect_success(self.query(Command::MIGRATE_SLOTS_ABORT).await?)
+    }
+
+    // Make a geo-replication standby cluster writable, for failing over to it
+    pub async fn geo_promote(&mut self) -> Result<(), ClientError> {
+        expect_success(self.query(Command::GEO_PROMOTE { local: false }).await?)
+    }
+
+    // Number of keys on the server
+    // Add `delta` to a counter, returning its new value
+    pub async fn counter_incrby(&mut self, key: &str, delta: i64) -> Result<i64, ClientError> {
+        expect_integer(self.query(Command::COUNTER_INCRBY { key: key.to_string(), delta }).await?)
+    }
+
+    pub async fn counter_get(&mut self, key: &str) -> Result<i64, ClientError> {
+        expect_integer(self.query(Command::COUNTER_GET { key: key.to_string() }).await?)
+    }
+
+    // Add members to a set, returning how many were new
+    pub async fn orset_add(&mut self, key: &str, members: &[&str]) -> Result<i64, ClientError> {
+        let members = members.iter().map(|member| member.to_string()).collect();
+        expect_integer(self.query(Command::ORSET_ADD { key: key.to_string(), members }).await?)
+    }
+
+    // Remove members from a set, returning how many were in it
+    pub async fn orset_rem(&mut self, key: &str, members: &[&str]) -> Result<i64, ClientError> {
+        let members = members.iter().map(|member| member.to_string()).collect();
+        expect_integer(self.query(Command::ORSET_REM { key: key.to_string(), members }).await?)
+    }
+
+    pub async fn orset_members(&mut self, key: &str) -> Result<Vec<String>, ClientError> {
+        match self.query(Command::ORSET_MEMBERS { key: key.to_string() }).await? {
+            Response::Members(members) => Ok(members),
+            Response::Error(message) => Err(ClientError::Server(message)),
+            response => Err(ClientError::UnexpectedResponse(response)),
+        }
+    }
+
+    pub async fn dbsize(&mut self) -> Result<i64, ClientError> {
+        expect_integer(self.query(Command::DBSIZE).await?)
+    }
+
+    pub async fn flushall(&mut self) -> Result<(), ClientError> {
+        expect_success(self.query(Command::FLUSHALL).await?)
+    }
+
+    // Keys on the server matching a glob pattern
+    pub async fn keys(&mut self, pattern: &str) -> Result<Vec<String>, ClientError> {
+        match self.query(Command::KEYS { pattern: pattern.to_string(), chunk: None }).await? {
+            Response::Keys(keys) => Ok(keys),
+            Response::Error(message) => Err(ClientError::Server(message)),
+            response => Err(ClientError::UnexpectedResponse(response)),
+        }
+    }
+
+    // Keys matching a glob pattern, handed to `each` in chunks of at most
+    // `chunk` keys as the server streams them, so the whole list is never
+    // held at once
+    pub async fn keys_chunked(&mut self, pattern: &str, chunk: usize, mut each: impl FnMut(Vec<String>)) -> Result<(), ClientError> {
+        let cmd = Command::KEYS { pattern: pattern.to_string(), chunk: Some(chunk) };
+        self.write_commands(std::slice::from_ref(&cmd)).await?;
+        loop {
+            match self.read_part().await? {
+                Response::KeysChunk(keys) => each(keys),
+                Response::Keys(keys) => {
+                    each(keys);
+                    return Ok(());
+                }
+                Response::Error(message) => return Err(ClientError::Server(message)),
+                response => return Err(ClientError::UnexpectedResponse(response)),
+            }
+        }
+    }
+
+    // Run a CLUSTER_DBSIZE, CLUSTER_FLUSHALL, CLUSTER_INFO or CLUSTER_KEYS,
+    // returning the combined result and the error of every member that failed
+    pub async fn cluster_wide(&mut self, cmd: Command) -> Result<(Response, BTreeMap<String, String>), ClientError> {
+        match self.query(cmd).await? {
+            Response::Aggregate { result, failures } => Ok((*result, failures)),
+            Response::Error(message) => Err(ClientError::Server(message)),
+            response => Err(ClientError::UnexpectedResponse(response)),
+        }
+    }
+
+    // Allow the next command on a slot this node is importing
+    pub async fn asking(&mut self) -> Result<(), ClientError> {
+        expect_success(self.query(Command::ASKING).await?)
+    }
+
+    // Wait for `replicas` replicas to acknowledge the writes made so far,
+    // returning how many did before the timeout
+    pub async fn wait(&mut self, replicas: usize, timeout: Duration) -> Result<i64, ClientError> {
+        expect_integer(self.query(Command::WAIT { replicas, timeout: timeout.as_millis() as u64 }).await?)
+    }
+
+    // Run a write that is acknowledged only once `replicas` replicas applied it
+    pub async fn with_quorum(&mut self, replicas: usize, cmd: Command) -> Result<Response, ClientError> {
+        self.query(Command::QUORUM { replicas, command: Box::new(cmd) }).await
+    }
+
+    // Read the newest copy of a key once `replicas` replicas answered with
+    // theirs; the primary repairs the replicas holding an older one
+    pub async fn quorum_get(&mut self, replicas: usize, key: &str) -> Result<Option<Bytes>, ClientError> {
+        match self.with_quorum(replicas, Command::GET { key: key.to_string() }).await {
+            Ok(Response::Data(data) | Response::Stale(data)) => Ok(Some(data)),
+            Ok(Response::Missing | Response::Nil) => Ok(None),
+            Ok(response) => Err(ClientError::UnexpectedResponse(response)),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Serve reads on this connection from the replica it is connected to
+    pub async fn readonly(&mut self) -> Result<(), ClientError> {
+        expect_success(self.query(Command::READONLY).await?)
+    }
+
+    pub async fn readwrite(&mut self) -> Result<(), ClientError> {
+        expect_success(self.query(Command::READWRITE).await?)
+    }
+
+    pub async fn shutdown(&mut self, mode: Option<ShutdownMode>) -> Result<(), ClientError> {
+        expect_success(self.query(Command::SHUTDOWN { mode }).await?)
+    }
+
+    // Have the server push invalidations of the keys this connection reads
+    // when they change, for a cache kept on the client's side
+    pub async fn client_tracking(&mut self, on: bool) -> Result<(), ClientError> {
+        expect_success(self.query(Command::CLIENT_TRACKING { on }).await?)
+    }
+
+    // Invalidations that arrived along with responses, oldest first. No keys
+    // means every key changed.
+    pub fn take_invalidations(&mut self) -> Vec<Vec<String>> {
+        self.invalidations.drain(..).collect()
+    }
+
+    // Wait for the next invalidation, on a connection not waiting for responses
+    pub async fn next_invalidation(&mut self) -> Result<Vec<String>, ClientError> {
+        if let Some(keys) = self.invalidations.pop_front() {
+            return Ok(keys);
+        }
+        match self.read_frame().await? {
+            Response::Invalidate { keys } => Ok(keys),
+            Response::Error(message) => Err(ClientError::Server(message)),
+            response => Err(ClientError::UnexpectedResponse(response)),
+        }
+    }
+
+    // Turn the connection into a subscription on `channels`
+    pub async fn subscribe(mut self, channels: &[&str]) -> Result<Subscription, ClientError> {
+        let channels = channels.iter().map(|channel| channel.to_string()).collect();
+        let count = expect_integer(self.query(Command::SUBSCRIBE { channels }).await?)?;
+        Ok(Subscription { conn: self, pending: VecDeque::new(), count })
+    }
+
+    // Turn the connection into a stream of the expiries and evictions of
+    // keys matching `patterns` on this node
+    pub async fn watch_keys(mut self, patterns: &[&str]) -> Result<KeyEvents, ClientError> {
+        let patterns = patterns.iter().map(|pattern| pattern.to_string()).collect();
+        let count = expect_integer(self.query(Command::KEYEVENTS_SUBSCRIBE { patterns }).await?)?;
+        Ok(KeyEvents { conn: self, count })
+    }
+}
+
+// What `get_or_lock` found
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Lookup {
+    Value(Bytes),
+    Stale(Bytes), // past its soft TTL
+    Missing, // cached as missing
+    Locked, // missing, and this client is to compute it
+    InProgress, // missing, and another client is computing it
+}
+
+// A connection in subscriber mode, receiving published messages
+pub struct Subscription {
+    conn: Connection,
+    pending: VecDeque<(String, Bytes)>, // messages read while waiting for a reply
+    count: i64,                          // channels subscribed to
+}
+
+impl Subscription {
+    // Number of channels currently subscribed to
+    pub fn channel_count(&self) -> i64 {
+        self.count
+    }
+
+    // Wait for the next published message
+    pub async fn next_message(&mut self) -> Result<(String, Bytes), ClientError> {
+        if let Some(message) = self.pending.pop_front() {
+            return Ok(message);
+        }
+        match self.conn.read_response().await? {
+            Response::Message { channel, message } => Ok((channel, message)),
+            Response::Error(message) => Err(ClientError::Server(message)),
+            response => Err(ClientError::UnexpectedResponse(response)),
+        }
+    }
+
+    pub async fn subscribe(&mut self, channels: &[&str]) -> Result<i64, ClientError> {
+        let channels = channels.iter().map(|channel| channel.to_string()).collect();
+        self.command(Command::SUBSCRIBE { channels }).await
+    }
+
+    // Unsubscribe from `channels`, or from every channel when empty
+    pub async fn unsubscribe(&mut self, channels: &[&str]) -> Result<i64, ClientError> {
+        let channels = channels.iter().map(|channel| channel.to_string()).collect();
+        self.command(Command::UNSUBSCRIBE { channels }).await
+    }
+
+    // Leave subscriber mode, returning the plain connection
+    pub async fn into_connection(mut self) -> Result<Connection, ClientError> {
+        self.unsubscribe(&[]).await?;
+        Ok(self.conn)
+    }
+
+    // Send a command and wait for its reply, keeping messages that arrive first
+    async fn command(&mut self, cmd: Command) -> Result<i64, ClientError> {
+        self.conn.write_commands(std::slice::from_ref(&cmd)).await?;
+        loop {
+            match self.conn.read_response().await? {
+                Response::Message { channel, message } => self.pending.push_back((channel, message)),
+                response => {
+                    self.count = expect_integer(response)?;
+                    return Ok(self.count);
+                }
+            }
+        }
+    }
+}
+
+// A connection streaming key events, from `Connection::watch_keys`
+pub struct KeyEvents {
+    conn: Connection,
+    count: i64, // patterns watched
+}
+
+impl KeyEvents {
+    pub fn pattern_count(&self) -> i64 {
+        self.count
+    }
+
+    // Wait for the next key to expire or be evicted
+    pub async fn next_event(&mut self) -> Result<KeyEvent, ClientError> {
+        match self.conn.read_response().await? {
+            Response::KeyEvent(event) => Ok(event),
+            Response::Error(message) => Err(ClientError::Server(message)),
+            response => Err(ClientError::UnexpectedResponse(response)),
+        }
+    }
+}
+
+fn expect_success(response: Response) -> Result<(), ClientError> {
+    match response {
+        Response::Success => Ok(()),
+        Response::Error(message) => Err(ClientError::Server(message)),
+        response => Err(ClientError::UnexpectedResponse(response)),
+    }
+}
+
+fn expect_data(response: Response) -> Result<Bytes, ClientError> {
+    match response {
+        Response::Data(data) => Ok(data),
+        Response::Error(message) => Err(ClientError::Server(message)),
+        response => Err(ClientError::UnexpectedResponse(response)),
+    }
+}
+
+pub(crate) fn expect_lookup(response: Response) -> Result<Lookup, ClientError> {
+    match response {
+        Response::Data(data) => Ok(Lookup::Value(data)),
+        Response::Stale(data) => Ok(Lookup::Stale(data)),
+        Response::Missing => Ok(Lookup::Missing),
+        Response::Locked => Ok(Lookup::Locked),
+        Response::InProgress => Ok(Lookup::InProgress),
+        Response::Error(message) => Err(ClientError::Server(message)),
+        response => Err(ClientError::UnexpectedResponse(response)),
+    }
+}
+
+fn expect_integer(response: Response) -> Result<i64, ClientError> {
+    match response {
+        Response::Integer(value) => Ok(value),
+        Response::Error(message) => Err(ClientError::Server(message)),
+        response => Err(ClientError::UnexpectedResponse(response)),
+    }
+}
@@ -0,0 +1,40 @@
+use thiserror::Error;
+use pluto_core::protocol::{ErrorCode, ErrorReply, Response};
+
+// Errors returned by the client
+#[derive(Error, Debug)]
+pub enum ClientError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Protocol error: {0}")]
+    Protocol(String),
+
+    // The server answered with `Response::Error`
+    #[error("Server error: {0}")]
+    Server(ErrorReply),
+
+    #[error("Unexpected response: {0:?}")]
+    UnexpectedResponse(Response),
+
+    #[error("Connection closed by the server")]
+    ConnectionClosed,
+
+    #[error("Timed out waiting for a pooled connection")]
+    PoolTimeout,
+}
+
+impl ClientError {
+    // The code the server failed the command with, if it did
+    pub fn code(&self) -> Option<ErrorCode> {
+        match self {
+            ClientError::Server(error) => Some(error.code),
+            _ => None,
+        }
+    }
+
+    // Whether the server reported a missing key
+    pub fn is_key_not_found(&self) -> bool {
+        self.code() == Some(ErrorCode::NotFound)
+    }
+}
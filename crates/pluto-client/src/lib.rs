@@ -0,0 +1,15 @@
+#![allow(unused_imports)]
+
+pub mod cluster;
+pub mod connection;
+pub mod error;
+pub mod pipeline;
+pub mod pool;
+
+pub use cluster::ClusterClient;
+pub use connection::{Connection, KeyEvents, Lookup, Subscription};
+pub use error::ClientError;
+pub use pipeline::Pipeline;
+pub use pool::{Pool, PoolConfig, PooledConnection};
+pub use pluto_core::codec::Encoding;
+pub use pluto_core::protocol::{Command, CommandDoc, CommandInfo, ErrorCode, ErrorReply, KeyEvent, KeyEventReason, ObjectInfo, ResetMode, Response, ShutdownMode, SlotState};
@@ -0,0 +1,104 @@
+use bytes::Bytes;
+use pluto_core::protocol::Command;
+
+// A batch of commands sent together with `Connection::execute`
+#[derive(Default)]
+pub struct Pipeline {
+    commands: Vec<Command>,
+}
+
+impl Pipeline {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.commands.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+
+    // Queue any command
+    pub fn command(&mut self, cmd: Command) -> &mut Self {
+        self.commands.push(cmd);
+        self
+    }
+
+    pub fn set(&mut self, key: &str, value: impl Into<Vec<u8>>) -> &mut Self {
+        self.command(Command::SET { key: key.to_string(), value: value.into(), visible_at: None })
+    }
+
+    pub fn getset(&mut self, key: &str, value: impl Into<Vec<u8>>) -> &mut Self {
+        self.command(Command::GETSET { key: key.to_string(), value: value.into() })
+    }
+
+    pub fn set_visible_at(&mut self, key: &str, value: impl Into<Vec<u8>>, visible_at: u64) -> &mut Self {
+        self.command(Command::SET { key: key.to_string(), value: value.into(), visible_at: Some(visible_at) })
+    }
+
+    pub fn get(&mut self, key: &str) -> &mut Self {
+        self.command(Command::GET { key: key.to_string() })
+    }
+
+    pub fn del(&mut self, keys: &[&str]) -> &mut Self {
+        let keys = keys.iter().map(|key| key.to_string()).collect();
+        self.command(Command::DEL { keys })
+    }
+
+    pub fn unlink(&mut self, keys: &[&str]) -> &mut Self {
+        let keys = keys.iter().map(|key| key.to_string()).collect();
+        self.command(Command::UNLINK { keys })
+    }
+
+    pub fn exists(&mut self, key: &str) -> &mut Self {
+        self.command(Command::EXISTS { key: key.to_string() })
+    }
+
+    pub fn exists_many(&mut self, keys: &[&str]) -> &mut Self {
+        let keys = keys.iter().map(|key| key.to_string()).collect();
+        self.command(Command::EXISTS_MANY { keys })
+    }
+
+    pub fn msetnx(&mut self, entries: &[(&str, &[u8])]) -> &mut Self {
+        let entries = entries.iter().map(|(key, value)| (key.to_string(), Bytes::copy_from_slice(value))).collect();
+        self.command(Command::MSETNX { entries })
+    }
+
+    pub fn expire(&mut self, key: &str, seconds: u64) -> &mut Self {
+        self.command(Command::EXPIRE { key: key.to_string(), seconds, jitter_pct: None })
+    }
+
+    pub fn expire_at(&mut self, key: &str, timestamp: u64) -> &mut Self {
+        self.command(Command::EXPIREAT { key: key.to_string(), timestamp })
+    }
+
+    pub fn pexpire(&mut self, key: &str, milliseconds: u64) -> &mut Self {
+        self.command(Command::PEXPIRE { key: key.to_string(), milliseconds, jitter_pct: None })
+    }
+
+    pub fn pexpire_at(&mut self, key: &str, timestamp_ms: u64) -> &mut Self {
+        self.command(Command::PEXPIREAT { key: key.to_string(), timestamp_ms })
+    }
+
+    pub fn persist(&mut self, key: &str) -> &mut Self {
+        self.command(Command::PERSIST { key: key.to_string() })
+    }
+
+    pub fn soft_expire(&mut self, key: &str, seconds: u64) -> &mut Self {
+        self.command(Command::SOFT_EXPIRE { key: key.to_string(), seconds })
+    }
+
+    pub fn set_missing(&mut self, key: &str, milliseconds: u64) -> &mut Self {
+        self.command(Command::SET_MISSING { key: key.to_string(), milliseconds })
+    }
+
+    pub fn publish(&mut self, channel: &str, message: impl Into<Vec<u8>>) -> &mut Self {
+        self.command(Command::PUBLISH { channel: channel.to_string(), message: message.into() })
+    }
+
+    pub(crate) fn into_commands(self) -> Vec<Command> {
+        self.commands
+    }
+}
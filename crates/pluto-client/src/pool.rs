@@ -0,0 +1,125 @@
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use pluto_core::codec::Encoding;
+use crate::connection::Connection;
+use crate::error::ClientError;
+
+// Pool settings
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    pub max_size: usize,             // connections open at once
+    pub encoding: Encoding,          // negotiated with HELLO on every new connection
+    pub password: Option<String>,    // sent with AUTH on every new connection
+    pub acquire_timeout: Duration,   // how long `get` waits for a free connection
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        PoolConfig {
+            max_size: 16,
+            encoding: Encoding::Json,
+            password: None,
+            acquire_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+struct PoolInner {
+    addr: String,
+    config: PoolConfig,
+    idle: Mutex<Vec<Connection>>,
+    permits: Arc<Semaphore>,
+}
+
+// A bounded pool of connections to one server. Cloning is cheap and shares
+// the same connections.
+#[derive(Clone)]
+pub struct Pool {
+    inner: Arc<PoolInner>,
+}
+
+impl Pool {
+    pub fn new(addr: &str, config: PoolConfig) -> Self {
+        let permits = Arc::new(Semaphore::new(config.max_size.max(1)));
+        Pool {
+            inner: Arc::new(PoolInner {
+                addr: addr.to_string(),
+                config,
+                idle: Mutex::new(Vec::new()),
+                permits,
+            }),
+        }
+    }
+
+    pub fn addr(&self) -> &str {
+        &self.inner.addr
+    }
+
+    // Connections currently idle in the pool
+    pub fn idle_count(&self) -> usize {
+        self.inner.idle.lock().unwrap().len()
+    }
+
+    // Take an idle connection or open a new one, waiting while the pool is full
+    pub async fn get(&self) -> Result<PooledConnection, ClientError> {
+        let permit = tokio::time::timeout(
+            self.inner.config.acquire_timeout,
+            self.inner.permits.clone().acquire_owned(),
+        )
+        .await
+        .map_err(|_| ClientError::PoolTimeout)?
+        .map_err(|_| ClientError::ConnectionClosed)?;
+
+        let idle = self.inner.idle.lock().unwrap().pop();
+        let conn = match idle {
+            Some(conn) => conn,
+            None => self.open().await?,
+        };
+        Ok(PooledConnection { conn: Some(conn), pool: self.inner.clone(), _permit: permit })
+    }
+
+    async fn open(&self) -> Result<Connection, ClientError> {
+        let config = &self.inner.config;
+        let mut conn = Connection::connect(&self.inner.addr).await?;
+        if let Some(password) = &config.password {
+            conn.auth(password).await?;
+        }
+        if config.encoding != Encoding::Json {
+            conn.hello(None, Some(config.encoding)).await?;
+        }
+        Ok(conn)
+    }
+}
+
+// A connection borrowed from a pool; it goes back to the pool when dropped
+// unless it broke while in use
+pub struct PooledConnection {
+    conn: Option<Connection>,
+    pool: Arc<PoolInner>,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Deref for PooledConnection {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.conn.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledConnection {
+    fn deref_mut(&mut self) -> &mut Connection {
+        self.conn.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledConnection {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take()
+            && !conn.is_broken() {
+            self.pool.idle.lock().unwrap().push(conn);
+        }
+    }
+}
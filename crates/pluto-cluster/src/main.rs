@@ -0,0 +1,448 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::time::{Duration, Instant};
+use clap::{Parser, Subcommand};
+use pluto_client::{ClientError, Connection, SlotState};
+use pluto_core::cluster::{ClusterData, NodeHealth, TOTAL_SLOTS};
+
+// How long to wait for every node to agree on the slot map after a change
+const CONVERGE_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Command line arguments
+#[derive(Parser, Debug)]
+#[command(name = "pluto-cluster")]
+#[command(author = "thebyteslayer")]
+#[command(version = "1.0.0")]
+#[command(about = "Cluster administration tool for flux-cache")]
+struct Args {
+    /// Password sent with AUTH to every node
+    #[arg(long, global = true)]
+    password: Option<String>,
+    #[command(subcommand)]
+    command: Action,
+}
+
+#[derive(Subcommand, Debug)]
+enum Action {
+    /// Form a cluster out of standalone nodes
+    Create {
+        /// Data addresses of the nodes, as host:port
+        #[arg(required = true, num_args = 2..)]
+        nodes: Vec<String>,
+    },
+    /// Add a node to an existing cluster
+    AddNode {
+        /// Address of the node to add
+        new_node: String,
+        /// Address of any node already in the cluster
+        existing: String,
+    },
+    /// Remove a node from the cluster
+    RemoveNode {
+        /// Address of the node to remove
+        node: String,
+        /// Address of any node that stays in the cluster
+        existing: String,
+    },
+    /// Move slots from one node to another
+    Reshard {
+        /// Address of any node in the cluster
+        existing: String,
+        /// Node giving up slots
+        #[arg(long)]
+        from: String,
+        /// Node receiving slots
+        #[arg(long)]
+        to: String,
+        /// Number of slots to move
+        #[arg(long)]
+        slots: usize,
+    },
+    /// Spread the slots evenly over the nodes
+    Rebalance {
+        /// Address of any node in the cluster
+        existing: String,
+    },
+    /// Verify that every node agrees on a slot map that covers all slots
+    Check {
+        /// Address of any node in the cluster
+        existing: String,
+    },
+}
+
+#[tokio::main]
+async fn main() {
+    let args = Args::parse();
+    let admin = Admin { password: args.password };
+    let result = match args.command {
+        Action::Create { nodes } => admin.create(&nodes).await,
+        Action::AddNode { new_node, existing } => admin.add_node(&new_node, &existing).await,
+        Action::RemoveNode { node, existing } => admin.remove_node(&node, &existing).await,
+        Action::Reshard { existing, from, to, slots } => admin.reshard(&existing, &from, &to, slots).await,
+        Action::Rebalance { existing } => admin.rebalance(&existing).await,
+        Action::Check { existing } => admin.check(&existing).await.map(|_| ()),
+    };
+    if let Err(e) = result {
+        eprintln!("[ERR] {}", e);
+        std::process::exit(1);
+    }
+}
+
+struct Admin {
+    password: Option<String>,
+}
+
+impl Admin {
+    async fn connect(&self, addr: &str) -> Result<Connection, ClientError> {
+        let mut conn = Connection::connect(addr).await?;
+        if let Some(password) = &self.password {
+            conn.auth(password).await?;
+        }
+        Ok(conn)
+    }
+
+    async fn slot_map(&self, addr: &str) -> Result<ClusterData, ClientError> {
+        let json = self.connect(addr).await?.cluster_slots().await?;
+        serde_json::from_str(&json).map_err(|e| ClientError::Protocol(format!("invalid slot map from {}: {}", addr, e)))
+    }
+
+    async fn create(&self, nodes: &[String]) -> Result<(), ClientError> {
+        // Every node must be running with clustering enabled and on its own
+        for node in nodes {
+            let data = self.slot_map(node).await?;
+            if data.nodes.iter().any(|slots| &slots.address != node) {
+                return Err(ClientError::Protocol(format!("{} is already part of a cluster", node)));
+            }
+        }
+        let (first, rest) = nodes.split_first().expect("clap requires at least two nodes");
+        for node in rest {
+            println!(">>> Joining {} to {}", node, first);
+            self.connect(first).await?.cluster_join(node).await?;
+            self.wait_for_member(first, node).await?;
+            self.wait_for_handoff(first).await?;
+        }
+        self.wait_for_agreement(first, nodes.len()).await?;
+        println!("[OK] Cluster of {} nodes created", nodes.len());
+        self.check(first).await?;
+        Ok(())
+    }
+
+    async fn add_node(&self, new_node: &str, existing: &str) -> Result<(), ClientError> {
+        let members = self.members(existing).await?;
+        if members.iter().any(|member| member == new_node) {
+            return Err(ClientError::Protocol(format!("{} is already in the cluster", new_node)));
+        }
+        println!(">>> Joining {} to the cluster through {}", new_node, existing);
+        self.connect(existing).await?.cluster_join(new_node).await?;
+        self.wait_for_member(existing, new_node).await?;
+        self.wait_for_handoff(existing).await?;
+        self.wait_for_agreement(existing, members.len() + 1).await?;
+        println!("[OK] {} added", new_node);
+        self.check(existing).await?;
+        Ok(())
+    }
+
+    async fn remove_node(&self, node: &str, existing: &str) -> Result<(), ClientError> {
+        if node == existing {
+            return Err(ClientError::Protocol("remove a node through another member of the cluster".to_string()));
+        }
+        let members = self.members(existing).await?;
+        if !members.iter().any(|member| member == node) {
+            return Err(ClientError::Protocol(format!("{} is not in the cluster", node)));
+        }
+        println!(">>> Removing {} through {}", node, existing);
+        self.connect(existing).await?.cluster_remove(node).await?;
+        self.wait_for_agreement(existing, members.len() - 1).await?;
+        println!("[OK] {} removed", node);
+        self.check(existing).await?;
+        Ok(())
+    }
+
+    async fn reshard(&self, existing: &str, from: &str, to: &str, slots: usize) -> Result<(), ClientError> {
+        let data = self.slot_map(existing).await?;
+        let members = members_of(&data);
+        for node in [from, to] {
+            if !members.iter().any(|member| member == node) {
+                return Err(ClientError::Protocol(format!("{} is not in the cluster", node)));
+            }
+        }
+        // The highest slots of `from` go, keeping what it has left contiguous
+        let owned = slots_of(&data, from);
+        if owned.len() < slots {
+            return Err(ClientError::Protocol(format!("{} only serves {} slots", from, owned.len())));
+        }
+        self.move_slots(existing, from, to, &owned[owned.len() - slots..]).await?;
+        println!("[OK] Moved {} slots from {} to {}", slots, from, to);
+        self.check(existing).await?;
+        Ok(())
+    }
+
+    // Even out the slot counts, moving slots from the nodes with the most to
+    // the nodes with the fewest
+    async fn rebalance(&self, existing: &str) -> Result<(), ClientError> {
+        let data = self.check(existing).await?;
+        let counts = slot_counts(&data);
+        let min = counts.values().min().copied().unwrap_or(0);
+        let max = counts.values().max().copied().unwrap_or(0);
+        if max - min <= 1 {
+            println!("[OK] Slots are already balanced");
+            return Ok(());
+        }
+        // Every node's fair share, the remainder going to the first nodes
+        let base = TOTAL_SLOTS / counts.len();
+        let extra = TOTAL_SLOTS % counts.len();
+        let mut surplus = Vec::new();
+        let mut deficit = Vec::new();
+        for (i, (address, count)) in counts.iter().enumerate() {
+            let target = if i < extra { base + 1 } else { base };
+            if *count > target {
+                surplus.push((address.clone(), count - target));
+            } else if *count < target {
+                deficit.push((address.clone(), target - count));
+            }
+        }
+        for (to, mut wanted) in deficit {
+            for (from, spare) in surplus.iter_mut().filter(|(_, spare)| *spare > 0) {
+                if wanted == 0 {
+                    break;
+                }
+                let count = wanted.min(*spare);
+                let owned = slots_of(&self.slot_map(existing).await?, from);
+                self.move_slots(existing, from, &to, &owned[owned.len() - count..]).await?;
+                *spare -= count;
+                wanted -= count;
+            }
+        }
+        println!("[OK] Slots rebalanced");
+        self.check(existing).await?;
+        Ok(())
+    }
+
+    // Hand slots over from one node to another without losing keys: the
+    // target imports them, the source migrates its keys there, asking clients
+    // to follow for the keys already moved, and then the slots change owner
+    async fn move_slots(&self, existing: &str, from: &str, to: &str, slots: &[usize]) -> Result<(), ClientError> {
+        println!(">>> Moving {} slots from {} to {}", slots.len(), from, to);
+        self.connect(to).await?.cluster_setslot(slots.to_vec(), SlotState::IMPORTING { address: from.to_string() }).await?;
+        self.connect(from).await?.cluster_setslot(slots.to_vec(), SlotState::MIGRATING { address: to.to_string() }).await?;
+        self.connect(from).await?.migrate_slots(slots.to_vec(), to).await?;
+        self.wait_for_migration(from).await?;
+        self.connect(existing).await?.cluster_setslot(slots.to_vec(), SlotState::NODE { address: to.to_string() }).await?;
+        let expected = members_of(&self.slot_map(existing).await?).len();
+        self.wait_for_agreement(existing, expected).await
+    }
+
+    // Wait for the slot migration running on `addr` to finish
+    async fn wait_for_migration(&self, addr: &str) -> Result<(), ClientError> {
+        print!(">>> Migrating keys");
+        loop {
+            let info = self.connect(addr).await?.info(Some("cluster")).await?;
+            let field = |name: &str| {
+                info.lines()
+                    .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+                    .unwrap_or("")
+                    .trim()
+                    .to_string()
+            };
+            if field("migrating_slots") == "0" {
+                println!(" {} keys moved", field("migration_keys_moved"));
+                let (done, total) = (field("migration_slots_done"), field("migration_slots_total"));
+                if done != total {
+                    return Err(ClientError::Protocol(format!("migration on {} stopped after {} of {} slots", addr, done, total)));
+                }
+                return Ok(());
+            }
+            print!(".");
+            std::io::stdout().flush().ok();
+            tokio::time::sleep(Duration::from_millis(500)).await;
+        }
+    }
+
+    // Wait for `addr` to finish handing slots over to the node that just joined
+    async fn wait_for_handoff(&self, addr: &str) -> Result<(), ClientError> {
+        print!(">>> Handing slots over");
+        loop {
+            let info = self.connect(addr).await?.info(Some("cluster")).await?;
+            let field = |name: &str| {
+                info.lines()
+                    .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+                    .unwrap_or("")
+                    .trim()
+                    .to_string()
+            };
+            if field("handoff_in_progress") == "0" {
+                let (moved, total) = (field("handoff_slots_moved"), field("handoff_slots_total"));
+                println!(" {} of {} slots moved", moved, total);
+                if moved != total {
+                    return Err(ClientError::Protocol(format!("handing slots over stopped after {} of {} slots, see the log of {}", moved, total, addr)));
+                }
+                return Ok(());
+            }
+            print!(".");
+            std::io::stdout().flush().ok();
+            tokio::time::sleep(Duration::from_millis(500)).await;
+        }
+    }
+
+    // Check the cluster as seen from `existing` and every member it knows,
+    // returning the agreed slot map
+    async fn check(&self, existing: &str) -> Result<ClusterData, ClientError> {
+        let data = self.slot_map(existing).await?;
+        let mut problems = Vec::new();
+
+        // Every slot must belong to exactly one node
+        let mut owners = vec![0u32; TOTAL_SLOTS];
+        for node in &data.nodes {
+            let (start, end) = node.slot_range;
+            if start > end || end >= TOTAL_SLOTS {
+                problems.push(format!("{} has an invalid slot range {}-{}", node.address, start, end));
+                continue;
+            }
+            for owner in &mut owners[start..=end] {
+                *owner += 1;
+            }
+        }
+        let uncovered = owners.iter().filter(|&&count| count == 0).count();
+        let overlapping = owners.iter().filter(|&&count| count > 1).count();
+        if uncovered > 0 {
+            problems.push(format!("{} slots are not assigned to any node", uncovered));
+        }
+        if overlapping > 0 {
+            problems.push(format!("{} slots are assigned to more than one node", overlapping));
+        }
+
+        // The members must agree the others are answering heartbeats
+        for (member, health) in &data.health {
+            if *health != NodeHealth::Online {
+                problems.push(format!("{} is marked {}", member, health_name(*health)));
+            }
+        }
+
+        // Every member must be reachable and report the same map
+        for member in members_of(&data) {
+            match self.slot_map(&member).await {
+                Ok(theirs) if theirs.epoch != data.epoch || !same_slots(&theirs, &data) => {
+                    problems.push(format!("{} reports a different slot map (epoch {} vs {})", member, theirs.epoch, data.epoch));
+                }
+                Ok(theirs) if !theirs.migrating.is_empty() || !theirs.importing.is_empty() => {
+                    problems.push(format!(
+                        "{} has open slots ({} migrating, {} importing)", member, theirs.migrating.len(), theirs.importing.len()
+                    ));
+                }
+                Ok(_) => {}
+                Err(e) => problems.push(format!("{} is unreachable: {}", member, e)),
+            }
+        }
+
+        println!(">>> Cluster epoch {}", data.epoch);
+        for (address, count) in slot_counts(&data) {
+            let id = data.nodes.iter().find(|node| node.address == address).map(|node| node.node_id.as_str()).unwrap_or("");
+            let health = data.health.get(&address).copied().unwrap_or_default();
+            println!("  {} {} slots={} health={}", address, id, count, health_name(health));
+        }
+        if problems.is_empty() {
+            println!("[OK] All {} slots covered and all nodes agree", TOTAL_SLOTS);
+            Ok(data)
+        } else {
+            for problem in &problems {
+                println!("[WARN] {}", problem);
+            }
+            Err(ClientError::Protocol(format!("{} problem(s) found", problems.len())))
+        }
+    }
+
+    async fn members(&self, existing: &str) -> Result<Vec<String>, ClientError> {
+        Ok(members_of(&self.slot_map(existing).await?))
+    }
+
+    // Wait until `addr` lists `member` in its slot map
+    async fn wait_for_member(&self, addr: &str, member: &str) -> Result<(), ClientError> {
+        let deadline = Instant::now() + CONVERGE_TIMEOUT;
+        loop {
+            if self.members(addr).await?.iter().any(|m| m == member) {
+                return Ok(());
+            }
+            if Instant::now() > deadline {
+                return Err(ClientError::Protocol(format!("{} did not add {} in time", addr, member)));
+            }
+            tokio::time::sleep(Duration::from_millis(200)).await;
+        }
+    }
+
+    // Wait until every member reports the same slot map with `expected` nodes
+    async fn wait_for_agreement(&self, addr: &str, expected: usize) -> Result<(), ClientError> {
+        let deadline = Instant::now() + CONVERGE_TIMEOUT;
+        print!(">>> Waiting for the cluster to agree");
+        loop {
+            let data = self.slot_map(addr).await?;
+            let members = members_of(&data);
+            let mut agreed = members.len() == expected;
+            for member in &members {
+                if !agreed {
+                    break;
+                }
+                agreed = matches!(self.slot_map(member).await, Ok(theirs) if same_slots(&theirs, &data));
+            }
+            if agreed {
+                println!();
+                return Ok(());
+            }
+            if Instant::now() > deadline {
+                println!();
+                return Err(ClientError::Protocol("nodes did not agree on the slot map in time".to_string()));
+            }
+            print!(".");
+            std::io::stdout().flush().ok();
+            tokio::time::sleep(Duration::from_millis(500)).await;
+        }
+    }
+}
+
+// Node addresses in slot map order
+fn members_of(data: &ClusterData) -> Vec<String> {
+    let mut members: Vec<String> = Vec::new();
+    for node in &data.nodes {
+        if !members.contains(&node.address) {
+            members.push(node.address.clone());
+        }
+    }
+    members
+}
+
+fn same_slots(a: &ClusterData, b: &ClusterData) -> bool {
+    let ranges = |data: &ClusterData| {
+        let mut ranges: Vec<(String, (usize, usize))> = data.nodes.iter()
+            .map(|node| (node.address.clone(), node.slot_range))
+            .collect();
+        ranges.sort();
+        ranges
+    };
+    ranges(a) == ranges(b)
+}
+
+// Every slot `address` serves, in order
+fn slots_of(data: &ClusterData, address: &str) -> Vec<usize> {
+    let mut slots: Vec<usize> = data.nodes.iter()
+        .filter(|node| node.address == address)
+        .flat_map(|node| node.slot_range.0..=node.slot_range.1)
+        .collect();
+    slots.sort_unstable();
+    slots
+}
+
+fn slot_counts(data: &ClusterData) -> BTreeMap<String, usize> {
+    let mut counts = BTreeMap::new();
+    for node in &data.nodes {
+        let (start, end) = node.slot_range;
+        *counts.entry(node.address.clone()).or_insert(0) += end.saturating_sub(start) + 1;
+    }
+    counts
+}
+
+fn health_name(health: NodeHealth) -> &'static str {
+    match health {
+        NodeHealth::Online => "online",
+        NodeHealth::Suspected => "suspected",
+        NodeHealth::Failed => "failed",
+    }
+}
@@ -0,0 +1,1018 @@
+use std::collections::BTreeSet;
+use std::hash::{BuildHasher, Hash, RandomState};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU8, AtomicU64, Ordering};
+use bytes::Bytes;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use crate::key::{Key, Prefixes};
+use crate::protocol::ErrorCode;
+use crate::storage::{MemoryEngine, StorageEngine};
+use crate::value::{INLINE_CAPACITY, Value};
+
+// Custom error type
+#[derive(Error, Debug)]
+pub enum ServerError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    
+    #[error("Serialization error: {0}")]
+    Serialization(#[from] serde_json::Error),
+    
+    #[error("Encoding error: {0}")]
+    Encoding(String),
+    
+    #[error("Compression error: {0}")]
+    Compression(String),
+    
+    #[error("Key not found: {0}")]
+    KeyNotFound(String),
+    
+    #[error("Invalid argument: {0}")]
+    InvalidArgument(String),
+
+    #[error("Invalid command: {0}")]
+    InvalidCommand(String),
+    
+    #[error("Unauthorized: {0}")]
+    Unauthorized(String),
+
+    #[error("Forbidden: {0}")]
+    Forbidden(String),
+
+    #[error("Wrong type: {0}")]
+    WrongType(String),
+    
+    #[error("Quorum not reached: {0}")]
+    QuorumNotReached(String),
+
+    #[error("Corrupted value: {0}")]
+    Corruption(String),
+
+    #[error("Origin error: {0}")]
+    Origin(String),
+
+    #[error("Quota exceeded: {0}")]
+    QuotaExceeded(String),
+
+    #[error("Timeout: {0}")]
+    Timeout(String),
+
+    #[error("Cluster down: {0}")]
+    ClusterDown(String),
+}
+
+impl ServerError {
+    // The code replies to clients carry for this error
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            ServerError::KeyNotFound(_) => ErrorCode::NotFound,
+            ServerError::InvalidCommand(_) => ErrorCode::Invalid,
+            ServerError::Unauthorized(_) => ErrorCode::NoAuth,
+            ServerError::Forbidden(_) => ErrorCode::NoPerm,
+            ServerError::WrongType(_) => ErrorCode::WrongType,
+            ServerError::QuorumNotReached(_) => ErrorCode::NoQuorum,
+            ServerError::Corruption(_) => ErrorCode::Corrupt,
+            ServerError::Origin(_) => ErrorCode::Origin,
+            ServerError::QuotaExceeded(_) => ErrorCode::Oom,
+            ServerError::Timeout(_) => ErrorCode::Timeout,
+            ServerError::ClusterDown(_) => ErrorCode::ClusterDown,
+            _ => ErrorCode::Err,
+        }
+    }
+}
+
+// Cache entry structure
+#[derive(Clone)]
+pub struct CacheEntry {
+    pub data: Value,
+    pub compressed: bool, // whether `data` holds zstd-compressed bytes
+    pub crdt: bool, // whether `data` holds the state of a `Crdt` rather than a value
+    pub tombstone: bool, // whether the key is cached as missing, holding no value
+    pub expires_at: Option<u64>, // unix time in milliseconds after which the entry is gone
+    pub stale_at: Option<u64>, // unix time in milliseconds after which reads flag the value stale
+    pub visible_at: Option<u64>, // unix time in milliseconds before which reads miss the key
+    pub stamp: WriteStamp,
+    pub checksum: u32, // CRC32 of `data`, checked whenever it is read
+    pub access: Access,
+}
+
+// Access counters start here, so that a new key doesn't read as the coldest
+const LFU_INIT: u8 = 5;
+// How much harder each step of the access counter is to reach than the last
+const LFU_LOG_FACTOR: f64 = 10.0;
+// The access counter drops by one for each of these an entry goes unread
+const LFU_DECAY_MS: u64 = 60_000;
+
+// When an entry was created, last used and how often, for OBJECT_IDLETIME,
+// OBJECT_FREQ and DEBUG_OBJECT, and whether a read past its soft TTL was
+// announced. Reads only hold the keyspace shared, hence the atomics.
+#[derive(Debug)]
+pub struct Access {
+    created: u64, // unix time in milliseconds the key was written on this node, or loaded
+    last: AtomicU64, // unix time in milliseconds
+    counter: AtomicU8, // logarithmic: 255 stands for about a million accesses
+    count: AtomicU64, // exact number of accesses
+    stale_announced: AtomicBool,
+}
+
+impl Default for Access {
+    fn default() -> Self {
+        let now = now_ms();
+        Access { created: now, last: AtomicU64::new(now), counter: AtomicU8::new(LFU_INIT), count: AtomicU64::new(0),
+            stale_announced: AtomicBool::new(false) }
+    }
+}
+
+impl Clone for Access {
+    fn clone(&self) -> Self {
+        let mut access = Access::default();
+        access.inherit(self);
+        access.stale_announced.store(self.stale_announced.load(Ordering::Relaxed), Ordering::Relaxed);
+        access
+    }
+}
+
+impl Access {
+    // Record an access at `now`
+    pub fn touch(&self, now: u64) {
+        let mut counter = self.frequency(now);
+        if counter < u8::MAX {
+            let odds = 1.0 / ((counter.saturating_sub(LFU_INIT)) as f64 * LFU_LOG_FACTOR + 1.0);
+            if rand::random::<f64>() < odds {
+                counter += 1;
+            }
+        }
+        self.counter.store(counter, Ordering::Relaxed);
+        self.last.store(now, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Unix time in milliseconds the key was created
+    pub fn created_at(&self) -> u64 {
+        self.created
+    }
+
+    // Unix time in milliseconds of the last access
+    pub fn accessed_at(&self) -> u64 {
+        self.last.load(Ordering::Relaxed)
+    }
+
+    // Reads and writes the key has seen
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    // Whether this is the first read past the soft TTL, which is announced
+    pub fn announce_stale(&self) -> bool {
+        !self.stale_announced.swap(true, Ordering::Relaxed)
+    }
+
+    // Milliseconds since the last access
+    pub fn idle_ms(&self, now: u64) -> u64 {
+        now.saturating_sub(self.last.load(Ordering::Relaxed))
+    }
+
+    // The access counter, decayed for the time since the last access
+    pub fn frequency(&self, now: u64) -> u8 {
+        let decay = (self.idle_ms(now) / LFU_DECAY_MS).min(u8::MAX as u64) as u8;
+        self.counter.load(Ordering::Relaxed).saturating_sub(decay)
+    }
+
+    // Carry over the counters of the entry this one replaces
+    fn inherit(&mut self, previous: &Access) {
+        self.created = previous.created;
+        self.count.store(previous.count.load(Ordering::Relaxed), Ordering::Relaxed);
+        self.counter.store(previous.counter.load(Ordering::Relaxed), Ordering::Relaxed);
+        self.last.store(previous.last.load(Ordering::Relaxed), Ordering::Relaxed);
+    }
+}
+
+// When an entry was last written and how many writes it has seen, which
+// decides between conflicting writes made in two clusters
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WriteStamp {
+    pub at: u64, // unix time in milliseconds
+    pub version: u64,
+}
+
+impl WriteStamp {
+    // Stamp of a write replacing `previous`
+    pub fn after(previous: Option<&CacheEntry>) -> Self {
+        WriteStamp { at: now_ms(), version: previous.map_or(0, |entry| entry.stamp.version) + 1 }
+    }
+}
+
+impl CacheEntry {
+    // An entry of stored bytes, checksummed as they are now
+    pub fn new(data: Bytes, compressed: bool, crdt: bool) -> Self {
+        let checksum = checksum(&data);
+        CacheEntry { data: Value::from(data), compressed, crdt, tombstone: false, expires_at: None, stale_at: None, visible_at: None, stamp: WriteStamp::default(), checksum, access: Access::default() }
+    }
+
+    // An entry recording that the key is missing, until `expires_at`
+    pub fn tombstone(expires_at: u64) -> Self {
+        let mut entry = CacheEntry::new(Bytes::new(), false, false);
+        entry.tombstone = true;
+        entry.expires_at = Some(expires_at);
+        entry
+    }
+
+    pub fn is_expired(&self, now_ms: u64) -> bool {
+        self.expires_at.is_some_and(|at| at <= now_ms)
+    }
+
+    // Whether the soft TTL passed, after which the value is served flagged stale
+    pub fn is_stale(&self, now_ms: u64) -> bool {
+        self.stale_at.is_some_and(|at| at <= now_ms)
+    }
+
+    // Whether the value is still scheduled to appear, until which it is kept
+    // from readers
+    pub fn is_hidden(&self, now_ms: u64) -> bool {
+        self.visible_at.is_some_and(|at| at > now_ms)
+    }
+
+    // Fail if the stored bytes changed since they were checksummed
+    pub fn verify(&self) -> Result<(), ServerError> {
+        if checksum(&self.data) != self.checksum {
+            return Err(ServerError::Corruption(format!(
+                "{} stored bytes don't match their checksum", self.data.len()
+            )));
+        }
+        Ok(())
+    }
+}
+
+pub fn checksum(data: &[u8]) -> u32 {
+    crc32fast::hash(data)
+}
+
+// Current unix time in milliseconds, the clock entry expiry is measured against
+pub fn now_ms() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+// Entries a scan visits between asking whether to give up
+const SCAN_CHECK_INTERVAL: usize = 1024;
+
+// Bytes an entry occupies besides its key and value: the `Key` itself, the
+// `CacheEntry` itself and the hash table's control byte
+const ENTRY_OVERHEAD: usize = std::mem::size_of::<Key>() + std::mem::size_of::<CacheEntry>() + 1;
+
+// Memory accounted to one entry
+pub fn entry_memory(key: &str, entry: &CacheEntry) -> usize {
+    entry_memory_for(key, entry.data.heap_len())
+}
+
+// What an entry holding `len` bytes under `key` would be accounted
+pub fn entry_memory_for(key: &str, len: usize) -> usize {
+    key.len() + len + ENTRY_OVERHEAD
+}
+
+// Shards the entries of a keyspace are spread over, by the hash of their key.
+// A write copies the shard it changes while a view holds it, so the more
+// there are, the less a write copies.
+const SHARDS: usize = 256;
+
+// The entries of one shard of a keyspace by key
+pub type Entries = hashbrown::HashMap<Key, CacheEntry, RandomState>;
+
+// The entries of a keyspace as they were when the view was taken, whatever
+// is written after. Taking a view copies nothing: the keyspace copies a
+// shard the view holds before its next write to it instead. Snapshots and
+// full syncs read a view, so they don't hold the keyspace while they do.
+#[derive(Clone)]
+pub struct View {
+    shards: Vec<Arc<Entries>>,
+    _held: Arc<()>, // counted by the keyspace, which knows when views are gone
+}
+
+impl View {
+    pub fn iter(&self) -> impl Iterator<Item = (&Key, &CacheEntry)> {
+        self.shards.iter().flat_map(|shard| shard.iter())
+    }
+
+    pub fn shards(&self) -> &[Arc<Entries>] {
+        &self.shards
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shards.iter().all(|shard| shard.is_empty())
+    }
+}
+
+fn empty_shards() -> Vec<Arc<Entries>> {
+    (0..SHARDS).map(|_| Arc::default()).collect()
+}
+
+// The key/value store, keeping track of the memory its entries use
+pub struct Keyspace {
+    shards: Vec<Arc<Entries>>,
+    hasher: RandomState, // picks the shard of a key
+    views: Arc<()>, // held by every view taken
+    stale_prefixes: bool, // whether keys removed while a view was held left prefixes to prune
+    used_memory: usize, // sum of `entry_memory` over all entries
+    expiries: BTreeSet<(u64, Key)>, // (expires_at, key) of every entry with an expiry
+    engine: Box<dyn StorageEngine>, // told of every change to the entries
+    usage: Vec<Usage>, // kept for the key patterns `track_usage` was given
+    inline_max: usize, // values of at most this many bytes are kept inside their entry
+    prefixes: Prefixes, // the prefixes the keys share, with shared prefixes
+    expired_reads: AtomicU64, // reads that found their key expired before the sweeper removed it
+}
+
+// How a keyspace lays its entries out in memory
+#[derive(Debug, Clone, Copy)]
+pub struct Layout {
+    pub inline_max: usize, // values of at most this many bytes (INLINE_CAPACITY at most) are kept inside their entry
+    pub shared_prefixes: bool, // keys keep what comes up to their last ':' once for all keys with it
+}
+
+impl Default for Layout {
+    fn default() -> Self {
+        Layout { inline_max: INLINE_CAPACITY, shared_prefixes: false }
+    }
+}
+
+// Keys and bytes of the entries whose keys match any of some glob patterns
+#[derive(Debug, Clone, Default)]
+pub struct Usage {
+    patterns: Vec<String>,
+    pub keys: usize,
+    pub bytes: usize, // sum of `entry_memory` over those entries
+}
+
+impl Usage {
+    pub fn covers(&self, key: &str) -> bool {
+        self.patterns.iter().any(|pattern| glob_match(pattern.as_bytes(), key.as_bytes()))
+    }
+}
+
+impl Default for Keyspace {
+    fn default() -> Self {
+        Keyspace {
+            shards: empty_shards(),
+            hasher: RandomState::new(),
+            views: Arc::new(()),
+            stale_prefixes: false,
+            used_memory: 0,
+            expiries: BTreeSet::new(),
+            engine: Box::new(MemoryEngine),
+            usage: Vec::new(),
+            inline_max: INLINE_CAPACITY,
+            prefixes: Prefixes::default(),
+            expired_reads: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Keyspace {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // An empty keyspace laid out as `layout` says
+    pub fn with_layout(layout: Layout) -> Self {
+        Keyspace {
+            inline_max: layout.inline_max,
+            prefixes: Prefixes::new(layout.shared_prefixes),
+            ..Self::default()
+        }
+    }
+
+    // A keyspace kept by `engine`, holding the entries it stored before
+    pub fn with_engine(engine: Box<dyn StorageEngine>, entries: Vec<(String, CacheEntry)>, layout: Layout) -> Self {
+        let mut keyspace = Keyspace { engine, ..Self::with_layout(layout) };
+        for (key, entry) in entries {
+            keyspace.account(key, entry);
+        }
+        keyspace
+    }
+
+    pub fn layout(&self) -> Layout {
+        Layout { inline_max: self.inline_max, shared_prefixes: self.prefixes.is_shared() }
+    }
+
+    pub fn storage(&self) -> &dyn StorageEngine {
+        self.engine.as_ref()
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shards.iter().all(|shard| shard.is_empty())
+    }
+
+    // The entries as they are now, for reading without holding the keyspace
+    pub fn view(&self) -> View {
+        View { shards: self.shards.clone(), _held: self.views.clone() }
+    }
+
+    fn shard_of<K: Hash + ?Sized>(&self, key: &K) -> usize {
+        self.hasher.hash_one(key) as usize % SHARDS
+    }
+
+    fn entry(&self, key: &str) -> Option<&CacheEntry> {
+        let key = self.prefixes.lookup(key);
+        self.shards[self.shard_of(&key)].get(&key)
+    }
+
+    // Bytes accounted to all entries
+    pub fn used_memory(&self) -> usize {
+        self.used_memory
+    }
+
+    // The prefixes the keys share, with shared prefixes
+    pub fn prefixes(&self) -> &Prefixes {
+        &self.prefixes
+    }
+
+    // Start keeping the usage of the keys matching `patterns`, returning
+    // the index to read it back with
+    pub fn track_usage(&mut self, patterns: Vec<String>) -> usize {
+        let mut usage = Usage { patterns, ..Usage::default() };
+        for (key, entry) in self.iter() {
+            let key = key.text();
+            if usage.covers(&key) {
+                usage.keys += 1;
+                usage.bytes += entry_memory(&key, entry);
+            }
+        }
+        self.usage.push(usage);
+        self.usage.len() - 1
+    }
+
+    // Keep the usage `other` keeps, at the same indexes, as when a keyspace
+    // loaded from storage takes the place of the one the server started with
+    pub fn track_usage_like(&mut self, other: &Keyspace) {
+        for usage in &other.usage {
+            self.track_usage(usage.patterns.clone());
+        }
+    }
+
+    pub fn usage(&self, index: usize) -> &Usage {
+        &self.usage[index]
+    }
+
+    // Count an entry in or out of every usage covering its key
+    fn meter(&mut self, key: &str, bytes: usize, added: bool) {
+        for usage in self.usage.iter_mut().filter(|usage| usage.covers(key)) {
+            if added {
+                usage.keys += 1;
+                usage.bytes += bytes;
+            } else {
+                usage.keys -= 1;
+                usage.bytes -= bytes;
+            }
+        }
+    }
+
+    // Expired entries are invisible to reads even before they are removed
+    pub fn get(&self, key: &str) -> Option<&CacheEntry> {
+        self.entry(key).filter(|entry| !entry.is_expired(now_ms()))
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.get(key).is_some()
+    }
+
+    // Look up a key on behalf of a client, counting it as an access
+    pub fn read(&self, key: &str) -> Option<&CacheEntry> {
+        let now = now_ms();
+        let entry = self.entry(key)?;
+        if entry.is_expired(now) {
+            self.expired_reads.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        if entry.is_hidden(now) {
+            return None;
+        }
+        entry.access.touch(now);
+        Some(entry)
+    }
+
+    pub fn expired_reads(&self) -> u64 {
+        self.expired_reads.load(Ordering::Relaxed)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Key, &CacheEntry)> {
+        self.shards.iter().flat_map(|shard| shard.iter())
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &Key> {
+        self.shards.iter().flat_map(|shard| shard.keys())
+    }
+
+    // Store an entry, returning the one it replaced. Writing a key counts as
+    // an access, on top of those the replaced entry saw.
+    pub fn insert(&mut self, key: String, mut entry: CacheEntry) -> Option<CacheEntry> {
+        let old = self.take(&key);
+        if let Some(old) = &old {
+            entry.access.inherit(&old.access);
+        }
+        entry.access.touch(now_ms());
+        self.engine.put(&key, &entry, old.as_ref());
+        self.account(key, entry);
+        self.compact_if_wanted();
+        old
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<CacheEntry> {
+        let entry = self.take(key)?;
+        self.engine.delete(key, &entry);
+        self.compact_if_wanted();
+        Some(entry)
+    }
+
+    fn account(&mut self, key: String, mut entry: CacheEntry) {
+        entry.data.inline_up_to(self.inline_max);
+        let bytes = entry_memory(&key, &entry);
+        self.used_memory += bytes;
+        self.meter(&key, bytes, true);
+        if self.stale_prefixes && Arc::strong_count(&self.views) == 1 {
+            self.prefixes.prune();
+            self.stale_prefixes = false;
+        }
+        let key = self.prefixes.key(key);
+        if let Some(at) = entry.expires_at {
+            self.expiries.insert((at, key.clone()));
+        }
+        let shard = self.shard_of(&key);
+        Arc::make_mut(&mut self.shards[shard]).insert(key, entry);
+    }
+
+    // Remove an entry without telling the engine
+    fn take(&mut self, key: &str) -> Option<CacheEntry> {
+        let lookup = self.prefixes.lookup(key);
+        let shard = self.shard_of(&lookup);
+        // A view shares the shard, so it is only copied when there is
+        // something to remove
+        if !self.shards[shard].contains_key(&lookup) {
+            return None;
+        }
+        let (stored, entry) = Arc::make_mut(&mut self.shards[shard]).remove_entry(&lookup)?;
+        let bytes = entry_memory(key, &entry);
+        self.used_memory -= bytes;
+        self.meter(key, bytes, false);
+        let stored = match entry.expires_at {
+            Some(at) => {
+                let expiry = (at, stored);
+                self.expiries.remove(&expiry);
+                expiry.1
+            }
+            None => stored,
+        };
+        self.release(&stored);
+        Some(entry)
+    }
+
+    // Forget the prefix of a removed key once no other key holds it. The
+    // keys of a view hold theirs too, so what they kept is pruned once the
+    // last view is gone.
+    fn release(&mut self, key: &Key) {
+        self.prefixes.release(key);
+        if Arc::strong_count(&self.views) > 1 {
+            self.stale_prefixes = true;
+        }
+    }
+
+    fn compact_if_wanted(&mut self) {
+        if self.engine.wants_compaction() {
+            let entries = self.shards.iter_mut().flat_map(|shard| Arc::make_mut(shard).iter_mut()).collect();
+            self.engine.compact(entries);
+        }
+    }
+
+    // Remove every entry, as a replica does before loading its primary's
+    // keyspace, returning them so the caller decides where they are freed
+    pub fn clear(&mut self) -> Vec<Arc<Entries>> {
+        self.expiries.clear();
+        self.prefixes.clear();
+        self.used_memory = 0;
+        for usage in &mut self.usage {
+            usage.keys = 0;
+            usage.bytes = 0;
+        }
+        self.engine.clear();
+        std::mem::replace(&mut self.shards, empty_shards())
+    }
+
+    // Set or clear the expiry of a live key; returns false when the key is missing
+    pub fn set_expiry(&mut self, key: &str, expires_at: Option<u64>) -> bool {
+        let now = now_ms();
+        let lookup = self.prefixes.lookup(key);
+        let shard = self.shard_of(&lookup);
+        if self.shards[shard].get(&lookup).is_none_or(|entry| entry.is_expired(now)) {
+            return false;
+        }
+        let Some((stored, entry)) = Arc::make_mut(&mut self.shards[shard]).get_key_value_mut(&lookup) else {
+            return false;
+        };
+        if let Some(at) = entry.expires_at {
+            self.expiries.remove(&(at, stored.clone()));
+        }
+        entry.expires_at = expires_at;
+        if let Some(at) = expires_at {
+            self.expiries.insert((at, stored.clone()));
+        }
+        self.engine.expire(key, expires_at);
+        true
+    }
+
+    // Set or clear the soft TTL of a live key; returns false when the key is missing
+    pub fn set_soft_expiry(&mut self, key: &str, stale_at: Option<u64>) -> bool {
+        let now = now_ms();
+        let lookup = self.prefixes.lookup(key);
+        let shard = self.shard_of(&lookup);
+        if self.shards[shard].get(&lookup).is_none_or(|entry| entry.is_expired(now)) {
+            return false;
+        }
+        let Some(entry) = Arc::make_mut(&mut self.shards[shard]).get_mut(&lookup) else {
+            return false;
+        };
+        entry.stale_at = stale_at;
+        entry.access.stale_announced.store(false, Ordering::Relaxed);
+        self.engine.soft_expire(key, stale_at);
+        true
+    }
+
+    // Remove every expired entry, returning them with their keys. Only the
+    // entries that are due are visited, so this is cheap to call often.
+    pub fn purge_expired(&mut self) -> Vec<(String, CacheEntry)> {
+        let now = now_ms();
+        let mut removed = Vec::new();
+        while self.expiries.first().is_some_and(|(at, _)| *at <= now) {
+            let Some((_, key)) = self.expiries.pop_first() else {
+                break;
+            };
+            let shard = self.shard_of(&key);
+            if let Some(entry) = Arc::make_mut(&mut self.shards[shard]).remove(&key) {
+                self.release(&key);
+                let key = key.to_string();
+                let bytes = entry_memory(&key, &entry);
+                self.used_memory -= bytes;
+                self.meter(&key, bytes, false);
+                self.engine.delete(&key, &entry);
+                removed.push((key, entry));
+            }
+        }
+        self.compact_if_wanted();
+        removed
+    }
+
+    // Up to `count` keys picked at random, with their entries, for eviction
+    // to choose among
+    pub fn sample(&self, count: usize) -> Vec<(String, &CacheEntry)> {
+        let mut sampled = Vec::with_capacity(count);
+        if self.len() <= count {
+            sampled.extend(self.iter().map(|(key, entry)| (key.text().into_owned(), entry)));
+            return sampled;
+        }
+        // Empty shards are tried a few times only, the first keys found
+        // standing in when the keyspace is too sparse to hit them
+        let mut rng = rand::thread_rng();
+        for _ in 0..count * 4 {
+            if sampled.len() == count {
+                return sampled;
+            }
+            let shard = &self.shards[rng.gen_range(0..SHARDS)];
+            if let Some((key, entry)) = shard.iter().nth(rng.gen_range(0..shard.len().max(1))) {
+                sampled.push((key.text().into_owned(), entry));
+            }
+        }
+        if sampled.is_empty() {
+            sampled.extend(self.iter().take(count).map(|(key, entry)| (key.text().into_owned(), entry)));
+        }
+        sampled
+    }
+
+    // The key whose expiry comes first, if any key has one
+    pub fn next_expiring(&self) -> Option<String> {
+        self.expiries.first().map(|(_, key)| key.text().into_owned())
+    }
+
+    // Move a value into a fresh allocation of exactly its size, returning
+    // the number of bytes moved; inline values have none to move
+    pub fn reallocate(&mut self, key: &str) -> Option<usize> {
+        let lookup = self.prefixes.lookup(key);
+        let shard = self.shard_of(&lookup);
+        if self.shards[shard].get(&lookup)?.data.is_inline() {
+            return Some(0);
+        }
+        let entry = Arc::make_mut(&mut self.shards[shard]).get_mut(&lookup)?;
+        entry.data = Value::from(Bytes::copy_from_slice(&entry.data));
+        Some(entry.data.len())
+    }
+
+    // Shrink the hash tables to the size the current entries need. Those a
+    // view holds are left alone: the copy a write makes of one is tight.
+    pub fn shrink_to_fit(&mut self) {
+        for shard in self.shards.iter_mut().filter_map(Arc::get_mut) {
+            shard.shrink_to_fit();
+        }
+    }
+
+    // Live keys matching a glob pattern, sorted
+    pub fn keys_matching(&self, pattern: &str) -> Vec<String> {
+        self.keys_matching_unless(pattern, || false).unwrap_or_default()
+    }
+
+    // Live keys matching a glob pattern, sorted, or None when `stop`, asked
+    // every so many entries, gave up on the scan
+    pub fn keys_matching_unless(&self, pattern: &str, stop: impl Fn() -> bool) -> Option<Vec<String>> {
+        let now = now_ms();
+        let mut keys = Vec::new();
+        for (scanned, (key, entry)) in self.iter().enumerate() {
+            if scanned % SCAN_CHECK_INTERVAL == 0 && stop() {
+                return None;
+            }
+            if entry.is_expired(now) {
+                continue;
+            }
+            let key = key.text();
+            if glob_match(pattern.as_bytes(), key.as_bytes()) {
+                keys.push(key.into_owned());
+            }
+        }
+        keys.sort();
+        Some(keys)
+    }
+}
+
+// Glob matching as KEYS does it: `*` matches any run of bytes, `?` one byte,
+// `[abc]`, `[^abc]` and `[a-z]` one byte from a set, and `\` escapes the next byte
+pub fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    // Where to retry after the last `*` when the rest fails to match
+    let mut star: Option<(usize, usize)> = None;
+    let (mut p, mut t) = (0, 0);
+    while t < text.len() {
+        let step = match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p, t));
+                p += 1;
+                continue;
+            }
+            Some(b'?') => Some(p + 1),
+            Some(b'[') => match_class(pattern, p, text[t]),
+            Some(b'\\') if p + 1 < pattern.len() => (pattern[p + 1] == text[t]).then_some(p + 2),
+            Some(&c) => (c == text[t]).then_some(p + 1),
+            None => None,
+        };
+        match (step, star) {
+            (Some(next), _) => {
+                p = next;
+                t += 1;
+            }
+            // Let the last `*` swallow one more byte and try again
+            (None, Some((star_p, star_t))) => {
+                star = Some((star_p, star_t + 1));
+                p = star_p + 1;
+                t = star_t + 1;
+            }
+            (None, None) => return false,
+        }
+    }
+    pattern[p.min(pattern.len())..].iter().all(|&c| c == b'*')
+}
+
+// Match one byte against the `[...]` class starting at `pattern[start]`,
+// returning the index after the class when it matches
+fn match_class(pattern: &[u8], start: usize, byte: u8) -> Option<usize> {
+    let mut i = start + 1;
+    let negate = pattern.get(i) == Some(&b'^');
+    if negate {
+        i += 1;
+    }
+    let mut matched = false;
+    while i < pattern.len() && pattern[i] != b']' {
+        if pattern[i] == b'\\' && i + 1 < pattern.len() {
+            i += 1;
+            matched |= pattern[i] == byte;
+        } else if i + 2 < pattern.len() && pattern[i + 1] == b'-' && pattern[i + 2] != b']' {
+            let (low, high) = (pattern[i].min(pattern[i + 2]), pattern[i].max(pattern[i + 2]));
+            matched |= (low..=high).contains(&byte);
+            i += 2;
+        } else {
+            matched |= pattern[i] == byte;
+        }
+        i += 1;
+    }
+    // An unclosed class is taken literally as far as it goes
+    (matched != negate).then_some(i + 1)
+}
+
+// Compress data using zstd
+pub fn compress_data(data: &[u8]) -> Result<Bytes, ServerError> {
+    let compressed = zstd::encode_all(data, 3)
+        .map_err(|e| ServerError::Compression(e.to_string()))?;
+    Ok(Bytes::from(compressed))
+}
+
+// Decompress data using zstd
+pub fn decompress_data(data: &[u8]) -> Result<Vec<u8>, ServerError> {
+    let decompressed = zstd::decode_all(data)
+        .map_err(|e| ServerError::Compression(e.to_string()))?;
+    Ok(decompressed)
+}
+
+// Build a cache entry, keeping the raw bytes when compression doesn't pay off
+pub fn encode_entry(value: Vec<u8>) -> Result<CacheEntry, ServerError> {
+    let compressed = compress_data(&value)?;
+    if compressed.len() < value.len() {
+        Ok(CacheEntry::new(compressed, true, false))
+    } else {
+        Ok(CacheEntry::new(Bytes::from(value), false, false))
+    }
+}
+
+// Get the stored value, checking it against its checksum; uncompressed
+// entries are shared without copying, unless they are inline
+pub fn entry_value(entry: &CacheEntry) -> Result<Bytes, ServerError> {
+    entry.verify()?;
+    if entry.compressed {
+        Ok(Bytes::from(decompress_data(&entry.data)?))
+    } else {
+        Ok(entry.data.to_bytes())
+    }
+}
+
+// Format of the blobs written by `dump_entry`; version 1 blobs lack the stamp,
+// version 2 blobs are never CRDTs, version 3 blobs lack the value checksum,
+// version 4 blobs never have a soft TTL, version 5 blobs are never tombstones
+// and version 6 blobs are always visible
+const DUMP_VERSION: u8 = 7;
+const DUMP_COMPRESSED: u8 = 0b01;
+const DUMP_HAS_TTL: u8 = 0b10;
+const DUMP_CRDT: u8 = 0b100;
+const DUMP_HAS_SOFT_TTL: u8 = 0b1000;
+const DUMP_TOMBSTONE: u8 = 0b10000;
+const DUMP_HAS_VISIBILITY: u8 = 0b100000;
+
+// Serialize an entry for DUMP: a version byte, flags, the entry's write stamp,
+// its value checksum, the remaining time to live in milliseconds when the
+// entry expires, the same until it goes stale when it has a soft TTL, the
+// unix time in milliseconds it becomes visible when it is scheduled, the
+// stored bytes as they are and a CRC16 of everything before it. The TTL is
+// relative so the blob can be restored on a node whose clock
+// differs; the value checksum goes along so bytes that rotted before the dump
+// are caught where they are restored.
+pub fn dump_entry(entry: &CacheEntry, now_ms: u64) -> Bytes {
+    let mut flags = 0;
+    if entry.compressed {
+        flags |= DUMP_COMPRESSED;
+    }
+    if entry.crdt {
+        flags |= DUMP_CRDT;
+    }
+    if entry.tombstone {
+        flags |= DUMP_TOMBSTONE;
+    }
+    let mut blob = Vec::with_capacity(entry.data.len() + 40);
+    blob.push(DUMP_VERSION);
+    blob.push(0);
+    blob.extend_from_slice(&entry.stamp.at.to_be_bytes());
+    blob.extend_from_slice(&entry.stamp.version.to_be_bytes());
+    blob.extend_from_slice(&entry.checksum.to_be_bytes());
+    if let Some(at) = entry.expires_at {
+        flags |= DUMP_HAS_TTL;
+        blob.extend_from_slice(&at.saturating_sub(now_ms).to_be_bytes());
+    }
+    if let Some(at) = entry.stale_at {
+        flags |= DUMP_HAS_SOFT_TTL;
+        blob.extend_from_slice(&at.saturating_sub(now_ms).to_be_bytes());
+    }
+    if let Some(at) = entry.visible_at {
+        flags |= DUMP_HAS_VISIBILITY;
+        blob.extend_from_slice(&at.to_be_bytes());
+    }
+    blob[1] = flags;
+    blob.extend_from_slice(&entry.data);
+    let crc = crate::cluster::crc16(&blob);
+    blob.extend_from_slice(&crc.to_be_bytes());
+    Bytes::from(blob)
+}
+
+// Rebuild an entry from a DUMP blob, checking its version and checksum
+pub fn restore_entry(blob: &[u8], now_ms: u64) -> Result<CacheEntry, ServerError> {
+    let invalid = |reason: &str| ServerError::InvalidArgument(format!("Invalid DUMP payload: {}", reason));
+    if blob.len() < 4 {
+        return Err(invalid("too short"));
+    }
+    let (body, crc) = blob.split_at(blob.len() - 2);
+    if crate::cluster::crc16(body).to_be_bytes() != crc {
+        return Err(invalid("checksum mismatch"));
+    }
+    if !(1..=DUMP_VERSION).contains(&body[0]) {
+        return Err(invalid(&format!("unsupported version {}", body[0])));
+    }
+    let flags = body[1];
+    let mut data = &body[2..];
+    let mut stamp = WriteStamp::default();
+    if body[0] >= 2 {
+        let Some((fields, rest)) = data.split_first_chunk::<16>() else {
+            return Err(invalid("too short"));
+        };
+        let (at, version) = fields.split_at(8);
+        stamp = WriteStamp {
+            at: u64::from_be_bytes(at.try_into().expect("8 bytes")),
+            version: u64::from_be_bytes(version.try_into().expect("8 bytes")),
+        };
+        data = rest;
+    }
+    let mut checksum = None;
+    if body[0] >= 4 {
+        let Some((value_checksum, rest)) = data.split_first_chunk::<4>() else {
+            return Err(invalid("too short"));
+        };
+        checksum = Some(u32::from_be_bytes(*value_checksum));
+        data = rest;
+    }
+    let mut expires_at = None;
+    if flags & DUMP_HAS_TTL != 0 {
+        let Some((ttl, rest)) = data.split_first_chunk::<8>() else {
+            return Err(invalid("too short"));
+        };
+        expires_at = Some(now_ms + u64::from_be_bytes(*ttl));
+        data = rest;
+    }
+    let mut stale_at = None;
+    if flags & DUMP_HAS_SOFT_TTL != 0 {
+        let Some((soft_ttl, rest)) = data.split_first_chunk::<8>() else {
+            return Err(invalid("too short"));
+        };
+        stale_at = Some(now_ms + u64::from_be_bytes(*soft_ttl));
+        data = rest;
+    }
+    let mut visible_at = None;
+    if flags & DUMP_HAS_VISIBILITY != 0 {
+        let Some((at, rest)) = data.split_first_chunk::<8>() else {
+            return Err(invalid("too short"));
+        };
+        visible_at = Some(u64::from_be_bytes(*at));
+        data = rest;
+    }
+    let mut entry = CacheEntry::new(Bytes::copy_from_slice(data), flags & DUMP_COMPRESSED != 0, flags & DUMP_CRDT != 0);
+    if let Some(checksum) = checksum {
+        entry.checksum = checksum;
+        entry.verify()?;
+    }
+    entry.expires_at = expires_at;
+    entry.stale_at = stale_at;
+    entry.visible_at = visible_at;
+    entry.tombstone = flags & DUMP_TOMBSTONE != 0;
+    entry.stamp = stamp;
+    Ok(entry)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, text: &str) -> bool {
+        glob_match(pattern.as_bytes(), text.as_bytes())
+    }
+
+    #[test]
+    fn glob_wildcards() {
+        assert!(matches("*", ""));
+        assert!(matches("*", "anything"));
+        assert!(matches("user:*", "user:1"));
+        assert!(!matches("user:*", "users:1"));
+        assert!(matches("h?llo", "hello"));
+        assert!(!matches("h?llo", "hllo"));
+        assert!(matches("*a*b*", "xxaxxbxx"));
+        assert!(!matches("*a*b", "xxbxxa"));
+        // The last `*` backtracks over a false start
+        assert!(matches("*ab", "aab"));
+        assert!(matches("a*b*c", "abbbc"));
+        assert!(!matches("abc", "abcd"));
+    }
+
+    #[test]
+    fn glob_classes() {
+        assert!(matches("h[ae]llo", "hallo"));
+        assert!(!matches("h[ae]llo", "hillo"));
+        assert!(matches("h[^e]llo", "hallo"));
+        assert!(!matches("h[^e]llo", "hello"));
+        assert!(matches("key[0-9]", "key7"));
+        assert!(!matches("key[0-9]", "keyx"));
+    }
+
+    #[test]
+    fn glob_escapes() {
+        assert!(matches("a\\*b", "a*b"));
+        assert!(!matches("a\\*b", "axb"));
+        assert!(matches("a\\?", "a?"));
+        assert!(!matches("a\\?", "ab"));
+    }
+}
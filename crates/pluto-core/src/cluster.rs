@@ -0,0 +1,510 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::Write;
+use serde::{Deserialize, Serialize};
+use rand::Rng;
+use chrono::{DateTime, Utc};
+use log::warn;
+use crate::merkle::{fnv1a, FNV_OFFSET};
+
+// Cluster state and slot management
+pub const TOTAL_SLOTS: usize = 16384;
+const CLUSTER_FILE: &str = "cluster.json";
+// Layout version of the cluster file; files without one use the legacy slot map layout
+const CLUSTER_FILE_VERSION: u32 = 2;
+// Points a node of weight 1 places on the ring in ring mode
+pub const VNODES_PER_WEIGHT: u32 = 64;
+
+// How slots are spread over the members. `Slots` splits them into one even
+// range per node, so a join or leave shifts most of them. `Ring` hashes every
+// slot onto a ring of virtual nodes and gives it to the next node along, so a
+// change only moves the slots next to the points that came or went.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Hashing {
+    #[default]
+    Slots,
+    Ring,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NodeSlots {
+    pub node_id: String,
+    pub address: String,
+    pub slot_range: (usize, usize),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClusterData {
+    pub timestamp: DateTime<Utc>,
+    pub nodes: Vec<NodeSlots>,
+    #[serde(default)]
+    pub epoch: u64,
+    #[serde(default)]
+    pub health: BTreeMap<String, NodeHealth>, // member address -> health as the reporting node sees it
+    #[serde(default)]
+    pub migrating: BTreeMap<usize, String>, // slots the reporting node is moving out, and where to
+    #[serde(default)]
+    pub importing: BTreeMap<usize, String>, // slots the reporting node is taking in, and from where
+}
+
+// Whether a member answers heartbeats. `Suspected` is one node's opinion;
+// `Failed` means a majority of the members suspect it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NodeHealth {
+    #[default]
+    Online,
+    Suspected,
+    Failed,
+}
+
+// Everything a node needs to come back with the same identity and slots
+#[derive(Debug, Serialize, Deserialize)]
+struct ClusterFile {
+    version: u32,
+    epoch: u64,
+    updated: DateTime<Utc>,
+    members: Vec<String>,
+    node_ids: HashMap<String, String>,
+    #[serde(default)]
+    weights: HashMap<String, u32>,
+    slot_map: Vec<NodeSlots>,
+}
+
+// A change to cluster membership, agreed on through the metadata log
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MetaCommand {
+    AddNode {
+        address: String,
+        node_id: String,
+        #[serde(default = "default_weight")]
+        weight: u32, // share of the ring in ring mode
+        // Whether the slots are redistributed as soon as the node is added;
+        // a join instead hands the slots over once their keys are copied
+        #[serde(default = "default_rebalance")]
+        rebalance: bool,
+    },
+    RemoveNode { address: String },
+    // Give slots to a member, or take them from every member when `address`
+    // is None. Lasts until the next membership change rebalances the slots.
+    AssignSlots { slots: Vec<usize>, address: Option<String> },
+    // Appended by a new leader so entries of earlier terms get committed
+    Noop,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClusterState {
+    pub nodes: Vec<String>, // list of node addresses
+    pub node_ids: std::collections::HashMap<String, String>, // address -> node_id mapping
+    pub slot_map: Vec<NodeSlots>, // slot assignments
+    pub last_updated: DateTime<Utc>, // when cluster was last modified
+    #[serde(default)]
+    pub epoch: u64, // index of the last metadata log entry applied
+    #[serde(skip)]
+    pub cluster_enabled: bool, // whether clustering is enabled
+    #[serde(skip)]
+    pub self_addr: String, // public address of this node
+    #[serde(default)]
+    pub weights: HashMap<String, u32>, // address -> ring weight
+    #[serde(skip)]
+    pub hashing: Hashing, // how slots are assigned, the same on every member
+    #[serde(skip)]
+    pub self_weight: u32, // ring weight this node joins with
+    #[serde(skip)]
+    pub health: HashMap<String, NodeHealth>, // members that missed heartbeats; the rest are online
+    #[serde(skip)]
+    pub migrating: BTreeMap<usize, String>, // slots of this node whose keys move to another node
+    #[serde(skip)]
+    pub importing: BTreeMap<usize, String>, // slots this node takes keys of before owning them
+}
+
+fn default_rebalance() -> bool {
+    true
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+// Slot a key belongs to: CRC16 (XMODEM) of the key modulo the slot count. When
+// the key holds a non-empty `{tag}`, only the tag is hashed so related keys
+// can be kept on the same node.
+pub fn key_slot(key: &str) -> usize {
+    let bytes = key.as_bytes();
+    let hashed = match bytes.iter().position(|&b| b == b'{') {
+        Some(open) => match bytes[open + 1..].iter().position(|&b| b == b'}') {
+            Some(len) if len > 0 => &bytes[open + 1..open + 1 + len],
+            _ => bytes,
+        },
+        None => bytes,
+    };
+    crc16(hashed) as usize % TOTAL_SLOTS
+}
+
+// SplitMix64 finalizer, spreading nearby inputs over the whole ring
+fn mix64(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9e3779b97f4a7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+pub(crate) fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+impl ClusterState {
+    pub fn new(self_addr: String, cluster_enabled: bool, hashing: Hashing, self_weight: u32) -> Self {
+        // Try to load existing cluster state first; the metadata log decides
+        // whether it is still current
+        if cluster_enabled
+            && let Ok(mut existing_state) = Self::load_from_cluster_file() {
+            existing_state.cluster_enabled = cluster_enabled;
+            existing_state.self_addr = self_addr;
+            existing_state.hashing = hashing;
+            existing_state.self_weight = self_weight;
+            return existing_state;
+        }
+        
+        // No existing cluster state, create new one
+        let node_id = Self::generate_node_id();
+        let mut node_ids = std::collections::HashMap::new();
+        node_ids.insert(self_addr.clone(), node_id);
+        
+        let mut state = ClusterState {
+            nodes: vec![self_addr.clone()],
+            node_ids,
+            slot_map: vec![],
+            last_updated: Utc::now(),
+            epoch: 0,
+            cluster_enabled,
+            weights: HashMap::from([(self_addr.clone(), self_weight)]),
+            self_addr,
+            hashing,
+            self_weight,
+            health: HashMap::new(),
+            migrating: BTreeMap::new(),
+            importing: BTreeMap::new(),
+        };
+        state.rebalance_slots();
+        if cluster_enabled {
+            state.write_cluster_file();
+        }
+        state
+    }
+
+    pub fn generate_node_id() -> String {
+        let mut rng = rand::thread_rng();
+        let chars: Vec<char> = "abcdefghijklmnopqrstuvwxyz0123456789".chars().collect();
+        (0..7)
+            .map(|_| chars[rng.gen_range(0..chars.len())])
+            .collect()
+    }
+
+    pub fn load_from_cluster_file() -> Result<Self, Box<dyn std::error::Error>> {
+        let content = fs::read_to_string(CLUSTER_FILE)?;
+        let value: serde_json::Value = serde_json::from_str(&content)?;
+        if value.get("version").is_none() {
+            return Self::from_legacy_file(serde_json::from_value(value)?);
+        }
+        let file: ClusterFile = serde_json::from_value(value)?;
+        if file.version != CLUSTER_FILE_VERSION {
+            return Err(format!("unsupported cluster file version {}", file.version).into());
+        }
+        Ok(ClusterState {
+            nodes: file.members,
+            node_ids: file.node_ids,
+            slot_map: file.slot_map,
+            last_updated: file.updated,
+            epoch: file.epoch,
+            cluster_enabled: true,
+            self_addr: String::new(),
+            weights: file.weights,
+            hashing: Hashing::default(),
+            self_weight: default_weight(),
+            health: HashMap::new(),
+            migrating: BTreeMap::new(),
+            importing: BTreeMap::new(),
+        })
+    }
+
+    // Files written before the versioned layout only hold the slot map
+    fn from_legacy_file(cluster_data: ClusterData) -> Result<Self, Box<dyn std::error::Error>> {
+        // Extract unique node addresses and IDs from slot map
+        let mut nodes: Vec<String> = Vec::new();
+        let mut node_ids = std::collections::HashMap::new();
+        
+        for ns in &cluster_data.nodes {
+            if !nodes.contains(&ns.address) {
+                nodes.push(ns.address.clone());
+                node_ids.insert(ns.address.clone(), ns.node_id.clone());
+            }
+        }
+        nodes.sort();
+        
+        Ok(ClusterState {
+            nodes,
+            node_ids,
+            slot_map: cluster_data.nodes,
+            last_updated: cluster_data.timestamp,
+            epoch: cluster_data.epoch,
+            cluster_enabled: true,
+            self_addr: String::new(),
+            weights: HashMap::new(),
+            hashing: Hashing::default(),
+            self_weight: default_weight(),
+            health: HashMap::new(),
+            migrating: BTreeMap::new(),
+            importing: BTreeMap::new(),
+        })
+    }
+
+    pub fn rebalance_slots(&mut self) {
+        self.slot_map.clear();
+        if self.nodes.is_empty() { return; }
+        match self.hashing {
+            Hashing::Slots => self.assign_ranges(),
+            Hashing::Ring => self.assign_ring(),
+        }
+        self.last_updated = Utc::now();
+        self.epoch += 1;
+    }
+
+    // One contiguous range per node, in address order
+    fn assign_ranges(&mut self) {
+        let n = self.nodes.len();
+        let base = TOTAL_SLOTS / n;
+        let extra = TOTAL_SLOTS % n;
+        let mut slots = 0;
+        // The first 'extra' nodes get (base+1) slots, the rest get 'base' slots
+        for (i, addr) in self.nodes.iter().enumerate() {
+            let count = if i < extra { base + 1 } else { base };
+            let start = slots;
+            let end = slots + count - 1;
+            let node_id = self.node_ids.get(addr).cloned().unwrap_or_else(Self::generate_node_id);
+            self.slot_map.push(NodeSlots {
+                node_id,
+                address: addr.clone(),
+                slot_range: (start, end),
+            });
+            slots += count;
+        }
+        // Ensure all slots are covered
+        assert_eq!(slots, TOTAL_SLOTS);
+    }
+
+    // Every slot goes to the first virtual node at or after its point on the
+    // ring. Slots sit evenly spaced around the ring in order, so each arc
+    // between two virtual nodes becomes one range.
+    fn assign_ring(&mut self) {
+        let ring = self.ring();
+        let spacing = u64::MAX / TOTAL_SLOTS as u64 + 1;
+        let owner_of = |slot: usize| {
+            let point = slot as u64 * spacing;
+            let at = ring.partition_point(|(vnode, _)| *vnode < point) % ring.len();
+            ring[at].1
+        };
+        let mut start = 0;
+        let mut owner = owner_of(0);
+        for slot in 1..=TOTAL_SLOTS {
+            let next = if slot < TOTAL_SLOTS { Some(owner_of(slot)) } else { None };
+            if next != Some(owner) {
+                let address = self.nodes[owner].clone();
+                let node_id = self.node_ids.get(&address).cloned().unwrap_or_else(Self::generate_node_id);
+                self.slot_map.push(NodeSlots { node_id, address, slot_range: (start, slot - 1) });
+                if let Some(next) = next {
+                    start = slot;
+                    owner = next;
+                }
+            }
+        }
+    }
+
+    // Points of every member's virtual nodes, sorted, each with the member's
+    // index in `nodes`. A point depends only on the node id and its number,
+    // so members keep their points as others come and go.
+    fn ring(&self) -> Vec<(u64, usize)> {
+        let mut ring = Vec::new();
+        for (index, address) in self.nodes.iter().enumerate() {
+            let id = self.node_ids.get(address).map_or(address.as_str(), String::as_str);
+            let seed = fnv1a(FNV_OFFSET, id.as_bytes());
+            let weight = self.weights.get(address).copied().unwrap_or(1).max(1);
+            for vnode in 0..weight * VNODES_PER_WEIGHT {
+                ring.push((mix64(seed ^ mix64(vnode as u64)), index));
+            }
+        }
+        ring.sort_unstable();
+        ring
+    }
+
+    // Persist the full cluster state. The file is written under a temporary
+    // name, synced and renamed over the old one, so a crash leaves either the
+    // previous state or the new one on disk, never a torn file.
+    pub fn write_cluster_file(&self) {
+        if !self.cluster_enabled {
+            return;
+        }
+        if let Err(e) = self.try_write_cluster_file() {
+            warn!("Failed to write {}: {}", CLUSTER_FILE, e);
+        }
+    }
+
+    fn try_write_cluster_file(&self) -> std::io::Result<()> {
+        let file = ClusterFile {
+            version: CLUSTER_FILE_VERSION,
+            epoch: self.epoch,
+            updated: self.last_updated,
+            members: self.nodes.clone(),
+            node_ids: self.node_ids.clone(),
+            weights: self.weights.clone(),
+            slot_map: self.slot_map.clone(),
+        };
+        let json = serde_json::to_string_pretty(&file)?;
+        let tmp_path = format!("{}.tmp", CLUSTER_FILE);
+        let mut tmp = fs::File::create(&tmp_path)?;
+        tmp.write_all(json.as_bytes())?;
+        tmp.sync_all()?;
+        fs::rename(&tmp_path, CLUSTER_FILE)
+    }
+
+    // Apply a committed metadata log entry. Every node applies the same
+    // entries in the same order, so they all arrive at the same slot map.
+    pub fn apply(&mut self, command: &MetaCommand, index: u64) {
+        match command {
+            MetaCommand::AddNode { address, node_id, weight, rebalance } => {
+                self.node_ids.insert(address.clone(), node_id.clone());
+                self.weights.insert(address.clone(), *weight);
+                if !self.nodes.contains(address) {
+                    self.nodes.push(address.clone());
+                    self.nodes.sort(); // keep order stable for slot assignment
+                }
+                if *rebalance || self.slot_map.is_empty() {
+                    self.rebalance_slots();
+                } else {
+                    self.last_updated = Utc::now();
+                }
+            }
+            MetaCommand::RemoveNode { address } => {
+                self.nodes.retain(|node| node != address);
+                self.node_ids.remove(address);
+                self.weights.remove(address);
+                if self.nodes.is_empty() {
+                    self.slot_map.clear();
+                    self.last_updated = Utc::now();
+                } else {
+                    self.rebalance_slots();
+                }
+            }
+            MetaCommand::AssignSlots { slots, address } => {
+                // A member removed since the entry was proposed gets nothing
+                if address.as_ref().is_none_or(|address| self.nodes.contains(address)) {
+                    self.assign_slots(slots, address.as_deref());
+                }
+            }
+            MetaCommand::Noop => {}
+        }
+        self.epoch = index;
+        self.write_cluster_file();
+    }
+
+    // Set the owner of individual slots, merging the result back into ranges.
+    // A slot changing hands is no longer migrating or importing.
+    fn assign_slots(&mut self, slots: &[usize], address: Option<&str>) {
+        let mut owners: Vec<Option<String>> = vec![None; TOTAL_SLOTS];
+        for node in &self.slot_map {
+            let (start, end) = node.slot_range;
+            for owner in &mut owners[start..=end.min(TOTAL_SLOTS - 1)] {
+                *owner = Some(node.address.clone());
+            }
+        }
+        for slot in slots.iter().filter(|slot| **slot < TOTAL_SLOTS) {
+            owners[*slot] = address.map(str::to_string);
+            self.migrating.remove(slot);
+            self.importing.remove(slot);
+        }
+        self.slot_map.clear();
+        let mut start = 0;
+        for slot in 1..=TOTAL_SLOTS {
+            if slot < TOTAL_SLOTS && owners[slot] == owners[start] {
+                continue;
+            }
+            if let Some(address) = &owners[start] {
+                let node_id = self.node_ids.get(address).cloned().unwrap_or_else(Self::generate_node_id);
+                self.slot_map.push(NodeSlots { node_id, address: address.clone(), slot_range: (start, slot - 1) });
+            }
+            start = slot;
+        }
+        self.last_updated = Utc::now();
+    }
+
+    // Forget every member before the metadata log is replayed from the start
+    pub fn reset(&mut self) {
+        self.nodes.clear();
+        self.node_ids.clear();
+        self.weights.clear();
+        self.slot_map.clear();
+        self.epoch = 0;
+        self.last_updated = Utc::now();
+    }
+
+    // Node that owns a slot; the slot map is kept sorted by range
+    pub fn slot_owner(&self, slot: usize) -> Option<&NodeSlots> {
+        let at = self.slot_map.partition_point(|node| node.slot_range.1 < slot);
+        self.slot_map.get(at).filter(|node| node.slot_range.0 <= slot)
+    }
+
+    pub fn get_cluster_json(&self) -> String {
+        let cluster_data = self.get_cluster_data();
+        serde_json::to_string_pretty(&cluster_data).unwrap_or_else(|_| "{}".to_string())
+    }
+
+    pub fn get_cluster_data(&self) -> ClusterData {
+        ClusterData {
+            timestamp: self.last_updated,
+            nodes: self.slot_map.clone(),
+            epoch: self.epoch,
+            health: self.nodes.iter()
+                .map(|node| (node.clone(), self.health.get(node).copied().unwrap_or_default()))
+                .collect(),
+            migrating: self.migrating.clone(),
+            importing: self.importing.clone(),
+        }
+    }
+} 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_slot_is_crc16_modulo_the_slot_count() {
+        assert_eq!(key_slot("123456789"), 0x31c3);
+        assert_eq!(key_slot("foo"), 12182);
+        assert!(key_slot("") < TOTAL_SLOTS);
+    }
+
+    #[test]
+    fn key_slot_hashes_only_the_tag() {
+        assert_eq!(key_slot("{user1000}.following"), key_slot("user1000"));
+        assert_eq!(key_slot("{user1000}.following"), key_slot("{user1000}.followers"));
+        // Only the first tag counts
+        assert_eq!(key_slot("foo{bar}{zap}"), key_slot("bar"));
+        assert_eq!(key_slot("foo{{bar}}zap"), key_slot("{bar"));
+    }
+
+    #[test]
+    fn key_slot_hashes_the_whole_key_without_a_tag() {
+        // An empty or unclosed tag doesn't count as one
+        assert_eq!(key_slot("foo{}bar"), crc16(b"foo{}bar") as usize % TOTAL_SLOTS);
+        assert_eq!(key_slot("foo{bar"), crc16(b"foo{bar") as usize % TOTAL_SLOTS);
+        assert_eq!(key_slot("{}{bar}"), crc16(b"{}{bar}") as usize % TOTAL_SLOTS);
+    }
+}
@@ -0,0 +1,292 @@
+use serde::{Deserialize, Serialize};
+use crate::protocol::{Command, Response};
+use crate::cache::ServerError;
+
+// Version of the native protocol reported by HELLO
+pub const PROTOCOL_VERSION: u32 = 1;
+
+// Largest length-prefixed frame accepted from a client
+const MAX_FRAME_SIZE: usize = 512 * 1024 * 1024;
+
+// Position of `Response::Data` in the `Response` enum, as bincode encodes it
+const DATA_VARIANT: u32 = 2;
+
+// Wire encoding of a native protocol connection. JSON documents are
+// self-delimiting; the binary encodings prefix every frame with its length
+// as a big-endian u32.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    #[default]
+    Json,
+    Msgpack,
+    Bincode,
+}
+
+impl Encoding {
+    // Every encoding the server understands
+    pub const ALL: [Encoding; 3] = [Encoding::Json, Encoding::Msgpack, Encoding::Bincode];
+}
+
+// Outcome of parsing a connection's read buffer
+pub struct Parsed {
+    pub commands: Vec<Command>,
+    pub consumed: usize,     // bytes used by `commands`
+    pub error: Option<String>, // unrecoverable input; the rest of the buffer is dropped
+}
+
+// Parse up to `limit` complete commands from the start of the buffer; a
+// trailing partial command is left for the next read. Parsing stops after a
+// command that switches the connection's encoding so the bytes after it are
+// decoded with the new one.
+pub fn parse_commands(buf: &[u8], limit: usize, encoding: Encoding) -> Parsed {
+    match encoding {
+        Encoding::Json => parse_json(buf, limit),
+        Encoding::Msgpack | Encoding::Bincode => parse_frames(buf, limit, encoding),
+    }
+}
+
+fn parse_json(buf: &[u8], limit: usize) -> Parsed {
+    let mut parsed = Parsed { commands: Vec::new(), consumed: 0, error: None };
+    let mut stream = serde_json::Deserializer::from_slice(buf).into_iter::<Command>();
+    while parsed.commands.len() < limit {
+        match stream.next() {
+            Some(Ok(cmd)) => {
+                let switches = cmd.switches_encoding();
+                parsed.commands.push(cmd);
+                parsed.consumed = stream.byte_offset();
+                if switches {
+                    break;
+                }
+            }
+            Some(Err(e)) if e.is_eof() => break,
+            Some(Err(e)) => {
+                parsed.error = Some(e.to_string());
+                break;
+            }
+            None => {
+                parsed.consumed = stream.byte_offset();
+                break;
+            }
+        }
+    }
+    parsed
+}
+
+fn parse_frames(buf: &[u8], limit: usize, encoding: Encoding) -> Parsed {
+    let mut parsed = Parsed { commands: Vec::new(), consumed: 0, error: None };
+    while parsed.commands.len() < limit {
+        let rest = &buf[parsed.consumed..];
+        if rest.len() < 4 {
+            break;
+        }
+        let len = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+        if len > MAX_FRAME_SIZE {
+            parsed.error = Some(format!("frame of {} bytes exceeds the {} byte limit", len, MAX_FRAME_SIZE));
+            break;
+        }
+        if rest.len() < 4 + len {
+            break;
+        }
+        match decode_command(&rest[4..4 + len], encoding) {
+            Ok(cmd) => {
+                let switches = cmd.switches_encoding();
+                parsed.commands.push(cmd);
+                parsed.consumed += 4 + len;
+                if switches {
+                    break;
+                }
+            }
+            Err(e) => {
+                parsed.error = Some(e);
+                break;
+            }
+        }
+    }
+    parsed
+}
+
+// Decode a single command body (without its length prefix)
+pub fn decode_command(body: &[u8], encoding: Encoding) -> Result<Command, String> {
+    match encoding {
+        Encoding::Json => serde_json::from_slice(body).map_err(|e| e.to_string()),
+        Encoding::Msgpack => rmp_serde::from_slice(body).map_err(|e| e.to_string()),
+        Encoding::Bincode => {
+            bincode::serde::decode_from_slice(body, bincode::config::standard())
+                .map(|(cmd, _)| cmd)
+                .map_err(|e| e.to_string())
+        }
+    }
+}
+
+// Encode a single response body (without its length prefix)
+pub fn encode_response(response: &Response, encoding: Encoding) -> Result<Vec<u8>, ServerError> {
+    match encoding {
+        Encoding::Json => Ok(serde_json::to_vec(response)?),
+        Encoding::Msgpack => rmp_serde::to_vec_named(response)
+            .map_err(|e| ServerError::Encoding(e.to_string())),
+        Encoding::Bincode => bincode::serde::encode_to_vec(response, bincode::config::standard())
+            .map_err(|e| ServerError::Encoding(e.to_string())),
+    }
+}
+
+// Bytes that precede a value of `len` bytes in an encoded `Response::Data`,
+// so the value itself can be written without copying it into the body
+pub fn data_header(len: usize, encoding: Encoding) -> Result<Vec<u8>, ServerError> {
+    let mut header = Vec::new();
+    match encoding {
+        Encoding::Json => header.extend_from_slice(format!("${}\r\n", len).as_bytes()),
+        Encoding::Msgpack => {
+            // {"Data": <bin>}
+            rmp::encode::write_map_len(&mut header, 1)
+                .and_then(|_| rmp::encode::write_str(&mut header, "Data"))
+                .and_then(|_| rmp::encode::write_bin_len(&mut header, len as u32))
+                .map_err(|e| ServerError::Encoding(e.to_string()))?;
+        }
+        Encoding::Bincode => {
+            // Variant index of `Response::Data` followed by the value length
+            header = bincode::encode_to_vec((DATA_VARIANT, len as u64), bincode::config::standard())
+                .map_err(|e| ServerError::Encoding(e.to_string()))?;
+        }
+    }
+    Ok(header)
+}
+
+// Encode a single command body (without its length prefix), as a client sends it
+pub fn encode_command(cmd: &Command, encoding: Encoding) -> Result<Vec<u8>, ServerError> {
+    match encoding {
+        Encoding::Json => Ok(serde_json::to_vec(cmd)?),
+        Encoding::Msgpack => rmp_serde::to_vec_named(cmd)
+            .map_err(|e| ServerError::Encoding(e.to_string())),
+        Encoding::Bincode => bincode::serde::encode_to_vec(cmd, bincode::config::standard())
+            .map_err(|e| ServerError::Encoding(e.to_string())),
+    }
+}
+
+// Parse one response from the start of a client's read buffer, returning it
+// with the number of bytes it used, or None while it is still incomplete
+pub fn parse_response(buf: &[u8], encoding: Encoding) -> Result<Option<(Response, usize)>, String> {
+    match encoding {
+        Encoding::Json => parse_json_response(buf),
+        Encoding::Msgpack | Encoding::Bincode => {
+            if buf.len() < 4 {
+                return Ok(None);
+            }
+            let len = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
+            if len > MAX_FRAME_SIZE {
+                return Err(format!("frame of {} bytes exceeds the {} byte limit", len, MAX_FRAME_SIZE));
+            }
+            if buf.len() < 4 + len {
+                return Ok(None);
+            }
+            let body = &buf[4..4 + len];
+            let response = match encoding {
+                Encoding::Msgpack => rmp_serde::from_slice(body).map_err(|e| e.to_string())?,
+                _ => bincode::serde::decode_from_slice(body, bincode::config::standard())
+                    .map(|(response, _)| response)
+                    .map_err(|e| e.to_string())?,
+            };
+            Ok(Some((response, 4 + len)))
+        }
+    }
+}
+
+// JSON responses are documents, except values which arrive as `$<len>\r\n`
+// followed by the raw bytes
+fn parse_json_response(buf: &[u8]) -> Result<Option<(Response, usize)>, String> {
+    if buf.first() == Some(&b'$') {
+        let Some(end) = buf.windows(2).position(|w| w == b"\r\n") else {
+            return Ok(None);
+        };
+        let len: usize = std::str::from_utf8(&buf[1..end])
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .ok_or_else(|| "invalid value length".to_string())?;
+        let start = end + 2;
+        if buf.len() < start + len {
+            return Ok(None);
+        }
+        let data = bytes::Bytes::copy_from_slice(&buf[start..start + len]);
+        return Ok(Some((Response::Data(data), start + len)));
+    }
+    let mut stream = serde_json::Deserializer::from_slice(buf).into_iter::<Response>();
+    match stream.next() {
+        Some(Ok(response)) => Ok(Some((response, stream.byte_offset()))),
+        Some(Err(e)) if e.is_eof() => Ok(None),
+        Some(Err(e)) => Err(e.to_string()),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get(key: &str) -> Command {
+        Command::GET { key: key.to_string() }
+    }
+
+    fn frame(cmd: &Command, encoding: Encoding) -> Vec<u8> {
+        let body = encode_command(cmd, encoding).unwrap();
+        let mut frame = (body.len() as u32).to_be_bytes().to_vec();
+        frame.extend_from_slice(&body);
+        frame
+    }
+
+    fn keys(parsed: &Parsed) -> Vec<String> {
+        parsed.commands.iter().flat_map(|cmd| cmd.keys()).map(String::from).collect()
+    }
+
+    #[test]
+    fn json_leaves_a_partial_command_for_the_next_read() {
+        let mut buf = serde_json::to_vec(&get("a")).unwrap();
+        let second = serde_json::to_vec(&get("b")).unwrap();
+        buf.extend_from_slice(&second[..second.len() - 3]);
+        let parsed = parse_commands(&buf, usize::MAX, Encoding::Json);
+        assert_eq!(keys(&parsed), ["a"]);
+        assert_eq!(parsed.consumed, buf.len() - (second.len() - 3));
+        assert!(parsed.error.is_none());
+
+        buf.extend_from_slice(&second[second.len() - 3..]);
+        let parsed = parse_commands(&buf[parsed.consumed..], usize::MAX, Encoding::Json);
+        assert_eq!(keys(&parsed), ["b"]);
+        assert!(parsed.error.is_none());
+    }
+
+    #[test]
+    fn frames_wait_for_the_whole_length_prefix_and_body() {
+        for encoding in [Encoding::Msgpack, Encoding::Bincode] {
+            let first = frame(&get("a"), encoding);
+            let second = frame(&get("b"), encoding);
+            let mut buf = first.clone();
+            buf.extend_from_slice(&second);
+            // A cut inside the prefix, inside the body, and right after the first frame
+            for cut in [first.len() + 2, buf.len() - 1, first.len()] {
+                let parsed = parse_commands(&buf[..cut], usize::MAX, encoding);
+                assert_eq!(keys(&parsed), ["a"], "{:?} cut at {}", encoding, cut);
+                assert_eq!(parsed.consumed, first.len());
+                assert!(parsed.error.is_none());
+            }
+            let parsed = parse_commands(&buf, usize::MAX, encoding);
+            assert_eq!(keys(&parsed), ["a", "b"]);
+            assert_eq!(parsed.consumed, buf.len());
+        }
+    }
+
+    #[test]
+    fn parsing_stops_at_the_limit() {
+        let mut buf = frame(&get("a"), Encoding::Bincode);
+        buf.extend_from_slice(&frame(&get("b"), Encoding::Bincode));
+        let parsed = parse_commands(&buf, 1, Encoding::Bincode);
+        assert_eq!(keys(&parsed), ["a"]);
+        assert_eq!(parsed.consumed, buf.len() / 2);
+    }
+
+    #[test]
+    fn oversized_frames_are_refused_before_they_arrive() {
+        let buf = ((MAX_FRAME_SIZE + 1) as u32).to_be_bytes();
+        let parsed = parse_commands(&buf, usize::MAX, Encoding::Msgpack);
+        assert!(parsed.commands.is_empty());
+        assert!(parsed.error.is_some());
+    }
+}
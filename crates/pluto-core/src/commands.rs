@@ -0,0 +1,139 @@
+use crate::protocol::{CommandDoc, CommandInfo};
+
+// What COMMAND and COMMAND_DOCS report about each command. Arity counts the
+// command's name as the first word, as the CLI takes it: N takes exactly N
+// words, -N at least N.
+
+pub struct CommandSpec {
+    pub name: &'static str,
+    pub arity: i32,
+    pub flags: &'static [&'static str], // "write" or "readonly", then "admin" and "internal" where they apply
+    pub since: &'static str, // server version that added the command
+    pub usage: &'static str, // arguments after the name
+    pub summary: &'static str,
+}
+
+impl CommandSpec {
+    pub fn info(&self) -> CommandInfo {
+        CommandInfo {
+            name: self.name.to_string(),
+            arity: self.arity,
+            flags: self.flags.iter().map(|flag| flag.to_string()).collect(),
+            since: self.since.to_string(),
+        }
+    }
+
+    pub fn doc(&self) -> CommandDoc {
+        CommandDoc {
+            name: self.name.to_string(),
+            usage: self.usage.to_string(),
+            summary: self.summary.to_string(),
+            since: self.since.to_string(),
+        }
+    }
+}
+
+// The spec of a command, by name in any case
+pub fn spec(name: &str) -> Option<&'static CommandSpec> {
+    COMMANDS.iter().find(|spec| spec.name.eq_ignore_ascii_case(name))
+}
+
+const W: &[&str] = &["write"];
+const R: &[&str] = &["readonly"];
+const WA: &[&str] = &["write", "admin"];
+const RA: &[&str] = &["readonly", "admin"];
+const RI: &[&str] = &["readonly", "internal"];
+
+const V1: &str = "1.0.0";
+
+pub const COMMANDS: &[CommandSpec] = &[
+    // Keys
+    CommandSpec { name: "SET", arity: -3, flags: W, since: V1, usage: "key value [VISIBLE_AT seconds | PVISIBLE_AT milliseconds]", summary: "Set a key, optionally hidden from reads until a unix time" },
+    CommandSpec { name: "GET", arity: 2, flags: R, since: V1, usage: "key", summary: "Read a key, replying Nil when it doesn't exist" },
+    CommandSpec { name: "GETSET", arity: 3, flags: W, since: V1, usage: "key value", summary: "Set a key and reply with the value it replaced" },
+    CommandSpec { name: "GET_OR_LOCK", arity: -3, flags: R, since: V1, usage: "key lock_ms [wait_ms]", summary: "Read a key, or take its lock to compute it when it's missing" },
+    CommandSpec { name: "UNLOCK", arity: 2, flags: R, since: V1, usage: "key", summary: "Release a key's GET_OR_LOCK lock without writing it" },
+    CommandSpec { name: "SET_MISSING", arity: 3, flags: W, since: V1, usage: "key milliseconds", summary: "Cache that a key is missing for a while" },
+    CommandSpec { name: "DEL", arity: -2, flags: W, since: V1, usage: "key [key ...]", summary: "Remove keys" },
+    CommandSpec { name: "UNLINK", arity: -2, flags: W, since: V1, usage: "key [key ...]", summary: "Remove keys, freeing large values in the background" },
+    CommandSpec { name: "EXISTS", arity: 2, flags: R, since: V1, usage: "key", summary: "Whether a key exists" },
+    CommandSpec { name: "EXISTS_MANY", arity: -2, flags: R, since: V1, usage: "key [key ...]", summary: "Number of the keys that exist" },
+    CommandSpec { name: "MSETNX", arity: -3, flags: W, since: V1, usage: "key value [key value ...]", summary: "Set every key, only when none of them exists" },
+    CommandSpec { name: "DUMP", arity: 2, flags: R, since: V1, usage: "key", summary: "Serialize a key's value and remaining TTL" },
+    CommandSpec { name: "RESTORE", arity: -3, flags: W, since: V1, usage: "key payload [REPLACE]", summary: "Re-create a key from a DUMP payload" },
+    CommandSpec { name: "MIGRATE", arity: -3, flags: W, since: V1, usage: "key address [COPY] [REPLACE]", summary: "Move a key to another node" },
+    CommandSpec { name: "KEYS", arity: -1, flags: R, since: V1, usage: "[pattern [CHUNK keys]]", summary: "Keys on this node matching a glob pattern" },
+    CommandSpec { name: "DBSIZE", arity: 1, flags: RA, since: V1, usage: "", summary: "Number of keys on this node" },
+    CommandSpec { name: "FLUSHALL", arity: 1, flags: W, since: V1, usage: "", summary: "Remove every key on this node" },
+    // Expiry
+    CommandSpec { name: "EXPIRE", arity: -3, flags: W, since: V1, usage: "key seconds [JITTER pct]", summary: "Remove a key after a number of seconds" },
+    CommandSpec { name: "PEXPIRE", arity: -3, flags: W, since: V1, usage: "key milliseconds [JITTER pct]", summary: "Remove a key after a number of milliseconds" },
+    CommandSpec { name: "EXPIREAT", arity: 3, flags: W, since: V1, usage: "key timestamp", summary: "Remove a key at a unix time in seconds" },
+    CommandSpec { name: "PEXPIREAT", arity: 3, flags: W, since: V1, usage: "key timestamp_ms", summary: "Remove a key at a unix time in milliseconds" },
+    CommandSpec { name: "PERSIST", arity: 2, flags: W, since: V1, usage: "key", summary: "Drop a key's expiry" },
+    CommandSpec { name: "TTL", arity: 2, flags: R, since: V1, usage: "key", summary: "Seconds before a key expires" },
+    CommandSpec { name: "PTTL", arity: 2, flags: R, since: V1, usage: "key", summary: "Milliseconds before a key expires" },
+    CommandSpec { name: "SOFT_EXPIRE", arity: 3, flags: W, since: V1, usage: "key seconds", summary: "Flag a key's value stale after a number of seconds" },
+    CommandSpec { name: "SOFT_TTL", arity: 2, flags: R, since: V1, usage: "key", summary: "Seconds before a key goes stale" },
+    // Introspection
+    CommandSpec { name: "MEMORY_USAGE", arity: 2, flags: R, since: V1, usage: "key", summary: "Bytes accounted to a key" },
+    CommandSpec { name: "MEMORY_DEFRAG", arity: 1, flags: RA, since: V1, usage: "", summary: "Re-allocate values and shrink the keyspace in the background" },
+    CommandSpec { name: "OBJECT_IDLETIME", arity: 2, flags: R, since: V1, usage: "key", summary: "Seconds since a key was last read or written" },
+    CommandSpec { name: "OBJECT_FREQ", arity: 2, flags: R, since: V1, usage: "key", summary: "A key's logarithmic access counter" },
+    CommandSpec { name: "DEBUG_OBJECT", arity: 2, flags: R, since: V1, usage: "key", summary: "A key's bookkeeping and how its value is stored" },
+    CommandSpec { name: "COMMAND", arity: 1, flags: RA, since: V1, usage: "", summary: "Every command with its arity, flags and version" },
+    CommandSpec { name: "COMMAND_DOCS", arity: -1, flags: RA, since: V1, usage: "[name ...]", summary: "Usage and summary of the named commands, or of all" },
+    // CRDTs
+    CommandSpec { name: "COUNTER_INCRBY", arity: -2, flags: W, since: V1, usage: "key [delta]", summary: "Add to a counter several primaries may update at once" },
+    CommandSpec { name: "COUNTER_GET", arity: 2, flags: R, since: V1, usage: "key", summary: "Value of a counter" },
+    CommandSpec { name: "ORSET_ADD", arity: -3, flags: W, since: V1, usage: "key member [member ...]", summary: "Add members to a set several primaries may update at once" },
+    CommandSpec { name: "ORSET_REM", arity: -3, flags: W, since: V1, usage: "key member [member ...]", summary: "Remove members from a set" },
+    CommandSpec { name: "ORSET_MEMBERS", arity: 2, flags: R, since: V1, usage: "key", summary: "Members of a set, sorted" },
+    // Connection
+    CommandSpec { name: "PING", arity: -1, flags: RA, since: V1, usage: "[message]", summary: "Liveness check" },
+    CommandSpec { name: "ECHO", arity: 2, flags: RA, since: V1, usage: "message", summary: "Reply with the message" },
+    CommandSpec { name: "HELLO", arity: -1, flags: RA, since: V1, usage: "[protocol]", summary: "Report server capabilities" },
+    CommandSpec { name: "AUTH", arity: -2, flags: RA, since: V1, usage: "[username] password", summary: "Authenticate" },
+    CommandSpec { name: "ENCODING", arity: 2, flags: R, since: V1, usage: "name", summary: "Switch the wire encoding of the connection" },
+    CommandSpec { name: "READONLY", arity: 1, flags: R, since: V1, usage: "", summary: "Let this connection read from a replica" },
+    CommandSpec { name: "READWRITE", arity: 1, flags: R, since: V1, usage: "", summary: "Undo READONLY" },
+    CommandSpec { name: "ASKING", arity: 1, flags: R, since: V1, usage: "", summary: "Sent before a command that follows an Ask redirect" },
+    CommandSpec { name: "CLIENT_TRACKING", arity: 2, flags: R, since: V1, usage: "ON|OFF", summary: "Have the connection told when keys it reads change" },
+    // Pub/Sub
+    CommandSpec { name: "PUBLISH", arity: 3, flags: R, since: V1, usage: "channel message", summary: "Send a message to a channel's subscribers" },
+    CommandSpec { name: "SUBSCRIBE", arity: -2, flags: R, since: V1, usage: "channel [channel ...]", summary: "Receive the messages of channels" },
+    CommandSpec { name: "UNSUBSCRIBE", arity: -1, flags: R, since: V1, usage: "[channel ...]", summary: "Stop receiving the messages of channels" },
+    CommandSpec { name: "KEYEVENTS_SUBSCRIBE", arity: -2, flags: R, since: V1, usage: "pattern [pattern ...]", summary: "Stream expiry and eviction events of keys matching patterns" },
+    CommandSpec { name: "KEYEVENTS_UNSUBSCRIBE", arity: -1, flags: R, since: V1, usage: "[pattern ...]", summary: "Stop watching patterns" },
+    // Server
+    CommandSpec { name: "INFO", arity: -1, flags: RA, since: V1, usage: "[section]", summary: "Server statistics" },
+    CommandSpec { name: "CONFIG_GET", arity: 2, flags: RA, since: V1, usage: "key|*", summary: "Configuration settings" },
+    CommandSpec { name: "VERSION", arity: 1, flags: RA, since: V1, usage: "", summary: "Server version, commit, build date and features" },
+    CommandSpec { name: "PERSISTENCE_STATUS", arity: 1, flags: RA, since: V1, usage: "", summary: "State of snapshots, the write-ahead log and backups" },
+    CommandSpec { name: "SHUTDOWN", arity: -1, flags: RA, since: V1, usage: "[SAVE|NOSAVE]", summary: "Stop the server after draining connections" },
+    // Replication
+    CommandSpec { name: "WAIT", arity: 3, flags: R, since: V1, usage: "replicas timeout", summary: "Block until replicas acknowledged the writes made so far" },
+    CommandSpec { name: "QUORUM", arity: -3, flags: W, since: V1, usage: "replicas command [arg ...]", summary: "Run a write and reply once replicas applied it, or read the newest copy of a key from them" },
+    CommandSpec { name: "SYNC", arity: 2, flags: RI, since: V1, usage: "address", summary: "Stream the keyspace and later writes to a replica" },
+    CommandSpec { name: "REPLACK", arity: 2, flags: RI, since: V1, usage: "offset", summary: "A replica applied every write up to an offset" },
+    CommandSpec { name: "MERKLE", arity: -2, flags: RI, since: V1, usage: "level [node ...]", summary: "Hashes of the primary's Merkle tree" },
+    CommandSpec { name: "DIGEST", arity: 2, flags: RI, since: V1, usage: "slot", summary: "Digest of every key in a slot" },
+    CommandSpec { name: "REPAIR", arity: -2, flags: RI, since: V1, usage: "key [key ...]", summary: "Stream the current value of keys to every replica" },
+    // Cluster
+    CommandSpec { name: "CLUSTER_JOIN", arity: 2, flags: RA, since: V1, usage: "address", summary: "Add a node to the cluster" },
+    CommandSpec { name: "CLUSTER_REMOVE", arity: 2, flags: RA, since: V1, usage: "address", summary: "Remove a node from the cluster" },
+    CommandSpec { name: "CLUSTER_ISOLATE", arity: 1, flags: RA, since: V1, usage: "", summary: "Leave the cluster, keeping the keys" },
+    CommandSpec { name: "CLUSTER_RESET", arity: -1, flags: WA, since: V1, usage: "[HARD|SOFT]", summary: "Forget the cluster; HARD also drops the keys and the node id" },
+    CommandSpec { name: "CLUSTER_SLOTS", arity: 1, flags: RA, since: V1, usage: "", summary: "The cluster's slot map" },
+    CommandSpec { name: "NODE_INFO", arity: 1, flags: RA, since: V1, usage: "", summary: "This node's id, address and weight" },
+    CommandSpec { name: "CLUSTER_DBSIZE", arity: 1, flags: RA, since: V1, usage: "", summary: "Number of keys on every member" },
+    CommandSpec { name: "CLUSTER_FLUSHALL", arity: 1, flags: W, since: V1, usage: "", summary: "Remove every key on every member" },
+    CommandSpec { name: "CLUSTER_INFO", arity: -1, flags: RA, since: V1, usage: "[section]", summary: "INFO of every member" },
+    CommandSpec { name: "CLUSTER_KEYS", arity: -1, flags: R, since: V1, usage: "[pattern [CHUNK keys]]", summary: "Keys on every member matching a glob pattern" },
+    CommandSpec { name: "MIGRATE_SLOTS", arity: -3, flags: WA, since: V1, usage: "address slot [slot ...]", summary: "Move every key of slots to another node" },
+    CommandSpec { name: "MIGRATE_SLOTS_ABORT", arity: 1, flags: RA, since: V1, usage: "", summary: "Stop the slot migration in progress" },
+    CommandSpec { name: "CLUSTER_SETSLOT", arity: -3, flags: RA, since: V1, usage: "slots NODE|MIGRATING|IMPORTING|UNASSIGNED|STABLE [address]", summary: "Assign slots, or mark them as moving" },
+    // Geo-replication
+    CommandSpec { name: "GEO_APPLY", arity: -3, flags: RI, since: V1, usage: "source batch", summary: "Apply a batch of writes shipped by the active cluster" },
+    CommandSpec { name: "GEO_PROMOTE", arity: -1, flags: RA, since: V1, usage: "[LOCAL]", summary: "Turn this standby cluster into a writable one" },
+];
@@ -0,0 +1,267 @@
+use std::collections::{BTreeMap, BTreeSet};
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use crate::cache::{CacheEntry, ServerError};
+
+// Values several primaries may update at once without coordinating, as
+// clusters replicating into each other with `geo_active` do. Every node's
+// updates are kept apart, so merging two states of a key gives the same state
+// in any order and loses no update. Nodes are told apart by their public
+// address, which must be unique across the clusters.
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Crdt {
+    Counter(Counter),
+    Set(ObservedRemoveSet),
+}
+
+impl Crdt {
+    // State stored in an entry
+    pub fn of_entry(key: &str, entry: &CacheEntry) -> Result<Self, ServerError> {
+        if !entry.crdt {
+            return Err(ServerError::WrongType(format!("{} holds a plain value", key)));
+        }
+        entry.verify()?;
+        let (crdt, _) = bincode::serde::decode_from_slice(&entry.data, bincode::config::standard())
+            .map_err(|e| ServerError::Encoding(e.to_string()))?;
+        Ok(crdt)
+    }
+
+    // Entry storing this state
+    pub fn to_entry(&self) -> CacheEntry {
+        let data = bincode::serde::encode_to_vec(self, bincode::config::standard()).expect("CRDT states serialize");
+        CacheEntry::new(Bytes::from(data), false, true)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Crdt::Counter(_) => "counter",
+            Crdt::Set(_) => "set",
+        }
+    }
+
+    // Take in the updates of another state of the same key
+    pub fn merge(&mut self, other: &Crdt) -> Result<(), ServerError> {
+        match (self, other) {
+            (Crdt::Counter(ours), Crdt::Counter(theirs)) => ours.merge(theirs),
+            (Crdt::Set(ours), Crdt::Set(theirs)) => ours.merge(theirs),
+            (ours, theirs) => {
+                return Err(ServerError::InvalidArgument(format!("Can't merge a {} into a {}", theirs.name(), ours.name())));
+            }
+        }
+        Ok(())
+    }
+}
+
+// Merge two entries holding states of the same key, keeping the expiry of the first
+pub fn merge_entries(key: &str, ours: &CacheEntry, theirs: &CacheEntry) -> Result<CacheEntry, ServerError> {
+    let mut merged = Crdt::of_entry(key, ours)?;
+    merged.merge(&Crdt::of_entry(key, theirs)?)?;
+    let mut entry = merged.to_entry();
+    entry.expires_at = ours.expires_at;
+    Ok(entry)
+}
+
+// A counter every node adds to and subtracts from on its own; its value is
+// the sum over all nodes
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Counter {
+    added: BTreeMap<String, u64>,
+    subtracted: BTreeMap<String, u64>,
+}
+
+impl Counter {
+    pub fn value(&self) -> i64 {
+        let added = self.added.values().fold(0u64, |sum, n| sum.wrapping_add(*n));
+        let subtracted = self.subtracted.values().fold(0u64, |sum, n| sum.wrapping_add(*n));
+        added.wrapping_sub(subtracted) as i64
+    }
+
+    pub fn add(&mut self, node: &str, delta: i64) {
+        let totals = if delta < 0 { &mut self.subtracted } else { &mut self.added };
+        let total = totals.entry(node.to_string()).or_default();
+        *total = total.saturating_add(delta.unsigned_abs());
+    }
+
+    // A node's totals only grow, so the larger one has seen more updates
+    fn merge(&mut self, other: &Counter) {
+        for (ours, theirs) in [(&mut self.added, &other.added), (&mut self.subtracted, &other.subtracted)] {
+            for (node, total) in theirs {
+                let own = ours.entry(node.clone()).or_default();
+                *own = (*own).max(*total);
+            }
+        }
+    }
+}
+
+// A set where adding a member wins over removing it concurrently. Each add
+// is tagged with the node that made it and that node's count of adds; a
+// removal drops only the tags it has seen, so an add it hasn't survives.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ObservedRemoveSet {
+    members: BTreeMap<String, BTreeSet<(String, u64)>>,
+    adds: BTreeMap<String, u64>, // adds made by each node that this state has seen
+}
+
+impl ObservedRemoveSet {
+    pub fn members(&self) -> Vec<String> {
+        self.members.keys().cloned().collect()
+    }
+
+    // Returns whether the member is new
+    pub fn add(&mut self, node: &str, member: &str) -> bool {
+        let count = self.adds.entry(node.to_string()).or_default();
+        *count += 1;
+        let tag = (node.to_string(), *count);
+        // The new tag stands in for the ones seen so far
+        self.members.insert(member.to_string(), BTreeSet::from([tag])).is_none()
+    }
+
+    // Returns whether the member was there
+    pub fn remove(&mut self, member: &str) -> bool {
+        self.members.remove(member).is_some()
+    }
+
+    // A tag one state lacks was removed there if that state has seen the add,
+    // and is unknown to it otherwise
+    fn merge(&mut self, other: &ObservedRemoveSet) {
+        let unseen = |adds: &BTreeMap<String, u64>, (node, count): &(String, u64)| {
+            adds.get(node).is_none_or(|seen| count > seen)
+        };
+        let names: BTreeSet<String> = self.members.keys().chain(other.members.keys()).cloned().collect();
+        let empty = BTreeSet::new();
+        for name in names {
+            let ours = self.members.get(&name).unwrap_or(&empty);
+            let theirs = other.members.get(&name).unwrap_or(&empty);
+            let tags: BTreeSet<(String, u64)> = ours.intersection(theirs)
+                .chain(ours.difference(theirs).filter(|tag| unseen(&other.adds, tag)))
+                .chain(theirs.difference(ours).filter(|tag| unseen(&self.adds, tag)))
+                .cloned()
+                .collect();
+            if tags.is_empty() {
+                self.members.remove(&name);
+            } else {
+                self.members.insert(name, tags);
+            }
+        }
+        for (node, count) in &other.adds {
+            let own = self.adds.entry(node.clone()).or_default();
+            *own = (*own).max(*count);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn merged(a: &ObservedRemoveSet, b: &ObservedRemoveSet) -> ObservedRemoveSet {
+        let mut merged = a.clone();
+        merged.merge(b);
+        merged
+    }
+
+    #[test]
+    fn merge_keeps_the_adds_of_both_sides() {
+        let mut a = ObservedRemoveSet::default();
+        let mut b = ObservedRemoveSet::default();
+        a.add("a", "x");
+        b.add("b", "y");
+        assert_eq!(merged(&a, &b).members(), ["x", "y"]);
+        assert_eq!(merged(&a, &b), merged(&b, &a));
+    }
+
+    #[test]
+    fn merge_applies_a_removal_of_an_add_it_has_seen() {
+        let mut a = ObservedRemoveSet::default();
+        a.add("a", "x");
+        let mut b = a.clone();
+        b.remove("x");
+        assert!(merged(&a, &b).members().is_empty());
+        assert!(merged(&b, &a).members().is_empty());
+    }
+
+    #[test]
+    fn concurrent_add_wins_over_remove() {
+        let mut a = ObservedRemoveSet::default();
+        a.add("a", "x");
+        let mut b = a.clone();
+        b.remove("x");
+        // Added again on `a` while `b` removed it
+        a.add("a", "x");
+        assert_eq!(merged(&a, &b).members(), ["x"]);
+        assert_eq!(merged(&b, &a).members(), ["x"]);
+    }
+
+    #[test]
+    fn merge_is_idempotent() {
+        let mut a = ObservedRemoveSet::default();
+        let mut b = ObservedRemoveSet::default();
+        a.add("a", "x");
+        b.add("b", "x");
+        b.add("b", "y");
+        let once = merged(&a, &b);
+        assert_eq!(merged(&once, &b), once);
+        assert_eq!(merged(&once, &once), once);
+    }
+
+    #[test]
+    fn counter_merge_sums_the_nodes_and_keeps_each_ones_latest_total() {
+        let mut a = Counter::default();
+        let mut b = Counter::default();
+        a.add("a", 5);
+        a.add("a", -2);
+        b.add("b", 10);
+        let mut merged = a.clone();
+        merged.merge(&b);
+        assert_eq!(merged.value(), 13);
+        // An older state of `a` arriving late changes nothing
+        let stale = merged.clone();
+        a.add("a", 4);
+        merged.merge(&a);
+        merged.merge(&stale);
+        assert_eq!(merged.value(), 17);
+    }
+
+    #[test]
+    fn counter_merge_is_commutative_and_idempotent() {
+        let mut a = Counter::default();
+        let mut b = Counter::default();
+        a.add("a", 3);
+        b.add("a", 1);
+        b.add("b", -7);
+        let mut ab = a.clone();
+        ab.merge(&b);
+        let mut ba = b.clone();
+        ba.merge(&a);
+        assert_eq!(ab, ba);
+        assert_eq!(ab.value(), -4);
+        let once = ab.clone();
+        ab.merge(&once);
+        assert_eq!(ab, once);
+    }
+
+    #[test]
+    fn states_of_different_types_dont_merge() {
+        let mut counter = Crdt::Counter(Counter::default());
+        let set = Crdt::Set(ObservedRemoveSet::default());
+        assert!(matches!(counter.merge(&set), Err(ServerError::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn merged_entries_keep_the_expiry_of_the_first() {
+        let mut ours = Counter::default();
+        ours.add("a", 1);
+        let mut theirs = Counter::default();
+        theirs.add("b", 2);
+        let mut ours = Crdt::Counter(ours).to_entry();
+        ours.expires_at = Some(1_000);
+        let theirs = Crdt::Counter(theirs).to_entry();
+        let merged = merge_entries("hits", &ours, &theirs).unwrap();
+        assert_eq!(merged.expires_at, Some(1_000));
+        match Crdt::of_entry("hits", &merged).unwrap() {
+            Crdt::Counter(counter) => assert_eq!(counter.value(), 3),
+            other => panic!("expected a counter, got a {}", other.name()),
+        }
+    }
+}
@@ -0,0 +1,119 @@
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use std::time::Duration;
+use bytes::Bytes;
+use crate::cache::{encode_entry, entry_value, now_ms, Keyspace, ServerError};
+use crate::encryption::Keyring;
+use crate::persistence::{load_snapshot, save_snapshot};
+
+// The cache engine for use inside a Rust process, without the network server.
+// Values are stored exactly as the server stores them, so a snapshot written
+// here can be loaded by a server node and the other way around.
+pub struct PlutoCache {
+    keyspace: RwLock<Keyspace>,
+    snapshot_file: Option<PathBuf>,
+    keys: Keyring,
+}
+
+impl PlutoCache {
+    // Open a cache backed by a snapshot file, loading it when it exists
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, ServerError> {
+        Self::open_encrypted(path, Keyring::default())
+    }
+
+    // Open a cache backed by a snapshot file encrypted with `keys`
+    pub fn open_encrypted(path: impl AsRef<Path>, keys: Keyring) -> Result<Self, ServerError> {
+        let snapshot_file = path.as_ref().to_path_buf();
+        let mut keyspace = Keyspace::new();
+        load_snapshot(&mut keyspace, &path_str(&snapshot_file)?, &keys)?;
+        Ok(PlutoCache {
+            keyspace: RwLock::new(keyspace),
+            snapshot_file: Some(snapshot_file),
+            keys,
+        })
+    }
+
+    // A cache that lives only in memory; `snapshot` is an error on it
+    pub fn in_memory() -> Self {
+        PlutoCache {
+            keyspace: RwLock::new(Keyspace::new()),
+            snapshot_file: None,
+            keys: Keyring::default(),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<Bytes>, ServerError> {
+        let keyspace = self.keyspace.read().unwrap();
+        match keyspace.get(key) {
+            Some(entry) => Ok(Some(entry_value(entry)?)),
+            None => Ok(None),
+        }
+    }
+
+    // Store a value, clearing any expiry the key had
+    pub fn set(&self, key: impl Into<String>, value: impl Into<Vec<u8>>) -> Result<(), ServerError> {
+        let entry = encode_entry(value.into())?;
+        self.keyspace.write().unwrap().insert(key.into(), entry);
+        Ok(())
+    }
+
+    // Remove a key, returning whether it existed
+    pub fn del(&self, key: &str) -> bool {
+        let mut keyspace = self.keyspace.write().unwrap();
+        let live = keyspace.contains_key(key);
+        keyspace.remove(key);
+        live
+    }
+
+    pub fn exists(&self, key: &str) -> bool {
+        self.keyspace.read().unwrap().contains_key(key)
+    }
+
+    // Expire a key after `ttl`; returns false when the key doesn't exist
+    pub fn expire(&self, key: &str, ttl: Duration) -> bool {
+        let expires_at = now_ms().saturating_add(ttl.as_millis() as u64);
+        self.keyspace.write().unwrap().set_expiry(key, Some(expires_at))
+    }
+
+    // Time left before a key expires, or None when it is missing or has no expiry
+    pub fn ttl(&self, key: &str) -> Option<Duration> {
+        let keyspace = self.keyspace.read().unwrap();
+        let expires_at = keyspace.get(key)?.expires_at?;
+        Some(Duration::from_millis(expires_at.saturating_sub(now_ms())))
+    }
+
+    // Number of keys, including expired ones not yet purged
+    pub fn len(&self) -> usize {
+        self.keyspace.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Bytes accounted to all entries
+    pub fn used_memory(&self) -> usize {
+        self.keyspace.read().unwrap().used_memory()
+    }
+
+    // Drop expired keys now instead of waiting for them to be overwritten
+    pub fn purge_expired(&self) -> usize {
+        self.keyspace.write().unwrap().purge_expired().len()
+    }
+
+    // Write every live key to the snapshot file given to `open`. Writes go
+    // on while it is written; those made after it started are left out.
+    pub fn snapshot(&self) -> Result<usize, ServerError> {
+        let path = self.snapshot_file.as_ref()
+            .ok_or_else(|| ServerError::InvalidArgument("cache was not opened with a snapshot file".to_string()))?;
+        let path = path_str(path)?;
+        let view = self.keyspace.read().unwrap().view();
+        Ok(save_snapshot(&view, &path, &self.keys)?)
+    }
+}
+
+fn path_str(path: &Path) -> Result<String, ServerError> {
+    path.to_str()
+        .map(str::to_string)
+        .ok_or_else(|| ServerError::InvalidArgument(format!("snapshot path is not valid UTF-8: {}", path.display())))
+}
@@ -0,0 +1,181 @@
+use std::io;
+use aes_gcm::{Aes256Gcm, KeyInit, Nonce};
+use aes_gcm::aead::Aead;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+// Encryption of the files persisted to disk, with AES-256-GCM. Each key is
+// known by an id taken from its hash, written next to what it encrypted, so
+// files written with a key rotated out since still open while that key is
+// kept among the previous ones.
+
+// Start of a file encrypted whole, like a snapshot, followed by the key id
+const SEALED_MAGIC: &[u8] = b"PLENC\x01";
+
+pub const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+
+pub type KeyId = [u8; 4];
+
+// The key new files are encrypted with, first, and the ones still accepted
+// for reading. Without keys nothing is encrypted.
+#[derive(Clone, Default)]
+pub struct Keyring {
+    keys: Vec<(KeyId, Aes256Gcm)>,
+}
+
+impl Keyring {
+    pub fn new(current: &[u8; KEY_LEN], previous: &[[u8; KEY_LEN]]) -> Self {
+        let keys = std::iter::once(current)
+            .chain(previous)
+            .map(|key| (key_id(key), Aes256Gcm::new(key.into())))
+            .collect();
+        Keyring { keys }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.keys.is_empty()
+    }
+
+    // Id of the key new files are encrypted with
+    pub fn current_id(&self) -> Option<KeyId> {
+        self.keys.first().map(|(id, _)| *id)
+    }
+
+    // Encrypt with the current key: a random nonce, then the ciphertext and
+    // its tag. Without keys the data is returned as it is.
+    pub fn encrypt(&self, plain: &[u8]) -> Vec<u8> {
+        let Some((_, cipher)) = self.keys.first() else {
+            return plain.to_vec();
+        };
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let sealed = cipher.encrypt(Nonce::from_slice(&nonce), plain).expect("AES-GCM encrypts any length we write");
+        let mut out = Vec::with_capacity(NONCE_LEN + sealed.len());
+        out.extend_from_slice(&nonce);
+        out.extend_from_slice(&sealed);
+        out
+    }
+
+    // Decrypt what `encrypt` returned under the key `id`
+    pub fn decrypt(&self, id: KeyId, sealed: &[u8]) -> io::Result<Vec<u8>> {
+        let Some((_, cipher)) = self.keys.iter().find(|(known, _)| *known == id) else {
+            return Err(invalid_data(format!("encrypted with key {}, which isn't configured", key_id_hex(id))));
+        };
+        if sealed.len() < NONCE_LEN {
+            return Err(invalid_data("encrypted data is cut short"));
+        }
+        let (nonce, sealed) = sealed.split_at(NONCE_LEN);
+        cipher.decrypt(Nonce::from_slice(nonce), sealed)
+            .map_err(|_| invalid_data(format!("failed to decrypt with key {}: the data was altered", key_id_hex(id))))
+    }
+
+    // Encrypt the content of a whole file; without keys it stays as it is
+    pub fn seal(&self, plain: Vec<u8>) -> Vec<u8> {
+        let Some(id) = self.current_id() else {
+            return plain;
+        };
+        let mut out = Vec::with_capacity(SEALED_MAGIC.len() + id.len() + NONCE_LEN + plain.len() + 16);
+        out.extend_from_slice(SEALED_MAGIC);
+        out.extend_from_slice(&id);
+        out.extend_from_slice(&self.encrypt(&plain));
+        out
+    }
+
+    // The content of a file `seal` wrote; files written unencrypted are
+    // returned as they are
+    pub fn unseal(&self, data: Vec<u8>) -> io::Result<Vec<u8>> {
+        match sealed_with(&data) {
+            Some(id) => self.decrypt(id, &data[SEALED_MAGIC.len() + id.len()..]),
+            None => Ok(data),
+        }
+    }
+}
+
+// Key a file `seal` wrote was encrypted with, or None if it wasn't
+pub fn sealed_with(data: &[u8]) -> Option<KeyId> {
+    data.strip_prefix(SEALED_MAGIC)?.first_chunk::<4>().copied()
+}
+
+// A key given as 64 hex digits
+pub fn parse_key(text: &str) -> Result<[u8; KEY_LEN], String> {
+    let text = text.trim();
+    if text.len() != KEY_LEN * 2 || !text.is_ascii() {
+        return Err(format!("an encryption key is {} hex digits", KEY_LEN * 2));
+    }
+    let mut key = [0u8; KEY_LEN];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&text[i * 2..i * 2 + 2], 16)
+            .map_err(|_| format!("an encryption key is {} hex digits", KEY_LEN * 2))?;
+    }
+    Ok(key)
+}
+
+fn key_id(key: &[u8; KEY_LEN]) -> KeyId {
+    let hash = Sha256::new().chain_update(b"pluto key id").chain_update(key).finalize();
+    [hash[0], hash[1], hash[2], hash[3]]
+}
+
+pub fn key_id_hex(id: KeyId) -> String {
+    id.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn invalid_data(e: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OLD: [u8; KEY_LEN] = [1; KEY_LEN];
+    const NEW: [u8; KEY_LEN] = [2; KEY_LEN];
+
+    #[test]
+    fn sealed_files_open_with_their_key() {
+        let keys = Keyring::new(&NEW, &[]);
+        let sealed = keys.seal(b"snapshot".to_vec());
+        assert_eq!(sealed_with(&sealed), keys.current_id());
+        assert!(!sealed.windows(8).any(|window| window == b"snapshot"));
+        assert_eq!(keys.unseal(sealed).unwrap(), b"snapshot");
+    }
+
+    #[test]
+    fn files_sealed_before_a_rotation_still_open() {
+        let sealed = Keyring::new(&OLD, &[]).seal(b"snapshot".to_vec());
+        let rotated = Keyring::new(&NEW, &[OLD]);
+        assert_eq!(rotated.unseal(sealed.clone()).unwrap(), b"snapshot");
+        // Once the old key is dropped, they no longer do
+        let error = Keyring::new(&NEW, &[]).unseal(sealed).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn altered_data_is_refused() {
+        let keys = Keyring::new(&NEW, &[]);
+        let mut sealed = keys.seal(b"snapshot".to_vec());
+        let last = sealed.len() - 1;
+        sealed[last] ^= 1;
+        assert!(keys.unseal(sealed).is_err());
+        let id = keys.current_id().unwrap();
+        assert!(keys.decrypt(id, &[0; NONCE_LEN - 1]).is_err());
+    }
+
+    #[test]
+    fn without_keys_nothing_is_encrypted() {
+        let keys = Keyring::default();
+        assert!(!keys.is_enabled());
+        assert_eq!(keys.seal(b"plain".to_vec()), b"plain");
+        // Files written before encryption was turned on still open
+        assert_eq!(Keyring::new(&NEW, &[]).unseal(b"plain".to_vec()).unwrap(), b"plain");
+    }
+
+    #[test]
+    fn keys_are_64_hex_digits() {
+        assert_eq!(parse_key(&"01".repeat(KEY_LEN)).unwrap(), OLD);
+        assert_eq!(parse_key(&format!(" {}\n", "02".repeat(KEY_LEN))).unwrap(), NEW);
+        assert!(parse_key("0102").is_err());
+        assert!(parse_key(&"zz".repeat(KEY_LEN)).is_err());
+        assert!(parse_key(&"é".repeat(KEY_LEN)).is_err());
+    }
+}
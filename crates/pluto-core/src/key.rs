@@ -0,0 +1,154 @@
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use hashbrown::Equivalent;
+
+// Where keys are split when their prefixes are shared: after the last one,
+// so `service:env:entity:` is kept once for all of `service:env:entity:<id>`
+const SEPARATOR: char = ':';
+
+// A key as the keyspace stores it, as its prefix and the rest. The prefix is
+// shared by every key that has it and held by none without shared prefixes.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Key {
+    prefix: Option<Arc<str>>,
+    rest: Box<str>,
+}
+
+impl Key {
+    fn parts(&self) -> (&str, &str) {
+        (self.prefix.as_deref().unwrap_or(""), &self.rest)
+    }
+
+    // The key as one string, which a key with a prefix is copied into
+    pub fn text(&self) -> Cow<'_, str> {
+        match &self.prefix {
+            None => Cow::Borrowed(&self.rest),
+            Some(prefix) => Cow::Owned(format!("{}{}", prefix, self.rest)),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.prefix.as_ref().map_or(0, |prefix| prefix.len()) + self.rest.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+// Hashes as its parts, the same as the `KeyRef` it is looked up by
+impl Hash for Key {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.parts().hash(state)
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (prefix, rest) = self.parts();
+        write!(f, "{}{}", prefix, rest)
+    }
+}
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.text(), f)
+    }
+}
+
+// A key being looked up, split the way it would be stored
+#[derive(Hash)]
+pub struct KeyRef<'a>(&'a str, &'a str);
+
+impl Equivalent<Key> for KeyRef<'_> {
+    fn equivalent(&self, key: &Key) -> bool {
+        (self.0, self.1) == key.parts()
+    }
+}
+
+// The prefixes the keys of a keyspace share, when it shares them
+#[derive(Default)]
+pub struct Prefixes {
+    shared: bool,
+    prefixes: HashSet<Arc<str>>,
+}
+
+impl Prefixes {
+    pub fn new(shared: bool) -> Self {
+        Prefixes { shared, prefixes: HashSet::new() }
+    }
+
+    pub fn is_shared(&self) -> bool {
+        self.shared
+    }
+
+    // Where a key is split into its prefix and the rest
+    fn split_at(&self, key: &str) -> usize {
+        if !self.shared {
+            return 0;
+        }
+        key.rfind(SEPARATOR).map_or(0, |at| at + SEPARATOR.len_utf8())
+    }
+
+    // `key` split as it is stored, to look it up with
+    pub fn lookup<'a>(&self, key: &'a str) -> KeyRef<'a> {
+        let (prefix, rest) = key.split_at(self.split_at(key));
+        KeyRef(prefix, rest)
+    }
+
+    // `key` as it is stored, sharing its prefix with the other keys with it
+    pub fn key(&mut self, key: String) -> Key {
+        let at = self.split_at(&key);
+        if at == 0 {
+            return Key { prefix: None, rest: key.into_boxed_str() };
+        }
+        let (prefix, rest) = key.split_at(at);
+        let prefix = match self.prefixes.get(prefix) {
+            Some(shared) => shared.clone(),
+            None => {
+                let shared: Arc<str> = Arc::from(prefix);
+                self.prefixes.insert(shared.clone());
+                shared
+            }
+        };
+        Key { prefix: Some(prefix), rest: Box::from(rest) }
+    }
+
+    // Forget the prefix of a key being dropped once no other key holds it
+    pub fn release(&mut self, key: &Key) {
+        // Held here and by the key
+        if let Some(prefix) = &key.prefix
+            && Arc::strong_count(prefix) <= 2 {
+            self.prefixes.remove(prefix);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.prefixes.clear();
+    }
+
+    // Forget every prefix no key holds anymore
+    pub fn prune(&mut self) {
+        self.prefixes.retain(|prefix| Arc::strong_count(prefix) > 1);
+    }
+
+    // Number of prefixes shared
+    pub fn len(&self) -> usize {
+        self.prefixes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.prefixes.is_empty()
+    }
+
+    // Bytes saved by sharing: what every holder of a prefix but one would
+    // take for a copy of its own
+    pub fn saved_bytes(&self) -> usize {
+        self.prefixes.iter()
+            .map(|prefix| prefix.len() * Arc::strong_count(prefix).saturating_sub(2))
+            .sum()
+    }
+}
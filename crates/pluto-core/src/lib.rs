@@ -0,0 +1,20 @@
+#![allow(unused_imports)]
+#![allow(non_camel_case_types)]
+#![allow(clippy::upper_case_acronyms)]
+
+pub mod cache;
+pub mod cluster;
+pub mod codec;
+pub mod commands;
+pub mod crdt;
+pub mod embedded;
+pub mod encryption;
+pub mod key;
+pub mod merkle;
+pub mod persistence;
+pub mod protocol;
+pub mod storage;
+pub mod value;
+pub mod wal;
+
+pub use embedded::PlutoCache;
@@ -0,0 +1,87 @@
+use crate::cache::{CacheEntry, Keyspace, now_ms};
+use crate::cluster::{key_slot, TOTAL_SLOTS};
+
+// Children of every inner node of the tree
+pub const FANOUT: usize = 16;
+
+// A hash tree over the keyspace with one leaf per slot. Two nodes holding the
+// same keys and values build identical trees, so comparing them from the root
+// down finds the slots that differ without sending every key. Expiry times are
+// left out, as a replica restores them relative to its own clock.
+pub struct MerkleTree {
+    levels: Vec<Vec<u64>>, // levels[0] is the root, the last level the slots
+}
+
+impl MerkleTree {
+    pub fn build(keyspace: &Keyspace) -> Self {
+        let now = now_ms();
+        let mut leaves = vec![0u64; TOTAL_SLOTS];
+        for (key, entry) in keyspace.iter() {
+            if !entry.is_expired(now) {
+                // Summing keeps a leaf independent of the order keys are visited in
+                let key = key.text();
+                let slot = key_slot(&key);
+                leaves[slot] = leaves[slot].wrapping_add(entry_digest(&key, entry));
+            }
+        }
+        let mut levels = vec![leaves];
+        while levels[0].len() > 1 {
+            let parents = levels[0].chunks(FANOUT)
+                .map(|children| children.iter().fold(FNV_OFFSET, |hash, child| fnv1a(hash, &child.to_le_bytes())))
+                .collect();
+            levels.insert(0, parents);
+        }
+        MerkleTree { levels }
+    }
+
+    // Number of levels, the slots being at `depth() - 1`
+    pub fn depth(&self) -> usize {
+        self.levels.len()
+    }
+
+    // Hash of node `index` at `level`, or 0 past the end of the level
+    pub fn hash(&self, level: usize, index: usize) -> u64 {
+        self.levels.get(level).and_then(|nodes| nodes.get(index)).copied().unwrap_or(0)
+    }
+
+    // Indexes of the children of node `index` at `level`
+    pub fn children(&self, level: usize, index: usize) -> std::ops::Range<usize> {
+        let len = self.levels.get(level + 1).map_or(0, Vec::len);
+        (index * FANOUT).min(len)..((index + 1) * FANOUT).min(len)
+    }
+}
+
+// Digest of every live key in `slot`, for finding the keys of a slot that differ
+pub fn slot_digests(keyspace: &Keyspace, slot: usize) -> Vec<(String, u64)> {
+    let now = now_ms();
+    let mut digests: Vec<(String, u64)> = keyspace.iter()
+        .filter(|(_, entry)| !entry.is_expired(now))
+        .filter_map(|(key, entry)| {
+            let key = key.text();
+            (key_slot(&key) == slot).then(|| (entry_digest(&key, entry), key.into_owned()))
+        })
+        .map(|(digest, key)| (key, digest))
+        .collect();
+    digests.sort();
+    digests
+}
+
+// Hash of a key and its stored value
+pub fn entry_digest(key: &str, entry: &CacheEntry) -> u64 {
+    let hash = fnv1a(FNV_OFFSET, &(key.len() as u64).to_le_bytes());
+    let hash = fnv1a(hash, key.as_bytes());
+    let hash = fnv1a(hash, &[entry.compressed as u8]);
+    fnv1a(hash, &entry.data)
+}
+
+pub(crate) const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+// FNV-1a, stable across builds and platforms unlike the std hasher
+pub(crate) fn fnv1a(mut hash: u64, bytes: &[u8]) -> u64 {
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    hash
+}
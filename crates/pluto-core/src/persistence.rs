@@ -0,0 +1,405 @@
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use log::{info, warn};
+use crate::cache::{checksum, now_ms, Access, CacheEntry, Keyspace, View, WriteStamp};
+use crate::encryption::{KeyId, Keyring, sealed_with};
+
+// Snapshot format version, bumped whenever the layout changes
+const SNAPSHOT_VERSION: u32 = 8;
+
+// On-disk form of a cache entry; values stay in their stored (possibly
+// compressed) form so saving and loading never recompress
+#[derive(Serialize, Deserialize)]
+struct SnapshotEntry {
+    key: String,
+    compressed: bool,
+    data: Bytes,
+    expires_at: Option<u64>,
+    stamp: WriteStamp,
+    crdt: bool,
+    checksum: u32,
+    stale_at: Option<u64>,
+    tombstone: bool,
+    visible_at: Option<u64>,
+}
+
+// Entry layout of version 7 snapshots, written before scheduled values
+#[derive(Deserialize)]
+struct SnapshotEntryV7 {
+    key: String,
+    compressed: bool,
+    data: Bytes,
+    expires_at: Option<u64>,
+    stamp: WriteStamp,
+    crdt: bool,
+    checksum: u32,
+    stale_at: Option<u64>,
+    tombstone: bool,
+}
+
+// Entry layout of version 6 snapshots, written before tombstones
+#[derive(Deserialize)]
+struct SnapshotEntryV6 {
+    key: String,
+    compressed: bool,
+    data: Bytes,
+    expires_at: Option<u64>,
+    stamp: WriteStamp,
+    crdt: bool,
+    checksum: u32,
+    stale_at: Option<u64>,
+}
+
+// Entry layout of version 5 snapshots, written before soft TTLs
+#[derive(Deserialize)]
+struct SnapshotEntryV5 {
+    key: String,
+    compressed: bool,
+    data: Bytes,
+    expires_at: Option<u64>,
+    stamp: WriteStamp,
+    crdt: bool,
+    checksum: u32,
+}
+
+// Entry layout of version 4 snapshots, written before values were checksummed
+#[derive(Deserialize)]
+struct SnapshotEntryV4 {
+    key: String,
+    compressed: bool,
+    data: Bytes,
+    expires_at: Option<u64>,
+    stamp: WriteStamp,
+    crdt: bool,
+}
+
+// Entry layout of version 3 snapshots, written before CRDT values
+#[derive(Deserialize)]
+struct SnapshotEntryV3 {
+    key: String,
+    compressed: bool,
+    data: Bytes,
+    expires_at: Option<u64>,
+    stamp: WriteStamp,
+}
+
+// Entry layout of version 2 snapshots, written before entries were stamped
+#[derive(Deserialize)]
+struct SnapshotEntryV2 {
+    key: String,
+    compressed: bool,
+    data: Bytes,
+    expires_at: Option<u64>,
+}
+
+// Entry layout of version 1 snapshots, written before keys could expire
+#[derive(Deserialize)]
+struct SnapshotEntryV1 {
+    key: String,
+    compressed: bool,
+    data: Bytes,
+}
+
+#[derive(Serialize)]
+struct Snapshot {
+    version: u32,
+    entries: Vec<SnapshotEntry>,
+}
+
+fn invalid_data(e: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+// Write every entry of a view of the cache to the snapshot file, encrypted
+// when there are keys. The file is written next to its final path and
+// renamed into place so a crash never leaves a partial snapshot behind.
+pub fn save_snapshot(view: &View, path: &str, keys: &Keyring) -> io::Result<usize> {
+    save_snapshot_counting(view, path, keys, &AtomicUsize::new(0))
+}
+
+// The same, counting the entries of the view gone through in `done`, for
+// whoever reports the save's progress
+pub fn save_snapshot_counting(view: &View, path: &str, keys: &Keyring, done: &AtomicUsize) -> io::Result<usize> {
+    let now = now_ms();
+    let snapshot = Snapshot {
+        version: SNAPSHOT_VERSION,
+        entries: view.iter()
+            .inspect(|_| {
+                done.fetch_add(1, Ordering::Relaxed);
+            })
+            .filter(|(_, entry)| !entry.is_expired(now))
+            .map(|(key, entry)| SnapshotEntry {
+                key: key.to_string(),
+                compressed: entry.compressed,
+                data: entry.data.to_bytes(),
+                expires_at: entry.expires_at,
+                stamp: entry.stamp,
+                crdt: entry.crdt,
+                checksum: entry.checksum,
+                stale_at: entry.stale_at,
+                tombstone: entry.tombstone,
+                visible_at: entry.visible_at,
+            })
+            .collect(),
+    };
+    let encoded = bincode::serde::encode_to_vec(&snapshot, bincode::config::standard())
+        .map_err(invalid_data)?;
+    let tmp_path = format!("{}.tmp", path);
+    // On disk before the rename, so a crash can't leave a truncated snapshot
+    // in place of the last good one
+    let mut tmp = fs::File::create(&tmp_path)?;
+    tmp.write_all(&keys.seal(encoded))?;
+    tmp.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    info!("Saved {} keys to {}", snapshot.entries.len(), path);
+    Ok(snapshot.entries.len())
+}
+
+// Key the snapshot file is encrypted with, or None if it isn't or doesn't exist
+pub fn snapshot_key(path: &str) -> io::Result<Option<KeyId>> {
+    let mut header = Vec::new();
+    match fs::File::open(path) {
+        Ok(file) => file.take(16).read_to_end(&mut header)?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    Ok(sealed_with(&header))
+}
+
+// Load the snapshot file into the cache, if there is one
+pub fn load_snapshot(cache: &mut Keyspace, path: &str, keys: &Keyring) -> io::Result<usize> {
+    if !Path::new(path).exists() {
+        return Ok(0);
+    }
+    let data = keys.unseal(fs::read(path)?)?;
+    let config = bincode::config::standard();
+    let (version, read): (u32, usize) =
+        bincode::serde::decode_from_slice(&data, config).map_err(invalid_data)?;
+    let entries = match version {
+        SNAPSHOT_VERSION => {
+            let (entries, _): (Vec<SnapshotEntry>, usize) =
+                bincode::serde::decode_from_slice(&data[read..], config).map_err(invalid_data)?;
+            entries
+        }
+        7 => {
+            let (entries, _): (Vec<SnapshotEntryV7>, usize) =
+                bincode::serde::decode_from_slice(&data[read..], config).map_err(invalid_data)?;
+            entries.into_iter()
+                .map(|e| SnapshotEntry {
+                    key: e.key,
+                    compressed: e.compressed,
+                    data: e.data,
+                    expires_at: e.expires_at,
+                    stamp: e.stamp,
+                    crdt: e.crdt,
+                    checksum: e.checksum,
+                    stale_at: e.stale_at,
+                    tombstone: e.tombstone,
+                    visible_at: None,
+                })
+                .collect()
+        }
+        6 => {
+            let (entries, _): (Vec<SnapshotEntryV6>, usize) =
+                bincode::serde::decode_from_slice(&data[read..], config).map_err(invalid_data)?;
+            entries.into_iter()
+                .map(|e| SnapshotEntry {
+                    key: e.key,
+                    compressed: e.compressed,
+                    data: e.data,
+                    expires_at: e.expires_at,
+                    stamp: e.stamp,
+                    crdt: e.crdt,
+                    checksum: e.checksum,
+                    stale_at: e.stale_at,
+                    tombstone: false,
+                    visible_at: None,
+                })
+                .collect()
+        }
+        5 => {
+            let (entries, _): (Vec<SnapshotEntryV5>, usize) =
+                bincode::serde::decode_from_slice(&data[read..], config).map_err(invalid_data)?;
+            entries.into_iter()
+                .map(|e| SnapshotEntry {
+                    key: e.key,
+                    compressed: e.compressed,
+                    data: e.data,
+                    expires_at: e.expires_at,
+                    stamp: e.stamp,
+                    crdt: e.crdt,
+                    checksum: e.checksum,
+                    stale_at: None,
+                    tombstone: false,
+                    visible_at: None,
+                })
+                .collect()
+        }
+        4 => {
+            let (entries, _): (Vec<SnapshotEntryV4>, usize) =
+                bincode::serde::decode_from_slice(&data[read..], config).map_err(invalid_data)?;
+            entries.into_iter()
+                .map(|e| SnapshotEntry {
+                    checksum: checksum(&e.data),
+                    key: e.key,
+                    compressed: e.compressed,
+                    data: e.data,
+                    expires_at: e.expires_at,
+                    stamp: e.stamp,
+                    crdt: e.crdt,
+                    stale_at: None,
+                    tombstone: false,
+                    visible_at: None,
+                })
+                .collect()
+        }
+        3 => {
+            let (entries, _): (Vec<SnapshotEntryV3>, usize) =
+                bincode::serde::decode_from_slice(&data[read..], config).map_err(invalid_data)?;
+            entries.into_iter()
+                .map(|e| SnapshotEntry {
+                    checksum: checksum(&e.data),
+                    key: e.key,
+                    compressed: e.compressed,
+                    data: e.data,
+                    expires_at: e.expires_at,
+                    stamp: e.stamp,
+                    crdt: false,
+                    stale_at: None,
+                    tombstone: false,
+                    visible_at: None,
+                })
+                .collect()
+        }
+        2 => {
+            let (entries, _): (Vec<SnapshotEntryV2>, usize) =
+                bincode::serde::decode_from_slice(&data[read..], config).map_err(invalid_data)?;
+            entries.into_iter()
+                .map(|e| SnapshotEntry {
+                    checksum: checksum(&e.data),
+                    key: e.key,
+                    compressed: e.compressed,
+                    data: e.data,
+                    expires_at: e.expires_at,
+                    stamp: WriteStamp::default(),
+                    crdt: false,
+                    stale_at: None,
+                    tombstone: false,
+                    visible_at: None,
+                })
+                .collect()
+        }
+        1 => {
+            let (entries, _): (Vec<SnapshotEntryV1>, usize) =
+                bincode::serde::decode_from_slice(&data[read..], config).map_err(invalid_data)?;
+            entries.into_iter()
+                .map(|e| SnapshotEntry {
+                    checksum: checksum(&e.data),
+                    key: e.key,
+                    compressed: e.compressed,
+                    data: e.data,
+                    expires_at: None,
+                    stamp: WriteStamp::default(),
+                    crdt: false,
+                    stale_at: None,
+                    tombstone: false,
+                    visible_at: None,
+                })
+                .collect()
+        }
+        _ => return Err(invalid_data(format!("unsupported snapshot version {}", version))),
+    };
+    // Keys that expired while the server was down are not restored, nor are
+    // values whose bytes rotted on disk
+    let now = now_ms();
+    let mut count = 0;
+    let mut corrupted = 0;
+    for entry in entries {
+        if entry.expires_at.is_some_and(|at| at <= now) {
+            continue;
+        }
+        let restored = CacheEntry {
+            data: entry.data.into(),
+            compressed: entry.compressed,
+            crdt: entry.crdt,
+            tombstone: entry.tombstone,
+            expires_at: entry.expires_at,
+            stale_at: entry.stale_at,
+            visible_at: entry.visible_at,
+            stamp: entry.stamp,
+            checksum: entry.checksum,
+            access: Access::default(),
+        };
+        if let Err(e) = restored.verify() {
+            warn!("Dropped {} from {}: {}", entry.key, path, e);
+            corrupted += 1;
+            continue;
+        }
+        cache.insert(entry.key, restored);
+        count += 1;
+    }
+    if corrupted > 0 {
+        warn!("Dropped {} corrupted keys from {}", corrupted, path);
+    }
+    info!("Loaded {} keys from {}", count, path);
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::encode_entry;
+    use crate::encryption::KEY_LEN;
+
+    // A snapshot path in the temp directory, removed once dropped
+    struct TempSnapshot(String);
+
+    impl TempSnapshot {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("pluto-snapshot-{}-{}.bin", name, std::process::id()));
+            TempSnapshot(path.to_string_lossy().into_owned())
+        }
+    }
+
+    impl Drop for TempSnapshot {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn keyspace() -> Keyspace {
+        let mut cache = Keyspace::new();
+        cache.insert("secret".to_string(), encode_entry(b"plaintext value".to_vec()).unwrap());
+        cache
+    }
+
+    #[test]
+    fn encrypted_snapshots_load_with_their_key() {
+        let snapshot = TempSnapshot::new("encrypted");
+        let keys = Keyring::new(&[3; KEY_LEN], &[]);
+        assert_eq!(save_snapshot(&keyspace().view(), &snapshot.0, &keys).unwrap(), 1);
+        assert_eq!(snapshot_key(&snapshot.0).unwrap(), keys.current_id());
+        let data = fs::read(&snapshot.0).unwrap();
+        assert!(!data.windows(6).any(|window| window == b"secret"));
+
+        let mut loaded = Keyspace::new();
+        assert_eq!(load_snapshot(&mut loaded, &snapshot.0, &keys).unwrap(), 1);
+        assert!(loaded.contains_key("secret"));
+        // Without the key the snapshot can't be read
+        let error = load_snapshot(&mut Keyspace::new(), &snapshot.0, &Keyring::new(&[4; KEY_LEN], &[])).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn unencrypted_snapshots_still_load_once_a_key_is_set() {
+        let snapshot = TempSnapshot::new("plain");
+        save_snapshot(&keyspace().view(), &snapshot.0, &Keyring::default()).unwrap();
+        assert_eq!(snapshot_key(&snapshot.0).unwrap(), None);
+        let mut loaded = Keyspace::new();
+        assert_eq!(load_snapshot(&mut loaded, &snapshot.0, &Keyring::new(&[3; KEY_LEN], &[])).unwrap(), 1);
+    }
+}
@@ -0,0 +1,779 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use crate::cache::{ServerError, WriteStamp};
+use crate::codec::Encoding;
+use crate::cluster::Hashing;
+
+// Define command types for our protocol
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Command {
+    // Set a key. With `visible_at`, a unix time in milliseconds, the value
+    // is stored right away but reads miss the key until then.
+    SET {
+        key: String,
+        #[serde(with = "serde_bytes")]
+        value: Vec<u8>,
+        #[serde(default)]
+        visible_at: Option<u64>,
+    },
+    // Read a key, replying Nil when it doesn't exist
+    GET { key: String },
+    DEL { keys: Vec<String> },
+    EXISTS { key: String },
+    CLUSTER_JOIN { address: String },
+    CLUSTER_REMOVE { address: String },
+    CLUSTER_ISOLATE,
+    // Forget every peer and start over as the only member of a new cluster,
+    // so the node can join another one; HARD also drops every key and takes
+    // a new node id
+    CLUSTER_RESET {
+        #[serde(default)]
+        mode: Option<ResetMode>,
+    },
+    CLUSTER_SLOTS,
+    NODE_INFO,
+    INFO {
+        #[serde(default)]
+        section: Option<String>,
+    },
+    CONFIG_GET { key: String },
+    SUBSCRIBE { channels: Vec<String> },
+    UNSUBSCRIBE {
+        #[serde(default)]
+        channels: Vec<String>,
+    },
+    // Send a message to a channel's subscribers, on whichever member they
+    // are connected to; replies with the number of them on the member that
+    // owns the channel's hash slot
+    PUBLISH {
+        channel: String,
+        #[serde(with = "serde_bytes")]
+        message: Vec<u8>,
+    },
+    // Switch the wire encoding of the connection after this command's response
+    ENCODING { name: Encoding },
+    // Handshake: report server capabilities, optionally requiring a protocol
+    // version and switching encodings in the same round trip
+    HELLO {
+        #[serde(default)]
+        protocol: Option<u32>,
+        #[serde(default)]
+        encoding: Option<Encoding>,
+    },
+    // Authenticate with requirepass, or as one of the configured users
+    AUTH {
+        #[serde(default)]
+        username: Option<String>,
+        password: String,
+    },
+    // Bytes accounted to a key: key, stored value and bookkeeping overhead
+    MEMORY_USAGE { key: String },
+    // Re-allocate values and shrink the keyspace in the background; progress is in INFO memory
+    MEMORY_DEFRAG,
+    // Liveness check; replies Pong, or echoes the message when one is given
+    PING {
+        #[serde(default, with = "serde_bytes")]
+        message: Option<Vec<u8>>,
+    },
+    ECHO {
+        #[serde(with = "serde_bytes")]
+        message: Vec<u8>,
+    },
+    // Stop the server after draining connections, optionally forcing or skipping a snapshot
+    SHUTDOWN {
+        #[serde(default)]
+        mode: Option<ShutdownMode>,
+    },
+    // Sent before a command that follows an Ask redirect
+    ASKING,
+    // Remove a key after `seconds`; replies 1 when the key exists, 0 otherwise.
+    // `jitter_pct` spreads the TTL by up to that percentage either way,
+    // overriding `ttl_jitter_pct`.
+    EXPIRE {
+        key: String,
+        seconds: u64,
+        #[serde(default)]
+        jitter_pct: Option<u8>,
+    },
+    // Seconds before a key expires: -1 without an expiry, -2 when missing
+    TTL { key: String },
+    // Serialize a key's value and remaining TTL into an opaque blob
+    DUMP { key: String },
+    // Re-create a key from a DUMP blob; fails when the key exists unless `replace` is set
+    RESTORE {
+        key: String,
+        #[serde(with = "serde_bytes")]
+        payload: Vec<u8>,
+        #[serde(default)]
+        replace: bool,
+    },
+    // Move a key to the node at `address`, deleting it here once the target
+    // acknowledged it; `copy` keeps the local key, `replace` overwrites the target's
+    MIGRATE {
+        key: String,
+        address: String,
+        #[serde(default)]
+        copy: bool,
+        #[serde(default)]
+        replace: bool,
+    },
+    // Let this connection read from a replica; writes are still redirected to the primary
+    READONLY,
+    // Undo READONLY, redirecting reads on a replica to the primary again
+    READWRITE,
+    // Sent by a replica to its primary: replies with the replication offset,
+    // then streams the keyspace and every later write as `Replicate` pushes
+    SYNC { address: String },
+    // Sent by a replica to its primary: every write up to `offset` has been
+    // applied; replies with the primary's offset, which the replica's lag is
+    // measured against
+    REPLACK { offset: u64 },
+    // Block until `replicas` replicas acknowledged the writes made so far, or
+    // `timeout` milliseconds passed (0 waits forever); replies with the number that did
+    WAIT { replicas: usize, timeout: u64 },
+    // Run a write and reply only once `replicas` replicas applied it,
+    // overriding `write_quorum` for this command. A GET is read on the
+    // replicas as well and answered with the newest copy once `replicas`
+    // answered; the stale copies are repaired in the background.
+    QUORUM { replicas: usize, command: Box<Command> },
+    // Sent by a replica to its primary: hashes of the listed nodes at `level`
+    // of the primary's Merkle tree, level 0 being the root. Asking for the
+    // root takes a fresh tree; deeper levels come from the same one.
+    MERKLE { level: usize, nodes: Vec<usize> },
+    // Sent by a replica to its primary: the digest of every key in a slot
+    DIGEST { slot: usize },
+    // Sent by a replica to its primary: stream the current value of the keys,
+    // or their deletion, to every replica
+    REPAIR { keys: Vec<String> },
+    // Number of keys on this node
+    DBSIZE,
+    // Remove every key on this node
+    FLUSHALL,
+    // Keys on this node matching a glob pattern. With a `chunk`, the reply is
+    // streamed as KeysChunk frames of at most that many keys, the last ones
+    // coming in the reply itself.
+    KEYS {
+        pattern: String,
+        #[serde(default)]
+        chunk: Option<usize>,
+    },
+    // The same, run on every member of the cluster; replies with the combined
+    // result and the members that failed
+    CLUSTER_DBSIZE,
+    CLUSTER_FLUSHALL,
+    CLUSTER_INFO {
+        #[serde(default)]
+        section: Option<String>,
+    },
+    CLUSTER_KEYS {
+        pattern: String,
+        #[serde(default)]
+        chunk: Option<usize>,
+    },
+    // Move every key of the slots to the node at `address` in the background,
+    // throttled and resumed after a restart; progress is in INFO cluster
+    MIGRATE_SLOTS { slots: Vec<usize>, address: String },
+    // Stop the slot migration in progress, keeping the keys not moved yet
+    MIGRATE_SLOTS_ABORT,
+    // Assign slots to a member or unassign them through the metadata log, or
+    // mark them on this node as moving out or in while their keys migrate
+    CLUSTER_SETSLOT { slots: Vec<usize>, state: SlotState },
+    // Sent by the cluster shipping its writes to this standby cluster: a
+    // zstd-compressed batch of writes. A member forwards the writes of slots
+    // other members own to them, marking the batch `forwarded`.
+    GEO_APPLY {
+        source: String,
+        #[serde(with = "serde_bytes")]
+        batch: Vec<u8>,
+        #[serde(default)]
+        forwarded: bool,
+    },
+    // Turn this standby cluster into a writable one, stopping geo-replication
+    // into it; `local` promotes only this node, as sent to the other members
+    GEO_PROMOTE {
+        #[serde(default)]
+        local: bool,
+    },
+    // Add `delta` to a counter that several primaries may update at once,
+    // creating it at 0; replies with the new value
+    COUNTER_INCRBY { key: String, delta: i64 },
+    // Value of a counter, 0 when missing
+    COUNTER_GET { key: String },
+    // Add members to a set that several primaries may update at once; an add
+    // wins over a concurrent removal. Replies with the number of new members.
+    ORSET_ADD { key: String, members: Vec<String> },
+    // Remove members from a set; replies with the number that were in it
+    ORSET_REM { key: String, members: Vec<String> },
+    // Members of a set, sorted; empty when missing
+    ORSET_MEMBERS { key: String },
+    // Remove keys like DEL, freeing large values in the background rather
+    // than while other clients wait; replies with the number removed
+    UNLINK { keys: Vec<String> },
+    // Remove a key at a unix time in seconds; replies 1 when the key exists, 0 otherwise
+    EXPIREAT { key: String, timestamp: u64 },
+    // Remove a key after `milliseconds`; replies and is jittered like EXPIRE
+    PEXPIRE {
+        key: String,
+        milliseconds: u64,
+        #[serde(default)]
+        jitter_pct: Option<u8>,
+    },
+    // Remove a key at a unix time in milliseconds; replies like EXPIRE
+    PEXPIREAT { key: String, timestamp_ms: u64 },
+    // Milliseconds before a key expires: -1 without an expiry, -2 when missing
+    PTTL { key: String },
+    // Drop a key's expiry; replies 1 when there was one, 0 otherwise
+    PERSIST { key: String },
+    // Seconds since a key was last read or written
+    OBJECT_IDLETIME { key: String },
+    // A key's logarithmic access counter, which decays while the key is idle
+    OBJECT_FREQ { key: String },
+    // Have the connection told when keys it reads change, by Invalidate pushes
+    CLIENT_TRACKING { on: bool },
+    // Set every key, only when none of them exists; replies 1 when they were
+    // set, 0 otherwise. The keys must be served by the same node.
+    MSETNX { entries: Vec<(String, Bytes)> },
+    // Number of the keys that exist, a key listed twice counting twice
+    EXISTS_MANY { keys: Vec<String> },
+    // A key's bookkeeping: when it was created, written and read, how often,
+    // and how its value is stored
+    DEBUG_OBJECT { key: String },
+    // Have GET flag a key's value stale after `seconds`, while still serving
+    // it until it expires; replies 1 when the key exists, 0 otherwise
+    SOFT_EXPIRE { key: String, seconds: u64 },
+    // Seconds before a key goes stale: -1 without a soft TTL, -2 when missing
+    SOFT_TTL { key: String },
+    // Cache that a key is missing for `milliseconds`, replacing any value;
+    // GET replies Missing until then rather than looking the key up again
+    SET_MISSING { key: String, milliseconds: u64 },
+    // Read a key; when it's missing, the first caller takes its lock for
+    // `lock_ms` and is told to compute it, while later ones wait up to
+    // `wait_ms` for it to be written and are told it is in progress if it
+    // isn't. Writing the key releases the lock.
+    GET_OR_LOCK {
+        key: String,
+        lock_ms: u64,
+        #[serde(default)]
+        wait_ms: u64,
+    },
+    // Release a key's GET_OR_LOCK lock without writing it; replies 1 when it was held
+    UNLOCK { key: String },
+    // Set a key and reply with the value it replaced, read as GET would, or
+    // Nil when it had none
+    GETSET {
+        key: String,
+        #[serde(with = "serde_bytes")]
+        value: Vec<u8>,
+    },
+    // Stream a KeyEvent for every key matching one of the glob patterns that
+    // this node expires or evicts; replies with the number of patterns now
+    // watched. Like SUBSCRIBE, it needs a streaming connection.
+    KEYEVENTS_SUBSCRIBE { patterns: Vec<String> },
+    // Stop watching patterns (all of them when empty); replies with the number left
+    KEYEVENTS_UNSUBSCRIBE {
+        #[serde(default)]
+        patterns: Vec<String>,
+    },
+    // Every command the server takes, with its arity, flags and the version
+    // that added it
+    COMMAND,
+    // Usage and summary of the named commands, of all when none is named;
+    // unknown names are left out
+    COMMAND_DOCS {
+        #[serde(default)]
+        names: Vec<String>,
+    },
+    // Version of the server, the commit and features it was built with, and
+    // the protocol it speaks; the same lines open INFO server
+    VERSION,
+    // Whether snapshots, the write-ahead log and backups are succeeding, and
+    // how far the snapshot being saved is, as `key:value` lines
+    PERSISTENCE_STATUS,
+}
+
+impl Command {
+    // Commands accepted on the admin port
+    pub fn is_admin(&self) -> bool {
+        matches!(
+            self,
+            Command::CLUSTER_JOIN { .. }
+                | Command::CLUSTER_REMOVE { .. }
+                | Command::CLUSTER_ISOLATE
+                | Command::CLUSTER_RESET { .. }
+                | Command::CLUSTER_SLOTS
+                | Command::NODE_INFO
+                | Command::INFO { .. }
+                | Command::CONFIG_GET { .. }
+                | Command::HELLO { .. }
+                | Command::AUTH { .. }
+                | Command::SHUTDOWN { .. }
+                | Command::PING { .. }
+                | Command::ECHO { .. }
+                | Command::MEMORY_DEFRAG
+                | Command::DBSIZE
+                | Command::CLUSTER_DBSIZE
+                | Command::CLUSTER_INFO { .. }
+                | Command::MIGRATE_SLOTS { .. }
+                | Command::MIGRATE_SLOTS_ABORT
+                | Command::CLUSTER_SETSLOT { .. }
+                | Command::GEO_PROMOTE { .. }
+                | Command::COMMAND
+                | Command::COMMAND_DOCS { .. }
+                | Command::VERSION
+                | Command::PERSISTENCE_STATUS
+        )
+    }
+
+    // Command name as it appears on the wire
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::SET { .. } => "SET",
+            Command::GET { .. } => "GET",
+            Command::DEL { .. } => "DEL",
+            Command::EXISTS { .. } => "EXISTS",
+            Command::CLUSTER_JOIN { .. } => "CLUSTER_JOIN",
+            Command::CLUSTER_REMOVE { .. } => "CLUSTER_REMOVE",
+            Command::CLUSTER_ISOLATE => "CLUSTER_ISOLATE",
+            Command::CLUSTER_RESET { .. } => "CLUSTER_RESET",
+            Command::CLUSTER_SLOTS => "CLUSTER_SLOTS",
+            Command::NODE_INFO => "NODE_INFO",
+            Command::INFO { .. } => "INFO",
+            Command::CONFIG_GET { .. } => "CONFIG_GET",
+            Command::SUBSCRIBE { .. } => "SUBSCRIBE",
+            Command::UNSUBSCRIBE { .. } => "UNSUBSCRIBE",
+            Command::PUBLISH { .. } => "PUBLISH",
+            Command::ENCODING { .. } => "ENCODING",
+            Command::HELLO { .. } => "HELLO",
+            Command::AUTH { .. } => "AUTH",
+            Command::PING { .. } => "PING",
+            Command::ECHO { .. } => "ECHO",
+            Command::SHUTDOWN { .. } => "SHUTDOWN",
+            Command::MEMORY_USAGE { .. } => "MEMORY_USAGE",
+            Command::MEMORY_DEFRAG => "MEMORY_DEFRAG",
+            Command::ASKING => "ASKING",
+            Command::EXPIRE { .. } => "EXPIRE",
+            Command::TTL { .. } => "TTL",
+            Command::DUMP { .. } => "DUMP",
+            Command::RESTORE { .. } => "RESTORE",
+            Command::MIGRATE { .. } => "MIGRATE",
+            Command::READONLY => "READONLY",
+            Command::READWRITE => "READWRITE",
+            Command::SYNC { .. } => "SYNC",
+            Command::REPLACK { .. } => "REPLACK",
+            Command::WAIT { .. } => "WAIT",
+            Command::QUORUM { .. } => "QUORUM",
+            Command::MERKLE { .. } => "MERKLE",
+            Command::DIGEST { .. } => "DIGEST",
+            Command::REPAIR { .. } => "REPAIR",
+            Command::DBSIZE => "DBSIZE",
+            Command::FLUSHALL => "FLUSHALL",
+            Command::KEYS { .. } => "KEYS",
+            Command::CLUSTER_DBSIZE => "CLUSTER_DBSIZE",
+            Command::CLUSTER_FLUSHALL => "CLUSTER_FLUSHALL",
+            Command::CLUSTER_INFO { .. } => "CLUSTER_INFO",
+            Command::CLUSTER_KEYS { .. } => "CLUSTER_KEYS",
+            Command::MIGRATE_SLOTS { .. } => "MIGRATE_SLOTS",
+            Command::MIGRATE_SLOTS_ABORT => "MIGRATE_SLOTS_ABORT",
+            Command::CLUSTER_SETSLOT { .. } => "CLUSTER_SETSLOT",
+            Command::GEO_APPLY { .. } => "GEO_APPLY",
+            Command::GEO_PROMOTE { .. } => "GEO_PROMOTE",
+            Command::COUNTER_INCRBY { .. } => "COUNTER_INCRBY",
+            Command::COUNTER_GET { .. } => "COUNTER_GET",
+            Command::ORSET_ADD { .. } => "ORSET_ADD",
+            Command::ORSET_REM { .. } => "ORSET_REM",
+            Command::ORSET_MEMBERS { .. } => "ORSET_MEMBERS",
+            Command::UNLINK { .. } => "UNLINK",
+            Command::EXPIREAT { .. } => "EXPIREAT",
+            Command::PEXPIRE { .. } => "PEXPIRE",
+            Command::PEXPIREAT { .. } => "PEXPIREAT",
+            Command::PTTL { .. } => "PTTL",
+            Command::PERSIST { .. } => "PERSIST",
+            Command::OBJECT_IDLETIME { .. } => "OBJECT_IDLETIME",
+            Command::OBJECT_FREQ { .. } => "OBJECT_FREQ",
+            Command::CLIENT_TRACKING { .. } => "CLIENT_TRACKING",
+            Command::MSETNX { .. } => "MSETNX",
+            Command::EXISTS_MANY { .. } => "EXISTS_MANY",
+            Command::DEBUG_OBJECT { .. } => "DEBUG_OBJECT",
+            Command::SOFT_EXPIRE { .. } => "SOFT_EXPIRE",
+            Command::SOFT_TTL { .. } => "SOFT_TTL",
+            Command::GET_OR_LOCK { .. } => "GET_OR_LOCK",
+            Command::UNLOCK { .. } => "UNLOCK",
+            Command::SET_MISSING { .. } => "SET_MISSING",
+            Command::GETSET { .. } => "GETSET",
+            Command::KEYEVENTS_SUBSCRIBE { .. } => "KEYEVENTS_SUBSCRIBE",
+            Command::KEYEVENTS_UNSUBSCRIBE { .. } => "KEYEVENTS_UNSUBSCRIBE",
+            Command::COMMAND => "COMMAND",
+            Command::COMMAND_DOCS { .. } => "COMMAND_DOCS",
+            Command::VERSION => "VERSION",
+            Command::PERSISTENCE_STATUS => "PERSISTENCE_STATUS",
+        }
+    }
+
+    // Number of cache keys the command touches
+    pub fn key_count(&self) -> usize {
+        match self {
+            Command::SET { .. } | Command::GET { .. } | Command::EXISTS { .. } | Command::MEMORY_USAGE { .. }
+                | Command::EXPIRE { .. } | Command::TTL { .. } | Command::DUMP { .. } | Command::RESTORE { .. }
+                | Command::MIGRATE { .. } | Command::COUNTER_INCRBY { .. } | Command::COUNTER_GET { .. }
+                | Command::ORSET_ADD { .. } | Command::ORSET_REM { .. } | Command::ORSET_MEMBERS { .. }
+                | Command::EXPIREAT { .. } | Command::PEXPIRE { .. } | Command::PEXPIREAT { .. } | Command::PTTL { .. }
+                | Command::PERSIST { .. } | Command::OBJECT_IDLETIME { .. } | Command::OBJECT_FREQ { .. }
+                | Command::DEBUG_OBJECT { .. } | Command::SOFT_EXPIRE { .. } | Command::SOFT_TTL { .. }
+                | Command::GET_OR_LOCK { .. } | Command::UNLOCK { .. } | Command::SET_MISSING { .. }
+                | Command::GETSET { .. } => 1,
+            Command::DEL { keys } | Command::UNLINK { keys } | Command::EXISTS_MANY { keys } => keys.len(),
+            Command::MSETNX { entries } => entries.len(),
+            Command::QUORUM { command, .. } => command.key_count(),
+            _ => 0,
+        }
+    }
+
+    // Cache keys the command touches, which decide the node it belongs to
+    pub fn keys(&self) -> Vec<&str> {
+        match self {
+            Command::SET { key, .. } | Command::GET { key } | Command::EXISTS { key } | Command::MEMORY_USAGE { key }
+                | Command::EXPIRE { key, .. } | Command::TTL { key } | Command::DUMP { key } | Command::RESTORE { key, .. }
+                | Command::MIGRATE { key, .. } | Command::COUNTER_INCRBY { key, .. } | Command::COUNTER_GET { key }
+                | Command::ORSET_ADD { key, .. } | Command::ORSET_REM { key, .. } | Command::ORSET_MEMBERS { key }
+                | Command::EXPIREAT { key, .. } | Command::PEXPIRE { key, .. } | Command::PEXPIREAT { key, .. }
+                | Command::PTTL { key } | Command::PERSIST { key } | Command::OBJECT_IDLETIME { key }
+                | Command::OBJECT_FREQ { key } | Command::DEBUG_OBJECT { key } | Command::SOFT_EXPIRE { key, .. }
+                | Command::SOFT_TTL { key } | Command::GET_OR_LOCK { key, .. } | Command::UNLOCK { key }
+                | Command::SET_MISSING { key, .. } | Command::GETSET { key, .. } => {
+                vec![key.as_str()]
+            }
+            Command::DEL { keys } | Command::UNLINK { keys } | Command::EXISTS_MANY { keys } => {
+                keys.iter().map(String::as_str).collect()
+            }
+            Command::MSETNX { entries } => entries.iter().map(|(key, _)| key.as_str()).collect(),
+            Command::QUORUM { command, .. } => command.keys(),
+            _ => Vec::new(),
+        }
+    }
+
+    // The keys of `keys`, to rewrite in place
+    pub fn keys_mut(&mut self) -> Vec<&mut String> {
+        match self {
+            Command::SET { key, .. } | Command::GET { key } | Command::EXISTS { key } | Command::MEMORY_USAGE { key }
+                | Command::EXPIRE { key, .. } | Command::TTL { key } | Command::DUMP { key } | Command::RESTORE { key, .. }
+                | Command::MIGRATE { key, .. } | Command::COUNTER_INCRBY { key, .. } | Command::COUNTER_GET { key }
+                | Command::ORSET_ADD { key, .. } | Command::ORSET_REM { key, .. } | Command::ORSET_MEMBERS { key }
+                | Command::EXPIREAT { key, .. } | Command::PEXPIRE { key, .. } | Command::PEXPIREAT { key, .. }
+                | Command::PTTL { key } | Command::PERSIST { key } | Command::OBJECT_IDLETIME { key }
+                | Command::OBJECT_FREQ { key } | Command::DEBUG_OBJECT { key } | Command::SOFT_EXPIRE { key, .. }
+                | Command::SOFT_TTL { key } | Command::GET_OR_LOCK { key, .. } | Command::UNLOCK { key }
+                | Command::SET_MISSING { key, .. } | Command::GETSET { key, .. } => {
+                vec![key]
+            }
+            Command::DEL { keys } | Command::UNLINK { keys } | Command::EXISTS_MANY { keys } => keys.iter_mut().collect(),
+            Command::MSETNX { entries } => entries.iter_mut().map(|(key, _)| key).collect(),
+            Command::QUORUM { command, .. } => command.keys_mut(),
+            _ => Vec::new(),
+        }
+    }
+
+    // Commands that modify the keyspace, which only a primary accepts
+    pub fn is_write(&self) -> bool {
+        if let Command::QUORUM { command, .. } = self {
+            return command.is_write();
+        }
+        matches!(
+            self,
+            Command::SET { .. } | Command::DEL { .. } | Command::EXPIRE { .. } | Command::RESTORE { .. }
+                | Command::MIGRATE { .. } | Command::FLUSHALL
+                | Command::CLUSTER_FLUSHALL | Command::MIGRATE_SLOTS { .. } | Command::COUNTER_INCRBY { .. }
+                | Command::ORSET_ADD { .. } | Command::ORSET_REM { .. } | Command::UNLINK { .. }
+                | Command::EXPIREAT { .. } | Command::PEXPIRE { .. } | Command::PEXPIREAT { .. } | Command::PERSIST { .. }
+                | Command::MSETNX { .. } | Command::SOFT_EXPIRE { .. } | Command::SET_MISSING { .. }
+                | Command::GETSET { .. }
+        )
+    }
+
+    // Whether the commands after this one use a different encoding
+    pub fn switches_encoding(&self) -> bool {
+        matches!(self, Command::ENCODING { .. } | Command::HELLO { encoding: Some(_), .. })
+    }
+
+    // Most keys per frame when the reply is streamed
+    pub fn chunk(&self) -> Option<usize> {
+        match self {
+            Command::KEYS { chunk, .. } | Command::CLUSTER_KEYS { chunk, .. } => *chunk,
+            _ => None,
+        }
+    }
+}
+
+// Define response types for our protocol
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Response {
+    Success,
+    Error(ErrorReply),
+    Data(Bytes),
+    Exists(bool),
+    Slots(String),
+    NodeInfo {
+        node_id: String,
+        address: String,
+        #[serde(default)]
+        weight: u32, // ring weight the node joins a cluster with
+        #[serde(default)]
+        hashing: Hashing,
+    },
+    Info(String),
+    Config(BTreeMap<String, String>),
+    Integer(i64),
+    // Pushed to subscribers when a message is published on one of their channels
+    Message { channel: String, message: Bytes },
+    Pong,
+    Hello {
+        server: String,
+        version: String,
+        protocol: u32,
+        encodings: Vec<Encoding>,
+        compression: Vec<String>,
+        auth_required: bool,
+    },
+    // The key's slot is served by another node; retry there and refresh the slot map
+    Moved { slot: usize, address: String },
+    // The slot is being moved; retry this one command at `address` after ASKING
+    Ask { slot: usize, address: String },
+    // Pushed to a replica after SYNC: a write to apply and the primary's offset
+    // after it, with the stamp of the entry it wrote. `local_only` marks writes
+    // geo-replication doesn't ship: deleting a key moved to another node, which
+    // ships it from there, and writes that came from another cluster.
+    Replicate {
+        offset: u64,
+        command: Command,
+        #[serde(default)]
+        stamp: Option<WriteStamp>,
+        #[serde(default)]
+        local_only: bool,
+    },
+    // Merkle tree hashes, in the order they were asked for
+    Digests(Vec<u64>),
+    // Keys of a slot with the digest of their value
+    KeyDigests(Vec<(String, u64)>),
+    // Key names, as KEYS lists them
+    Keys(Vec<String>),
+    // Members of a set, sorted
+    Members(Vec<String>),
+    // Result of a command run on every cluster member, combined, with the
+    // error of each member that didn't answer
+    Aggregate { result: Box<Response>, failures: BTreeMap<String, String> },
+    // Pushed to a connection with CLIENT_TRACKING on when keys it read
+    // change; no keys means every key did, as after FLUSHALL
+    Invalidate { keys: Vec<String> },
+    // A key's bookkeeping, as DEBUG_OBJECT reports it
+    Object(ObjectInfo),
+    // A value GET found past its soft TTL, for the reader to refresh
+    Stale(Bytes),
+    // GET_OR_LOCK found the key missing and took its lock: compute the value and write it
+    Locked,
+    // GET_OR_LOCK found the key missing and locked by a client computing it
+    InProgress,
+    // The key is cached as missing, as SET_MISSING recorded
+    Missing,
+    // Pushed to a connection watching keys with KEYEVENTS_SUBSCRIBE
+    KeyEvent(KeyEvent),
+    // The key doesn't exist; errors are kept for reads that failed
+    Nil,
+    // Reply to COMMAND
+    Commands(Vec<CommandInfo>),
+    // Reply to COMMAND_DOCS
+    CommandDocs(Vec<CommandDoc>),
+    // Part of a streamed list of keys; more frames follow, up to the reply itself
+    KeysChunk(Vec<String>),
+}
+
+impl Response {
+    // Split a list of keys, alone or combined by a cluster-wide command, into
+    // KeysChunk frames of at most `chunk` keys followed by the reply with the rest
+    pub fn into_frames(mut self, chunk: usize) -> Vec<Response> {
+        let mut frames = Vec::new();
+        if chunk > 0 && let Some(keys) = self.listed_keys() {
+            let mut rest = std::mem::take(keys).into_iter();
+            while rest.len() > chunk {
+                frames.push(Response::KeysChunk(rest.by_ref().take(chunk).collect()));
+            }
+            *keys = rest.collect();
+        }
+        frames.push(self);
+        frames
+    }
+
+    // Put a streamed reply back together from the keys of its KeysChunk frames
+    pub fn with_leading_keys(mut self, mut leading: Vec<String>) -> Response {
+        if !leading.is_empty() && let Some(keys) = self.listed_keys() {
+            leading.append(keys);
+            *keys = leading;
+        }
+        self
+    }
+
+    fn listed_keys(&mut self) -> Option<&mut Vec<String>> {
+        match self {
+            Response::Keys(keys) => Some(keys),
+            Response::Aggregate { result, .. } => result.listed_keys(),
+            _ => None,
+        }
+    }
+}
+
+// A failed command's reply: a code clients can branch on, and a message for people
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ErrorReply {
+    pub code: ErrorCode,
+    pub message: String,
+    #[serde(default)]
+    pub details: Option<String>, // what went wrong underneath, when there's more to tell
+}
+
+impl ErrorReply {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        ErrorReply { code, message: message.into(), details: None }
+    }
+
+    pub fn with_details(mut self, details: impl ToString) -> Self {
+        self.details = Some(details.to_string());
+        self
+    }
+}
+
+impl From<ServerError> for ErrorReply {
+    fn from(error: ServerError) -> Self {
+        ErrorReply::new(error.code(), error.to_string())
+    }
+}
+
+impl fmt::Display for ErrorReply {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.details {
+            Some(details) => write!(f, "{} ({})", self.message, details),
+            None => f.write_str(&self.message),
+        }
+    }
+}
+
+// Kinds of failure. Keys owned elsewhere aren't errors: they are answered
+// with Moved or Ask.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum ErrorCode {
+    Err, // any failure without a code of its own
+    Syntax, // the command couldn't be parsed
+    Invalid, // its arguments are out of range, too large, or don't go together
+    NotFound, // a command needing the key, or node, found none
+    WrongType, // the key holds a kind of value the command doesn't work on
+    NoAuth, // authentication is required, or failed
+    NoPerm, // the user may not run the command or touch the key
+    Oom, // a quota would be exceeded
+    NoQuorum, // too few replicas acknowledged a write
+    Timeout,
+    Corrupt, // a stored value failed its checksum
+    Origin, // the origin backing the key failed
+    RateLimited, // the connection sent more commands than it may
+    Denied, // the server refused the connection: protected mode, or client limits
+    ClusterDown, // the node can't reach enough of the cluster to take writes
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ErrorCode::Err => "ERR",
+            ErrorCode::Syntax => "SYNTAX",
+            ErrorCode::Invalid => "INVALID",
+            ErrorCode::NotFound => "NOTFOUND",
+            ErrorCode::WrongType => "WRONGTYPE",
+            ErrorCode::NoAuth => "NOAUTH",
+            ErrorCode::NoPerm => "NOPERM",
+            ErrorCode::Oom => "OOM",
+            ErrorCode::NoQuorum => "NOQUORUM",
+            ErrorCode::Timeout => "TIMEOUT",
+            ErrorCode::Corrupt => "CORRUPT",
+            ErrorCode::Origin => "ORIGIN",
+            ErrorCode::RateLimited => "RATELIMITED",
+            ErrorCode::Denied => "DENIED",
+            ErrorCode::ClusterDown => "CLUSTERDOWN",
+        })
+    }
+}
+
+// What COMMAND reports about a command; see `commands::CommandSpec`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CommandInfo {
+    pub name: String,
+    pub arity: i32, // words including the name: N exactly, -N at least N
+    pub flags: Vec<String>,
+    pub since: String,
+}
+
+// What COMMAND_DOCS reports about a command
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CommandDoc {
+    pub name: String,
+    pub usage: String, // arguments after the name
+    pub summary: String,
+    pub since: String,
+}
+
+// A key matching a watched pattern left the keyspace
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyEvent {
+    pub key: String,
+    pub reason: KeyEventReason,
+    pub size: usize, // bytes the key took, as MEMORY_USAGE counts them
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum KeyEventReason {
+    Expired, // its expiry passed
+    Evicted, // removed to stay within memory limits
+}
+
+// What DEBUG_OBJECT reports about a key. Times are unix times in milliseconds;
+// creation and accesses count from when this node wrote or loaded the key.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ObjectInfo {
+    pub created_at: u64,
+    pub modified_at: u64, // time of the write stamped on the entry
+    pub accessed_at: u64,
+    pub accesses: u64, // reads and writes
+    pub version: u64, // writes the key has seen, across clusters
+    pub stored_bytes: usize, // value as stored, after compression
+    pub memory: usize, // as MEMORY_USAGE counts it
+    pub codec: String, // "raw", "zstd", "crdt" or "missing"
+    pub inline: bool, // whether the value is kept inside its entry
+    #[serde(default)]
+    pub visible_at: Option<u64>, // when a value scheduled by SET becomes readable
+}
+
+// What CLUSTER_SETSLOT does with its slots
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SlotState {
+    // Owned by the member at `address`, on every node
+    NODE { address: String },
+    // Owned by no member, on every node
+    UNASSIGNED,
+    // On this node: keys missing here are asked of `address`, which takes the slot over
+    MIGRATING { address: String },
+    // On this node: commands sent after ASKING are served before the slot is ours
+    IMPORTING { address: String },
+    // On this node: neither migrating nor importing
+    STABLE,
+}
+
+// Whether SHUTDOWN writes a snapshot, overriding `save_on_shutdown`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ShutdownMode {
+    SAVE,
+    NOSAVE,
+}
+
+// How much CLUSTER_RESET forgets; SOFT unless given
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ResetMode {
+    SOFT,
+    HARD,
+}
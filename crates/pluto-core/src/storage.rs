@@ -0,0 +1,633 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use bytes::Bytes;
+use log::{error, warn};
+use memmap2::Mmap;
+use crate::cache::{now_ms, Access, CacheEntry, WriteStamp};
+use crate::key::Key;
+
+// Where a keyspace keeps its entries besides the hash table in memory. Every
+// change to the keyspace is passed on, so an engine persisting them can give
+// the keyspace back after a restart.
+pub trait StorageEngine: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    // An entry was stored under `key`, replacing `replaced`
+    fn put(&mut self, key: &str, entry: &CacheEntry, replaced: Option<&CacheEntry>);
+
+    fn expire(&mut self, key: &str, expires_at: Option<u64>);
+
+    fn soft_expire(&mut self, key: &str, stale_at: Option<u64>);
+
+    fn delete(&mut self, key: &str, removed: &CacheEntry);
+
+    fn clear(&mut self);
+
+    // Whether enough of what is stored is outdated to rewrite it
+    fn wants_compaction(&self) -> bool {
+        false
+    }
+
+    // Rewrite the live entries, which may move where their values are kept
+    fn compact(&mut self, _entries: Vec<(&Key, &mut CacheEntry)>) {}
+
+    // The file syncing which makes every change so far survive losing
+    // power, handed out so the keyspace needn't be held while it syncs
+    fn file_to_sync(&self) -> io::Result<Option<File>> {
+        Ok(None)
+    }
+
+    fn stats(&self) -> StorageStats {
+        StorageStats::default()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StorageStats {
+    pub segments: usize,
+    pub bytes: u64, // on disk, across all segments
+    pub garbage_bytes: u64, // of records a later one made outdated
+    pub compactions: u64,
+    pub write_errors: u64,
+}
+
+// Entries kept in memory only, lost with the process unless a snapshot or
+// the write-ahead log has them
+pub struct MemoryEngine;
+
+impl StorageEngine for MemoryEngine {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    fn put(&mut self, _key: &str, _entry: &CacheEntry, _replaced: Option<&CacheEntry>) {}
+
+    fn expire(&mut self, _key: &str, _expires_at: Option<u64>) {}
+
+    fn soft_expire(&mut self, _key: &str, _stale_at: Option<u64>) {}
+
+    fn delete(&mut self, _key: &str, _removed: &CacheEntry) {}
+
+    fn clear(&mut self) {}
+}
+
+// Every change appended to segment files in a directory, as a log. When the
+// process restarts the segments are mapped into memory and the keyspace is
+// rebuilt from their records, its values pointing into the mapped files
+// rather than copied out of them, so a restart takes as long as reading the
+// keys. Once most of the log is outdated the live entries are rewritten into
+// a base segment, and the segments before it are dropped.
+//
+// A segment starts with SEGMENT_MAGIC and whether it is a base, then holds
+// records of
+//
+//   crc32 of the body    u32
+//   length of the body   u32
+//   kind                 u8
+//   key length           u32
+//   key
+//   PUT:          flags u8, expires_at u64, stamp at u64, stamp version u64,
+//                 value checksum u32, stale_at u64 and visible_at u64 when
+//                 flagged, then the stored bytes
+//   EXPIRE:       flags u8, expires_at u64
+//   DELETE:       nothing
+//   SOFT_EXPIRE:  flags u8, stale_at u64
+//
+// all little endian.
+pub struct MappedEngine {
+    dir: PathBuf,
+    active: File,
+    active_id: u64,
+    active_len: u64,
+    segments: Vec<u64>, // ids of the segments in use, the active one last
+    bytes: u64,
+    garbage_bytes: u64,
+    compactions: u64,
+    write_errors: u64,
+    sync_writes: bool,
+}
+
+const SEGMENT_MAGIC: &[u8] = b"PLSEG\x01";
+const SEGMENT_HEADER_LEN: u64 = SEGMENT_MAGIC.len() as u64 + 1;
+const SEGMENT_EXTENSION: &str = "seg";
+
+// The active segment is closed and a new one started past this size
+const SEGMENT_BYTES: u64 = 64 * 1024 * 1024;
+// Compaction waits for at least this much garbage, and for it to outweigh
+// the live records
+const COMPACT_MIN_BYTES: u64 = 16 * 1024 * 1024;
+
+const RECORD_PUT: u8 = 1;
+const RECORD_EXPIRE: u8 = 2;
+const RECORD_DELETE: u8 = 3;
+const RECORD_SOFT_EXPIRE: u8 = 4;
+
+const FLAG_COMPRESSED: u8 = 1;
+const FLAG_CRDT: u8 = 2;
+const FLAG_EXPIRES: u8 = 4;
+const FLAG_STALES: u8 = 8;
+const FLAG_TOMBSTONE: u8 = 16;
+const FLAG_HIDDEN: u8 = 32;
+
+// Before the key
+const RECORD_HEADER_LEN: usize = 4 + 4 + 1 + 4;
+// Between the key and the stored bytes of a PUT
+const PUT_FIELDS_LEN: usize = 1 + 8 + 8 + 8 + 4;
+const EXPIRE_FIELDS_LEN: usize = 1 + 8;
+
+impl MappedEngine {
+    // Open the segments in `dir`, returning the engine and the live entries
+    // they hold. With `sync_writes` every record is synced as it is written.
+    pub fn open(dir: impl AsRef<Path>, sync_writes: bool) -> io::Result<(Self, Vec<(String, CacheEntry)>)> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let mut ids = list_segments(&dir)?;
+        // A base segment holds everything the ones before it did
+        let mut base = None;
+        for id in ids.iter().rev() {
+            if is_base(&segment_path(&dir, *id))? {
+                base = Some(*id);
+                break;
+            }
+        }
+        if let Some(base) = base {
+            for id in ids.iter().filter(|id| **id < base) {
+                fs::remove_file(segment_path(&dir, *id))?;
+            }
+            ids.retain(|id| *id >= base);
+        }
+
+        let mut entries: HashMap<String, CacheEntry> = HashMap::new();
+        let mut bytes = 0;
+        let mut garbage_bytes = 0;
+        let last = ids.last().copied();
+        let mut kept = Vec::with_capacity(ids.len() + 1);
+        for id in ids {
+            let path = segment_path(&dir, id);
+            let file = File::open(&path)?;
+            let len = file.metadata()?.len();
+            // Left by a restart nothing was written after
+            if len <= SEGMENT_HEADER_LEN && Some(id) != base {
+                fs::remove_file(&path)?;
+                continue;
+            }
+            kept.push(id);
+            bytes += len;
+            // SAFETY: segments are only ever appended to, and by this process
+            // alone; a torn record at the end is never read again
+            let map = unsafe { Mmap::map(&file)? };
+            let segment = Bytes::from_owner(map);
+            let (records, complete) = read_records(&segment, SEGMENT_HEADER_LEN as usize);
+            for record in records {
+                garbage_bytes += record.apply(&segment, &mut entries);
+            }
+            if complete < segment.len() {
+                let what = if Some(id) == last { "a torn write" } else { "a corrupted record" };
+                warn!("{} ends in {}; read up to byte {} of {}", path.display(), what, complete, segment.len());
+                garbage_bytes += (segment.len() - complete) as u64;
+            }
+        }
+        let now = now_ms();
+        entries.retain(|key, entry| {
+            let live = !entry.is_expired(now);
+            if !live {
+                garbage_bytes += put_len(key, entry);
+            }
+            live
+        });
+
+        // Records are never appended to a segment written before, whose end
+        // may be torn
+        let active_id = last.map_or(1, |last| last + 1);
+        let active = start_segment(&dir, active_id)?;
+        kept.push(active_id);
+        let engine = MappedEngine {
+            dir,
+            active,
+            active_id,
+            active_len: SEGMENT_HEADER_LEN,
+            segments: kept,
+            bytes: bytes + SEGMENT_HEADER_LEN,
+            garbage_bytes,
+            compactions: 0,
+            write_errors: 0,
+            sync_writes,
+        };
+        Ok((engine, entries.into_iter().collect()))
+    }
+
+    fn append(&mut self, record: &[u8]) {
+        let written = self.active.write_all(record).and_then(|_| {
+            if self.sync_writes {
+                self.active.sync_data()?;
+            }
+            Ok(())
+        });
+        if let Err(e) = written {
+            error!("Failed to write to {}: {}", segment_path(&self.dir, self.active_id).display(), e);
+            self.write_errors += 1;
+            return;
+        }
+        self.active_len += record.len() as u64;
+        self.bytes += record.len() as u64;
+        if self.active_len >= SEGMENT_BYTES {
+            let next = self.active_id + 1;
+            match self.active.sync_data().and_then(|_| start_segment(&self.dir, next)) {
+                Ok(file) => {
+                    self.active = file;
+                    self.active_id = next;
+                    self.active_len = SEGMENT_HEADER_LEN;
+                    self.segments.push(next);
+                    self.bytes += SEGMENT_HEADER_LEN;
+                }
+                Err(e) => {
+                    error!("Failed to start segment {} in {}: {}", next, self.dir.display(), e);
+                    self.write_errors += 1;
+                }
+            }
+        }
+    }
+
+    // Write `entries` into a new base segment and drop every segment before
+    // it, returning the base mapped, with where each entry's stored bytes
+    // are in it
+    fn rewrite(&mut self, entries: &[(&Key, &mut CacheEntry)]) -> io::Result<(Bytes, Vec<usize>)> {
+        let base_id = self.active_id + 1;
+        let tmp_path = segment_path(&self.dir, base_id).with_extension("tmp");
+        let mut file = io::BufWriter::new(File::create(&tmp_path)?);
+        file.write_all(SEGMENT_MAGIC)?;
+        file.write_all(&[1])?;
+        let mut offset = SEGMENT_HEADER_LEN as usize;
+        let mut starts = Vec::with_capacity(entries.len());
+        for (key, entry) in entries {
+            let record = put_record(&key.text(), entry);
+            starts.push(offset + record.len() - entry.data.len());
+            offset += record.len();
+            file.write_all(&record)?;
+        }
+        let file = file.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        drop(file);
+        fs::rename(&tmp_path, segment_path(&self.dir, base_id))?;
+        sync_dir(&self.dir)?;
+
+        for id in self.segments.drain(..) {
+            fs::remove_file(segment_path(&self.dir, id))?;
+        }
+        self.active = start_segment(&self.dir, base_id + 1)?;
+        self.active_id = base_id + 1;
+        self.active_len = SEGMENT_HEADER_LEN;
+        self.segments = vec![base_id, self.active_id];
+        self.bytes = offset as u64 + SEGMENT_HEADER_LEN;
+        self.garbage_bytes = 0;
+
+        let file = File::open(segment_path(&self.dir, base_id))?;
+        // SAFETY: a base segment is never written to again
+        let map = unsafe { Mmap::map(&file)? };
+        Ok((Bytes::from_owner(map), starts))
+    }
+}
+
+impl StorageEngine for MappedEngine {
+    fn name(&self) -> &'static str {
+        "mmap"
+    }
+
+    fn put(&mut self, key: &str, entry: &CacheEntry, replaced: Option<&CacheEntry>) {
+        if let Some(replaced) = replaced {
+            self.garbage_bytes += put_len(key, replaced);
+        }
+        self.append(&put_record(key, entry));
+    }
+
+    fn expire(&mut self, key: &str, expires_at: Option<u64>) {
+        let mut record = record_start(RECORD_EXPIRE, key, EXPIRE_FIELDS_LEN);
+        record.push(if expires_at.is_some() { FLAG_EXPIRES } else { 0 });
+        record.extend_from_slice(&expires_at.unwrap_or(0).to_le_bytes());
+        // Only needed until the key's next PUT, so counted as garbage from the start
+        self.garbage_bytes += record.len() as u64;
+        self.append(&record_end(record));
+    }
+
+    fn soft_expire(&mut self, key: &str, stale_at: Option<u64>) {
+        let mut record = record_start(RECORD_SOFT_EXPIRE, key, EXPIRE_FIELDS_LEN);
+        record.push(if stale_at.is_some() { FLAG_STALES } else { 0 });
+        record.extend_from_slice(&stale_at.unwrap_or(0).to_le_bytes());
+        self.garbage_bytes += record.len() as u64;
+        self.append(&record_end(record));
+    }
+
+    fn delete(&mut self, key: &str, removed: &CacheEntry) {
+        let record = record_end(record_start(RECORD_DELETE, key, 0));
+        self.garbage_bytes += put_len(key, removed) + record.len() as u64;
+        self.append(&record);
+    }
+
+    fn clear(&mut self) {
+        if let Err(e) = self.rewrite(&[]) {
+            error!("Failed to clear {}: {}", self.dir.display(), e);
+            self.write_errors += 1;
+        }
+    }
+
+    fn wants_compaction(&self) -> bool {
+        self.garbage_bytes >= COMPACT_MIN_BYTES && self.garbage_bytes * 2 > self.bytes
+    }
+
+    fn compact(&mut self, mut entries: Vec<(&Key, &mut CacheEntry)>) {
+        match self.rewrite(&entries) {
+            Ok((base, starts)) => {
+                // Values written since the last restart move out of the heap,
+                // but for those kept inside their entry
+                for ((_, entry), start) in entries.iter_mut().zip(starts) {
+                    if !entry.data.is_inline() {
+                        entry.data = base.slice(start..start + entry.data.len()).into();
+                    }
+                }
+                self.compactions += 1;
+            }
+            Err(e) => {
+                error!("Failed to compact {}: {}", self.dir.display(), e);
+                self.write_errors += 1;
+            }
+        }
+    }
+
+    fn file_to_sync(&self) -> io::Result<Option<File>> {
+        self.active.try_clone().map(Some)
+    }
+
+    fn stats(&self) -> StorageStats {
+        StorageStats {
+            segments: self.segments.len(),
+            bytes: self.bytes,
+            garbage_bytes: self.garbage_bytes,
+            compactions: self.compactions,
+            write_errors: self.write_errors,
+        }
+    }
+}
+
+// A record read from a mapped segment
+struct Record {
+    kind: u8,
+    key: String,
+    fields: std::ops::Range<usize>, // what follows the key, within the segment
+    len: u64,
+}
+
+impl Record {
+    // Apply the record to the entries read so far, returning how many bytes
+    // of records it made outdated
+    fn apply(self, segment: &Bytes, entries: &mut HashMap<String, CacheEntry>) -> u64 {
+        let fields = &segment[self.fields.clone()];
+        match self.kind {
+            RECORD_PUT if fields.len() >= PUT_FIELDS_LEN && fields.len() >= put_fields_len(fields[0]) => {
+                let flags = fields[0];
+                let expires_at = u64_at(fields, 1);
+                let stamp = WriteStamp { at: u64_at(fields, 9), version: u64_at(fields, 17) };
+                let checksum = u32::from_le_bytes(fields[25..29].try_into().expect("4 bytes"));
+                let entry = CacheEntry {
+                    data: segment.slice(self.fields.start + put_fields_len(flags)..self.fields.end).into(),
+                    compressed: flags & FLAG_COMPRESSED != 0,
+                    crdt: flags & FLAG_CRDT != 0,
+                    tombstone: flags & FLAG_TOMBSTONE != 0,
+                    expires_at: (flags & FLAG_EXPIRES != 0).then_some(expires_at),
+                    stale_at: (flags & FLAG_STALES != 0).then(|| u64_at(fields, PUT_FIELDS_LEN)),
+                    visible_at: (flags & FLAG_HIDDEN != 0).then(|| u64_at(fields, put_fields_len(flags) - 8)),
+                    stamp,
+                    checksum,
+                    access: Access::default(),
+                };
+                entries.insert(self.key.clone(), entry).map_or(0, |old| put_len(&self.key, &old))
+            }
+            RECORD_EXPIRE if fields.len() >= EXPIRE_FIELDS_LEN => {
+                if let Some(entry) = entries.get_mut(&self.key) {
+                    entry.expires_at = (fields[0] & FLAG_EXPIRES != 0).then(|| u64_at(fields, 1));
+                }
+                self.len
+            }
+            RECORD_SOFT_EXPIRE if fields.len() >= EXPIRE_FIELDS_LEN => {
+                if let Some(entry) = entries.get_mut(&self.key) {
+                    entry.stale_at = (fields[0] & FLAG_STALES != 0).then(|| u64_at(fields, 1));
+                }
+                self.len
+            }
+            RECORD_DELETE => {
+                self.len + entries.remove(&self.key).map_or(0, |old| put_len(&self.key, &old))
+            }
+            kind => {
+                warn!("Skipping a storage record of unknown kind {} for {}", kind, self.key);
+                self.len
+            }
+        }
+    }
+}
+
+// The records of a segment from `start`, and where the intact ones end
+fn read_records(segment: &[u8], start: usize) -> (Vec<Record>, usize) {
+    let mut records = Vec::new();
+    let mut at = start;
+    while let Some(header) = segment.get(at..at + RECORD_HEADER_LEN) {
+        let crc = u32::from_le_bytes(header[0..4].try_into().expect("4 bytes"));
+        let body_len = u32::from_le_bytes(header[4..8].try_into().expect("4 bytes")) as usize;
+        let key_len = u32::from_le_bytes(header[9..13].try_into().expect("4 bytes")) as usize;
+        let end = at + 8 + body_len;
+        let Some(body) = segment.get(at + 8..end) else {
+            break;
+        };
+        if crc32fast::hash(body) != crc || body_len < 5 || key_len > body_len - 5 {
+            break;
+        }
+        let key_start = at + RECORD_HEADER_LEN;
+        let Ok(key) = std::str::from_utf8(&segment[key_start..key_start + key_len]) else {
+            break;
+        };
+        records.push(Record {
+            kind: header[8],
+            key: key.to_string(),
+            fields: key_start + key_len..end,
+            len: (end - at) as u64,
+        });
+        at = end;
+    }
+    (records, at)
+}
+
+fn put_record(key: &str, entry: &CacheEntry) -> Vec<u8> {
+    let flags = put_flags(entry);
+    let mut record = record_start(RECORD_PUT, key, put_fields_len(flags) + entry.data.len());
+    record.push(flags);
+    record.extend_from_slice(&entry.expires_at.unwrap_or(0).to_le_bytes());
+    record.extend_from_slice(&entry.stamp.at.to_le_bytes());
+    record.extend_from_slice(&entry.stamp.version.to_le_bytes());
+    record.extend_from_slice(&entry.checksum.to_le_bytes());
+    if let Some(at) = entry.stale_at {
+        record.extend_from_slice(&at.to_le_bytes());
+    }
+    if let Some(at) = entry.visible_at {
+        record.extend_from_slice(&at.to_le_bytes());
+    }
+    record.extend_from_slice(&entry.data);
+    record_end(record)
+}
+
+fn put_flags(entry: &CacheEntry) -> u8 {
+    let mut flags = 0;
+    if entry.compressed {
+        flags |= FLAG_COMPRESSED;
+    }
+    if entry.crdt {
+        flags |= FLAG_CRDT;
+    }
+    if entry.expires_at.is_some() {
+        flags |= FLAG_EXPIRES;
+    }
+    if entry.stale_at.is_some() {
+        flags |= FLAG_STALES;
+    }
+    if entry.tombstone {
+        flags |= FLAG_TOMBSTONE;
+    }
+    if entry.visible_at.is_some() {
+        flags |= FLAG_HIDDEN;
+    }
+    flags
+}
+
+// Bytes between the key and the stored bytes of a PUT with `flags`
+fn put_fields_len(flags: u8) -> usize {
+    let optional = [FLAG_STALES, FLAG_HIDDEN].iter().filter(|&&flag| flags & flag != 0).count();
+    PUT_FIELDS_LEN + 8 * optional
+}
+
+// Bytes the PUT record of an entry takes
+fn put_len(key: &str, entry: &CacheEntry) -> u64 {
+    (RECORD_HEADER_LEN + key.len() + put_fields_len(put_flags(entry)) + entry.data.len()) as u64
+}
+
+// A record up to its key, with room for `fields` bytes more
+fn record_start(kind: u8, key: &str, fields: usize) -> Vec<u8> {
+    let mut record = Vec::with_capacity(RECORD_HEADER_LEN + key.len() + fields);
+    record.extend_from_slice(&[0; 8]);
+    record.push(kind);
+    record.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    record.extend_from_slice(key.as_bytes());
+    record
+}
+
+// Fill in the length and checksum of a record's body
+fn record_end(mut record: Vec<u8>) -> Vec<u8> {
+    let body_len = (record.len() - 8) as u32;
+    let crc = crc32fast::hash(&record[8..]);
+    record[0..4].copy_from_slice(&crc.to_le_bytes());
+    record[4..8].copy_from_slice(&body_len.to_le_bytes());
+    record
+}
+
+fn u64_at(data: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(data[at..at + 8].try_into().expect("8 bytes"))
+}
+
+fn segment_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{:020}.{}", id, SEGMENT_EXTENSION))
+}
+
+// Ids of the segments in `dir`, in order
+fn list_segments(dir: &Path) -> io::Result<Vec<u64>> {
+    let mut ids = Vec::new();
+    for item in fs::read_dir(dir)? {
+        let path = item?.path();
+        if path.extension().is_some_and(|extension| extension == SEGMENT_EXTENSION)
+            && let Some(id) = path.file_stem().and_then(|stem| stem.to_str()?.parse().ok()) {
+            ids.push(id);
+        }
+    }
+    ids.sort_unstable();
+    Ok(ids)
+}
+
+fn is_base(path: &Path) -> io::Result<bool> {
+    let mut header = [0u8; SEGMENT_HEADER_LEN as usize];
+    match File::open(path)?.read_exact(&mut header) {
+        Ok(()) if header.starts_with(SEGMENT_MAGIC) => Ok(header[SEGMENT_MAGIC.len()] == 1),
+        Ok(()) => Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} isn't a storage segment", path.display()))),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+fn start_segment(dir: &Path, id: u64) -> io::Result<File> {
+    let mut file = OpenOptions::new().create(true).append(true).open(segment_path(dir, id))?;
+    file.write_all(SEGMENT_MAGIC)?;
+    file.write_all(&[0])?;
+    file.sync_data()?;
+    Ok(file)
+}
+
+// Make a rename in `dir` survive losing power
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn put(key: &str, value: &'static [u8]) -> Vec<u8> {
+        put_record(key, &CacheEntry::new(Bytes::from_static(value), false, false))
+    }
+
+    fn delete(key: &str) -> Vec<u8> {
+        record_end(record_start(RECORD_DELETE, key, 0))
+    }
+
+    fn keys(records: &[Record]) -> Vec<&str> {
+        records.iter().map(|record| record.key.as_str()).collect()
+    }
+
+    #[test]
+    fn reads_every_intact_record() {
+        let segment = [put("a", b"1"), delete("a"), put("b", b"2")].concat();
+        let (records, end) = read_records(&segment, 0);
+        assert_eq!(keys(&records), ["a", "a", "b"]);
+        assert_eq!(records[1].kind, RECORD_DELETE);
+        assert_eq!(end, segment.len());
+    }
+
+    #[test]
+    fn stops_before_a_torn_tail() {
+        let first = put("a", b"1");
+        let second = put("b", b"2");
+        let whole = [first.clone(), second.clone()].concat();
+        // Cut inside the header, inside the key and inside the value
+        for cut in [first.len() + 3, first.len() + RECORD_HEADER_LEN, whole.len() - 1] {
+            let (records, end) = read_records(&whole[..cut], 0);
+            assert_eq!(keys(&records), ["a"], "cut at {}", cut);
+            assert_eq!(end, first.len());
+        }
+    }
+
+    #[test]
+    fn stops_at_a_corrupt_record() {
+        let first = put("a", b"1");
+        let mut segment = [first.clone(), put("b", b"2"), put("c", b"3")].concat();
+        let last = segment.len() - 1;
+        segment[first.len() + RECORD_HEADER_LEN] ^= 0xff;
+        segment[last] ^= 0xff;
+        let (records, end) = read_records(&segment, 0);
+        assert_eq!(keys(&records), ["a"]);
+        assert_eq!(end, first.len());
+    }
+
+    #[test]
+    fn reads_from_the_start_given() {
+        let first = put("a", b"1");
+        let segment = [first.clone(), put("b", b"2")].concat();
+        let (records, end) = read_records(&segment, first.len());
+        assert_eq!(keys(&records), ["b"]);
+        assert_eq!(end, segment.len());
+    }
+}
@@ -0,0 +1,87 @@
+use std::ops::Deref;
+use bytes::Bytes;
+
+// Most bytes a value can hold inside its entry. Up to here the inline form
+// takes no more room than a `Bytes`, whose pointers leave a niche for it.
+pub const INLINE_CAPACITY: usize = 23;
+
+// The bytes an entry holds: inside the entry when there are few of them,
+// saving the separate allocation (and its allocator overhead) they would
+// otherwise take, or shared with whoever else holds them
+#[derive(Clone)]
+pub enum Value {
+    Inline { len: u8, bytes: [u8; INLINE_CAPACITY] },
+    Shared(Bytes),
+}
+
+impl Value {
+    // The bytes inline, when they fit
+    pub fn inline(data: &[u8]) -> Option<Self> {
+        if data.len() > INLINE_CAPACITY {
+            return None;
+        }
+        let mut bytes = [0; INLINE_CAPACITY];
+        bytes[..data.len()].copy_from_slice(data);
+        Some(Value::Inline { len: data.len() as u8, bytes })
+    }
+
+    // Move the bytes inline when there are at most `max` of them
+    pub fn inline_up_to(&mut self, max: usize) {
+        if let Value::Shared(data) = self
+            && data.len() <= max
+            && let Some(inline) = Value::inline(data) {
+            *self = inline;
+        }
+    }
+
+    pub fn is_inline(&self) -> bool {
+        matches!(self, Value::Inline { .. })
+    }
+
+    // Bytes held outside the entry
+    pub fn heap_len(&self) -> usize {
+        match self {
+            Value::Inline { .. } => 0,
+            Value::Shared(data) => data.len(),
+        }
+    }
+
+    // The bytes as a `Bytes`, which copies inline ones
+    pub fn to_bytes(&self) -> Bytes {
+        match self {
+            Value::Inline { .. } => Bytes::copy_from_slice(self),
+            Value::Shared(data) => data.clone(),
+        }
+    }
+}
+
+impl Deref for Value {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Value::Inline { len, bytes } => &bytes[..*len as usize],
+            Value::Shared(data) => data,
+        }
+    }
+}
+
+impl AsRef<[u8]> for Value {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl From<Bytes> for Value {
+    fn from(data: Bytes) -> Self {
+        Value::Shared(data)
+    }
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+
+impl Eq for Value {}
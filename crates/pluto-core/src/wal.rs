@@ -0,0 +1,205 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use crate::cache::WriteStamp;
+use crate::encryption::{KeyId, Keyring};
+use crate::protocol::Command;
+
+// The write-ahead log is a directory of segments, each named after the
+// position of its first write. A segment is a header followed by records,
+// each a big-endian u32 length, the bincode-encoded record and a CRC16 of it.
+// In an encrypted segment the header ends in the id of the key, and each
+// record is encrypted with it before the CRC is taken.
+
+// Start of every segment: a magic string and the format version
+const SEGMENT_MAGIC: &[u8] = b"PLWAL";
+const SEGMENT_HEADER: &[u8] = b"PLWAL\x01";
+const ENCRYPTED_SEGMENT_HEADER: &[u8] = b"PLWAL\x02";
+
+const SEGMENT_EXTENSION: &str = "wal";
+
+// A write as the log records it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WalRecord {
+    pub position: u64, // one more than the write before it, across restarts
+    pub at: u64,       // unix time in milliseconds the write was applied
+    pub command: Command,
+    pub stamp: Option<WriteStamp>,
+    pub local_only: bool,
+}
+
+// File name of the segment starting at `first`
+pub fn segment_name(first: u64) -> String {
+    format!("{:020}.{}", first, SEGMENT_EXTENSION)
+}
+
+// Position a segment starts at, from its file name
+pub fn segment_first(path: &Path) -> Option<u64> {
+    if path.extension()? != SEGMENT_EXTENSION {
+        return None;
+    }
+    path.file_stem()?.to_str()?.parse().ok()
+}
+
+// Segments in a directory, ordered by the position they start at
+pub fn list_segments(dir: &Path) -> io::Result<Vec<(u64, PathBuf)>> {
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if let Some(first) = segment_first(&path) {
+            segments.push((first, path));
+        }
+    }
+    segments.sort();
+    Ok(segments)
+}
+
+// A record as it is written, borrowing the command
+#[derive(Serialize)]
+struct RecordRef<'a> {
+    position: u64,
+    at: u64,
+    command: &'a Command,
+    stamp: Option<WriteStamp>,
+    local_only: bool,
+}
+
+// Header of a segment written with the current key of `keys`
+pub fn segment_header(keys: &Keyring) -> Vec<u8> {
+    match keys.current_id() {
+        Some(id) => [ENCRYPTED_SEGMENT_HEADER, &id].concat(),
+        None => SEGMENT_HEADER.to_vec(),
+    }
+}
+
+// Frame a write as a record of a segment whose header `segment_header` wrote
+pub fn encode_record(
+    position: u64,
+    at: u64,
+    command: &Command,
+    stamp: Option<WriteStamp>,
+    local_only: bool,
+    keys: &Keyring,
+) -> io::Result<Vec<u8>> {
+    let record = RecordRef { position, at, command, stamp, local_only };
+    let body = bincode::serde::encode_to_vec(&record, bincode::config::standard())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+    let body = if keys.is_enabled() { keys.encrypt(&body) } else { body };
+    let mut framed = Vec::with_capacity(body.len() + 6);
+    framed.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    framed.extend_from_slice(&body);
+    framed.extend_from_slice(&crate::cluster::crc16(&body).to_be_bytes());
+    Ok(framed)
+}
+
+// Records of a segment, in order. A crash can leave the last record torn;
+// reading stops there, and the second value tells whether it did.
+pub fn read_segment(path: &Path, keys: &Keyring) -> io::Result<(Vec<WalRecord>, bool)> {
+    let data = fs::read(path)?;
+    let invalid = |reason: String| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), reason));
+    let (mut rest, key): (&[u8], Option<KeyId>) = if let Some(rest) = data.strip_prefix(SEGMENT_HEADER) {
+        (rest, None)
+    } else if let Some(rest) = data.strip_prefix(ENCRYPTED_SEGMENT_HEADER) {
+        let Some((id, rest)) = rest.split_first_chunk::<4>() else {
+            return Ok((Vec::new(), true));
+        };
+        (rest, Some(*id))
+    } else {
+        if data.len() < SEGMENT_HEADER.len() && SEGMENT_HEADER.starts_with(&data) {
+            return Ok((Vec::new(), !data.is_empty()));
+        }
+        let reason = if data.starts_with(SEGMENT_MAGIC) { "unsupported segment version" } else { "not a log segment" };
+        return Err(invalid(reason.to_string()));
+    };
+    let mut records = Vec::new();
+    while !rest.is_empty() {
+        let Some((len, after)) = rest.split_first_chunk::<4>() else {
+            return Ok((records, true));
+        };
+        let len = u32::from_be_bytes(*len) as usize;
+        if after.len() < len + 2 {
+            return Ok((records, true));
+        }
+        let (body, after) = after.split_at(len);
+        let (crc, after) = after.split_at(2);
+        if crate::cluster::crc16(body).to_be_bytes() != crc {
+            return Ok((records, true));
+        }
+        // A record whole by its CRC that doesn't decrypt was tampered with
+        let decrypted;
+        let body = match key {
+            Some(id) => {
+                decrypted = keys.decrypt(id, body).map_err(|e| invalid(e.to_string()))?;
+                &decrypted[..]
+            }
+            None => body,
+        };
+        let Ok((record, _)) = bincode::serde::decode_from_slice(body, bincode::config::standard()) else {
+            return Ok((records, true));
+        };
+        records.push(record);
+        rest = after;
+    }
+    Ok((records, false))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encryption::KEY_LEN;
+
+    // A segment file in the temp directory, removed once dropped
+    struct TempSegment(PathBuf);
+
+    impl TempSegment {
+        fn new(name: &str) -> Self {
+            TempSegment(std::env::temp_dir().join(format!("pluto-wal-{}-{}.wal", name, std::process::id())))
+        }
+    }
+
+    impl Drop for TempSegment {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn write_segment(path: &Path, keys: &Keyring, count: u64) -> Vec<u8> {
+        let mut data = segment_header(keys);
+        for position in 1..=count {
+            let command = Command::SET { key: format!("secret{}", position), value: b"value".to_vec(), visible_at: None };
+            data.extend(encode_record(position, 0, &command, None, false, keys).unwrap());
+        }
+        fs::write(path, &data).unwrap();
+        data
+    }
+
+    #[test]
+    fn encrypted_segments_read_back_with_their_key() {
+        let segment = TempSegment::new("encrypted");
+        let keys = Keyring::new(&[7; KEY_LEN], &[]);
+        let data = write_segment(&segment.0, &keys, 3);
+        assert!(!data.windows(7).any(|window| window == b"secret1"));
+        let (records, torn) = read_segment(&segment.0, &keys).unwrap();
+        assert!(!torn);
+        assert_eq!(records.iter().map(|record| record.position).collect::<Vec<_>>(), [1, 2, 3]);
+        // Rotated out but kept among the previous keys, it still reads
+        let rotated = Keyring::new(&[8; KEY_LEN], &[[7; KEY_LEN]]);
+        assert_eq!(read_segment(&segment.0, &rotated).unwrap().0.len(), 3);
+        assert!(read_segment(&segment.0, &Keyring::new(&[8; KEY_LEN], &[])).is_err());
+    }
+
+    #[test]
+    fn an_altered_record_is_refused_rather_than_read_as_torn() {
+        let segment = TempSegment::new("altered");
+        let keys = Keyring::new(&[7; KEY_LEN], &[]);
+        let mut data = write_segment(&segment.0, &keys, 1);
+        // Flip a byte of the ciphertext and fix up the CRC, as an attacker would
+        let body = segment_header(&keys).len() + 4..data.len() - 2;
+        data[body.start + 20] ^= 1;
+        let crc = crate::cluster::crc16(&data[body.clone()]).to_be_bytes();
+        data[body.end..].copy_from_slice(&crc);
+        fs::write(&segment.0, &data).unwrap();
+        assert!(read_segment(&segment.0, &keys).is_err());
+    }
+}
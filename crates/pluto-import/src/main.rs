@@ -0,0 +1,271 @@
+mod rdb;
+mod resp;
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::time::Instant;
+use clap::{Parser, Subcommand};
+use tokio::task::JoinSet;
+use pluto_client::{ClientError, ClusterClient, ErrorCode, ErrorReply, PoolConfig};
+use pluto_core::cache::now_ms;
+use rdb::{RdbReader, Value};
+use resp::{RedisConnection, Reply};
+
+// Command line arguments
+#[derive(Parser, Debug)]
+#[command(name = "pluto-import")]
+#[command(author = "thebyteslayer")]
+#[command(version = "1.0.0")]
+#[command(about = "Import string keys from Redis into flux-cache")]
+struct Args {
+    /// Address of any flux-cache node to import into, as host:port
+    #[arg(long, global = true, default_value = "127.0.0.1:6124")]
+    to: String,
+    /// Password sent with AUTH to every flux-cache node
+    #[arg(long, global = true)]
+    password: Option<String>,
+    /// Keys written concurrently
+    #[arg(long, global = true, default_value_t = 64)]
+    parallel: usize,
+    #[command(subcommand)]
+    source: Source,
+}
+
+#[derive(Subcommand, Debug)]
+enum Source {
+    /// Read keys from a running Redis server with SCAN and DUMP
+    Redis {
+        /// Address of the Redis server, as host:port
+        #[arg(long)]
+        from: String,
+        /// Password of the Redis server
+        #[arg(long)]
+        redis_password: Option<String>,
+        /// Redis database to read
+        #[arg(long, default_value_t = 0)]
+        db: u64,
+        /// Only import keys matching this glob pattern
+        #[arg(long = "match", default_value = "*")]
+        pattern: String,
+        /// Keys asked for per SCAN call
+        #[arg(long, default_value_t = 1000)]
+        count: usize,
+    },
+    /// Read keys from an RDB snapshot file
+    Rdb {
+        /// Path to the RDB file
+        file: String,
+        /// Redis database to read
+        #[arg(long, default_value_t = 0)]
+        db: u64,
+    },
+}
+
+#[tokio::main]
+async fn main() {
+    let args = Args::parse();
+    let config = PoolConfig {
+        max_size: args.parallel.max(1),
+        password: args.password.clone(),
+        ..PoolConfig::default()
+    };
+    let result = match ClusterClient::connect(&[args.to.as_str()], config).await {
+        Ok(client) => {
+            let mut importer = Importer::new(client, args.parallel.max(1));
+            let started = Instant::now();
+            let result = match args.source {
+                Source::Redis { from, redis_password, db, pattern, count } => {
+                    importer.scan_redis(&from, redis_password.as_deref(), db, &pattern, count).await
+                }
+                Source::Rdb { file, db } => importer.read_rdb(&file, db).await,
+            };
+            importer.finish().await;
+            importer.report(started);
+            result.and_then(|_| match importer.failed {
+                0 => Ok(()),
+                failed => Err(ClientError::Protocol(format!("{} keys could not be written", failed))),
+            })
+        }
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        eprintln!("[ERR] {}", e);
+        std::process::exit(1);
+    }
+}
+
+// Writes keys to the cluster, a bounded number at a time, and counts what
+// happened to each of them
+struct Importer {
+    client: ClusterClient,
+    parallel: usize,
+    writes: JoinSet<Result<(), (String, ClientError)>>,
+    imported: u64,
+    expired: u64,
+    failed: u64,
+    invalid_keys: u64, // not UTF-8, so not representable as a key here
+    skipped: BTreeMap<&'static str, u64>, // non-string values by type
+}
+
+impl Importer {
+    fn new(client: ClusterClient, parallel: usize) -> Self {
+        Importer {
+            client,
+            parallel,
+            writes: JoinSet::new(),
+            imported: 0,
+            expired: 0,
+            failed: 0,
+            invalid_keys: 0,
+            skipped: BTreeMap::new(),
+        }
+    }
+
+    async fn scan_redis(&mut self, addr: &str, password: Option<&str>, db: u64, pattern: &str, count: usize) -> Result<(), ClientError> {
+        let mut redis = RedisConnection::connect(addr).await?;
+        if let Some(password) = password {
+            redis.command(&[b"AUTH", password.as_bytes()]).await?;
+        }
+        if db != 0 {
+            redis.command(&[b"SELECT", db.to_string().as_bytes()]).await?;
+        }
+        let count = count.max(1).to_string();
+        let mut cursor = b"0".to_vec();
+        loop {
+            let reply = redis.command(&[b"SCAN", &cursor, b"MATCH", pattern.as_bytes(), b"COUNT", count.as_bytes()]).await?;
+            let (next, keys) = match reply {
+                Reply::Array(Some(mut items)) if items.len() == 2 => match (items.remove(0), items.remove(0)) {
+                    (Reply::Bulk(Some(next)), Reply::Array(Some(keys))) => (next, keys),
+                    other => return Err(ClientError::Protocol(format!("unexpected SCAN reply from Redis: {:?}", other))),
+                },
+                other => return Err(ClientError::Protocol(format!("unexpected SCAN reply from Redis: {:?}", other))),
+            };
+            let keys: Vec<Vec<u8>> = keys.into_iter()
+                .filter_map(|key| match key {
+                    Reply::Bulk(Some(key)) => Some(key),
+                    _ => None,
+                })
+                .collect();
+
+            // DUMP and PTTL every key of the batch in one round trip
+            let commands: Vec<Vec<Vec<u8>>> = keys.iter()
+                .flat_map(|key| [vec![b"DUMP".to_vec(), key.clone()], vec![b"PTTL".to_vec(), key.clone()]])
+                .collect();
+            let mut replies = redis.pipeline(&commands).await?.into_iter();
+            for key in keys {
+                let (dump, ttl) = (replies.next(), replies.next());
+                let payload = match dump {
+                    Some(Reply::Bulk(Some(payload))) => payload,
+                    Some(Reply::Bulk(None)) => continue, // deleted since the SCAN
+                    Some(Reply::Error(message)) => return Err(ClientError::Server(ErrorReply::new(ErrorCode::Err, message))),
+                    other => return Err(ClientError::Protocol(format!("unexpected DUMP reply from Redis: {:?}", other))),
+                };
+                let expires_at = match ttl {
+                    Some(Reply::Integer(-2)) => continue,
+                    Some(Reply::Integer(ms)) if ms >= 0 => Some(now_ms() + ms as u64),
+                    _ => None,
+                };
+                // A DUMP payload is the value type, the value, then a version and checksum
+                let Some((&value_type, body)) = payload.split_first() else {
+                    return Err(ClientError::Protocol("empty DUMP payload from Redis".to_string()));
+                };
+                let value = RdbReader::new(body).read_value(value_type)?;
+                self.import(key, value, expires_at).await;
+            }
+
+            if next == b"0" {
+                return Ok(());
+            }
+            cursor = next;
+        }
+    }
+
+    async fn read_rdb(&mut self, path: &str, db: u64) -> Result<(), ClientError> {
+        let mut reader = RdbReader::new(BufReader::new(File::open(path)?));
+        let version = reader.read_header()?;
+        println!(">>> Reading {} (RDB version {})", path, version);
+        while let Some(entry) = reader.next_entry()? {
+            if entry.db == db {
+                self.import(entry.key, entry.value, entry.expires_at).await;
+            }
+        }
+        Ok(())
+    }
+
+    // Queue one key for writing, waiting for a slot if enough writes are in flight
+    async fn import(&mut self, key: Vec<u8>, value: Value, expires_at: Option<u64>) {
+        let value = match value {
+            Value::String(value) => value,
+            Value::Other(kind) => {
+                *self.skipped.entry(kind).or_insert(0) += 1;
+                return;
+            }
+        };
+        let Ok(key) = String::from_utf8(key) else {
+            self.invalid_keys += 1;
+            return;
+        };
+        // Expiries are set in whole seconds, rounded up
+        let ttl = match expires_at {
+            Some(at) if at <= now_ms() => {
+                self.expired += 1;
+                return;
+            }
+            Some(at) => Some((at - now_ms()).div_ceil(1000)),
+            None => None,
+        };
+        while self.writes.len() >= self.parallel {
+            self.reap().await;
+        }
+        let client = self.client.clone();
+        self.writes.spawn(async move {
+            let result = match client.set(&key, value).await {
+                Ok(()) => match ttl {
+                    Some(seconds) => client.expire(&key, seconds).await.map(|_| ()),
+                    None => Ok(()),
+                },
+                Err(e) => Err(e),
+            };
+            result.map_err(|e| (key, e))
+        });
+    }
+
+    // Wait for one write to finish and count it
+    async fn reap(&mut self) {
+        match self.writes.join_next().await {
+            Some(Ok(Ok(()))) => self.imported += 1,
+            Some(Ok(Err((key, e)))) => {
+                eprintln!("[WARN] {}: {}", key, e);
+                self.failed += 1;
+            }
+            Some(Err(e)) => {
+                eprintln!("[WARN] {}", e);
+                self.failed += 1;
+            }
+            None => {}
+        }
+    }
+
+    async fn finish(&mut self) {
+        while !self.writes.is_empty() {
+            self.reap().await;
+        }
+    }
+
+    fn report(&self, started: Instant) {
+        println!("[OK] Imported {} keys in {:.2}s", self.imported, started.elapsed().as_secs_f64());
+        if self.expired > 0 {
+            println!("  {} keys had already expired", self.expired);
+        }
+        for (kind, count) in &self.skipped {
+            println!("  {} {} keys skipped; only strings are imported", count, kind);
+        }
+        if self.invalid_keys > 0 {
+            println!("  {} keys skipped because their names are not valid UTF-8", self.invalid_keys);
+        }
+        if self.failed > 0 {
+            println!("  {} keys failed", self.failed);
+        }
+    }
+}
@@ -0,0 +1,328 @@
+use std::io::{self, Read};
+
+// Opcodes that can appear where a key is expected
+const OPCODE_SLOT_INFO: u8 = 0xF4;
+const OPCODE_FUNCTION2: u8 = 0xF5;
+const OPCODE_FUNCTION_PRE_GA: u8 = 0xF6;
+const OPCODE_MODULE_AUX: u8 = 0xF7;
+const OPCODE_IDLE: u8 = 0xF8;
+const OPCODE_FREQ: u8 = 0xF9;
+const OPCODE_AUX: u8 = 0xFA;
+const OPCODE_RESIZEDB: u8 = 0xFB;
+const OPCODE_EXPIRETIME_MS: u8 = 0xFC;
+const OPCODE_EXPIRETIME: u8 = 0xFD;
+const OPCODE_SELECTDB: u8 = 0xFE;
+const OPCODE_EOF: u8 = 0xFF;
+
+// Value types
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_SET: u8 = 2;
+const TYPE_ZSET: u8 = 3;
+const TYPE_HASH: u8 = 4;
+const TYPE_ZSET_2: u8 = 5;
+const TYPE_HASH_ZIPMAP: u8 = 9;
+const TYPE_LIST_ZIPLIST: u8 = 10;
+const TYPE_SET_INTSET: u8 = 11;
+const TYPE_ZSET_ZIPLIST: u8 = 12;
+const TYPE_HASH_ZIPLIST: u8 = 13;
+const TYPE_LIST_QUICKLIST: u8 = 14;
+const TYPE_HASH_LISTPACK: u8 = 16;
+const TYPE_ZSET_LISTPACK: u8 = 17;
+const TYPE_LIST_QUICKLIST_2: u8 = 18;
+const TYPE_SET_LISTPACK: u8 = 20;
+
+// Special string encodings flagged by the top two bits of a length
+const ENC_INT8: u8 = 0;
+const ENC_INT16: u8 = 1;
+const ENC_INT32: u8 = 2;
+const ENC_LZF: u8 = 3;
+
+// A value decoded from an RDB file or a DUMP payload. Only strings are
+// kept; other types are skipped over and reported by their type name.
+pub enum Value {
+    String(Vec<u8>),
+    Other(&'static str),
+}
+
+// One key read from an RDB file
+pub struct Entry {
+    pub db: u64,
+    pub key: Vec<u8>,
+    pub value: Value,
+    pub expires_at: Option<u64>, // unix ms
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// Reads RDB encoded data: a whole file with `next_entry`, or a single value
+// such as the body of a DUMP payload with `read_value`
+pub struct RdbReader<R> {
+    input: R,
+    db: u64,
+    done: bool,
+}
+
+impl<R: Read> RdbReader<R> {
+    pub fn new(input: R) -> Self {
+        RdbReader { input, db: 0, done: false }
+    }
+
+    // Check the `REDIS0011` magic at the start of a file, returning the version
+    pub fn read_header(&mut self) -> io::Result<u32> {
+        let mut header = [0u8; 9];
+        self.input.read_exact(&mut header)?;
+        if &header[..5] != b"REDIS" {
+            return Err(invalid("not an RDB file".to_string()));
+        }
+        std::str::from_utf8(&header[5..])
+            .ok()
+            .and_then(|version| version.parse().ok())
+            .ok_or_else(|| invalid("invalid RDB version".to_string()))
+    }
+
+    // Next key in the file, or None at the end
+    pub fn next_entry(&mut self) -> io::Result<Option<Entry>> {
+        let mut expires_at = None;
+        while !self.done {
+            let opcode = self.read_u8()?;
+            match opcode {
+                OPCODE_EOF => self.done = true, // a checksum may follow; it is not verified
+                OPCODE_SELECTDB => self.db = self.read_length()?,
+                OPCODE_RESIZEDB => {
+                    self.read_length()?;
+                    self.read_length()?;
+                }
+                OPCODE_AUX => {
+                    self.read_string()?;
+                    self.read_string()?;
+                }
+                OPCODE_EXPIRETIME_MS => {
+                    let mut ms = [0u8; 8];
+                    self.input.read_exact(&mut ms)?;
+                    expires_at = Some(u64::from_le_bytes(ms));
+                }
+                OPCODE_EXPIRETIME => {
+                    let mut secs = [0u8; 4];
+                    self.input.read_exact(&mut secs)?;
+                    expires_at = Some(u32::from_le_bytes(secs) as u64 * 1000);
+                }
+                OPCODE_FREQ => {
+                    self.read_u8()?;
+                }
+                OPCODE_IDLE => {
+                    self.read_length()?;
+                }
+                OPCODE_SLOT_INFO => {
+                    for _ in 0..3 {
+                        self.read_length()?;
+                    }
+                }
+                OPCODE_FUNCTION2 => {
+                    self.read_string()?;
+                }
+                OPCODE_FUNCTION_PRE_GA | OPCODE_MODULE_AUX => {
+                    return Err(invalid(format!("unsupported RDB opcode {:#x}", opcode)));
+                }
+                value_type => {
+                    let key = self.read_string()?;
+                    let value = self.read_value(value_type)?;
+                    return Ok(Some(Entry { db: self.db, key, value, expires_at }));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    // Read a value of the given type. Aggregates are consumed so the reader
+    // stays in step, but only strings are returned.
+    pub fn read_value(&mut self, value_type: u8) -> io::Result<Value> {
+        let name = match value_type {
+            TYPE_STRING => return Ok(Value::String(self.read_string()?)),
+            TYPE_LIST | TYPE_SET => {
+                self.skip_strings(1)?;
+                if value_type == TYPE_LIST { "list" } else { "set" }
+            }
+            TYPE_LIST_QUICKLIST => {
+                self.skip_strings(1)?;
+                "list"
+            }
+            TYPE_LIST_QUICKLIST_2 => {
+                // Each node is a container kind followed by its listpack
+                let nodes = self.read_length()?;
+                for _ in 0..nodes {
+                    self.read_length()?;
+                    self.read_string()?;
+                }
+                "list"
+            }
+            TYPE_HASH => {
+                self.skip_strings(2)?;
+                "hash"
+            }
+            TYPE_ZSET => {
+                // Scores are stored as a length byte and that many ASCII digits
+                let members = self.read_length()?;
+                for _ in 0..members {
+                    self.read_string()?;
+                    let len = self.read_u8()?;
+                    if len < 253 {
+                        self.skip(len as u64)?;
+                    }
+                }
+                "zset"
+            }
+            TYPE_ZSET_2 => {
+                let members = self.read_length()?;
+                for _ in 0..members {
+                    self.read_string()?;
+                    self.skip(8)?;
+                }
+                "zset"
+            }
+            TYPE_HASH_ZIPMAP | TYPE_HASH_ZIPLIST | TYPE_HASH_LISTPACK => {
+                self.read_string()?;
+                "hash"
+            }
+            TYPE_LIST_ZIPLIST => {
+                self.read_string()?;
+                "list"
+            }
+            TYPE_SET_INTSET | TYPE_SET_LISTPACK => {
+                self.read_string()?;
+                "set"
+            }
+            TYPE_ZSET_ZIPLIST | TYPE_ZSET_LISTPACK => {
+                self.read_string()?;
+                "zset"
+            }
+            // Streams, modules and hashes with field expiry can't be skipped
+            // without decoding them fully
+            other => return Err(invalid(format!("unsupported RDB value type {}", other))),
+        };
+        Ok(Value::Other(name))
+    }
+
+    // Skip a count of elements made of `per_element` strings each
+    fn skip_strings(&mut self, per_element: u64) -> io::Result<()> {
+        let count = self.read_length()?;
+        for _ in 0..count * per_element {
+            self.read_string()?;
+        }
+        Ok(())
+    }
+
+    fn skip(&mut self, len: u64) -> io::Result<()> {
+        let skipped = io::copy(&mut (&mut self.input).take(len), &mut io::sink())?;
+        if skipped < len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(())
+    }
+
+    fn read_u8(&mut self) -> io::Result<u8> {
+        let mut byte = [0u8; 1];
+        self.input.read_exact(&mut byte)?;
+        Ok(byte[0])
+    }
+
+    fn read_bytes(&mut self, len: usize) -> io::Result<Vec<u8>> {
+        let mut bytes = vec![0u8; len];
+        self.input.read_exact(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    // A length, or the special string encoding it stands for
+    fn read_length_or_encoding(&mut self) -> io::Result<(u64, bool)> {
+        let first = self.read_u8()?;
+        match first >> 6 {
+            0 => Ok(((first & 0x3F) as u64, false)),
+            1 => Ok(((((first & 0x3F) as u64) << 8) | self.read_u8()? as u64, false)),
+            2 => match first {
+                0x80 => {
+                    let mut len = [0u8; 4];
+                    self.input.read_exact(&mut len)?;
+                    Ok((u32::from_be_bytes(len) as u64, false))
+                }
+                0x81 => {
+                    let mut len = [0u8; 8];
+                    self.input.read_exact(&mut len)?;
+                    Ok((u64::from_be_bytes(len), false))
+                }
+                _ => Err(invalid(format!("invalid RDB length byte {:#x}", first))),
+            },
+            _ => Ok(((first & 0x3F) as u64, true)),
+        }
+    }
+
+    fn read_length(&mut self) -> io::Result<u64> {
+        match self.read_length_or_encoding()? {
+            (len, false) => Ok(len),
+            (_, true) => Err(invalid("expected a length, found an encoded string".to_string())),
+        }
+    }
+
+    fn read_string(&mut self) -> io::Result<Vec<u8>> {
+        let (len, encoded) = self.read_length_or_encoding()?;
+        if !encoded {
+            return self.read_bytes(len as usize);
+        }
+        match len as u8 {
+            ENC_INT8 => Ok((self.read_u8()? as i8).to_string().into_bytes()),
+            ENC_INT16 => {
+                let mut int = [0u8; 2];
+                self.input.read_exact(&mut int)?;
+                Ok(i16::from_le_bytes(int).to_string().into_bytes())
+            }
+            ENC_INT32 => {
+                let mut int = [0u8; 4];
+                self.input.read_exact(&mut int)?;
+                Ok(i32::from_le_bytes(int).to_string().into_bytes())
+            }
+            ENC_LZF => {
+                let compressed_len = self.read_length()? as usize;
+                let len = self.read_length()? as usize;
+                let compressed = self.read_bytes(compressed_len)?;
+                lzf_decompress(&compressed, len)
+            }
+            other => Err(invalid(format!("unknown RDB string encoding {}", other))),
+        }
+    }
+}
+
+// Decompress an LZF block into exactly `len` bytes
+fn lzf_decompress(input: &[u8], len: usize) -> io::Result<Vec<u8>> {
+    let corrupt = || invalid("corrupt LZF string".to_string());
+    let mut output = Vec::with_capacity(len);
+    let mut i = 0;
+    while i < input.len() {
+        let ctrl = input[i] as usize;
+        i += 1;
+        if ctrl < 32 {
+            // Literal run of ctrl + 1 bytes
+            let run = input.get(i..i + ctrl + 1).ok_or_else(corrupt)?;
+            output.extend_from_slice(run);
+            i += ctrl + 1;
+        } else {
+            // Back reference
+            let mut run = ctrl >> 5;
+            if run == 7 {
+                run += *input.get(i).ok_or_else(corrupt)? as usize;
+                i += 1;
+            }
+            let low = *input.get(i).ok_or_else(corrupt)? as usize;
+            i += 1;
+            let offset = ((ctrl & 0x1F) << 8) + low + 1;
+            let start = output.len().checked_sub(offset).ok_or_else(corrupt)?;
+            for j in 0..run + 2 {
+                output.push(output[start + j]);
+            }
+        }
+    }
+    if output.len() != len {
+        return Err(corrupt());
+    }
+    Ok(output)
+}
@@ -0,0 +1,97 @@
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use pluto_client::{ClientError, ErrorCode, ErrorReply};
+
+// A reply in the Redis serialization protocol
+#[derive(Debug)]
+pub enum Reply {
+    Simple, // status replies such as OK carry nothing the importer needs
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Option<Vec<Reply>>),
+}
+
+// Just enough of a Redis client to read a dataset out of it
+pub struct RedisConnection {
+    stream: BufReader<TcpStream>,
+}
+
+impl RedisConnection {
+    pub async fn connect(addr: &str) -> Result<Self, ClientError> {
+        let stream = TcpStream::connect(addr).await?;
+        stream.set_nodelay(true)?;
+        Ok(RedisConnection { stream: BufReader::new(stream) })
+    }
+
+    // Send a command and wait for its reply, turning error replies into errors
+    pub async fn command(&mut self, args: &[&[u8]]) -> Result<Reply, ClientError> {
+        let mut replies = self.pipeline(&[args.iter().map(|arg| arg.to_vec()).collect()]).await?;
+        match replies.pop() {
+            Some(Reply::Error(message)) => Err(ClientError::Server(ErrorReply::new(ErrorCode::Err, message))),
+            Some(reply) => Ok(reply),
+            None => Err(ClientError::ConnectionClosed),
+        }
+    }
+
+    // Send several commands in one write and read their replies in order
+    pub async fn pipeline(&mut self, commands: &[Vec<Vec<u8>>]) -> Result<Vec<Reply>, ClientError> {
+        let mut out = Vec::new();
+        for args in commands {
+            out.extend_from_slice(format!("*{}\r\n", args.len()).as_bytes());
+            for arg in args {
+                out.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+                out.extend_from_slice(arg);
+                out.extend_from_slice(b"\r\n");
+            }
+        }
+        self.stream.get_mut().write_all(&out).await?;
+        let mut replies = Vec::with_capacity(commands.len());
+        for _ in commands {
+            replies.push(self.read_reply().await?);
+        }
+        Ok(replies)
+    }
+
+    async fn read_line(&mut self) -> Result<String, ClientError> {
+        let mut line = String::new();
+        if self.stream.read_line(&mut line).await? == 0 {
+            return Err(ClientError::ConnectionClosed);
+        }
+        Ok(line.trim_end_matches("\r\n").to_string())
+    }
+
+    async fn read_reply(&mut self) -> Result<Reply, ClientError> {
+        let line = self.read_line().await?;
+        let (kind, rest) = line.split_at_checked(1)
+            .ok_or_else(|| ClientError::Protocol("empty reply from Redis".to_string()))?;
+        let number = || rest.parse::<i64>().map_err(|_| ClientError::Protocol(format!("invalid reply from Redis: {}", line)));
+        match kind {
+            "+" => Ok(Reply::Simple),
+            "-" => Ok(Reply::Error(rest.to_string())),
+            ":" => Ok(Reply::Integer(number()?)),
+            "$" => {
+                let len = number()?;
+                if len < 0 {
+                    return Ok(Reply::Bulk(None));
+                }
+                let mut data = vec![0u8; len as usize + 2];
+                self.stream.read_exact(&mut data).await?;
+                data.truncate(len as usize);
+                Ok(Reply::Bulk(Some(data)))
+            }
+            "*" => {
+                let len = number()?;
+                if len < 0 {
+                    return Ok(Reply::Array(None));
+                }
+                let mut items = Vec::with_capacity(len as usize);
+                for _ in 0..len {
+                    items.push(Box::pin(self.read_reply()).await?);
+                }
+                Ok(Reply::Array(Some(items)))
+            }
+            _ => Err(ClientError::Protocol(format!("unknown reply from Redis: {}", line))),
+        }
+    }
+}
@@ -0,0 +1,39 @@
+use std::process::Command;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    println!("cargo:rerun-if-changed=proto/pluto.proto");
+
+    // Identify the build for INFO server and VERSION: the commit, and when it
+    // was built (SOURCE_DATE_EPOCH for reproducible builds)
+    let git = |args: &[&str]| {
+        Command::new("git").args(args).output().ok()
+            .filter(|output| output.status.success())
+            .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+    };
+    let sha = git(&["rev-parse", "--short=12", "HEAD"]).unwrap_or_else(|| "unknown".to_string());
+    let dirty = git(&["status", "--porcelain", "--untracked-files=no"]).is_some_and(|status| !status.is_empty());
+    println!("cargo:rustc-env=PLUTO_GIT_SHA={}{}", sha, if dirty { "-dirty" } else { "" });
+    let built = std::env::var("SOURCE_DATE_EPOCH").ok()
+        .and_then(|secs| secs.parse().ok())
+        .and_then(|secs| chrono::DateTime::from_timestamp(secs, 0))
+        .unwrap_or_else(chrono::Utc::now);
+    println!("cargo:rustc-env=PLUTO_BUILD_DATE={}", built.format("%Y-%m-%dT%H:%M:%SZ"));
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    if let Some(git_dir) = git(&["rev-parse", "--absolute-git-dir"]) {
+        println!("cargo:rerun-if-changed={}/HEAD", git_dir);
+        println!("cargo:rerun-if-changed={}/index", git_dir);
+        if let Some(head) = git(&["symbolic-ref", "-q", "HEAD"]) {
+            println!("cargo:rerun-if-changed={}/{}", git_dir, head);
+        }
+    }
+
+    // Generate the gRPC service from the published .proto (without needing protoc)
+    #[cfg(feature = "grpc")]
+    {
+        let descriptors = protox::compile(["../../proto/pluto.proto"], ["../../proto"])?;
+        tonic_prost_build::configure()
+            .build_client(false)
+            .compile_fds(descriptors)?;
+    }
+    Ok(())
+}
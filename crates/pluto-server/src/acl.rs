@@ -0,0 +1,457 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use serde::{Deserialize, Serialize};
+use subtle::ConstantTimeEq;
+use pluto_core::cache::{Keyspace, ServerError, entry_memory, entry_memory_for, glob_match};
+use pluto_core::cluster::key_slot;
+use pluto_core::protocol::{Command, Response};
+use crate::middleware::{Call, Middleware};
+
+// Users other than the one requirepass stands for, each authenticating with
+// AUTH <name> <password>. A user is sandboxed to the keys matching its
+// patterns, and may be held to a number of keys and bytes among them, so
+// that one tenant of a shared node can't take the memory of the others.
+// Only admin users run commands that reach past their keys, such as KEYS,
+// FLUSHALL or the cluster commands.
+//
+// A user may belong to a tenant, which gives it a keyspace of its own: its
+// keys are stored under "<tenant>:" without it seeing the prefix, so tenants
+// can't see or touch each other's keys whatever they are named. The users of
+// a tenant share its quotas, on top of their own. A tenant's key hashes to
+// the slot of its name without the prefix, the only name its clients know.
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AclUser {
+    pub name: String,
+    pub password: String,
+    #[serde(default = "default_keys")]
+    pub keys: Vec<String>, // glob patterns of the keys the user may touch
+    #[serde(default)]
+    pub admin: bool, // may run keyspace-wide and administrative commands
+    #[serde(default)]
+    pub max_keys: usize, // keys the user may hold among its patterns; 0 means unlimited
+    #[serde(default)]
+    pub max_bytes: usize, // bytes those keys may take, as MEMORY_USAGE counts them; 0 means unlimited
+    #[serde(default)]
+    pub tenant: String, // the tenant whose keyspace the user works in; empty for the shared one
+}
+
+fn default_keys() -> Vec<String> {
+    vec!["*".to_string()]
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TenantRule {
+    pub name: String,
+    #[serde(default)]
+    pub max_keys: usize, // keys the tenant may hold; 0 means unlimited
+    #[serde(default)]
+    pub max_bytes: usize, // bytes its keys may take; 0 means unlimited
+}
+
+// Limits on the keys and bytes of the usage the keyspace keeps at `usage`
+#[derive(Debug)]
+struct Quota {
+    owner: String, // "User <name>" or "Tenant <name>", for errors
+    max_keys: usize,
+    max_bytes: usize,
+    usage: usize,
+}
+
+// A configured user, with the usage its quotas are checked against
+#[derive(Debug)]
+pub struct User {
+    pub rule: AclUser,
+    quota: Option<Quota>, // when a quota is set
+    pub tenant: Option<Arc<Tenant>>,
+}
+
+#[derive(Debug)]
+pub struct Tenant {
+    pub rule: TenantRule,
+    prefix: String, // "<name>:", which its keys are stored under
+    quota: Quota, // kept whether or not limits are set, for INFO tenants
+}
+
+#[derive(Debug, Default)]
+pub struct Acl {
+    users: HashMap<String, Arc<User>>,
+    tenants: BTreeMap<String, Arc<Tenant>>,
+}
+
+impl Acl {
+    // The users of `rules` and the tenants they belong to, with `cache`
+    // keeping the usage their quotas are checked against. A tenant users
+    // name without it being configured has no quotas.
+    pub fn new(rules: &[AclUser], tenants: &[TenantRule], cache: &mut Keyspace) -> Self {
+        let mut acl = Acl::default();
+        let named = rules.iter()
+            .filter(|rule| !rule.tenant.is_empty())
+            .map(|rule| TenantRule { name: rule.tenant.clone(), max_keys: 0, max_bytes: 0 });
+        for rule in tenants.iter().cloned().chain(named) {
+            if acl.tenants.contains_key(&rule.name) {
+                continue;
+            }
+            let prefix = format!("{}:", rule.name);
+            let quota = Quota {
+                owner: format!("Tenant {}", rule.name),
+                max_keys: rule.max_keys,
+                max_bytes: rule.max_bytes,
+                usage: cache.track_usage(vec![format!("{}*", escape(&prefix))]),
+            };
+            acl.tenants.insert(rule.name.clone(), Arc::new(Tenant { rule, prefix, quota }));
+        }
+        for rule in rules {
+            let tenant = acl.tenants.get(&rule.tenant).cloned();
+            let quota = (rule.max_keys > 0 || rule.max_bytes > 0).then(|| {
+                let prefix = tenant.as_ref().map(|tenant| escape(&tenant.prefix)).unwrap_or_default();
+                Quota {
+                    owner: format!("User {}", rule.name),
+                    max_keys: rule.max_keys,
+                    max_bytes: rule.max_bytes,
+                    usage: cache.track_usage(rule.keys.iter().map(|pattern| format!("{}{}", prefix, pattern)).collect()),
+                }
+            });
+            acl.users.insert(rule.name.clone(), Arc::new(User { rule: rule.clone(), quota, tenant }));
+        }
+        acl
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty()
+    }
+
+    pub fn authenticate(&self, name: &str, password: &str) -> Result<Arc<User>, ServerError> {
+        match self.users.get(name) {
+            Some(user) if bool::from(user.rule.password.as_bytes().ct_eq(password.as_bytes())) => Ok(user.clone()),
+            _ => Err(ServerError::Unauthorized("Invalid username or password".to_string())),
+        }
+    }
+
+    // Slot of a key as stored, a tenant's key hashing as its users name it so
+    // that they and every node agree on where it lives. Tenant names hold no
+    // ':', so the prefix ends at the first one.
+    pub fn key_slot(&self, key: &str) -> usize {
+        match key.split_once(':') {
+            Some((tenant, name)) if self.tenants.contains_key(tenant) => key_slot(name),
+            _ => key_slot(key),
+        }
+    }
+
+    // Keys and bytes held by each user with a quota, for INFO
+    pub fn usage<'a>(&'a self, cache: &'a Keyspace) -> impl Iterator<Item = (&'a User, usize, usize)> + 'a {
+        self.users.values().filter_map(move |user| {
+            let usage = cache.usage(user.quota.as_ref()?.usage);
+            Some((user.as_ref(), usage.keys, usage.bytes))
+        })
+    }
+
+    // Keys and bytes held by each tenant, by name
+    pub fn tenant_usage<'a>(&'a self, cache: &'a Keyspace) -> impl Iterator<Item = (&'a Tenant, usize, usize)> + 'a {
+        self.tenants.values().map(move |tenant| {
+            let usage = cache.usage(tenant.quota.usage);
+            (tenant.as_ref(), usage.keys, usage.bytes)
+        })
+    }
+}
+
+impl Quota {
+    // Refuse a write adding `keys` keys and `bytes` bytes that would take the
+    // usage past the limits
+    fn check(&self, (keys, bytes): (usize, usize), cache: &Keyspace) -> Result<(), ServerError> {
+        let usage = cache.usage(self.usage);
+        if keys > 0 && self.max_keys > 0 && usage.keys + keys > self.max_keys {
+            return Err(ServerError::QuotaExceeded(format!("{} may hold at most {} keys", self.owner, self.max_keys)));
+        }
+        if bytes > 0 && self.max_bytes > 0 && usage.bytes + bytes > self.max_bytes {
+            return Err(ServerError::QuotaExceeded(format!("{} may hold at most {} bytes", self.owner, self.max_bytes)));
+        }
+        Ok(())
+    }
+}
+
+// The keys and bytes a write would add to the keyspace, its keys named as
+// stored. Writes that only shrink or drop keys add none, so an owner over its
+// quota (after the quota was lowered, say) can make room. A write not listed
+// here is refused rather than let past the quotas.
+fn growth(cmd: &Command, cache: &Keyspace) -> Result<(usize, usize), ServerError> {
+    // A value of `len` bytes written to `key`, replacing the one there or,
+    // for the CRDTs, growing it
+    let grow = |key: &str, len: usize, replaces: bool| match cache.get(key) {
+        None => (1, entry_memory_for(key, len)),
+        Some(entry) if replaces => (0, entry_memory_for(key, len).saturating_sub(entry_memory(key, entry))),
+        Some(_) => (0, len),
+    };
+    let added = match cmd {
+        Command::QUORUM { command, .. } => return growth(command, cache),
+        Command::SET { key, value, .. } | Command::GETSET { key, value } => grow(key, value.len(), true),
+        Command::RESTORE { key, payload, .. } => grow(key, payload.len(), true),
+        Command::SET_MISSING { key, .. } => grow(key, 0, true),
+        Command::COUNTER_INCRBY { key, .. } => grow(key, 0, false),
+        Command::ORSET_ADD { key, members } => grow(key, members.iter().map(String::len).sum(), false),
+        Command::MSETNX { entries } => entries.iter()
+            .map(|(key, value)| grow(key, value.len(), true))
+            .fold((0, 0), |(keys, bytes), (k, b)| (keys + k, bytes + b)),
+        Command::DEL { .. } | Command::UNLINK { .. } | Command::ORSET_REM { .. } | Command::EXPIRE { .. }
+            | Command::EXPIREAT { .. } | Command::PEXPIRE { .. } | Command::PEXPIREAT { .. } | Command::PERSIST { .. }
+            | Command::SOFT_EXPIRE { .. } | Command::FLUSHALL | Command::CLUSTER_FLUSHALL | Command::MIGRATE { .. }
+            | Command::MIGRATE_SLOTS { .. } => (0, 0),
+        cmd if !cmd.is_write() => (0, 0),
+        cmd => return Err(ServerError::Forbidden(format!("{} can't be checked against the quotas", cmd.name()))),
+    };
+    Ok(added)
+}
+
+impl User {
+    fn may_access(&self, key: &str) -> bool {
+        self.rule.keys.iter().any(|pattern| glob_match(pattern.as_bytes(), key.as_bytes()))
+    }
+
+    // The quotas the user's writes are held to: its own and its tenant's,
+    // when they set limits
+    fn quotas(&self) -> impl Iterator<Item = &Quota> {
+        let tenant = self.tenant.as_ref()
+            .map(|tenant| &tenant.quota)
+            .filter(|quota| quota.max_keys > 0 || quota.max_bytes > 0);
+        self.quota.iter().chain(tenant)
+    }
+
+    // Refuse a write that would take the user or its tenant past their
+    // quotas. The write has entered the tenant, and the caller holds the
+    // keyspace's write lock until it is applied, so that two writes can't
+    // both fit in the room left for one.
+    pub fn check_quotas(&self, cmd: &Command, cache: &Keyspace) -> Result<(), ServerError> {
+        if self.quotas().next().is_none() {
+            return Ok(());
+        }
+        let added = growth(cmd, cache)?;
+        for quota in self.quotas() {
+            quota.check(added, cache)?;
+        }
+        Ok(())
+    }
+
+    // A key as the user names it, as it is stored
+    pub fn stored_key(&self, key: &str) -> String {
+        match &self.tenant {
+            Some(tenant) => format!("{}{}", tenant.prefix, key),
+            None => key.to_string(),
+        }
+    }
+
+    // Move a command into the user's tenant: its keys, and the pattern of
+    // KEYS, get the tenant's prefix
+    pub fn enter(&self, mut cmd: Command) -> Command {
+        let Some(tenant) = &self.tenant else {
+            return cmd;
+        };
+        for key in cmd.keys_mut() {
+            key.insert_str(0, &tenant.prefix);
+        }
+        if let Command::KEYS { pattern, .. } = &mut cmd {
+            pattern.insert_str(0, &escape(&tenant.prefix));
+        }
+        cmd
+    }
+
+    // Move a reply out of the user's tenant, dropping the prefix from the
+    // keys it names and the keys KEYS found that the user may not access
+    pub fn leave(&self, response: Response) -> Response {
+        let Some(tenant) = &self.tenant else {
+            return response;
+        };
+        let strip = |keys: Vec<String>| keys.into_iter()
+            .filter_map(|key| key.strip_prefix(tenant.prefix.as_str()).map(str::to_string));
+        match response {
+            Response::Keys(keys) => Response::Keys(strip(keys).filter(|key| self.may_access(key)).collect()),
+            Response::Invalidate { keys } => Response::Invalidate { keys: strip(keys).collect() },
+            response => response,
+        }
+    }
+
+    // An error as the user sees it, naming the key without the tenant's prefix
+    pub fn leave_error(&self, error: ServerError) -> ServerError {
+        match (&self.tenant, error) {
+            (Some(tenant), ServerError::KeyNotFound(key)) => match key.strip_prefix(tenant.prefix.as_str()) {
+                Some(key) => ServerError::KeyNotFound(key.to_string()),
+                None => ServerError::KeyNotFound(key),
+            },
+            (_, error) => error,
+        }
+    }
+}
+
+// Commands that reach past the keys they name, which only admin users run.
+// KEYS stays within a tenant, so its users may run it.
+fn is_privileged(cmd: &Command, tenant: bool) -> bool {
+    match cmd {
+        Command::QUORUM { command, .. } => is_privileged(command, tenant),
+        Command::KEYS { .. } => !tenant,
+        _ => matches!(
+            cmd,
+            Command::CLUSTER_JOIN { .. } | Command::CLUSTER_REMOVE { .. } | Command::CLUSTER_ISOLATE
+                | Command::CLUSTER_RESET { .. }
+                | Command::CLUSTER_SETSLOT { .. } | Command::CONFIG_GET { .. } | Command::SHUTDOWN { .. }
+                | Command::MEMORY_DEFRAG | Command::MIGRATE { .. } | Command::MIGRATE_SLOTS { .. }
+                | Command::MIGRATE_SLOTS_ABORT | Command::GEO_APPLY { .. } | Command::GEO_PROMOTE { .. }
+                | Command::SYNC { .. } | Command::REPLACK { .. } | Command::MERKLE { .. } | Command::DIGEST { .. }
+                | Command::REPAIR { .. } | Command::FLUSHALL | Command::CLUSTER_FLUSHALL
+                | Command::CLUSTER_KEYS { .. } | Command::DBSIZE | Command::CLUSTER_DBSIZE
+                | Command::KEYEVENTS_SUBSCRIBE { .. }
+        ),
+    }
+}
+
+// A glob pattern matching `text` literally
+pub(crate) fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+// Holds callers that authenticated as a user to what the user may do. It
+// sees commands as the user sent them, before they enter its tenant. The
+// quotas are checked where the write is applied, under the write lock.
+pub struct Sandbox;
+
+impl Middleware for Sandbox {
+    fn before(&self, call: &Call) -> Result<(), ServerError> {
+        let Some(user) = &call.caller.user else {
+            return Ok(());
+        };
+        if !user.rule.admin && is_privileged(call.command, user.tenant.is_some()) {
+            return Err(ServerError::Forbidden(format!("User {} may not run {}", user.rule.name, call.command.name())));
+        }
+        if let Some(key) = call.command.keys().into_iter().find(|key| !user.may_access(key)) {
+            return Err(ServerError::Forbidden(format!("User {} may not access key {}", user.rule.name, key)));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::RwLock;
+    use bytes::Bytes;
+    use crate::api::process_command;
+    use crate::environment::FluxConfig;
+    use crate::state::ServerState;
+
+    fn rule(name: &str, max_keys: usize, max_bytes: usize, tenant: &str) -> AclUser {
+        AclUser {
+            name: name.to_string(),
+            password: "password".to_string(),
+            keys: vec!["*".to_string()],
+            admin: false,
+            max_keys,
+            max_bytes,
+            tenant: tenant.to_string(),
+        }
+    }
+
+    fn state_with(users: Vec<AclUser>, tenants: Vec<TenantRule>) -> Arc<RwLock<ServerState>> {
+        let config = FluxConfig { users, tenants, ..Default::default() };
+        Arc::new(RwLock::new(ServerState::new("127.0.0.1:7000".to_string(), config)))
+    }
+
+    fn user(state: &Arc<RwLock<ServerState>>, name: &str) -> Arc<User> {
+        state.read().unwrap().acl.authenticate(name, "password").unwrap()
+    }
+
+    // Run a command as the user would, inside its tenant
+    async fn run(state: &Arc<RwLock<ServerState>>, user: &User, cmd: Command) -> Result<Response, ServerError> {
+        process_command(user.enter(cmd), state, Some(user)).await
+    }
+
+    fn set(key: &str, value: &str) -> Command {
+        Command::SET { key: key.to_string(), value: value.as_bytes().to_vec(), visible_at: None }
+    }
+
+    fn msetnx(keys: &[&str]) -> Command {
+        Command::MSETNX { entries: keys.iter().map(|key| (key.to_string(), Bytes::from_static(b"v"))).collect() }
+    }
+
+    #[tokio::test]
+    async fn msetnx_counts_every_key_it_sets() {
+        let state = state_with(vec![rule("app", 2, 0, "")], Vec::new());
+        let app = user(&state, "app");
+        let refused = run(&state, &app, msetnx(&["a", "b", "c"])).await;
+        assert!(matches!(refused, Err(ServerError::QuotaExceeded(_))));
+        assert_eq!(state.read().unwrap().cache.len(), 0);
+        assert!(matches!(run(&state, &app, msetnx(&["a", "b"])).await, Ok(Response::Integer(1))));
+    }
+
+    #[tokio::test]
+    async fn set_missing_takes_a_key_of_the_quota() {
+        let state = state_with(vec![rule("app", 1, 0, "")], Vec::new());
+        let app = user(&state, "app");
+        run(&state, &app, set("a", "v")).await.unwrap();
+        let missing = Command::SET_MISSING { key: "b".to_string(), milliseconds: 60_000 };
+        assert!(matches!(run(&state, &app, missing).await, Err(ServerError::QuotaExceeded(_))));
+    }
+
+    #[tokio::test]
+    async fn writes_that_add_nothing_pass_at_the_limit() {
+        let state = state_with(vec![rule("app", 1, 200, "")], Vec::new());
+        let app = user(&state, "app");
+        run(&state, &app, set("a", "a longer value")).await.unwrap();
+        assert!(matches!(run(&state, &app, set("b", "v")).await, Err(ServerError::QuotaExceeded(_))));
+        // Replacing a value with a shorter one, and dropping a key, go through
+        run(&state, &app, set("a", "short")).await.unwrap();
+        run(&state, &app, Command::DEL { keys: vec!["a".to_string()] }).await.unwrap();
+        run(&state, &app, set("b", "v")).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn quorum_writes_are_held_to_the_quota() {
+        let state = state_with(vec![rule("app", 1, 0, "")], Vec::new());
+        let app = user(&state, "app");
+        run(&state, &app, set("a", "v")).await.unwrap();
+        let quorum = Command::QUORUM { replicas: 0, command: Box::new(set("b", "v")) };
+        assert!(matches!(run(&state, &app, quorum).await, Err(ServerError::QuotaExceeded(_))));
+    }
+
+    #[tokio::test]
+    async fn users_of_a_tenant_share_its_quota() {
+        let tenants = vec![TenantRule { name: "acme".to_string(), max_keys: 2, max_bytes: 0 }];
+        let state = state_with(vec![rule("alice", 0, 0, "acme"), rule("bob", 0, 0, "acme")], tenants);
+        let (alice, bob) = (user(&state, "alice"), user(&state, "bob"));
+        run(&state, &alice, set("a", "v")).await.unwrap();
+        run(&state, &bob, set("b", "v")).await.unwrap();
+        assert!(matches!(run(&state, &bob, set("c", "v")).await, Err(ServerError::QuotaExceeded(_))));
+        assert!(state.read().unwrap().cache.contains_key("acme:a"));
+    }
+
+    #[test]
+    fn tenant_keys_hash_as_their_users_name_them() {
+        let state = state_with(vec![rule("alice", 0, 0, "acme")], Vec::new());
+        let alice = user(&state, "alice");
+        let state = state.read().unwrap();
+        let stored = alice.stored_key("user:1000");
+        assert_eq!(stored, "acme:user:1000");
+        assert_eq!(state.acl.key_slot(&stored), key_slot("user:1000"));
+        assert_eq!(state.acl.key_slot("acme:{user1000}.following"), key_slot("user1000"));
+        // Only a tenant's prefix is hashed past
+        assert_eq!(state.acl.key_slot("other:user:1000"), key_slot("other:user:1000"));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_writes_cant_share_the_room_left() {
+        let state = state_with(vec![rule("app", 5, 0, "")], Vec::new());
+        let app = user(&state, "app");
+        let mut writes = tokio::task::JoinSet::new();
+        for i in 0..64 {
+            let (state, app) = (state.clone(), app.clone());
+            writes.spawn(async move { run(&state, &app, set(&format!("key{}", i), "v")).await.is_ok() });
+        }
+        let written = writes.join_all().await.into_iter().filter(|ok| *ok).count();
+        assert_eq!(written, 5);
+        assert_eq!(state.read().unwrap().cache.len(), 5);
+    }
+}
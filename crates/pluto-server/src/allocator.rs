@@ -0,0 +1,73 @@
+// Global allocator selection. The system allocator is used unless the crate
+// is built with the `jemalloc` or `mimalloc` feature.
+
+#[cfg(all(feature = "jemalloc", feature = "mimalloc"))]
+compile_error!("the jemalloc and mimalloc features are mutually exclusive");
+
+#[cfg(feature = "jemalloc")]
+#[global_allocator]
+static GLOBAL: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
+
+#[cfg(feature = "mimalloc")]
+#[global_allocator]
+static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
+
+#[cfg(feature = "jemalloc")]
+pub const NAME: &str = "jemalloc";
+#[cfg(feature = "mimalloc")]
+pub const NAME: &str = "mimalloc";
+#[cfg(not(any(feature = "jemalloc", feature = "mimalloc")))]
+pub const NAME: &str = "libc";
+
+// Process memory as seen by the allocator, in bytes
+pub struct AllocatorStats {
+    pub name: &'static str,
+    pub allocated: Option<usize>, // bytes handed out to the program
+    pub resident: Option<usize>,  // bytes backed by physical memory
+}
+
+#[cfg(feature = "jemalloc")]
+pub fn stats() -> AllocatorStats {
+    use tikv_jemalloc_ctl::{epoch, stats};
+    // jemalloc caches its statistics until the epoch is advanced
+    let _ = epoch::advance();
+    AllocatorStats {
+        name: NAME,
+        allocated: stats::allocated::read().ok(),
+        resident: stats::resident::read().ok(),
+    }
+}
+
+#[cfg(feature = "mimalloc")]
+pub fn stats() -> AllocatorStats {
+    let (mut elapsed, mut user, mut system) = (0, 0, 0);
+    let (mut rss, mut peak_rss, mut commit, mut peak_commit, mut faults) = (0, 0, 0, 0, 0);
+    // SAFETY: every pointer refers to a live, writable usize
+    unsafe {
+        libmimalloc_sys::mi_process_info(
+            &mut elapsed, &mut user, &mut system,
+            &mut rss, &mut peak_rss, &mut commit, &mut peak_commit, &mut faults,
+        );
+    }
+    AllocatorStats {
+        name: NAME,
+        allocated: Some(commit),
+        resident: Some(rss),
+    }
+}
+
+#[cfg(not(any(feature = "jemalloc", feature = "mimalloc")))]
+pub fn stats() -> AllocatorStats {
+    use sysinfo::System;
+    // The system allocator keeps no statistics; fall back to the process RSS
+    let resident = sysinfo::get_current_pid().ok().and_then(|pid| {
+        let mut system = System::new();
+        system.refresh_process(pid);
+        system.process(pid).map(|process| process.memory() as usize)
+    });
+    AllocatorStats {
+        name: NAME,
+        allocated: None,
+        resident,
+    }
+}
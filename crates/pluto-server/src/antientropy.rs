@@ -0,0 +1,128 @@
+use std::collections::BTreeSet;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use log::{debug, info, warn};
+use pluto_core::cache::{ServerError, dump_entry, now_ms};
+use pluto_core::merkle::{MerkleTree, slot_digests};
+use pluto_core::protocol::{Command, Response};
+use crate::peer::PeerConnection;
+use crate::state::ServerState;
+
+// Keys sent in one REPAIR
+const REPAIR_BATCH: usize = 1000;
+
+// Compare the keyspace with the primary's every `anti_entropy_interval_secs`
+// and have it resend the keys that differ. The replication stream keeps a
+// replica in step while the link is up; this heals what it missed, such as
+// writes lost while a partition cut the stream without a resync.
+pub async fn run(state: Arc<RwLock<ServerState>>) {
+    let (replication, interval, shutdown) = {
+        let state = state.read().unwrap();
+        (state.replication.clone(), state.config.anti_entropy_interval_secs, state.shutdown.clone())
+    };
+    let Some(primary) = replication.primary.clone() else {
+        return;
+    };
+    if interval == 0 {
+        return;
+    }
+    let mut ticker = tokio::time::interval(Duration::from_secs(interval));
+    ticker.tick().await; // the first tick is immediate; the initial sync already copied everything
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            _ = shutdown.wait() => break,
+        }
+        // Mid-sync the keyspace is expected to differ
+        if !replication.link_up() {
+            continue;
+        }
+        match repair_from(&state, &primary).await {
+            Ok(0) => debug!("Anti-entropy found no divergence from {}", primary),
+            Ok(repaired) => {
+                info!("Anti-entropy had {} keys resent by {}", repaired, primary);
+                replication.record_repairs(repaired);
+            }
+            Err(e) => warn!("Anti-entropy with {} failed: {}", primary, e),
+        }
+    }
+}
+
+// Walk both Merkle trees from the root, descending only into nodes whose
+// hashes differ, and ask the primary to resend the keys of differing slots.
+// Returns the number of keys repaired.
+async fn repair_from(state: &Arc<RwLock<ServerState>>, primary: &str) -> Result<usize, ServerError> {
+    let credentials = state.read().unwrap().config.peer_credentials();
+    let mut peer = PeerConnection::connect(primary, &credentials).await?;
+    let local = MerkleTree::build(&state.read().unwrap().cache);
+
+    let mut differing = vec![0];
+    for level in 0..local.depth() {
+        if differing.is_empty() {
+            return Ok(0);
+        }
+        let remote = digests(&mut peer, Command::MERKLE { level, nodes: differing.clone() }).await?;
+        if remote.len() != differing.len() {
+            return Err(ServerError::InvalidArgument("Primary sent a tree of another shape".to_string()));
+        }
+        let changed = differing.iter().zip(remote).filter(|(index, hash)| local.hash(level, **index) != *hash).map(|(index, _)| *index);
+        differing = if level + 1 < local.depth() {
+            changed.flat_map(|index| local.children(level, index)).collect()
+        } else {
+            changed.collect()
+        };
+    }
+
+    // `differing` now holds slots; find the keys in them that differ
+    let mut keys = BTreeSet::new();
+    for slot in differing {
+        let remote = match peer.send(&[Command::DIGEST { slot }]).await?.pop() {
+            Some(Response::KeyDigests(remote)) => remote,
+            other => return Err(unexpected(other)),
+        };
+        let local = slot_digests(&state.read().unwrap().cache, slot);
+        // Keys present on one side only, or with a different value
+        let remote: BTreeSet<(String, u64)> = remote.into_iter().collect();
+        let local: BTreeSet<(String, u64)> = local.into_iter().collect();
+        keys.extend(remote.symmetric_difference(&local).map(|(key, _)| key.clone()));
+    }
+
+    // The primary streams the fixes like any other write, so they can't
+    // overtake a newer write to the same key
+    let keys: Vec<String> = keys.into_iter().collect();
+    for batch in keys.chunks(REPAIR_BATCH) {
+        match peer.send(&[Command::REPAIR { keys: batch.to_vec() }]).await?.pop() {
+            Some(Response::Integer(_)) => {}
+            other => return Err(unexpected(other)),
+        }
+    }
+    Ok(keys.len())
+}
+
+// Stream the keys as this node holds them to every replica, or their
+// deletion when it holds none. Called with the write lock held, so the
+// fixes stay in order with every other write.
+pub fn resend(state: &ServerState, keys: &[String]) {
+    let now = now_ms();
+    for key in keys {
+        let command = match state.cache.get(key) {
+            Some(entry) => Command::RESTORE { key: key.clone(), payload: dump_entry(entry, now).to_vec(), replace: true },
+            None => Command::DEL { keys: vec![key.clone()] },
+        };
+        state.replication.propagate(command);
+    }
+}
+
+async fn digests(peer: &mut PeerConnection, cmd: Command) -> Result<Vec<u64>, ServerError> {
+    match peer.send(&[cmd]).await?.pop() {
+        Some(Response::Digests(hashes)) => Ok(hashes),
+        other => Err(unexpected(other)),
+    }
+}
+
+fn unexpected(response: Option<Response>) -> ServerError {
+    match response {
+        Some(Response::Error(e)) => ServerError::InvalidArgument(format!("Primary refused anti-entropy: {}", e)),
+        other => ServerError::InvalidArgument(format!("Unexpected response from primary: {:?}", other)),
+    }
+}
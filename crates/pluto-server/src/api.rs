@@ -0,0 +1,1405 @@
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::Ordering;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, Interest};
+use tokio::net::TcpStream;
+use bytes::{Buf, Bytes, BytesMut};
+use std::time::{Duration, Instant};
+use log::{debug, error, warn};
+use tracing::Instrument;
+use subtle::ConstantTimeEq;
+use pluto_core::cache::{ServerError, CacheEntry, WriteStamp, entry_memory, encode_entry, entry_value, now_ms, dump_entry, restore_entry};
+use pluto_core::crdt::{Counter, Crdt, ObservedRemoveSet};
+use pluto_core::protocol::{Command, ErrorCode, ErrorReply, ObjectInfo, ResetMode, Response, ShutdownMode, SlotState};
+use pluto_core::cluster::{Hashing, MetaCommand, TOTAL_SLOTS};
+use pluto_core::merkle::{MerkleTree, slot_digests};
+use crate::state::ServerState;
+use crate::buffer::READ_BUFFER_SIZE;
+use crate::batch::ResponseBatch;
+use crate::clients::{ConnectionLimits, OutputBuffer};
+use crate::info::{build_info, persistence_status};
+use crate::network::{self, ListenerKind, PROTECTED_MODE_REFUSAL};
+use crate::origin::Origins;
+use pluto_core::codec::{parse_commands, Encoding, PROTOCOL_VERSION};
+use pluto_core::commands::{self, CommandSpec, COMMANDS};
+use crate::session::Session;
+use crate::acl::User;
+use crate::middleware::{Caller, Transport};
+use crate::deadline;
+use crate::version;
+use crate::wal::WalFsync;
+use crate::defrag;
+use crate::fanout;
+use crate::lazyfree;
+use crate::eviction;
+use crate::geo;
+use crate::antientropy;
+use crate::handoff;
+use crate::heartbeat;
+use crate::migrate;
+use crate::raft;
+use crate::readrepair;
+use crate::peer::{PeerConnection, PeerCredentials};
+use crate::relay;
+use crate::replication::{self, ReplicaStream};
+use crate::tracking::{self, Tracker};
+
+// Helper function to get node info from a remote server
+async fn get_node_info(address: &str, credentials: &PeerCredentials) -> Result<(String, String, u32, Hashing), Box<dyn std::error::Error>> {
+    let mut peer = PeerConnection::connect(address, credentials).await?;
+    match peer.send(&[Command::NODE_INFO]).await?.pop() {
+        // Nodes from before ring mode report no weight
+        Some(Response::NodeInfo { node_id, address, weight, hashing }) => Ok((node_id, address, weight.max(1), hashing)),
+        Some(Response::Error(e)) => Err(format!("Node returned error: {}", e).into()),
+        _ => Err("Unexpected response from node".into()),
+    }
+}
+
+// Name reported by HELLO, independent of the crate the server is built from
+pub const SERVER_NAME: &str = "flux-cache";
+
+// Capabilities reported by HELLO
+fn hello(protocol: Option<u32>, auth_required: bool) -> Result<Response, ServerError> {
+    if let Some(requested) = protocol
+        && requested > PROTOCOL_VERSION {
+        return Err(ServerError::InvalidArgument(format!(
+            "Unsupported protocol version {}, server speaks up to {}", requested, PROTOCOL_VERSION
+        )));
+    }
+    Ok(Response::Hello {
+        server: SERVER_NAME.to_string(),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        protocol: PROTOCOL_VERSION,
+        encodings: Encoding::ALL.to_vec(),
+        compression: vec!["zstd".to_string()], // values are stored zstd-compressed
+        auth_required,
+    })
+}
+
+// Whether clients must authenticate before running commands
+pub(crate) fn auth_required(state: &ServerState) -> bool {
+    !state.config.requirepass.is_empty() || !state.acl.is_empty()
+}
+
+// The user a password authenticates as: the named ACL user, or None for
+// requirepass when no name is given
+pub(crate) fn authenticate(state: &ServerState, username: Option<&str>, password: &str) -> Result<Option<Arc<User>>, ServerError> {
+    if let Some(username) = username {
+        return state.acl.authenticate(username, password).map(Some);
+    }
+    if state.config.requirepass.is_empty() {
+        return Err(ServerError::InvalidArgument("No password is configured".to_string()));
+    }
+    // Compared in constant time, so the reply time doesn't give away how
+    // much of the password matched
+    if !bool::from(password.as_bytes().ct_eq(state.config.requirepass.as_bytes())) {
+        return Err(ServerError::Unauthorized("Invalid password".to_string()));
+    }
+    Ok(None)
+}
+
+// Redirect a key command when another node serves its slot. Every key of a
+// multi-key command must belong to the same node. While a slot migrates, the
+// keys already moved are asked of the importing node, which serves them to
+// connections that sent ASKING first.
+fn cluster_redirect(cmd: &Command, state: &Arc<RwLock<ServerState>>, asking: bool) -> Result<Option<Response>, ServerError> {
+    let keys = cmd.keys();
+    let Some(first) = keys.first() else {
+        return Ok(None);
+    };
+    let state = state.read().unwrap();
+    if !state.cluster_enabled {
+        return Ok(None);
+    }
+    let slot = state.acl.key_slot(first);
+    let owner = state.cluster.slot_owner(slot).map(|node| node.address.as_str());
+    for key in &keys[1..] {
+        let other = state.cluster.slot_owner(state.acl.key_slot(key)).map(|node| node.address.as_str());
+        if other != owner {
+            return Err(ServerError::InvalidArgument("Keys in request don't hash to the same node".to_string()));
+        }
+    }
+    if owner == Some(state.cluster.self_addr.as_str()) {
+        if let Some(target) = state.cluster.migrating.get(&slot)
+            && !keys.iter().all(|key| state.cache.contains_key(key)) {
+            return Ok(Some(Response::Ask { slot, address: target.clone() }));
+        }
+        return Ok(None);
+    }
+    if asking && state.cluster.importing.contains_key(&slot) {
+        return Ok(None);
+    }
+    match owner {
+        Some(address) => Ok(Some(Response::Moved { slot, address: address.to_string() })),
+        None => Err(ServerError::InvalidArgument(format!("Slot {} is not served by any node", slot))),
+    }
+}
+
+// Ownership changes go through the metadata log so every node applies them
+// at the same epoch. MIGRATING and IMPORTING only concern the two nodes a
+// slot moves between and stay local to each.
+async fn cluster_setslot(
+    state: &Arc<RwLock<ServerState>>,
+    slots: Vec<usize>,
+    slot_state: SlotState,
+) -> Result<Response, ServerError> {
+    if slots.is_empty() {
+        return Err(ServerError::InvalidArgument("No slots given".to_string()));
+    }
+    if let Some(slot) = slots.iter().find(|slot| **slot >= TOTAL_SLOTS) {
+        return Err(ServerError::InvalidArgument(format!("Slot {} out of range", slot)));
+    }
+    let address = {
+        let mut state = state.write().unwrap();
+        if !state.cluster_enabled {
+            return Err(ServerError::KeyNotFound("Clustering is disabled".to_string()));
+        }
+        let address = match &slot_state {
+            SlotState::NODE { address } | SlotState::MIGRATING { address } | SlotState::IMPORTING { address } => {
+                Some(address.clone())
+            }
+            SlotState::UNASSIGNED | SlotState::STABLE => None,
+        };
+        if let Some(address) = &address
+            && !state.raft.members().contains(address) {
+            return Err(ServerError::KeyNotFound("Node not found in cluster".to_string()));
+        }
+        let self_addr = state.cluster.self_addr.clone();
+        let cluster = &mut state.cluster;
+        let ours = |slot: &usize| cluster.slot_owner(*slot).is_some_and(|node| node.address == self_addr);
+        match slot_state {
+            SlotState::NODE { .. } | SlotState::UNASSIGNED => address,
+            SlotState::MIGRATING { address } => {
+                if let Some(slot) = slots.iter().find(|slot| !ours(slot)) {
+                    return Err(ServerError::InvalidArgument(format!("Slot {} is not served by this node", slot)));
+                }
+                for slot in slots {
+                    cluster.importing.remove(&slot);
+                    cluster.migrating.insert(slot, address.clone());
+                }
+                return Ok(Response::Success);
+            }
+            SlotState::IMPORTING { address } => {
+                if let Some(slot) = slots.iter().find(|slot| ours(slot)) {
+                    return Err(ServerError::InvalidArgument(format!("Slot {} is already served by this node", slot)));
+                }
+                for slot in slots {
+                    cluster.migrating.remove(&slot);
+                    cluster.importing.insert(slot, address.clone());
+                }
+                return Ok(Response::Success);
+            }
+            SlotState::STABLE => {
+                for slot in slots {
+                    cluster.migrating.remove(&slot);
+                    cluster.importing.remove(&slot);
+                }
+                return Ok(Response::Success);
+            }
+        }
+    };
+    raft::propose(state, MetaCommand::AssignSlots { slots, address }, true).await?;
+    Ok(Response::Success)
+}
+
+// Send commands a replica doesn't serve to its primary: every write, and reads
+// unless the connection opted in with READONLY
+fn replica_redirect(cmd: &Command, state: &Arc<RwLock<ServerState>>, readonly: bool) -> Option<Response> {
+    let keys = cmd.keys();
+    let first = keys.first()?;
+    if readonly && !cmd.is_write() {
+        return None;
+    }
+    let state = state.read().unwrap();
+    let primary = state.replication.primary.as_ref()?;
+    Some(Response::Moved { slot: state.acl.key_slot(first), address: primary.clone() })
+}
+
+// Apply a command that modifies the keyspace and stream it to the replicas.
+// Replicas apply their primary's writes through here as well.
+pub fn apply_write(state: &mut ServerState, cmd: Command) -> Result<Response, ServerError> {
+    stamped_write(state, cmd, None, false)
+}
+
+// Apply a write another cluster made, keeping the stamp it made there when
+// it has one. Geo-replication doesn't ship it back.
+pub fn apply_geo_write(state: &mut ServerState, cmd: Command, stamp: Option<WriteStamp>) -> Result<Response, ServerError> {
+    stamped_write(state, cmd, stamp, true)
+}
+
+// Apply a write, stamping the entries it writes with `stamp` or, by default,
+// a stamp following the entry it replaces
+pub fn stamped_write(
+    state: &mut ServerState,
+    cmd: Command,
+    stamp: Option<WriteStamp>,
+    local_only: bool,
+) -> Result<Response, ServerError> {
+    let replicated = state.replication.records_writes().then(|| cmd.clone());
+    if state.tracking.is_active() {
+        state.tracking.invalidate(&cmd.keys());
+    }
+    if state.singleflight.is_active() {
+        state.singleflight.release(&cmd.keys());
+    }
+    let mut written = None;
+    let response = match cmd {
+        Command::SET { key, value, visible_at } => {
+            let mut entry = encode_entry(value)?;
+            entry.visible_at = visible_at;
+            entry.stamp = stamp.unwrap_or_else(|| WriteStamp::after(state.cache.get(&key)));
+            written = Some(entry.stamp);
+            state.cache.insert(key, entry);
+            Response::Success
+        },
+        // Replicas and other clusters are only sent the new value, as a SET
+        Command::GETSET { key, value } => {
+            let old = match state.cache.read(&key) {
+                Some(entry) if entry.crdt => return Err(ServerError::WrongType(format!(
+                    "{} holds a {}; it can't be swapped for a value", key, Crdt::of_entry(&key, entry)?.name()
+                ))),
+                Some(entry) if !entry.tombstone => Some(entry_value(entry)?),
+                _ => None,
+            };
+            stamped_write(state, Command::SET { key, value, visible_at: None }, stamp, local_only)?;
+            return Ok(old.map_or(Response::Nil, Response::Data));
+        },
+        Command::MSETNX { entries } => {
+            if entries.iter().any(|(key, _)| state.cache.contains_key(key)) {
+                return Ok(Response::Integer(0));
+            }
+            let stamp = stamp.unwrap_or_else(|| WriteStamp::after(None));
+            written = Some(stamp);
+            for (key, value) in entries {
+                let mut entry = encode_entry(value.into())?;
+                entry.stamp = stamp;
+                state.cache.insert(key, entry);
+            }
+            Response::Integer(1)
+        },
+        Command::DEL { keys } => {
+            // A deletion is stamped like a write replacing the newest of the keys
+            let newest = keys.iter().filter_map(|key| state.cache.get(key)).max_by_key(|entry| entry.stamp.version);
+            written = Some(stamp.unwrap_or_else(|| WriteStamp::after(newest)));
+            let mut found = false;
+            for key in keys {
+                if state.cache.remove(&key).is_some() {
+                    found = true;
+                }
+            }
+            if !found {
+                return Err(ServerError::KeyNotFound("None of the keys found".to_string()));
+            }
+            Response::Success
+        },
+        Command::EXPIRE { key, seconds, .. } => {
+            let expires_at = now_ms().saturating_add(seconds.saturating_mul(1000));
+            let found = state.cache.set_expiry(&key, Some(expires_at));
+            Response::Integer(found as i64)
+        },
+        Command::EXPIREAT { key, timestamp } => {
+            let found = state.cache.set_expiry(&key, Some(timestamp.saturating_mul(1000)));
+            Response::Integer(found as i64)
+        },
+        Command::PEXPIRE { key, milliseconds, .. } => {
+            let found = state.cache.set_expiry(&key, Some(now_ms().saturating_add(milliseconds)));
+            Response::Integer(found as i64)
+        },
+        Command::PEXPIREAT { key, timestamp_ms } => {
+            let found = state.cache.set_expiry(&key, Some(timestamp_ms));
+            Response::Integer(found as i64)
+        },
+        Command::SOFT_EXPIRE { key, seconds } => {
+            let stale_at = now_ms().saturating_add(seconds.saturating_mul(1000));
+            Response::Integer(state.cache.set_soft_expiry(&key, Some(stale_at)) as i64)
+        },
+        Command::SET_MISSING { key, milliseconds } => {
+            let mut entry = CacheEntry::tombstone(now_ms().saturating_add(milliseconds));
+            entry.stamp = stamp.unwrap_or_else(|| WriteStamp::after(state.cache.get(&key)));
+            written = Some(entry.stamp);
+            state.cache.insert(key, entry);
+            Response::Success
+        },
+        Command::PERSIST { key } => {
+            let expiring = state.cache.get(&key).is_some_and(|entry| entry.expires_at.is_some());
+            if expiring {
+                state.cache.set_expiry(&key, None);
+            }
+            Response::Integer(expiring as i64)
+        },
+        Command::RESTORE { key, payload, replace } => {
+            let mut entry = restore_entry(&payload, now_ms())?;
+            if !replace && state.cache.contains_key(&key) {
+                return Err(ServerError::InvalidArgument(format!("Target key {} already exists", key)));
+            }
+            // The blob keeps the entry's stamp, unless it predates stamps
+            if let Some(stamp) = stamp {
+                entry.stamp = stamp;
+            } else if entry.stamp == WriteStamp::default() {
+                entry.stamp = WriteStamp::after(state.cache.get(&key));
+            }
+            written = Some(entry.stamp);
+            state.cache.insert(key, entry);
+            Response::Success
+        },
+        Command::UNLINK { keys } => {
+            let newest = keys.iter().filter_map(|key| state.cache.get(key)).max_by_key(|entry| entry.stamp.version);
+            written = Some(stamp.unwrap_or_else(|| WriteStamp::after(newest)));
+            let removed: Vec<CacheEntry> = keys.iter().filter_map(|key| state.cache.remove(key)).collect();
+            let count = removed.len();
+            lazyfree::free_entries(state, removed);
+            Response::Integer(count as i64)
+        },
+        Command::FLUSHALL => {
+            lazyfree::clear(state);
+            Response::Success
+        },
+        cmd @ (Command::COUNTER_INCRBY { .. } | Command::ORSET_ADD { .. } | Command::ORSET_REM { .. }) => {
+            return crdt_write(state, cmd, local_only);
+        },
+        cmd => return Err(ServerError::InvalidArgument(format!("{} is not a replicated write", cmd.name()))),
+    };
+    if let Some(cmd) = replicated {
+        state.replication.propagate_stamped(cmd, written, local_only);
+    }
+    Ok(response)
+}
+
+// Update a CRDT on behalf of this node. Replicas and other clusters are sent
+// the whole new state as a RESTORE, which the other clusters merge into theirs.
+fn crdt_write(state: &mut ServerState, cmd: Command, local_only: bool) -> Result<Response, ServerError> {
+    let node = state.cluster.self_addr.clone();
+    let (key, empty) = match &cmd {
+        Command::COUNTER_INCRBY { key, .. } => (key.clone(), Crdt::Counter(Counter::default())),
+        Command::ORSET_ADD { key, .. } | Command::ORSET_REM { key, .. } => (key.clone(), Crdt::Set(ObservedRemoveSet::default())),
+        cmd => return Err(ServerError::InvalidArgument(format!("{} is not a CRDT update", cmd.name()))),
+    };
+    // A key cached as missing holds no CRDT yet
+    let previous = state.cache.get(&key).filter(|entry| !entry.tombstone);
+    let expires_at = previous.and_then(|entry| entry.expires_at);
+    let mut crdt = previous.map(|entry| Crdt::of_entry(&key, entry)).transpose()?.unwrap_or(empty);
+    let response = match (cmd, &mut crdt) {
+        (Command::COUNTER_INCRBY { delta, .. }, Crdt::Counter(counter)) => {
+            counter.add(&node, delta);
+            Response::Integer(counter.value())
+        }
+        (Command::ORSET_ADD { members, .. }, Crdt::Set(set)) => {
+            Response::Integer(members.iter().filter(|member| set.add(&node, member)).count() as i64)
+        }
+        (Command::ORSET_REM { members, .. }, Crdt::Set(set)) => {
+            Response::Integer(members.iter().filter(|member| set.remove(member)).count() as i64)
+        }
+        (cmd, crdt) => {
+            return Err(ServerError::WrongType(format!("{} can't update {}, which holds a {}", cmd.name(), key, crdt.name())));
+        }
+    };
+    let mut entry = crdt.to_entry();
+    entry.expires_at = expires_at;
+    let payload = dump_entry(&entry, now_ms()).to_vec();
+    stamped_write(state, Command::RESTORE { key, payload, replace: true }, None, local_only)?;
+    Ok(response)
+}
+
+// Apply a write, then hold the reply until it is synced to the log, with
+// wal_fsync "always", and `quorum` replicas (`write_quorum` when not given)
+// applied it. The write stays applied here when the quorum isn't reached in
+// time; the error only tells the client it may not survive losing this node.
+// A write of an ACL user is first held to its quotas, under the same lock.
+async fn write_with_quorum(
+    state: &Arc<RwLock<ServerState>>,
+    cmd: Command,
+    quorum: Option<usize>,
+    user: Option<&User>,
+) -> Result<Response, ServerError> {
+    let (response, offset, replication, quorum, timeout) = {
+        let mut state = state.write().unwrap();
+        if let Some(user) = user {
+            user.check_quotas(&cmd, &state.cache)?;
+        }
+        eviction::make_room(&mut state, &cmd)?;
+        let response = apply_write(&mut state, cmd)?;
+        let quorum = quorum.unwrap_or(state.config.write_quorum);
+        let timeout = std::time::Duration::from_millis(state.config.write_quorum_timeout_ms);
+        (response, state.replication.offset(), state.replication.clone(), quorum, timeout)
+    };
+    if let Some(wal) = replication.wal().filter(|wal| wal.fsync == WalFsync::Always) {
+        wal.sync_to(wal.position()).await?;
+    }
+    if quorum == 0 {
+        return Ok(response);
+    }
+    let acked = replication.wait_for_acks(offset, quorum, Some(timeout)).await;
+    if acked < quorum {
+        return Err(ServerError::QuorumNotReached(format!(
+            "{} of {} replicas applied the write", acked, quorum
+        )));
+    }
+    Ok(response)
+}
+
+// Pass a client's SET or DEL on to the origin backing the key before
+// applying it, so a write the origin refused is never cached
+async fn write_through(state: &Arc<RwLock<ServerState>>, cmd: &Command) -> Result<(), ServerError> {
+    let origins = state.read().unwrap().origins.clone();
+    if origins.is_empty() {
+        return Ok(());
+    }
+    origins.write_through(cmd).await
+}
+
+// Fetch a key missing here from the origin backing it and keep it. Replicas
+// and geo standbys take their writes from elsewhere, so they only pass the
+// value on.
+async fn read_through(
+    state: &Arc<RwLock<ServerState>>,
+    origins: &Origins,
+    key: String,
+) -> Result<Response, ServerError> {
+    let fetched = origins.fetch(&key).await?;
+    let mut state = state.write().unwrap();
+    let writable = !state.replication.is_replica() && !state.geo.is_standby();
+    let Some((value, ttl_secs)) = fetched else {
+        // The origin's misses are cached as missing for as long as its rule says
+        let missing_ttl_ms = origins.missing_ttl_ms(&key);
+        if writable && missing_ttl_ms > 0 && !state.cache.contains_key(&key) {
+            apply_write(&mut state, Command::SET_MISSING { key: key.clone(), milliseconds: missing_ttl_ms })?;
+        }
+        return Ok(Response::Nil);
+    };
+    if !writable {
+        return Ok(Response::Data(Bytes::from(value)));
+    }
+    // A client set the key while it was being fetched; theirs is newer. One
+    // scheduled to appear later stays hidden, the origin's value with it.
+    match state.cache.get(&key) {
+        Some(entry) if entry.is_hidden(now_ms()) => return Ok(Response::Nil),
+        Some(entry) if entry.tombstone => return Ok(Response::Missing),
+        Some(entry) => return Ok(Response::Data(entry_value(entry)?)),
+        None => {}
+    }
+    // Past maxmemory with nothing to evict, the value is served uncached
+    let set = Command::SET { key: key.clone(), value: value.clone(), visible_at: None };
+    if eviction::make_room(&mut state, &set).is_err() {
+        return Ok(Response::Data(Bytes::from(value)));
+    }
+    apply_write(&mut state, set)?;
+    if ttl_secs > 0 {
+        let expire = Command::EXPIRE { key, seconds: ttl_secs, jitter_pct: None };
+        let expire = with_jitter(expire, state.config.ttl_jitter_pct);
+        apply_write(&mut state, expire)?;
+    }
+    Ok(Response::Data(Bytes::from(value)))
+}
+
+// Process client commands, from the ACL user `user` when there is one
+pub async fn process_command(
+    cmd: Command, 
+    state: &Arc<RwLock<ServerState>>,
+    user: Option<&User>,
+) -> Result<Response, ServerError> {
+    process_asking_command(cmd, state, false, user).await
+}
+
+// Spread the TTL an EXPIRE or PEXPIRE sets by up to `jitter_pct` (or its own
+// percentage) either way, so keys set together don't all expire together.
+// The spread TTL is written as an exact PEXPIRE, which replicas and the
+// write-ahead log apply as is.
+fn with_jitter(cmd: Command, default_pct: u8) -> Command {
+    let (key, milliseconds, pct) = match cmd {
+        Command::EXPIRE { key, seconds, jitter_pct } if jitter_pct.unwrap_or(default_pct) > 0 => {
+            (key, seconds.saturating_mul(1000), jitter_pct.unwrap_or(default_pct))
+        }
+        Command::PEXPIRE { key, milliseconds, jitter_pct } if jitter_pct.unwrap_or(default_pct) > 0 => {
+            (key, milliseconds, jitter_pct.unwrap_or(default_pct))
+        }
+        cmd => return cmd,
+    };
+    let spread = milliseconds as f64 * pct.min(100) as f64 / 100.0;
+    let milliseconds = (milliseconds as f64 + rand::thread_rng().gen_range(-spread..=spread)).round() as u64;
+    Command::PEXPIRE { key, milliseconds, jitter_pct: Some(0) }
+}
+
+// A client's read of a key, or None when it's missing
+pub fn read_value(state: &ServerState, key: &str) -> Result<Option<Response>, ServerError> {
+    let entry = state.cache.read(key);
+    state.stats.record_read(entry.is_some());
+    match entry {
+        Some(entry) if entry.tombstone => {
+            state.stats.missing_reads.fetch_add(1, Ordering::Relaxed);
+            Ok(Some(Response::Missing))
+        }
+        Some(entry) if entry.crdt => Err(ServerError::WrongType(format!(
+            "{} holds a {}; read it with COUNTER_GET or ORSET_MEMBERS", key, Crdt::of_entry(key, entry)?.name()
+        ))),
+        // The first read past the soft TTL tells whoever refreshes keys on
+        // the revalidation channel
+        Some(entry) if entry.is_stale(now_ms()) => {
+            let value = entry_value(entry)?;
+            state.stats.stale_reads.fetch_add(1, Ordering::Relaxed);
+            let channel = &state.config.revalidate_channel;
+            if !channel.is_empty() && entry.access.announce_stale() {
+                relay::publish_in_background(state, channel, Bytes::copy_from_slice(key.as_bytes()));
+            }
+            Ok(Some(Response::Stale(value)))
+        }
+        Some(entry) => Ok(Some(Response::Data(entry_value(entry)?))),
+        None => Ok(None),
+    }
+}
+
+// Process a command that may follow ASKING, which lets it use a slot this
+// node is importing
+#[tracing::instrument(name = "process_command", level = "debug", skip_all, fields(command = cmd.name()))]
+pub async fn process_asking_command(
+    cmd: Command,
+    state: &Arc<RwLock<ServerState>>,
+    asking: bool,
+    user: Option<&User>,
+) -> Result<Response, ServerError> {
+    if let Some(redirect) = cluster_redirect(&cmd, state, asking)? {
+        return Ok(redirect);
+    }
+    if cmd.is_write()
+        && let Some(redirect) = replica_redirect(&cmd, state, true) {
+        return Ok(redirect);
+    }
+    if cmd.is_write() {
+        heartbeat::check_quorum(&state.read().unwrap())?;
+    }
+    if state.read().unwrap().geo.refuses(&cmd, asking) {
+        return Err(ServerError::InvalidArgument(
+            "This cluster is a geo-replication standby; GEO_PROMOTE makes it writable".to_string()
+        ));
+    }
+    match cmd {
+        cmd @ (Command::SET { .. } | Command::DEL { .. } | Command::UNLINK { .. } | Command::EXPIRE { .. } | Command::RESTORE { .. }
+            | Command::FLUSHALL | Command::COUNTER_INCRBY { .. } | Command::ORSET_ADD { .. } | Command::ORSET_REM { .. }
+            | Command::EXPIREAT { .. } | Command::PEXPIRE { .. } | Command::PEXPIREAT { .. } | Command::PERSIST { .. }
+            | Command::SOFT_EXPIRE { .. } | Command::SET_MISSING { .. } | Command::GETSET { .. }) => {
+            write_through(state, &cmd).await?;
+            let cmd = with_jitter(cmd, state.read().unwrap().config.ttl_jitter_pct);
+            write_with_quorum(state, cmd, None, user).await
+        },
+        // Whether the keys are set is only known under the write lock, so
+        // they are written through once they were
+        Command::MSETNX { entries } => {
+            let response = write_with_quorum(state, Command::MSETNX { entries: entries.clone() }, None, user).await?;
+            if matches!(response, Response::Integer(1)) {
+                write_through(state, &Command::MSETNX { entries }).await?;
+            }
+            Ok(response)
+        },
+        Command::QUORUM { replicas, command } => {
+            if let Command::GET { key } = *command {
+                return readrepair::quorum_get(state, key, replicas).await;
+            }
+            if !matches!(*command, Command::SET { .. } | Command::DEL { .. } | Command::UNLINK { .. } | Command::EXPIRE { .. } | Command::RESTORE { .. }
+                | Command::FLUSHALL | Command::COUNTER_INCRBY { .. } | Command::ORSET_ADD { .. } | Command::ORSET_REM { .. }
+                | Command::EXPIREAT { .. } | Command::PEXPIRE { .. } | Command::PEXPIREAT { .. } | Command::PERSIST { .. }
+                | Command::SOFT_EXPIRE { .. } | Command::SET_MISSING { .. } | Command::GETSET { .. }) {
+                return Err(ServerError::InvalidArgument(format!("{} can't be run with a quorum", command.name())));
+            }
+            write_through(state, &command).await?;
+            let command = with_jitter(*command, state.read().unwrap().config.ttl_jitter_pct);
+            write_with_quorum(state, command, Some(replicas), user).await
+        },
+        Command::GET { key } => {
+            let origins = {
+                let state = state.read().unwrap();
+                if let Some(value) = read_value(&state, &key)? {
+                    return Ok(value);
+                }
+                state.origins.clone()
+            };
+            read_through(state, &origins, key).await
+        },
+        Command::GET_OR_LOCK { key, lock_ms, wait_ms } => {
+            // The wait starts before the keyspace is let go, so the write
+            // releasing the lock can't slip in unnoticed
+            let written = {
+                let state = state.read().unwrap();
+                if let Some(value) = read_value(&state, &key)? {
+                    return Ok(value);
+                }
+                match state.singleflight.acquire(&key, Duration::from_millis(lock_ms)) {
+                    Ok(()) => return Ok(Response::Locked),
+                    Err(written) => written.notified_owned(),
+                }
+            };
+            let _ = tokio::time::timeout(Duration::from_millis(wait_ms), written).await;
+            let state = state.read().unwrap();
+            match read_value(&state, &key)? {
+                Some(value) => Ok(value),
+                None => {
+                    state.singleflight.wait_timeouts.fetch_add(1, Ordering::Relaxed);
+                    Ok(Response::InProgress)
+                }
+            }
+        },
+        Command::UNLOCK { key } => {
+            let state = state.write().unwrap();
+            Ok(Response::Integer(state.singleflight.release(&[&key]) as i64))
+        },
+        Command::COUNTER_GET { key } => {
+            let state = state.read().unwrap();
+            let entry = state.cache.read(&key);
+            state.stats.record_read(entry.is_some());
+            match entry.map(|entry| Crdt::of_entry(&key, entry)).transpose()? {
+                Some(Crdt::Counter(counter)) => Ok(Response::Integer(counter.value())),
+                Some(other) => Err(ServerError::WrongType(format!("{} holds a {}, not a counter", key, other.name()))),
+                None => Ok(Response::Integer(0)),
+            }
+        },
+        Command::ORSET_MEMBERS { key } => {
+            let state = state.read().unwrap();
+            let entry = state.cache.read(&key);
+            state.stats.record_read(entry.is_some());
+            match entry.map(|entry| Crdt::of_entry(&key, entry)).transpose()? {
+                Some(Crdt::Set(set)) => Ok(Response::Members(set.members())),
+                Some(other) => Err(ServerError::WrongType(format!("{} holds a {}, not a set", key, other.name()))),
+                None => Ok(Response::Members(Vec::new())),
+            }
+        },
+        Command::DBSIZE => Ok(Response::Integer(state.read().unwrap().cache.len() as i64)),
+        Command::KEYS { pattern, .. } => {
+            let keys = state.read().unwrap().cache.keys_matching_unless(&pattern, deadline::expired);
+            keys.map(Response::Keys).ok_or_else(deadline::exceeded)
+        },
+        cmd @ (Command::CLUSTER_DBSIZE | Command::CLUSTER_FLUSHALL | Command::CLUSTER_INFO { .. }
+            | Command::CLUSTER_KEYS { .. }) => fanout::fan_out(state, cmd).await,
+        Command::EXISTS { key } => {
+            let state = state.read().unwrap();
+            Ok(Response::Exists(state.cache.read(&key).is_some()))
+        },
+        Command::EXISTS_MANY { keys } => {
+            let state = state.read().unwrap();
+            Ok(Response::Integer(keys.iter().filter(|key| state.cache.read(key).is_some()).count() as i64))
+        },
+        Command::CLUSTER_JOIN { address } => {
+            // Check if clustering is enabled first
+            {
+                let state_read = state.read().unwrap();
+                if !state_read.cluster_enabled {
+                    return Err(ServerError::KeyNotFound("Clustering is disabled".to_string()));
+                }
+            } // Lock is dropped here
+            
+            // First, contact the new node to get its real node ID
+            let credentials = state.read().unwrap().config.peer_credentials();
+            let (node_id, actual_address, weight, hashing) = get_node_info(&address, &credentials).await
+                .map_err(|e| ServerError::KeyNotFound(format!("Failed to contact new node {}: {}", address, e)))?;
+            let cluster_id = {
+                let state = state.read().unwrap();
+                // Members assigning slots differently would redirect keys back and forth
+                if hashing != state.cluster.hashing {
+                    return Err(ServerError::InvalidArgument(format!(
+                        "{} uses {:?} hashing, this cluster {:?}", actual_address, hashing, state.cluster.hashing
+                    )));
+                }
+                if state.cluster.nodes.contains(&actual_address) {
+                    return Ok(Response::Success);
+                }
+                state.raft.cluster_id.clone()
+            };
+            // The new node has to agree to take our metadata log before the
+            // leader starts sending it
+            raft::invite(&actual_address, &credentials, cluster_id).await?;
+            raft::propose(state, MetaCommand::AddNode { address: actual_address.clone(), node_id, weight, rebalance: false }, true).await?;
+            // Its slots follow once their keys are copied; progress is in INFO cluster
+            handoff::start(state, actual_address)?;
+            Ok(Response::Success)
+        },
+        Command::CLUSTER_REMOVE { address } => {
+            // Check if clustering is enabled first
+            {
+                let state_read = state.read().unwrap();
+                if !state_read.cluster_enabled {
+                    return Err(ServerError::KeyNotFound("Clustering is disabled".to_string()));
+                }
+                if !state_read.raft.members().contains(&address) {
+                    return Err(ServerError::KeyNotFound("Node not found in cluster".to_string()));
+                }
+            } // Lock is dropped here
+            
+            raft::propose(state, MetaCommand::RemoveNode { address: address.clone() }, true).await?;
+
+            // The removed node isolates itself once it sees the entry; tell it
+            // directly too in case it is no longer sent the log
+            let target_address_clone = address.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+                
+                // Send CLUSTER_ISOLATE command to the target node
+                let isolate_cmd = serde_json::json!("CLUSTER_ISOLATE");
+                
+                match TcpStream::connect(&target_address_clone).await {
+                    Ok(mut stream) => {
+                        let data = serde_json::to_vec(&isolate_cmd).unwrap();
+                        if let Err(e) = stream.write_all(&data).await {
+                            warn!("Failed to send isolate command to {}: {}", target_address_clone, e);
+                        } else {
+                            debug!("Sent isolate command to {}", target_address_clone);
+                        }
+                    }
+                    Err(e) => {
+                        warn!("Failed to connect to {} for isolation: {}", target_address_clone, e);
+                    }
+                }
+            });
+            Ok(Response::Success)
+        },
+        Command::CLUSTER_ISOLATE => {
+            // Check if clustering is enabled first
+            let mut state = state.write().unwrap();
+            if !state.cluster_enabled {
+                return Err(ServerError::KeyNotFound("Clustering is disabled".to_string()));
+            }
+            
+            // Leave the cluster and start a new one with only this node
+            raft::isolate(&mut state);
+            debug!("Node {} isolated from cluster", state.cluster.self_addr);
+            Ok(Response::Success)
+        },
+        Command::CLUSTER_RESET { mode } => {
+            let hard = mode == Some(ResetMode::HARD);
+            let mut state = state.write().unwrap();
+            if !state.cluster_enabled {
+                return Err(ServerError::KeyNotFound("Clustering is disabled".to_string()));
+            }
+            // Slots moving out stop moving; their keys stay here
+            state.migration.abort();
+            raft::reset(&mut state, hard);
+            if hard {
+                // Replicas drop their keys too; other clusters keep theirs
+                stamped_write(&mut state, Command::FLUSHALL, None, true)?;
+            }
+            warn!("Node {} reset its cluster state{}", state.cluster.self_addr, if hard { " and dropped every key" } else { "" });
+            Ok(Response::Success)
+        },
+        Command::CLUSTER_SLOTS => {
+            let state = state.read().unwrap();
+            if !state.cluster_enabled {
+                return Err(ServerError::KeyNotFound("Clustering is disabled".to_string()));
+            }
+            Ok(Response::Slots(state.cluster.get_cluster_json()))
+        },
+        Command::INFO { section } => {
+            let state = state.read().unwrap();
+            build_info(&state, section.as_deref())
+                .map(Response::Info)
+                .map_err(ServerError::InvalidArgument)
+        },
+        Command::CONFIG_GET { key } => {
+            let state = state.read().unwrap();
+            let table = toml::Table::try_from(&state.config)
+                .map_err(|e| ServerError::InvalidArgument(e.to_string()))?;
+            // "*" returns every setting, otherwise the named one
+            let values: BTreeMap<String, String> = table.into_iter()
+                .filter(|(name, _)| key == "*" || *name == key)
+                .map(|(name, value)| match name.as_str() {
+                    // Never hand out the password itself
+                    "requirepass" if !state.config.requirepass.is_empty() => (name, "\"<redacted>\"".to_string()),
+                    _ => (name, value.to_string()),
+                })
+                .collect();
+            if values.is_empty() {
+                return Err(ServerError::InvalidArgument(format!("Unknown config key: {}", key)));
+            }
+            Ok(Response::Config(values))
+        },
+        Command::VERSION => {
+            Ok(Response::Info(version::build_lines().into_iter().map(|(key, value)| format!("{}:{}\r\n", key, value)).collect()))
+        },
+        Command::PERSISTENCE_STATUS => Ok(Response::Info(persistence_status(&state.read().unwrap()))),
+        Command::COMMAND => Ok(Response::Commands(COMMANDS.iter().map(CommandSpec::info).collect())),
+        Command::COMMAND_DOCS { names } if names.is_empty() => {
+            Ok(Response::CommandDocs(COMMANDS.iter().map(CommandSpec::doc).collect()))
+        },
+        Command::COMMAND_DOCS { names } => {
+            Ok(Response::CommandDocs(names.iter().filter_map(|name| commands::spec(name)).map(CommandSpec::doc).collect()))
+        },
+        Command::PUBLISH { channel, message } => {
+            Ok(Response::Integer(relay::publish(state, channel, Bytes::from(message)).await? as i64))
+        },
+        Command::SUBSCRIBE { .. } | Command::UNSUBSCRIBE { .. } | Command::KEYEVENTS_SUBSCRIBE { .. }
+            | Command::KEYEVENTS_UNSUBSCRIBE { .. } => {
+            Err(ServerError::InvalidArgument("Subscriptions need a streaming connection".to_string()))
+        },
+        Command::ENCODING { .. } | Command::HELLO { encoding: Some(_), .. } => {
+            Err(ServerError::InvalidArgument("Encodings can only be changed on a streaming connection".to_string()))
+        },
+        Command::HELLO { protocol, encoding: None } => {
+            hello(protocol, auth_required(&state.read().unwrap()))
+        },
+        Command::AUTH { .. } | Command::SHUTDOWN { .. } | Command::READONLY | Command::READWRITE
+            | Command::CLIENT_TRACKING { .. }
+            | Command::SYNC { .. } | Command::REPLACK { .. } | Command::MERKLE { .. } => {
+            Err(ServerError::InvalidArgument("This command needs a streaming connection".to_string()))
+        },
+        Command::MEMORY_USAGE { key } => {
+            let state = state.read().unwrap();
+            match state.cache.get(&key) {
+                Some(entry) => Ok(Response::Integer(entry_memory(&key, entry) as i64)),
+                None => Err(ServerError::KeyNotFound(key)),
+            }
+        },
+        Command::OBJECT_IDLETIME { key } => {
+            let state = state.read().unwrap();
+            match state.cache.get(&key) {
+                Some(entry) => Ok(Response::Integer((entry.access.idle_ms(now_ms()) / 1000) as i64)),
+                None => Err(ServerError::KeyNotFound(key)),
+            }
+        },
+        Command::OBJECT_FREQ { key } => {
+            let state = state.read().unwrap();
+            match state.cache.get(&key) {
+                Some(entry) => Ok(Response::Integer(entry.access.frequency(now_ms()) as i64)),
+                None => Err(ServerError::KeyNotFound(key)),
+            }
+        },
+        Command::DEBUG_OBJECT { key } => {
+            let state = state.read().unwrap();
+            let Some(entry) = state.cache.get(&key) else {
+                return Err(ServerError::KeyNotFound(key));
+            };
+            let codec = if entry.tombstone {
+                "missing"
+            } else if entry.crdt {
+                "crdt"
+            } else if entry.compressed {
+                "zstd"
+            } else {
+                "raw"
+            };
+            Ok(Response::Object(ObjectInfo {
+                created_at: entry.access.created_at(),
+                modified_at: entry.stamp.at,
+                accessed_at: entry.access.accessed_at(),
+                accesses: entry.access.count(),
+                version: entry.stamp.version,
+                stored_bytes: entry.data.len(),
+                memory: entry_memory(&key, entry),
+                codec: codec.to_string(),
+                inline: entry.data.is_inline(),
+                visible_at: entry.visible_at,
+            }))
+        },
+        Command::TTL { key } => {
+            let state = state.read().unwrap();
+            let ttl = match state.cache.get(&key) {
+                None => -2,
+                Some(CacheEntry { expires_at: None, .. }) => -1,
+                // Round up so a key with time left never reports 0
+                Some(CacheEntry { expires_at: Some(at), .. }) => at.saturating_sub(now_ms()).div_ceil(1000) as i64,
+            };
+            Ok(Response::Integer(ttl))
+        },
+        Command::SOFT_TTL { key } => {
+            let state = state.read().unwrap();
+            let ttl = match state.cache.get(&key) {
+                None => -2,
+                Some(CacheEntry { stale_at: None, .. }) => -1,
+                Some(CacheEntry { stale_at: Some(at), .. }) => at.saturating_sub(now_ms()).div_ceil(1000) as i64,
+            };
+            Ok(Response::Integer(ttl))
+        },
+        Command::PTTL { key } => {
+            let state = state.read().unwrap();
+            let ttl = match state.cache.get(&key) {
+                None => -2,
+                Some(CacheEntry { expires_at: None, .. }) => -1,
+                Some(CacheEntry { expires_at: Some(at), .. }) => at.saturating_sub(now_ms()) as i64,
+            };
+            Ok(Response::Integer(ttl))
+        },
+        Command::DUMP { key } => {
+            let state = state.read().unwrap();
+            match state.cache.get(&key) {
+                Some(entry) => Ok(Response::Data(dump_entry(entry, now_ms()))),
+                None => Err(ServerError::KeyNotFound(key)),
+            }
+        },
+        Command::WAIT { replicas, timeout } => {
+            let replication = state.read().unwrap().replication.clone();
+            if replication.is_replica() {
+                return Err(ServerError::InvalidArgument("WAIT cannot be used on a replica".to_string()));
+            }
+            let timeout = (timeout > 0).then(|| std::time::Duration::from_millis(timeout));
+            let acked = replication.wait_for_acks(replication.offset(), replicas, timeout).await;
+            Ok(Response::Integer(acked as i64))
+        },
+        Command::DIGEST { slot } => {
+            if slot >= TOTAL_SLOTS {
+                return Err(ServerError::InvalidArgument(format!("Slot {} out of range", slot)));
+            }
+            let state = state.read().unwrap();
+            Ok(Response::KeyDigests(slot_digests(&state.cache, slot)))
+        },
+        Command::REPAIR { keys } => {
+            let state = state.write().unwrap();
+            if state.replication.is_replica() {
+                return Err(ServerError::InvalidArgument("REPAIR is sent to a primary".to_string()));
+            }
+            // Resent under the write lock, in order with every other write
+            antientropy::resend(&state, &keys);
+            Ok(Response::Integer(keys.len() as i64))
+        },
+        Command::MIGRATE { key, address, copy, replace } => migrate::migrate(state, key, address, copy, replace).await,
+        Command::MIGRATE_SLOTS { slots, address } => {
+            migrate::migrate_slots(state, slots, address)?;
+            Ok(Response::Success)
+        },
+        Command::MIGRATE_SLOTS_ABORT => {
+            if state.read().unwrap().migration.abort() {
+                Ok(Response::Success)
+            } else {
+                Err(ServerError::InvalidArgument("No slot migration is running".to_string()))
+            }
+        },
+        Command::MEMORY_DEFRAG => {
+            if defrag::start(state) {
+                Ok(Response::Success)
+            } else {
+                Err(ServerError::InvalidArgument("Defragmentation is already running".to_string()))
+            }
+        },
+        Command::PING { message: Some(message) } | Command::ECHO { message } => {
+            Ok(Response::Data(Bytes::from(message)))
+        },
+        Command::PING { message: None } => Ok(Response::Pong),
+        // Only a streaming connection remembers ASKING for its next command
+        Command::ASKING => Ok(Response::Success),
+        Command::CLUSTER_SETSLOT { slots, state: slot_state } => cluster_setslot(state, slots, slot_state).await,
+        Command::GEO_APPLY { source, batch, forwarded } => geo::apply(state, source, batch, forwarded).await,
+        Command::GEO_PROMOTE { local } => geo::promote(state, local).await,
+        Command::NODE_INFO => {
+            let state = state.read().unwrap();
+            let our_address = state.cluster.self_addr.clone();
+            let our_node_id = state.cluster.node_ids
+                .get(&our_address)
+                .cloned()
+                .unwrap_or_else(|| "unknown".to_string());
+            Ok(Response::NodeInfo { 
+                node_id: our_node_id, 
+                address: our_address,
+                weight: state.cluster.self_weight,
+                hashing: state.cluster.hashing,
+            })
+        },
+
+    }
+}
+
+// Run a command from HTTP or gRPC through the middleware
+pub async fn execute_command(
+    cmd: Command,
+    state: &Arc<RwLock<ServerState>>,
+    caller: Caller,
+) -> Result<Response, ServerError> {
+    let (middleware, timeout) = {
+        let state = state.read().unwrap();
+        (state.middleware.clone(), Duration::from_millis(state.config.command_timeout_ms))
+    };
+    let timeout = if deadline::applies_to(&cmd) { timeout } else { Duration::ZERO };
+    middleware.run(cmd, &caller, state, |cmd| async {
+        deadline::run(timeout, process_command(caller.enter(cmd), state, caller.user.as_deref())).await.and_then(|result| result)
+            .map(|response| caller.leave(response))
+            .map_err(|e| caller.leave_error(e))
+    }).await
+}
+
+// Run a command on a streaming connection (native protocol or WebSocket),
+// handling the commands that act on the connection itself
+pub async fn execute_session_command(
+    cmd: Command,
+    state: &Arc<RwLock<ServerState>>,
+    session: &mut Session,
+) -> Result<Response, ServerError> {
+    let (middleware, caller) = (session.middleware.clone(), session.caller.clone());
+    let timeout = if deadline::applies_to(&cmd) { session.command_timeout } else { Duration::ZERO };
+    let outer = &caller;
+    middleware.run(cmd, &caller, state, |cmd| async move {
+        deadline::run(timeout, run_session_command(outer.enter(cmd), state, session)).await.and_then(|result| result)
+            .map(|response| outer.leave(response))
+            .map_err(|e| outer.leave_error(e))
+    }).await
+}
+
+async fn run_session_command(
+    cmd: Command,
+    state: &Arc<RwLock<ServerState>>,
+    session: &mut Session,
+) -> Result<Response, ServerError> {
+    // ASKING only applies to the command right after it
+    let asking = std::mem::take(&mut session.asking);
+    match cmd {
+        Command::SUBSCRIBE { channels } => Ok(Response::Integer(session.subscriber.subscribe(channels) as i64)),
+        Command::UNSUBSCRIBE { channels } => Ok(Response::Integer(session.subscriber.unsubscribe(channels) as i64)),
+        Command::KEYEVENTS_SUBSCRIBE { patterns } => Ok(Response::Integer(session.subscriber.watch(patterns) as i64)),
+        Command::KEYEVENTS_UNSUBSCRIBE { patterns } => Ok(Response::Integer(session.subscriber.unwatch(patterns) as i64)),
+        Command::ENCODING { name } => {
+            session.encoding = name;
+            Ok(Response::Success)
+        },
+        Command::HELLO { protocol, encoding } => {
+            let auth_required = auth_required(&state.read().unwrap());
+            let response = hello(protocol, auth_required)?;
+            if let Some(encoding) = encoding {
+                session.encoding = encoding;
+            }
+            Ok(response)
+        },
+        Command::AUTH { username, password } => {
+            let user = authenticate(&state.read().unwrap(), username.as_deref(), &password)?;
+            session.caller.authenticated = true;
+            session.caller.user = user;
+            Ok(Response::Success)
+        },
+        Command::SHUTDOWN { mode } => {
+            let state = state.read().unwrap();
+            warn!("SHUTDOWN requested by a client");
+            state.shutdown.trigger_with(mode);
+            Ok(Response::Success)
+        },
+        Command::ASKING => {
+            session.asking = true;
+            Ok(Response::Success)
+        },
+        Command::READONLY => {
+            session.readonly = true;
+            Ok(Response::Success)
+        },
+        Command::READWRITE => {
+            session.readonly = false;
+            Ok(Response::Success)
+        },
+        Command::CLIENT_TRACKING { on } => {
+            if !on {
+                session.tracker = None;
+            } else if session.tracker.is_none() {
+                session.tracker = Some(Tracker::start(state.read().unwrap().tracking.clone()));
+            }
+            Ok(Response::Success)
+        },
+        Command::SYNC { address } => {
+            let stream = ReplicaStream::start(&state.read().unwrap(), address);
+            let offset = stream.offset();
+            session.replica = Some(stream);
+            Ok(Response::Integer(offset as i64))
+        },
+        Command::MERKLE { level, nodes } => {
+            if level == 0 || session.merkle.is_none() {
+                session.merkle = Some(MerkleTree::build(&state.read().unwrap().cache));
+            }
+            let tree = session.merkle.as_ref().expect("built above");
+            Ok(Response::Digests(nodes.into_iter().map(|index| tree.hash(level, index)).collect()))
+        },
+        Command::REPLACK { offset } => match &session.replica {
+            Some(stream) => {
+                stream.ack(offset);
+                Ok(Response::Integer(state.read().unwrap().replication.offset() as i64))
+            }
+            None => Err(ServerError::InvalidArgument("REPLACK is only sent by replicas after SYNC".to_string())),
+        },
+        cmd => {
+            if let Some(redirect) = replica_redirect(&cmd, state, session.readonly) {
+                return Ok(redirect);
+            }
+            // Keys read are remembered for tracking, missing ones included,
+            // as a client may cache that they are missing
+            if let Some(tracker) = &session.tracker
+                && !cmd.is_write() {
+                tracker.remember(cmd.keys().into_iter().map(String::from).collect());
+            }
+            process_asking_command(cmd, state, asking, session.caller.user.as_deref()).await
+        },
+    }
+}
+
+// The socket under a client connection: tokio's, or one driven by io_uring
+pub(crate) trait Connection {
+    // Read more of the client's input into `buf`, 0 once it hung up. Nothing
+    // is lost when the future is dropped before it resolves.
+    async fn read_buf(&mut self, buf: &mut BytesMut) -> std::io::Result<usize>;
+
+    // Write every queued response
+    async fn write_batch(&mut self, batch: &mut ResponseBatch) -> std::io::Result<()>;
+
+    // Resolves once the connection was reset or failed. An EOF isn't enough:
+    // a client that shut down its write side still reads the replies.
+    async fn closed(&mut self);
+}
+
+impl Connection for TcpStream {
+    async fn read_buf(&mut self, buf: &mut BytesMut) -> std::io::Result<usize> {
+        AsyncReadExt::read_buf(self, buf).await
+    }
+
+    async fn write_batch(&mut self, batch: &mut ResponseBatch) -> std::io::Result<()> {
+        batch.write_to(self).await
+    }
+
+    async fn closed(&mut self) {
+        // A reset raises the socket's error, whatever input is still unread
+        let _ = self.ready(Interest::ERROR).await;
+    }
+}
+
+// Write the queued responses, failing once the client's output buffer goes
+// over its limits, as when the client stops reading
+async fn flush<C: Connection>(batch: &mut ResponseBatch, conn: &mut C, output: &OutputBuffer) -> std::io::Result<()> {
+    let queued = batch.len();
+    if !output.add(queued) {
+        return Err(std::io::Error::other("output buffer limit reached"));
+    }
+    let result = tokio::select! {
+        result = conn.write_batch(batch) => result,
+        _ = output.overflowed() => Err(std::io::Error::other("output buffer limit reached")),
+    };
+    output.remove(queued);
+    result
+}
+
+// Wait before watching for a hang-up, so quick commands don't pay for it
+const HANG_UP_CHECK_AFTER: Duration = Duration::from_millis(50);
+
+// Resolves once the connection to the client on the other end of `conn` broke
+async fn hung_up<C: Connection>(conn: &mut C) {
+    tokio::time::sleep(HANG_UP_CHECK_AFTER).await;
+    conn.closed().await
+}
+
+// Handle a client connection
+pub async fn handle_client(
+    socket: TcpStream, 
+    state: Arc<RwLock<ServerState>>,
+    kind: ListenerKind,
+) {
+    let peer_addr = match socket.peer_addr() {
+        Ok(addr) => addr,
+        Err(e) => {
+            error!("Failed to get client address: {}", e);
+            return;
+        }
+    };
+    serve_connection(socket, peer_addr, state, kind).await
+}
+
+// Serve the commands of a client until it hangs up or is disconnected
+#[tracing::instrument(name = "connection", level = "debug", skip_all, fields(kind = ?kind, peer = %peer_addr))]
+pub(crate) async fn serve_connection<C: Connection>(
+    mut conn: C,
+    peer_addr: SocketAddr,
+    state: Arc<RwLock<ServerState>>,
+    kind: ListenerKind,
+) {
+    let (pool, clients, shutdown, stats, mut session, max_inflight_commands, max_inflight_bytes, max_command_bytes, limits, refused) = {
+        let state = state.read().unwrap();
+        (
+            state.buffer_pool.clone(),
+            state.clients.clone(),
+            state.shutdown.clone(),
+            state.stats.clone(),
+            Session::new(&state, match kind {
+                ListenerKind::Data => Transport::Native,
+                ListenerKind::Admin => Transport::Admin,
+            }, Some(peer_addr)),
+            state.config.max_inflight_commands.max(1),
+            state.config.max_inflight_bytes,
+            state.config.max_command_bytes,
+            match kind {
+                ListenerKind::Data => ConnectionLimits {
+                    max_clients: state.config.maxclients,
+                    max_clients_per_ip: state.config.max_clients_per_ip,
+                    max_commands_per_sec_per_ip: state.config.max_commands_per_sec_per_ip,
+                },
+                // Operators must be able to reach a node that is out of client slots
+                ListenerKind::Admin => ConnectionLimits {
+                    max_clients: usize::MAX,
+                    max_clients_per_ip: 0,
+                    max_commands_per_sec_per_ip: 0,
+                },
+            },
+            network::protected_refuses(&state.config, peer_addr),
+        )
+    };
+    let mut batch = ResponseBatch::new();
+    if refused {
+        warn!("Refusing connection from {}: protected mode", peer_addr);
+        batch.push(&Response::Error(ErrorReply::new(ErrorCode::Denied, PROTECTED_MODE_REFUSAL)), Encoding::Json).ok();
+        let _ = conn.write_batch(&mut batch).await;
+        return;
+    }
+    let client = match clients.register(peer_addr, &limits) {
+        Ok(client) => client,
+        Err(reason) => {
+            warn!("Rejecting connection from {}: {}", peer_addr, reason);
+            batch.push(&Response::Error(ErrorReply::new(ErrorCode::Denied, reason.to_string())), Encoding::Json).ok();
+            let _ = conn.write_batch(&mut batch).await;
+            return;
+        }
+    };
+    let output = session.output.clone();
+    let mut buf = BytesMut::with_capacity(READ_BUFFER_SIZE);
+    'connection: loop {
+        pool.reserve(&mut buf);
+        let read = tokio::select! {
+            read = conn.read_buf(&mut buf) => read,
+            Some(push) = session.subscriber.recv() => {
+                // Forward a published message to this subscriber
+                batch.push(&push.into_response(), session.encoding).ok();
+                if let Err(e) = flush(&mut batch, &mut conn, &output).await {
+                    error!("Failed to write message: {}", e);
+                    break;
+                }
+                continue;
+            }
+            Some(keys) = tracking::next_invalidation(&mut session.tracker) => {
+                // Tell a tracking client that keys it read changed
+                batch.push(&session.caller.leave(Response::Invalidate { keys }), session.encoding).ok();
+                if let Err(e) = flush(&mut batch, &mut conn, &output).await {
+                    error!("Failed to write invalidation: {}", e);
+                    break;
+                }
+                continue;
+            }
+            push = replication::next_push(&mut session.replica) => {
+                // Forward a write to the replica on the other end; a replica
+                // that fell behind reconnects and syncs again
+                let Some(push) = push else {
+                    break;
+                };
+                batch.push(&push, session.encoding).ok();
+                if let Err(e) = flush(&mut batch, &mut conn, &output).await {
+                    error!("Failed to write to replica: {}", e);
+                    break;
+                }
+                continue;
+            }
+            _ = client.killed() => {
+                debug!("Closing connection {} from {}", client.id, client.addr);
+                break;
+            }
+            _ = output.overflowed() => break,
+            // Commands already read have been answered by the time we get here
+            _ = shutdown.wait() => {
+                debug!("Closing connection {} from {} for shutdown", client.id, client.addr);
+                break;
+            }
+        };
+        match read {
+            Ok(0) => {
+                // Connection was closed
+                debug!("Client disconnected");
+                break;
+            }
+            Ok(n) => {
+                debug!("Read {n} bytes from client");
+                client.touch();
+                // Work through the pipelined commands in rounds of at most
+                // `max_inflight_commands`. Nothing more is read until each
+                // round's responses are written, so a client that stops
+                // reading its responses stops being served.
+                loop {
+                    let parsed = parse_commands(&buf, max_inflight_commands, session.encoding);
+                    buf.advance(parsed.consumed);
+                    if parsed.commands.is_empty() && parsed.error.is_none() {
+                        break;
+                    }
+                    for cmd in parsed.commands {
+                        debug!("Received command: {:?}", cmd);
+                        let span = tracing::debug_span!(
+                            "command",
+                            command = cmd.name(),
+                            keys = cmd.key_count(),
+                            bytes = tracing::field::Empty,
+                            duration_us = tracing::field::Empty,
+                        );
+                        let started = Instant::now();
+                        // A response goes out in the encoding its command arrived in
+                        let encoding = session.encoding;
+                        // Keys per frame when the reply is streamed; 0 sends it whole
+                        let chunk = cmd.chunk().unwrap_or(0);
+                        let response = if !client.allow_command() {
+                            Response::Error(ErrorReply::new(ErrorCode::RateLimited, "rate limit exceeded"))
+                        } else {
+                            let result = if !deadline::applies_to(&cmd) {
+                                execute_session_command(cmd, &state, &mut session).instrument(span.clone()).await
+                            } else {
+                                // A read whose client hung up is dropped rather than finished
+                                tokio::select! {
+                                    result = execute_session_command(cmd, &state, &mut session).instrument(span.clone()) => result,
+                                    _ = hung_up(&mut conn) => {
+                                        debug!("Dropping a command of connection {}, whose client hung up", client.id);
+                                        stats.cancelled_commands.fetch_add(1, Ordering::Relaxed);
+                                        break 'connection;
+                                    }
+                                }
+                            };
+                            match result {
+                                Ok(resp) => resp,
+                                Err(e) => Response::Error(e.into()),
+                            }
+                        };
+                        let mut bytes = 0;
+                        for frame in response.into_frames(chunk) {
+                            let queued = batch.len();
+                            if let Err(e) = batch.push(&frame, encoding) {
+                                error!("Failed to serialize response: {}", e);
+                                batch.push(&Response::Error(ErrorReply::new(ErrorCode::Err, e.to_string())), encoding).ok();
+                            }
+                            bytes += batch.len() - queued;
+                            // Drain queued responses before they grow past the byte
+                            // limit, which a streamed reply does between its frames
+                            if batch.len() >= max_inflight_bytes
+                                && let Err(e) = flush(&mut batch, &mut conn, &output).await {
+                                error!("Failed to write response: {}", e);
+                                break 'connection;
+                            }
+                        }
+                        span.record("bytes", bytes);
+                        span.record("duration_us", started.elapsed().as_micros() as u64);
+                    }
+                    if let Some(e) = parsed.error {
+                        error!("Failed to parse command: {}", e);
+                        // Send error response and drop the unparseable input
+                        batch.push(&Response::Error(ErrorReply::new(ErrorCode::Syntax, "Invalid command").with_details(e)), session.encoding).ok();
+                        buf.clear();
+                    }
+                    // Write all queued responses with as few syscalls as possible
+                    if let Err(e) = flush(&mut batch, &mut conn, &output).await {
+                        error!("Failed to write response: {}", e);
+                        break 'connection;
+                    }
+                }
+                client.set_listening(session.subscriber.is_subscribed() || session.tracker.is_some() || session.replica.is_some());
+                if buf.is_empty() {
+                    // Reset the buffer for the next command
+                    pool.recycle(&mut buf);
+                } else if buf.len() > max_command_bytes {
+                    // What is left is one command still arriving; one this
+                    // large is refused rather than buffered without end
+                    warn!("Closing connection {} from {}: command over {} bytes", client.id, client.addr, max_command_bytes);
+                    let reply = ErrorReply::new(ErrorCode::Invalid, format!("command exceeds max_command_bytes ({})", max_command_bytes));
+                    batch.push(&Response::Error(reply), session.encoding).ok();
+                    let _ = flush(&mut batch, &mut conn, &output).await;
+                    break;
+                }
+            }
+            Err(e) => {
+                error!("Failed to read from socket: {}", e);
+                break;
+            }
+        }
+    }
+    if output.is_overflowed() {
+        warn!("Closed connection {} from {}: output buffer over its limit", client.id, client.addr);
+        stats.evicted_clients.fetch_add(1, Ordering::Relaxed);
+    }
+    clients.unregister(client.id);
+    // Give a scratch buffer still held by this connection back to the pool
+    pool.release(buf);
+}
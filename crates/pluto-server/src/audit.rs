@@ -0,0 +1,160 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+use log::error;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use crate::backup::{hex, hmac};
+use crate::environment::{FluxConfig, read_flux_toml};
+use crate::middleware::{Middleware, Outcome, Transport};
+
+// The audit log records who ran administrative commands (and, with
+// audit_writes, every write), when, on which keys and how it went, one JSON
+// line each. Each record carries the hash of the one before it, and its own
+// hash covers that, so a record removed or changed breaks the chain from
+// there on. With audit_key set the hashes are HMACs, which can't be
+// recomputed by someone rewriting the file without the key.
+
+// `prev` of the first record
+const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct AuditRecord {
+    seq: u64,
+    time: String, // RFC 3339, UTC
+    transport: String,
+    client: Option<String>, // address of the client, when the transport has one
+    authenticated: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    user: Option<String>, // the ACL user the client authenticated as
+    command: String,
+    keys: Vec<String>,
+    result: String, // "ok", or the error the command failed with
+    prev: String, // hash of the record before
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hash: Option<String>,
+}
+
+// Audits commands as they complete, writing records on a thread of its own
+pub struct AuditLog {
+    sender: Sender<AuditRecord>,
+    writes: bool,
+}
+
+impl AuditLog {
+    // Open the log, continuing the chain of the records already in it
+    pub fn open(config: &FluxConfig) -> io::Result<Self> {
+        let (mut seq, mut prev) = (0, GENESIS.to_string());
+        if let Ok(file) = File::open(&config.audit_file) {
+            for line in BufReader::new(file).lines() {
+                let record: AuditRecord = serde_json::from_str(&line?)
+                    .map_err(|e| io::Error::other(format!("{} holds a record that isn't one: {}", config.audit_file, e)))?;
+                seq = record.seq;
+                prev = record.hash.unwrap_or_default();
+            }
+        }
+        let mut file = OpenOptions::new().create(true).append(true).open(&config.audit_file)?;
+        let key = config.audit_key.clone().into_bytes();
+        let path = config.audit_file.clone();
+        let (sender, receiver) = mpsc::channel::<AuditRecord>();
+        thread::Builder::new().name("audit".to_string()).spawn(move || {
+            for mut record in receiver {
+                seq += 1;
+                record.seq = seq;
+                record.prev = std::mem::take(&mut prev);
+                let hash = record_hash(&record, &key);
+                record.hash = Some(hash.clone());
+                prev = hash;
+                let mut line = serde_json::to_vec(&record).expect("audit records serialize");
+                line.push(b'\n');
+                if let Err(e) = file.write_all(&line).and_then(|_| file.flush()) {
+                    error!("Failed to write audit record {} to {}: {}", seq, path, e);
+                }
+            }
+        })?;
+        Ok(AuditLog { sender, writes: config.audit_writes })
+    }
+
+    // Commands that are always audited
+    fn is_administrative(command: &str) -> bool {
+        matches!(command, "AUTH" | "CONFIG_GET" | "FLUSHALL" | "SHUTDOWN" | "GEO_PROMOTE" | "MIGRATE_SLOTS" | "MIGRATE_SLOTS_ABORT")
+            || command.starts_with("CLUSTER_")
+    }
+}
+
+impl Middleware for AuditLog {
+    fn after(&self, outcome: &Outcome) {
+        let audited = Self::is_administrative(outcome.command) || (self.writes && outcome.write);
+        if !audited {
+            return;
+        }
+        let record = AuditRecord {
+            seq: 0,
+            time: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            transport: match outcome.caller.transport {
+                Transport::Native => "native",
+                Transport::Admin => "admin",
+                Transport::WebSocket => "websocket",
+                Transport::Http => "http",
+                Transport::Grpc => "grpc",
+            }.to_string(),
+            client: outcome.caller.addr.map(|addr| addr.to_string()),
+            authenticated: outcome.caller.authenticated,
+            user: outcome.caller.user.as_ref().map(|user| user.rule.name.clone()),
+            command: outcome.command.to_string(),
+            keys: outcome.keys.to_vec(),
+            result: match outcome.result {
+                Ok(_) => "ok".to_string(),
+                Err(e) => e.to_string(),
+            },
+            prev: String::new(),
+            hash: None,
+        };
+        // Only fails once the thread is gone, which was logged when it went
+        let _ = self.sender.send(record);
+    }
+
+    fn wants_keys(&self) -> bool {
+        true
+    }
+}
+
+// Hash of a record and the hash before it, which it holds
+fn record_hash(record: &AuditRecord, key: &[u8]) -> String {
+    let unhashed = AuditRecord { hash: None, ..record.clone() };
+    let bytes = serde_json::to_vec(&unhashed).expect("audit records serialize");
+    if key.is_empty() {
+        hex(&Sha256::digest(&bytes))
+    } else {
+        hex(&hmac(key, &bytes))
+    }
+}
+
+// Check the chain of the audit log flxc.toml names, or of `file`, reporting
+// the first record that was changed, removed or added out of turn
+pub fn verify(file: Option<&str>) -> io::Result<()> {
+    let config = read_flux_toml();
+    let path = file.unwrap_or(&config.audit_file);
+    let key = config.audit_key.as_bytes();
+    let mut prev = GENESIS.to_string();
+    let mut seq = 0;
+    for (number, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let broken = |reason: String| io::Error::other(format!("{} line {}: {}", path, number + 1, reason));
+        let record: AuditRecord = serde_json::from_str(&line?).map_err(|e| broken(e.to_string()))?;
+        if record.seq != seq + 1 {
+            return Err(broken(format!("record {} follows record {}", record.seq, seq)));
+        }
+        if record.prev != prev {
+            return Err(broken(format!("record {} doesn't follow the record before it", record.seq)));
+        }
+        let hash = record_hash(&record, key);
+        if record.hash.as_deref() != Some(hash.as_str()) {
+            return Err(broken(format!("record {} was changed", record.seq)));
+        }
+        seq = record.seq;
+        prev = hash;
+    }
+    println!("{}: {} records, chain intact", path, seq);
+    Ok(())
+}
@@ -0,0 +1,572 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use hmac::{Hmac, Mac};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use pluto_core::cache::{ServerError, now_ms};
+use pluto_core::wal::{list_segments, segment_first};
+use crate::environment::{FluxConfig, read_flux_toml};
+use crate::{storage, wal};
+use crate::shutdown::PendingSave;
+use crate::state::ServerState;
+
+// Snapshots, and the write-ahead log segments after them, are uploaded to an
+// S3-compatible bucket under the prefix of `backup_url`:
+//
+//   <prefix>/snapshots/<unix ms>.snapshot   every snapshot uploaded
+//   <prefix>/snapshots/<unix ms>.json       the log position of each
+//   <prefix>/snapshots/latest.json          which one is newest, and its log position
+//   <prefix>/wal/<segment>                  every full log segment
+//
+// `flux-cache restore --from s3://bucket/prefix` downloads the newest snapshot
+// and the segments after it, which the next start replays. With `--at`, it
+// takes the newest snapshot uploaded before that time, and the replay stops there.
+
+// Longest one request to the bucket may take
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(300);
+
+const LATEST: &str = "snapshots/latest.json";
+
+// A snapshot uploaded and where the log picks up after it
+#[derive(Serialize, Deserialize)]
+struct Uploaded {
+    snapshot: String,
+    wal_position: u64,
+}
+
+// Uploads so far, for INFO backup
+#[derive(Default)]
+pub struct Backup {
+    pub uploads: AtomicU64,
+    pub failures: AtomicU64,
+    pub last_at: AtomicU64, // unix time in milliseconds of the last snapshot uploaded, 0 before
+    pub last_bytes: AtomicU64,
+    pub last_error: Mutex<String>, // of the last backup, empty when it succeeded
+    running: tokio::sync::Mutex<()>, // held while a snapshot is saved and uploaded
+}
+
+impl Backup {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn failed(&self, e: &ServerError) {
+        self.failures.fetch_add(1, Ordering::Relaxed);
+        *self.last_error.lock().unwrap() = e.to_string();
+    }
+}
+
+// An S3 bucket and the prefix backups go under, with the credentials to sign
+// requests with (AWS signature version 4)
+pub struct ObjectStore {
+    client: reqwest::Client,
+    endpoint: reqwest::Url,
+    bucket: String,
+    prefix: String,
+    region: String,
+    access_key: String,
+    secret_key: String,
+    session_token: Option<String>,
+}
+
+impl ObjectStore {
+    // Credentials come from the config, else from the usual AWS variables
+    pub fn new(config: &FluxConfig, url: &str) -> Result<Self, ServerError> {
+        let Some((bucket, prefix)) = url.strip_prefix("s3://").map(|rest| rest.split_once('/').unwrap_or((rest, ""))) else {
+            return Err(ServerError::InvalidArgument(format!("{} is not an s3:// URL", url)));
+        };
+        if bucket.is_empty() {
+            return Err(ServerError::InvalidArgument(format!("{} names no bucket", url)));
+        }
+        let endpoint = if config.backup_endpoint.is_empty() {
+            format!("https://s3.{}.amazonaws.com", config.backup_region)
+        } else {
+            config.backup_endpoint.clone()
+        };
+        let endpoint = reqwest::Url::parse(&endpoint)
+            .map_err(|e| ServerError::InvalidArgument(format!("Invalid backup_endpoint {}: {}", endpoint, e)))?;
+        let from_env = |value: &str, var: &str| {
+            if value.is_empty() { std::env::var(var).unwrap_or_default() } else { value.to_string() }
+        };
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .map_err(|e| ServerError::InvalidArgument(e.to_string()))?;
+        Ok(ObjectStore {
+            client,
+            endpoint,
+            bucket: bucket.to_string(),
+            prefix: prefix.trim_matches('/').to_string(),
+            region: config.backup_region.clone(),
+            access_key: from_env(&config.backup_access_key, "AWS_ACCESS_KEY_ID"),
+            secret_key: from_env(&config.backup_secret_key, "AWS_SECRET_ACCESS_KEY"),
+            session_token: std::env::var("AWS_SESSION_TOKEN").ok().filter(|token| !token.is_empty()),
+        })
+    }
+
+    fn key(&self, name: &str) -> String {
+        if self.prefix.is_empty() { name.to_string() } else { format!("{}/{}", self.prefix, name) }
+    }
+
+    pub async fn put(&self, name: &str, body: Vec<u8>) -> Result<(), ServerError> {
+        self.send(reqwest::Method::PUT, &self.key(name), &[], body).await?;
+        Ok(())
+    }
+
+    // An object's content, or None when it doesn't exist
+    pub async fn get(&self, name: &str) -> Result<Option<Vec<u8>>, ServerError> {
+        match self.send(reqwest::Method::GET, &self.key(name), &[], Vec::new()).await {
+            Ok(body) => Ok(Some(body)),
+            Err(ServerError::KeyNotFound(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Names of the objects under `dir`, relative to it
+    pub async fn list(&self, dir: &str) -> Result<Vec<String>, ServerError> {
+        let prefix = format!("{}/", self.key(dir));
+        let mut names = Vec::new();
+        let mut token: Option<String> = None;
+        loop {
+            let mut query = vec![("list-type", "2".to_string()), ("prefix", prefix.clone())];
+            if let Some(token) = &token {
+                query.push(("continuation-token", token.clone()));
+            }
+            let body = self.send(reqwest::Method::GET, "", &query, Vec::new()).await?;
+            let xml = String::from_utf8_lossy(&body);
+            names.extend(xml_values(&xml, "Key").into_iter().filter_map(|key| key.strip_prefix(&prefix)).map(str::to_string));
+            token = xml_values(&xml, "NextContinuationToken").first().map(|token| token.to_string());
+            if xml_values(&xml, "IsTruncated").first() != Some(&"true") || token.is_none() {
+                return Ok(names);
+            }
+        }
+    }
+
+    async fn send(
+        &self,
+        method: reqwest::Method,
+        key: &str,
+        query: &[(&str, String)],
+        body: Vec<u8>,
+    ) -> Result<Vec<u8>, ServerError> {
+        let path = format!("/{}/{}", uri_encode(&self.bucket, true), uri_encode(key, false));
+        let mut query: Vec<(String, String)> = query.iter().map(|(name, value)| (uri_encode(name, true), uri_encode(value, true))).collect();
+        query.sort();
+        let query = query.iter().map(|(name, value)| format!("{}={}", name, value)).collect::<Vec<_>>().join("&");
+        let mut url = self.endpoint.clone();
+        url.set_path(&path);
+        url.set_query((!query.is_empty()).then_some(query.as_str()));
+
+        let host = match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_string(),
+            (None, _) => return Err(ServerError::InvalidArgument(format!("backup_endpoint {} has no host", self.endpoint))),
+        };
+        let now = chrono::Utc::now();
+        let date = now.format("%Y%m%d").to_string();
+        let timestamp = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let payload_hash = hex(&Sha256::digest(&body));
+        let mut headers = vec![
+            ("host", host),
+            ("x-amz-content-sha256", payload_hash.clone()),
+            ("x-amz-date", timestamp.clone()),
+        ];
+        if let Some(token) = &self.session_token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+        let signed_headers = headers.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(";");
+        let canonical_headers: String = headers.iter().map(|(name, value)| format!("{}:{}\n", name, value.trim())).collect();
+        let canonical_request = format!(
+            "{}\n{}\n{}\n{}\n{}\n{}",
+            method, path, query, canonical_headers, signed_headers, payload_hash
+        );
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}", timestamp, scope, hex(&Sha256::digest(canonical_request.as_bytes()))
+        );
+        let mut signing_key = hmac(format!("AWS4{}", self.secret_key).as_bytes(), date.as_bytes());
+        for part in [self.region.as_str(), "s3", "aws4_request"] {
+            signing_key = hmac(&signing_key, part.as_bytes());
+        }
+        let signature = hex(&hmac(&signing_key, string_to_sign.as_bytes()));
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.access_key, scope, signed_headers, signature
+        );
+
+        let mut request = self.client.request(method, url).header("authorization", authorization).body(body);
+        for (name, value) in headers.into_iter().filter(|(name, _)| *name != "host") {
+            request = request.header(name, value);
+        }
+        let response = request.send().await.map_err(|e| ServerError::InvalidArgument(format!("Request to the bucket failed: {}", e)))?;
+        let status = response.status();
+        let body = response.bytes().await.map_err(|e| ServerError::InvalidArgument(e.to_string()))?;
+        if status == reqwest::StatusCode::NOT_FOUND {
+            return Err(ServerError::KeyNotFound(key.to_string()));
+        }
+        if !status.is_success() {
+            let code = xml_values(&String::from_utf8_lossy(&body), "Code").first().map(|code| code.to_string()).unwrap_or_default();
+            return Err(ServerError::InvalidArgument(format!("Bucket replied {} {}", status, code)));
+        }
+        Ok(body.to_vec())
+    }
+}
+
+pub(crate) fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// Percent-encode all but unreserved characters, and slashes unless asked to
+pub(crate) fn uri_encode(text: &str, encode_slash: bool) -> String {
+    text.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+            b'/' if !encode_slash => "/".to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+// Text of every <tag> element, enough to read the listings S3 replies with
+fn xml_values<'a>(xml: &'a str, tag: &str) -> Vec<&'a str> {
+    let (open, close) = (format!("<{}>", tag), format!("</{}>", tag));
+    let mut values = Vec::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find(&open) {
+        rest = &rest[start + open.len()..];
+        let Some(end) = rest.find(&close) else {
+            break;
+        };
+        values.push(&rest[..end]);
+        rest = &rest[end + close.len()..];
+    }
+    values
+}
+
+// Save a snapshot and upload it, every `backup_interval_secs`
+pub async fn run(state: Arc<RwLock<ServerState>>) {
+    let (config, shutdown) = {
+        let state = state.read().unwrap();
+        (state.config.clone(), state.shutdown.clone())
+    };
+    if config.backup_url.is_empty() || config.backup_interval_secs == 0 {
+        return;
+    }
+    let mut interval = tokio::time::interval(Duration::from_secs(config.backup_interval_secs));
+    interval.tick().await;
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.wait() => break,
+        }
+        if let Err(e) = save_and_upload(&state).await {
+            error!("Backup failed: {}", e);
+        }
+    }
+}
+
+// Save a snapshot and upload it along with its log position
+pub async fn save_and_upload(state: &Arc<RwLock<ServerState>>) -> Result<(), ServerError> {
+    let backup = state.read().unwrap().backup.clone();
+    let Ok(_running) = backup.running.try_lock() else {
+        return Err(ServerError::InvalidArgument("A backup is already running".to_string()));
+    };
+    // Writes go on while the snapshot is written out
+    let pending = PendingSave::new(&state.read().unwrap());
+    let position = tokio::task::spawn_blocking(move || pending.save())
+        .await
+        .map_err(|e| ServerError::Io(io::Error::other(e)))
+        .and_then(|saved| saved)
+        .inspect_err(|e| backup.failed(e))?;
+    upload_snapshot(state, position).await
+}
+
+// Upload the snapshot file just saved; its writes end at log `position`
+pub async fn upload_snapshot(state: &Arc<RwLock<ServerState>>, position: u64) -> Result<(), ServerError> {
+    let (config, backup) = {
+        let state = state.read().unwrap();
+        (state.config.clone(), state.backup.clone())
+    };
+    let result = async {
+        let store = ObjectStore::new(&config, &config.backup_url)?;
+        let data = tokio::fs::read(&config.snapshot_file).await?;
+        let bytes = data.len() as u64;
+        let at = now_ms();
+        let name = format!("snapshots/{}.snapshot", at);
+        store.put(&name, data).await?;
+        let uploaded = serde_json::to_vec(&Uploaded { snapshot: name.clone(), wal_position: position })?;
+        store.put(&format!("snapshots/{}.json", at), uploaded.clone()).await?;
+        store.put(LATEST, uploaded).await?;
+        info!("Uploaded snapshot {} ({} bytes) to {}", name, bytes, config.backup_url);
+        Ok(bytes)
+    }.await;
+    match result {
+        Ok(bytes) => {
+            backup.uploads.fetch_add(1, Ordering::Relaxed);
+            backup.last_at.store(now_ms(), Ordering::Relaxed);
+            backup.last_bytes.store(bytes, Ordering::Relaxed);
+            backup.last_error.lock().unwrap().clear();
+            Ok(())
+        }
+        Err(e) => {
+            backup.failed(&e);
+            Err(e)
+        }
+    }
+}
+
+// Restore the data for the next start to load: from the backups under
+// `from`, to how it was at time `at`, or both
+pub async fn restore(from: Option<&str>, at: Option<&str>, force: bool) -> io::Result<()> {
+    let config = read_flux_toml();
+    let target = match at {
+        Some(at) => {
+            let time = chrono::DateTime::parse_from_rfc3339(at)
+                .map_err(|e| io::Error::other(format!("Invalid time {} (expected RFC 3339, like 2024-05-01T12:00:00Z): {}", at, e)))?;
+            Some(u64::try_from(time.timestamp_millis()).map_err(|_| io::Error::other(format!("{} is before 1970", at)))?)
+        }
+        None => None,
+    };
+    match (from, target) {
+        (Some(url), target) => restore_from(&config, url, target, force).await?,
+        (None, Some(target)) => wal::prepare_recovery(&config, target)?,
+        (None, None) => return Err(io::Error::other("Pass --from, --at or both")),
+    }
+    storage::discard(&config)
+}
+
+// Download the newest snapshot under `url` uploaded before `target`, and the
+// log segments after it, into the places flxc.toml names
+async fn restore_from(config: &FluxConfig, url: &str, target: Option<u64>, force: bool) -> io::Result<()> {
+    let store = ObjectStore::new(config, url).map_err(io::Error::other)?;
+    let dir = Path::new(&config.wal_dir);
+    let local_segments = if dir.is_dir() { list_segments(dir)? } else { Vec::new() };
+    if !force && (Path::new(&config.snapshot_file).exists() || !local_segments.is_empty()) {
+        return Err(io::Error::other(format!(
+            "{} or {} holds data already; pass --force to replace it", config.snapshot_file, config.wal_dir
+        )));
+    }
+    let listing = match target {
+        None => LATEST.to_string(),
+        Some(target) => {
+            let newest = store.list("snapshots").await.map_err(io::Error::other)?
+                .into_iter()
+                .filter_map(|name| name.strip_suffix(".json")?.parse::<u64>().ok())
+                .filter(|at| *at <= target)
+                .max()
+                .ok_or_else(|| io::Error::other(format!("No snapshot was uploaded to {} before that time", url)))?;
+            format!("snapshots/{}.json", newest)
+        }
+    };
+    let uploaded = store.get(&listing).await.map_err(io::Error::other)?
+        .ok_or_else(|| io::Error::other(format!("No snapshot has been uploaded to {}", url)))?;
+    let uploaded: Uploaded = serde_json::from_slice(&uploaded)?;
+    let snapshot = store.get(&uploaded.snapshot).await.map_err(io::Error::other)?
+        .ok_or_else(|| io::Error::other(format!("{} is missing from {}", uploaded.snapshot, url)))?;
+    let tmp_path = format!("{}.tmp", config.snapshot_file);
+    fs::write(&tmp_path, &snapshot)?;
+    fs::rename(&tmp_path, &config.snapshot_file)?;
+    println!("Restored {} ({} bytes) to {}", uploaded.snapshot, snapshot.len(), config.snapshot_file);
+
+    // The segment holding the first write after the snapshot, and every later one
+    let mut segments: Vec<(u64, String)> = store.list("wal").await.map_err(io::Error::other)?
+        .into_iter()
+        .filter_map(|name| Some((segment_first(Path::new(&name))?, name)))
+        .collect();
+    segments.sort();
+    let start = segments.iter().rposition(|(first, _)| *first <= uploaded.wal_position + 1).unwrap_or(0);
+    fs::create_dir_all(dir)?;
+    // Local segments belong to another history now
+    for (_, path) in local_segments {
+        fs::remove_file(path)?;
+    }
+    for (_, name) in &segments[start..] {
+        let data = store.get(&format!("wal/{}", name)).await.map_err(io::Error::other)?
+            .ok_or_else(|| io::Error::other(format!("wal/{} vanished from {}", name, url)))?;
+        fs::write(dir.join(name), data)?;
+    }
+    // The snapshot was saved no later than it was uploaded
+    let uploaded_at = uploaded.snapshot.strip_prefix("snapshots/")
+        .and_then(|name| name.strip_suffix(".snapshot"))
+        .and_then(|at| at.parse().ok())
+        .unwrap_or(0);
+    wal::write_checkpoint(dir, uploaded.wal_position, uploaded_at)?;
+    println!("Restored {} write-ahead log segments to {}", segments.len() - start, config.wal_dir);
+    if let Some(target) = target {
+        wal::set_recovery_target(dir, target)?;
+        println!("The next start replays them up to that time");
+    }
+    if !config.wal_enabled && start < segments.len() {
+        warn!("wal_enabled is off; the restored segments are only replayed once it is set");
+        println!("Set wal_enabled to replay them at startup");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::{BTreeMap, HashMap};
+    use std::path::PathBuf;
+    use axum::body::Bytes;
+    use axum::extract::{Query, State};
+    use axum::http::{Method, StatusCode, Uri};
+    use pluto_core::wal::segment_name;
+
+    type Objects = Arc<Mutex<BTreeMap<String, Vec<u8>>>>;
+
+    // Enough of S3 for the backups: objects put, got and listed by prefix
+    async fn bucket(
+        State(objects): State<Objects>,
+        method: Method,
+        uri: Uri,
+        Query(query): Query<HashMap<String, String>>,
+        body: Bytes,
+    ) -> (StatusCode, Vec<u8>) {
+        let key = uri.path().trim_start_matches("/bucket/").to_string();
+        let mut objects = objects.lock().unwrap();
+        match method {
+            Method::PUT => {
+                objects.insert(key, body.to_vec());
+                (StatusCode::OK, Vec::new())
+            }
+            Method::GET if key.is_empty() => {
+                let prefix = query.get("prefix").cloned().unwrap_or_default();
+                let keys: String = objects.keys().filter(|key| key.starts_with(&prefix)).map(|key| format!("<Key>{}</Key>", key)).collect();
+                (StatusCode::OK, format!("<ListBucketResult><IsTruncated>false</IsTruncated>{}</ListBucketResult>", keys).into_bytes())
+            }
+            Method::GET => match objects.get(&key) {
+                Some(data) => (StatusCode::OK, data.clone()),
+                None => (StatusCode::NOT_FOUND, b"<Error><Code>NoSuchKey</Code></Error>".to_vec()),
+            },
+            _ => (StatusCode::METHOD_NOT_ALLOWED, Vec::new()),
+        }
+    }
+
+    // The bucket served on a local port, and the objects in it
+    async fn serve_bucket() -> (String, Objects) {
+        let objects = Objects::default();
+        let app = axum::Router::new().fallback(bucket).with_state(objects.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        (endpoint, objects)
+    }
+
+    // A directory in the temp directory, removed once dropped
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("pluto-backup-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+
+        fn config(&self, endpoint: &str) -> FluxConfig {
+            FluxConfig {
+                snapshot_file: self.0.join("dump.snapshot").to_string_lossy().into_owned(),
+                wal_dir: self.0.join("wal").to_string_lossy().into_owned(),
+                backup_url: "s3://bucket/backups".to_string(),
+                backup_endpoint: endpoint.to_string(),
+                backup_access_key: "access".to_string(),
+                backup_secret_key: "secret".to_string(),
+                ..Default::default()
+            }
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    // Upload `data` as the snapshot holding the writes up to `position`
+    async fn upload(config: &FluxConfig, data: &[u8], position: u64) {
+        fs::write(&config.snapshot_file, data).unwrap();
+        let state = Arc::new(RwLock::new(ServerState::new("127.0.0.1:7000".to_string(), config.clone())));
+        upload_snapshot(&state, position).await.unwrap();
+        assert_eq!(state.read().unwrap().backup.uploads.load(Ordering::Relaxed), 1);
+        // Snapshots are named after the millisecond they were uploaded in
+        tokio::time::sleep(Duration::from_millis(2)).await;
+    }
+
+    #[test]
+    fn xml_values_reads_every_element() {
+        let xml = "<R><Key>a</Key><Size>1</Size><Key>b/c</Key><Key>torn";
+        assert_eq!(xml_values(xml, "Key"), vec!["a", "b/c"]);
+        assert!(xml_values(xml, "Code").is_empty());
+    }
+
+    #[test]
+    fn only_s3_urls_with_a_bucket_are_taken() {
+        let config = FluxConfig::default();
+        let store = ObjectStore::new(&config, "s3://bucket/a/prefix/").unwrap();
+        assert_eq!(store.key("snapshots/latest.json"), "a/prefix/snapshots/latest.json");
+        assert_eq!(ObjectStore::new(&config, "s3://bucket").unwrap().key("wal/x"), "wal/x");
+        assert!(ObjectStore::new(&config, "https://bucket/prefix").is_err());
+        assert!(ObjectStore::new(&config, "s3:///prefix").is_err());
+    }
+
+    #[tokio::test]
+    async fn restores_the_newest_snapshot_and_the_segments_after_it() {
+        let (endpoint, objects) = serve_bucket().await;
+        let source = TempDir::new("source");
+        let config = source.config(&endpoint);
+        upload(&config, b"older", 5).await;
+        upload(&config, b"newer", 12).await;
+        let store = ObjectStore::new(&config, &config.backup_url).unwrap();
+        for first in [1, 10, 20] {
+            store.put(&format!("wal/{}", segment_name(first)), format!("segment {}", first).into_bytes()).await.unwrap();
+        }
+        assert!(objects.lock().unwrap().contains_key("backups/snapshots/latest.json"));
+
+        let target = TempDir::new("target");
+        let config = target.config(&endpoint);
+        restore_from(&config, &config.backup_url, None, false).await.unwrap();
+        assert_eq!(fs::read(&config.snapshot_file).unwrap(), b"newer");
+        // Write 13 is in the segment starting at 10, so earlier ones are left out
+        let restored: Vec<u64> = list_segments(Path::new(&config.wal_dir)).unwrap().into_iter().map(|(first, _)| first).collect();
+        assert_eq!(restored, vec![10, 20]);
+        assert_eq!(wal::read_checkpoint(Path::new(&config.wal_dir)).0, 12);
+
+        // Data is only replaced when asked to
+        assert!(restore_from(&config, &config.backup_url, None, false).await.is_err());
+        restore_from(&config, &config.backup_url, None, true).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn restores_the_newest_snapshot_before_a_time() {
+        let (endpoint, _objects) = serve_bucket().await;
+        let source = TempDir::new("source-at");
+        let config = source.config(&endpoint);
+        upload(&config, b"older", 5).await;
+        let between = now_ms();
+        tokio::time::sleep(Duration::from_millis(2)).await;
+        upload(&config, b"newer", 12).await;
+
+        let target = TempDir::new("target-at");
+        let config = target.config(&endpoint);
+        restore_from(&config, &config.backup_url, Some(between), false).await.unwrap();
+        assert_eq!(fs::read(&config.snapshot_file).unwrap(), b"older");
+        assert_eq!(wal::read_checkpoint(Path::new(&config.wal_dir)).0, 5);
+        assert_eq!(wal::read_recovery_target(Path::new(&config.wal_dir)), Some(between));
+
+        // Nothing was uploaded before the first snapshot
+        let err = restore_from(&config, &config.backup_url, Some(1), true).await.unwrap_err();
+        assert!(err.to_string().contains("before that time"), "{}", err);
+    }
+}
@@ -0,0 +1,131 @@
+use std::collections::VecDeque;
+use std::io::IoSlice;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use pluto_core::cache::ServerError;
+use pluto_core::codec::{self, Encoding};
+use pluto_core::protocol::Response;
+
+// Maximum number of chunks handed to a single vectored write
+const MAX_IOVECS: usize = 64;
+
+// Responses queued for a connection, written out together with vectored writes.
+// Small frames are coalesced into one buffer while value payloads are kept as
+// separate chunks so they go to the socket without being copied.
+pub struct ResponseBatch {
+    chunks: VecDeque<Bytes>,
+    pending: BytesMut,
+    len: usize,
+}
+
+impl ResponseBatch {
+    pub fn new() -> Self {
+        ResponseBatch {
+            chunks: VecDeque::new(),
+            pending: BytesMut::new(),
+            len: 0,
+        }
+    }
+
+    // Number of bytes waiting to be written
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // Queue a response. With JSON, values are framed as `$<len>\r\n` followed
+    // by the raw bytes and every other response is a JSON document. Binary
+    // encodings prefix each response with its length as a big-endian u32.
+    pub fn push(&mut self, response: &Response, encoding: Encoding) -> Result<(), ServerError> {
+        let before = self.pending.len();
+        match (response, encoding) {
+            (Response::Data(data), _) => {
+                let header = codec::data_header(data.len(), encoding)?;
+                if encoding != Encoding::Json {
+                    self.pending.put_u32((header.len() + data.len()) as u32);
+                }
+                self.pending.put_slice(&header);
+                self.len += self.pending.len() - before;
+                self.push_bytes(data.clone());
+            }
+            (_, Encoding::Json) => {
+                serde_json::to_writer((&mut self.pending).writer(), response)?;
+                self.len += self.pending.len() - before;
+            }
+            (_, _) => {
+                let body = codec::encode_response(response, encoding)?;
+                self.pending.put_u32(body.len() as u32);
+                self.pending.put_slice(&body);
+                self.len += self.pending.len() - before;
+            }
+        }
+        Ok(())
+    }
+
+    // Queue a payload as its own chunk, keeping the frame order intact
+    fn push_bytes(&mut self, data: Bytes) {
+        if data.is_empty() {
+            return;
+        }
+        if !self.pending.is_empty() {
+            self.chunks.push_back(self.pending.split().freeze());
+        }
+        self.len += data.len();
+        self.chunks.push_back(data);
+    }
+
+    // Write every queued response and flush the writer
+    pub async fn write_to<W: AsyncWrite + Unpin>(&mut self, writer: &mut W) -> std::io::Result<()> {
+        self.seal();
+        while !self.chunks.is_empty() {
+            let slices: Vec<IoSlice<'_>> = self.chunks.iter()
+                .take(MAX_IOVECS)
+                .map(|chunk| IoSlice::new(chunk))
+                .collect();
+            let written = writer.write_vectored(&slices).await?;
+            self.consume(written)?;
+        }
+        writer.flush().await
+    }
+
+    // The chunks for the next vectored write, for writers that must own what
+    // they write; `consume` then drops what was written
+    pub fn next_chunks(&mut self) -> Vec<Bytes> {
+        self.seal();
+        self.chunks.iter().take(MAX_IOVECS).cloned().collect()
+    }
+
+    fn seal(&mut self) {
+        if !self.pending.is_empty() {
+            self.chunks.push_back(self.pending.split().freeze());
+        }
+    }
+
+    // Drop fully written chunks and trim a partially written one
+    pub fn consume(&mut self, mut written: usize) -> std::io::Result<()> {
+        if written == 0 {
+            return Err(std::io::ErrorKind::WriteZero.into());
+        }
+        self.len -= written;
+        while written > 0 {
+            let front = self.chunks.front_mut().expect("written bytes exceed queued chunks");
+            if written >= front.len() {
+                written -= front.len();
+                self.chunks.pop_front();
+            } else {
+                front.advance(written);
+                written = 0;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for ResponseBatch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
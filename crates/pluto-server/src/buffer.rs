@@ -0,0 +1,75 @@
+use std::sync::Mutex;
+use bytes::BytesMut;
+
+// Initial read buffer size for each connection, enough for typical commands
+pub const READ_BUFFER_SIZE: usize = 4 * 1024;
+// Size of the pooled scratch buffers used once a command outgrows the read buffer
+pub const SCRATCH_BUFFER_SIZE: usize = 1024 * 1024;
+// Buffers that grew past this are freed instead of being pooled
+const MAX_POOLED_CAPACITY: usize = 4 * SCRATCH_BUFFER_SIZE;
+// Upper bound on idle scratch buffers kept around
+const MAX_POOLED_BUFFERS: usize = 64;
+
+// Pool of large scratch buffers shared by all connections
+pub struct BufferPool {
+    buffers: Mutex<Vec<BytesMut>>,
+}
+
+impl BufferPool {
+    pub fn new() -> Self {
+        BufferPool {
+            buffers: Mutex::new(Vec::new()),
+        }
+    }
+
+    // Take a scratch buffer from the pool, allocating one if the pool is empty
+    pub fn acquire(&self) -> BytesMut {
+        self.buffers.lock().unwrap()
+            .pop()
+            .unwrap_or_else(|| BytesMut::with_capacity(SCRATCH_BUFFER_SIZE))
+    }
+
+    // Hand a scratch buffer back so the next large command can reuse it
+    pub fn release(&self, mut buf: BytesMut) {
+        if buf.capacity() < SCRATCH_BUFFER_SIZE || buf.capacity() > MAX_POOLED_CAPACITY {
+            return;
+        }
+        buf.clear();
+        let mut buffers = self.buffers.lock().unwrap();
+        if buffers.len() < MAX_POOLED_BUFFERS {
+            buffers.push(buf);
+        }
+    }
+
+    // Make sure a connection's read buffer has room for the next read,
+    // moving to a pooled scratch buffer when the small one is full
+    pub fn reserve(&self, buf: &mut BytesMut) {
+        if buf.len() < buf.capacity() {
+            return;
+        }
+        if buf.capacity() < SCRATCH_BUFFER_SIZE {
+            let mut scratch = self.acquire();
+            scratch.extend_from_slice(buf);
+            *buf = scratch;
+        } else {
+            // Commands larger than a scratch buffer grow it geometrically
+            buf.reserve(buf.capacity());
+        }
+    }
+
+    // Reset a drained read buffer, returning a scratch buffer to the pool
+    // so idle connections only hold on to a small buffer
+    pub fn recycle(&self, buf: &mut BytesMut) {
+        buf.clear();
+        if buf.capacity() >= SCRATCH_BUFFER_SIZE {
+            let scratch = std::mem::replace(buf, BytesMut::with_capacity(READ_BUFFER_SIZE));
+            self.release(scratch);
+        }
+    }
+}
+
+impl Default for BufferPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,300 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use tokio::time;
+use log::{debug, info};
+
+// Connection limits applied when a client registers
+pub struct ConnectionLimits {
+    pub max_clients: usize,
+    pub max_clients_per_ip: usize,      // 0 means unlimited
+    pub max_commands_per_sec_per_ip: u32, // 0 means unlimited
+}
+
+// Output buffer limits of one class of clients: a client with more than
+// `hard` bytes waiting to be written to it, or more than `soft` for
+// `soft_secs` on end, is disconnected. Zero turns a limit off.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OutputLimits {
+    pub hard: usize,
+    pub soft: usize,
+    pub soft_secs: u64,
+}
+
+// Bytes waiting to be written to a connection: the responses being written
+// and the messages published to it that it has yet to take. A client that
+// stops reading (a subscriber gone quiet, say) can't grow it past the limits
+// of its class, pubsub while it has subscriptions and normal otherwise.
+pub struct OutputBuffer {
+    normal: OutputLimits,
+    pubsub: OutputLimits,
+    subscribed: AtomicBool,
+    queued: AtomicUsize,
+    created: Instant,
+    soft_since_ms: AtomicU64, // milliseconds after `created` it went over the soft limit, plus one; 0 while under it
+    overflowed: AtomicBool,
+    overflow: Notify,
+}
+
+impl OutputBuffer {
+    pub fn new(normal: OutputLimits, pubsub: OutputLimits) -> Self {
+        OutputBuffer {
+            normal,
+            pubsub,
+            subscribed: AtomicBool::new(false),
+            queued: AtomicUsize::new(0),
+            created: Instant::now(),
+            soft_since_ms: AtomicU64::new(0),
+            overflowed: AtomicBool::new(false),
+            overflow: Notify::new(),
+        }
+    }
+
+    pub fn set_subscribed(&self, subscribed: bool) {
+        self.subscribed.store(subscribed, Ordering::Relaxed);
+    }
+
+    // Count bytes queued for the client. Past its limits they aren't, and
+    // the connection is told to close.
+    pub fn add(&self, bytes: usize) -> bool {
+        if self.is_overflowed() {
+            return false;
+        }
+        let queued = self.queued.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        let limits = if self.subscribed.load(Ordering::Relaxed) { self.pubsub } else { self.normal };
+        let over_hard = limits.hard > 0 && queued > limits.hard;
+        let over_soft = limits.soft > 0 && queued > limits.soft && {
+            let now = self.created.elapsed().as_millis() as u64 + 1;
+            let since = self.soft_since_ms.compare_exchange(0, now, Ordering::Relaxed, Ordering::Relaxed)
+                .unwrap_or_else(|since| since);
+            now - since >= limits.soft_secs * 1000
+        };
+        if over_hard || over_soft {
+            self.queued.fetch_sub(bytes, Ordering::Relaxed);
+            self.overflowed.store(true, Ordering::Relaxed);
+            self.overflow.notify_one();
+            return false;
+        }
+        true
+    }
+
+    // Count bytes written to the client, or no longer waiting to be
+    pub fn remove(&self, bytes: usize) {
+        let queued = self.queued.fetch_sub(bytes, Ordering::Relaxed) - bytes;
+        let limits = if self.subscribed.load(Ordering::Relaxed) { self.pubsub } else { self.normal };
+        if queued <= limits.soft {
+            self.soft_since_ms.store(0, Ordering::Relaxed);
+        }
+    }
+
+    pub fn is_overflowed(&self) -> bool {
+        self.overflowed.load(Ordering::Relaxed)
+    }
+
+    // Resolves once the connection went over its limits
+    pub async fn overflowed(&self) {
+        if !self.is_overflowed() {
+            self.overflow.notified().await;
+        }
+    }
+}
+
+// Token bucket shared by all connections from one address
+pub struct RateBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateBucket {
+    fn new(rate: u32) -> Self {
+        RateBucket {
+            tokens: rate as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    // Take a token, refilling at `rate` per second with a burst of one second's worth
+    fn try_take(&mut self, rate: u32) -> bool {
+        let now = Instant::now();
+        let refill = now.duration_since(self.last_refill).as_secs_f64() * rate as f64;
+        self.tokens = (self.tokens + refill).min(rate as f64);
+        self.last_refill = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+// Per-address bookkeeping
+struct IpEntry {
+    connections: usize,
+    bucket: Arc<Mutex<RateBucket>>,
+}
+
+// A connected client as seen by the registry
+pub struct ClientHandle {
+    pub id: u64,
+    pub addr: SocketAddr,
+    pub connected_at: Instant,
+    last_active_ms: AtomicU64, // milliseconds since `connected_at`
+    listening: AtomicBool, // waits on pushes (messages, key events, invalidations, a replication stream) rather than sending
+    kill: Notify,
+    rate: Arc<Mutex<RateBucket>>,
+    max_commands_per_sec: u32,
+}
+
+impl ClientHandle {
+    // Record traffic from the client
+    pub fn touch(&self) {
+        let elapsed = self.connected_at.elapsed().as_millis() as u64;
+        self.last_active_ms.store(elapsed, Ordering::Relaxed);
+    }
+
+    // Time since the client last sent anything
+    pub fn idle_for(&self) -> Duration {
+        let last_active = Duration::from_millis(self.last_active_ms.load(Ordering::Relaxed));
+        self.connected_at.elapsed().saturating_sub(last_active)
+    }
+
+    // A connection that only takes pushes is quiet by design, so the idle
+    // reaper leaves it alone
+    pub fn set_listening(&self, listening: bool) {
+        self.listening.store(listening, Ordering::Relaxed);
+    }
+
+    // Ask the connection handler to close the connection
+    pub fn kill(&self) {
+        self.kill.notify_one();
+    }
+
+    // Check the command rate limit shared by every connection from this address
+    pub fn allow_command(&self) -> bool {
+        if self.max_commands_per_sec == 0 {
+            return true;
+        }
+        self.rate.lock().unwrap().try_take(self.max_commands_per_sec)
+    }
+
+    // Resolves once the connection has been asked to close
+    pub async fn killed(&self) {
+        self.kill.notified().await;
+    }
+}
+
+// Registry of every open client connection
+pub struct ClientRegistry {
+    next_id: AtomicU64,
+    clients: Mutex<HashMap<u64, Arc<ClientHandle>>>,
+    ips: Mutex<HashMap<IpAddr, IpEntry>>,
+}
+
+impl ClientRegistry {
+    pub fn new() -> Self {
+        ClientRegistry {
+            next_id: AtomicU64::new(1),
+            clients: Mutex::new(HashMap::new()),
+            ips: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Register a new connection, or explain why it was refused
+    pub fn register(&self, addr: SocketAddr, limits: &ConnectionLimits) -> Result<Arc<ClientHandle>, &'static str> {
+        let mut clients = self.clients.lock().unwrap();
+        if clients.len() >= limits.max_clients {
+            return Err("max clients reached");
+        }
+        let mut ips = self.ips.lock().unwrap();
+        let entry = ips.entry(addr.ip()).or_insert_with(|| IpEntry {
+            connections: 0,
+            bucket: Arc::new(Mutex::new(RateBucket::new(limits.max_commands_per_sec_per_ip))),
+        });
+        if limits.max_clients_per_ip > 0 && entry.connections >= limits.max_clients_per_ip {
+            return Err("max clients per address reached");
+        }
+        entry.connections += 1;
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let handle = Arc::new(ClientHandle {
+            id,
+            addr,
+            connected_at: Instant::now(),
+            last_active_ms: AtomicU64::new(0),
+            listening: AtomicBool::new(false),
+            kill: Notify::new(),
+            rate: entry.bucket.clone(),
+            max_commands_per_sec: limits.max_commands_per_sec_per_ip,
+        });
+        clients.insert(id, handle.clone());
+        Ok(handle)
+    }
+
+    pub fn unregister(&self, id: u64) {
+        let removed = self.clients.lock().unwrap().remove(&id);
+        if let Some(client) = removed {
+            let mut ips = self.ips.lock().unwrap();
+            if let Some(entry) = ips.get_mut(&client.addr.ip()) {
+                entry.connections -= 1;
+                if entry.connections == 0 {
+                    ips.remove(&client.addr.ip());
+                }
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.clients.lock().unwrap().len()
+    }
+
+    // Every open connection, oldest first
+    pub fn list(&self) -> Vec<Arc<ClientHandle>> {
+        let mut clients: Vec<_> = self.clients.lock().unwrap().values().cloned().collect();
+        clients.sort_by_key(|client| client.id);
+        clients
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.clients.lock().unwrap().is_empty()
+    }
+
+    // Close every connection that has been idle for longer than `timeout`,
+    // other than those listening for pushes
+    pub fn reap_idle(&self, timeout: Duration) -> usize {
+        let clients = self.clients.lock().unwrap();
+        let mut reaped = 0;
+        for client in clients.values() {
+            if client.idle_for() > timeout && !client.listening.load(Ordering::Relaxed) {
+                debug!("Closing idle connection {} from {}", client.id, client.addr);
+                client.kill();
+                reaped += 1;
+            }
+        }
+        reaped
+    }
+}
+
+impl Default for ClientRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Periodically close idle connections. A timeout of zero disables the reaper.
+pub async fn run_idle_reaper(clients: Arc<ClientRegistry>, timeout_secs: u64) {
+    if timeout_secs == 0 {
+        return;
+    }
+    let timeout = Duration::from_secs(timeout_secs);
+    let mut interval = time::interval(Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+        let reaped = clients.reap_idle(timeout);
+        if reaped > 0 {
+            info!("Closed {} idle connections", reaped);
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use std::process::Stdio;
+use std::sync::atomic::AtomicU64;
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use pluto_core::cache::{glob_match, ServerError, WriteStamp};
+use crate::environment::FluxConfig;
+
+// When two clusters replicate into each other, both may write a key before
+// hearing of the other's write. Geo-replication then settles the two writes by
+// the key's policy instead of letting whichever arrives last overwrite the
+// other. Every policy orders two writes the same way on either side, so both
+// clusters end up with the same value.
+
+// How two concurrent writes to a key are settled
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConflictPolicy {
+    // The write made last by wall clock
+    #[default]
+    LastWriteWins,
+    // The write to the entry written most often, then the later one
+    HighestVersion,
+    // The output of `geo_conflict_merge_command` given both values; a
+    // deletion, or a command that fails, falls back to the last write
+    Merge,
+}
+
+// A policy for the keys matching a glob pattern
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConflictRule {
+    pub pattern: String,
+    pub policy: ConflictPolicy,
+}
+
+// Longest a merge command may run
+const MERGE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Conflicts met applying another cluster's writes, for INFO geo
+#[derive(Default)]
+pub struct ConflictStats {
+    pub detected: AtomicU64,
+    pub kept_local: AtomicU64,
+    pub took_remote: AtomicU64,
+    pub merged: AtomicU64,
+    pub merge_failed: AtomicU64,
+}
+
+// Policy of a key: that of the first rule matching it, else the default
+pub fn policy_for(config: &FluxConfig, key: &str) -> ConflictPolicy {
+    config.geo_conflict_rules.iter()
+        .find(|rule| glob_match(rule.pattern.as_bytes(), key.as_bytes()))
+        .map_or(config.geo_conflict_policy, |rule| rule.policy)
+}
+
+// A write as the policies compare it; a deletion has no value
+pub struct Candidate<'a> {
+    pub stamp: WriteStamp,
+    pub value: Option<&'a [u8]>,
+}
+
+// Whether `remote` beats `local`. Merges order the two values the same way
+// as the last write wins; ties fall to the larger value, deletions last.
+pub fn remote_wins(policy: ConflictPolicy, local: &Candidate, remote: &Candidate) -> bool {
+    let order = |candidate: &Candidate| match policy {
+        ConflictPolicy::HighestVersion => (candidate.stamp.version, candidate.stamp.at),
+        ConflictPolicy::LastWriteWins | ConflictPolicy::Merge => (candidate.stamp.at, candidate.stamp.version),
+    };
+    (order(remote), remote.value) > (order(local), local.value)
+}
+
+// Stamp of the value merged from two writes, newer than both
+pub fn merged_stamp(a: WriteStamp, b: WriteStamp) -> WriteStamp {
+    WriteStamp { at: a.at.max(b.at), version: a.version.max(b.version) + 1 }
+}
+
+// Run the merge command through the shell with the older value followed by
+// the newer one on stdin; PLUTO_KEY names the key and PLUTO_OLDER_LEN the
+// length of the older value. Its output is the merged value. The command must
+// be deterministic, as both clusters merge the same conflict on their own.
+pub async fn merge(command: &str, key: &str, older: &[u8], newer: &[u8]) -> Result<Vec<u8>, ServerError> {
+    if command.is_empty() {
+        return Err(ServerError::InvalidArgument("geo_conflict_merge_command is not set".to_string()));
+    }
+    let mut child = tokio::process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .env("PLUTO_KEY", key)
+        .env("PLUTO_OLDER_LEN", older.len().to_string())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let input = [older, newer].concat();
+    let run = async move {
+        stdin.write_all(&input).await?;
+        drop(stdin);
+        child.wait_with_output().await
+    };
+    let output = tokio::time::timeout(MERGE_TIMEOUT, run).await
+        .map_err(|_| ServerError::InvalidArgument(format!("Merge command timed out after {:?}", MERGE_TIMEOUT)))??;
+    if !output.status.success() {
+        return Err(ServerError::InvalidArgument(format!("Merge command exited with {}", output.status)));
+    }
+    Ok(output.stdout)
+}
@@ -0,0 +1,98 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+use axum::Json;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::{Html, IntoResponse, Response as HttpResponse};
+use serde::Serialize;
+use pluto_core::cluster::ClusterData;
+use pluto_core::protocol::{Command, Response};
+use crate::fanout;
+use crate::state::ServerState;
+use crate::stats::SlowEntry;
+use crate::version;
+
+// A small web UI for triage, served on the admin port when dashboard_enabled
+// is set: /dashboard is the page, which polls /dashboard/overview. What it
+// shows comes from INFO of every member, the slot map CLUSTER_SLOTS replies
+// with, this node's slowlog and its connected clients. When a password or
+// users are configured, both ask for requirepass or an admin user's
+// credentials over HTTP Basic auth.
+
+const PAGE: &str = include_str!("dashboard.html");
+
+type SharedState = Arc<RwLock<ServerState>>;
+
+#[derive(Serialize)]
+struct Overview {
+    node: String,
+    version: &'static str,
+    cluster: Option<ClusterData>, // the slot map and the health of each member, when clustered
+    members: BTreeMap<String, Member>,
+    slowlog: Vec<SlowEntry>,
+    clients: Vec<Client>,
+}
+
+// INFO of one member as `key -> value`, or why it didn't answer
+#[derive(Serialize)]
+struct Member {
+    info: BTreeMap<String, String>,
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Client {
+    id: u64,
+    addr: String,
+    age_secs: u64,
+    idle_secs: u64,
+}
+
+pub async fn page(State(state): State<SharedState>) -> HttpResponse {
+    if !state.read().unwrap().config.dashboard_enabled {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    Html(PAGE).into_response()
+}
+
+pub async fn overview(State(state): State<SharedState>) -> HttpResponse {
+    if !state.read().unwrap().config.dashboard_enabled {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    let members = fanout::ask_members(&state, &Command::INFO { section: None }).await
+        .into_iter()
+        .map(|(address, answer)| {
+            let member = match answer {
+                Ok(Response::Info(report)) => Member { info: parse_info(&report), error: None },
+                Ok(other) => Member { info: BTreeMap::new(), error: Some(format!("unexpected response {:?}", other)) },
+                Err(e) => Member { info: BTreeMap::new(), error: Some(e) },
+            };
+            (address, member)
+        })
+        .collect();
+    let state = state.read().unwrap();
+    let overview = Overview {
+        node: state.cluster.self_addr.clone(),
+        version: version::VERSION,
+        cluster: state.cluster_enabled.then(|| state.cluster.get_cluster_data()),
+        members,
+        slowlog: state.stats.slowlog(),
+        clients: state.clients.list().iter()
+            .map(|client| Client {
+                id: client.id,
+                addr: client.addr.to_string(),
+                age_secs: client.connected_at.elapsed().as_secs(),
+                idle_secs: client.idle_for().as_secs(),
+            })
+            .collect(),
+    };
+    Json(overview).into_response()
+}
+
+fn parse_info(report: &str) -> BTreeMap<String, String> {
+    report.lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| line.split_once(':'))
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
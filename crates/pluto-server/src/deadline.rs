@@ -0,0 +1,41 @@
+use std::future::Future;
+use std::time::{Duration, Instant};
+use pluto_core::cache::ServerError;
+use pluto_core::protocol::Command;
+
+// A deadline on the commands of clients, from command_timeout_ms. A command
+// past it is dropped at its next await, and the scans that hold the keyspace
+// lock for long (KEYS) check it as they go. Writes run to the end whatever
+// the time: one dropped halfway could reach the keyspace without reaching
+// the replicas or the write-ahead log.
+
+tokio::task_local! {
+    static DEADLINE: Instant;
+}
+
+// Whether a command may be cut short; WAIT has a timeout of its own
+pub fn applies_to(cmd: &Command) -> bool {
+    !cmd.is_write() && !matches!(cmd, Command::WAIT { .. })
+}
+
+// Run a command, failing it once `timeout` passed; a zero timeout never does
+pub async fn run<F>(timeout: Duration, command: F) -> Result<F::Output, ServerError>
+where
+    F: Future,
+{
+    if timeout.is_zero() {
+        return Ok(command.await);
+    }
+    let deadline = Instant::now() + timeout;
+    let command = DEADLINE.scope(deadline, command);
+    tokio::time::timeout(timeout, command).await.map_err(|_| exceeded())
+}
+
+// Whether the command running on this task is past its deadline
+pub fn expired() -> bool {
+    DEADLINE.try_with(|deadline| Instant::now() >= *deadline).unwrap_or(false)
+}
+
+pub fn exceeded() -> ServerError {
+    ServerError::Timeout("Command exceeded command_timeout_ms".to_string())
+}
@@ -0,0 +1,86 @@
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+use log::info;
+use crate::state::ServerState;
+
+// Keys re-allocated per write lock acquisition
+const DEFRAG_BATCH: usize = 128;
+
+// Progress of the current or last keyspace defragmentation
+pub struct Defrag {
+    running: AtomicBool,
+    pub total: AtomicUsize,       // keys in the keyspace when the run started
+    pub scanned: AtomicUsize,     // keys visited so far
+    pub reallocated: AtomicU64,   // bytes of values moved to fresh allocations
+    pub runs: AtomicU64,          // completed runs
+}
+
+impl Defrag {
+    pub fn new() -> Self {
+        Defrag {
+            running: AtomicBool::new(false),
+            total: AtomicUsize::new(0),
+            scanned: AtomicUsize::new(0),
+            reallocated: AtomicU64::new(0),
+            runs: AtomicU64::new(0),
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::SeqCst)
+    }
+}
+
+impl Default for Defrag {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Start a defragmentation run in the background; false if one is already running
+pub fn start(state: &Arc<RwLock<ServerState>>) -> bool {
+    let defrag = state.read().unwrap().defrag.clone();
+    if defrag.running.swap(true, Ordering::SeqCst) {
+        return false;
+    }
+    tokio::spawn(run(state.clone(), defrag));
+    true
+}
+
+// Copy every value into a fresh, exactly sized allocation and then shrink the
+// hash table, a batch of keys at a time so other commands keep running.
+// Values sliced out of larger buffers or left behind after a wave of deletes
+// stop pinning memory the allocator could otherwise return.
+async fn run(state: Arc<RwLock<ServerState>>, defrag: Arc<Defrag>) {
+    let (keys, keys_per_sec) = {
+        let state = state.read().unwrap();
+        (state.cache.keys().map(|key| key.to_string()).collect::<Vec<_>>(), state.config.defrag_keys_per_sec.max(1))
+    };
+    defrag.total.store(keys.len(), Ordering::SeqCst);
+    defrag.scanned.store(0, Ordering::SeqCst);
+    defrag.reallocated.store(0, Ordering::SeqCst);
+    info!("Defragmenting {} keys at up to {} keys/s", keys.len(), keys_per_sec);
+
+    // Pause between batches so the run stays under the configured rate
+    let pause = Duration::from_secs_f64(DEFRAG_BATCH as f64 / keys_per_sec as f64);
+    for batch in keys.chunks(DEFRAG_BATCH) {
+        {
+            let mut state = state.write().unwrap();
+            for key in batch {
+                // Keys deleted since the run started are simply skipped
+                if let Some(moved) = state.cache.reallocate(key) {
+                    defrag.reallocated.fetch_add(moved as u64, Ordering::Relaxed);
+                }
+            }
+        }
+        defrag.scanned.fetch_add(batch.len(), Ordering::SeqCst);
+        tokio::time::sleep(pause).await;
+    }
+
+    // Rebuild the table at the size the remaining keys need
+    state.write().unwrap().cache.shrink_to_fit();
+    defrag.runs.fetch_add(1, Ordering::SeqCst);
+    defrag.running.store(false, Ordering::SeqCst);
+    info!("Defragmentation finished after {} keys", defrag.scanned.load(Ordering::SeqCst));
+}
@@ -0,0 +1,815 @@
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+use serde::{Deserialize, Deserializer, Serialize};
+use pluto_core::cache::Layout;
+use pluto_core::cluster::Hashing;
+use pluto_core::encryption::{Keyring, parse_key};
+use pluto_core::value::INLINE_CAPACITY;
+use crate::conflict::{ConflictPolicy, ConflictRule};
+use crate::eviction::EvictionPolicy;
+use crate::origin::OriginRule;
+use crate::peer::PeerCredentials;
+use crate::acl::{AclUser, TenantRule};
+use crate::storage::Engine;
+use crate::wal::WalFsync;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct FluxConfig {
+    #[serde(default = "default_bind", deserialize_with = "string_or_list")]
+    pub bind: Vec<String>,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    #[serde(default = "default_cluster_enabled")]
+    pub cluster_enabled: bool,
+    #[serde(default)]
+    pub cluster_hashing: Hashing, // "slots" or "ring"; every member must use the same
+    #[serde(default = "default_cluster_weight")]
+    pub cluster_weight: u32, // share of the slots this node takes in ring mode
+    #[serde(default)]
+    pub cluster_require_quorum: bool, // refuse writes while this node can't reach a majority of the members
+    #[serde(default = "default_public_ip")]
+    pub public_ip: String,
+    #[serde(default = "default_public_port")]
+    pub public_port: u16,
+    #[serde(default = "default_max_inflight_commands")]
+    pub max_inflight_commands: usize,
+    #[serde(default = "default_max_inflight_bytes")]
+    pub max_inflight_bytes: usize,
+    #[serde(default = "default_max_command_bytes")]
+    pub max_command_bytes: usize, // a client whose unparsed input grows past this is disconnected
+    #[serde(default = "default_idle_timeout_secs")]
+    pub idle_timeout_secs: u64,
+    #[serde(default = "default_maxclients")]
+    pub maxclients: usize,
+    #[serde(default)]
+    pub maxmemory: usize, // bytes the keys and values may take, as used_memory counts them; 0 means unlimited
+    #[serde(default)]
+    pub maxmemory_policy: EvictionPolicy, // "noeviction", "allkeys-lru", "allkeys-lfu" or "volatile-ttl"
+    #[serde(default)]
+    pub max_clients_per_ip: usize,
+    #[serde(default)]
+    pub max_commands_per_sec_per_ip: u32,
+    #[serde(default = "default_slowlog_slower_than_us")]
+    pub slowlog_slower_than_us: u64, // commands running longer are logged for the dashboard; 0 logs none
+    #[serde(default = "default_slowlog_max_len")]
+    pub slowlog_max_len: usize, // slow commands kept, newest first
+    #[serde(default)]
+    pub dashboard_enabled: bool, // serve the web dashboard at /dashboard on the admin port
+    #[serde(default = "default_max_key_bytes")]
+    pub max_key_bytes: usize, // longer keys are refused; 0 means unlimited
+    #[serde(default = "default_max_value_bytes")]
+    pub max_value_bytes: usize, // larger values, DUMP payloads and messages are refused; 0 means unlimited
+    #[serde(default = "default_tracking_table_max_keys")]
+    pub tracking_table_max_keys: usize, // keys remembered for CLIENT_TRACKING; 0 means unlimited
+    #[serde(default)]
+    pub revalidate_channel: String, // published the key of the first read past its soft TTL; empty publishes nothing
+    #[serde(default)]
+    pub ttl_jitter_pct: u8, // spread TTLs set with EXPIRE and PEXPIRE by up to this percentage either way
+    #[serde(default)]
+    pub command_timeout_ms: u64, // reads running longer fail with a timeout; 0 means no limit
+    #[serde(default)]
+    pub client_output_hard_limit: usize, // bytes waiting for a client before it is disconnected; 0 means unlimited
+    #[serde(default)]
+    pub client_output_soft_limit: usize, // bytes it may have waiting for client_output_soft_secs at most
+    #[serde(default)]
+    pub client_output_soft_secs: u64,
+    #[serde(default = "default_pubsub_output_hard_limit")]
+    pub pubsub_output_hard_limit: usize, // the same for clients with subscriptions
+    #[serde(default = "default_pubsub_output_soft_limit")]
+    pub pubsub_output_soft_limit: usize,
+    #[serde(default = "default_pubsub_output_soft_secs")]
+    pub pubsub_output_soft_secs: u64,
+    #[serde(default = "default_tcp_nodelay")]
+    pub tcp_nodelay: bool,
+    #[serde(default = "default_tcp_keepalive_secs")]
+    pub tcp_keepalive_secs: u64,
+    #[serde(default = "default_tcp_backlog")]
+    pub tcp_backlog: i32,
+    #[serde(default = "default_tcp_buffer_size")]
+    pub tcp_send_buffer: usize,
+    #[serde(default = "default_tcp_buffer_size")]
+    pub tcp_recv_buffer: usize,
+    #[serde(default)]
+    pub io_uring_threads: usize, // serve the data port on this many io_uring threads; 0 serves it with tokio
+    #[serde(default = "default_admin_enabled")]
+    pub admin_enabled: bool,
+    #[serde(default = "default_admin_bind", deserialize_with = "string_or_list")]
+    pub admin_bind: Vec<String>,
+    #[serde(default)]
+    pub admin_port: u16,
+    #[serde(default)]
+    pub http_enabled: bool,
+    #[serde(default)]
+    pub http_port: u16,
+    #[serde(default)]
+    pub grpc_enabled: bool,
+    #[serde(default)]
+    pub grpc_port: u16,
+    #[serde(default = "default_shutdown_timeout_secs")]
+    pub shutdown_timeout_secs: u64,
+    #[serde(default = "default_snapshot_file")]
+    pub snapshot_file: String,
+    #[serde(default)]
+    pub save_on_shutdown: bool,
+    #[serde(default)]
+    pub storage_engine: Engine, // "memory", or "mmap" to keep every write in storage_dir as well
+    #[serde(default = "default_storage_dir")]
+    pub storage_dir: String,
+    #[serde(default)]
+    pub storage_fsync: WalFsync, // "always", "everysec" or "no"
+    #[serde(default = "default_inline_value_max")]
+    pub inline_value_max: usize, // values of up to this many bytes (23 at most) are stored inside their entry
+    #[serde(default)]
+    pub share_key_prefixes: bool, // keep what keys have up to their last ':' once in memory for all keys with it
+    #[serde(default)]
+    pub requirepass: String,
+    #[serde(default)]
+    pub cluster_user: String, // the admin user nodes authenticate to each other as; required once users are set
+    #[serde(default = "default_protected_mode")]
+    pub protected_mode: bool, // without requirepass, refuse clients on other hosts
+    #[serde(default)]
+    pub audit_enabled: bool, // record administrative commands in audit_file
+    #[serde(default = "default_audit_file")]
+    pub audit_file: String,
+    #[serde(default)]
+    pub audit_writes: bool, // record every write as well
+    #[serde(default)]
+    pub audit_key: String, // HMAC key for the record hashes; empty uses plain SHA-256
+    #[serde(default)]
+    pub meter_prefixes: Vec<String>, // key prefixes metered on their own in INFO metering
+    #[serde(default = "default_log_level")]
+    pub log_level: String,
+    #[serde(default = "default_log_target")]
+    pub log_target: String,
+    #[serde(default = "default_log_file")]
+    pub log_file: String,
+    #[serde(default = "default_log_format")]
+    pub log_format: String,
+    #[serde(default = "default_log_rotation")]
+    pub log_rotation: String,
+    #[serde(default = "default_log_max_size_mb")]
+    pub log_max_size_mb: u64,
+    #[serde(default = "default_log_max_files")]
+    pub log_max_files: usize,
+    #[serde(default)]
+    pub otel_enabled: bool,
+    #[serde(default = "default_otel_endpoint")]
+    pub otel_endpoint: String,
+    #[serde(default = "default_otel_service_name")]
+    pub otel_service_name: String,
+    #[serde(default = "default_defrag_keys_per_sec")]
+    pub defrag_keys_per_sec: usize,
+    #[serde(default)]
+    pub replica_of: String, // address of the primary to replicate; empty on a primary
+    #[serde(default)]
+    pub write_quorum: usize, // replicas that must apply a write before it is acknowledged
+    #[serde(default = "default_write_quorum_timeout_ms")]
+    pub write_quorum_timeout_ms: u64,
+    #[serde(default = "default_anti_entropy_interval_secs")]
+    pub anti_entropy_interval_secs: u64, // how often a replica compares its keyspace with the primary; 0 disables
+    #[serde(default = "default_migrate_keys_per_sec")]
+    pub migrate_keys_per_sec: u64, // rate limit of MIGRATE_SLOTS; 0 disables
+    #[serde(default)]
+    pub migrate_bytes_per_sec: u64, // bandwidth limit of MIGRATE_SLOTS; 0 disables
+    #[serde(default)]
+    pub wal_enabled: bool, // record every write in the write-ahead log and replay it at startup
+    #[serde(default = "default_wal_dir")]
+    pub wal_dir: String,
+    #[serde(default = "default_wal_segment_bytes")]
+    pub wal_segment_bytes: u64, // size at which the log moves on to a new segment
+    #[serde(default)]
+    pub wal_fsync: WalFsync, // "always", "everysec" or "no"
+    #[serde(default = "default_wal_fsync_window_us")]
+    pub wal_fsync_window_us: u64, // with "always", how long a sync waits for more writes to share it
+    #[serde(default)]
+    pub wal_archive_dir: String, // directory full segments are copied to; empty disables
+    #[serde(default)]
+    pub wal_archive_command: String, // shell command run on every full segment; empty disables
+    #[serde(default = "default_wal_keep_segments")]
+    pub wal_keep_segments: usize, // full segments kept in wal_dir once archived; 0 keeps all
+    #[serde(default)]
+    pub wal_restore_from: String, // directory of archived segments to replay at startup; empty disables
+    #[serde(default)]
+    pub backup_url: String, // s3://bucket/prefix snapshots and log segments are uploaded to; empty disables
+    #[serde(default)]
+    pub backup_endpoint: String, // of an S3-compatible service; empty uses AWS S3 in backup_region
+    #[serde(default = "default_backup_region")]
+    pub backup_region: String,
+    #[serde(default)]
+    pub backup_access_key: String, // empty uses AWS_ACCESS_KEY_ID
+    #[serde(default)]
+    pub backup_secret_key: String, // empty uses AWS_SECRET_ACCESS_KEY
+    #[serde(default)]
+    pub backup_interval_secs: u64, // how often a snapshot is saved and uploaded; 0 only does at shutdown
+    #[serde(default = "default_backup_wal")]
+    pub backup_wal: bool, // upload full write-ahead log segments as well
+    #[serde(default)]
+    pub encryption_key: String, // 64 hex digits snapshots and log segments are encrypted with; empty uses the next two
+    #[serde(default)]
+    pub encryption_key_command: String, // prints the key, e.g. by decrypting it with a KMS; empty uses PLUTO_ENCRYPTION_KEY
+    #[serde(default)]
+    pub encryption_previous_keys: Vec<String>, // keys rotated out, still accepted for reading files written with them
+    #[serde(default)]
+    pub warm_from: String, // node to copy keys from at startup before reporting ready; empty disables
+    #[serde(default)]
+    pub warm_keys: Vec<String>, // keys to copy, or glob patterns like "user:*"
+    #[serde(default)]
+    pub warm_manifest: String, // file listing more keys or patterns to copy, one per line
+    #[serde(default = "default_warm_timeout_secs")]
+    pub warm_timeout_secs: u64, // report ready after this even if copying hasn't finished
+    #[serde(default)]
+    pub geo_replicate_to: String, // any node of a standby cluster to ship every write to; empty disables
+    #[serde(default)]
+    pub geo_standby: bool, // refuse client writes and take the writes of another cluster until GEO_PROMOTE
+    #[serde(default = "default_geo_batch_writes")]
+    pub geo_batch_writes: usize, // writes shipped to the standby in one batch at most
+    #[serde(default = "default_geo_batch_interval_ms")]
+    pub geo_batch_interval_ms: u64, // longest a write waits for its batch to fill
+    #[serde(default)]
+    pub geo_active: bool, // take the writes of another cluster while taking client writes too
+    #[serde(default)]
+    pub geo_conflict_policy: ConflictPolicy, // "last-write-wins", "highest-version" or "merge"
+    #[serde(default)]
+    pub geo_conflict_merge_command: String, // shell command the merge policy runs
+    #[serde(default)]
+    pub geo_conflict_rules: Vec<ConflictRule>, // policies by key pattern, the first match winning
+    #[serde(default)]
+    pub origins: Vec<OriginRule>, // backing stores read through, by key prefix
+    #[serde(default)]
+    pub users: Vec<AclUser>, // ACL users, sandboxed to key patterns with optional quotas
+    #[serde(default)]
+    pub tenants: Vec<TenantRule>, // quotas of the tenants users work in
+}
+
+impl Default for FluxConfig {
+    fn default() -> Self {
+        FluxConfig {
+            bind: default_bind(),
+            port: default_port(),
+            cluster_enabled: default_cluster_enabled(),
+            cluster_hashing: Hashing::default(),
+            cluster_weight: default_cluster_weight(),
+            cluster_require_quorum: false,
+            public_ip: default_public_ip(),
+            public_port: default_public_port(),
+            max_inflight_commands: default_max_inflight_commands(),
+            max_inflight_bytes: default_max_inflight_bytes(),
+            max_command_bytes: default_max_command_bytes(),
+            idle_timeout_secs: default_idle_timeout_secs(),
+            maxclients: default_maxclients(),
+            maxmemory: 0,
+            maxmemory_policy: EvictionPolicy::default(),
+            max_clients_per_ip: 0,
+            max_commands_per_sec_per_ip: 0,
+            slowlog_slower_than_us: default_slowlog_slower_than_us(),
+            slowlog_max_len: default_slowlog_max_len(),
+            dashboard_enabled: false,
+            max_key_bytes: default_max_key_bytes(),
+            max_value_bytes: default_max_value_bytes(),
+            tracking_table_max_keys: default_tracking_table_max_keys(),
+            revalidate_channel: String::new(),
+            ttl_jitter_pct: 0,
+            command_timeout_ms: 0,
+            client_output_hard_limit: 0,
+            client_output_soft_limit: 0,
+            client_output_soft_secs: 0,
+            pubsub_output_hard_limit: default_pubsub_output_hard_limit(),
+            pubsub_output_soft_limit: default_pubsub_output_soft_limit(),
+            pubsub_output_soft_secs: default_pubsub_output_soft_secs(),
+            tcp_nodelay: default_tcp_nodelay(),
+            tcp_keepalive_secs: default_tcp_keepalive_secs(),
+            tcp_backlog: default_tcp_backlog(),
+            tcp_send_buffer: default_tcp_buffer_size(),
+            tcp_recv_buffer: default_tcp_buffer_size(),
+            io_uring_threads: 0,
+            admin_enabled: default_admin_enabled(),
+            admin_bind: default_admin_bind(),
+            admin_port: 0,
+            http_enabled: false,
+            http_port: 0,
+            grpc_enabled: false,
+            grpc_port: 0,
+            shutdown_timeout_secs: default_shutdown_timeout_secs(),
+            snapshot_file: default_snapshot_file(),
+            save_on_shutdown: false,
+            storage_engine: Engine::default(),
+            storage_dir: default_storage_dir(),
+            storage_fsync: WalFsync::default(),
+            inline_value_max: default_inline_value_max(),
+            share_key_prefixes: false,
+            requirepass: String::new(),
+            cluster_user: String::new(),
+            protected_mode: default_protected_mode(),
+            audit_enabled: false,
+            audit_file: default_audit_file(),
+            audit_writes: false,
+            audit_key: String::new(),
+            meter_prefixes: Vec::new(),
+            log_level: default_log_level(),
+            log_target: default_log_target(),
+            log_file: default_log_file(),
+            log_format: default_log_format(),
+            log_rotation: default_log_rotation(),
+            log_max_size_mb: default_log_max_size_mb(),
+            log_max_files: default_log_max_files(),
+            otel_enabled: false,
+            otel_endpoint: default_otel_endpoint(),
+            otel_service_name: default_otel_service_name(),
+            defrag_keys_per_sec: default_defrag_keys_per_sec(),
+            replica_of: String::new(),
+            write_quorum: 0,
+            write_quorum_timeout_ms: default_write_quorum_timeout_ms(),
+            anti_entropy_interval_secs: default_anti_entropy_interval_secs(),
+            migrate_keys_per_sec: default_migrate_keys_per_sec(),
+            migrate_bytes_per_sec: 0,
+            wal_enabled: false,
+            wal_dir: default_wal_dir(),
+            wal_segment_bytes: default_wal_segment_bytes(),
+            wal_fsync: WalFsync::default(),
+            wal_fsync_window_us: default_wal_fsync_window_us(),
+            wal_archive_dir: String::new(),
+            wal_archive_command: String::new(),
+            wal_keep_segments: default_wal_keep_segments(),
+            wal_restore_from: String::new(),
+            backup_url: String::new(),
+            backup_endpoint: String::new(),
+            backup_region: default_backup_region(),
+            backup_access_key: String::new(),
+            backup_secret_key: String::new(),
+            backup_interval_secs: 0,
+            backup_wal: default_backup_wal(),
+            encryption_key: String::new(),
+            encryption_key_command: String::new(),
+            encryption_previous_keys: Vec::new(),
+            warm_from: String::new(),
+            warm_keys: Vec::new(),
+            warm_manifest: String::new(),
+            warm_timeout_secs: default_warm_timeout_secs(),
+            geo_replicate_to: String::new(),
+            geo_standby: false,
+            geo_batch_writes: default_geo_batch_writes(),
+            geo_batch_interval_ms: default_geo_batch_interval_ms(),
+            geo_active: false,
+            geo_conflict_policy: ConflictPolicy::default(),
+            geo_conflict_merge_command: String::new(),
+            geo_conflict_rules: Vec::new(),
+            origins: Vec::new(),
+            users: Vec::new(),
+            tenants: Vec::new(),
+        }
+    }
+}
+
+fn default_bind() -> Vec<String> { 
+    vec!["127.0.0.1".to_string()]
+}
+
+// Accept either a single address or a list of addresses
+fn string_or_list<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(addr) => vec![addr],
+        OneOrMany::Many(addrs) => addrs,
+    })
+}
+
+fn default_port() -> u16 { 
+    6124 
+}
+
+fn default_cluster_enabled() -> bool {
+    false
+}
+
+fn default_cluster_weight() -> u32 {
+    1 // the same share as every other node
+}
+
+fn default_public_ip() -> String {
+    "127.0.0.1".to_string()
+}
+
+fn default_public_port() -> u16 {
+    6124
+}
+
+fn default_max_inflight_commands() -> usize {
+    1024
+}
+
+fn default_max_inflight_bytes() -> usize {
+    8 * 1024 * 1024
+}
+
+fn default_max_command_bytes() -> usize {
+    512 * 1024 * 1024
+}
+
+fn default_idle_timeout_secs() -> u64 {
+    300 // 0 disables the idle timeout
+}
+
+fn default_shutdown_timeout_secs() -> u64 {
+    10 // time given to open connections to finish before exiting
+}
+
+fn default_snapshot_file() -> String {
+    "flux.snapshot".to_string()
+}
+
+fn default_storage_dir() -> String {
+    "storage".to_string()
+}
+
+fn default_inline_value_max() -> usize {
+    INLINE_CAPACITY
+}
+
+fn default_protected_mode() -> bool {
+    true
+}
+
+fn default_audit_file() -> String {
+    "audit.log".to_string()
+}
+
+fn default_log_level() -> String {
+    "info".to_string() // off, error, warn, info, debug or trace
+}
+
+fn default_log_target() -> String {
+    "stdout".to_string() // stdout or file
+}
+
+fn default_log_file() -> String {
+    "flux.log".to_string()
+}
+
+fn default_log_format() -> String {
+    "plain".to_string() // plain or json
+}
+
+fn default_log_rotation() -> String {
+    "daily".to_string() // never, hourly or daily
+}
+
+fn default_log_max_size_mb() -> u64 {
+    100 // 0 disables size-based rotation
+}
+
+fn default_log_max_files() -> usize {
+    7
+}
+
+fn default_otel_endpoint() -> String {
+    "http://localhost:4318/v1/traces".to_string() // OTLP over HTTP
+}
+
+fn default_otel_service_name() -> String {
+    "flux-cache".to_string()
+}
+
+fn default_defrag_keys_per_sec() -> usize {
+    50000 // rate limit of MEMORY_DEFRAG
+}
+
+fn default_write_quorum_timeout_ms() -> u64 {
+    1000 // how long a write waits for its quorum before failing
+}
+
+fn default_anti_entropy_interval_secs() -> u64 {
+    60 // replicas look for keys that drifted from the primary once a minute
+}
+
+fn default_migrate_keys_per_sec() -> u64 {
+    10000 // keeps a reshard from crowding out client traffic
+}
+
+fn default_wal_dir() -> String {
+    "wal".to_string()
+}
+
+fn default_wal_segment_bytes() -> u64 {
+    64 * 1024 * 1024
+}
+
+fn default_wal_fsync_window_us() -> u64 {
+    100
+}
+
+fn default_wal_keep_segments() -> usize {
+    8
+}
+
+fn default_backup_region() -> String {
+    "us-east-1".to_string()
+}
+
+fn default_backup_wal() -> bool {
+    true
+}
+
+fn default_warm_timeout_secs() -> u64 {
+    30
+}
+
+fn default_geo_batch_writes() -> usize {
+    1000
+}
+
+fn default_geo_batch_interval_ms() -> u64 {
+    100 // bounds the lag a quiet cluster adds to its standby
+}
+
+fn default_maxclients() -> usize {
+    10000
+}
+
+fn default_pubsub_output_hard_limit() -> usize {
+    32 * 1024 * 1024
+}
+
+fn default_pubsub_output_soft_limit() -> usize {
+    8 * 1024 * 1024
+}
+
+fn default_pubsub_output_soft_secs() -> u64 {
+    60
+}
+
+fn default_slowlog_slower_than_us() -> u64 {
+    10_000
+}
+
+fn default_slowlog_max_len() -> usize {
+    128
+}
+
+fn default_max_key_bytes() -> usize {
+    64 * 1024
+}
+
+fn default_max_value_bytes() -> usize {
+    512 * 1024 * 1024
+}
+
+fn default_tracking_table_max_keys() -> usize {
+    1_000_000
+}
+
+fn default_tcp_nodelay() -> bool {
+    true
+}
+
+fn default_tcp_keepalive_secs() -> u64 {
+    300 // 0 disables keepalive probes
+}
+
+fn default_tcp_backlog() -> i32 {
+    1024
+}
+
+fn default_tcp_buffer_size() -> usize {
+    16 * 1024 * 1024 // 0 keeps the OS default
+}
+
+// `configured`, or `port` + `offset` when it is 0. A data port too high to
+// add the offset to needs the other port set explicitly.
+fn derived_port(configured: u16, port: u16, offset: u16, key: &str) -> Result<u16, String> {
+    if configured != 0 {
+        return Ok(configured);
+    }
+    port.checked_add(offset)
+        .ok_or_else(|| format!("port {} + {} is past 65535; set {} explicitly", port, offset, key))
+}
+
+fn default_admin_enabled() -> bool {
+    true
+}
+
+fn default_admin_bind() -> Vec<String> {
+    vec!["127.0.0.1".to_string()]
+}
+
+impl FluxConfig {
+    // Admin port for a node serving on `port`; 0 in the config means port + 20000
+    pub fn admin_port_for(&self, port: u16) -> Result<u16, String> {
+        derived_port(self.admin_port, port, 20000, "admin_port")
+    }
+
+    // HTTP gateway port for a node serving on `port`; 0 in the config means port + 30000
+    pub fn http_port_for(&self, port: u16) -> Result<u16, String> {
+        derived_port(self.http_port, port, 30000, "http_port")
+    }
+
+    // gRPC port for a node serving on `port`; 0 in the config means port + 40000
+    pub fn grpc_port_for(&self, port: u16) -> Result<u16, String> {
+        derived_port(self.grpc_port, port, 40000, "grpc_port")
+    }
+
+    // Whether clients on other hosts are refused: protected_mode is on, no
+    // password or user is set, and a listener is bound where they can reach it
+    pub fn is_protected(&self) -> bool {
+        let reachable = |ips: &[String]| ips.iter().any(|ip| ip.parse::<IpAddr>().map_or(true, |ip| !ip.is_loopback()));
+        self.protected_mode
+            && self.requirepass.is_empty()
+            && self.users.is_empty()
+            && (reachable(&self.bind) || (self.admin_enabled && reachable(&self.admin_bind)))
+    }
+
+    // What this node authenticates to other nodes with: its replicas and
+    // primary, the other cluster members and a geo standby
+    pub fn peer_credentials(&self) -> PeerCredentials {
+        match self.users.iter().find(|user| !self.cluster_user.is_empty() && user.name == self.cluster_user) {
+            Some(user) => PeerCredentials { username: Some(user.name.clone()), password: user.password.clone() },
+            None => PeerCredentials::password(&self.requirepass),
+        }
+    }
+
+    // Whether this node connects to other nodes on its own: as a cluster
+    // member, a replica, a geo replication peer or to warm up from one
+    fn connects_to_peers(&self) -> bool {
+        self.cluster_enabled
+            || !self.replica_of.is_empty()
+            || !self.warm_from.is_empty()
+            || !self.geo_replicate_to.is_empty()
+            || self.geo_standby
+            || self.geo_active
+    }
+
+    // Once users are configured, nodes authenticate to each other as the one
+    // cluster_user names. It runs replication, migration and cluster
+    // commands on any key, so it must be an admin outside any tenant. A node
+    // on its own needs none.
+    pub fn check_cluster_user(&self) -> Result<(), String> {
+        if self.cluster_user.is_empty() {
+            if !self.users.is_empty() && self.connects_to_peers() {
+                return Err("users are set, so cluster_user must name the user nodes authenticate to each other as".to_string());
+            }
+            return Ok(());
+        }
+        let user = self.users.iter().find(|user| user.name == self.cluster_user)
+            .ok_or_else(|| format!("cluster_user {} is not one of the users", self.cluster_user))?;
+        if !user.admin || !user.tenant.is_empty() || !user.keys.iter().any(|pattern| pattern == "*") {
+            return Err(format!("cluster_user {} must be an admin with keys [\"*\"] and no tenant", self.cluster_user));
+        }
+        Ok(())
+    }
+
+    // A tenant's keys are stored under "<name>:", and slots are hashed past
+    // the first ':', so a tenant's name can't hold one
+    pub fn check_tenants(&self) -> Result<(), String> {
+        if self.tenants.iter().any(|tenant| tenant.name.is_empty()) {
+            return Err("every tenant needs a name".to_string());
+        }
+        let names = self.tenants.iter().map(|tenant| &tenant.name).chain(self.users.iter().map(|user| &user.tenant));
+        if let Some(name) = names.into_iter().find(|name| name.contains(':')) {
+            return Err(format!("tenant {} can't have a ':' in its name", name));
+        }
+        Ok(())
+    }
+
+    // How the keyspace lays its entries out in memory
+    pub fn layout(&self) -> Layout {
+        Layout { inline_max: self.inline_value_max, shared_prefixes: self.share_key_prefixes }
+    }
+
+    // Keys persisted files are encrypted with: encryption_key, else the output
+    // of encryption_key_command, else PLUTO_ENCRYPTION_KEY. Empty when none is set.
+    pub fn keyring(&self) -> Result<Keyring, String> {
+        let current = if !self.encryption_key.is_empty() {
+            self.encryption_key.clone()
+        } else if !self.encryption_key_command.is_empty() {
+            let output = std::process::Command::new("sh")
+                .arg("-c")
+                .arg(&self.encryption_key_command)
+                .output()
+                .map_err(|e| format!("encryption_key_command failed to run: {}", e))?;
+            if !output.status.success() {
+                return Err(format!(
+                    "encryption_key_command exited with {}: {}",
+                    output.status, String::from_utf8_lossy(&output.stderr).trim()
+                ));
+            }
+            String::from_utf8_lossy(&output.stdout).trim().to_string()
+        } else {
+            std::env::var("PLUTO_ENCRYPTION_KEY").unwrap_or_default()
+        };
+        if current.is_empty() {
+            if !self.encryption_previous_keys.is_empty() {
+                return Err("encryption_previous_keys is set without a current key".to_string());
+            }
+            return Ok(Keyring::default());
+        }
+        let current = parse_key(&current).map_err(|e| format!("Invalid encryption key: {}", e))?;
+        let previous = self.encryption_previous_keys.iter()
+            .map(|key| parse_key(key).map_err(|e| format!("Invalid key in encryption_previous_keys: {}", e)))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Keyring::new(&current, &previous))
+    }
+}
+
+fn write_complete_config(config: &FluxConfig) {
+    let conf_path = "flxc.toml";
+    match toml::to_string(config) {
+        Ok(toml_str) => {
+            let _ = fs::write(conf_path, toml_str);
+        }
+        Err(e) => eprintln!("[WARN] Could not serialize flxc.toml: {e}"),
+    }
+}
+
+pub fn read_flux_toml() -> FluxConfig {
+    let conf_path = "flxc.toml";
+    if !Path::new(conf_path).exists() {
+        // Create default config if missing
+        let default = FluxConfig::default();
+        write_complete_config(&default);
+        return default;
+    }
+    
+    let content = fs::read_to_string(conf_path).unwrap_or_default();
+    
+    let parsed_config = match toml::from_str::<FluxConfig>(&content) {
+        Ok(cfg) => cfg,
+        Err(e) => {
+            eprintln!("[WARN] Could not parse flxc.toml: {e}. Using defaults.");
+            return FluxConfig::default();
+        }
+    };
+    
+    // If any field is missing from the file, rewrite it with complete config
+    // (missing fields were already filled in with their defaults)
+    let present: toml::Table = toml::from_str(&content).unwrap_or_default();
+    let expected = toml::Table::try_from(&parsed_config).unwrap_or_default();
+    if expected.keys().any(|key| !present.contains_key(key)) {
+        write_complete_config(&parsed_config);
+    }
+    
+    parsed_config
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(name: &str, admin: bool) -> AclUser {
+        AclUser {
+            name: name.to_string(),
+            password: "secret".to_string(),
+            keys: vec!["*".to_string()],
+            admin,
+            max_keys: 0,
+            max_bytes: 0,
+            tenant: String::new(),
+        }
+    }
+
+    #[test]
+    fn a_standalone_node_needs_no_cluster_user() {
+        let config = FluxConfig { users: vec![user("app", false)], ..Default::default() };
+        assert!(config.check_cluster_user().is_ok());
+    }
+
+    #[test]
+    fn nodes_talking_to_peers_need_a_cluster_user() {
+        let users = vec![user("app", false), user("nodes", true)];
+        let clustered = FluxConfig { users: users.clone(), cluster_enabled: true, ..Default::default() };
+        assert!(clustered.check_cluster_user().is_err());
+        let replica = FluxConfig { users: users.clone(), replica_of: "10.0.0.1:7379".to_string(), ..Default::default() };
+        assert!(replica.check_cluster_user().is_err());
+
+        let named = FluxConfig { cluster_user: "nodes".to_string(), ..clustered };
+        assert!(named.check_cluster_user().is_ok());
+        assert_eq!(named.peer_credentials().username.as_deref(), Some("nodes"));
+    }
+
+    #[test]
+    fn the_cluster_user_must_be_an_unrestricted_admin() {
+        let config = FluxConfig {
+            users: vec![user("app", false)],
+            cluster_user: "app".to_string(),
+            cluster_enabled: true,
+            ..Default::default()
+        };
+        assert!(config.check_cluster_user().is_err());
+        let missing = FluxConfig { cluster_user: "nobody".to_string(), ..config };
+        assert!(missing.check_cluster_user().is_err());
+    }
+
+    #[test]
+    fn tenant_names_hold_no_colon() {
+        let tenant = |name: &str| TenantRule { name: name.to_string(), max_keys: 0, max_bytes: 0 };
+        assert!(FluxConfig { tenants: vec![tenant("acme")], ..Default::default() }.check_tenants().is_ok());
+        assert!(FluxConfig { tenants: vec![tenant("acme:eu")], ..Default::default() }.check_tenants().is_err());
+        assert!(FluxConfig { tenants: vec![tenant("")], ..Default::default() }.check_tenants().is_err());
+        let users = vec![AclUser { tenant: "acme:eu".to_string(), ..user("app", false) }];
+        assert!(FluxConfig { users, ..Default::default() }.check_tenants().is_err());
+    }
+}
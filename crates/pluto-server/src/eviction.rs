@@ -0,0 +1,204 @@
+use std::sync::atomic::Ordering;
+use serde::{Deserialize, Serialize};
+use pluto_core::cache::{CacheEntry, ServerError, entry_memory, now_ms};
+use pluto_core::protocol::{Command, KeyEventReason};
+use crate::api::apply_write;
+use crate::state::ServerState;
+
+// Once the keys and values take more than maxmemory, the writes of clients
+// first evict keys, picked by maxmemory_policy, until they fit again. The
+// evicted keys are deleted like a client's DEL would, so the replicas and the
+// write-ahead log drop them too; replicas never evict on their own. Watchers
+// of the keys are told of each eviction.
+
+// What makes room once maxmemory is reached
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum EvictionPolicy {
+    // Evict nothing; writes adding to the keyspace are refused instead
+    #[default]
+    Noeviction,
+    // The least recently used of a sample of keys
+    AllkeysLru,
+    // The least frequently used of a sample of keys
+    AllkeysLfu,
+    // The key whose expiry comes first, among the keys with one
+    VolatileTtl,
+}
+
+impl EvictionPolicy {
+    pub fn name(&self) -> &'static str {
+        match self {
+            EvictionPolicy::Noeviction => "noeviction",
+            EvictionPolicy::AllkeysLru => "allkeys-lru",
+            EvictionPolicy::AllkeysLfu => "allkeys-lfu",
+            EvictionPolicy::VolatileTtl => "volatile-ttl",
+        }
+    }
+}
+
+// Keys sampled for each key evicted by the LRU and LFU policies; more come
+// closer to the true least used key, at the cost of a slower eviction
+const SAMPLES: usize = 5;
+
+// Evict keys until the keyspace is back within maxmemory, before `cmd` is
+// applied. When nothing can be evicted, a write adding to the keyspace is
+// refused; one that shrinks or drops keys goes through, making room itself.
+pub fn make_room(state: &mut ServerState, cmd: &Command) -> Result<(), ServerError> {
+    let (maxmemory, policy) = (state.config.maxmemory, state.config.maxmemory_policy);
+    if maxmemory == 0 || state.replication.is_replica() {
+        return Ok(());
+    }
+    while state.cache.used_memory() > maxmemory {
+        let Some(key) = victim(state, policy) else {
+            if !adds(cmd) {
+                return Ok(());
+            }
+            return Err(ServerError::QuotaExceeded(format!(
+                "Used memory is past maxmemory ({} bytes) and maxmemory_policy evicts nothing more", maxmemory
+            )));
+        };
+        let size = state.cache.get(&key).map_or(0, |entry| entry_memory(&key, entry));
+        apply_write(state, Command::DEL { keys: vec![key.clone()] })?;
+        state.stats.evicted_keys.fetch_add(1, Ordering::Relaxed);
+        if state.pubsub.is_watched() {
+            state.pubsub.key_event(&key, KeyEventReason::Evicted, size);
+        }
+    }
+    Ok(())
+}
+
+// Whether a write may add keys or bytes to the keyspace
+fn adds(cmd: &Command) -> bool {
+    match cmd {
+        Command::QUORUM { command, .. } => adds(command),
+        _ => matches!(
+            cmd,
+            Command::SET { .. } | Command::GETSET { .. } | Command::MSETNX { .. } | Command::RESTORE { .. }
+                | Command::SET_MISSING { .. } | Command::COUNTER_INCRBY { .. } | Command::ORSET_ADD { .. }
+        ),
+    }
+}
+
+// The key `policy` evicts next
+fn victim(state: &ServerState, policy: EvictionPolicy) -> Option<String> {
+    let now = now_ms();
+    let least = |rank: fn(&CacheEntry, u64) -> (u64, u64)| {
+        state.cache.sample(SAMPLES).into_iter()
+            .min_by_key(|(_, entry)| rank(entry, now))
+            .map(|(key, _)| key)
+    };
+    match policy {
+        EvictionPolicy::Noeviction => None,
+        EvictionPolicy::AllkeysLru => least(|entry, _| (entry.access.accessed_at(), 0)),
+        // Ties, common among keys read rarely, go to the least recently used
+        EvictionPolicy::AllkeysLfu => least(|entry, now| (entry.access.frequency(now) as u64, entry.access.accessed_at())),
+        EvictionPolicy::VolatileTtl => state.cache.next_expiring(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, RwLock};
+    use pluto_core::cache::ServerError;
+    use pluto_core::protocol::Response;
+    use crate::api::process_command;
+    use crate::clients::{OutputBuffer, OutputLimits};
+    use crate::environment::FluxConfig;
+    use crate::pubsub::{PushMessage, Subscriber};
+
+    fn state_with(maxmemory: usize, maxmemory_policy: EvictionPolicy) -> Arc<RwLock<ServerState>> {
+        let config = FluxConfig { maxmemory, maxmemory_policy, ..Default::default() };
+        Arc::new(RwLock::new(ServerState::new("127.0.0.1:7000".to_string(), config)))
+    }
+
+    fn set(key: &str) -> Command {
+        Command::SET { key: key.to_string(), value: vec![b'v'; 100], visible_at: None }
+    }
+
+    fn get(key: &str) -> Command {
+        Command::GET { key: key.to_string() }
+    }
+
+    #[tokio::test]
+    async fn noeviction_refuses_writes_that_add_but_not_deletes() {
+        let state = state_with(1, EvictionPolicy::Noeviction);
+        process_command(set("a"), &state, None).await.unwrap();
+        let refused = process_command(set("b"), &state, None).await;
+        assert!(matches!(refused, Err(ServerError::QuotaExceeded(_))));
+        process_command(Command::DEL { keys: vec!["a".to_string()] }, &state, None).await.unwrap();
+        process_command(set("b"), &state, None).await.unwrap();
+        assert_eq!(state.read().unwrap().stats.evicted_keys.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn lru_keeps_the_keyspace_within_maxmemory() {
+        let state = state_with(2_000, EvictionPolicy::AllkeysLru);
+        for i in 0..100 {
+            process_command(set(&format!("key{}", i)), &state, None).await.unwrap();
+        }
+        let state = state.read().unwrap();
+        let entry = state.cache.get("key99").unwrap();
+        // Room is made before each write, which may then go past maxmemory
+        assert!(state.cache.used_memory() <= 2_000 + entry_memory("key99", entry));
+        assert_eq!(state.stats.evicted_keys.load(Ordering::Relaxed) as usize, 100 - state.cache.len());
+    }
+
+    #[tokio::test]
+    async fn volatile_ttl_evicts_the_keys_expiring_first() {
+        let state = state_with(0, EvictionPolicy::VolatileTtl);
+        for (key, seconds) in [("later", 600), ("sooner", 60)] {
+            process_command(set(key), &state, None).await.unwrap();
+            process_command(Command::EXPIRE { key: key.to_string(), seconds, jitter_pct: None }, &state, None).await.unwrap();
+        }
+        // Room for these two keys; a write past it evicts before the next one
+        let used = state.read().unwrap().cache.used_memory();
+        state.write().unwrap().config.maxmemory = used;
+        process_command(set("c"), &state, None).await.unwrap();
+        process_command(set("d"), &state, None).await.unwrap();
+        assert!(matches!(process_command(get("sooner"), &state, None).await, Ok(Response::Nil)));
+        assert!(matches!(process_command(get("later"), &state, None).await, Ok(Response::Data(_))));
+        process_command(set("e"), &state, None).await.unwrap();
+        assert!(matches!(process_command(get("later"), &state, None).await, Ok(Response::Nil)));
+        // With no key left to expire, nothing more can go
+        let refused = process_command(set("f"), &state, None).await;
+        assert!(matches!(refused, Err(ServerError::QuotaExceeded(_))));
+        assert_eq!(state.read().unwrap().stats.evicted_keys.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn watchers_are_told_of_evictions() {
+        let state = state_with(1, EvictionPolicy::AllkeysLru);
+        let unlimited = OutputLimits { hard: 0, soft: 0, soft_secs: 0 };
+        let pubsub = state.read().unwrap().pubsub.clone();
+        let mut subscriber = Subscriber::new(pubsub, Arc::new(OutputBuffer::new(unlimited, unlimited)));
+        subscriber.watch(vec!["user:*".to_string()]);
+        process_command(set("user:1"), &state, None).await.unwrap();
+        process_command(set("user:2"), &state, None).await.unwrap();
+        match subscriber.recv().await.map(PushMessage::into_response) {
+            Some(Response::KeyEvent(event)) => {
+                assert_eq!(event.key, "user:1");
+                assert_eq!(event.reason, KeyEventReason::Evicted);
+                assert!(event.size > 0);
+            }
+            other => panic!("expected an eviction event, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn lfu_evicts_the_least_used_key() {
+        let state = state_with(0, EvictionPolicy::AllkeysLfu);
+        let mut state = state.write().unwrap();
+        apply_write(&mut state, set("cold")).unwrap();
+        apply_write(&mut state, set("hot")).unwrap();
+        // Apart from the write, so the reads are the most recent accesses
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        for _ in 0..1_000 {
+            state.cache.read("hot");
+        }
+        // A keyspace as small as a sample is sampled whole
+        assert_eq!(victim(&state, EvictionPolicy::AllkeysLfu).as_deref(), Some("cold"));
+        assert_eq!(victim(&state, EvictionPolicy::AllkeysLru).as_deref(), Some("cold"));
+    }
+}
@@ -0,0 +1,32 @@
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use pluto_core::cache::entry_memory;
+use pluto_core::protocol::KeyEventReason;
+use crate::lazyfree;
+use crate::state::ServerState;
+
+// How often keys past their expiry are removed in the background
+const SWEEP_INTERVAL: Duration = Duration::from_millis(100);
+
+// Expired keys are already invisible to reads; this frees their memory
+pub async fn run_expiry_sweeper(state: Arc<RwLock<ServerState>>) {
+    let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+    loop {
+        interval.tick().await;
+        let mut state = state.write().unwrap();
+        let removed = state.cache.purge_expired();
+        if !removed.is_empty() {
+            state.stats.expired_keys.fetch_add(removed.len() as u64, Ordering::Relaxed);
+            if state.pubsub.is_watched() {
+                for (key, entry) in &removed {
+                    state.pubsub.key_event(key, KeyEventReason::Expired, entry_memory(key, entry));
+                }
+            }
+            if state.tracking.is_active() {
+                state.tracking.invalidate(&removed.iter().map(|(key, _)| key.as_str()).collect::<Vec<_>>());
+            }
+            lazyfree::free_entries(&state, removed.into_iter().map(|(_, entry)| entry).collect());
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::task::JoinSet;
+use pluto_core::cache::ServerError;
+use pluto_core::protocol::{Command, Response};
+use crate::api::apply_write;
+use crate::info::build_info;
+use crate::peer::PeerConnection;
+use crate::state::ServerState;
+
+// Longest a member may take to answer before it is reported as failed
+const MEMBER_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Run a CLUSTER_ command's single-node form on every member of the cluster
+// and combine the answers. Members that fail are listed in the reply rather
+// than failing the whole command, so one dead node doesn't hide the rest.
+pub async fn fan_out(state: &Arc<RwLock<ServerState>>, cmd: Command) -> Result<Response, ServerError> {
+    let per_node = match cmd {
+        Command::CLUSTER_DBSIZE => Command::DBSIZE,
+        Command::CLUSTER_FLUSHALL => Command::FLUSHALL,
+        Command::CLUSTER_INFO { section } => Command::INFO { section },
+        Command::CLUSTER_KEYS { pattern, .. } => Command::KEYS { pattern, chunk: None },
+        cmd => return Err(ServerError::InvalidArgument(format!("{} is not a cluster-wide command", cmd.name()))),
+    };
+    let answers = ask_members(state, &per_node).await;
+    Ok(combine(&per_node, answers))
+}
+
+// Run a single-node command on every member of the cluster, this node
+// included, with the answer or error of each by address
+pub async fn ask_members(state: &Arc<RwLock<ServerState>>, per_node: &Command) -> BTreeMap<String, Result<Response, String>> {
+    let (members, self_addr, credentials) = {
+        let state = state.read().unwrap();
+        let members = if state.cluster_enabled { state.cluster.nodes.clone() } else { Vec::new() };
+        (members, state.cluster.self_addr.clone(), state.config.peer_credentials())
+    };
+
+    // This node answers directly, the others over a connection each, all at once
+    let mut answers = BTreeMap::new();
+    answers.insert(self_addr.clone(), run_local(state, per_node.clone()));
+    let mut requests = JoinSet::new();
+    for member in members.into_iter().filter(|member| *member != self_addr) {
+        let cmd = per_node.clone();
+        let credentials = credentials.clone();
+        requests.spawn(async move {
+            let request = async {
+                PeerConnection::connect(&member, &credentials).await?.send(&[cmd]).await
+            };
+            let answer = match tokio::time::timeout(MEMBER_TIMEOUT, request).await {
+                Ok(Ok(mut responses)) => match responses.pop() {
+                    Some(Response::Error(e)) => Err(e.to_string()),
+                    Some(response) => Ok(response),
+                    None => Err("no response".to_string()),
+                },
+                Ok(Err(e)) => Err(e.to_string()),
+                Err(_) => Err("timed out".to_string()),
+            };
+            (member, answer)
+        });
+    }
+    while let Some(joined) = requests.join_next().await {
+        if let Ok((member, answer)) = joined {
+            answers.insert(member, answer);
+        }
+    }
+    answers
+}
+
+// The single-node form of the command, run on this node
+fn run_local(state: &Arc<RwLock<ServerState>>, cmd: Command) -> Result<Response, String> {
+    match cmd {
+        Command::DBSIZE => Ok(Response::Integer(state.read().unwrap().cache.len() as i64)),
+        Command::KEYS { pattern, .. } => Ok(Response::Keys(state.read().unwrap().cache.keys_matching(&pattern))),
+        Command::INFO { section } => build_info(&state.read().unwrap(), section.as_deref()).map(Response::Info),
+        cmd => apply_write(&mut state.write().unwrap(), cmd).map_err(|e| e.to_string()),
+    }
+}
+
+// Merge the members' answers: key counts are summed, key lists joined,
+// INFO reports listed per member and FLUSHALL counts the members flushed
+fn combine(cmd: &Command, answers: BTreeMap<String, Result<Response, String>>) -> Response {
+    let mut failures = BTreeMap::new();
+    let mut total = 0;
+    let mut keys = BTreeSet::new();
+    let mut info = String::new();
+    for (member, answer) in answers {
+        match answer {
+            Ok(Response::Integer(count)) => total += count,
+            Ok(Response::Keys(member_keys)) => keys.extend(member_keys),
+            Ok(Response::Info(report)) => {
+                info.push_str(&format!("# Node {}\r\n{}\r\n", member, report.trim_end()));
+            }
+            Ok(Response::Success) => total += 1,
+            Ok(other) => {
+                failures.insert(member, format!("unexpected response {:?}", other));
+            }
+            Err(e) => {
+                failures.insert(member, e);
+            }
+        }
+    }
+    let result = match cmd {
+        Command::KEYS { .. } => Response::Keys(keys.into_iter().collect()),
+        Command::INFO { .. } => Response::Info(info),
+        _ => Response::Integer(total),
+    };
+    Response::Aggregate { result: Box::new(result), failures }
+}
@@ -0,0 +1,656 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+use bytes::{Bytes, BytesMut};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use log::{debug, info, warn};
+use pluto_core::cache::{ServerError, WriteStamp, compress_data, decompress_data, dump_entry, entry_value, now_ms, restore_entry};
+use pluto_core::crdt::merge_entries;
+use pluto_core::cluster::TOTAL_SLOTS;
+use pluto_core::codec::{encode_command, parse_response, Encoding};
+use pluto_core::protocol::{Command, Response};
+use crate::api::{apply_geo_write, stamped_write};
+use crate::conflict::{merge, merged_stamp, policy_for, remote_wins, Candidate, ConflictPolicy, ConflictStats};
+use crate::buffer::READ_BUFFER_SIZE;
+use crate::environment::FluxConfig;
+use crate::peer::PeerConnection;
+use crate::raft::Role;
+use crate::state::ServerState;
+
+// Geo-replication keeps a standby cluster in another datacenter a copy of
+// this one. A single member ships for the whole cluster: it follows every
+// member's write stream like a replica would, and sends the writes to the
+// standby in compressed batches over one connection. The standby routes each
+// write to the member owning its slot and refuses client writes until it is
+// promoted. Whenever the channel breaks the standby is flushed and sent every
+// member's keyspace again, so it never keeps keys deleted in between.
+//
+// Two clusters may also ship to each other, each with `geo_active` set. Both
+// take client writes then, and writes to the same key made on both sides are
+// settled by the conflict policies instead of being flushed.
+
+// Written when a standby is promoted, so it stays writable across restarts;
+// removing it makes the node a standby again
+const PROMOTED_FILE: &str = "geo_promoted.json";
+
+// Pause between attempts to reach the standby
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+// How often the member streams are pinged, keeping the idle reaper off them,
+// and the shipper checks it still ships for the same members
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+// Writes queued between the member streams and the standby connection; a
+// standby that can't keep up makes the members drop their streams
+const QUEUE_WRITES: usize = 65536;
+
+// A write as shipped to the standby
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum GeoWrite {
+    Write { command: Command, stamp: Option<WriteStamp> },
+    // Remove every key of the slots, for a FLUSHALL run on a single member
+    FlushSlots(Vec<usize>),
+    // Remove every key, for a FLUSHALL outside a cluster
+    FlushAll,
+    // The keyspace of every member follows; a standby drops its own first
+    Resync,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct GeoBatch {
+    queued_at: u64, // when the oldest write was queued, in ms since the epoch
+    writes: Vec<GeoWrite>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PromotedFile {
+    promoted_at: u64, // ms since the epoch
+}
+
+// Geo-replication role of a node and the counters of both sides, for INFO geo
+pub struct Geo {
+    standby: AtomicBool,
+    // Shipping side
+    pub shipping: AtomicBool,
+    pub link_up: AtomicBool,
+    pub sources_up: AtomicUsize, // member streams followed
+    pub pending: AtomicUsize,    // writes not acknowledged by the standby yet
+    pub oldest_ms: AtomicU64,    // when the oldest of them was queued, 0 when none
+    pub batches_sent: AtomicU64,
+    pub writes_shipped: AtomicU64,
+    pub bytes_raw: AtomicU64,
+    pub bytes_sent: AtomicU64, // after compression
+    // Standby side
+    source: Mutex<Option<String>>,
+    pub batches_applied: AtomicU64,
+    pub writes_applied: AtomicU64,
+    pub lag_ms: AtomicU64,     // age of the oldest write of the last batch when applied
+    pub applied_at: AtomicU64, // when the last batch was applied, 0 before the first
+    pub conflicts: ConflictStats,
+}
+
+impl Geo {
+    pub fn new(config: &FluxConfig) -> Self {
+        let promoted = config.geo_standby && Path::new(PROMOTED_FILE).exists();
+        if promoted {
+            info!("Standby was promoted before, found {}; accepting writes", PROMOTED_FILE);
+        }
+        Geo {
+            standby: AtomicBool::new(config.geo_standby && !promoted),
+            shipping: AtomicBool::new(false),
+            link_up: AtomicBool::new(false),
+            sources_up: AtomicUsize::new(0),
+            pending: AtomicUsize::new(0),
+            oldest_ms: AtomicU64::new(0),
+            batches_sent: AtomicU64::new(0),
+            writes_shipped: AtomicU64::new(0),
+            bytes_raw: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+            source: Mutex::new(None),
+            batches_applied: AtomicU64::new(0),
+            writes_applied: AtomicU64::new(0),
+            lag_ms: AtomicU64::new(0),
+            applied_at: AtomicU64::new(0),
+            conflicts: ConflictStats::default(),
+        }
+    }
+
+    pub fn is_standby(&self) -> bool {
+        self.standby.load(Ordering::SeqCst)
+    }
+
+    // Node shipping writes to this standby, once it sent any
+    pub fn source(&self) -> Option<String> {
+        self.source.lock().unwrap().clone()
+    }
+
+    // Whether a standby turns the command away: every client write, but not
+    // keys moved between its own members by a slot migration
+    pub fn refuses(&self, cmd: &Command, asking: bool) -> bool {
+        self.is_standby()
+            && cmd.is_write()
+            && !asking
+            && !matches!(cmd, Command::MIGRATE { .. } | Command::MIGRATE_SLOTS { .. })
+    }
+
+    // Clear the shipping side's view of a channel that closed
+    fn link_down(&self) {
+        self.link_up.store(false, Ordering::Relaxed);
+        self.sources_up.store(0, Ordering::Relaxed);
+        self.pending.store(0, Ordering::Relaxed);
+        self.oldest_ms.store(0, Ordering::Relaxed);
+    }
+}
+
+// Ship every write of the cluster to `geo_replicate_to` whenever this node is
+// the one shipping, starting over whenever the channel breaks
+pub async fn run(state: Arc<RwLock<ServerState>>) {
+    let (target, geo, shutdown) = {
+        let state = state.read().unwrap();
+        (state.config.geo_replicate_to.clone(), state.geo.clone(), state.shutdown.clone())
+    };
+    if target.is_empty() {
+        return;
+    }
+    loop {
+        if is_shipper(&state) {
+            geo.shipping.store(true, Ordering::Relaxed);
+            tokio::select! {
+                result = ship(&state, &geo, &target) => {
+                    if let Err(e) = result {
+                        warn!("Shipping writes to standby {} stopped: {}", target, e);
+                    }
+                }
+                _ = shutdown.wait() => break,
+            }
+            geo.link_down();
+        }
+        geo.shipping.store(false, Ordering::Relaxed);
+        tokio::select! {
+            _ = tokio::time::sleep(RECONNECT_DELAY) => {}
+            _ = shutdown.wait() => break,
+        }
+    }
+}
+
+// The leader of the metadata log ships for its cluster, a node outside a
+// cluster for itself; replicas leave it to their primary
+fn is_shipper(state: &Arc<RwLock<ServerState>>) -> bool {
+    let state = state.read().unwrap();
+    !state.replication.is_replica() && (!state.cluster_enabled || state.raft.role == Role::Leader)
+}
+
+// Members whose writes are shipped
+fn shipped_members(state: &ServerState) -> Vec<String> {
+    if state.cluster_enabled {
+        state.cluster.nodes.clone()
+    } else {
+        vec![state.cluster.self_addr.clone()]
+    }
+}
+
+// Follow every member and send their writes to the standby until the
+// channel breaks, leadership moves or the members change
+async fn ship(state: &Arc<RwLock<ServerState>>, geo: &Arc<Geo>, target: &str) -> Result<(), ServerError> {
+    let (members, self_addr, credentials, batch_writes, batch_interval) = {
+        let state = state.read().unwrap();
+        (
+            shipped_members(&state),
+            state.cluster.self_addr.clone(),
+            state.config.peer_credentials(),
+            state.config.geo_batch_writes.max(1),
+            Duration::from_millis(state.config.geo_batch_interval_ms),
+        )
+    };
+    // Both clusters share their node credentials, like a primary and its replicas
+    let mut standby = PeerConnection::connect_with(target, &credentials, Encoding::Bincode).await?;
+    geo.link_up.store(true, Ordering::Relaxed);
+    info!("Shipping the writes of {} members to standby {}", members.len(), target);
+
+    // The standby starts over from a copy of every member's keyspace
+    let (tx, mut rx) = mpsc::channel(QUEUE_WRITES);
+    geo.pending.store(1, Ordering::Relaxed);
+    let _ = tx.send((now_ms(), GeoWrite::Resync)).await;
+    let mut readers = JoinSet::new();
+    for member in members.clone() {
+        readers.spawn(follow(state.clone(), geo.clone(), member, tx.clone()));
+    }
+    drop(tx);
+
+    let mut batch = Vec::new();
+    let mut oldest = 0;
+    let mut flush_at = tokio::time::Instant::now();
+    let mut check = tokio::time::interval(HEARTBEAT_INTERVAL);
+    loop {
+        // Fill the batch until it is full or its oldest write waited long enough
+        tokio::select! {
+            write = rx.recv() => {
+                let Some((queued_at, write)) = write else {
+                    return Err(ServerError::InvalidArgument("Every member stream closed".to_string()));
+                };
+                if batch.is_empty() {
+                    oldest = queued_at;
+                    flush_at = tokio::time::Instant::now() + batch_interval;
+                    geo.oldest_ms.store(queued_at, Ordering::Relaxed);
+                }
+                batch.push(write);
+                if batch.len() < batch_writes {
+                    continue;
+                }
+            }
+            _ = tokio::time::sleep_until(flush_at), if !batch.is_empty() => {}
+            Some(joined) = readers.join_next() => {
+                let reason = match joined {
+                    Ok(Err(e)) => e.to_string(),
+                    _ => "stream ended".to_string(),
+                };
+                return Err(ServerError::InvalidArgument(format!("Lost a member stream: {}", reason)));
+            }
+            _ = check.tick() => {
+                let (shipper, current) = (is_shipper(state), shipped_members(&state.read().unwrap()));
+                if !shipper || current != members {
+                    info!("Cluster changed, shipping to standby {} again", target);
+                    return Ok(());
+                }
+                continue;
+            }
+        }
+
+        let writes = std::mem::take(&mut batch);
+        let count = writes.len();
+        let json = serde_json::to_vec(&GeoBatch { queued_at: oldest, writes })?;
+        let compressed = compress_data(&json)?;
+        let cmd = Command::GEO_APPLY { source: self_addr.clone(), batch: compressed.to_vec(), forwarded: false };
+        match standby.send(&[cmd]).await?.pop() {
+            Some(Response::Integer(_)) => {}
+            Some(Response::Error(e)) => return Err(ServerError::InvalidArgument(format!("Standby refused a batch: {}", e))),
+            other => return Err(ServerError::InvalidArgument(format!("Unexpected response from standby: {:?}", other))),
+        }
+        geo.batches_sent.fetch_add(1, Ordering::Relaxed);
+        geo.writes_shipped.fetch_add(count as u64, Ordering::Relaxed);
+        geo.bytes_raw.fetch_add(json.len() as u64, Ordering::Relaxed);
+        geo.bytes_sent.fetch_add(compressed.len() as u64, Ordering::Relaxed);
+        geo.pending.fetch_sub(count, Ordering::Relaxed);
+        geo.oldest_ms.store(0, Ordering::Relaxed);
+    }
+}
+
+// Follow one member's write stream, as its replicas do, and queue its writes
+async fn follow(
+    state: Arc<RwLock<ServerState>>,
+    geo: Arc<Geo>,
+    member: String,
+    tx: mpsc::Sender<(u64, GeoWrite)>,
+) -> Result<(), ServerError> {
+    let (credentials, address) = {
+        let state = state.read().unwrap();
+        (state.config.peer_credentials(), format!("{} (geo)", state.cluster.self_addr))
+    };
+    let mut stream = TcpStream::connect(&member).await?;
+    let mut request = Vec::new();
+    if let Some(auth) = credentials.auth() {
+        request.extend(encode_command(&auth, Encoding::Json)?);
+    }
+    request.extend(encode_command(&Command::SYNC { address }, Encoding::Json)?);
+    stream.write_all(&request).await?;
+
+    // Pings rather than REPLACK keep the link alive, so the stream never
+    // counts towards WAIT and write quorums
+    let mut heartbeat = tokio::time::interval(HEARTBEAT_INTERVAL);
+    let mut buf = BytesMut::with_capacity(READ_BUFFER_SIZE);
+    loop {
+        let Some((response, used)) = parse_response(&buf, Encoding::Json).map_err(ServerError::Encoding)? else {
+            tokio::select! {
+                read = stream.read_buf(&mut buf) => {
+                    if read? == 0 {
+                        return Err(ServerError::Io(std::io::ErrorKind::UnexpectedEof.into()));
+                    }
+                }
+                _ = heartbeat.tick() => {
+                    stream.write_all(&encode_command(&Command::PING { message: None }, Encoding::Json)?).await?;
+                }
+            }
+            continue;
+        };
+        let _ = buf.split_to(used);
+        let write = match response {
+            // The node the key moved to ships it
+            Response::Replicate { local_only: true, .. } => continue,
+            Response::Replicate { command: Command::FLUSHALL, .. } => {
+                let state = state.read().unwrap();
+                if state.cluster_enabled {
+                    let owned = (0..TOTAL_SLOTS)
+                        .filter(|slot| state.cluster.slot_owner(*slot).is_some_and(|node| node.address == member))
+                        .collect();
+                    GeoWrite::FlushSlots(owned)
+                } else {
+                    GeoWrite::FlushAll
+                }
+            }
+            Response::Replicate { command, stamp, .. } => GeoWrite::Write { command, stamp },
+            Response::Integer(offset) => {
+                debug!("Following {} for the standby from offset {}", member, offset);
+                geo.sources_up.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+            Response::Success | Response::Pong => continue,
+            Response::Error(e) => return Err(ServerError::InvalidArgument(format!("{} refused SYNC: {}", member, e))),
+            other => return Err(ServerError::InvalidArgument(format!("Unexpected response from {}: {:?}", member, other))),
+        };
+        geo.pending.fetch_add(1, Ordering::Relaxed);
+        if tx.send((now_ms(), write)).await.is_err() {
+            return Ok(());
+        }
+    }
+}
+
+// Apply a batch shipped to this standby or active cluster, forwarding the
+// writes of slots other members own to them; replies with the number of
+// writes in it
+pub async fn apply(
+    state: &Arc<RwLock<ServerState>>,
+    source: String,
+    batch: Vec<u8>,
+    forwarded: bool,
+) -> Result<Response, ServerError> {
+    let (geo, credentials, merge_command) = {
+        let state = state.read().unwrap();
+        if !state.geo.is_standby() && !state.config.geo_active {
+            return Err(ServerError::InvalidArgument("This node takes no geo-replicated writes".to_string()));
+        }
+        (state.geo.clone(), state.config.peer_credentials(), state.config.geo_conflict_merge_command.clone())
+    };
+    let batch: GeoBatch = serde_json::from_slice(&decompress_data(&batch)?)?;
+    let count = batch.writes.len();
+
+    // Split the writes by the member owning their slot, keeping their order
+    let (local, remote, merges) = {
+        let mut state = state.write().unwrap();
+        let members = if forwarded { Vec::new() } else { shipped_members(&state) };
+        let self_addr = state.cluster.self_addr.clone();
+        let mut local = Vec::new();
+        let mut remote: BTreeMap<String, Vec<GeoWrite>> = BTreeMap::new();
+        for write in batch.writes {
+            let (commands, stamp) = match write {
+                GeoWrite::Write { command: Command::DEL { keys }, stamp } if keys.len() > 1 => {
+                    (keys.into_iter().map(|key| Command::DEL { keys: vec![key] }).collect(), stamp)
+                }
+                GeoWrite::Write { command: Command::UNLINK { keys }, stamp } if keys.len() > 1 => {
+                    (keys.into_iter().map(|key| Command::UNLINK { keys: vec![key] }).collect(), stamp)
+                }
+                // Keys set there are set here too, whether or not they exist
+                GeoWrite::Write { command: Command::MSETNX { entries }, stamp } => {
+                    (entries.into_iter().map(|(key, value)| Command::SET { key, value: value.into(), visible_at: None }).collect(), stamp)
+                }
+                GeoWrite::Write { command, stamp } => (vec![command], stamp),
+                // A flush reaches every member
+                flush => {
+                    for member in members.iter().filter(|member| **member != self_addr) {
+                        remote.entry(member.clone()).or_default().push(flush.clone());
+                    }
+                    local.push(flush);
+                    continue;
+                }
+            };
+            for cmd in commands {
+                let owner = cmd.keys().first()
+                    .and_then(|key| state.cluster.slot_owner(state.acl.key_slot(key)))
+                    .map(|node| node.address.clone())
+                    .filter(|owner| *owner != self_addr && members.contains(owner));
+                let write = GeoWrite::Write { command: cmd, stamp };
+                match owner {
+                    Some(owner) => remote.entry(owner).or_default().push(write),
+                    None => local.push(write),
+                }
+            }
+        }
+        let applied = local.len();
+        let merges = apply_local(&mut state, local);
+        (applied, remote, merges)
+    };
+
+    let mut forwards = JoinSet::new();
+    for (member, writes) in remote {
+        let source = source.clone();
+        let credentials = credentials.clone();
+        let json = serde_json::to_vec(&GeoBatch { queued_at: batch.queued_at, writes })?;
+        forwards.spawn(async move {
+            let cmd = Command::GEO_APPLY { source, batch: compress_data(&json)?.to_vec(), forwarded: true };
+            let response = PeerConnection::connect_with(&member, &credentials, Encoding::Bincode).await?.send(&[cmd]).await?.pop();
+            match response {
+                Some(Response::Integer(_)) => Ok(()),
+                Some(Response::Error(e)) => Err(ServerError::InvalidArgument(format!("{} refused forwarded writes: {}", member, e))),
+                other => Err(ServerError::InvalidArgument(format!("Unexpected response from {}: {:?}", member, other))),
+            }
+        });
+    }
+    while let Some(joined) = forwards.join_next().await {
+        joined.map_err(|e| ServerError::InvalidArgument(e.to_string()))??;
+    }
+    for pending in merges {
+        settle_merge(state, &geo, &merge_command, pending).await;
+    }
+
+    let now = now_ms();
+    *geo.source.lock().unwrap() = Some(source);
+    geo.batches_applied.fetch_add(1, Ordering::Relaxed);
+    geo.writes_applied.fetch_add(local as u64, Ordering::Relaxed);
+    geo.lag_ms.store(now.saturating_sub(batch.queued_at), Ordering::Relaxed);
+    geo.applied_at.store(now, Ordering::Relaxed);
+    Ok(Response::Integer(count as i64))
+}
+
+// Apply writes to this node's keyspace, streaming them to its own replicas.
+// Conflicts the merge policy settles are returned, to merge without the lock.
+fn apply_local(state: &mut ServerState, writes: Vec<GeoWrite>) -> Vec<PendingMerge> {
+    let active = state.config.geo_active;
+    let mut merges = Vec::new();
+    for write in writes {
+        let (mut cmd, mut stamp) = match write {
+            GeoWrite::Write { command, stamp } => (command, stamp),
+            // An active cluster keeps its own keys when the other one starts over
+            GeoWrite::Resync if active => continue,
+            GeoWrite::Resync | GeoWrite::FlushAll => (Command::FLUSHALL, None),
+            GeoWrite::FlushSlots(slots) => {
+                let slots: HashSet<usize> = slots.into_iter().collect();
+                let keys: Vec<String> = state.cache.keys()
+                    .map(|key| key.to_string())
+                    .filter(|key| slots.contains(&state.acl.key_slot(key)))
+                    .collect();
+                if keys.is_empty() {
+                    continue;
+                }
+                (Command::DEL { keys }, None)
+            }
+        };
+        if active && let Some(remote) = stamp {
+            match resolve(state, &cmd, remote) {
+                Resolution::Apply => {}
+                Resolution::Replace(merged, merged_stamp) => (cmd, stamp) = (merged, Some(merged_stamp)),
+                Resolution::Skip => continue,
+                Resolution::Merge(pending) => {
+                    merges.push(pending);
+                    continue;
+                }
+            }
+        }
+        // Deleting a key the standby never had is expected after a resync
+        if let Err(e) = apply_geo_write(state, cmd, stamp) {
+            debug!("Geo-replicated write failed: {}", e);
+        }
+    }
+    merges
+}
+
+// What becomes of a write from the other cluster
+enum Resolution {
+    Apply,
+    Replace(Command, WriteStamp), // apply this write instead
+    Skip,
+    Merge(PendingMerge),
+}
+
+// A conflict left to the merge command
+struct PendingMerge {
+    key: String,
+    local: WriteStamp,           // stamp of the local entry it was found with
+    remote: (Command, WriteStamp), // the other cluster's write, applied if it wins without a merge
+    remote_newer: bool,
+    older: Bytes,
+    newer: Bytes,
+}
+
+// Settle a write from the other cluster against the local entry. A write
+// with a higher version was made after the other side saw ours and simply
+// replaces it; otherwise both were written concurrently and the key's policy
+// picks the winner.
+fn resolve(state: &ServerState, cmd: &Command, remote: WriteStamp) -> Resolution {
+    let Some(key) = cmd.keys().first().map(|key| key.to_string()) else {
+        return Resolution::Apply;
+    };
+    let Some(local) = state.cache.get(&key) else {
+        return Resolution::Apply;
+    };
+    // Two states of a CRDT merge into one holding the updates of both. The
+    // merged state isn't shipped back: the other side merges ours the same way.
+    if let Command::RESTORE { payload, .. } = cmd
+        && local.crdt
+        && let Ok(remote_entry) = restore_entry(payload, now_ms())
+        && let Ok(merged) = merge_entries(&key, local, &remote_entry) {
+        let payload = dump_entry(&merged, now_ms()).to_vec();
+        return Resolution::Replace(Command::RESTORE { key, payload, replace: true }, merged_stamp(local.stamp, remote));
+    }
+    if remote.version > local.stamp.version {
+        return Resolution::Apply;
+    }
+    let remote_value = match cmd {
+        Command::SET { value, .. } => Some(Bytes::from(value.clone())),
+        Command::RESTORE { payload, .. } => {
+            match restore_entry(payload, now_ms()).and_then(|entry| entry_value(&entry)) {
+                Ok(value) => Some(value),
+                Err(_) => return Resolution::Apply, // fails the same way applied
+            }
+        }
+        Command::DEL { .. } | Command::UNLINK { .. } => None,
+        _ => return Resolution::Apply,
+    };
+    let Ok(local_value) = entry_value(local) else {
+        return Resolution::Apply;
+    };
+    // Our own write coming back, after the other side took it
+    if local.stamp == remote && remote_value.as_ref() == Some(&local_value) {
+        return Resolution::Skip;
+    }
+
+    let conflicts = &state.geo.conflicts;
+    conflicts.detected.fetch_add(1, Ordering::Relaxed);
+    let policy = policy_for(&state.config, &key);
+    let local_candidate = Candidate { stamp: local.stamp, value: Some(&local_value) };
+    let remote_candidate = Candidate { stamp: remote, value: remote_value.as_deref() };
+    let remote_newer = remote_wins(policy, &local_candidate, &remote_candidate);
+    if policy == ConflictPolicy::Merge && let Some(remote_value) = remote_value.clone() {
+        let (older, newer) = if remote_newer { (local_value, remote_value) } else { (remote_value, local_value) };
+        return Resolution::Merge(PendingMerge {
+            key,
+            local: local.stamp,
+            remote: (cmd.clone(), remote),
+            remote_newer,
+            older,
+            newer,
+        });
+    }
+    if remote_newer {
+        conflicts.took_remote.fetch_add(1, Ordering::Relaxed);
+        Resolution::Apply
+    } else {
+        conflicts.kept_local.fetch_add(1, Ordering::Relaxed);
+        Resolution::Skip
+    }
+}
+
+// Run the merge command on a conflict and store its result, which is shipped
+// back so the other cluster converges on it too. A failed merge keeps the
+// newer write; an entry written again meanwhile is left to that write.
+async fn settle_merge(state: &Arc<RwLock<ServerState>>, geo: &Geo, command: &str, pending: PendingMerge) {
+    let merged = merge(command, &pending.key, &pending.older, &pending.newer).await;
+    let mut state = state.write().unwrap();
+    if state.cache.get(&pending.key).map(|entry| entry.stamp) != Some(pending.local) {
+        return;
+    }
+    let (remote_cmd, remote_stamp) = pending.remote;
+    let result = match merged {
+        Ok(value) => {
+            geo.conflicts.merged.fetch_add(1, Ordering::Relaxed);
+            let stamp = merged_stamp(pending.local, remote_stamp);
+            stamped_write(&mut state, Command::SET { key: pending.key.clone(), value, visible_at: None }, Some(stamp), false)
+        }
+        Err(e) => {
+            warn!("Merging the conflicting writes of {} failed, keeping the newer one: {}", pending.key, e);
+            geo.conflicts.merge_failed.fetch_add(1, Ordering::Relaxed);
+            if !pending.remote_newer {
+                return;
+            }
+            apply_geo_write(&mut state, remote_cmd, Some(remote_stamp))
+        }
+    };
+    if let Err(e) = result {
+        debug!("Settling the conflict on {} failed: {}", pending.key, e);
+    }
+}
+
+// Make this standby writable, along with the other members of its cluster
+// unless `local` is set
+pub async fn promote(state: &Arc<RwLock<ServerState>>, local: bool) -> Result<Response, ServerError> {
+    let (geo, configured, others, credentials) = {
+        let state = state.read().unwrap();
+        let self_addr = state.cluster.self_addr.clone();
+        let others: Vec<String> = if state.cluster_enabled && !local {
+            state.cluster.nodes.iter().filter(|node| **node != self_addr).cloned().collect()
+        } else {
+            Vec::new()
+        };
+        (state.geo.clone(), state.config.geo_standby, others, state.config.peer_credentials())
+    };
+    if !configured {
+        return Err(ServerError::InvalidArgument("This node is not a geo-replication standby".to_string()));
+    }
+    // Promoting again is harmless, so a partly promoted cluster can be retried
+    if geo.standby.swap(false, Ordering::SeqCst) {
+        persist(&PromotedFile { promoted_at: now_ms() })?;
+        warn!("Promoted from geo-replication standby, accepting writes");
+    }
+
+    let mut failures = Vec::new();
+    for member in others {
+        let promoted = async {
+            PeerConnection::connect(&member, &credentials).await?.send(&[Command::GEO_PROMOTE { local: true }]).await
+        };
+        match promoted.await {
+            Ok(responses) if matches!(responses.as_slice(), [Response::Success]) => {}
+            Ok(responses) => failures.push(format!("{} ({:?})", member, responses)),
+            Err(e) => failures.push(format!("{} ({})", member, e)),
+        }
+    }
+    if !failures.is_empty() {
+        return Err(ServerError::InvalidArgument(format!("Promoted this node but not {}", failures.join(", "))));
+    }
+    Ok(Response::Success)
+}
+
+// Written like the cluster file: to a temporary name, synced, then renamed
+fn persist(file: &PromotedFile) -> std::io::Result<()> {
+    let json = serde_json::to_string_pretty(file)?;
+    let tmp_path = format!("{}.tmp", PROMOTED_FILE);
+    let mut tmp = fs::File::create(&tmp_path)?;
+    tmp.write_all(json.as_bytes())?;
+    tmp.sync_all()?;
+    fs::rename(&tmp_path, PROMOTED_FILE)
+}
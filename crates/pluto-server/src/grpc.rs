@@ -0,0 +1,208 @@
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use log::{error, info};
+use tokio_stream::{Stream, StreamExt};
+use tonic::{Request, Response as GrpcResponse, Status, Streaming};
+use pluto_core::cache::ServerError;
+use pluto_core::protocol::{Command, Response};
+use crate::api::execute_command;
+use crate::http::http_caller;
+use crate::middleware::{Caller, Transport};
+use crate::network::{self, PROTECTED_MODE_REFUSAL};
+use crate::state::ServerState;
+
+pub mod proto {
+    tonic::include_proto!("pluto.v1");
+}
+
+use proto::cache_server::{Cache, CacheServer};
+use proto::*;
+
+// Size of the chunks used when streaming a value back to the client
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
+type SharedState = Arc<RwLock<ServerState>>;
+
+// gRPC front end running the same commands as the native protocol
+pub struct GrpcService {
+    state: SharedState,
+}
+
+impl GrpcService {
+    async fn run(&self, caller: Caller, cmd: Command) -> Result<Response, Status> {
+        execute_command(cmd, &self.state, caller).await.map_err(to_status)
+    }
+}
+
+// The caller the interceptor found the request's credentials to name
+fn caller<T>(request: &Request<T>) -> Result<Caller, Status> {
+    request.extensions().get::<Caller>().cloned()
+        .ok_or_else(|| Status::unauthenticated("Authentication required"))
+}
+
+fn to_status(error: ServerError) -> Status {
+    match error {
+        ServerError::KeyNotFound(msg) => Status::not_found(msg),
+        ServerError::InvalidArgument(msg) | ServerError::InvalidCommand(msg) => Status::invalid_argument(msg),
+        ServerError::Unauthorized(msg) => Status::unauthenticated(msg),
+        ServerError::Forbidden(msg) => Status::permission_denied(msg),
+        ServerError::WrongType(msg) => Status::failed_precondition(msg),
+        ServerError::QuorumNotReached(msg) => Status::unavailable(msg),
+        ServerError::ClusterDown(msg) => Status::unavailable(msg),
+        ServerError::Corruption(msg) => Status::data_loss(msg),
+        ServerError::Origin(msg) => Status::unavailable(msg),
+        ServerError::QuotaExceeded(msg) => Status::resource_exhausted(msg),
+        ServerError::Timeout(msg) => Status::deadline_exceeded(msg),
+        other => Status::internal(other.to_string()),
+    }
+}
+
+fn unexpected(response: Response) -> Status {
+    match response {
+        // Another node serves the key; gRPC clients aren't cluster aware, so name it
+        Response::Moved { slot, address } | Response::Ask { slot, address } => {
+            Status::failed_precondition(format!("MOVED {} {}", slot, address))
+        }
+        response => Status::internal(format!("Unexpected response: {:?}", response)),
+    }
+}
+
+#[tonic::async_trait]
+impl Cache for GrpcService {
+    async fn set(&self, request: Request<SetRequest>) -> Result<GrpcResponse<SetResponse>, Status> {
+        let caller = caller(&request)?;
+        let SetRequest { key, value } = request.into_inner();
+        self.run(caller, Command::SET { key, value, visible_at: None }).await?;
+        Ok(GrpcResponse::new(SetResponse {}))
+    }
+
+    async fn get(&self, request: Request<GetRequest>) -> Result<GrpcResponse<GetResponse>, Status> {
+        let caller = caller(&request)?;
+        let key = request.into_inner().key;
+        match self.run(caller, Command::GET { key: key.clone() }).await? {
+            Response::Data(data) | Response::Stale(data) => Ok(GrpcResponse::new(GetResponse { value: data.to_vec() })),
+            Response::Missing => Err(Status::not_found("cached as missing")),
+            Response::Nil => Err(Status::not_found(key)),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    async fn del(&self, request: Request<DelRequest>) -> Result<GrpcResponse<DelResponse>, Status> {
+        let caller = caller(&request)?;
+        let keys = request.into_inner().keys;
+        self.run(caller, Command::DEL { keys }).await?;
+        Ok(GrpcResponse::new(DelResponse {}))
+    }
+
+    async fn exists(&self, request: Request<ExistsRequest>) -> Result<GrpcResponse<ExistsResponse>, Status> {
+        let caller = caller(&request)?;
+        let key = request.into_inner().key;
+        match self.run(caller, Command::EXISTS { key }).await? {
+            Response::Exists(exists) => Ok(GrpcResponse::new(ExistsResponse { exists })),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    async fn set_stream(&self, request: Request<Streaming<SetChunk>>) -> Result<GrpcResponse<SetResponse>, Status> {
+        let caller = caller(&request)?;
+        let mut chunks = request.into_inner();
+        let mut key = None;
+        let mut value = Vec::new();
+        while let Some(chunk) = chunks.message().await? {
+            if key.is_none() {
+                key = Some(chunk.key);
+            }
+            value.extend_from_slice(&chunk.data);
+        }
+        let key = key.ok_or_else(|| Status::invalid_argument("empty SetStream"))?;
+        self.run(caller, Command::SET { key, value, visible_at: None }).await?;
+        Ok(GrpcResponse::new(SetResponse {}))
+    }
+
+    type GetStreamStream = Pin<Box<dyn Stream<Item = Result<ValueChunk, Status>> + Send>>;
+
+    async fn get_stream(&self, request: Request<GetRequest>) -> Result<GrpcResponse<Self::GetStreamStream>, Status> {
+        let caller = caller(&request)?;
+        let key = request.into_inner().key;
+        let data = match self.run(caller, Command::GET { key: key.clone() }).await? {
+            Response::Data(data) | Response::Stale(data) => data,
+            Response::Missing => return Err(Status::not_found("cached as missing")),
+            Response::Nil => return Err(Status::not_found(key)),
+            other => return Err(unexpected(other)),
+        };
+        // Chunks are slices of the shared value, so nothing is copied until encoding
+        let chunks: Vec<_> = (0..data.len())
+            .step_by(STREAM_CHUNK_SIZE)
+            .map(|start| data.slice(start..(start + STREAM_CHUNK_SIZE).min(data.len())))
+            .collect();
+        let stream = tokio_stream::iter(chunks).map(|chunk| Ok(ValueChunk { data: chunk.to_vec() }));
+        Ok(GrpcResponse::new(Box::pin(stream)))
+    }
+
+    async fn cluster_join(&self, request: Request<ClusterJoinRequest>) -> Result<GrpcResponse<ClusterResponse>, Status> {
+        let caller = caller(&request)?;
+        let address = request.into_inner().address;
+        self.run(caller, Command::CLUSTER_JOIN { address }).await?;
+        Ok(GrpcResponse::new(ClusterResponse {}))
+    }
+
+    async fn cluster_remove(&self, request: Request<ClusterRemoveRequest>) -> Result<GrpcResponse<ClusterResponse>, Status> {
+        let caller = caller(&request)?;
+        let address = request.into_inner().address;
+        self.run(caller, Command::CLUSTER_REMOVE { address }).await?;
+        Ok(GrpcResponse::new(ClusterResponse {}))
+    }
+
+    async fn cluster_slots(&self, request: Request<ClusterSlotsRequest>) -> Result<GrpcResponse<ClusterSlotsResponse>, Status> {
+        let caller = caller(&request)?;
+        match self.run(caller, Command::CLUSTER_SLOTS).await? {
+            Response::Slots(json) => Ok(GrpcResponse::new(ClusterSlotsResponse { json })),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    async fn node_info(&self, request: Request<NodeInfoRequest>) -> Result<GrpcResponse<NodeInfoResponse>, Status> {
+        let caller = caller(&request)?;
+        match self.run(caller, Command::NODE_INFO).await? {
+            Response::NodeInfo { node_id, address, .. } => Ok(GrpcResponse::new(NodeInfoResponse { node_id, address })),
+            other => Err(unexpected(other)),
+        }
+    }
+}
+
+// Serve the gRPC API on every given address
+pub async fn serve(addrs: Vec<SocketAddr>, state: SharedState) {
+    let mut servers = tokio::task::JoinSet::new();
+    let config = state.read().unwrap().config.clone();
+    // Refuse the clients protected mode keeps out, then find who the rest
+    // are from the credentials of their `authorization` metadata, given as
+    // to the REST gateway
+    let authenticate = {
+        let state = state.clone();
+        move |mut request: Request<()>| {
+            let addr = request.remote_addr();
+            if addr.is_some_and(|addr| network::protected_refuses(&config, addr)) {
+                return Err(Status::permission_denied(PROTECTED_MODE_REFUSAL));
+            }
+            let headers = request.metadata().clone().into_headers();
+            let caller = http_caller(&state.read().unwrap(), &headers, Transport::Grpc, addr).map_err(to_status)?;
+            request.extensions_mut().insert(caller);
+            Ok(request)
+        }
+    };
+    for addr in addrs {
+        let service = CacheServer::with_interceptor(GrpcService { state: state.clone() }, authenticate.clone());
+        info!("gRPC API listening on {}", addr);
+        servers.spawn(async move {
+            if let Err(e) = tonic::transport::Server::builder()
+                .add_service(service)
+                .serve(addr)
+                .await
+            {
+                error!("gRPC server error on {}: {}", addr, e);
+            }
+        });
+    }
+    while servers.join_next().await.is_some() {}
+}
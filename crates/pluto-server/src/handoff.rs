@@ -0,0 +1,165 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use log::{info, warn};
+use pluto_core::cache::ServerError;
+use pluto_core::cluster::{ClusterState, MetaCommand, TOTAL_SLOTS};
+use pluto_core::protocol::{Command, Response, SlotState};
+use crate::peer::{PeerConnection, PeerCredentials};
+use crate::raft;
+use crate::state::ServerState;
+
+// A node joins owning no slots. The slots a rebalance would give it, and any
+// others it would move, are then handed over one source and target at a
+// time: the target imports them, the source migrates their keys and only
+// then do they change owner, so a join doesn't turn them into misses.
+
+// How long the new member may take to appear in the slot map
+const MEMBER_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How long a node may take to accept a slot mark, while it catches up with
+// the metadata log
+const SETSLOT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How often the source is asked whether its migration finished
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+// Progress of the current or last handoff, for INFO cluster
+pub struct Handoff {
+    running: AtomicBool,
+    pub slots_total: AtomicUsize,
+    pub slots_moved: AtomicUsize,
+}
+
+impl Handoff {
+    pub fn new() -> Self {
+        Handoff {
+            running: AtomicBool::new(false),
+            slots_total: AtomicUsize::new(0),
+            slots_moved: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::SeqCst)
+    }
+}
+
+impl Default for Handoff {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Hand slots over to `joiner` in the background once it is a member
+pub fn start(state: &Arc<RwLock<ServerState>>, joiner: String) -> Result<(), ServerError> {
+    let handoff = state.read().unwrap().handoff.clone();
+    if handoff.running.swap(true, Ordering::SeqCst) {
+        return Err(ServerError::InvalidArgument("Slots are still being handed over to the last node to join".to_string()));
+    }
+    let state = state.clone();
+    tokio::spawn(async move {
+        match run(&state, &handoff, &joiner).await {
+            Ok(()) => info!("Handed {} slots over after {} joined", handoff.slots_moved.load(Ordering::SeqCst), joiner),
+            Err(e) => warn!("Handing slots over to {} stopped: {}", joiner, e),
+        }
+        handoff.running.store(false, Ordering::SeqCst);
+    });
+    Ok(())
+}
+
+async fn run(state: &Arc<RwLock<ServerState>>, handoff: &Handoff, joiner: &str) -> Result<(), ServerError> {
+    // The join may have been committed by another node; wait to apply it
+    let deadline = Instant::now() + MEMBER_TIMEOUT;
+    while !state.read().unwrap().cluster.nodes.iter().any(|node| node == joiner) {
+        if Instant::now() > deadline {
+            return Err(ServerError::InvalidArgument(format!("{} did not become a member in time", joiner)));
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+    let (moves, credentials) = {
+        let state = state.read().unwrap();
+        let mut planned = state.cluster.clone();
+        planned.rebalance_slots();
+        (slot_moves(&state.cluster, &planned), state.config.peer_credentials())
+    };
+    handoff.slots_total.store(moves.values().map(Vec::len).sum(), Ordering::SeqCst);
+    handoff.slots_moved.store(0, Ordering::SeqCst);
+
+    for ((from, to), slots) in moves {
+        // Slots nobody served have no keys to move
+        if let Some(from) = &from {
+            info!("Moving {} slots from {} to {}", slots.len(), from, to);
+            setslot(&to, &credentials, &slots, SlotState::IMPORTING { address: from.clone() }).await?;
+            setslot(from, &credentials, &slots, SlotState::MIGRATING { address: to.clone() }).await?;
+            migrate(from, &credentials, &slots, &to).await?;
+        }
+        raft::propose(state, MetaCommand::AssignSlots { slots: slots.clone(), address: Some(to) }, true).await?;
+        handoff.slots_moved.fetch_add(slots.len(), Ordering::SeqCst);
+    }
+    Ok(())
+}
+
+// Slots whose owner differs between two slot maps, grouped by old and new owner
+fn slot_moves(current: &ClusterState, planned: &ClusterState) -> BTreeMap<(Option<String>, String), Vec<usize>> {
+    let mut moves: BTreeMap<(Option<String>, String), Vec<usize>> = BTreeMap::new();
+    for slot in 0..TOTAL_SLOTS {
+        let from = current.slot_owner(slot).map(|node| node.address.clone());
+        let Some(to) = planned.slot_owner(slot).map(|node| node.address.clone()) else {
+            continue;
+        };
+        if from.as_ref() != Some(&to) {
+            moves.entry((from, to)).or_default().push(slot);
+        }
+    }
+    moves
+}
+
+async fn setslot(address: &str, credentials: &PeerCredentials, slots: &[usize], slot_state: SlotState) -> Result<(), ServerError> {
+    let deadline = Instant::now() + SETSLOT_TIMEOUT;
+    loop {
+        let cmd = Command::CLUSTER_SETSLOT { slots: slots.to_vec(), state: slot_state.clone() };
+        match PeerConnection::connect(address, credentials).await?.send(&[cmd]).await?.pop() {
+            Some(Response::Success) => return Ok(()),
+            // A node that hasn't applied the join yet doesn't know the other end
+            Some(Response::Error(e)) if Instant::now() < deadline => {
+                warn!("{} refused CLUSTER_SETSLOT, retrying: {}", address, e);
+                tokio::time::sleep(POLL_INTERVAL).await;
+            }
+            other => return Err(unexpected(address, other)),
+        }
+    }
+}
+
+// Migrate the keys of `slots` and wait until the source is done
+async fn migrate(from: &str, credentials: &PeerCredentials, slots: &[usize], to: &str) -> Result<(), ServerError> {
+    let mut peer = PeerConnection::connect(from, credentials).await?;
+    match peer.send(&[Command::MIGRATE_SLOTS { slots: slots.to_vec(), address: to.to_string() }]).await?.pop() {
+        Some(Response::Success) => {}
+        other => return Err(unexpected(from, other)),
+    }
+    loop {
+        tokio::time::sleep(POLL_INTERVAL).await;
+        let info = match peer.send(&[Command::INFO { section: Some("cluster".to_string()) }]).await?.pop() {
+            Some(Response::Info(info)) => info,
+            other => return Err(unexpected(from, other)),
+        };
+        let field = |name: &str| info.lines().find_map(|line| line.strip_prefix(name)?.strip_prefix(':')).map(str::trim);
+        if field("migrating_slots") == Some("0") {
+            return match (field("migration_slots_done"), field("migration_slots_total")) {
+                (Some(done), Some(total)) if done == total => Ok(()),
+                (done, total) => Err(ServerError::InvalidArgument(format!(
+                    "Migration on {} stopped after {} of {} slots", from, done.unwrap_or("?"), total.unwrap_or("?")
+                ))),
+            };
+        }
+    }
+}
+
+fn unexpected(address: &str, response: Option<Response>) -> ServerError {
+    match response {
+        Some(Response::Error(e)) => ServerError::InvalidArgument(format!("{} refused: {}", address, e)),
+        other => ServerError::InvalidArgument(format!("Unexpected response from {}: {:?}", address, other)),
+    }
+}
@@ -0,0 +1,91 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use serde::Serialize;
+use pluto_core::cluster::TOTAL_SLOTS;
+use crate::state::ServerState;
+
+// Progress markers checked by the health and readiness probes
+pub struct Health {
+    listeners: AtomicUsize,       // accept loops currently running
+    snapshot_loaded: AtomicBool,  // the startup snapshot has been restored
+    warming: AtomicBool,          // keys are still being copied from warm_from
+}
+
+impl Health {
+    pub fn new() -> Self {
+        Health {
+            listeners: AtomicUsize::new(0),
+            snapshot_loaded: AtomicBool::new(false),
+            warming: AtomicBool::new(false),
+        }
+    }
+
+    pub fn listener_started(&self) {
+        self.listeners.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn listener_stopped(&self) {
+        self.listeners.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    pub fn set_snapshot_loaded(&self) {
+        self.snapshot_loaded.store(true, Ordering::SeqCst);
+    }
+
+    pub fn set_warming(&self, warming: bool) {
+        self.warming.store(warming, Ordering::SeqCst);
+    }
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Result of a probe, returned as the probe's JSON body
+#[derive(Debug, Serialize)]
+pub struct HealthReport {
+    pub ok: bool,
+    pub listeners: usize,
+    pub snapshot_loaded: bool,
+    pub warming: bool,
+    pub slots_covered: usize,
+    pub shutting_down: bool,
+}
+
+// Liveness: the node is serving clients on at least one listener
+pub fn liveness(state: &ServerState) -> HealthReport {
+    let mut report = report(state);
+    report.ok = report.listeners > 0;
+    report
+}
+
+// Readiness: live, restored from disk and warmed, every slot assigned and not
+// shutting down
+pub fn readiness(state: &ServerState) -> HealthReport {
+    let mut report = report(state);
+    report.ok = report.listeners > 0
+        && report.snapshot_loaded
+        && !report.warming
+        && report.slots_covered == TOTAL_SLOTS
+        && !report.shutting_down;
+    report
+}
+
+fn report(state: &ServerState) -> HealthReport {
+    let slots_covered = if state.cluster_enabled {
+        state.cluster.slot_map.iter()
+            .map(|node| node.slot_range.1 + 1 - node.slot_range.0)
+            .sum()
+    } else {
+        TOTAL_SLOTS // a standalone node owns every slot
+    };
+    HealthReport {
+        ok: false,
+        listeners: state.health.listeners.load(Ordering::SeqCst),
+        snapshot_loaded: state.health.snapshot_loaded.load(Ordering::SeqCst),
+        warming: state.health.warming.load(Ordering::SeqCst),
+        slots_covered,
+        shutting_down: state.shutdown.is_triggered(),
+    }
+}
@@ -0,0 +1,186 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use log::{info, warn};
+use pluto_core::cache::ServerError;
+use pluto_core::cluster::NodeHealth;
+use crate::state::ServerState;
+use crate::whisper::{WhisperMessage, WhisperResponse, WhisperServer};
+
+// Every member pings the others over the whisper port and passes along which
+// members it stopped hearing from. A member one node can't reach is suspected;
+// once a majority of the members suspect it, it is marked failed in the slot
+// map, so clients stop routing to it before its slots are moved elsewhere.
+
+// How often every other member is pinged
+const PING_INTERVAL: Duration = Duration::from_secs(1);
+
+// Silence after which a member is suspected
+const SUSPECT_AFTER: Duration = Duration::from_secs(5);
+
+// How long another member's suspicion counts without being repeated
+const REPORT_TTL: Duration = Duration::from_secs(10);
+
+struct Peer {
+    last_seen: Instant,
+    suspected_by: HashMap<String, Instant>, // members that reported this one, and when
+}
+
+pub struct Heartbeats {
+    peers: HashMap<String, Peer>,
+}
+
+impl Default for Heartbeats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Heartbeats {
+    pub fn new() -> Self {
+        Heartbeats { peers: HashMap::new() }
+    }
+
+    // Members added since the last call count as just seen, so a new member
+    // isn't suspected before it had the chance to answer
+    fn peer(&mut self, address: &str) -> &mut Peer {
+        self.peers.entry(address.to_string()).or_insert_with(|| Peer {
+            last_seen: Instant::now(),
+            suspected_by: HashMap::new(),
+        })
+    }
+
+    pub fn record_seen(&mut self, address: &str) {
+        self.peer(address).last_seen = Instant::now();
+    }
+
+    // Replace what `reporter` said about every member with its latest list
+    pub fn record_suspects(&mut self, reporter: &str, suspects: &[String]) {
+        let now = Instant::now();
+        for (address, peer) in self.peers.iter_mut() {
+            if suspects.contains(address) {
+                peer.suspected_by.insert(reporter.to_string(), now);
+            } else {
+                peer.suspected_by.remove(reporter);
+            }
+        }
+        for address in suspects {
+            if !self.peers.contains_key(address) {
+                self.peer(address).suspected_by.insert(reporter.to_string(), now);
+            }
+        }
+    }
+
+    // Members this node hasn't heard from recently
+    pub fn suspects(&self) -> Vec<String> {
+        let now = Instant::now();
+        self.peers.iter()
+            .filter(|(_, peer)| now.duration_since(peer.last_seen) >= SUSPECT_AFTER)
+            .map(|(address, _)| address.clone())
+            .collect()
+    }
+
+    // Drop members that left, along with any report about this node itself,
+    // and work out the health of the rest
+    fn evaluate(&mut self, members: &[String], self_addr: &str) -> HashMap<String, NodeHealth> {
+        self.peers.retain(|address, _| members.contains(address) && address != self_addr);
+        let now = Instant::now();
+        let mut health = HashMap::new();
+        for member in members.iter().filter(|member| *member != self_addr) {
+            let peer = self.peer(member);
+            peer.suspected_by.retain(|reporter, at| members.contains(reporter) && now.duration_since(*at) < REPORT_TTL);
+            if now.duration_since(peer.last_seen) < SUSPECT_AFTER {
+                continue;
+            }
+            let votes = 1 + peer.suspected_by.keys().filter(|reporter| *reporter != self_addr).count();
+            let marked = if votes * 2 > members.len() { NodeHealth::Failed } else { NodeHealth::Suspected };
+            health.insert(member.clone(), marked);
+        }
+        health
+    }
+}
+
+pub async fn run(state: Arc<RwLock<ServerState>>) {
+    let (enabled, shutdown) = {
+        let state = state.read().unwrap();
+        (state.cluster_enabled, state.shutdown.clone())
+    };
+    if !enabled {
+        return;
+    }
+    let mut interval = tokio::time::interval(PING_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.wait() => break,
+        }
+        let (self_addr, peers, suspects, credentials) = {
+            let mut state = state.write().unwrap();
+            update_health(&mut state);
+            let self_addr = state.cluster.self_addr.clone();
+            let peers: Vec<String> = state.cluster.nodes.iter().filter(|node| **node != self_addr).cloned().collect();
+            (self_addr, peers, state.heartbeats.suspects(), state.config.peer_credentials())
+        };
+        for peer in peers {
+            let state = state.clone();
+            let credentials = credentials.clone();
+            let message = WhisperMessage::Ping { from: self_addr.clone(), suspects: suspects.clone() };
+            tokio::spawn(async move {
+                if let Ok(WhisperResponse { data: Some(WhisperMessage::Pong { suspects }), .. }) =
+                    WhisperServer::send_whisper_message(&peer, &credentials, message).await
+                {
+                    let mut state = state.write().unwrap();
+                    state.heartbeats.record_seen(&peer);
+                    state.heartbeats.record_suspects(&peer, &suspects);
+                }
+            });
+        }
+    }
+}
+
+// Answer a ping, which is itself proof the sender is alive
+pub fn handle_ping(state: &mut ServerState, from: String, suspects: Vec<String>) -> WhisperMessage {
+    state.heartbeats.record_seen(&from);
+    state.heartbeats.record_suspects(&from, &suspects);
+    WhisperMessage::Pong { suspects: state.heartbeats.suspects() }
+}
+
+// Members this node reaches, itself included, out of every member
+pub fn reachable(state: &ServerState) -> (usize, usize) {
+    let members = state.cluster.nodes.len();
+    (members.saturating_sub(state.cluster.health.len()), members)
+}
+
+// With cluster_require_quorum set, a node on the minority side of a partition
+// refuses writes to its slots, so the two sides can't take diverging writes
+// to the same keys
+pub fn check_quorum(state: &ServerState) -> Result<(), ServerError> {
+    if !state.cluster_enabled || !state.config.cluster_require_quorum {
+        return Ok(());
+    }
+    let (reached, members) = reachable(state);
+    if reached * 2 > members {
+        return Ok(());
+    }
+    Err(ServerError::ClusterDown(format!(
+        "This node reaches {} of {} cluster members, too few to accept writes", reached, members
+    )))
+}
+
+// Publish the members' health in the slot map, logging every change
+fn update_health(state: &mut ServerState) {
+    let members = state.cluster.nodes.clone();
+    let health = state.heartbeats.evaluate(&members, &state.cluster.self_addr);
+    for member in &members {
+        let before = state.cluster.health.get(member).copied().unwrap_or_default();
+        let after = health.get(member).copied().unwrap_or_default();
+        if before != after {
+            match after {
+                NodeHealth::Online => info!("Node {} is reachable again", member),
+                NodeHealth::Suspected => warn!("Node {} missed heartbeats and is suspected", member),
+                NodeHealth::Failed => warn!("Node {} is marked failed by a majority of the cluster", member),
+            }
+        }
+    }
+    state.cluster.health = health;
+}
@@ -0,0 +1,350 @@
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::Ordering;
+use axum::{Extension, Json, Router};
+use axum::body::Bytes;
+use axum::extract::{ConnectInfo, Path, Request, State};
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::http::{HeaderMap, StatusCode, header};
+use axum::middleware::{self as layer, Next};
+use axum::response::{IntoResponse, Response as HttpResponse};
+use axum::routing::get;
+use axum::extract::connect_info::Connected;
+use axum::serve::{IncomingStream, Listener};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use log::{debug, error, info};
+use pluto_core::cache::ServerError;
+use pluto_core::codec::{decode_command, encode_response, Encoding};
+use pluto_core::protocol::{Command, ErrorCode, ErrorReply, Response};
+use crate::api::{auth_required, authenticate, execute_command, execute_session_command};
+use crate::middleware::{Caller, Transport};
+use crate::network::{self, PROTECTED_MODE_REFUSAL};
+use crate::state::ServerState;
+use crate::session::Session;
+use crate::tracking;
+use crate::{dashboard, metering, replication};
+use crate::health::{liveness, readiness, HealthReport};
+
+type SharedState = Arc<RwLock<ServerState>>;
+
+// Routes of the REST gateway. WebSocket sessions send AUTH like native
+// clients; the other routes carry credentials on every request.
+pub fn router(state: SharedState) -> Router {
+    let rest = Router::new()
+        .route("/keys/{*key}", get(get_key).put(put_key).delete(delete_key))
+        .route("/cluster/slots", get(cluster_slots))
+        .layer(layer::from_fn_with_state(state.clone(), require_credentials));
+    Router::new()
+        .route("/ws", get(websocket))
+        .merge(rest)
+        .layer(layer::from_fn_with_state(state.clone(), protect))
+        .with_state(state)
+}
+
+// Run the request as the caller its credentials name, refusing it when they
+// name none
+async fn require_credentials(
+    State(state): State<SharedState>,
+    ConnectInfo(PeerAddr(addr)): ConnectInfo<PeerAddr>,
+    mut request: Request,
+    next: Next,
+) -> HttpResponse {
+    let caller = http_caller(&state.read().unwrap(), request.headers(), Transport::Http, Some(addr));
+    match caller {
+        Ok(caller) => {
+            request.extensions_mut().insert(caller);
+            next.run(request).await
+        }
+        Err(e) => unauthorized(e),
+    }
+}
+
+fn unauthorized(error: ServerError) -> HttpResponse {
+    (StatusCode::UNAUTHORIZED, [(header::WWW_AUTHENTICATE, "Basic realm=\"flux-cache\"")], error.to_string()).into_response()
+}
+
+// Refuse the clients protected mode keeps out
+async fn protect(
+    State(state): State<SharedState>,
+    ConnectInfo(PeerAddr(addr)): ConnectInfo<PeerAddr>,
+    request: Request,
+    next: Next,
+) -> HttpResponse {
+    if network::protected_refuses(&state.read().unwrap().config, addr) {
+        return (StatusCode::FORBIDDEN, PROTECTED_MODE_REFUSAL).into_response();
+    }
+    next.run(request).await
+}
+
+// Who a request comes from, by the credentials of its `Authorization: Basic`
+// header: the user and password AUTH takes, an empty user standing for
+// requirepass. Anyone is let in when neither is configured.
+pub(crate) fn http_caller(state: &ServerState, headers: &HeaderMap, transport: Transport, addr: Option<SocketAddr>) -> Result<Caller, ServerError> {
+    let user = if auth_required(state) {
+        let (username, password) = basic_credentials(headers)
+            .ok_or_else(|| ServerError::Unauthorized("Authentication required".to_string()))?;
+        authenticate(state, (!username.is_empty()).then_some(username.as_str()), &password)?
+    } else {
+        None
+    };
+    Ok(Caller { transport, addr, authenticated: true, user })
+}
+
+fn basic_credentials(headers: &HeaderMap) -> Option<(String, String)> {
+    let encoded = headers.get(header::AUTHORIZATION)?.to_str().ok()?.strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(BASE64.decode(encoded.trim()).ok()?).ok()?;
+    let (username, password) = decoded.split_once(':')?;
+    Some((username.to_string(), password.to_string()))
+}
+
+// Routes served to HTTP clients of the admin port
+pub fn admin_router(state: SharedState) -> Router {
+    // The dashboard shows the whole node, so it takes requirepass or an admin user
+    let dashboard = Router::new()
+        .route("/dashboard", get(dashboard::page))
+        .route("/dashboard/overview", get(dashboard::overview))
+        .layer(layer::from_fn_with_state(state.clone(), require_admin));
+    Router::new()
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .route("/metrics", get(metrics))
+        .merge(dashboard)
+        .layer(layer::from_fn_with_state(state.clone(), protect))
+        .with_state(state)
+}
+
+// Ask for credentials until the request carries those of requirepass or an
+// admin user
+async fn require_admin(
+    State(state): State<SharedState>,
+    ConnectInfo(PeerAddr(addr)): ConnectInfo<PeerAddr>,
+    request: Request,
+    next: Next,
+) -> HttpResponse {
+    let caller = http_caller(&state.read().unwrap(), request.headers(), Transport::Admin, Some(addr));
+    match caller {
+        Ok(caller) if caller.user.as_ref().is_none_or(|user| user.rule.admin) => next.run(request).await,
+        Ok(_) => (StatusCode::FORBIDDEN, "The dashboard is for admin users").into_response(),
+        Err(e) => unauthorized(e),
+    }
+}
+
+// Address of the client on the other end of a request, whichever listener
+// it came in on
+#[derive(Clone, Copy)]
+pub struct PeerAddr(SocketAddr);
+
+impl Connected<IncomingStream<'_, tokio::net::TcpListener>> for PeerAddr {
+    fn connect_info(stream: IncomingStream<'_, tokio::net::TcpListener>) -> Self {
+        PeerAddr(*stream.remote_addr())
+    }
+}
+
+impl Connected<IncomingStream<'_, HandoffListener>> for PeerAddr {
+    fn connect_info(stream: IncomingStream<'_, HandoffListener>) -> Self {
+        PeerAddr(*stream.remote_addr())
+    }
+}
+
+// Connections handed over by the admin accept loops once they turn out to speak HTTP
+pub struct HandoffListener {
+    rx: mpsc::Receiver<(TcpStream, SocketAddr)>,
+}
+
+impl HandoffListener {
+    pub fn new(rx: mpsc::Receiver<(TcpStream, SocketAddr)>) -> Self {
+        HandoffListener { rx }
+    }
+}
+
+impl Listener for HandoffListener {
+    type Io = TcpStream;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (TcpStream, SocketAddr) {
+        match self.rx.recv().await {
+            Some(conn) => conn,
+            // Every admin accept loop has stopped; no more connections will come
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        Ok(SocketAddr::from(([0, 0, 0, 0], 0)))
+    }
+}
+
+// Serve the admin HTTP endpoints on connections handed over from the admin port
+pub async fn serve_admin(listener: HandoffListener, state: SharedState) {
+    if let Err(e) = axum::serve(listener, admin_router(state).into_make_service_with_connect_info::<PeerAddr>()).await {
+        error!("Admin HTTP error: {}", e);
+    }
+}
+
+async fn healthz(State(state): State<SharedState>) -> HttpResponse {
+    probe(liveness(&state.read().unwrap()))
+}
+
+async fn readyz(State(state): State<SharedState>) -> HttpResponse {
+    probe(readiness(&state.read().unwrap()))
+}
+
+async fn metrics(State(state): State<SharedState>) -> HttpResponse {
+    let body = {
+        let state = state.read().unwrap();
+        let mut body = metering::prometheus(&state.metering.meters(&state.acl, &state.cache));
+        body.push_str(&replication::prometheus(&state.replication));
+        body
+    };
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body).into_response()
+}
+
+fn probe(report: HealthReport) -> HttpResponse {
+    let status = if report.ok { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    match serde_json::to_vec(&report) {
+        Ok(body) => (status, [(header::CONTENT_TYPE, "application/json")], body).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+// Serve the REST gateway on every given address
+pub async fn serve(addrs: Vec<SocketAddr>, state: SharedState) -> std::io::Result<()> {
+    let mut servers = tokio::task::JoinSet::new();
+    for addr in addrs {
+        let listener = tokio::net::TcpListener::bind(addr).await?;
+        info!("HTTP gateway listening on {}", addr);
+        let app = router(state.clone());
+        servers.spawn(async move {
+            if let Err(e) = axum::serve(listener, app.into_make_service_with_connect_info::<PeerAddr>()).await {
+                error!("HTTP gateway error on {}: {}", addr, e);
+            }
+        });
+    }
+    while servers.join_next().await.is_some() {}
+    Ok(())
+}
+
+async fn get_key(State(state): State<SharedState>, Extension(caller): Extension<Caller>, Path(key): Path<String>) -> HttpResponse {
+    run(Command::GET { key }, &state, caller).await
+}
+
+async fn put_key(State(state): State<SharedState>, Extension(caller): Extension<Caller>, Path(key): Path<String>, body: Bytes) -> HttpResponse {
+    run(Command::SET { key, value: body.to_vec(), visible_at: None }, &state, caller).await
+}
+
+async fn delete_key(State(state): State<SharedState>, Extension(caller): Extension<Caller>, Path(key): Path<String>) -> HttpResponse {
+    run(Command::DEL { keys: vec![key] }, &state, caller).await
+}
+
+async fn cluster_slots(State(state): State<SharedState>, Extension(caller): Extension<Caller>) -> HttpResponse {
+    run(Command::CLUSTER_SLOTS, &state, caller).await
+}
+
+async fn websocket(ws: WebSocketUpgrade, State(state): State<SharedState>) -> HttpResponse {
+    ws.on_upgrade(move |socket| websocket_session(socket, state))
+}
+
+// Each text or binary frame carries one `Command`; responses and published
+// messages go back as `Response` frames. Frames are JSON text until the
+// session switches to a binary encoding.
+async fn websocket_session(mut socket: WebSocket, state: SharedState) {
+    let mut session = Session::new(&state.read().unwrap(), Transport::WebSocket, None);
+    let output = session.output.clone();
+    'session: loop {
+        let encoding = session.encoding;
+        // Keys per frame when the reply is streamed; 0 sends it whole
+        let (response, chunk) = tokio::select! {
+            frame = socket.recv() => {
+                let payload = match frame {
+                    Some(Ok(Message::Text(text))) => Bytes::from(text),
+                    Some(Ok(Message::Binary(data))) => data,
+                    Some(Ok(Message::Close(_))) | None => break,
+                    Some(Ok(_)) => continue, // ping/pong is answered by axum
+                    Some(Err(e)) => {
+                        debug!("WebSocket error: {}", e);
+                        break;
+                    }
+                };
+                match decode_command(&payload, encoding) {
+                    Ok(cmd) => {
+                        let chunk = cmd.chunk().unwrap_or(0);
+                        match execute_session_command(cmd, &state, &mut session).await {
+                            Ok(resp) => (resp, chunk),
+                            Err(e) => (Response::Error(e.into()), 0),
+                        }
+                    }
+                    Err(e) => (Response::Error(ErrorReply::new(ErrorCode::Syntax, "Invalid command").with_details(e)), 0),
+                }
+            }
+            Some(push) = session.subscriber.recv() => (push.into_response(), 0),
+            Some(keys) = tracking::next_invalidation(&mut session.tracker) => {
+                (session.caller.leave(Response::Invalidate { keys }), 0)
+            }
+            _ = output.overflowed() => {
+                debug!("Closing WebSocket: output buffer over its limit");
+                state.read().unwrap().stats.evicted_clients.fetch_add(1, Ordering::Relaxed);
+                break;
+            }
+        };
+        for response in response.into_frames(chunk) {
+            let frame = match encode_response(&response, encoding) {
+                Ok(body) if encoding == Encoding::Json => match String::from_utf8(body) {
+                    Ok(json) => Message::Text(json.into()),
+                    Err(e) => Message::Binary(e.into_bytes().into()),
+                },
+                Ok(body) => Message::Binary(body.into()),
+                Err(e) => {
+                    error!("Failed to serialize WebSocket response: {}", e);
+                    break 'session;
+                }
+            };
+            if socket.send(frame).await.is_err() {
+                break 'session;
+            }
+        }
+    }
+}
+
+// Execute a command and translate its result into an HTTP response
+async fn run(cmd: Command, state: &SharedState, caller: Caller) -> HttpResponse {
+    match execute_command(cmd, state, caller).await {
+        Ok(Response::Data(data)) => {
+            ([(header::CONTENT_TYPE, "application/octet-stream")], data).into_response()
+        }
+        Ok(Response::Stale(data)) => {
+            ([(header::CONTENT_TYPE, "application/octet-stream"), (header::WARNING, "110 - \"Response is Stale\"")], data).into_response()
+        }
+        Ok(Response::Missing) => (StatusCode::NOT_FOUND, "cached as missing").into_response(),
+        Ok(Response::Nil) => StatusCode::NOT_FOUND.into_response(),
+        Ok(Response::Slots(json)) => {
+            ([(header::CONTENT_TYPE, "application/json")], json).into_response()
+        }
+        Ok(Response::Success) => StatusCode::NO_CONTENT.into_response(),
+        Ok(redirect @ (Response::Moved { .. } | Response::Ask { .. })) => {
+            (StatusCode::MISDIRECTED_REQUEST, Json(redirect)).into_response()
+        }
+        Ok(other) => match serde_json::to_vec(&other) {
+            Ok(body) => ([(header::CONTENT_TYPE, "application/json")], body).into_response(),
+            Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+        },
+        Err(e) => (error_status(&e), e.to_string()).into_response(),
+    }
+}
+
+fn error_status(error: &ServerError) -> StatusCode {
+    match error {
+        ServerError::KeyNotFound(_) => StatusCode::NOT_FOUND,
+        ServerError::InvalidArgument(_) | ServerError::InvalidCommand(_) => StatusCode::BAD_REQUEST,
+        ServerError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+        ServerError::Forbidden(_) => StatusCode::FORBIDDEN,
+        ServerError::WrongType(_) => StatusCode::CONFLICT,
+        ServerError::QuorumNotReached(_) | ServerError::ClusterDown(_) => StatusCode::SERVICE_UNAVAILABLE,
+        ServerError::Origin(_) => StatusCode::BAD_GATEWAY,
+        ServerError::QuotaExceeded(_) => StatusCode::INSUFFICIENT_STORAGE,
+        ServerError::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
@@ -0,0 +1,458 @@
+use std::sync::atomic::Ordering;
+use pluto_core::cache::now_ms;
+use pluto_core::cluster::NodeHealth;
+use crate::{allocator, heartbeat, version, wal};
+use crate::state::ServerState;
+
+// Sections reported by INFO when no section is requested
+const DEFAULT_SECTIONS: &[&str] = &["server", "clients", "memory", "stats", "storage", "persistence", "replication", "wal", "backup", "origin", "geo", "keyspace", "cluster"];
+
+// Build the INFO report, either for one section or for all of them
+pub fn build_info(state: &ServerState, section: Option<&str>) -> Result<String, String> {
+    let sections: Vec<&str> = match section {
+        Some(name) => vec![name],
+        None => DEFAULT_SECTIONS.to_vec(),
+    };
+    let mut out = String::new();
+    for name in sections {
+        let lines = match name.to_ascii_lowercase().as_str() {
+            "server" => owned(server_section(state)),
+            "clients" => owned(clients_section(state)),
+            "memory" => owned(memory_section(state)),
+            "stats" => owned(stats_section(state)),
+            "replication" => replication_section(state),
+            "storage" => owned(storage_section(state)),
+            "persistence" => owned(persistence_section(state)),
+            "wal" => owned(wal_section(state)),
+            "backup" => owned(backup_section(state)),
+            "origin" => owned(origin_section(state)),
+            "geo" => owned(geo_section(state)),
+            "keyspace" => owned(keyspace_section(state)),
+            "cluster" => owned(cluster_section(state)),
+            "latencystats" => latency_section(state),
+            "acl" => acl_section(state),
+            "tenants" => tenants_section(state),
+            "metering" => metering_section(state),
+            other => return Err(format!("Unknown INFO section: {}", other)),
+        };
+        if !out.is_empty() {
+            out.push_str("\r\n");
+        }
+        out.push_str(&format!("# {}\r\n", capitalize(name)));
+        for (key, value) in lines {
+            out.push_str(&format!("{}:{}\r\n", key, value));
+        }
+    }
+    Ok(out)
+}
+
+fn owned(lines: Vec<(&'static str, String)>) -> Vec<(String, String)> {
+    lines.into_iter().map(|(key, value)| (key.to_string(), value)).collect()
+}
+
+fn capitalize(name: &str) -> String {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(first) => first.to_ascii_uppercase().to_string() + &chars.as_str().to_ascii_lowercase(),
+        None => String::new(),
+    }
+}
+
+fn server_section(state: &ServerState) -> Vec<(&'static str, String)> {
+    let mut lines = version::build_lines();
+    lines.extend([
+        ("process_id", std::process::id().to_string()),
+        ("uptime_in_seconds", state.started_at.elapsed().as_secs().to_string()),
+        ("bind", state.config.bind.join(",")),
+        ("port", state.config.port.to_string()),
+    ]);
+    lines
+}
+
+fn clients_section(state: &ServerState) -> Vec<(&'static str, String)> {
+    vec![
+        ("connected_clients", state.clients.len().to_string()),
+        ("maxclients", state.config.maxclients.to_string()),
+        ("tracking_clients", state.tracking.clients().to_string()),
+        ("tracking_total_keys", state.tracking.keys().to_string()),
+    ]
+}
+
+fn memory_section(state: &ServerState) -> Vec<(&'static str, String)> {
+    let allocator = allocator::stats();
+    let mut lines = vec![
+        ("used_memory", state.cache.used_memory().to_string()),
+        ("used_memory_human", human_bytes(state.cache.used_memory())),
+        ("maxmemory", state.config.maxmemory.to_string()),
+        ("maxmemory_policy", state.config.maxmemory_policy.name().to_string()),
+        ("mem_allocator", allocator.name.to_string()),
+        ("key_prefixes_shared", state.cache.prefixes().len().to_string()),
+        ("key_prefixes_saved_bytes", state.cache.prefixes().saved_bytes().to_string()),
+    ];
+    if let Some(allocated) = allocator.allocated {
+        lines.push(("allocator_allocated", allocated.to_string()));
+    }
+    if let Some(resident) = allocator.resident {
+        lines.push(("used_memory_rss", resident.to_string()));
+        lines.push(("used_memory_rss_human", human_bytes(resident)));
+        // Resident memory per byte of cache data
+        if state.cache.used_memory() > 0 {
+            lines.push(("mem_fragmentation_ratio", format!("{:.2}", resident as f64 / state.cache.used_memory() as f64)));
+        }
+        // Resident memory per byte the allocator handed out
+        if let Some(allocated) = allocator.allocated.filter(|allocated| *allocated > 0) {
+            lines.push(("allocator_frag_ratio", format!("{:.2}", resident as f64 / allocated as f64)));
+        }
+    }
+    let defrag = &state.defrag;
+    lines.push(("defrag_running", (defrag.is_running() as u8).to_string()));
+    lines.push(("defrag_scanned", defrag.scanned.load(Ordering::Relaxed).to_string()));
+    lines.push(("defrag_total", defrag.total.load(Ordering::Relaxed).to_string()));
+    lines.push(("defrag_reallocated_bytes", defrag.reallocated.load(Ordering::Relaxed).to_string()));
+    lines.push(("defrag_runs", defrag.runs.load(Ordering::Relaxed).to_string()));
+    lines
+}
+
+// Format a byte count the way operators read it, e.g. 1.50M
+fn human_bytes(bytes: usize) -> String {
+    const UNITS: [&str; 5] = ["B", "K", "M", "G", "T"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 { format!("{}B", bytes) } else { format!("{:.2}{}", value, UNITS[unit]) }
+}
+
+fn stats_section(state: &ServerState) -> Vec<(&'static str, String)> {
+    let stats = &state.stats;
+    let history: Vec<String> = stats.keyspace_history().iter().map(|keys| keys.to_string()).collect();
+    vec![
+        ("keyspace_hits", stats.hits.load(Ordering::Relaxed).to_string()),
+        ("keyspace_misses", stats.misses.load(Ordering::Relaxed).to_string()),
+        ("keyspace_hit_ratio", format!("{:.4}", stats.hit_ratio())),
+        ("expired_reads", state.cache.expired_reads().to_string()),
+        ("stale_reads", stats.stale_reads.load(Ordering::Relaxed).to_string()),
+        ("missing_reads", stats.missing_reads.load(Ordering::Relaxed).to_string()),
+        ("singleflight_locks", state.singleflight.held().to_string()),
+        ("singleflight_acquired", state.singleflight.acquired.load(Ordering::Relaxed).to_string()),
+        ("singleflight_waits", state.singleflight.waits.load(Ordering::Relaxed).to_string()),
+        ("singleflight_wait_timeouts", state.singleflight.wait_timeouts.load(Ordering::Relaxed).to_string()),
+        ("expired_keys", stats.expired_keys.load(Ordering::Relaxed).to_string()),
+        ("evicted_keys", stats.evicted_keys.load(Ordering::Relaxed).to_string()),
+        ("timed_out_commands", stats.timed_out_commands.load(Ordering::Relaxed).to_string()),
+        ("cancelled_commands", stats.cancelled_commands.load(Ordering::Relaxed).to_string()),
+        ("evicted_clients", stats.evicted_clients.load(Ordering::Relaxed).to_string()),
+        ("slow_commands", stats.slow_commands().to_string()),
+        // Removed keys whose memory the lazy-free thread has yet to give back
+        ("lazyfree_pending_objects", state.lazy_free.pending().to_string()),
+        ("lazyfreed_objects", state.lazy_free.freed().to_string()),
+        ("keys", state.cache.len().to_string()),
+        // Key count sampled once a minute, oldest first
+        ("keyspace_history", history.join(",")),
+    ]
+}
+
+fn replication_section(state: &ServerState) -> Vec<(String, String)> {
+    let replication = &state.replication;
+    match &replication.primary {
+        Some(primary) => owned(vec![
+            ("role", "replica".to_string()),
+            ("primary", primary.clone()),
+            ("primary_link_status", if replication.link_up() { "up" } else { "down" }.to_string()),
+            ("primary_repl_offset", replication.primary_offset().to_string()),
+            ("primary_latest_offset", replication.primary_latest().to_string()),
+            ("replica_lag", replication.lag().to_string()),
+            // -1 until the primary is first heard from
+            ("primary_last_io_ms", replication.primary_last_io_ms().map_or("-1".to_string(), |ms| ms.to_string())),
+            ("anti_entropy_repaired_keys", replication.repaired().to_string()),
+        ]),
+        None => {
+            let replicas = replication.replicas();
+            let mut lines = owned(vec![
+                ("role", "primary".to_string()),
+                ("connected_replicas", replicas.len().to_string()),
+                ("repl_offset", replication.offset().to_string()),
+                ("max_replica_lag", replicas.iter().map(|replica| replica.lag).max().unwrap_or(0).to_string()),
+                ("read_repaired_keys", replication.read_repaired().to_string()),
+            ]);
+            // One line per replica with the offset it acknowledged and how far behind it is
+            for (i, replica) in replicas.into_iter().enumerate() {
+                lines.push((
+                    format!("replica{}", i),
+                    format!("address={},offset={},lag={},last_ack_ms={}", replica.address, replica.acked, replica.lag, replica.last_ack_ms),
+                ));
+            }
+            lines
+        }
+    }
+}
+
+fn storage_section(state: &ServerState) -> Vec<(&'static str, String)> {
+    let storage = state.cache.storage();
+    let stats = storage.stats();
+    let mut lines = vec![("storage_engine", storage.name().to_string())];
+    if stats.segments > 0 {
+        lines.extend([
+            ("storage_dir", state.config.storage_dir.clone()),
+            ("storage_segments", stats.segments.to_string()),
+            ("storage_bytes", stats.bytes.to_string()),
+            ("storage_garbage_bytes", stats.garbage_bytes.to_string()),
+            ("storage_compactions", stats.compactions.to_string()),
+            ("storage_write_errors", stats.write_errors.to_string()),
+        ]);
+    }
+    lines
+}
+
+// Whether snapshots, the write-ahead log and backups are succeeding, with
+// the progress of the snapshot being saved
+fn persistence_section(state: &ServerState) -> Vec<(&'static str, String)> {
+    let snapshots = &state.snapshots;
+    let last_at = snapshots.last_at.load(Ordering::Relaxed);
+    let mut lines = vec![
+        ("snapshot_file", state.config.snapshot_file.clone()),
+        ("snapshot_in_progress", (snapshots.in_progress() as u8).to_string()),
+        ("snapshot_progress_pct", snapshots.percent_done().to_string()),
+        ("snapshot_running_ms", snapshots.running_ms().to_string()),
+        ("snapshot_saves", snapshots.saves.load(Ordering::Relaxed).to_string()),
+        ("snapshot_failures", snapshots.failures.load(Ordering::Relaxed).to_string()),
+        // -1 until the first save
+        ("snapshot_last_save_age_ms", if last_at == 0 { "-1".to_string() } else { now_ms().saturating_sub(last_at).to_string() }),
+        ("snapshot_last_save_keys", snapshots.last_keys.load(Ordering::Relaxed).to_string()),
+        ("snapshot_last_save_duration_ms", snapshots.last_duration_ms.load(Ordering::Relaxed).to_string()),
+        ("snapshot_last_status", status(&snapshots.last_error.lock().unwrap())),
+    ];
+    match state.replication.wal() {
+        Some(wal) => {
+            let synced_at = wal.last_synced_at.load(Ordering::Relaxed);
+            lines.extend([
+                ("wal_enabled", "1".to_string()),
+                ("wal_last_status", status(&wal.last_error.lock().unwrap())),
+                // -1 until the first sync
+                ("wal_last_sync_age_ms", if synced_at == 0 { "-1".to_string() } else { now_ms().saturating_sub(synced_at).to_string() }),
+            ]);
+        }
+        None => lines.push(("wal_enabled", "0".to_string())),
+    }
+    match state.config.backup_url.is_empty() {
+        true => lines.push(("backup_enabled", "0".to_string())),
+        false => lines.extend([
+            ("backup_enabled", "1".to_string()),
+            ("backup_last_status", status(&state.backup.last_error.lock().unwrap())),
+        ]),
+    }
+    lines
+}
+
+// `ok`, or `err` when the last attempt left an error
+fn status(last_error: &str) -> String {
+    if last_error.is_empty() { "ok".to_string() } else { "err".to_string() }
+}
+
+// What PERSISTENCE_STATUS replies: INFO persistence, wal and backup as
+// `key:value` lines
+pub fn persistence_status(state: &ServerState) -> String {
+    let mut lines = persistence_section(state);
+    lines.extend(wal_section(state).into_iter().filter(|(key, _)| *key != "wal_enabled"));
+    lines.extend(backup_section(state).into_iter().filter(|(key, _)| *key != "backup_enabled"));
+    lines.into_iter().map(|(key, value)| format!("{}:{}\r\n", key, value)).collect()
+}
+
+fn wal_section(state: &ServerState) -> Vec<(&'static str, String)> {
+    let Some(wal) = state.replication.wal() else {
+        return vec![("wal_enabled", "0".to_string())];
+    };
+    let (segment, segment_bytes) = wal.current_segment();
+    vec![
+        ("wal_enabled", "1".to_string()),
+        ("wal_position", wal.position().to_string()),
+        ("wal_segment", segment),
+        ("wal_segment_bytes", segment_bytes.to_string()),
+        ("wal_fsync", format!("{:?}", wal.fsync).to_lowercase()),
+        ("wal_group_commits", wal.group_commits.load(Ordering::Relaxed).to_string()),
+        ("wal_group_committed_writes", wal.group_committed.load(Ordering::Relaxed).to_string()),
+        ("wal_replayed_writes", wal.replayed.to_string()),
+        ("wal_archiving", (wal::archiving(&state.config) as u8).to_string()),
+        ("wal_segments_archived", wal.archived.load(Ordering::Relaxed).to_string()),
+        ("wal_archive_failures", wal.archive_failures.load(Ordering::Relaxed).to_string()),
+        ("wal_last_archived", wal.last_archived.lock().unwrap().clone()),
+        ("wal_write_errors", wal.write_errors.load(Ordering::Relaxed).to_string()),
+        ("wal_last_error", wal.last_error.lock().unwrap().clone()),
+    ]
+}
+
+fn backup_section(state: &ServerState) -> Vec<(&'static str, String)> {
+    if state.config.backup_url.is_empty() {
+        return vec![("backup_enabled", "0".to_string())];
+    }
+    let backup = &state.backup;
+    let last_at = backup.last_at.load(Ordering::Relaxed);
+    vec![
+        ("backup_enabled", "1".to_string()),
+        ("backup_url", state.config.backup_url.clone()),
+        ("backup_interval_secs", state.config.backup_interval_secs.to_string()),
+        ("backup_uploads", backup.uploads.load(Ordering::Relaxed).to_string()),
+        ("backup_failures", backup.failures.load(Ordering::Relaxed).to_string()),
+        // -1 until the first upload
+        ("backup_last_age_ms", if last_at == 0 { "-1".to_string() } else { now_ms().saturating_sub(last_at).to_string() }),
+        ("backup_last_bytes", backup.last_bytes.load(Ordering::Relaxed).to_string()),
+        ("backup_last_error", backup.last_error.lock().unwrap().clone()),
+    ]
+}
+
+fn origin_section(state: &ServerState) -> Vec<(&'static str, String)> {
+    let origins = &state.origins;
+    vec![
+        ("origin_rules", origins.len().to_string()),
+        ("origin_fetches", origins.fetches.load(Ordering::Relaxed).to_string()),
+        ("origin_fetch_misses", origins.fetch_misses.load(Ordering::Relaxed).to_string()),
+        ("origin_fetch_errors", origins.fetch_errors.load(Ordering::Relaxed).to_string()),
+        ("origin_writes", origins.writes.load(Ordering::Relaxed).to_string()),
+        ("origin_write_errors", origins.write_errors.load(Ordering::Relaxed).to_string()),
+    ]
+}
+
+fn geo_section(state: &ServerState) -> Vec<(&'static str, String)> {
+    let geo = &state.geo;
+    let now = now_ms();
+    let shipping = !state.config.geo_replicate_to.is_empty() && !geo.is_standby();
+    let role = if geo.is_standby() {
+        "standby"
+    } else if state.config.geo_active {
+        "active"
+    } else if shipping {
+        "primary"
+    } else if state.config.geo_standby {
+        "promoted"
+    } else {
+        "none"
+    };
+    let mut lines = vec![("geo_role", role.to_string())];
+    if shipping {
+        let oldest = geo.oldest_ms.load(Ordering::Relaxed);
+        lines.extend([
+            ("geo_standby", state.config.geo_replicate_to.clone()),
+            // Only one member of the cluster ships; the others report 0 and a down link
+            ("geo_shipping", (geo.shipping.load(Ordering::Relaxed) as u8).to_string()),
+            ("geo_link_status", if geo.link_up.load(Ordering::Relaxed) { "up" } else { "down" }.to_string()),
+            ("geo_sources_connected", geo.sources_up.load(Ordering::Relaxed).to_string()),
+            ("geo_pending_writes", geo.pending.load(Ordering::Relaxed).to_string()),
+            ("geo_lag_ms", if oldest == 0 { "0".to_string() } else { now.saturating_sub(oldest).to_string() }),
+            ("geo_batches_sent", geo.batches_sent.load(Ordering::Relaxed).to_string()),
+            ("geo_writes_shipped", geo.writes_shipped.load(Ordering::Relaxed).to_string()),
+            ("geo_bytes_raw", geo.bytes_raw.load(Ordering::Relaxed).to_string()),
+            ("geo_bytes_sent", geo.bytes_sent.load(Ordering::Relaxed).to_string()),
+        ]);
+    }
+    if geo.is_standby() || state.config.geo_active {
+        let applied_at = geo.applied_at.load(Ordering::Relaxed);
+        lines.extend([
+            ("geo_source", geo.source().unwrap_or_default()),
+            ("geo_batches_applied", geo.batches_applied.load(Ordering::Relaxed).to_string()),
+            ("geo_writes_applied", geo.writes_applied.load(Ordering::Relaxed).to_string()),
+            ("geo_apply_lag_ms", geo.lag_ms.load(Ordering::Relaxed).to_string()),
+            // -1 until the first batch arrives
+            ("geo_last_batch_age_ms", if applied_at == 0 { "-1".to_string() } else { now.saturating_sub(applied_at).to_string() }),
+        ]);
+    }
+    if state.config.geo_active {
+        let conflicts = &geo.conflicts;
+        lines.extend([
+            ("geo_conflicts", conflicts.detected.load(Ordering::Relaxed).to_string()),
+            ("geo_conflicts_kept_local", conflicts.kept_local.load(Ordering::Relaxed).to_string()),
+            ("geo_conflicts_took_remote", conflicts.took_remote.load(Ordering::Relaxed).to_string()),
+            ("geo_conflicts_merged", conflicts.merged.load(Ordering::Relaxed).to_string()),
+            ("geo_conflicts_merge_failed", conflicts.merge_failed.load(Ordering::Relaxed).to_string()),
+        ]);
+    }
+    lines
+}
+
+fn keyspace_section(state: &ServerState) -> Vec<(&'static str, String)> {
+    vec![
+        ("keys", state.cache.len().to_string()),
+    ]
+}
+
+fn cluster_section(state: &ServerState) -> Vec<(&'static str, String)> {
+    vec![
+        ("cluster_enabled", (state.cluster_enabled as u8).to_string()),
+        ("cluster_known_nodes", state.cluster.nodes.len().to_string()),
+        ("cluster_current_epoch", state.cluster.epoch.to_string()),
+        ("cluster_hashing", format!("{:?}", state.cluster.hashing).to_ascii_lowercase()),
+        ("cluster_nodes_suspected", count_health(state, NodeHealth::Suspected).to_string()),
+        ("cluster_nodes_failed", count_health(state, NodeHealth::Failed).to_string()),
+        ("cluster_reachable_nodes", heartbeat::reachable(state).0.to_string()),
+        ("cluster_require_quorum", (state.config.cluster_require_quorum as u8).to_string()),
+        ("cluster_writes_refused", (heartbeat::check_quorum(state).is_err() as u8).to_string()),
+        ("raft_role", format!("{:?}", state.raft.role).to_ascii_lowercase()),
+        ("raft_term", state.raft.term.to_string()),
+        ("raft_leader", state.raft.leader.clone().unwrap_or_default()),
+        ("raft_commit_index", state.raft.commit_index.to_string()),
+        ("handoff_in_progress", (state.handoff.is_running() as u8).to_string()),
+        ("handoff_slots_moved", state.handoff.slots_moved.load(Ordering::Relaxed).to_string()),
+        ("handoff_slots_total", state.handoff.slots_total.load(Ordering::Relaxed).to_string()),
+        ("migrating_slots", (state.migration.is_running() as u8).to_string()),
+        ("migration_target", state.migration.target.lock().unwrap().clone()),
+        ("migration_slots_done", state.migration.slots_done.load(Ordering::Relaxed).to_string()),
+        ("migration_slots_total", state.migration.slots_total.load(Ordering::Relaxed).to_string()),
+        ("migration_keys_moved", state.migration.keys_moved.load(Ordering::Relaxed).to_string()),
+        ("migration_bytes_moved", state.migration.bytes_moved.load(Ordering::Relaxed).to_string()),
+        ("pubsub_channels", state.pubsub.channels().len().to_string()),
+        ("pubsub_followers", state.relay.follower_count().to_string()),
+        ("pubsub_forwarded", state.relay.forwarded.load(Ordering::Relaxed).to_string()),
+        ("pubsub_relayed", state.relay.relayed.load(Ordering::Relaxed).to_string()),
+        ("pubsub_relay_errors", state.relay.relay_errors.load(Ordering::Relaxed).to_string()),
+    ]
+}
+
+fn count_health(state: &ServerState, health: NodeHealth) -> usize {
+    state.cluster.health.values().filter(|marked| **marked == health).count()
+}
+
+// Execution latency per command type, in microseconds
+fn latency_section(state: &ServerState) -> Vec<(String, String)> {
+    state.stats.latency_summaries().into_iter()
+        .map(|summary| (
+            format!("latency_percentiles_usec_{}", summary.command.to_ascii_lowercase()),
+            format!(
+                "calls={},p50={},p95={},p99={},max={}",
+                summary.calls, summary.p50, summary.p95, summary.p99, summary.max
+            ),
+        ))
+        .collect()
+}
+
+// What each user with a quota holds against it
+fn acl_section(state: &ServerState) -> Vec<(String, String)> {
+    let mut lines: Vec<(String, String)> = state.acl.usage(&state.cache)
+        .map(|(user, keys, bytes)| (
+            format!("user_{}", user.rule.name),
+            format!("keys={},max_keys={},bytes={},max_bytes={}", keys, user.rule.max_keys, bytes, user.rule.max_bytes),
+        ))
+        .collect();
+    lines.sort();
+    lines
+}
+
+// What each tenant holds, against its quota when it has one
+fn tenants_section(state: &ServerState) -> Vec<(String, String)> {
+    state.acl.tenant_usage(&state.cache)
+        .map(|(tenant, keys, bytes)| (
+            format!("tenant_{}", tenant.rule.name),
+            format!("keys={},max_keys={},bytes={},max_bytes={}", keys, tenant.rule.max_keys, bytes, tenant.rule.max_bytes),
+        ))
+        .collect()
+}
+
+// What each tenant and metered prefix holds and ran
+fn metering_section(state: &ServerState) -> Vec<(String, String)> {
+    state.metering.meters(&state.acl, &state.cache).into_iter()
+        .map(|meter| (
+            format!("{}_{}", meter.kind, meter.name),
+            format!("keys={},bytes={},commands={},writes={}", meter.keys, meter.bytes, meter.commands, meter.writes),
+        ))
+        .collect()
+}
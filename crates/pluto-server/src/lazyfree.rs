@@ -0,0 +1,90 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::Arc;
+use std::thread;
+use log::error;
+use pluto_core::cache::CacheEntry;
+use crate::state::ServerState;
+
+// Memory of removed entries is given back on a thread of its own, so that
+// UNLINK, FLUSHALL and expiry don't hold the state lock while a large value
+// is freed. Small removals are freed where they happen, as handing them over
+// costs more than freeing them.
+
+// Removals smaller than this are freed inline
+const LAZYFREE_MIN_BYTES: usize = 64 * 1024;
+
+type Garbage = (Box<dyn Send>, u64);
+
+pub struct LazyFree {
+    sender: Sender<Garbage>,
+    counts: Arc<Counts>,
+}
+
+#[derive(Default)]
+struct Counts {
+    pending: AtomicU64, // entries handed over and not freed yet
+    freed: AtomicU64, // entries the thread freed
+}
+
+impl LazyFree {
+    pub fn new() -> Self {
+        let (sender, receiver) = mpsc::channel::<Garbage>();
+        let counts = Arc::new(Counts::default());
+        let thread_counts = counts.clone();
+        let spawned = thread::Builder::new().name("lazy-free".to_string()).spawn(move || {
+            for (garbage, entries) in receiver {
+                drop(garbage);
+                thread_counts.pending.fetch_sub(entries, Ordering::Relaxed);
+                thread_counts.freed.fetch_add(entries, Ordering::Relaxed);
+            }
+        });
+        // Without the thread the receiver is gone, and everything is freed inline
+        if let Err(e) = spawned {
+            error!("Failed to start the lazy-free thread: {}", e);
+        }
+        LazyFree { sender, counts }
+    }
+
+    // Free `garbage`, holding `entries` entries of `bytes` stored bytes in all,
+    // on the lazy-free thread when it is large enough to be worth it
+    pub fn free<T: Send + 'static>(&self, garbage: T, entries: u64, bytes: usize) {
+        if bytes < LAZYFREE_MIN_BYTES {
+            return;
+        }
+        self.counts.pending.fetch_add(entries, Ordering::Relaxed);
+        if self.sender.send((Box::new(garbage), entries)).is_err() {
+            self.counts.pending.fetch_sub(entries, Ordering::Relaxed);
+        }
+    }
+
+    pub fn pending(&self) -> u64 {
+        self.counts.pending.load(Ordering::Relaxed)
+    }
+
+    pub fn freed(&self) -> u64 {
+        self.counts.freed.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for LazyFree {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Remove every key, freeing them lazily
+pub fn clear(state: &mut ServerState) {
+    let bytes = state.cache.used_memory();
+    let count = state.cache.len() as u64;
+    let entries = state.cache.clear();
+    state.tracking.invalidate_all();
+    state.lazy_free.free(entries, count, bytes);
+}
+
+// Free removed entries lazily
+pub fn free_entries(state: &ServerState, entries: Vec<CacheEntry>) {
+    let bytes = entries.iter().map(|entry| entry.data.len()).sum();
+    let count = entries.len() as u64;
+    state.lazy_free.free(entries, count, bytes);
+}
@@ -0,0 +1,63 @@
+#![allow(unused_imports)]
+#![allow(non_camel_case_types)]
+#![allow(clippy::upper_case_acronyms)]
+
+pub mod acl;
+pub mod allocator;
+pub mod antientropy;
+pub mod api;
+pub mod audit;
+pub mod backup;
+pub mod batch;
+pub mod buffer;
+pub mod clients;
+pub mod conflict;
+pub mod dashboard;
+pub mod deadline;
+pub mod defrag;
+pub mod environment;
+pub mod eviction;
+pub mod expiry;
+pub mod fanout;
+pub mod geo;
+pub mod handoff;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod health;
+pub mod heartbeat;
+pub mod http;
+pub mod info;
+pub mod lazyfree;
+pub mod logging;
+pub mod metering;
+pub mod middleware;
+pub mod migrate;
+pub mod network;
+pub mod origin;
+pub mod peer;
+pub mod pubsub;
+pub mod raft;
+pub mod readrepair;
+pub mod relay;
+pub mod replication;
+pub mod server;
+pub mod session;
+pub mod shutdown;
+pub mod singleflight;
+pub mod snapshot;
+pub mod state;
+pub mod stats;
+pub mod storage;
+pub mod telemetry;
+pub mod tracking;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+pub mod uring;
+pub mod validate;
+pub mod version;
+pub mod wal;
+pub mod warm;
+pub mod whisper;
+
+pub use backup::restore;
+pub use audit::verify as verify_audit;
+pub use server::run;
@@ -0,0 +1,152 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use chrono::Local;
+use env_logger::{Builder, Target};
+use log::LevelFilter;
+use crate::environment::FluxConfig;
+
+// Set up the global logger from the config. RUST_LOG, when set, overrides
+// `log_level` so single modules can be turned up while debugging.
+pub fn init(conf: &FluxConfig) {
+    let level = conf.log_level.parse::<LevelFilter>().unwrap_or_else(|_| {
+        eprintln!("[WARN] Unknown log_level {:?}, using info", conf.log_level);
+        LevelFilter::Info
+    });
+    let mut builder = Builder::new();
+    builder.filter_level(level).parse_default_env();
+
+    match conf.log_format.as_str() {
+        "json" => {
+            builder.format(|buf, record| {
+                let line = serde_json::json!({
+                    "ts": Local::now().to_rfc3339(),
+                    "level": record.level().as_str(),
+                    "target": record.target(),
+                    "msg": record.args().to_string(),
+                });
+                writeln!(buf, "{}", line)
+            });
+        }
+        "plain" => {
+            builder.format(|buf, record| {
+                writeln!(
+                    buf,
+                    "{} {:<5} {} - {}",
+                    Local::now().format("%Y-%m-%d %H:%M:%S%.3f"),
+                    record.level(),
+                    record.target(),
+                    record.args()
+                )
+            });
+        }
+        other => eprintln!("[WARN] Unknown log_format {:?}, using plain", other),
+    }
+
+    match conf.log_target.as_str() {
+        "stdout" => {
+            builder.target(Target::Stdout);
+        }
+        "file" => match RotatingFile::open(conf) {
+            Ok(file) => {
+                builder.target(Target::Pipe(Box::new(file)));
+            }
+            Err(e) => eprintln!("[WARN] Could not open log file {}: {e}, logging to stdout", conf.log_file),
+        },
+        other => eprintln!("[WARN] Unknown log_target {:?}, using stdout", other),
+    }
+
+    if let Err(e) = builder.try_init() {
+        eprintln!("[WARN] Logger already initialized: {e}");
+    }
+}
+
+// When a time-rotated log starts a new file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Rotation {
+    Never,
+    Hourly,
+    Daily,
+}
+
+impl Rotation {
+    // Identifies the current period; a change means the file must rotate
+    fn period(self) -> String {
+        match self {
+            Rotation::Never => String::new(),
+            Rotation::Hourly => Local::now().format("%Y%m%d%H").to_string(),
+            Rotation::Daily => Local::now().format("%Y%m%d").to_string(),
+        }
+    }
+}
+
+// Log file that moves itself aside once it grows past `log_max_size_mb` or a
+// new rotation period starts, keeping `log_max_files` old files as
+// `<log_file>.1` (newest) to `<log_file>.N` (oldest)
+struct RotatingFile {
+    path: String,
+    file: File,
+    size: u64,
+    max_size: u64, // bytes; 0 disables size rotation
+    max_files: usize,
+    rotation: Rotation,
+    period: String,
+}
+
+impl RotatingFile {
+    fn open(conf: &FluxConfig) -> io::Result<Self> {
+        let rotation = match conf.log_rotation.as_str() {
+            "hourly" => Rotation::Hourly,
+            "daily" => Rotation::Daily,
+            "never" => Rotation::Never,
+            other => {
+                eprintln!("[WARN] Unknown log_rotation {:?}, using daily", other);
+                Rotation::Daily
+            }
+        };
+        let file = OpenOptions::new().create(true).append(true).open(&conf.log_file)?;
+        Ok(RotatingFile {
+            path: conf.log_file.clone(),
+            size: file.metadata()?.len(),
+            file,
+            max_size: conf.log_max_size_mb * 1024 * 1024,
+            max_files: conf.log_max_files,
+            rotation,
+            period: rotation.period(),
+        })
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.max_files == 0 {
+            // No history kept, just start over
+            self.file = File::create(&self.path)?;
+        } else {
+            let _ = fs::remove_file(format!("{}.{}", self.path, self.max_files));
+            for n in (1..self.max_files).rev() {
+                let _ = fs::rename(format!("{}.{}", self.path, n), format!("{}.{}", self.path, n + 1));
+            }
+            fs::rename(&self.path, format!("{}.1", self.path))?;
+            self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        }
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let period = self.rotation.period();
+        let full = self.max_size > 0 && self.size + buf.len() as u64 > self.max_size && self.size > 0;
+        if full || period != self.period {
+            self.period = period;
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
@@ -0,0 +1,126 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use pluto_core::cache::Keyspace;
+use crate::acl::{Acl, escape};
+use crate::middleware::{Middleware, Outcome};
+
+// Usage metering for chargeback: the keys, bytes and commands of each tenant
+// and of each key prefix in meter_prefixes. A command counts against the
+// tenant of the user who ran it and against the prefix of its first key, as
+// the key is stored. Keys and bytes are what the keyspace holds now; the
+// command counts run from startup.
+
+#[derive(Default)]
+struct Counts {
+    commands: AtomicU64,
+    writes: AtomicU64,
+}
+
+impl Counts {
+    fn count(&self, write: bool) {
+        self.commands.fetch_add(1, Ordering::Relaxed);
+        if write {
+            self.writes.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn load(&self) -> (u64, u64) {
+        (self.commands.load(Ordering::Relaxed), self.writes.load(Ordering::Relaxed))
+    }
+}
+
+// What one tenant or prefix holds and ran
+pub struct Meter {
+    pub kind: &'static str, // "tenant" or "prefix"
+    pub name: String,
+    pub keys: usize,
+    pub bytes: usize,
+    pub commands: u64,
+    pub writes: u64,
+}
+
+pub struct Metering {
+    prefixes: Vec<(String, usize, Counts)>, // prefix, index of its usage in the keyspace, commands
+    tenants: Mutex<BTreeMap<String, Counts>>,
+}
+
+impl Metering {
+    pub fn new(prefixes: &[String], cache: &mut Keyspace) -> Self {
+        let prefixes = prefixes.iter()
+            .map(|prefix| {
+                let usage = cache.track_usage(vec![format!("{}*", escape(prefix))]);
+                (prefix.clone(), usage, Counts::default())
+            })
+            .collect();
+        Metering { prefixes, tenants: Mutex::new(BTreeMap::new()) }
+    }
+
+    // Every tenant, then every prefix, each by name
+    pub fn meters(&self, acl: &Acl, cache: &Keyspace) -> Vec<Meter> {
+        let tenants = self.tenants.lock().unwrap();
+        let mut meters: Vec<Meter> = acl.tenant_usage(cache)
+            .map(|(tenant, keys, bytes)| {
+                let (commands, writes) = tenants.get(&tenant.rule.name).map_or((0, 0), Counts::load);
+                Meter { kind: "tenant", name: tenant.rule.name.clone(), keys, bytes, commands, writes }
+            })
+            .collect();
+        let mut prefixes: Vec<Meter> = self.prefixes.iter()
+            .map(|(prefix, usage, counts)| {
+                let usage = cache.usage(*usage);
+                let (commands, writes) = counts.load();
+                Meter { kind: "prefix", name: prefix.clone(), keys: usage.keys, bytes: usage.bytes, commands, writes }
+            })
+            .collect();
+        prefixes.sort_by(|a, b| a.name.cmp(&b.name));
+        meters.append(&mut prefixes);
+        meters
+    }
+}
+
+// The meters in the Prometheus text format, for /metrics on the admin port
+pub fn prometheus(meters: &[Meter]) -> String {
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, value: fn(&Meter) -> u64| {
+        out.push_str(&format!("# HELP {} {} by tenant and by metered key prefix\n# TYPE {} {}\n", name, help, name, kind));
+        for meter in meters {
+            out.push_str(&format!("{}{{{}=\"{}\"}} {}\n", name, meter.kind, label(&meter.name), value(meter)));
+        }
+    };
+    metric("flux_meter_keys", "gauge", "Keys held", |meter| meter.keys as u64);
+    metric("flux_meter_bytes", "gauge", "Bytes held", |meter| meter.bytes as u64);
+    metric("flux_meter_commands_total", "counter", "Commands run", |meter| meter.commands);
+    metric("flux_meter_writes_total", "counter", "Writes run", |meter| meter.writes);
+    out
+}
+
+// A Prometheus label value, escaped
+pub fn label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+impl Middleware for Metering {
+    fn after(&self, outcome: &Outcome) {
+        let user = outcome.caller.user.as_ref();
+        if let Some(tenant) = user.and_then(|user| user.tenant.as_ref()) {
+            let mut tenants = self.tenants.lock().unwrap();
+            tenants.entry(tenant.rule.name.clone()).or_default().count(outcome.write);
+        }
+        let Some(key) = outcome.keys.first() else {
+            return;
+        };
+        let key = match user {
+            Some(user) => user.stored_key(key),
+            None => key.clone(),
+        };
+        for (prefix, _, counts) in &self.prefixes {
+            if key.starts_with(prefix.as_str()) {
+                counts.count(outcome.write);
+            }
+        }
+    }
+
+    fn wants_keys(&self) -> bool {
+        !self.prefixes.is_empty()
+    }
+}
@@ -0,0 +1,218 @@
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+use pluto_core::cache::{ServerError, now_ms};
+use pluto_core::protocol::{Command, Response};
+use crate::acl::{Sandbox, User};
+use crate::audit::AuditLog;
+use crate::environment::FluxConfig;
+use crate::metering::Metering;
+use crate::state::ServerState;
+use crate::stats::{SlowEntry, Stats};
+use crate::validate::Validate;
+
+// Every command from a client passes through a chain of middleware on its
+// way in and out, whichever transport it came by. Concerns that apply to all
+// commands alike (authentication, ACLs, which port takes what, argument
+// checks, latency) live here rather than in the command handlers.
+
+// The transport a command arrived by
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Native,    // the data port
+    Admin,     // the admin port, which takes administrative commands only
+    WebSocket,
+    Http,
+    Grpc,
+}
+
+// Who sent a command
+#[derive(Debug, Clone)]
+pub struct Caller {
+    pub transport: Transport,
+    pub addr: Option<SocketAddr>,
+    pub authenticated: bool, // always true when no password or user is configured
+    pub user: Option<Arc<User>>, // the ACL user authenticated as; None has every right
+}
+
+impl Caller {
+    // A command as it runs for the caller, inside the tenant of its user
+    pub fn enter(&self, cmd: Command) -> Command {
+        match &self.user {
+            Some(user) => user.enter(cmd),
+            None => cmd,
+        }
+    }
+
+    // A reply or push as the caller sees it, from inside its tenant
+    pub fn leave(&self, response: Response) -> Response {
+        match &self.user {
+            Some(user) => user.leave(response),
+            None => response,
+        }
+    }
+
+    pub fn leave_error(&self, error: ServerError) -> ServerError {
+        match &self.user {
+            Some(user) => user.leave_error(error),
+            None => error,
+        }
+    }
+}
+
+// A command about to run
+pub struct Call<'a> {
+    pub command: &'a Command,
+    pub caller: &'a Caller,
+    pub state: &'a Arc<RwLock<ServerState>>, // not locked; a middleware locks it if it must
+}
+
+// A command that ran, or was refused by a middleware
+pub struct Outcome<'a> {
+    pub command: &'static str,
+    pub write: bool, // whether the command modifies the keyspace
+    pub keys: &'a [String], // empty unless a middleware asks for them
+    pub caller: &'a Caller,
+    pub result: &'a Result<Response, ServerError>,
+    pub elapsed: Duration,
+}
+
+pub trait Middleware: Send + Sync {
+    // Runs before the command; an error is sent as the command's reply
+    // instead of running it, and the middleware after this one are skipped
+    fn before(&self, _call: &Call) -> Result<(), ServerError> {
+        Ok(())
+    }
+
+    // Runs after the command, in the reverse order of `before`
+    fn after(&self, _outcome: &Outcome) {}
+
+    // Whether `after` needs the keys of the command, which cost a copy
+    fn wants_keys(&self) -> bool {
+        false
+    }
+}
+
+// The middleware commands pass through, outermost first
+#[derive(Default)]
+pub struct Chain {
+    layers: Vec<Arc<dyn Middleware>>,
+    wants_keys: bool,
+}
+
+impl Chain {
+    // The middleware every server runs, with the audit log when it is on
+    pub fn new(config: &FluxConfig, stats: Arc<Stats>, metering: Arc<Metering>, audit: Option<AuditLog>) -> Self {
+        let mut chain = Chain::default();
+        chain.push(Arc::new(Latency {
+            stats,
+            slower_than: Duration::from_micros(config.slowlog_slower_than_us),
+            max_len: config.slowlog_max_len,
+        }));
+        // Outside the checks below, so that refused commands are audited too
+        if let Some(audit) = audit {
+            chain.push(Arc::new(audit));
+        }
+        chain.push(Arc::new(AdminPort));
+        chain.push(Arc::new(RequireAuth));
+        chain.push(Arc::new(Sandbox));
+        // Only commands the caller may run are looked at closely
+        chain.push(Arc::new(Validate::new(config)));
+        // Inside the checks, so that only the commands that ran are metered
+        chain.push(metering);
+        chain
+    }
+
+    // Add a middleware inside the ones already there
+    pub fn push(&mut self, layer: Arc<dyn Middleware>) {
+        self.wants_keys |= layer.wants_keys();
+        self.layers.push(layer);
+    }
+
+    // Run a command through the chain, with `next` running the command itself
+    pub async fn run<F, Fut>(&self, cmd: Command, caller: &Caller, state: &Arc<RwLock<ServerState>>, next: F) -> Result<Response, ServerError>
+    where
+        F: FnOnce(Command) -> Fut,
+        Fut: Future<Output = Result<Response, ServerError>>,
+    {
+        let started = Instant::now();
+        let command = cmd.name();
+        let write = cmd.is_write();
+        let keys: Vec<String> = match self.wants_keys {
+            true => cmd.keys().into_iter().map(String::from).collect(),
+            false => Vec::new(),
+        };
+        let call = Call { command: &cmd, caller, state };
+        let mut entered = 0;
+        let mut refused = None;
+        for layer in &self.layers {
+            if let Err(e) = layer.before(&call) {
+                refused = Some(e);
+                break;
+            }
+            entered += 1;
+        }
+        let result = match refused {
+            Some(e) => Err(e),
+            None => next(cmd).await,
+        };
+        let outcome = Outcome { command, write, keys: &keys, caller, result: &result, elapsed: started.elapsed() };
+        for layer in self.layers[..entered].iter().rev() {
+            layer.after(&outcome);
+        }
+        result
+    }
+}
+
+// Records how long each command took, for INFO latencystats, and the
+// commands that ran out of time
+struct Latency {
+    stats: Arc<Stats>,
+    slower_than: Duration, // commands running longer go in the slowlog; zero logs none
+    max_len: usize,
+}
+
+impl Middleware for Latency {
+    fn after(&self, outcome: &Outcome) {
+        self.stats.record_latency(outcome.command, outcome.elapsed);
+        if let Err(ServerError::Timeout(_)) = outcome.result {
+            self.stats.timed_out_commands.fetch_add(1, Ordering::Relaxed);
+        }
+        if !self.slower_than.is_zero() && outcome.elapsed > self.slower_than {
+            self.stats.record_slow(SlowEntry {
+                id: 0,
+                at: now_ms(),
+                duration_us: outcome.elapsed.as_micros() as u64,
+                command: outcome.command,
+                client: outcome.caller.addr.map(|addr| addr.to_string()),
+                user: outcome.caller.user.as_ref().map(|user| user.rule.name.clone()),
+            }, self.max_len);
+        }
+    }
+}
+
+// Keeps the admin port to administrative commands
+struct AdminPort;
+
+impl Middleware for AdminPort {
+    fn before(&self, call: &Call) -> Result<(), ServerError> {
+        if call.caller.transport == Transport::Admin && !call.command.is_admin() {
+            return Err(ServerError::InvalidArgument("Command not allowed on the admin port".to_string()));
+        }
+        Ok(())
+    }
+}
+
+// Refuses everything but AUTH and HELLO until the caller authenticates
+struct RequireAuth;
+
+impl Middleware for RequireAuth {
+    fn before(&self, call: &Call) -> Result<(), ServerError> {
+        if !call.caller.authenticated && !matches!(call.command, Command::AUTH { .. } | Command::HELLO { .. }) {
+            return Err(ServerError::Unauthorized("Authentication required".to_string()));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,383 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use pluto_core::cache::{ServerError, dump_entry, now_ms};
+use pluto_core::cluster::TOTAL_SLOTS;
+use pluto_core::protocol::{Command, Response};
+use crate::peer::PeerConnection;
+use crate::state::ServerState;
+
+// Longest a MIGRATE waits for the target node before giving up on the key
+const MIGRATE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Move a key to the node at `address`: copy it there with RESTORE, wait for
+// the target to acknowledge it and only then delete the local copy. The key
+// is left in place when it was written to while the transfer was in flight.
+pub async fn migrate(
+    state: &Arc<RwLock<ServerState>>,
+    key: String,
+    address: String,
+    copy: bool,
+    replace: bool,
+) -> Result<Response, ServerError> {
+    let (payload, sent, credentials) = {
+        let state = state.read().unwrap();
+        let Some(entry) = state.cache.get(&key) else {
+            return Err(ServerError::KeyNotFound(key));
+        };
+        let sent = (entry.data.clone(), entry.expires_at);
+        (dump_entry(entry, now_ms()), sent, state.config.peer_credentials())
+    };
+
+    let commands = [
+        Command::ASKING,
+        Command::RESTORE { key: key.clone(), payload: payload.to_vec(), replace },
+    ];
+    let transfer = async {
+        PeerConnection::connect(&address, &credentials).await?.send(&commands).await
+    };
+    let responses = tokio::time::timeout(MIGRATE_TIMEOUT, transfer)
+        .await
+        .map_err(|_| ServerError::Io(std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            format!("Timed out migrating {} to {}", key, address),
+        )))??;
+    match responses.last() {
+        Some(Response::Success) => {}
+        Some(Response::Error(e)) => {
+            return Err(ServerError::InvalidArgument(format!("Target {} refused {}: {}", address, key, e)));
+        }
+        Some(response) => {
+            return Err(ServerError::InvalidArgument(format!(
+                "Unexpected response from {} while migrating {}: {:?}", address, key, response
+            )));
+        }
+        None => unreachable!("RESTORE is always sent"),
+    }
+
+    if !copy {
+        let mut state = state.write().unwrap();
+        let unchanged = state.cache.get(&key).is_some_and(|entry| (&entry.data, entry.expires_at) == (&sent.0, sent.1));
+        if unchanged {
+            state.cache.remove(&key);
+            state.replication.propagate_migrated(Command::DEL { keys: vec![key] });
+        }
+    }
+    Ok(Response::Success)
+}
+
+// Slot migrations survive restarts in this file until every slot is done
+const MIGRATION_FILE: &str = "migration.json";
+
+// Keys sent to the target in one round trip
+const MIGRATE_BATCH: usize = 100;
+
+// Wait before retrying a batch the target didn't take
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+// How often the progress markers are written while keys are moving
+const PERSIST_INTERVAL: Duration = Duration::from_secs(1);
+
+// Progress of one slot, persisted as the migration goes
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct SlotProgress {
+    keys_moved: u64,
+    done: bool, // the slot held no more keys
+}
+
+#[derive(Serialize, Deserialize)]
+struct MigrationFile {
+    target: String,
+    slots: BTreeMap<usize, SlotProgress>,
+    keys_moved: u64,
+    bytes_moved: u64,
+}
+
+// Progress of the current or last slot migration, for INFO cluster
+pub struct SlotMigration {
+    running: AtomicBool,
+    abort: AtomicBool,
+    pub target: Mutex<String>,
+    pub slots_total: AtomicUsize,
+    pub slots_done: AtomicUsize,
+    pub keys_moved: AtomicU64,
+    pub bytes_moved: AtomicU64,
+}
+
+impl SlotMigration {
+    pub fn new() -> Self {
+        SlotMigration {
+            running: AtomicBool::new(false),
+            abort: AtomicBool::new(false),
+            target: Mutex::new(String::new()),
+            slots_total: AtomicUsize::new(0),
+            slots_done: AtomicUsize::new(0),
+            keys_moved: AtomicU64::new(0),
+            bytes_moved: AtomicU64::new(0),
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::SeqCst)
+    }
+
+    // Ask the running migration to stop after its current batch
+    pub fn abort(&self) -> bool {
+        if !self.is_running() {
+            return false;
+        }
+        self.abort.store(true, Ordering::SeqCst);
+        true
+    }
+}
+
+impl Default for SlotMigration {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Start moving the keys of `slots` to `address` in the background
+pub fn migrate_slots(state: &Arc<RwLock<ServerState>>, slots: Vec<usize>, address: String) -> Result<(), ServerError> {
+    if let Some(slot) = slots.iter().find(|slot| **slot >= TOTAL_SLOTS) {
+        return Err(ServerError::InvalidArgument(format!("Slot {} out of range", slot)));
+    }
+    let migration = {
+        let state = state.read().unwrap();
+        if state.replication.is_replica() {
+            return Err(ServerError::InvalidArgument("MIGRATE_SLOTS is sent to a primary".to_string()));
+        }
+        if address == state.cluster.self_addr {
+            return Err(ServerError::InvalidArgument("Can't migrate slots to this node itself".to_string()));
+        }
+        state.migration.clone()
+    };
+    if migration.running.swap(true, Ordering::SeqCst) {
+        return Err(ServerError::InvalidArgument("A slot migration is already running".to_string()));
+    }
+    let file = MigrationFile {
+        target: address,
+        slots: slots.into_iter().map(|slot| (slot, SlotProgress::default())).collect(),
+        keys_moved: 0,
+        bytes_moved: 0,
+    };
+    if let Err(e) = persist(&file) {
+        migration.running.store(false, Ordering::SeqCst);
+        return Err(ServerError::Io(e));
+    }
+    tokio::spawn(run(state.clone(), migration, file));
+    Ok(())
+}
+
+// Pick up a migration that was cut short by a restart
+pub fn resume(state: &Arc<RwLock<ServerState>>) {
+    let file = match fs::read_to_string(MIGRATION_FILE) {
+        Ok(content) => match serde_json::from_str::<MigrationFile>(&content) {
+            Ok(file) => file,
+            Err(e) => {
+                warn!("Ignoring unreadable {}: {}", MIGRATION_FILE, e);
+                return;
+            }
+        },
+        Err(_) => return,
+    };
+    let migration = state.read().unwrap().migration.clone();
+    if file.slots.values().all(|progress| progress.done) || migration.running.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    info!("Resuming the migration of {} slots to {}", file.slots.values().filter(|progress| !progress.done).count(), file.target);
+    tokio::spawn(run(state.clone(), migration, file));
+}
+
+// Move the keys of every slot not done yet, a batch at a time and no faster
+// than `migrate_keys_per_sec` and `migrate_bytes_per_sec`. Keys leave this node
+// once the target stored them, so a resumed run finds only the ones left.
+async fn run(state: Arc<RwLock<ServerState>>, migration: Arc<SlotMigration>, mut file: MigrationFile) {
+    let (credentials, keys_per_sec, bytes_per_sec, shutdown, acl) = {
+        let state = state.read().unwrap();
+        let config = &state.config;
+        (config.peer_credentials(), config.migrate_keys_per_sec, config.migrate_bytes_per_sec, state.shutdown.clone(), state.acl.clone())
+    };
+    *migration.target.lock().unwrap() = file.target.clone();
+    migration.abort.store(false, Ordering::SeqCst);
+    migration.slots_total.store(file.slots.len(), Ordering::SeqCst);
+    migration.keys_moved.store(file.keys_moved, Ordering::SeqCst);
+    migration.bytes_moved.store(file.bytes_moved, Ordering::SeqCst);
+    info!("Migrating {} slots to {} at up to {} keys/s", file.slots.len(), file.target, keys_per_sec);
+
+    // The rate is measured from the start of this run
+    let started = Instant::now();
+    let (mut keys_sent, mut bytes_sent) = (0u64, 0u64);
+    let mut peer: Option<PeerConnection> = None;
+    'passes: loop {
+        migration.slots_done.store(file.slots.values().filter(|progress| progress.done).count(), Ordering::SeqCst);
+        // One scan finds the keys of every pending slot; slots found empty
+        // are done, the others are scanned again after their keys moved
+        let pending = pending_keys(&state, &mut file);
+        if let Err(e) = persist(&file) {
+            warn!("Failed to write {}: {}", MIGRATION_FILE, e);
+        }
+        if pending.is_empty() {
+            break;
+        }
+        let mut persisted = Instant::now();
+        for batch in pending.chunks(MIGRATE_BATCH) {
+            if migration.abort.load(Ordering::SeqCst) || shutdown.is_triggered() {
+                break 'passes;
+            }
+            let (moved, bytes) = loop {
+                if peer.is_none() {
+                    peer = PeerConnection::connect(&file.target, &credentials).await.map_err(|e| {
+                        warn!("Slot migration can't reach {}: {}", file.target, e);
+                    }).ok();
+                }
+                if let Some(conn) = peer.as_mut() {
+                    match move_batch(&state, conn, batch).await {
+                        Ok(result) => break result,
+                        Err(e) => {
+                            warn!("Slot migration to {} failed, retrying: {}", file.target, e);
+                            peer = None;
+                        }
+                    }
+                }
+                tokio::select! {
+                    _ = tokio::time::sleep(RETRY_DELAY) => {}
+                    _ = shutdown.wait() => break 'passes,
+                }
+                if migration.abort.load(Ordering::SeqCst) {
+                    break 'passes;
+                }
+            };
+            for key in &moved {
+                file.slots.entry(acl.key_slot(key)).or_default().keys_moved += 1;
+            }
+            file.keys_moved += moved.len() as u64;
+            file.bytes_moved += bytes;
+            migration.keys_moved.store(file.keys_moved, Ordering::SeqCst);
+            migration.bytes_moved.store(file.bytes_moved, Ordering::SeqCst);
+            if persisted.elapsed() >= PERSIST_INTERVAL {
+                if let Err(e) = persist(&file) {
+                    warn!("Failed to write {}: {}", MIGRATION_FILE, e);
+                }
+                persisted = Instant::now();
+            }
+
+            // Pause until the keys and bytes sent so far fit the limits
+            keys_sent += batch.len() as u64;
+            bytes_sent += bytes;
+            let mut due = Duration::ZERO;
+            if keys_per_sec > 0 {
+                due = due.max(Duration::from_secs_f64(keys_sent as f64 / keys_per_sec as f64));
+            }
+            if bytes_per_sec > 0 {
+                due = due.max(Duration::from_secs_f64(bytes_sent as f64 / bytes_per_sec as f64));
+            }
+            if let Some(pause) = due.checked_sub(started.elapsed()) {
+                tokio::time::sleep(pause).await;
+            }
+        }
+    }
+
+    let aborted = migration.abort.swap(false, Ordering::SeqCst);
+    let finished = file.slots.values().all(|progress| progress.done);
+    let saved = if finished || aborted { fs::remove_file(MIGRATION_FILE) } else { persist(&file) };
+    if let Err(e) = saved {
+        warn!("Failed to update {}: {}", MIGRATION_FILE, e);
+    }
+    migration.slots_done.store(file.slots.values().filter(|progress| progress.done).count(), Ordering::SeqCst);
+    migration.running.store(false, Ordering::SeqCst);
+    if finished {
+        info!("Migrated {} keys of {} slots to {}", file.keys_moved, file.slots.len(), file.target);
+    } else if aborted {
+        info!("Slot migration to {} aborted after {} keys", file.target, file.keys_moved);
+    } else {
+        info!("Slot migration to {} paused after {} keys; it resumes on restart", file.target, file.keys_moved);
+    }
+}
+
+// Keys of the slots not done yet in slot order, marking the slots that hold
+// none as done
+fn pending_keys(state: &Arc<RwLock<ServerState>>, file: &mut MigrationFile) -> Vec<String> {
+    let mut pending: BTreeMap<usize, Vec<String>> = BTreeMap::new();
+    {
+        let state = state.read().unwrap();
+        let now = now_ms();
+        for (key, entry) in state.cache.iter() {
+            let key = key.text();
+            let slot = state.acl.key_slot(&key);
+            if !entry.is_expired(now) && file.slots.get(&slot).is_some_and(|progress| !progress.done) {
+                pending.entry(slot).or_default().push(key.into_owned());
+            }
+        }
+    }
+    for (slot, progress) in file.slots.iter_mut() {
+        if !pending.contains_key(slot) {
+            progress.done = true;
+        }
+    }
+    pending.into_values().flatten().collect()
+}
+
+// Copy a batch of keys to the target and delete the ones it stored, unless
+// they were written to in the meantime. Returns the keys moved and their size.
+async fn move_batch(
+    state: &Arc<RwLock<ServerState>>,
+    peer: &mut PeerConnection,
+    keys: &[String],
+) -> Result<(Vec<String>, u64), ServerError> {
+    let mut sent = Vec::new();
+    let mut commands = Vec::new();
+    {
+        let state = state.read().unwrap();
+        let now = now_ms();
+        for key in keys {
+            // Keys deleted since the scan are skipped
+            if let Some(entry) = state.cache.get(key) {
+                let payload = dump_entry(entry, now);
+                sent.push((key.clone(), entry.data.clone(), entry.expires_at, payload.len() as u64));
+                commands.push(Command::ASKING);
+                commands.push(Command::RESTORE { key: key.clone(), payload: payload.to_vec(), replace: true });
+            }
+        }
+    }
+    if commands.is_empty() {
+        return Ok((Vec::new(), 0));
+    }
+    let responses = tokio::time::timeout(MIGRATE_TIMEOUT, peer.send(&commands))
+        .await
+        .map_err(|_| ServerError::Io(std::io::Error::new(std::io::ErrorKind::TimedOut, "Timed out sending a batch")))??;
+
+    let (mut moved, mut bytes) = (Vec::new(), 0);
+    let mut state = state.write().unwrap();
+    for ((key, data, expires_at, size), response) in sent.into_iter().zip(responses.into_iter().skip(1).step_by(2)) {
+        match response {
+            Response::Success => {}
+            Response::Error(e) => return Err(ServerError::InvalidArgument(format!("Target refused {}: {}", key, e))),
+            other => return Err(ServerError::InvalidArgument(format!("Unexpected response for {}: {:?}", key, other))),
+        }
+        let unchanged = state.cache.get(&key).is_some_and(|entry| (&entry.data, entry.expires_at) == (&data, expires_at));
+        // A key written to since is sent again on the next pass
+        if unchanged {
+            state.cache.remove(&key);
+            state.replication.propagate_migrated(Command::DEL { keys: vec![key.clone()] });
+            moved.push(key);
+            bytes += size;
+        }
+    }
+    Ok((moved, bytes))
+}
+
+// Written like the cluster file: to a temporary name, synced, then renamed
+fn persist(file: &MigrationFile) -> std::io::Result<()> {
+    let json = serde_json::to_string_pretty(file)?;
+    let tmp_path = format!("{}.tmp", MIGRATION_FILE);
+    let mut tmp = fs::File::create(&tmp_path)?;
+    tmp.write_all(json.as_bytes())?;
+    tmp.sync_all()?;
+    fs::rename(&tmp_path, MIGRATION_FILE)
+}
@@ -0,0 +1,156 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use socket2::{Domain, SockRef, Socket, TcpKeepalive, Type};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use log::debug;
+use crate::environment::FluxConfig;
+use crate::state::ServerState;
+use crate::api::handle_client;
+
+// Which port a listener serves
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListenerKind {
+    Data,  // cache traffic, every command
+    Admin, // control plane, administrative commands only
+}
+
+// Hands admin connections that speak HTTP over to the admin HTTP server
+pub type HttpHandoff = mpsc::Sender<(TcpStream, SocketAddr)>;
+
+// Sent to the clients protected mode refuses
+pub const PROTECTED_MODE_REFUSAL: &str = "DENIED flux-cache is in protected mode: it listens on a non-loopback \
+    address and no requirepass is set, so only clients on this host may connect. Set requirepass, bind to \
+    127.0.0.1 only, or set protected_mode = false in flxc.toml if every host that can reach this one is trusted";
+
+// Whether protected mode refuses a client connecting from `addr`
+pub fn protected_refuses(conf: &FluxConfig, addr: SocketAddr) -> bool {
+    conf.is_protected() && !addr.ip().to_canonical().is_loopback()
+}
+
+// Turn the configured bind IPs into socket addresses on the given port
+pub fn parse_bind_addrs(bind: &[String], port: u16) -> Result<Vec<SocketAddr>, String> {
+    if bind.is_empty() {
+        return Err("no bind address configured".to_string());
+    }
+    bind.iter()
+        .map(|ip| {
+            ip.parse::<IpAddr>()
+                .map(|ip| SocketAddr::new(ip, port))
+                .map_err(|e| format!("{}: {}", ip, e))
+        })
+        .collect()
+}
+
+// Create a listening socket with the TCP options from the config
+pub fn bind_listener(bind_addr: SocketAddr, conf: &FluxConfig) -> std::io::Result<TcpListener> {
+    let socket = Socket::new(
+        match bind_addr {
+            SocketAddr::V4(_) => Domain::IPV4,
+            SocketAddr::V6(_) => Domain::IPV6,
+        },
+        Type::STREAM,
+        None,
+    )?;
+
+    // Buffer sizes set on the listener are inherited by accepted sockets
+    set_buffer_sizes(&socket, conf)?;
+
+    // Allow address reuse to avoid "address already in use" errors
+    socket.set_reuse_address(true)?;
+    
+    // Keep IPv6 listeners IPv6-only so they can sit next to IPv4 listeners on the same port
+    if bind_addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+
+    // Bind and convert to tokio listener
+    socket.bind(&bind_addr.into())?;
+    socket.listen(conf.tcp_backlog)?;
+    socket.set_nonblocking(true)?; // Required before handing the socket to tokio
+
+    TcpListener::from_std(socket.into())
+}
+
+// Apply per-connection TCP options to an accepted socket
+pub fn tune_client_socket(socket: &SockRef<'_>, conf: &FluxConfig) -> std::io::Result<()> {
+    set_buffer_sizes(socket, conf)?;
+    socket.set_nodelay(conf.tcp_nodelay)?;
+    if conf.tcp_keepalive_secs > 0 {
+        let interval = Duration::from_secs(conf.tcp_keepalive_secs);
+        let keepalive = TcpKeepalive::new()
+            .with_time(interval)
+            .with_interval(interval);
+        socket.set_tcp_keepalive(&keepalive)?;
+    }
+    Ok(())
+}
+
+// Set send/receive buffer sizes; zero keeps the OS default
+fn set_buffer_sizes(socket: &Socket, conf: &FluxConfig) -> std::io::Result<()> {
+    if conf.tcp_recv_buffer > 0 {
+        socket.set_recv_buffer_size(conf.tcp_recv_buffer)?;
+    }
+    if conf.tcp_send_buffer > 0 {
+        socket.set_send_buffer_size(conf.tcp_send_buffer)?;
+    }
+    Ok(())
+}
+
+// Accept connections on a listener and hand each one to its own task until
+// the server shuts down
+pub async fn serve(
+    listener: TcpListener,
+    state: Arc<RwLock<ServerState>>,
+    kind: ListenerKind,
+    http: Option<HttpHandoff>,
+) {
+    // Registry of active connections, TCP options and the shutdown signal
+    let (clients, conf, shutdown, health) = {
+        let state = state.read().unwrap();
+        (state.clients.clone(), state.config.clone(), state.shutdown.clone(), state.health.clone())
+    };
+    
+    health.listener_started();
+    loop {
+        let (socket, addr) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    eprintln!("Failed to accept connection: {}", e);
+                    continue;
+                }
+            },
+            _ = shutdown.wait() => {
+                debug!("Accept loop on {:?} stopping for shutdown", listener.local_addr());
+                break;
+            }
+        };
+        debug!("Accepted connection from: {} (active: {})", addr, clients.len());
+        // Apply TCP options in place on the tokio socket
+        if let Err(e) = tune_client_socket(&SockRef::from(&socket), &conf) {
+            debug!("Failed to apply TCP options for {}: {}", addr, e);
+        }
+        // Spawn a new task to handle the connection
+        let state = state.clone();
+        let http = http.clone();
+        tokio::spawn(async move {
+            if let Some(http) = http
+                && is_http(&socket).await {
+                let _ = http.send((socket, addr)).await;
+                return;
+            }
+            handle_client(socket, state, kind).await;
+            debug!("Client handler task completed for {}", addr);
+        });
+    }
+    health.listener_stopped();
+}
+
+// Whether a new connection opens with an HTTP request line. Native protocol
+// commands start with JSON, which never begins with an uppercase letter.
+async fn is_http(socket: &TcpStream) -> bool {
+    let mut first = [0u8; 1];
+    matches!(socket.peek(&mut first).await, Ok(1) if first[0].is_ascii_uppercase())
+}
@@ -0,0 +1,313 @@
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use pluto_core::cache::ServerError;
+use pluto_core::protocol::{Command, ErrorCode, Response};
+use crate::backup::uri_encode;
+use crate::peer::{PeerConnection, PeerCredentials};
+
+// Backing stores keys are cached from. A GET missing a key under an origin's
+// prefix fetches it from there and keeps it; with `write_through`, SET and DEL
+// from clients reach the origin before they are applied here. An origin is
+//
+//   http(s)://host/path    GET, PUT and DELETE of <url>/<key>; 404 means missing
+//   pluto://host:port      another flux-cache node
+//   redis://host:port      a Redis server
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OriginRule {
+    pub prefix: String, // keys starting with it are backed by this origin
+    pub url: String,
+    #[serde(default)]
+    pub write_through: bool,
+    #[serde(default)]
+    pub ttl_secs: u64, // values fetched expire after this; 0 keeps them
+    #[serde(default)]
+    pub missing_ttl_ms: u64, // keys the origin lacks are cached as missing this long; 0 doesn't
+    #[serde(default)]
+    pub password: String, // AUTH sent to a pluto or Redis origin
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+fn default_timeout_ms() -> u64 {
+    1000
+}
+
+enum Backend {
+    Http { client: reqwest::Client, base: String },
+    Pluto(String),
+    Redis(String),
+}
+
+struct Origin {
+    rule: OriginRule,
+    backend: Backend,
+}
+
+// The configured origins, with counters for INFO origin
+#[derive(Default)]
+pub struct Origins {
+    origins: Vec<Origin>,
+    pub fetches: AtomicU64,
+    pub fetch_misses: AtomicU64,
+    pub fetch_errors: AtomicU64,
+    pub writes: AtomicU64,
+    pub write_errors: AtomicU64,
+}
+
+impl Origins {
+    pub fn new(rules: &[OriginRule]) -> Result<Self, String> {
+        let mut origins = Vec::with_capacity(rules.len());
+        for rule in rules {
+            let backend = if rule.url.starts_with("http://") || rule.url.starts_with("https://") {
+                let client = reqwest::Client::builder()
+                    .timeout(Duration::from_millis(rule.timeout_ms))
+                    .build()
+                    .map_err(|e| e.to_string())?;
+                Backend::Http { client, base: rule.url.trim_end_matches('/').to_string() }
+            } else if let Some(address) = rule.url.strip_prefix("pluto://") {
+                Backend::Pluto(address.to_string())
+            } else if let Some(address) = rule.url.strip_prefix("redis://") {
+                Backend::Redis(address.to_string())
+            } else {
+                return Err(format!("origin {} is not an http(s)://, pluto:// or redis:// URL", rule.url));
+            };
+            origins.push(Origin { rule: rule.clone(), backend });
+        }
+        Ok(Origins { origins, ..Default::default() })
+    }
+
+    pub fn len(&self) -> usize {
+        self.origins.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.origins.is_empty()
+    }
+
+    // The origin with the longest prefix of `key`
+    fn for_key(&self, key: &str) -> Option<&Origin> {
+        self.origins.iter()
+            .filter(|origin| key.starts_with(&origin.rule.prefix))
+            .max_by_key(|origin| origin.rule.prefix.len())
+    }
+
+    // Fetch a missing key, returning its value and how long to keep it, or
+    // None when no origin backs it or the origin lacks it too
+    pub async fn fetch(&self, key: &str) -> Result<Option<(Vec<u8>, u64)>, ServerError> {
+        let Some(origin) = self.for_key(key) else {
+            return Ok(None);
+        };
+        self.fetches.fetch_add(1, Ordering::Relaxed);
+        match origin.get(key).await {
+            Ok(Some(value)) => Ok(Some((value, origin.rule.ttl_secs))),
+            Ok(None) => {
+                self.fetch_misses.fetch_add(1, Ordering::Relaxed);
+                Ok(None)
+            }
+            Err(e) => {
+                self.fetch_errors.fetch_add(1, Ordering::Relaxed);
+                Err(e)
+            }
+        }
+    }
+
+    // How long a miss of the origin backing `key` is cached for
+    pub fn missing_ttl_ms(&self, key: &str) -> u64 {
+        self.for_key(key).map_or(0, |origin| origin.rule.missing_ttl_ms)
+    }
+
+    // Pass a client's SET, GETSET, MSETNX, DEL or UNLINK to the origins writing through
+    pub async fn write_through(&self, cmd: &Command) -> Result<(), ServerError> {
+        let writes: Vec<(&Origin, &str, Option<&[u8]>)> = match cmd {
+            Command::SET { key, value, .. } | Command::GETSET { key, value } => self.for_key(key).map(|origin| (origin, key.as_str(), Some(&value[..]))).into_iter().collect(),
+            Command::DEL { keys } | Command::UNLINK { keys } => keys.iter().filter_map(|key| self.for_key(key).map(|origin| (origin, key.as_str(), None))).collect(),
+            Command::MSETNX { entries } => entries.iter().filter_map(|(key, value)| self.for_key(key).map(|origin| (origin, key.as_str(), Some(&value[..])))).collect(),
+            _ => Vec::new(),
+        };
+        for (origin, key, value) in writes {
+            if !origin.rule.write_through {
+                continue;
+            }
+            self.writes.fetch_add(1, Ordering::Relaxed);
+            let written = match value {
+                Some(value) => origin.put(key, value).await,
+                None => origin.delete(key).await,
+            };
+            if let Err(e) = written {
+                self.write_errors.fetch_add(1, Ordering::Relaxed);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Origin {
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, ServerError> {
+        self.timed(async {
+            match &self.backend {
+                Backend::Http { client, base } => {
+                    let response = client.get(format!("{}/{}", base, uri_encode(key, true))).send().await.map_err(http_error)?;
+                    if response.status() == reqwest::StatusCode::NOT_FOUND {
+                        return Ok(None);
+                    }
+                    let response = response.error_for_status().map_err(http_error)?;
+                    Ok(Some(response.bytes().await.map_err(http_error)?.to_vec()))
+                }
+                Backend::Pluto(address) => {
+                    let mut peer = PeerConnection::connect(address, &PeerCredentials::password(&self.rule.password)).await?;
+                    match peer.send(&[Command::GET { key: key.to_string() }]).await?.pop() {
+                        Some(Response::Data(value) | Response::Stale(value)) => Ok(Some(value.to_vec())),
+                        Some(Response::Missing | Response::Nil) => Ok(None),
+                        other => Err(unexpected(&self.rule.url, other)),
+                    }
+                }
+                Backend::Redis(address) => {
+                    match self.redis(address, &[b"GET", key.as_bytes()]).await? {
+                        Reply::Bulk(value) => Ok(value),
+                        reply => Err(ServerError::Origin(format!("{} replied {:?} to GET", self.rule.url, reply))),
+                    }
+                }
+            }
+        }).await
+    }
+
+    async fn put(&self, key: &str, value: &[u8]) -> Result<(), ServerError> {
+        self.timed(async {
+            match &self.backend {
+                Backend::Http { client, base } => {
+                    client.put(format!("{}/{}", base, uri_encode(key, true)))
+                        .body(value.to_vec())
+                        .send()
+                        .await
+                        .and_then(|response| response.error_for_status())
+                        .map_err(http_error)?;
+                    Ok(())
+                }
+                Backend::Pluto(address) => {
+                    let mut peer = PeerConnection::connect(address, &PeerCredentials::password(&self.rule.password)).await?;
+                    match peer.send(&[Command::SET { key: key.to_string(), value: value.to_vec(), visible_at: None }]).await?.pop() {
+                        Some(Response::Success) => Ok(()),
+                        other => Err(unexpected(&self.rule.url, other)),
+                    }
+                }
+                Backend::Redis(address) => {
+                    self.redis(address, &[b"SET", key.as_bytes(), value]).await?;
+                    Ok(())
+                }
+            }
+        }).await
+    }
+
+    // Deleting a key the origin lacks is no error
+    async fn delete(&self, key: &str) -> Result<(), ServerError> {
+        self.timed(async {
+            match &self.backend {
+                Backend::Http { client, base } => {
+                    let response = client.delete(format!("{}/{}", base, uri_encode(key, true))).send().await.map_err(http_error)?;
+                    if response.status() != reqwest::StatusCode::NOT_FOUND {
+                        response.error_for_status().map_err(http_error)?;
+                    }
+                    Ok(())
+                }
+                Backend::Pluto(address) => {
+                    let mut peer = PeerConnection::connect(address, &PeerCredentials::password(&self.rule.password)).await?;
+                    match peer.send(&[Command::DEL { keys: vec![key.to_string()] }]).await?.pop() {
+                        Some(Response::Success) => Ok(()),
+                        Some(Response::Error(e)) if e.code == ErrorCode::NotFound => Ok(()),
+                        other => Err(unexpected(&self.rule.url, other)),
+                    }
+                }
+                Backend::Redis(address) => {
+                    self.redis(address, &[b"DEL", key.as_bytes()]).await?;
+                    Ok(())
+                }
+            }
+        }).await
+    }
+
+    async fn timed<T>(&self, request: impl Future<Output = Result<T, ServerError>>) -> Result<T, ServerError> {
+        tokio::time::timeout(Duration::from_millis(self.rule.timeout_ms), request).await
+            .map_err(|_| ServerError::Origin(format!("{} took over {} ms", self.rule.url, self.rule.timeout_ms)))?
+            .map_err(|e| match e {
+                ServerError::Origin(_) => e,
+                e => ServerError::Origin(format!("{}: {}", self.rule.url, e)),
+            })
+    }
+
+    // Run one command on a Redis origin, authenticating first when needed
+    async fn redis(&self, address: &str, args: &[&[u8]]) -> Result<Reply, ServerError> {
+        let mut stream = BufReader::new(TcpStream::connect(address).await?);
+        if !self.rule.password.is_empty() {
+            write_resp(&mut stream, &[b"AUTH", self.rule.password.as_bytes()]).await?;
+            if let Reply::Error(e) = read_resp(&mut stream).await? {
+                return Err(ServerError::Unauthorized(format!("{} refused AUTH: {}", self.rule.url, e)));
+            }
+        }
+        write_resp(&mut stream, args).await?;
+        match read_resp(&mut stream).await? {
+            Reply::Error(e) => Err(ServerError::Origin(format!("{}: {}", self.rule.url, e))),
+            reply => Ok(reply),
+        }
+    }
+}
+
+// The replies a Redis origin sends to GET, SET and DEL
+#[derive(Debug)]
+enum Reply {
+    Simple,
+    Error(String),
+    Integer,
+    Bulk(Option<Vec<u8>>),
+}
+
+async fn write_resp(stream: &mut BufReader<TcpStream>, args: &[&[u8]]) -> Result<(), ServerError> {
+    let mut out = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        out.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        out.extend_from_slice(arg);
+        out.extend_from_slice(b"\r\n");
+    }
+    stream.get_mut().write_all(&out).await?;
+    Ok(())
+}
+
+async fn read_resp(stream: &mut BufReader<TcpStream>) -> Result<Reply, ServerError> {
+    let mut line = String::new();
+    if stream.read_line(&mut line).await? == 0 {
+        return Err(ServerError::Io(std::io::ErrorKind::UnexpectedEof.into()));
+    }
+    let line = line.trim_end_matches("\r\n");
+    let invalid = || ServerError::Origin(format!("invalid reply from Redis: {}", line));
+    match line.split_at_checked(1).ok_or_else(invalid)? {
+        ("+", _) => Ok(Reply::Simple),
+        ("-", message) => Ok(Reply::Error(message.to_string())),
+        (":", _) => Ok(Reply::Integer),
+        ("$", len) => {
+            let Ok(len) = len.parse::<usize>() else {
+                return Ok(Reply::Bulk(None)); // $-1
+            };
+            let mut data = vec![0u8; len + 2];
+            stream.read_exact(&mut data).await?;
+            data.truncate(len);
+            Ok(Reply::Bulk(Some(data)))
+        }
+        _ => Err(invalid()),
+    }
+}
+
+fn http_error(e: reqwest::Error) -> ServerError {
+    ServerError::Origin(e.to_string())
+}
+
+fn unexpected(url: &str, response: Option<Response>) -> ServerError {
+    match response {
+        Some(Response::Error(e)) => ServerError::Origin(format!("{} refused: {}", url, e)),
+        other => ServerError::Origin(format!("Unexpected response from {}: {:?}", url, other)),
+    }
+}
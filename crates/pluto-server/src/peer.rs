@@ -0,0 +1,144 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use bytes::BytesMut;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use pluto_core::cache::ServerError;
+use pluto_core::codec::{encode_command, parse_response, Encoding};
+use pluto_core::protocol::{Command, Response};
+use crate::buffer::READ_BUFFER_SIZE;
+
+// What one node authenticates to another with: requirepass, or the ACL user
+// cluster_user names once users are configured
+#[derive(Debug, Clone, Default)]
+pub struct PeerCredentials {
+    pub username: Option<String>,
+    pub password: String,
+}
+
+impl PeerCredentials {
+    // Credentials of requirepass alone, as origins take them
+    pub fn password(password: &str) -> Self {
+        PeerCredentials { username: None, password: password.to_string() }
+    }
+
+    // The AUTH to open a connection with, None when there is no password
+    pub fn auth(&self) -> Option<Command> {
+        (!self.password.is_empty()).then(|| Command::AUTH { username: self.username.clone(), password: self.password.clone() })
+    }
+}
+
+// A native protocol connection to another node, for commands one node sends another
+pub struct PeerConnection {
+    stream: TcpStream,
+    buf: BytesMut,
+    encoding: Encoding,
+}
+
+impl PeerConnection {
+    // Connect and authenticate with `credentials` unless they have no password
+    pub async fn connect(address: &str, credentials: &PeerCredentials) -> Result<Self, ServerError> {
+        let mut peer = PeerConnection {
+            stream: TcpStream::connect(address).await?,
+            buf: BytesMut::with_capacity(READ_BUFFER_SIZE),
+            encoding: Encoding::Json,
+        };
+        if let Some(auth) = credentials.auth()
+            && let [Response::Error(e)] = peer.send(&[auth]).await?.as_slice() {
+            return Err(ServerError::Unauthorized(format!("{} refused AUTH: {}", address, e)));
+        }
+        Ok(peer)
+    }
+
+    // Connect, authenticate and switch to `encoding`, for peers sending bulk data
+    pub async fn connect_with(address: &str, credentials: &PeerCredentials, encoding: Encoding) -> Result<Self, ServerError> {
+        let mut peer = PeerConnection::connect(address, credentials).await?;
+        if encoding != Encoding::Json {
+            // The reply still comes in the old encoding
+            if let [Response::Error(e)] = peer.send(&[Command::ENCODING { name: encoding }]).await?.as_slice() {
+                return Err(ServerError::InvalidArgument(format!("{} refused {:?}: {}", address, encoding, e)));
+            }
+            peer.encoding = encoding;
+        }
+        Ok(peer)
+    }
+
+    // Send commands in one write and collect a response per command
+    pub async fn send(&mut self, commands: &[Command]) -> Result<Vec<Response>, ServerError> {
+        let mut request = Vec::new();
+        for cmd in commands {
+            let body = encode_command(cmd, self.encoding)?;
+            if self.encoding != Encoding::Json {
+                request.extend_from_slice(&(body.len() as u32).to_be_bytes());
+            }
+            request.extend(body);
+        }
+        self.stream.write_all(&request).await?;
+
+        let mut responses = Vec::with_capacity(commands.len());
+        while responses.len() < commands.len() {
+            match parse_response(&self.buf, self.encoding).map_err(ServerError::Encoding)? {
+                Some((response, used)) => {
+                    let _ = self.buf.split_to(used);
+                    responses.push(response);
+                }
+                None => {
+                    if self.stream.read_buf(&mut self.buf).await? == 0 {
+                        return Err(ServerError::Io(std::io::ErrorKind::UnexpectedEof.into()));
+                    }
+                }
+            }
+        }
+        Ok(responses)
+    }
+}
+
+// Idle connections kept per node, past which a returned one is closed
+const MAX_IDLE_PER_PEER: usize = 8;
+
+// Connections to other nodes kept open between commands, for those sent
+// often enough that connecting and authenticating each time would show
+pub struct PeerPool {
+    idle: Mutex<HashMap<String, Vec<PeerConnection>>>,
+}
+
+impl PeerPool {
+    pub fn new() -> Self {
+        PeerPool {
+            idle: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Send commands over an idle connection to `address`, or a new one when
+    // there is none. An idle connection the node closed meanwhile fails, and
+    // the commands are sent again over a new one, so they must be safe to
+    // run twice.
+    pub async fn send(&self, address: &str, credentials: &PeerCredentials, commands: &[Command]) -> Result<Vec<Response>, ServerError> {
+        let idle = self.idle.lock().unwrap().get_mut(address).and_then(Vec::pop);
+        let (peer, responses) = match idle {
+            Some(mut peer) => match peer.send(commands).await {
+                Ok(responses) => (peer, responses),
+                Err(_) => send_fresh(address, credentials, commands).await?,
+            },
+            None => send_fresh(address, credentials, commands).await?,
+        };
+        let mut idle = self.idle.lock().unwrap();
+        let connections = idle.entry(address.to_string()).or_default();
+        if connections.len() < MAX_IDLE_PER_PEER {
+            connections.push(peer);
+        }
+        Ok(responses)
+    }
+}
+
+async fn send_fresh(address: &str, credentials: &PeerCredentials, commands: &[Command]) -> Result<(PeerConnection, Vec<Response>), ServerError> {
+    let mut peer = PeerConnection::connect(address, credentials).await?;
+    let responses = peer.send(commands).await?;
+    Ok((peer, responses))
+}
+
+impl Default for PeerPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
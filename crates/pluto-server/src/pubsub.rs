@@ -0,0 +1,268 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use bytes::Bytes;
+use tokio::sync::{mpsc, Notify};
+use log::debug;
+use pluto_core::cache::glob_match;
+use pluto_core::protocol::{KeyEvent, KeyEventReason, Response};
+use crate::clients::OutputBuffer;
+
+// Messages a subscriber can have queued before new ones are dropped
+const SUBSCRIBER_QUEUE: usize = 1024;
+
+// What is pushed to a subscriber: a message published on one of its
+// channels, or a key matching one of its patterns leaving the keyspace
+#[derive(Debug, Clone)]
+pub enum PushMessage {
+    Message { channel: String, message: Bytes },
+    KeyEvent(KeyEvent),
+}
+
+impl PushMessage {
+    // What the message adds to its subscriber's output buffer
+    fn size(&self) -> usize {
+        match self {
+            PushMessage::Message { channel, message } => channel.len() + message.len(),
+            PushMessage::KeyEvent(event) => event.key.len() + 16,
+        }
+    }
+
+    pub fn into_response(self) -> Response {
+        match self {
+            PushMessage::Message { channel, message } => Response::Message { channel, message },
+            PushMessage::KeyEvent(event) => Response::KeyEvent(event),
+        }
+    }
+}
+
+// Where a subscriber's messages go
+struct Mailbox {
+    tx: mpsc::Sender<PushMessage>,
+    output: Arc<OutputBuffer>,
+}
+
+impl Mailbox {
+    // Queue a message unless the subscriber is too far behind, returning
+    // whether it was
+    fn deliver(&self, id: u64, push: PushMessage) -> bool {
+        let size = push.size();
+        if !self.output.add(size) {
+            debug!("Dropping a message for subscriber {}: output buffer limit reached", id);
+            return false;
+        }
+        match self.tx.try_send(push) {
+            Ok(()) => true,
+            Err(e) => {
+                self.output.remove(size);
+                debug!("Dropping a message for subscriber {}: {}", id, e);
+                false
+            }
+        }
+    }
+}
+
+// A connection watching keys, with the patterns they must match
+struct Watcher {
+    patterns: Vec<String>,
+    mailbox: Mailbox,
+}
+
+// Channel registry shared by every connection
+pub struct PubSub {
+    next_id: AtomicU64,
+    channels: Mutex<HashMap<String, HashMap<u64, Mailbox>>>,
+    changed: Notify, // a channel gained its first subscriber or lost its last
+    watchers: Mutex<HashMap<u64, Watcher>>,
+    watching: AtomicUsize, // watchers; key removals skip the table without any
+}
+
+impl PubSub {
+    pub fn new() -> Self {
+        PubSub {
+            next_id: AtomicU64::new(1),
+            channels: Mutex::new(HashMap::new()),
+            changed: Notify::new(),
+            watchers: Mutex::new(HashMap::new()),
+            watching: AtomicUsize::new(0),
+        }
+    }
+
+    // Channels with subscribers on this node
+    pub fn channels(&self) -> Vec<String> {
+        self.channels.lock().unwrap().keys().cloned().collect()
+    }
+
+    // Wait for the set of channels with subscribers to change
+    pub async fn changed(&self) {
+        self.changed.notified().await
+    }
+
+    // Deliver a message to every subscriber of the channel, returning how many received it
+    pub fn publish(&self, channel: &str, message: Bytes) -> usize {
+        let channels = self.channels.lock().unwrap();
+        let Some(subscribers) = channels.get(channel) else {
+            return 0;
+        };
+        subscribers.iter()
+            .filter(|(id, mailbox)| {
+                mailbox.deliver(**id, PushMessage::Message { channel: channel.to_string(), message: message.clone() })
+            })
+            .count()
+    }
+
+    pub fn is_watched(&self) -> bool {
+        self.watching.load(Ordering::Relaxed) > 0
+    }
+
+    // Tell the connections watching a pattern `key` matches that it left the
+    // keyspace, returning how many were told
+    pub fn key_event(&self, key: &str, reason: KeyEventReason, size: usize) -> usize {
+        let watchers = self.watchers.lock().unwrap();
+        watchers.iter()
+            .filter(|(_, watcher)| watcher.patterns.iter().any(|pattern| glob_match(pattern.as_bytes(), key.as_bytes())))
+            .filter(|(id, watcher)| {
+                watcher.mailbox.deliver(**id, PushMessage::KeyEvent(KeyEvent { key: key.to_string(), reason, size }))
+            })
+            .count()
+    }
+
+    // Replace the patterns a connection watches, none leaving the table
+    fn watch(&self, id: u64, patterns: Vec<String>, mailbox: Mailbox) {
+        let mut watchers = self.watchers.lock().unwrap();
+        if patterns.is_empty() {
+            watchers.remove(&id);
+        } else {
+            watchers.insert(id, Watcher { patterns, mailbox });
+        }
+        self.watching.store(watchers.len(), Ordering::Relaxed);
+    }
+
+    fn add(&self, channel: &str, id: u64, mailbox: Mailbox) {
+        let mut channels = self.channels.lock().unwrap();
+        if !channels.contains_key(channel) {
+            self.changed.notify_one();
+        }
+        channels.entry(channel.to_string()).or_default().insert(id, mailbox);
+    }
+
+    fn remove(&self, channel: &str, id: u64) {
+        let mut channels = self.channels.lock().unwrap();
+        if let Some(subscribers) = channels.get_mut(channel) {
+            subscribers.remove(&id);
+            if subscribers.is_empty() {
+                channels.remove(channel);
+                self.changed.notify_one();
+            }
+        }
+    }
+}
+
+impl Default for PubSub {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// The subscriptions of a single connection
+pub struct Subscriber {
+    id: u64,
+    pubsub: Arc<PubSub>,
+    tx: mpsc::Sender<PushMessage>,
+    rx: mpsc::Receiver<PushMessage>,
+    channels: HashSet<String>,
+    patterns: Vec<String>, // of the keys watched
+    output: Arc<OutputBuffer>, // of the connection, which queued messages count against
+}
+
+impl Subscriber {
+    pub fn new(pubsub: Arc<PubSub>, output: Arc<OutputBuffer>) -> Self {
+        let (tx, rx) = mpsc::channel(SUBSCRIBER_QUEUE);
+        Subscriber {
+            id: pubsub.next_id.fetch_add(1, Ordering::Relaxed),
+            pubsub,
+            tx,
+            rx,
+            channels: HashSet::new(),
+            patterns: Vec::new(),
+            output,
+        }
+    }
+
+    // Subscribe to channels, returning the number of channels now subscribed
+    pub fn subscribe(&mut self, channels: Vec<String>) -> usize {
+        for channel in channels {
+            if self.channels.insert(channel.clone()) {
+                self.pubsub.add(&channel, self.id, Mailbox { tx: self.tx.clone(), output: self.output.clone() });
+            }
+        }
+        self.output.set_subscribed(self.is_subscribed());
+        self.channels.len()
+    }
+
+    // Unsubscribe from channels (all of them when empty), returning the number left
+    pub fn unsubscribe(&mut self, channels: Vec<String>) -> usize {
+        let channels = if channels.is_empty() {
+            self.channels.iter().cloned().collect()
+        } else {
+            channels
+        };
+        for channel in channels {
+            if self.channels.remove(&channel) {
+                self.pubsub.remove(&channel, self.id);
+            }
+        }
+        self.output.set_subscribed(self.is_subscribed());
+        self.channels.len()
+    }
+
+    // Watch keys matching `patterns`, returning the number of patterns now watched
+    pub fn watch(&mut self, patterns: Vec<String>) -> usize {
+        for pattern in patterns {
+            if !self.patterns.contains(&pattern) {
+                self.patterns.push(pattern);
+            }
+        }
+        self.rewatch()
+    }
+
+    // Stop watching `patterns` (all of them when empty), returning the number left
+    pub fn unwatch(&mut self, patterns: Vec<String>) -> usize {
+        if patterns.is_empty() {
+            self.patterns.clear();
+        } else {
+            self.patterns.retain(|pattern| !patterns.contains(pattern));
+        }
+        self.rewatch()
+    }
+
+    fn rewatch(&mut self) -> usize {
+        let mailbox = Mailbox { tx: self.tx.clone(), output: self.output.clone() };
+        self.pubsub.watch(self.id, self.patterns.clone(), mailbox);
+        self.output.set_subscribed(self.is_subscribed());
+        self.patterns.len()
+    }
+
+    pub fn is_subscribed(&self) -> bool {
+        !self.channels.is_empty() || !self.patterns.is_empty()
+    }
+
+    // Wait for the next message on any subscribed channel
+    pub async fn recv(&mut self) -> Option<PushMessage> {
+        let push = self.rx.recv().await?;
+        self.output.remove(push.size());
+        Some(push)
+    }
+}
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        for channel in self.channels.drain() {
+            self.pubsub.remove(&channel, self.id);
+        }
+        if !self.patterns.is_empty() {
+            let mailbox = Mailbox { tx: self.tx.clone(), output: self.output.clone() };
+            self.pubsub.watch(self.id, Vec::new(), mailbox);
+        }
+    }
+}
@@ -0,0 +1,1076 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+use log::{debug, info, warn};
+use pluto_core::cache::ServerError;
+use pluto_core::cluster::{ClusterState, MetaCommand};
+use crate::heartbeat::Heartbeats;
+use crate::peer::PeerCredentials;
+use crate::state::ServerState;
+use crate::whisper::{WhisperMessage, WhisperServer};
+
+// Cluster membership is kept in a Raft log replicated between the members
+// over the whisper port. Joins and removals are appended by the leader and
+// applied to the slot map once a majority stored them, so two nodes can
+// never hand out different slot maps for the same epoch.
+
+// Term, vote and commit index, rewritten whenever they change
+const RAFT_STATE_FILE: &str = "raft-state.json";
+
+// The log itself, one JSON entry per line, only ever appended to
+const RAFT_LOG_FILE: &str = "raft-log.jsonl";
+
+// Where older versions kept state and log together; migrated on startup
+const LEGACY_RAFT_FILE: &str = "raft.json";
+
+// How often the election and heartbeat timers are checked
+const TICK_INTERVAL: Duration = Duration::from_millis(100);
+
+// How often the leader sends AppendEntries, with or without new entries
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(300);
+
+// A follower that hears nothing from a leader for this long (randomized per
+// node) starts an election
+const ELECTION_TIMEOUT_MS: std::ops::Range<u64> = 1000..2000;
+
+// Entries sent in a single AppendEntries
+const MAX_ENTRIES_PER_APPEND: usize = 64;
+
+// How long a membership change waits to be committed
+const PROPOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogEntry {
+    pub term: u64,
+    pub command: MetaCommand,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+// What survives a restart besides the log
+#[derive(Clone, Serialize, Deserialize)]
+struct HardState {
+    cluster_id: String,
+    term: u64,
+    voted_for: Option<String>,
+    commit_index: u64,
+}
+
+// A line of the log file. An entry at an index the file already holds
+// replaces it and everything after it, so dropping a conflicting suffix is
+// an append like any other.
+#[derive(Serialize, Deserialize)]
+struct LogLine {
+    index: u64,
+    #[serde(flatten)]
+    entry: LogEntry,
+}
+
+#[derive(Deserialize)]
+struct LegacyRaftFile {
+    #[serde(flatten)]
+    state: HardState,
+    log: Vec<LogEntry>,
+}
+
+struct LogFile {
+    file: fs::File,
+    dirty: bool, // appended to since the last sync
+}
+
+// Raft's files. Changes are written under the state lock but only synced by
+// `sync`, which runs outside it, before anything that depends on them
+// leaves the node.
+#[derive(Default)]
+pub struct RaftStore {
+    log: Mutex<Option<LogFile>>,
+    pending: Mutex<Option<HardState>>, // hard state not yet written
+    syncing: Mutex<()>,
+}
+
+impl RaftStore {
+    // Read the hard state and log, migrating the file of older versions
+    fn load() -> std::io::Result<(Self, HardState, Vec<LogEntry>)> {
+        if fs::metadata(RAFT_STATE_FILE).is_err() && fs::metadata(LEGACY_RAFT_FILE).is_ok() {
+            let content = fs::read_to_string(LEGACY_RAFT_FILE)?;
+            let legacy: LegacyRaftFile = serde_json::from_str(&content)?;
+            let store = RaftStore::default();
+            store.rewrite(&legacy.state, &legacy.log)?;
+            fs::remove_file(LEGACY_RAFT_FILE)?;
+            info!("Migrated {} to {} and {}", LEGACY_RAFT_FILE, RAFT_STATE_FILE, RAFT_LOG_FILE);
+            return Ok((store, legacy.state, legacy.log));
+        }
+        let content = fs::read_to_string(RAFT_STATE_FILE)?;
+        let state: HardState = serde_json::from_str(&content)?;
+        let (file, log) = read_log(Path::new(RAFT_LOG_FILE))?;
+        let store = RaftStore { log: Mutex::new(Some(LogFile { file, dirty: false })), ..Default::default() };
+        Ok((store, state, log))
+    }
+
+    // Append entry `index`, replacing whatever the file held from there on
+    fn append(&self, index: u64, entry: &LogEntry) {
+        let mut log = self.log.lock().unwrap();
+        let Some(log_file) = log.as_mut() else {
+            return;
+        };
+        let line = LogLine { index, entry: entry.clone() };
+        let result = serde_json::to_vec(&line)
+            .map_err(std::io::Error::from)
+            .and_then(|mut bytes| {
+                bytes.push(b'\n');
+                log_file.file.write_all(&bytes)
+            });
+        match result {
+            Ok(()) => log_file.dirty = true,
+            Err(e) => {
+                // A half-written line is dropped on load; stop appending after it
+                warn!("Failed to append to {}: {}", RAFT_LOG_FILE, e);
+                *log = None;
+            }
+        }
+    }
+
+    fn save_state(&self, state: HardState) {
+        *self.pending.lock().unwrap() = Some(state);
+    }
+
+    // Replace both files, syncing them before returning. Only used when the
+    // log starts over, which is rare enough to do under the state lock.
+    fn rewrite(&self, state: &HardState, entries: &[LogEntry]) -> std::io::Result<()> {
+        let _syncing = self.syncing.lock().unwrap();
+        let tmp_path = format!("{}.tmp", RAFT_LOG_FILE);
+        let mut tmp = fs::File::create(&tmp_path)?;
+        for (offset, entry) in entries.iter().enumerate() {
+            let line = LogLine { index: offset as u64 + 1, entry: entry.clone() };
+            let mut bytes = serde_json::to_vec(&line)?;
+            bytes.push(b'\n');
+            tmp.write_all(&bytes)?;
+        }
+        tmp.sync_all()?;
+        fs::rename(&tmp_path, RAFT_LOG_FILE)?;
+        let file = fs::OpenOptions::new().append(true).open(RAFT_LOG_FILE)?;
+        *self.log.lock().unwrap() = Some(LogFile { file, dirty: false });
+        *self.pending.lock().unwrap() = None;
+        write_state(state)
+    }
+
+    // Make everything written so far durable: the log first, so the commit
+    // index on disk never runs ahead of the entries it covers
+    pub fn sync(&self) -> std::io::Result<()> {
+        let _syncing = self.syncing.lock().unwrap();
+        let file = {
+            let mut log = self.log.lock().unwrap();
+            match log.as_mut() {
+                Some(log_file) if log_file.dirty => {
+                    log_file.dirty = false;
+                    Some(log_file.file.try_clone()?)
+                }
+                _ => None,
+            }
+        };
+        if let Some(file) = file {
+            file.sync_data()?;
+        }
+        let pending = self.pending.lock().unwrap().take();
+        match pending {
+            Some(state) => write_state(&state),
+            None => Ok(()),
+        }
+    }
+}
+
+// Written like the cluster file: to a temporary name, synced, then renamed
+fn write_state(state: &HardState) -> std::io::Result<()> {
+    let json = serde_json::to_string_pretty(state)?;
+    let tmp_path = format!("{}.tmp", RAFT_STATE_FILE);
+    let mut tmp = fs::File::create(&tmp_path)?;
+    tmp.write_all(json.as_bytes())?;
+    tmp.sync_all()?;
+    fs::rename(&tmp_path, RAFT_STATE_FILE)
+}
+
+// Replay the log file, cutting off a torn last line, and open it for appending
+fn read_log(path: &Path) -> std::io::Result<(fs::File, Vec<LogEntry>)> {
+    let file = fs::OpenOptions::new().read(true).append(true).create(true).open(path)?;
+    let mut reader = BufReader::new(&file);
+    let mut log = Vec::new();
+    let mut valid = 0;
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line)? == 0 || line.last() != Some(&b'\n') {
+            break;
+        }
+        let Ok(LogLine { index, entry }) = serde_json::from_slice::<LogLine>(&line) else {
+            break;
+        };
+        if index == 0 || index > log.len() as u64 + 1 {
+            break;
+        }
+        log.truncate(index as usize - 1);
+        log.push(entry);
+        valid += line.len() as u64;
+    }
+    drop(reader);
+    if file.metadata()?.len() > valid {
+        warn!("Dropping a torn tail of {} after {} entries", path.display(), log.len());
+        file.set_len(valid)?;
+        file.sync_all()?;
+    }
+    Ok((file, log))
+}
+
+// Make the Raft files durable off the runtime, before replying or sending
+pub async fn sync(store: Arc<RaftStore>) {
+    match tokio::task::spawn_blocking(move || store.sync()).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => warn!("Failed to sync the metadata log: {}", e),
+        Err(e) => warn!("Failed to sync the metadata log: {}", e),
+    }
+}
+
+pub struct Raft {
+    pub cluster_id: String, // the group this node belongs to; messages from other groups are refused
+    pub term: u64,
+    voted_for: Option<String>,
+    log: Vec<LogEntry>, // entry `i` of the log is `log[i - 1]`
+    pub commit_index: u64,
+    last_applied: u64,
+    pub role: Role,
+    pub leader: Option<String>,
+    invited: Option<String>, // cluster this node agreed to join with CLUSTER_JOIN
+    self_addr: String,
+    last_heard: Instant, // last AppendEntries from the current leader
+    election_deadline: Instant,
+    next_heartbeat: Instant,
+    votes: HashSet<String>,
+    next_index: HashMap<String, u64>,
+    match_index: HashMap<String, u64>,
+    applied: Arc<Notify>, // woken whenever committed entries are applied
+    store: Arc<RaftStore>,
+}
+
+impl Raft {
+    // Load the metadata log, or start one from the members in the cluster
+    // file. Nodes upgraded from the same cluster file start from identical
+    // logs, so they form one group without a join.
+    pub fn load_or_bootstrap(cluster: &mut ClusterState) -> Self {
+        let mut raft = Raft {
+            cluster_id: String::new(),
+            term: 0,
+            voted_for: None,
+            log: Vec::new(),
+            commit_index: 0,
+            last_applied: 0,
+            role: Role::Follower,
+            leader: None,
+            invited: None,
+            self_addr: cluster.self_addr.clone(),
+            last_heard: Instant::now(),
+            election_deadline: Instant::now(),
+            next_heartbeat: Instant::now(),
+            votes: HashSet::new(),
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            applied: Arc::new(Notify::new()),
+            store: Arc::new(RaftStore::default()),
+        };
+        raft.reset_election_deadline();
+        if !cluster.cluster_enabled {
+            return raft;
+        }
+        match RaftStore::load() {
+            Ok((store, state, log)) => {
+                raft.cluster_id = state.cluster_id;
+                raft.term = state.term;
+                raft.voted_for = state.voted_for;
+                raft.log = log;
+                raft.commit_index = state.commit_index.min(raft.last_index());
+                raft.store = Arc::new(store);
+            }
+            Err(e) => {
+                if fs::metadata(RAFT_STATE_FILE).is_ok() || fs::metadata(LEGACY_RAFT_FILE).is_ok() {
+                    warn!("Failed to load the metadata log: {}; starting a new one", e);
+                }
+                let mut members = cluster.nodes.clone();
+                if !members.contains(&raft.self_addr) {
+                    members = vec![raft.self_addr.clone()];
+                }
+                members.sort();
+                raft.log = members.iter()
+                    .map(|address| LogEntry {
+                        term: 0,
+                        command: MetaCommand::AddNode {
+                            address: address.clone(),
+                            node_id: cluster.node_ids.get(address).cloned().unwrap_or_else(ClusterState::generate_node_id),
+                            weight: match cluster.weights.get(address) {
+                                Some(weight) => *weight,
+                                None if *address == raft.self_addr => cluster.self_weight,
+                                None => 1,
+                            },
+                            rebalance: true,
+                        },
+                    })
+                    .collect();
+                raft.cluster_id = bootstrap_id(&raft.log);
+                raft.commit_index = raft.last_index();
+                raft.rewrite();
+            }
+        }
+        // Rebuild the slot map from the committed entries
+        cluster.reset();
+        raft.apply_to(cluster);
+        raft
+    }
+
+    pub fn last_index(&self) -> u64 {
+        self.log.len() as u64
+    }
+
+    fn term_at(&self, index: u64) -> u64 {
+        match index {
+            0 => 0,
+            i => self.log.get(i as usize - 1).map(|entry| entry.term).unwrap_or(0),
+        }
+    }
+
+    // Members as of the latest entry, committed or not; a change takes
+    // effect as soon as it is in the log
+    pub fn members(&self) -> Vec<String> {
+        let mut members: Vec<String> = Vec::new();
+        for entry in &self.log {
+            match &entry.command {
+                MetaCommand::AddNode { address, .. } => {
+                    if !members.contains(address) {
+                        members.push(address.clone());
+                    }
+                }
+                MetaCommand::RemoveNode { address } => members.retain(|member| member != address),
+                MetaCommand::AssignSlots { .. } | MetaCommand::Noop => {}
+            }
+        }
+        members.sort();
+        members
+    }
+
+    fn peers(&self) -> Vec<String> {
+        self.members().into_iter().filter(|member| *member != self.self_addr).collect()
+    }
+
+    fn reset_election_deadline(&mut self) {
+        let timeout = rand::thread_rng().gen_range(ELECTION_TIMEOUT_MS);
+        self.election_deadline = Instant::now() + Duration::from_millis(timeout);
+    }
+
+    pub fn store(&self) -> Arc<RaftStore> {
+        self.store.clone()
+    }
+
+    fn hard_state(&self) -> HardState {
+        HardState {
+            cluster_id: self.cluster_id.clone(),
+            term: self.term,
+            voted_for: self.voted_for.clone(),
+            commit_index: self.commit_index,
+        }
+    }
+
+    // Queue the term, vote and commit index for the next sync
+    fn save_state(&self) {
+        self.store.save_state(self.hard_state());
+    }
+
+    fn push(&mut self, entry: LogEntry) {
+        self.log.push(entry);
+        self.store.append(self.last_index(), &self.log[self.log.len() - 1]);
+    }
+
+    // Write out the whole log when it starts over
+    fn rewrite(&self) {
+        if let Err(e) = self.store.rewrite(&self.hard_state(), &self.log) {
+            warn!("Failed to write the metadata log: {}", e);
+        }
+    }
+
+    fn step_down(&mut self, term: u64) {
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+            self.save_state();
+        }
+        if self.role != Role::Follower {
+            debug!("Stepping down to follower in term {}", self.term);
+        }
+        self.role = Role::Follower;
+    }
+
+    fn become_leader(&mut self) {
+        info!("Elected metadata leader for term {}", self.term);
+        self.role = Role::Leader;
+        self.leader = Some(self.self_addr.clone());
+        let next = self.last_index() + 1;
+        self.next_index = self.peers().into_iter().map(|peer| (peer, next)).collect();
+        self.match_index.clear();
+        self.next_heartbeat = Instant::now();
+        // Entries of earlier terms only count as committed once one of ours is
+        self.append(MetaCommand::Noop);
+    }
+
+    // Append a command to the leader's log, returning its index
+    fn append(&mut self, command: MetaCommand) -> u64 {
+        self.push(LogEntry { term: self.term, command });
+        self.advance_commit();
+        self.last_index()
+    }
+
+    // Commit the newest entry of the current term that a majority stored
+    fn advance_commit(&mut self) {
+        let members = self.members();
+        for index in (self.commit_index + 1..=self.last_index()).rev() {
+            if self.term_at(index) != self.term {
+                break;
+            }
+            let stored = members.iter()
+                .filter(|member| **member == self.self_addr || self.match_index.get(*member).copied().unwrap_or(0) >= index)
+                .count();
+            if stored * 2 > members.len() {
+                self.commit_index = index;
+                self.save_state();
+                break;
+            }
+        }
+    }
+
+    // Apply committed entries to the slot map, returning true when this
+    // node was removed from the cluster by one of them
+    fn apply_to(&mut self, cluster: &mut ClusterState) -> bool {
+        let mut removed = false;
+        while self.last_applied < self.commit_index {
+            self.last_applied += 1;
+            let entry = &self.log[self.last_applied as usize - 1];
+            if matches!(&entry.command, MetaCommand::RemoveNode { address } if *address == self.self_addr) {
+                removed = true;
+            }
+            cluster.apply(&entry.command, self.last_applied);
+        }
+        self.applied.notify_waiters();
+        removed
+    }
+
+    // Leave the group and start over as the only member of a new one
+    fn isolate(&mut self, cluster: &mut ClusterState) {
+        let node_id = cluster.node_ids.get(&self.self_addr).cloned().unwrap_or_else(ClusterState::generate_node_id);
+        self.log = vec![LogEntry {
+            term: 0,
+            command: MetaCommand::AddNode { address: self.self_addr.clone(), node_id, weight: cluster.self_weight, rebalance: true },
+        }];
+        self.cluster_id = ClusterState::generate_node_id();
+        self.term = 0;
+        self.voted_for = None;
+        self.commit_index = 1;
+        self.last_applied = 0;
+        self.role = Role::Follower;
+        self.leader = None;
+        self.invited = None;
+        self.rewrite();
+        cluster.reset();
+        self.apply_to(cluster);
+    }
+
+    // Join the group `cluster_id` as a fresh member: the leader resends its whole log
+    fn adopt(&mut self, cluster_id: String, cluster: &mut ClusterState) {
+        info!("Joining cluster {}", cluster_id);
+        self.cluster_id = cluster_id;
+        self.log.clear();
+        self.term = 0;
+        self.voted_for = None;
+        self.commit_index = 0;
+        self.last_applied = 0;
+        self.invited = None;
+        self.rewrite();
+        cluster.reset();
+    }
+
+    fn append_entries_for(&self, peer: &str) -> WhisperMessage {
+        let next = self.next_index.get(peer).copied().unwrap_or(self.last_index() + 1).max(1);
+        let prev_log_index = next - 1;
+        let end = (prev_log_index as usize + MAX_ENTRIES_PER_APPEND).min(self.log.len());
+        WhisperMessage::AppendEntries {
+            cluster_id: self.cluster_id.clone(),
+            term: self.term,
+            leader: self.self_addr.clone(),
+            prev_log_index,
+            prev_log_term: self.term_at(prev_log_index),
+            entries: self.log[prev_log_index as usize..end].to_vec(),
+            leader_commit: self.commit_index,
+        }
+    }
+}
+
+// Id of a group bootstrapped from a list of members: the node id of the
+// first one, the same on every node that starts from that list
+fn bootstrap_id(log: &[LogEntry]) -> String {
+    match log.first().map(|entry| &entry.command) {
+        Some(MetaCommand::AddNode { node_id, .. }) => node_id.clone(),
+        _ => ClusterState::generate_node_id(),
+    }
+}
+
+// Apply newly committed entries, isolating this node when it was removed
+fn apply_committed(state: &mut ServerState) {
+    let ServerState { raft, cluster, .. } = state;
+    if raft.apply_to(cluster) {
+        info!("Removed from the cluster, continuing on our own");
+        raft.isolate(cluster);
+    }
+}
+
+// Run the election and heartbeat timers until shutdown
+pub async fn run(state: Arc<RwLock<ServerState>>) {
+    let (enabled, shutdown) = {
+        let state = state.read().unwrap();
+        (state.cluster_enabled, state.shutdown.clone())
+    };
+    if !enabled {
+        return;
+    }
+    let mut interval = tokio::time::interval(TICK_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.wait() => break,
+        }
+        let (outgoing, store) = {
+            let mut state = state.write().unwrap();
+            (tick(&mut state), state.raft.store())
+        };
+        // A candidate's own vote is durable before it asks for others
+        sync(store).await;
+        send_all(&state, outgoing);
+    }
+}
+
+// Advance the timers, returning the messages to send
+fn tick(state: &mut ServerState) -> Vec<(String, WhisperMessage)> {
+    let now = Instant::now();
+    let raft = &mut state.raft;
+    match raft.role {
+        Role::Leader if now >= raft.next_heartbeat => {
+            raft.next_heartbeat = now + HEARTBEAT_INTERVAL;
+            raft.peers().into_iter().map(|peer| {
+                let message = raft.append_entries_for(&peer);
+                (peer, message)
+            }).collect()
+        }
+        Role::Leader => Vec::new(),
+        Role::Follower | Role::Candidate if now >= raft.election_deadline => {
+            raft.reset_election_deadline();
+            // A node waiting to be sent the log of the group it joins doesn't campaign
+            if !raft.members().contains(&raft.self_addr) {
+                return Vec::new();
+            }
+            raft.term += 1;
+            raft.role = Role::Candidate;
+            raft.leader = None;
+            raft.voted_for = Some(raft.self_addr.clone());
+            raft.votes = HashSet::from([raft.self_addr.clone()]);
+            raft.save_state();
+            debug!("Starting election for term {}", raft.term);
+            let peers = raft.peers();
+            if peers.is_empty() {
+                raft.become_leader();
+                apply_committed(state);
+                return Vec::new();
+            }
+            let request = WhisperMessage::RequestVote {
+                cluster_id: raft.cluster_id.clone(),
+                term: raft.term,
+                candidate: raft.self_addr.clone(),
+                last_log_index: raft.last_index(),
+                last_log_term: raft.term_at(raft.last_index()),
+            };
+            peers.into_iter().map(|peer| (peer, request.clone())).collect()
+        }
+        Role::Follower | Role::Candidate => Vec::new(),
+    }
+}
+
+// Send Raft messages to their peers in the background and handle the replies
+fn send_all(state: &Arc<RwLock<ServerState>>, outgoing: Vec<(String, WhisperMessage)>) {
+    if outgoing.is_empty() {
+        return;
+    }
+    let credentials = state.read().unwrap().config.peer_credentials();
+    for (peer, message) in outgoing {
+        let state = state.clone();
+        let credentials = credentials.clone();
+        tokio::spawn(async move {
+            match WhisperServer::send_whisper_message(&peer, &credentials, message).await {
+                Ok(response) => {
+                    if let Some(reply) = response.data {
+                        let (outgoing, store) = {
+                            let mut state = state.write().unwrap();
+                            (handle_reply(&mut state, &peer, reply), state.raft.store())
+                        };
+                        sync(store).await;
+                        send_all(&state, outgoing);
+                    }
+                }
+                Err(e) => debug!("Raft message to {} failed: {}", peer, e),
+            }
+        });
+    }
+}
+
+// Handle a peer's answer to RequestVote or AppendEntries, returning follow-up messages
+fn handle_reply(state: &mut ServerState, peer: &str, reply: WhisperMessage) -> Vec<(String, WhisperMessage)> {
+    let raft = &mut state.raft;
+    match reply {
+        WhisperMessage::Vote { term, granted } => {
+            if term > raft.term {
+                raft.step_down(term);
+                return Vec::new();
+            }
+            if raft.role == Role::Candidate && term == raft.term && granted {
+                raft.votes.insert(peer.to_string());
+                let members = raft.members();
+                let votes = members.iter().filter(|member| raft.votes.contains(*member)).count();
+                if votes * 2 > members.len() {
+                    raft.become_leader();
+                    apply_committed(state);
+                }
+            }
+            Vec::new()
+        }
+        WhisperMessage::AppendResult { term, success, match_index } => {
+            if term > raft.term {
+                raft.step_down(term);
+                return Vec::new();
+            }
+            if raft.role != Role::Leader || term != raft.term {
+                return Vec::new();
+            }
+            if success {
+                let matched = raft.match_index.entry(peer.to_string()).or_insert(0);
+                *matched = (*matched).max(match_index);
+                raft.next_index.insert(peer.to_string(), match_index + 1);
+                let before = raft.commit_index;
+                raft.advance_commit();
+                if raft.commit_index > before {
+                    apply_committed(state);
+                }
+                // Keep going while the peer is behind
+                let raft = &state.raft;
+                if raft.role == Role::Leader && match_index < raft.last_index() && raft.members().iter().any(|member| member == peer) {
+                    return vec![(peer.to_string(), raft.append_entries_for(peer))];
+                }
+                Vec::new()
+            } else {
+                // Back up to just after the last entry the peer may share with us
+                let next = raft.next_index.get(peer).copied().unwrap_or(raft.last_index() + 1);
+                raft.next_index.insert(peer.to_string(), (match_index + 1).min(next.saturating_sub(1)).max(1));
+                vec![(peer.to_string(), raft.append_entries_for(peer))]
+            }
+        }
+        _ => Vec::new(),
+    }
+}
+
+// Answer a candidate asking for our vote
+pub fn handle_request_vote(
+    state: &mut ServerState,
+    cluster_id: String,
+    term: u64,
+    candidate: String,
+    last_log_index: u64,
+    last_log_term: u64,
+) -> WhisperMessage {
+    let raft = &mut state.raft;
+    let refuse = |raft: &Raft| WhisperMessage::Vote { term: raft.term, granted: false };
+    // Removed nodes and nodes of other groups can't disrupt the cluster, and
+    // neither can a candidate while our leader is alive
+    if cluster_id != raft.cluster_id || !raft.members().contains(&candidate) {
+        return refuse(raft);
+    }
+    if raft.role == Role::Follower && raft.leader.is_some()
+        && raft.last_heard.elapsed() < Duration::from_millis(ELECTION_TIMEOUT_MS.start) {
+        return refuse(raft);
+    }
+    if term < raft.term {
+        return refuse(raft);
+    }
+    if term > raft.term {
+        raft.step_down(term);
+        raft.leader = None;
+    }
+    let our_last_term = raft.term_at(raft.last_index());
+    let up_to_date = last_log_term > our_last_term
+        || (last_log_term == our_last_term && last_log_index >= raft.last_index());
+    let granted = up_to_date && raft.voted_for.as_ref().is_none_or(|voted| *voted == candidate);
+    if granted {
+        raft.voted_for = Some(candidate);
+        raft.save_state();
+        raft.reset_election_deadline();
+    }
+    WhisperMessage::Vote { term: raft.term, granted }
+}
+
+// Store the leader's entries when they extend our log
+#[allow(clippy::too_many_arguments)]
+pub fn handle_append_entries(
+    state: &mut ServerState,
+    cluster_id: String,
+    term: u64,
+    leader: String,
+    prev_log_index: u64,
+    prev_log_term: u64,
+    entries: Vec<LogEntry>,
+    leader_commit: u64,
+) -> WhisperMessage {
+    if cluster_id != state.raft.cluster_id {
+        if state.raft.invited.as_ref() != Some(&cluster_id) {
+            // Answer in the sender's term so it doesn't step down for a stranger
+            return WhisperMessage::AppendResult { term, success: false, match_index: 0 };
+        }
+        let ServerState { raft, cluster, .. } = state;
+        raft.adopt(cluster_id, cluster);
+    }
+    let raft = &mut state.raft;
+    if term < raft.term {
+        return WhisperMessage::AppendResult { term: raft.term, success: false, match_index: 0 };
+    }
+    raft.step_down(term);
+    raft.leader = Some(leader);
+    raft.last_heard = Instant::now();
+    raft.reset_election_deadline();
+
+    if prev_log_index > raft.last_index() || raft.term_at(prev_log_index) != prev_log_term {
+        let hint = raft.last_index().min(prev_log_index.saturating_sub(1));
+        return WhisperMessage::AppendResult { term: raft.term, success: false, match_index: hint };
+    }
+    for (offset, entry) in entries.iter().enumerate() {
+        let index = prev_log_index + 1 + offset as u64;
+        if index <= raft.last_index() {
+            if raft.term_at(index) == entry.term {
+                continue;
+            }
+            // A conflicting suffix was never committed; drop it
+            raft.log.truncate(index as usize - 1);
+        }
+        raft.push(entry.clone());
+    }
+    let last_new = prev_log_index + entries.len() as u64;
+    if leader_commit > raft.commit_index {
+        raft.commit_index = leader_commit.min(last_new);
+        raft.save_state();
+    }
+    let term = raft.term;
+    apply_committed(state);
+    WhisperMessage::AppendResult { term, success: true, match_index: last_new }
+}
+
+// Agree to join the group `cluster_id`; only a node that is on its own can
+pub fn handle_invite(state: &mut ServerState, cluster_id: String) -> Result<(), String> {
+    let raft = &mut state.raft;
+    let members = raft.members();
+    if members.len() > 1 && cluster_id != raft.cluster_id {
+        return Err(format!("already a member of a cluster of {} nodes", members.len()));
+    }
+    if cluster_id != raft.cluster_id {
+        raft.invited = Some(cluster_id);
+    }
+    Ok(())
+}
+
+// Leave the cluster, as CLUSTER_ISOLATE does
+pub fn isolate(state: &mut ServerState) {
+    let ServerState { raft, cluster, .. } = state;
+    raft.isolate(cluster);
+}
+
+// Forget every peer and what was known of them, as CLUSTER_RESET does, and
+// start over as the only member of a new cluster. A hard reset also takes a
+// new node id, so the node joins its next cluster as a stranger.
+pub fn reset(state: &mut ServerState, hard: bool) {
+    let ServerState { raft, cluster, heartbeats, .. } = state;
+    if hard {
+        cluster.node_ids.insert(cluster.self_addr.clone(), ClusterState::generate_node_id());
+    }
+    cluster.health.clear();
+    cluster.migrating.clear();
+    cluster.importing.clear();
+    *heartbeats = Heartbeats::new();
+    raft.isolate(cluster);
+}
+
+// Append a membership change and wait until it is applied. Followers hand
+// the change to the leader when `forward` is set.
+pub async fn propose(state: &Arc<RwLock<ServerState>>, command: MetaCommand, forward: bool) -> Result<(), ServerError> {
+    let leader = {
+        let state = state.read().unwrap();
+        (state.raft.role != Role::Leader).then(|| state.raft.leader.clone())
+    };
+    if let Some(leader) = leader {
+        return match leader {
+            Some(leader) if forward => {
+                let credentials = state.read().unwrap().config.peer_credentials();
+                forward_to_leader(&leader, &credentials, command).await
+            }
+            Some(leader) => Err(ServerError::InvalidArgument(format!("Not the cluster leader, {} is", leader))),
+            None => Err(ServerError::InvalidArgument("No cluster leader elected yet, try again".to_string())),
+        };
+    }
+    let (index, term, applied, store) = {
+        let mut guard = state.write().unwrap();
+        let raft = &mut guard.raft;
+        if raft.role != Role::Leader {
+            return Err(ServerError::InvalidArgument("Lost cluster leadership, try again".to_string()));
+        }
+        let applied = raft.applied.clone();
+        let index = raft.append(command);
+        let term = raft.term;
+        raft.next_heartbeat = Instant::now(); // replicate on the next tick
+        apply_committed(&mut guard);
+        (index, term, applied, guard.raft.store())
+    };
+    sync(store).await;
+    let deadline = tokio::time::Instant::now() + PROPOSE_TIMEOUT;
+    loop {
+        let notified = applied.notified();
+        tokio::pin!(notified);
+        notified.as_mut().enable();
+        {
+            let state = state.read().unwrap();
+            let raft = &state.raft;
+            if raft.term_at(index) != term || raft.last_index() < index {
+                return Err(ServerError::InvalidArgument("Leadership changed before the change was committed".to_string()));
+            }
+            if raft.last_applied >= index {
+                return Ok(());
+            }
+        }
+        if tokio::time::timeout_at(deadline, notified).await.is_err() {
+            return Err(ServerError::InvalidArgument("Timed out waiting for a majority of the cluster".to_string()));
+        }
+    }
+}
+
+async fn forward_to_leader(leader: &str, credentials: &PeerCredentials, command: MetaCommand) -> Result<(), ServerError> {
+    match WhisperServer::send_whisper_message(leader, credentials, WhisperMessage::Propose { command }).await {
+        Ok(response) if response.success => Ok(()),
+        Ok(response) => Err(ServerError::InvalidArgument(response.message.unwrap_or_else(|| "Leader refused the change".to_string()))),
+        Err(e) => Err(ServerError::InvalidArgument(format!("Failed to reach cluster leader {}: {}", leader, e))),
+    }
+}
+
+// Ask a node to accept the log of our group
+pub async fn invite(address: &str, credentials: &PeerCredentials, cluster_id: String) -> Result<(), ServerError> {
+    match WhisperServer::send_whisper_message(address, credentials, WhisperMessage::Invite { cluster_id }).await {
+        Ok(response) if response.success => Ok(()),
+        Ok(response) => Err(ServerError::InvalidArgument(format!(
+            "{} refused to join: {}", address, response.message.unwrap_or_default()
+        ))),
+        Err(e) => Err(ServerError::InvalidArgument(format!("Failed to reach {}: {}", address, e))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::environment::FluxConfig;
+
+    const SELF: &str = "127.0.0.1:7000";
+    const PEER: &str = "127.0.0.1:7001";
+    const OTHER: &str = "127.0.0.1:7002";
+
+    fn add(address: &str, term: u64) -> LogEntry {
+        LogEntry {
+            term,
+            command: MetaCommand::AddNode { address: address.to_string(), node_id: address.to_string(), weight: 1, rebalance: false },
+        }
+    }
+
+    fn noop(term: u64) -> LogEntry {
+        LogEntry { term, command: MetaCommand::Noop }
+    }
+
+    // A follower of a three-member group in term 2, whose log holds the members
+    fn follower() -> ServerState {
+        let mut state = ServerState::new(SELF.to_string(), FluxConfig::default());
+        state.raft.cluster_id = "group".to_string();
+        state.raft.term = 2;
+        state.raft.log = vec![add(SELF, 0), add(PEER, 0), add(OTHER, 0), noop(1)];
+        state.raft.commit_index = 3;
+        state.raft.last_applied = 3;
+        state
+    }
+
+    fn vote(state: &mut ServerState, term: u64, candidate: &str, last_log_index: u64, last_log_term: u64) -> bool {
+        match handle_request_vote(state, "group".to_string(), term, candidate.to_string(), last_log_index, last_log_term) {
+            WhisperMessage::Vote { granted, .. } => granted,
+            other => panic!("expected a vote, got {:?}", other),
+        }
+    }
+
+    fn append(state: &mut ServerState, term: u64, prev: (u64, u64), entries: Vec<LogEntry>, commit: u64) -> (bool, u64) {
+        let (prev_log_index, prev_log_term) = prev;
+        match handle_append_entries(state, "group".to_string(), term, PEER.to_string(), prev_log_index, prev_log_term, entries, commit) {
+            WhisperMessage::AppendResult { success, match_index, .. } => (success, match_index),
+            other => panic!("expected an append result, got {:?}", other),
+        }
+    }
+
+    fn terms(raft: &Raft) -> Vec<u64> {
+        raft.log.iter().map(|entry| entry.term).collect()
+    }
+
+    #[test]
+    fn votes_once_per_term_for_an_up_to_date_candidate() {
+        let mut state = follower();
+        assert!(!vote(&mut state, 1, PEER, 4, 1), "stale term");
+        assert!(!vote(&mut state, 3, PEER, 3, 0), "candidate missing our last entry");
+        assert!(vote(&mut state, 3, PEER, 4, 1));
+        assert_eq!(state.raft.term, 3);
+        assert_eq!(state.raft.voted_for.as_deref(), Some(PEER));
+        assert!(!vote(&mut state, 3, OTHER, 4, 1), "already voted this term");
+        assert!(vote(&mut state, 3, PEER, 4, 1), "a repeated request gets the same answer");
+        // A new term frees the vote
+        assert!(vote(&mut state, 4, OTHER, 4, 1));
+    }
+
+    #[test]
+    fn refuses_votes_for_strangers_and_other_groups() {
+        let mut state = follower();
+        assert!(!vote(&mut state, 3, "127.0.0.1:7999", 4, 1));
+        let granted = handle_request_vote(&mut state, "elsewhere".to_string(), 3, PEER.to_string(), 4, 1);
+        assert!(matches!(granted, WhisperMessage::Vote { granted: false, .. }));
+        assert_eq!(state.raft.term, 2);
+    }
+
+    #[test]
+    fn refuses_entries_that_dont_follow_our_log() {
+        let mut state = follower();
+        // The leader's entry 4 is from term 2, ours from term 1
+        let (success, hint) = append(&mut state, 2, (4, 2), vec![noop(2)], 0);
+        assert!(!success);
+        assert_eq!(hint, 3);
+        // Past our end
+        let (success, hint) = append(&mut state, 2, (9, 2), vec![noop(2)], 0);
+        assert!(!success);
+        assert_eq!(hint, 4);
+        assert_eq!(terms(&state.raft), [0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn drops_a_conflicting_suffix_for_the_leaders_entries() {
+        let mut state = follower();
+        state.raft.log.extend([noop(1), noop(1)]);
+        let (success, match_index) = append(&mut state, 2, (4, 1), vec![noop(2)], 0);
+        assert!(success);
+        assert_eq!(match_index, 5);
+        assert_eq!(terms(&state.raft), [0, 0, 0, 1, 2]);
+        // Entries already held are kept as they are
+        let (success, _) = append(&mut state, 2, (3, 0), vec![noop(1)], 0);
+        assert!(success);
+        assert_eq!(terms(&state.raft), [0, 0, 0, 1, 2]);
+    }
+
+    #[test]
+    fn commits_no_further_than_the_entries_received() {
+        let mut state = follower();
+        let (success, _) = append(&mut state, 2, (4, 1), vec![noop(2)], 9);
+        assert!(success);
+        assert_eq!(state.raft.commit_index, 5);
+        assert_eq!(state.raft.last_applied, 5);
+    }
+
+    #[test]
+    fn a_newer_term_makes_a_leader_step_down() {
+        let mut state = follower();
+        state.raft.role = Role::Leader;
+        let (success, _) = append(&mut state, 3, (4, 1), Vec::new(), 0);
+        assert!(success);
+        assert_eq!(state.raft.role, Role::Follower);
+        assert_eq!(state.raft.term, 3);
+        assert_eq!(state.raft.voted_for, None);
+        assert_eq!(state.raft.leader.as_deref(), Some(PEER));
+        // An older leader is refused
+        let (success, _) = append(&mut state, 2, (4, 1), Vec::new(), 0);
+        assert!(!success);
+    }
+
+    // A log file under the temporary directory, removed when dropped
+    struct TempLog(std::path::PathBuf);
+
+    impl TempLog {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("pluto-raft-{}-{}.jsonl", name, std::process::id()));
+            let _ = fs::remove_file(&path);
+            TempLog(path)
+        }
+    }
+
+    impl Drop for TempLog {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn store_at(path: &Path) -> RaftStore {
+        let (file, _) = read_log(path).unwrap();
+        RaftStore { log: Mutex::new(Some(LogFile { file, dirty: false })), ..Default::default() }
+    }
+
+    #[test]
+    fn log_replays_appends_and_overwrites() {
+        let temp = TempLog::new("replay");
+        let store = store_at(&temp.0);
+        store.append(1, &add(SELF, 0));
+        store.append(2, &noop(1));
+        store.append(3, &noop(1));
+        // The leader of term 2 replaced entry 2 onwards
+        store.append(2, &noop(2));
+        store.sync().unwrap();
+        let (_, log) = read_log(&temp.0).unwrap();
+        assert_eq!(log.iter().map(|entry| entry.term).collect::<Vec<_>>(), [0, 2]);
+    }
+
+    #[test]
+    fn log_drops_a_torn_tail() {
+        let temp = TempLog::new("torn");
+        let store = store_at(&temp.0);
+        store.append(1, &add(SELF, 0));
+        store.append(2, &noop(1));
+        drop(store);
+        let intact = fs::metadata(&temp.0).unwrap().len();
+        let mut file = fs::OpenOptions::new().append(true).open(&temp.0).unwrap();
+        file.write_all(b"{\"index\":3,\"term\":1,\"comm").unwrap();
+        drop(file);
+
+        let (_, log) = read_log(&temp.0).unwrap();
+        assert_eq!(log.len(), 2);
+        assert_eq!(fs::metadata(&temp.0).unwrap().len(), intact);
+    }
+
+    #[test]
+    fn log_stops_at_a_gap() {
+        let temp = TempLog::new("gap");
+        let store = store_at(&temp.0);
+        store.append(1, &add(SELF, 0));
+        store.append(3, &noop(1));
+        drop(store);
+        let (_, log) = read_log(&temp.0).unwrap();
+        assert_eq!(log.len(), 1);
+    }
+}
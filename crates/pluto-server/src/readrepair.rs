@@ -0,0 +1,119 @@
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use log::{debug, warn};
+use tokio::task::{JoinError, JoinSet};
+use pluto_core::cache::{CacheEntry, ServerError, WriteStamp, dump_entry, entry_value, now_ms, restore_entry};
+use pluto_core::protocol::{Command, ErrorCode, Response};
+use crate::antientropy;
+use crate::api::{read_value, stamped_write};
+use crate::peer::{PeerCredentials, PeerPool};
+use crate::state::ServerState;
+
+// A GET run with QUORUM: the key is read here and on every connected
+// replica, and the reply goes out once `replicas` of them answered, with the
+// newest copy among those. The other replicas are waited for in the
+// background, and once all answered the stale copies are fixed, so hot keys
+// heal as they are read rather than at the next anti-entropy pass.
+//
+// Copies are compared by their write stamps. A key missing here counts as
+// deleted, since DEL leaves no stamp behind: a replica still holding it is
+// told to drop it rather than bringing it back.
+
+type Read = (String, Result<Option<CacheEntry>, ServerError>);
+
+pub async fn quorum_get(state: &Arc<RwLock<ServerState>>, key: String, replicas: usize) -> Result<Response, ServerError> {
+    let (local, stamp, addresses, credentials, timeout, peers) = {
+        let state = state.read().unwrap();
+        if state.replication.is_replica() {
+            return Err(ServerError::InvalidArgument("QUORUM reads are served by the primary".to_string()));
+        }
+        let local = read_value(&state, &key)?;
+        let stamp = local.as_ref().and(state.cache.get(&key)).map(|entry| entry.stamp);
+        let addresses: Vec<String> = state.replication.replicas().into_iter().map(|replica| replica.address).collect();
+        let timeout = Duration::from_millis(state.config.write_quorum_timeout_ms);
+        (local, stamp, addresses, state.config.peer_credentials(), timeout, state.peers.clone())
+    };
+
+    let mut reads = JoinSet::new();
+    for address in addresses {
+        let (key, credentials, peers) = (key.clone(), credentials.clone(), peers.clone());
+        reads.spawn(async move {
+            let read = tokio::time::timeout(timeout, read_replica(&peers, &address, &credentials, &key)).await
+                .unwrap_or_else(|_| Err(ServerError::Timeout(format!("{} didn't answer", address))));
+            (address, read)
+        });
+    }
+    let mut copies = Vec::new();
+    while copies.len() < replicas {
+        let Some(joined) = reads.join_next().await else {
+            return Err(ServerError::QuorumNotReached(format!("{} of {} replicas answered the read", copies.len(), replicas)));
+        };
+        collect(&key, joined, &mut copies);
+    }
+
+    let response = match newest(&copies, stamp) {
+        Some(entry) if entry.tombstone => Response::Missing,
+        Some(entry) => Response::Data(entry_value(entry)?),
+        None => local.unwrap_or(Response::Nil),
+    };
+    let state = state.clone();
+    tokio::spawn(async move {
+        while let Some(joined) = reads.join_next().await {
+            collect(&key, joined, &mut copies);
+        }
+        if copies.iter().any(|copy| copy.as_ref().map(|entry| entry.stamp) != stamp) {
+            let newer = newest(&copies, stamp).cloned();
+            repair(&state, key, newer);
+        }
+    });
+    Ok(response)
+}
+
+fn collect(key: &str, joined: Result<Read, JoinError>, copies: &mut Vec<Option<CacheEntry>>) {
+    match joined {
+        Ok((_, Ok(copy))) => copies.push(copy),
+        Ok((address, Err(e))) => debug!("Quorum read of {} on {} failed: {}", key, address, e),
+        Err(_) => {}
+    }
+}
+
+// The newest copy a replica holds, when it is newer than ours
+fn newest(copies: &[Option<CacheEntry>], stamp: Option<WriteStamp>) -> Option<&CacheEntry> {
+    let stamp = stamp?;
+    copies.iter().flatten().filter(|entry| newer_than(entry.stamp, stamp)).max_by_key(|entry| (entry.stamp.version, entry.stamp.at))
+}
+
+// What a replica holds of the key, None when it holds nothing
+async fn read_replica(peers: &PeerPool, address: &str, credentials: &PeerCredentials, key: &str) -> Result<Option<CacheEntry>, ServerError> {
+    // A replica only serves reads to connections that sent READONLY
+    match peers.send(address, credentials, &[Command::READONLY, Command::DUMP { key: key.to_string() }]).await?.pop() {
+        Some(Response::Data(payload)) => restore_entry(&payload, now_ms()).map(Some),
+        Some(Response::Error(e)) if e.code == ErrorCode::NotFound => Ok(None),
+        Some(Response::Error(e)) => Err(ServerError::InvalidArgument(format!("{} refused DUMP: {}", address, e))),
+        other => Err(ServerError::InvalidArgument(format!("Unexpected response from {}: {:?}", address, other))),
+    }
+}
+
+// Take a replica's newer copy when there is one, unless a write replaced ours
+// meanwhile, then stream the key as it stands to every replica
+fn repair(state: &Arc<RwLock<ServerState>>, key: String, newer: Option<CacheEntry>) {
+    let mut state = state.write().unwrap();
+    if let Some(entry) = newer {
+        let current = state.cache.get(&key).map(|entry| entry.stamp);
+        if current.is_some_and(|current| newer_than(entry.stamp, current)) {
+            let restore = Command::RESTORE { key: key.clone(), payload: dump_entry(&entry, now_ms()).to_vec(), replace: true };
+            // The restore streams to the replicas like any write
+            match stamped_write(&mut state, restore, None, false) {
+                Ok(_) => state.replication.record_read_repair(),
+                Err(e) => warn!("Read repair of {} failed: {}", key, e),
+            }
+            return;
+        }
+    }
+    antientropy::resend(&state, std::slice::from_ref(&key));
+    state.replication.record_read_repair();
+}
+
+fn newer_than(stamp: WriteStamp, other: WriteStamp) -> bool {
+    (stamp.version, stamp.at) > (other.version, other.at)
+}
@@ -0,0 +1,201 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use bytes::Bytes;
+use log::debug;
+use pluto_core::cache::ServerError;
+use pluto_core::cluster::key_slot;
+use crate::peer::PeerCredentials;
+use crate::state::ServerState;
+use crate::whisper::{WhisperMessage, WhisperResponse, WhisperServer};
+
+// Sharded Pub/Sub: a channel belongs to the member owning its hash slot, as a
+// key would. Publishes go to the owner, which delivers them to its own
+// subscribers and relays them only to the members that have subscribers of
+// the channel. Members tell each owner which of its channels they follow
+// every second and whenever their subscriptions change; an owner forgets a
+// member that stops telling it.
+
+// How often members tell owners which channels they follow
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(1);
+
+// How long what a member told an owner counts without being repeated
+const INTEREST_TTL: Duration = Duration::from_secs(5);
+
+struct Interest {
+    channels: HashSet<String>,
+    at: Instant,
+}
+
+// Which members follow the channels this node owns
+pub struct Relay {
+    interest: Mutex<HashMap<String, Interest>>,
+    pub forwarded: AtomicU64, // publishes passed on to their channel's owner
+    pub relayed: AtomicU64, // messages relayed to members with subscribers
+    pub relay_errors: AtomicU64, // of both, the ones the other member didn't take
+}
+
+impl Relay {
+    pub fn new() -> Self {
+        Relay {
+            interest: Mutex::new(HashMap::new()),
+            forwarded: AtomicU64::new(0),
+            relayed: AtomicU64::new(0),
+            relay_errors: AtomicU64::new(0),
+        }
+    }
+
+    // Replace what `member` follows with its latest list
+    pub fn record_interest(&self, member: String, channels: Vec<String>) {
+        let mut interest = self.interest.lock().unwrap();
+        if channels.is_empty() {
+            interest.remove(&member);
+        } else {
+            interest.insert(member, Interest { channels: channels.into_iter().collect(), at: Instant::now() });
+        }
+    }
+
+    // Members with subscribers of `channel`
+    fn followers(&self, channel: &str) -> Vec<String> {
+        let mut interest = self.interest.lock().unwrap();
+        interest.retain(|_, told| told.at.elapsed() < INTEREST_TTL);
+        interest.iter()
+            .filter(|(_, told)| told.channels.contains(channel))
+            .map(|(member, _)| member.clone())
+            .collect()
+    }
+
+    // Members following at least one channel of this node
+    pub fn follower_count(&self) -> usize {
+        self.interest.lock().unwrap().values().filter(|told| told.at.elapsed() < INTEREST_TTL).count()
+    }
+}
+
+impl Default for Relay {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// The member a channel belongs to, or None when it is this node's or there is
+// no cluster
+fn owner_of(state: &ServerState, channel: &str) -> Option<String> {
+    if !state.cluster_enabled {
+        return None;
+    }
+    let owner = state.cluster.slot_owner(key_slot(channel))?;
+    (owner.address != state.cluster.self_addr).then(|| owner.address.clone())
+}
+
+// Publish a client's message, returning how many subscribers of the owner
+// received it; the other members' subscribers get it relayed right after
+pub async fn publish(state: &Arc<RwLock<ServerState>>, channel: String, message: Bytes) -> Result<usize, ServerError> {
+    let (owner, credentials) = {
+        let state = state.read().unwrap();
+        match owner_of(&state, &channel) {
+            Some(owner) => {
+                state.relay.forwarded.fetch_add(1, Ordering::Relaxed);
+                (owner, state.config.peer_credentials())
+            }
+            None => return Ok(deliver(&state, &channel, message)),
+        }
+    };
+    let forward = WhisperMessage::Publish { channel, message: message.to_vec(), relayed: false };
+    match WhisperServer::send_whisper_message(&owner, &credentials, forward).await {
+        Ok(WhisperResponse { data: Some(WhisperMessage::Published { receivers }), .. }) => Ok(receivers),
+        Ok(response) => {
+            state.read().unwrap().relay.relay_errors.fetch_add(1, Ordering::Relaxed);
+            Err(ServerError::InvalidArgument(format!(
+                "{} didn't take the message: {}", owner, response.message.unwrap_or_default()
+            )))
+        }
+        Err(e) => {
+            state.read().unwrap().relay.relay_errors.fetch_add(1, Ordering::Relaxed);
+            Err(ServerError::InvalidArgument(format!("Couldn't reach {}, which owns the channel: {}", owner, e)))
+        }
+    }
+}
+
+// Publish a message the server itself sends, without waiting on other members
+pub fn publish_in_background(state: &ServerState, channel: &str, message: Bytes) {
+    match owner_of(state, channel) {
+        Some(owner) => {
+            state.relay.forwarded.fetch_add(1, Ordering::Relaxed);
+            let forward = WhisperMessage::Publish { channel: channel.to_string(), message: message.to_vec(), relayed: false };
+            send(state.relay.clone(), owner, state.config.peer_credentials(), forward);
+        }
+        None => {
+            deliver(state, channel, message);
+        }
+    }
+}
+
+// Deliver a message as the channel's owner: to this node's subscribers, and
+// on to every member following the channel. Returns the local receivers.
+pub fn deliver(state: &ServerState, channel: &str, message: Bytes) -> usize {
+    let receivers = state.pubsub.publish(channel, message.clone());
+    if state.cluster_enabled {
+        let credentials = state.config.peer_credentials();
+        for member in state.relay.followers(channel) {
+            state.relay.relayed.fetch_add(1, Ordering::Relaxed);
+            let relay = WhisperMessage::Publish { channel: channel.to_string(), message: message.to_vec(), relayed: true };
+            send(state.relay.clone(), member, credentials.clone(), relay);
+        }
+    }
+    receivers
+}
+
+fn send(relay: Arc<Relay>, member: String, credentials: PeerCredentials, message: WhisperMessage) {
+    tokio::spawn(async move {
+        let sent = WhisperServer::send_whisper_message(&member, &credentials, message).await;
+        if !matches!(sent, Ok(WhisperResponse { success: true, .. })) {
+            relay.relay_errors.fetch_add(1, Ordering::Relaxed);
+            debug!("Couldn't relay a message to {}", member);
+        }
+    });
+}
+
+// Tell the owners of the channels this node has subscribers of, every
+// ANNOUNCE_INTERVAL and whenever a channel gains its first subscriber or
+// loses its last
+pub async fn run(state: Arc<RwLock<ServerState>>) {
+    let (enabled, pubsub, shutdown) = {
+        let state = state.read().unwrap();
+        (state.cluster_enabled, state.pubsub.clone(), state.shutdown.clone())
+    };
+    if !enabled {
+        return;
+    }
+    let mut interval = tokio::time::interval(ANNOUNCE_INTERVAL);
+    let mut told: BTreeSet<String> = BTreeSet::new(); // owners told we follow channels of theirs
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = pubsub.changed() => {}
+            _ = shutdown.wait() => break,
+        }
+        let (self_addr, mut by_owner, credentials) = {
+            let state = state.read().unwrap();
+            let mut by_owner: BTreeMap<String, Vec<String>> = BTreeMap::new();
+            for channel in pubsub.channels() {
+                if let Some(owner) = owner_of(&state, &channel) {
+                    by_owner.entry(owner).or_default().push(channel);
+                }
+            }
+            (state.cluster.self_addr.clone(), by_owner, state.config.peer_credentials())
+        };
+        // Owners we stopped following hear so right away
+        for owner in &told {
+            by_owner.entry(owner.clone()).or_default();
+        }
+        told = by_owner.iter().filter(|(_, channels)| !channels.is_empty()).map(|(owner, _)| owner.clone()).collect();
+        for (owner, channels) in by_owner {
+            let message = WhisperMessage::Subscriptions { from: self_addr.clone(), channels };
+            let credentials = credentials.clone();
+            tokio::spawn(async move {
+                let _ = WhisperServer::send_whisper_message(&owner, &credentials, message).await;
+            });
+        }
+    }
+}
@@ -0,0 +1,460 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+use bytes::BytesMut;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::{broadcast, Notify};
+use log::{debug, info, warn};
+use pluto_core::cache::{ServerError, View, WriteStamp, dump_entry, now_ms};
+use pluto_core::codec::{encode_command, parse_response, Encoding};
+use pluto_core::protocol::{Command, Response};
+use crate::api::stamped_write;
+use crate::buffer::READ_BUFFER_SIZE;
+use crate::lazyfree;
+use crate::metering::label;
+use crate::state::ServerState;
+use crate::wal::Wal;
+
+// Writes a replica can fall behind by before it has to sync again
+const BACKLOG_WRITES: usize = 65536;
+
+// Pause between attempts to reach the primary
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+// How often a replica acknowledges its offset when no writes arrive, keeping
+// the idle reaper off the link
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+// A write applied on this node, as streamed to its replicas
+#[derive(Debug, Clone)]
+pub struct ReplicatedWrite {
+    pub offset: u64,
+    pub command: Command,
+    pub stamp: Option<WriteStamp>, // of the entry written, or of the deletion
+    pub local_only: bool,          // not shipped by geo-replication
+}
+
+// A replica streaming from this node
+struct ReplicaInfo {
+    address: String,
+    acked: u64, // highest offset the replica reported as applied
+    acked_at: u64, // unix time in milliseconds of its last acknowledgement
+}
+
+// How far a connected replica is behind this node, for INFO replication and /metrics
+pub struct ReplicaLag {
+    pub address: String,
+    pub acked: u64,
+    pub lag: u64, // writes streamed that it hasn't acknowledged
+    pub last_ack_ms: u64, // since it last acknowledged anything; it does every second when idle
+}
+
+// Replication role of a node and the bookkeeping of both sides
+pub struct Replication {
+    pub primary: Option<String>, // address of the primary when this node is a replica
+    offset: AtomicU64,           // writes streamed to replicas so far
+    primary_offset: AtomicU64,   // replica: the primary's offset as of the last write applied
+    primary_latest: AtomicU64,   // replica: the primary's offset as it last reported it
+    primary_io_at: AtomicU64,    // replica: unix time in milliseconds anything last came from the primary
+    link_up: AtomicBool,         // replica: whether the stream from the primary is open
+    repaired: AtomicU64,         // replica: keys anti-entropy had the primary resend
+    read_repaired: AtomicU64,    // primary: keys QUORUM reads found stale on a replica and fixed
+    next_id: AtomicU64,
+    replicas: Mutex<HashMap<u64, ReplicaInfo>>, // connected replicas by stream id
+    acked: Notify, // woken whenever a replica acknowledges a new offset
+    tx: broadcast::Sender<ReplicatedWrite>,
+    wal: OnceLock<Arc<Wal>>, // records every write streamed, once replayed at startup
+}
+
+impl Replication {
+    pub fn new(replica_of: &str) -> Self {
+        let (tx, _) = broadcast::channel(BACKLOG_WRITES);
+        Replication {
+            primary: (!replica_of.is_empty()).then(|| replica_of.to_string()),
+            offset: AtomicU64::new(0),
+            primary_offset: AtomicU64::new(0),
+            primary_latest: AtomicU64::new(0),
+            primary_io_at: AtomicU64::new(0),
+            link_up: AtomicBool::new(false),
+            repaired: AtomicU64::new(0),
+            read_repaired: AtomicU64::new(0),
+            next_id: AtomicU64::new(1),
+            replicas: Mutex::new(HashMap::new()),
+            acked: Notify::new(),
+            tx,
+            wal: OnceLock::new(),
+        }
+    }
+
+    pub fn is_replica(&self) -> bool {
+        self.primary.is_some()
+    }
+
+    // Whether any replica is streaming writes or the write-ahead log records
+    // them, so callers can skip copying commands
+    pub fn records_writes(&self) -> bool {
+        self.tx.receiver_count() > 0 || self.wal.get().is_some()
+    }
+
+    // Record every write from now on in the write-ahead log
+    pub fn attach_wal(&self, wal: Arc<Wal>) {
+        let _ = self.wal.set(wal);
+    }
+
+    pub fn wal(&self) -> Option<Arc<Wal>> {
+        self.wal.get().cloned()
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.offset.load(Ordering::Relaxed)
+    }
+
+    pub fn primary_offset(&self) -> u64 {
+        self.primary_offset.load(Ordering::Relaxed)
+    }
+
+    pub fn link_up(&self) -> bool {
+        self.link_up.load(Ordering::Relaxed)
+    }
+
+    // Replica: the primary's offset as it last reported it
+    pub fn primary_latest(&self) -> u64 {
+        self.primary_latest.load(Ordering::Relaxed)
+    }
+
+    // Replica: writes the primary made that this node hasn't applied yet
+    pub fn lag(&self) -> u64 {
+        self.primary_latest().saturating_sub(self.primary_offset())
+    }
+
+    // Replica: milliseconds since anything came from the primary, None
+    // before anything did
+    pub fn primary_last_io_ms(&self) -> Option<u64> {
+        match self.primary_io_at.load(Ordering::Relaxed) {
+            0 => None,
+            at => Some(now_ms().saturating_sub(at)),
+        }
+    }
+
+    fn primary_reached(&self, offset: u64) {
+        self.primary_latest.fetch_max(offset, Ordering::Relaxed);
+        self.primary_io_at.store(now_ms(), Ordering::Relaxed);
+    }
+
+    pub fn repaired(&self) -> u64 {
+        self.repaired.load(Ordering::Relaxed)
+    }
+
+    pub fn record_repairs(&self, keys: usize) {
+        self.repaired.fetch_add(keys as u64, Ordering::Relaxed);
+    }
+
+    pub fn read_repaired(&self) -> u64 {
+        self.read_repaired.load(Ordering::Relaxed)
+    }
+
+    pub fn record_read_repair(&self) {
+        self.read_repaired.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Acknowledged offset and lag of every connected replica
+    pub fn replicas(&self) -> Vec<ReplicaLag> {
+        let (offset, now) = (self.offset(), now_ms());
+        self.replicas.lock().unwrap().values()
+            .map(|replica| ReplicaLag {
+                address: replica.address.clone(),
+                acked: replica.acked,
+                lag: offset.saturating_sub(replica.acked),
+                last_ack_ms: now.saturating_sub(replica.acked_at),
+            })
+            .collect()
+    }
+
+    // Replicas that acknowledged every write up to `offset`
+    pub fn acked_count(&self, offset: u64) -> usize {
+        self.replicas.lock().unwrap().values().filter(|replica| replica.acked >= offset).count()
+    }
+
+    // Wait until `replicas` replicas acknowledged `offset` or the timeout
+    // passes, returning how many did. No timeout waits until they do.
+    pub async fn wait_for_acks(&self, offset: u64, replicas: usize, timeout: Option<Duration>) -> usize {
+        let deadline = timeout.map(|timeout| tokio::time::Instant::now() + timeout);
+        loop {
+            // Register for the wakeup before counting so an ack in between isn't missed
+            let notified = self.acked.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            let acked = self.acked_count(offset);
+            if acked >= replicas {
+                return acked;
+            }
+            match deadline {
+                Some(deadline) => {
+                    if tokio::time::timeout_at(deadline, notified).await.is_err() {
+                        return self.acked_count(offset);
+                    }
+                }
+                None => notified.await,
+            }
+        }
+    }
+
+    // Stream a write to the replicas. Called with the state's write lock held
+    // so replicas see writes in the order they were applied.
+    pub fn propagate(&self, command: Command) {
+        self.propagate_stamped(command, None, false);
+    }
+
+    // Stream the deletion of keys that were migrated to another node
+    pub fn propagate_migrated(&self, command: Command) {
+        self.propagate_stamped(command, None, true);
+    }
+
+    // Stream a write along with the stamp it left on its entry
+    pub fn propagate_stamped(&self, command: Command, stamp: Option<WriteStamp>, local_only: bool) {
+        if let Some(wal) = self.wal.get() {
+            wal.append(&command, stamp, local_only);
+        }
+        let offset = self.offset.fetch_add(1, Ordering::Relaxed) + 1;
+        let _ = self.tx.send(ReplicatedWrite { offset, command, stamp, local_only });
+    }
+}
+
+// The stream a primary sends to one replica: a copy of the keyspace taken at
+// SYNC, then every write applied after it
+pub struct ReplicaStream {
+    id: u64,
+    replication: Arc<Replication>,
+    offset: u64,
+    view: Option<View>, // the keyspace as the replica connected, until all of it was sent
+    shard: usize, // next shard of `view` to send
+    snapshot: VecDeque<(Command, WriteStamp)>, // restores of the shard being sent
+    rx: broadcast::Receiver<ReplicatedWrite>,
+}
+
+impl ReplicaStream {
+    // Start streaming to a replica. Holding the state lock while subscribing
+    // and taking a view means every write is either in the view or in the
+    // stream. The view is sent a shard at a time, without the lock.
+    pub fn start(state: &ServerState, address: String) -> Self {
+        let replication = state.replication.clone();
+        let rx = replication.tx.subscribe();
+        let view = state.cache.view();
+        let id = replication.next_id.fetch_add(1, Ordering::Relaxed);
+        info!("Replica {} connected, sending {} keys", address, view.len());
+        replication.replicas.lock().unwrap().insert(id, ReplicaInfo { address, acked: 0, acked_at: now_ms() });
+        ReplicaStream {
+            id,
+            offset: replication.offset(),
+            replication,
+            view: Some(view),
+            shard: 0,
+            snapshot: VecDeque::new(),
+            rx,
+        }
+    }
+
+    // Fill `snapshot` with the restores of the next shard of the view that
+    // has live keys, dropping the view once all of it was sent
+    fn next_shard(&mut self) {
+        while self.snapshot.is_empty() {
+            let Some(shard) = self.view.as_ref().and_then(|view| view.shards().get(self.shard)) else {
+                self.view = None;
+                return;
+            };
+            let now = now_ms();
+            self.snapshot = shard.iter()
+                .filter(|(_, entry)| !entry.is_expired(now))
+                .map(|(key, entry)| {
+                    let restore = Command::RESTORE { key: key.to_string(), payload: dump_entry(entry, now).to_vec(), replace: true };
+                    (restore, entry.stamp)
+                })
+                .collect();
+            self.shard += 1;
+        }
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    // Record the offset the replica reports as applied
+    pub fn ack(&self, offset: u64) {
+        if let Some(replica) = self.replication.replicas.lock().unwrap().get_mut(&self.id) {
+            replica.acked_at = now_ms();
+            if offset > replica.acked {
+                replica.acked = offset;
+                self.replication.acked.notify_waiters();
+            }
+        }
+    }
+
+    // Next push for the replica, or None when it fell too far behind and has to sync again
+    async fn next(&mut self) -> Option<Response> {
+        self.next_shard();
+        if let Some((command, stamp)) = self.snapshot.pop_front() {
+            return Some(Response::Replicate { offset: self.offset, command, stamp: Some(stamp), local_only: false });
+        }
+        match self.rx.recv().await {
+            Ok(write) => Some(Response::Replicate {
+                offset: write.offset,
+                command: write.command,
+                stamp: write.stamp,
+                local_only: write.local_only,
+            }),
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                warn!("Replica fell {} writes behind, dropping its stream", missed);
+                None
+            }
+            Err(broadcast::error::RecvError::Closed) => None,
+        }
+    }
+}
+
+impl Drop for ReplicaStream {
+    fn drop(&mut self) {
+        self.replication.replicas.lock().unwrap().remove(&self.id);
+        // A WAIT counting on this replica may now only be able to time out
+        self.replication.acked.notify_waiters();
+    }
+}
+
+// Wait for the next push of a connection's replica stream; never resolves on
+// connections that are not streaming to a replica
+pub async fn next_push(stream: &mut Option<ReplicaStream>) -> Option<Response> {
+    match stream {
+        Some(stream) => stream.next().await,
+        None => std::future::pending().await,
+    }
+}
+
+// Offsets and lag in the Prometheus text format, for /metrics on the admin port
+pub fn prometheus(replication: &Replication) -> String {
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, values: Vec<(String, f64)>| {
+        out.push_str(&format!("# HELP {} {}\n# TYPE {} {}\n", name, help, name, kind));
+        for (labels, value) in values {
+            out.push_str(&format!("{}{} {}\n", name, labels, value));
+        }
+    };
+    if replication.is_replica() {
+        let link_up = replication.link_up() as u8;
+        metric("flux_primary_link_up", "gauge", "Whether the stream from the primary is open", vec![(String::new(), link_up as f64)]);
+        metric("flux_repl_applied_offset", "gauge", "Primary offset of the last write applied", vec![(String::new(), replication.primary_offset() as f64)]);
+        metric("flux_repl_primary_offset", "gauge", "Primary offset as the primary last reported it", vec![(String::new(), replication.primary_latest() as f64)]);
+        metric("flux_repl_lag_writes", "gauge", "Writes made on the primary not applied yet", vec![(String::new(), replication.lag() as f64)]);
+        if let Some(ms) = replication.primary_last_io_ms() {
+            metric("flux_primary_last_io_seconds", "gauge", "Seconds since anything came from the primary", vec![(String::new(), ms as f64 / 1000.0)]);
+        }
+        return out;
+    }
+    let replicas = replication.replicas();
+    let each = |value: fn(&ReplicaLag) -> f64| {
+        replicas.iter().map(|replica| (format!("{{replica=\"{}\"}}", label(&replica.address)), value(replica))).collect()
+    };
+    metric("flux_repl_offset", "counter", "Writes streamed to replicas", vec![(String::new(), replication.offset() as f64)]);
+    metric("flux_connected_replicas", "gauge", "Replicas streaming from this node", vec![(String::new(), replicas.len() as f64)]);
+    metric("flux_replica_acked_offset", "gauge", "Offset each replica acknowledged", each(|replica| replica.acked as f64));
+    metric("flux_replica_lag_writes", "gauge", "Writes each replica hasn't acknowledged", each(|replica| replica.lag as f64));
+    metric("flux_replica_last_ack_seconds", "gauge", "Seconds since each replica acknowledged anything", each(|replica| replica.last_ack_ms as f64 / 1000.0));
+    out
+}
+
+// Keep a replica in sync with its primary, reconnecting whenever the stream breaks
+pub async fn run_replica(state: Arc<RwLock<ServerState>>) {
+    let (replication, shutdown) = {
+        let state = state.read().unwrap();
+        (state.replication.clone(), state.shutdown.clone())
+    };
+    let Some(primary) = replication.primary.clone() else {
+        return;
+    };
+    loop {
+        tokio::select! {
+            result = sync_from(&state, &primary) => {
+                if let Err(e) = result {
+                    warn!("Replication from {} stopped: {}", primary, e);
+                }
+            }
+            _ = shutdown.wait() => break,
+        }
+        replication.link_up.store(false, Ordering::Relaxed);
+        tokio::select! {
+            _ = tokio::time::sleep(RECONNECT_DELAY) => {}
+            _ = shutdown.wait() => break,
+        }
+    }
+}
+
+// Load the primary's keyspace and apply its writes until the connection drops
+async fn sync_from(state: &Arc<RwLock<ServerState>>, primary: &str) -> Result<(), ServerError> {
+    let (replication, credentials, address) = {
+        let state = state.read().unwrap();
+        (state.replication.clone(), state.config.peer_credentials(), state.cluster.self_addr.clone())
+    };
+    let mut stream = TcpStream::connect(primary).await?;
+    // Primary and replicas authenticate to each other with the same node credentials
+    let mut request = Vec::new();
+    let mut replies = 1;
+    if let Some(auth) = credentials.auth() {
+        request.extend(encode_command(&auth, Encoding::Json)?);
+        replies += 1;
+    }
+    request.extend(encode_command(&Command::SYNC { address }, Encoding::Json)?);
+    stream.write_all(&request).await?;
+
+    let mut heartbeat = tokio::time::interval(HEARTBEAT_INTERVAL);
+    let mut buf = BytesMut::with_capacity(READ_BUFFER_SIZE);
+    let mut acked = None;
+    loop {
+        let Some((response, used)) = parse_response(&buf, Encoding::Json).map_err(ServerError::Encoding)? else {
+            // Everything received is applied; acknowledge it before waiting for more
+            let offset = replication.primary_offset();
+            if replies == 0 && acked != Some(offset) {
+                stream.write_all(&encode_command(&Command::REPLACK { offset }, Encoding::Json)?).await?;
+                acked = Some(offset);
+            }
+            tokio::select! {
+                read = stream.read_buf(&mut buf) => {
+                    if read? == 0 {
+                        return Err(ServerError::Io(std::io::ErrorKind::UnexpectedEof.into()));
+                    }
+                }
+                _ = heartbeat.tick() => acked = None,
+            }
+            continue;
+        };
+        let _ = buf.split_to(used);
+        match response {
+            Response::Replicate { offset, command, stamp, local_only } => {
+                // Entries keep the primary's stamps, so a replica taking over
+                // resolves geo-replication conflicts the same way
+                let mut state = state.write().unwrap();
+                if let Err(e) = stamped_write(&mut state, command, stamp, local_only) {
+                    debug!("Replicated write at offset {} failed: {}", offset, e);
+                }
+                replication.primary_offset.store(offset, Ordering::Relaxed);
+                replication.primary_reached(offset);
+            }
+            // Replies to our acknowledgements, with the primary's offset
+            Response::Integer(offset) if replies == 0 => replication.primary_reached(offset as u64),
+            Response::Success if replies == 0 => {} // from primaries that don't report it
+            Response::Success if replies > 1 => replies -= 1,
+            // The keyspace copy follows the SYNC reply and replaces ours
+            Response::Integer(offset) if replies == 1 => {
+                replies = 0;
+                lazyfree::clear(&mut state.write().unwrap());
+                replication.primary_offset.store(offset as u64, Ordering::Relaxed);
+                replication.primary_reached(offset as u64);
+                replication.link_up.store(true, Ordering::Relaxed);
+                info!("Syncing from primary {} at offset {}", primary, offset);
+            }
+            Response::Error(e) => return Err(ServerError::InvalidArgument(format!("Primary refused SYNC: {}", e))),
+            other => {
+                return Err(ServerError::InvalidArgument(format!("Unexpected response from primary: {:?}", other)));
+            }
+        }
+    }
+}
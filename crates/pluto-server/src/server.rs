@@ -0,0 +1,335 @@
+use std::sync::{Arc, RwLock};
+use std::net::SocketAddr;
+use log::{debug, info, warn};
+use pluto_core::persistence;
+use crate::environment::read_flux_toml;
+use crate::state::ServerState;
+use crate::whisper::{WhisperServer, WHISPER_PORT_OFFSET};
+use crate::network::{self, ListenerKind};
+use crate::wal::Wal;
+use crate::origin::Origins;
+use crate::audit::AuditLog;
+use crate::middleware::Chain;
+use crate::{antientropy, backup, clients, expiry, geo, heartbeat, http, logging, migrate, raft, relay, replication, shutdown, stats, storage, telemetry, wal, warm};
+#[cfg(feature = "grpc")]
+use crate::grpc;
+
+// Start every listener and run until shutdown. `port_override` replaces the
+// port from flxc.toml, as the --port flag does.
+pub async fn run(port_override: Option<u16>) -> std::io::Result<()> {
+    // Read bind IP and port from flxc.toml (create if missing)
+    let mut conf = read_flux_toml();
+    logging::init(&conf);
+    if let Err(e) = conf.check_cluster_user() {
+        eprintln!("Invalid cluster_user - {}", e);
+        return Ok(());
+    }
+    if let Err(e) = conf.check_tenants() {
+        eprintln!("Invalid tenants - {}", e);
+        return Ok(());
+    }
+    let telemetry = telemetry::init(&conf);
+    let port = port_override.unwrap_or(conf.port);
+    conf.port = port;
+    
+    // Create public address for cluster communication
+    let public_port = if let Some(port) = port_override { 
+        // If port was overridden via CLI, use the same override for public port
+        port 
+    } else { 
+        conf.public_port 
+    };
+    let public_addr = format!("{}:{}", conf.public_ip, public_port);
+    
+    // Create server state with public address for cluster
+    let state = Arc::new(RwLock::new(ServerState::new(public_addr.clone(), conf.clone())));
+    
+    // Restore the cache from the last snapshot
+    {
+        let mut state = state.write().unwrap();
+        state.keys = match conf.keyring() {
+            Ok(keys) => keys,
+            Err(e) => {
+                eprintln!("Failed to load the encryption key - {}", e);
+                return Ok(());
+            }
+        };
+        state.origins = match Origins::new(&conf.origins) {
+            Ok(origins) => Arc::new(origins),
+            Err(e) => {
+                eprintln!("Failed to set up the origins - {}", e);
+                return Ok(());
+            }
+        };
+        if conf.audit_enabled {
+            let audit = match AuditLog::open(&conf) {
+                Ok(audit) => audit,
+                Err(e) => {
+                    eprintln!("Failed to open the audit log {} - {}", conf.audit_file, e);
+                    return Ok(());
+                }
+            };
+            state.middleware = Arc::new(Chain::new(&conf, state.stats.clone(), state.metering.clone(), Some(audit)));
+        }
+        let keys = state.keys.clone();
+        // A storage engine that kept the keyspace holds every write the
+        // snapshot and the log have
+        let stored = match storage::open(&conf, &keys) {
+            Ok(Some((mut keyspace, written))) => {
+                keyspace.track_usage_like(&state.cache);
+                state.cache = keyspace;
+                written
+            }
+            Ok(None) => false,
+            Err(e) => {
+                eprintln!("Failed to open the storage in {} - {}", conf.storage_dir, e);
+                return Ok(());
+            }
+        };
+        let snapshot_loaded = if stored {
+            info!("Loaded {} keys from {}", state.cache.len(), conf.storage_dir);
+            false
+        } else {
+            match persistence::load_snapshot(&mut state.cache, &conf.snapshot_file, &keys) {
+                Ok(_) => std::path::Path::new(&conf.snapshot_file).exists(),
+                Err(e) => {
+                    eprintln!("Failed to load snapshot {} - {}", conf.snapshot_file, e);
+                    false
+                }
+            }
+        };
+        // Then the writes made after it, from the write-ahead log
+        if conf.wal_enabled || !conf.wal_restore_from.is_empty() {
+            let replayed = if stored {
+                wal::last_position(&state).map(|position| (position, 0))
+            } else {
+                wal::replay(&mut state, snapshot_loaded)
+            };
+            let (position, replayed) = match replayed {
+                Ok(replayed) => replayed,
+                Err(e) => {
+                    eprintln!("Failed to replay the write-ahead log - {}", e);
+                    return Ok(());
+                }
+            };
+            if conf.wal_enabled {
+                match Wal::open(&conf, position, replayed, keys.clone()) {
+                    Ok(log) => state.replication.attach_wal(Arc::new(log)),
+                    Err(e) => {
+                        eprintln!("Failed to open the write-ahead log in {} - {}", conf.wal_dir, e);
+                        return Ok(());
+                    }
+                }
+            }
+        }
+        // A snapshot written unencrypted or with a key rotated out since is
+        // rewritten with the current key
+        if keys.is_enabled()
+            && snapshot_loaded
+            && persistence::snapshot_key(&conf.snapshot_file).ok().flatten() != keys.current_id() {
+            match shutdown::save(&state) {
+                Ok(_) => info!("Encrypted {} with the current key", conf.snapshot_file),
+                Err(e) => eprintln!("Failed to rewrite {} with the current key - {}", conf.snapshot_file, e),
+            }
+        }
+        state.health.set_snapshot_loaded();
+        state.health.set_warming(!conf.warm_from.is_empty());
+    }
+    
+    // Parse bind addresses
+    let bind_addrs = match network::parse_bind_addrs(&conf.bind, port) {
+        Ok(addrs) => addrs,
+        Err(e) => {
+            eprintln!("Invalid address format - {}", e);
+            return Ok(());
+        }
+    };
+    
+    if conf.is_protected() {
+        warn!("Protected mode: no requirepass is set, so clients on other hosts are refused; set requirepass or protected_mode = false to accept them");
+    }
+
+    // Create one listener per bind address with the configured TCP options
+    let mut listeners = Vec::new();
+    for bind_addr in &bind_addrs {
+        listeners.push((network::bind_listener(*bind_addr, &conf)?, ListenerKind::Data));
+    }
+    
+    // Admin listeners for the control plane, localhost only unless configured otherwise
+    let admin_addrs = if conf.admin_enabled {
+        let admin_port = match conf.admin_port_for(port) {
+            Ok(admin_port) => admin_port,
+            Err(e) => {
+                eprintln!("Invalid admin port - {}", e);
+                return Ok(());
+            }
+        };
+        match network::parse_bind_addrs(&conf.admin_bind, admin_port) {
+            Ok(addrs) => addrs,
+            Err(e) => {
+                eprintln!("Invalid admin address format - {}", e);
+                return Ok(());
+            }
+        }
+    } else {
+        Vec::new()
+    };
+    for admin_addr in &admin_addrs {
+        listeners.push((network::bind_listener(*admin_addr, &conf)?, ListenerKind::Admin));
+    }
+    
+    // Start whisper server for inter-node communication. Only cluster
+    // members whisper, so a node on its own may use a port too high for it.
+    match WhisperServer::whisper_port_for(port) {
+        Some(whisper_port) => {
+            let whisper_server = WhisperServer::new(whisper_port, state.clone());
+            let whisper_addrs = bind_addrs.clone();
+            tokio::spawn(async move {
+                if let Err(e) = whisper_server.start(whisper_addrs).await {
+                    eprintln!("Whisper server error: {}", e);
+                }
+            });
+        }
+        None if conf.cluster_enabled => {
+            eprintln!("Invalid port - port {} + {} is past 65535, leaving no whisper port for the cluster", port, WHISPER_PORT_OFFSET);
+            return Ok(());
+        }
+        None => warn!("Port {} leaves no whisper port; fine while cluster_enabled is off", port),
+    }
+    
+    // Start the REST gateway if enabled
+    let http_port = match conf.http_enabled.then(|| conf.http_port_for(port)).transpose() {
+        Ok(http_port) => http_port,
+        Err(e) => {
+            eprintln!("Invalid HTTP port - {}", e);
+            return Ok(());
+        }
+    };
+    if let Some(http_port) = http_port {
+        let http_addrs: Vec<SocketAddr> = bind_addrs.iter()
+            .map(|addr| SocketAddr::new(addr.ip(), http_port))
+            .collect();
+        for http_addr in &http_addrs {
+            debug!("Starting HTTP gateway on {}", http_addr);
+        }
+        let http_state = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = http::serve(http_addrs, http_state).await {
+                eprintln!("HTTP gateway error: {}", e);
+            }
+        });
+    }
+    
+    // Start the gRPC API if enabled
+    let grpc_port = match conf.grpc_enabled.then(|| conf.grpc_port_for(port)).transpose() {
+        Ok(grpc_port) => grpc_port,
+        Err(e) => {
+            eprintln!("Invalid gRPC port - {}", e);
+            return Ok(());
+        }
+    };
+    #[cfg(feature = "grpc")]
+    if let Some(grpc_port) = grpc_port {
+        let grpc_addrs: Vec<SocketAddr> = bind_addrs.iter()
+            .map(|addr| SocketAddr::new(addr.ip(), grpc_port))
+            .collect();
+        tokio::spawn(grpc::serve(grpc_addrs, state.clone()));
+    }
+    #[cfg(not(feature = "grpc"))]
+    if conf.grpc_enabled {
+        eprintln!("[WARN] grpc_enabled is set but this build does not include the grpc feature");
+    }
+    
+    // Start the idle connection reaper
+    let idle_clients = state.read().unwrap().clients.clone();
+    tokio::spawn(clients::run_idle_reaper(idle_clients, conf.idle_timeout_secs));
+    
+    // Track the keyspace size over time for INFO stats
+    tokio::spawn(stats::run_keyspace_sampler(state.clone()));
+    
+    // Remove keys whose expiry has passed
+    tokio::spawn(expiry::run_expiry_sweeper(state.clone()));
+    
+    // Follow the primary when this node is a replica
+    tokio::spawn(replication::run_replica(state.clone()));
+    
+    // Heal keys that drifted from the primary
+    tokio::spawn(antientropy::run(state.clone()));
+    
+    // Ship every write to the standby cluster when one is configured
+    tokio::spawn(geo::run(state.clone()));
+
+    // Flush and archive the write-ahead log
+    tokio::spawn(wal::run(state.clone()));
+
+    // Sync the storage engine's writes to disk
+    tokio::spawn(storage::run(state.clone()));
+
+    // Upload a snapshot to the backup bucket every backup_interval_secs
+    tokio::spawn(backup::run(state.clone()));
+
+    // Copy the hot keys from warm_from before reporting ready
+    tokio::spawn(warm::run(state.clone()));
+    
+    // Finish a slot migration interrupted by a restart
+    migrate::resume(&state);
+    
+    // Elect a leader for the cluster metadata log
+    tokio::spawn(raft::run(state.clone()));
+    
+    // Ping the other members and mark the ones that stop answering
+    tokio::spawn(heartbeat::run(state.clone()));
+
+    // Tell the owners of channels with subscribers here to relay their messages
+    tokio::spawn(relay::run(state.clone()));
+    
+    // Print startup message
+    for bind_addr in &bind_addrs {
+        println!("Flux is running on {}", bind_addr);
+        println!("Whisper protocol running on {}", SocketAddr::new(bind_addr.ip(), port + 10000));
+    }
+    for admin_addr in &admin_addrs {
+        println!("Admin port running on {}", admin_addr);
+    }
+    if let Some(http_port) = http_port {
+        println!("HTTP gateway running on port {}", http_port);
+    }
+    if cfg!(feature = "grpc") && let Some(grpc_port) = grpc_port {
+        println!("gRPC API running on port {}", grpc_port);
+    }
+    
+    // Stop cleanly on SIGINT/SIGTERM
+    let shutdown = state.read().unwrap().shutdown.clone();
+    tokio::spawn(shutdown::listen_for_signals(shutdown.clone()));
+    
+    // Run one accept loop per listener until shutdown is requested
+    // Health probes and other HTTP requests on the admin port go to the admin HTTP server
+    let (http_handoff, http_incoming) = tokio::sync::mpsc::channel(64);
+    tokio::spawn(http::serve_admin(http::HandoffListener::new(http_incoming), state.clone()));
+    
+    let mut servers = tokio::task::JoinSet::new();
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    if conf.io_uring_threads > 0 {
+        let data = crate::uring::take_data_listeners(&mut listeners)?;
+        servers.spawn(crate::uring::serve(data, state.clone(), conf.io_uring_threads));
+    }
+    #[cfg(not(all(feature = "io-uring", target_os = "linux")))]
+    if conf.io_uring_threads > 0 {
+        warn!("io_uring_threads is set, but this build has no io_uring backend (the io-uring feature, on Linux); serving the data port with tokio");
+    }
+    for (listener, kind) in listeners {
+        let http = (kind == ListenerKind::Admin).then(|| http_handoff.clone());
+        servers.spawn(network::serve(listener, state.clone(), kind, http));
+    }
+    drop(http_handoff);
+    while servers.join_next().await.is_some() {}
+    
+    // The accept loops have stopped; let open connections finish, then persist state
+    println!("Shutting down");
+    shutdown.trigger();
+    shutdown::drain_and_persist(&state).await;
+    if let Some(telemetry) = telemetry {
+        telemetry.shutdown();
+    }
+    Ok(())
+}
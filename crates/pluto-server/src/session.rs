@@ -0,0 +1,63 @@
+use pluto_core::codec::Encoding;
+use pluto_core::merkle::MerkleTree;
+use crate::state::ServerState;
+use crate::pubsub::Subscriber;
+use crate::tracking::Tracker;
+use crate::replication::ReplicaStream;
+use crate::middleware::{Caller, Chain, Transport};
+use crate::clients::{OutputBuffer, OutputLimits};
+use crate::api::auth_required;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+// Per-connection state of a streaming connection (native protocol or WebSocket)
+pub struct Session {
+    pub subscriber: Subscriber,
+    pub encoding: Encoding,
+    pub caller: Caller,
+    pub readonly: bool,      // READONLY: serve reads on a replica instead of redirecting them
+    pub asking: bool,        // ASKING: the next command may use a slot this node is importing
+    pub replica: Option<ReplicaStream>, // writes streamed to a replica after SYNC
+    pub tracker: Option<Tracker>, // CLIENT_TRACKING: keys read are remembered and invalidated
+    pub merkle: Option<MerkleTree>, // tree a replica is comparing against, built by MERKLE on the root
+    pub middleware: Arc<Chain>,
+    pub command_timeout: Duration, // reads taking longer fail; zero means no limit
+    pub output: Arc<OutputBuffer>, // bytes waiting to be written to the client
+}
+
+impl Session {
+    pub fn new(state: &ServerState, transport: Transport, addr: Option<SocketAddr>) -> Self {
+        let config = &state.config;
+        let output = Arc::new(OutputBuffer::new(
+            OutputLimits {
+                hard: config.client_output_hard_limit,
+                soft: config.client_output_soft_limit,
+                soft_secs: config.client_output_soft_secs,
+            },
+            OutputLimits {
+                hard: config.pubsub_output_hard_limit,
+                soft: config.pubsub_output_soft_limit,
+                soft_secs: config.pubsub_output_soft_secs,
+            },
+        ));
+        Session {
+            subscriber: Subscriber::new(state.pubsub.clone(), output.clone()),
+            encoding: Encoding::Json,
+            caller: Caller {
+                transport,
+                addr,
+                authenticated: !auth_required(state),
+                user: None,
+            },
+            readonly: false,
+            asking: false,
+            replica: None,
+            tracker: None,
+            merkle: None,
+            middleware: state.middleware.clone(),
+            command_timeout: Duration::from_millis(state.config.command_timeout_ms),
+            output,
+        }
+    }
+}
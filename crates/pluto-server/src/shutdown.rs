@@ -0,0 +1,189 @@
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::sync::broadcast;
+use log::{error, info, warn};
+use pluto_core::protocol::ShutdownMode;
+use crate::{backup, storage};
+use crate::snapshot::Snapshots;
+use crate::state::ServerState;
+use crate::wal::Wal;
+use pluto_core::cache::{now_ms, ServerError, View};
+use pluto_core::encryption::Keyring;
+use pluto_core::persistence::save_snapshot_counting;
+
+// Server-wide shutdown notification. Accept loops and connections wait on it
+// so they can stop between commands instead of dying mid-write.
+pub struct Shutdown {
+    tx: broadcast::Sender<()>,
+    triggered: AtomicBool,
+    mode: Mutex<Option<ShutdownMode>>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        let (tx, _) = broadcast::channel(1);
+        Shutdown {
+            tx,
+            triggered: AtomicBool::new(false),
+            mode: Mutex::new(None),
+        }
+    }
+
+    // Start shutting down; later calls are no-ops
+    pub fn trigger(&self) {
+        if !self.triggered.swap(true, Ordering::SeqCst) {
+            let _ = self.tx.send(());
+        }
+    }
+
+    // Start shutting down on request of a client
+    pub fn trigger_with(&self, mode: Option<ShutdownMode>) {
+        if mode.is_some() {
+            *self.mode.lock().unwrap() = mode;
+        }
+        self.trigger();
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.triggered.load(Ordering::SeqCst)
+    }
+
+    // Resolve once shutdown has been triggered, including before the call
+    pub async fn wait(&self) {
+        // Subscribe before checking the flag so a concurrent trigger is not missed
+        let mut rx = self.tx.subscribe();
+        if self.triggered.load(Ordering::SeqCst) {
+            return;
+        }
+        let _ = rx.recv().await;
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Trigger shutdown on SIGINT or SIGTERM
+pub async fn listen_for_signals(shutdown: Arc<Shutdown>) {
+    let ctrl_c = tokio::signal::ctrl_c();
+    #[cfg(unix)]
+    {
+        let mut sigterm = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(signal) => signal,
+            Err(e) => {
+                warn!("Failed to install SIGTERM handler: {}", e);
+                let _ = ctrl_c.await;
+                shutdown.trigger();
+                return;
+            }
+        };
+        tokio::select! {
+            _ = ctrl_c => info!("Received SIGINT"),
+            _ = sigterm.recv() => info!("Received SIGTERM"),
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = ctrl_c.await;
+        info!("Received Ctrl-C");
+    }
+    shutdown.trigger();
+}
+
+// Wait for open connections to finish their current commands, then write
+// out state that must survive a restart
+pub async fn drain_and_persist(state: &Arc<RwLock<ServerState>>) {
+    let (clients, timeout_secs, save) = {
+        let state = state.read().unwrap();
+        let save = match *state.shutdown.mode.lock().unwrap() {
+            Some(mode) => mode == ShutdownMode::SAVE,
+            None => state.config.save_on_shutdown,
+        };
+        (state.clients.clone(), state.config.shutdown_timeout_secs, save)
+    };
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(timeout_secs);
+    while !clients.is_empty() {
+        if tokio::time::Instant::now() >= deadline {
+            warn!("Shutdown deadline reached with {} connections still open", clients.len());
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    let (saved, upload) = {
+        let state = state.read().unwrap();
+        let mut saved = None;
+        if save {
+            match self::save(&state) {
+                Ok(position) => saved = Some(position),
+                Err(e) => error!("Failed to save snapshot on shutdown: {}", e),
+            }
+        }
+        if let Some(wal) = state.replication.wal()
+            && let Err(e) = wal.close() {
+            error!("Failed to flush the write-ahead log on shutdown: {}", e);
+        }
+        state.cluster.write_cluster_file();
+        (saved, !state.config.backup_url.is_empty())
+    };
+    if let Err(e) = storage::sync(state) {
+        error!("Failed to sync the storage engine on shutdown: {}", e);
+    }
+    if let Some(position) = saved
+        && upload
+        && let Err(e) = backup::upload_snapshot(state, position).await {
+        error!("Failed to upload snapshot on shutdown: {}", e);
+    }
+    info!("Shutdown complete");
+}
+
+// Save a snapshot and mark the log as covered up to it, returning the log
+// position it holds writes up to (0 without a log)
+pub fn save(state: &ServerState) -> Result<u64, ServerError> {
+    PendingSave::new(state).save()
+}
+
+// A snapshot about to be saved: a view of the keyspace and where the log was
+// when it was taken. Taking it needs the state's lock, saving it doesn't.
+pub struct PendingSave {
+    view: View,
+    path: String,
+    keys: Keyring,
+    wal: Option<Arc<Wal>>,
+    position: u64, // of the last write in the view
+    at: u64,
+    status: Arc<Snapshots>,
+}
+
+impl PendingSave {
+    pub fn new(state: &ServerState) -> Self {
+        let wal = state.replication.wal();
+        PendingSave {
+            view: state.cache.view(),
+            path: state.config.snapshot_file.clone(),
+            keys: state.keys.clone(),
+            position: wal.as_ref().map_or(0, |wal| wal.position()),
+            wal,
+            at: now_ms(),
+            status: state.snapshots.clone(),
+        }
+    }
+
+    // Write the snapshot and mark the log as covered up to it, returning
+    // the log position it holds writes up to (0 without a log)
+    pub fn save(self) -> Result<u64, ServerError> {
+        self.status.begin(self.view.len());
+        let result = save_snapshot_counting(&self.view, &self.path, &self.keys, &self.status.done).and_then(|keys| {
+            if let Some(wal) = &self.wal {
+                wal.checkpoint(self.position, self.at)?;
+            }
+            Ok(keys)
+        });
+        self.status.finish(&result);
+        result?;
+        Ok(self.position)
+    }
+}
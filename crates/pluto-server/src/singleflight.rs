@@ -0,0 +1,85 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+// Stampede protection: the first client to GET_OR_LOCK a missing key takes
+// its lock and computes the value, while the others wait for it to be written
+// rather than all computing it at once. A lock goes away when the key is
+// written, on UNLOCK, or once its time runs out should its holder give up.
+
+// Keys being computed, and who waits for them
+pub struct Locks {
+    held: Mutex<HashMap<String, Lock>>,
+    active: AtomicUsize, // locks held; writes skip the table without any
+    pub acquired: AtomicU64,
+    pub waits: AtomicU64, // GET_OR_LOCKs that found the key locked
+    pub wait_timeouts: AtomicU64, // of those, the ones the key wasn't written in time for
+}
+
+struct Lock {
+    until: Instant,
+    written: Arc<Notify>,
+}
+
+impl Locks {
+    pub fn new() -> Self {
+        Locks {
+            held: Mutex::new(HashMap::new()),
+            active: AtomicUsize::new(0),
+            acquired: AtomicU64::new(0),
+            waits: AtomicU64::new(0),
+            wait_timeouts: AtomicU64::new(0),
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active.load(Ordering::Relaxed) > 0
+    }
+
+    pub fn held(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    // Take the lock of `key` for `ttl`, or hand back what to wait on when
+    // someone else holds it
+    pub fn acquire(&self, key: &str, ttl: Duration) -> Result<(), Arc<Notify>> {
+        let now = Instant::now();
+        let mut held = self.held.lock().unwrap();
+        if let Some(lock) = held.get(key).filter(|lock| lock.until > now) {
+            self.waits.fetch_add(1, Ordering::Relaxed);
+            return Err(lock.written.clone());
+        }
+        // Locks whose holder gave up are dropped as new ones are taken
+        held.retain(|_, lock| lock.until > now);
+        held.insert(key.to_string(), Lock { until: now + ttl, written: Arc::new(Notify::new()) });
+        self.active.store(held.len(), Ordering::Relaxed);
+        self.acquired.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    // Drop the locks of `keys`, waking whoever waits for them; returns how
+    // many were held
+    pub fn release(&self, keys: &[&str]) -> usize {
+        if !self.is_active() {
+            return 0;
+        }
+        let mut held = self.held.lock().unwrap();
+        let mut released = 0;
+        for key in keys {
+            if let Some(lock) = held.remove(*key) {
+                lock.written.notify_waiters();
+                released += 1;
+            }
+        }
+        self.active.store(held.len(), Ordering::Relaxed);
+        released
+    }
+}
+
+impl Default for Locks {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,77 @@
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use pluto_core::cache::now_ms;
+
+// Snapshot saves, whether made on shutdown, for a backup or to re-encrypt
+// the file, for INFO persistence and PERSISTENCE_STATUS
+#[derive(Default)]
+pub struct Snapshots {
+    running: AtomicBool,
+    started_at: AtomicU64, // unix time in milliseconds the save in progress started
+    total: AtomicUsize, // entries in the view being saved
+    pub done: AtomicUsize, // of them gone through so far
+    pub saves: AtomicU64,
+    pub failures: AtomicU64,
+    pub last_at: AtomicU64, // unix time in milliseconds of the last save that succeeded, 0 before
+    pub last_keys: AtomicU64,
+    pub last_duration_ms: AtomicU64,
+    pub last_error: Mutex<String>, // of the last save, empty when it succeeded
+}
+
+impl Snapshots {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Mark a save of `total` entries as started
+    pub fn begin(&self, total: usize) {
+        self.total.store(total, Ordering::Relaxed);
+        self.done.store(0, Ordering::Relaxed);
+        self.started_at.store(now_ms(), Ordering::Relaxed);
+        self.running.store(true, Ordering::Relaxed);
+    }
+
+    // Record how the save started by `begin` ended, with the keys it saved
+    pub fn finish<E: ToString>(&self, result: &Result<usize, E>) {
+        let at = now_ms();
+        match result {
+            Ok(keys) => {
+                self.saves.fetch_add(1, Ordering::Relaxed);
+                self.last_at.store(at, Ordering::Relaxed);
+                self.last_keys.store(*keys as u64, Ordering::Relaxed);
+                self.last_duration_ms.store(at.saturating_sub(self.started_at.load(Ordering::Relaxed)), Ordering::Relaxed);
+                self.last_error.lock().unwrap().clear();
+            }
+            Err(e) => {
+                self.failures.fetch_add(1, Ordering::Relaxed);
+                *self.last_error.lock().unwrap() = e.to_string();
+            }
+        }
+        self.running.store(false, Ordering::Relaxed);
+    }
+
+    pub fn in_progress(&self) -> bool {
+        self.running.load(Ordering::Relaxed)
+    }
+
+    // How far the save in progress is, 0 when none is. Going through the
+    // entries is most of the work, so it stays under 100 until the file is
+    // written out.
+    pub fn percent_done(&self) -> u64 {
+        if !self.in_progress() {
+            return 0;
+        }
+        match self.total.load(Ordering::Relaxed) {
+            0 => 99,
+            total => (self.done.load(Ordering::Relaxed) * 100 / total).min(99) as u64,
+        }
+    }
+
+    // Milliseconds the save in progress has been running, 0 when none is
+    pub fn running_ms(&self) -> u64 {
+        match self.in_progress() {
+            true => now_ms().saturating_sub(self.started_at.load(Ordering::Relaxed)),
+            false => 0,
+        }
+    }
+}
@@ -0,0 +1,112 @@
+use std::sync::Arc;
+use std::time::Instant;
+use pluto_core::cache::Keyspace;
+use pluto_core::cluster::ClusterState;
+use pluto_core::encryption::Keyring;
+use crate::buffer::BufferPool;
+use crate::clients::ClientRegistry;
+use crate::pubsub::PubSub;
+use crate::relay::Relay;
+use crate::acl::Acl;
+use crate::metering::Metering;
+use crate::middleware::Chain;
+use crate::tracking::Tracking;
+use crate::singleflight::Locks;
+use crate::heartbeat::Heartbeats;
+use crate::raft::Raft;
+use crate::replication::Replication;
+use crate::shutdown::Shutdown;
+use crate::health::Health;
+use crate::stats::Stats;
+use crate::defrag::Defrag;
+use crate::migrate::SlotMigration;
+use crate::handoff::Handoff;
+use crate::geo::Geo;
+use crate::backup::Backup;
+use crate::snapshot::Snapshots;
+use crate::origin::Origins;
+use crate::lazyfree::LazyFree;
+use crate::environment::FluxConfig;
+use crate::peer::PeerPool;
+
+// Server state
+pub struct ServerState {
+    pub cache: Keyspace,
+    pub cluster: ClusterState,
+    pub cluster_enabled: bool,
+    pub buffer_pool: Arc<BufferPool>,
+    pub config: FluxConfig,
+    pub clients: Arc<ClientRegistry>,
+    pub started_at: Instant,
+    pub pubsub: Arc<PubSub>,
+    pub relay: Arc<Relay>, // members following the channels this node owns
+    pub tracking: Arc<Tracking>,
+    pub singleflight: Arc<Locks>, // GET_OR_LOCK locks of keys being computed
+    pub shutdown: Arc<Shutdown>,
+    pub health: Arc<Health>,
+    pub stats: Arc<Stats>,
+    pub middleware: Arc<Chain>, // every client command runs through it
+    pub acl: Arc<Acl>, // users besides requirepass, with the usage of their quotas kept in `cache`
+    pub metering: Arc<Metering>,
+    pub defrag: Arc<Defrag>,
+    pub replication: Arc<Replication>,
+    pub migration: Arc<SlotMigration>,
+    pub handoff: Arc<Handoff>,
+    pub geo: Arc<Geo>,
+    pub backup: Arc<Backup>,
+    pub snapshots: Arc<Snapshots>,
+    pub keys: Keyring, // persisted files are encrypted with
+    pub origins: Arc<Origins>,
+    pub lazy_free: LazyFree,
+    pub raft: Raft,
+    pub heartbeats: Heartbeats,
+    pub peers: Arc<PeerPool>, // connections to other nodes kept open between commands
+}
+
+impl ServerState {
+    pub fn new(self_addr: String, config: FluxConfig) -> Self {
+        let cluster_enabled = config.cluster_enabled;
+        let replication = Arc::new(Replication::new(&config.replica_of));
+        let geo = Arc::new(Geo::new(&config));
+        let tracking = Arc::new(Tracking::new(config.tracking_table_max_keys));
+        let stats = Arc::new(Stats::new());
+        let mut cluster = ClusterState::new(self_addr, cluster_enabled, config.cluster_hashing, config.cluster_weight.max(1));
+        let raft = Raft::load_or_bootstrap(&mut cluster);
+        let mut cache = Keyspace::with_layout(config.layout());
+        let acl = Arc::new(Acl::new(&config.users, &config.tenants, &mut cache));
+        let metering = Arc::new(Metering::new(&config.meter_prefixes, &mut cache));
+        let middleware = Arc::new(Chain::new(&config, stats.clone(), metering.clone(), None));
+        ServerState {
+            cache,
+            cluster,
+            cluster_enabled,
+            buffer_pool: Arc::new(BufferPool::new()),
+            config,
+            clients: Arc::new(ClientRegistry::new()),
+            started_at: Instant::now(),
+            pubsub: Arc::new(PubSub::new()),
+            relay: Arc::new(Relay::new()),
+            tracking,
+            singleflight: Arc::new(Locks::new()),
+            shutdown: Arc::new(Shutdown::new()),
+            health: Arc::new(Health::new()),
+            middleware,
+            stats,
+            acl,
+            metering,
+            defrag: Arc::new(Defrag::new()),
+            replication,
+            migration: Arc::new(SlotMigration::new()),
+            handoff: Arc::new(Handoff::new()),
+            geo,
+            backup: Arc::new(Backup::new()),
+            snapshots: Arc::new(Snapshots::new()),
+            keys: Keyring::default(),
+            origins: Arc::new(Origins::default()),
+            lazy_free: LazyFree::new(),
+            raft,
+            heartbeats: Heartbeats::new(),
+            peers: Arc::new(PeerPool::new()),
+        }
+    }
+}
@@ -0,0 +1,155 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use hdrhistogram::Histogram;
+use serde::Serialize;
+use crate::state::ServerState;
+
+// Significant figures kept by the latency histograms
+const LATENCY_PRECISION: u8 = 3;
+
+// Keyspace size samples kept, one per minute
+const KEYSPACE_SAMPLES: usize = 60;
+
+// Latency percentiles of one command type, in microseconds
+pub struct LatencySummary {
+    pub command: &'static str,
+    pub calls: u64,
+    pub p50: u64,
+    pub p95: u64,
+    pub p99: u64,
+    pub max: u64,
+}
+
+// A command that ran longer than slowlog_slower_than_us
+#[derive(Debug, Clone, Serialize)]
+pub struct SlowEntry {
+    pub id: u64,
+    pub at: u64, // unix time in milliseconds it finished
+    pub duration_us: u64,
+    pub command: &'static str,
+    pub client: Option<String>, // address of the client, when the transport has one
+    pub user: Option<String>,
+}
+
+// Server-wide command statistics
+pub struct Stats {
+    latency: Mutex<BTreeMap<&'static str, Histogram<u64>>>,
+    pub hits: AtomicU64,
+    pub misses: AtomicU64,
+    pub stale_reads: AtomicU64, // reads that found the key past its soft TTL
+    pub missing_reads: AtomicU64, // reads that found the key cached as missing
+    pub expired_keys: AtomicU64,  // keys removed because their expiry passed
+    pub evicted_keys: AtomicU64,  // keys removed to stay within maxmemory
+    pub timed_out_commands: AtomicU64, // commands that ran past command_timeout_ms
+    pub cancelled_commands: AtomicU64, // commands dropped because their client hung up
+    pub evicted_clients: AtomicU64, // connections closed for going over an output buffer limit
+    keyspace_history: Mutex<VecDeque<usize>>,
+    slowlog: Mutex<VecDeque<SlowEntry>>, // newest last
+    slow_commands: AtomicU64, // logged since startup, numbering the entries
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Stats {
+            latency: Mutex::new(BTreeMap::new()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            stale_reads: AtomicU64::new(0),
+            missing_reads: AtomicU64::new(0),
+            expired_keys: AtomicU64::new(0),
+            evicted_keys: AtomicU64::new(0),
+            timed_out_commands: AtomicU64::new(0),
+            cancelled_commands: AtomicU64::new(0),
+            evicted_clients: AtomicU64::new(0),
+            keyspace_history: Mutex::new(VecDeque::with_capacity(KEYSPACE_SAMPLES)),
+            slowlog: Mutex::new(VecDeque::new()),
+            slow_commands: AtomicU64::new(0),
+        }
+    }
+
+    // Count a read that found (or didn't find) its key
+    pub fn record_read(&self, hit: bool) {
+        let counter = if hit { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Fraction of reads that were hits, 0 before the first read
+    pub fn hit_ratio(&self) -> f64 {
+        let hits = self.hits.load(Ordering::Relaxed);
+        let total = hits + self.misses.load(Ordering::Relaxed);
+        if total == 0 { 0.0 } else { hits as f64 / total as f64 }
+    }
+
+    fn sample_keyspace(&self, keys: usize) {
+        let mut history = self.keyspace_history.lock().unwrap();
+        if history.len() == KEYSPACE_SAMPLES {
+            history.pop_front();
+        }
+        history.push_back(keys);
+    }
+
+    // Number of keys at each of the last samples, oldest first
+    pub fn keyspace_history(&self) -> Vec<usize> {
+        self.keyspace_history.lock().unwrap().iter().copied().collect()
+    }
+
+    // Record how long a command took to execute
+    pub fn record_latency(&self, command: &'static str, elapsed: Duration) {
+        let mut latency = self.latency.lock().unwrap();
+        let histogram = latency.entry(command).or_insert_with(|| {
+            Histogram::new(LATENCY_PRECISION).expect("valid histogram precision")
+        });
+        histogram.saturating_record(elapsed.as_micros() as u64);
+    }
+
+    // Log a slow command, keeping the newest `max_len`
+    pub fn record_slow(&self, mut entry: SlowEntry, max_len: usize) {
+        entry.id = self.slow_commands.fetch_add(1, Ordering::Relaxed) + 1;
+        let mut slowlog = self.slowlog.lock().unwrap();
+        while slowlog.len() >= max_len.max(1) {
+            slowlog.pop_front();
+        }
+        slowlog.push_back(entry);
+    }
+
+    // The slow commands logged, newest first
+    pub fn slowlog(&self) -> Vec<SlowEntry> {
+        self.slowlog.lock().unwrap().iter().rev().cloned().collect()
+    }
+
+    pub fn slow_commands(&self) -> u64 {
+        self.slow_commands.load(Ordering::Relaxed)
+    }
+
+    // Percentiles for every command type seen so far, by command name
+    pub fn latency_summaries(&self) -> Vec<LatencySummary> {
+        self.latency.lock().unwrap().iter()
+            .map(|(command, histogram)| LatencySummary {
+                command,
+                calls: histogram.len(),
+                p50: histogram.value_at_quantile(0.50),
+                p95: histogram.value_at_quantile(0.95),
+                p99: histogram.value_at_quantile(0.99),
+                max: histogram.max(),
+            })
+            .collect()
+    }
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Sample the number of keys once a minute for the keyspace history
+pub async fn run_keyspace_sampler(state: Arc<RwLock<ServerState>>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(60));
+    loop {
+        interval.tick().await;
+        let state = state.read().unwrap();
+        state.stats.sample_keyspace(state.cache.len());
+    }
+}
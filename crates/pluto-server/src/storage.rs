@@ -0,0 +1,81 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use log::error;
+use serde::{Deserialize, Serialize};
+use pluto_core::cache::Keyspace;
+use pluto_core::encryption::Keyring;
+use pluto_core::storage::MappedEngine;
+use crate::environment::FluxConfig;
+use crate::state::ServerState;
+use crate::wal::WalFsync;
+
+// Where the keyspace is kept
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Engine {
+    // In memory, surviving a restart through snapshots and the write-ahead log
+    #[default]
+    Memory,
+    // Every write also appended to segments in storage_dir, which are mapped
+    // back into memory on restart
+    Mmap,
+}
+
+// The keyspace the configured engine kept and whether it was written to
+// before, or None with the memory engine
+pub fn open(config: &FluxConfig, keys: &Keyring) -> io::Result<Option<(Keyspace, bool)>> {
+    match config.storage_engine {
+        Engine::Memory => Ok(None),
+        Engine::Mmap => {
+            if keys.is_enabled() {
+                return Err(io::Error::other("the mmap storage engine keeps values unencrypted, so it can't be used with an encryption key"));
+            }
+            let written = fs::read_dir(&config.storage_dir).is_ok_and(|mut items| items.next().is_some());
+            let (engine, entries) = MappedEngine::open(&config.storage_dir, config.storage_fsync == WalFsync::Always)?;
+            Ok(Some((Keyspace::with_engine(Box::new(engine), entries, config.layout()), written)))
+        }
+    }
+}
+
+// Drop what the storage engine kept, so the next start loads the snapshot
+// and log a restore put in place instead
+pub fn discard(config: &FluxConfig) -> io::Result<()> {
+    if config.storage_engine == Engine::Memory || !Path::new(&config.storage_dir).exists() {
+        return Ok(());
+    }
+    fs::remove_dir_all(&config.storage_dir)?;
+    println!("Removed {}, which held the data from before the restore", config.storage_dir);
+    Ok(())
+}
+
+// Sync the storage engine once a second when storage_fsync is "everysec"
+pub async fn run(state: Arc<RwLock<ServerState>>) {
+    let (config, shutdown) = {
+        let state = state.read().unwrap();
+        (state.config.clone(), state.shutdown.clone())
+    };
+    if config.storage_engine == Engine::Memory || config.storage_fsync != WalFsync::Everysec {
+        return;
+    }
+    let mut tick = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        tokio::select! {
+            _ = tick.tick() => {}
+            _ = shutdown.wait() => break,
+        }
+        if let Err(e) = sync(&state) {
+            error!("Failed to sync {}: {}", config.storage_dir, e);
+        }
+    }
+}
+
+pub fn sync(state: &Arc<RwLock<ServerState>>) -> io::Result<()> {
+    let file = state.read().unwrap().cache.storage().file_to_sync()?;
+    match file {
+        Some(file) => file.sync_data(),
+        None => Ok(()),
+    }
+}
@@ -0,0 +1,64 @@
+use crate::environment::FluxConfig;
+
+// Exports the `tracing` spans around connections and commands over OTLP.
+// Without the `otel` feature, or with `otel_enabled` off, spans stay local
+// no-ops and cost next to nothing.
+pub struct Telemetry {
+    #[cfg(feature = "otel")]
+    provider: opentelemetry_sdk::trace::SdkTracerProvider,
+}
+
+#[cfg(feature = "otel")]
+pub fn init(conf: &FluxConfig) -> Option<Telemetry> {
+    use opentelemetry::trace::TracerProvider as _;
+    use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+    use opentelemetry_sdk::Resource;
+    use opentelemetry_sdk::trace::SdkTracerProvider;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    if !conf.otel_enabled {
+        return None;
+    }
+    let exporter = match SpanExporter::builder()
+        .with_http()
+        .with_endpoint(conf.otel_endpoint.clone())
+        .build()
+    {
+        Ok(exporter) => exporter,
+        Err(e) => {
+            eprintln!("[WARN] Could not create OTLP exporter for {}: {e}", conf.otel_endpoint);
+            return None;
+        }
+    };
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(Resource::builder().with_service_name(conf.otel_service_name.clone()).build())
+        .build();
+    let tracer = provider.tracer("flux-cache");
+    let subscriber = tracing_subscriber::registry()
+        .with(tracing_opentelemetry::layer().with_tracer(tracer));
+    if let Err(e) = tracing::subscriber::set_global_default(subscriber) {
+        eprintln!("[WARN] Could not install the tracing subscriber: {e}");
+        return None;
+    }
+    log::info!("Exporting traces to {}", conf.otel_endpoint);
+    Some(Telemetry { provider })
+}
+
+#[cfg(not(feature = "otel"))]
+pub fn init(conf: &FluxConfig) -> Option<Telemetry> {
+    if conf.otel_enabled {
+        eprintln!("[WARN] otel_enabled is set but this build does not include the otel feature");
+    }
+    None
+}
+
+impl Telemetry {
+    // Flush spans that are still queued for export
+    pub fn shutdown(self) {
+        #[cfg(feature = "otel")]
+        if let Err(e) = self.provider.shutdown() {
+            eprintln!("[WARN] Could not flush traces: {e}");
+        }
+    }
+}
@@ -0,0 +1,176 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use tokio::sync::mpsc;
+
+// Client tracking: connections that turn it on with CLIENT_TRACKING have the
+// keys they read remembered, and are pushed an invalidation when one of them
+// changes, so that they can keep a copy of the values close to them. A key is
+// forgotten once its invalidation is sent, until the connection reads it again.
+
+// Invalidations a connection can have queued; past this it is told to drop
+// everything instead
+const TRACKING_QUEUE: usize = 1024;
+
+// Connections with tracking on and the keys they read
+pub struct Tracking {
+    next_id: AtomicU64,
+    active: AtomicUsize, // connections with tracking on; writes skip the table without any
+    tables: Mutex<Tables>,
+    max_keys: usize, // keys remembered before the oldest are invalidated; 0 means unlimited
+}
+
+#[derive(Default)]
+struct Tables {
+    keys: HashMap<String, HashSet<u64>>,
+    clients: HashMap<u64, Arc<Inbox>>,
+}
+
+// Where a tracking connection's invalidations are queued
+struct Inbox {
+    tx: mpsc::Sender<Vec<String>>,
+    overflowed: AtomicBool, // an invalidation was dropped; the connection must drop everything
+}
+
+impl Tracking {
+    pub fn new(max_keys: usize) -> Self {
+        Tracking {
+            next_id: AtomicU64::new(1),
+            active: AtomicUsize::new(0),
+            tables: Mutex::new(Tables::default()),
+            max_keys,
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active.load(Ordering::Relaxed) > 0
+    }
+
+    pub fn clients(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    // Keys some connection is waiting to hear about
+    pub fn keys(&self) -> usize {
+        self.tables.lock().unwrap().keys.len()
+    }
+
+    // Tell the connections that read `keys` that they changed
+    pub fn invalidate(&self, keys: &[&str]) {
+        if keys.is_empty() {
+            return;
+        }
+        let mut tables = self.tables.lock().unwrap();
+        let mut changed: HashMap<u64, Vec<String>> = HashMap::new();
+        for key in keys {
+            for id in tables.keys.remove(*key).into_iter().flatten() {
+                changed.entry(id).or_default().push(key.to_string());
+            }
+        }
+        for (id, keys) in changed {
+            if let Some(inbox) = tables.clients.get(&id) {
+                inbox.send(keys);
+            }
+        }
+    }
+
+    // Tell every tracking connection that all keys changed, as after FLUSHALL
+    pub fn invalidate_all(&self) {
+        if !self.is_active() {
+            return;
+        }
+        let mut tables = self.tables.lock().unwrap();
+        tables.keys.clear();
+        for inbox in tables.clients.values() {
+            inbox.send(Vec::new());
+        }
+    }
+
+    fn remember(&self, id: u64, keys: Vec<String>) {
+        let mut tables = self.tables.lock().unwrap();
+        for key in keys {
+            tables.keys.entry(key).or_default().insert(id);
+        }
+        // Make room by invalidating keys, which their readers must fetch again
+        while self.max_keys > 0 && tables.keys.len() > self.max_keys {
+            let Some(key) = tables.keys.keys().next().cloned() else {
+                break;
+            };
+            for id in tables.keys.remove(&key).into_iter().flatten() {
+                if let Some(inbox) = tables.clients.get(&id) {
+                    inbox.send(vec![key.clone()]);
+                }
+            }
+        }
+    }
+
+    fn add(&self, id: u64, inbox: Arc<Inbox>) {
+        self.tables.lock().unwrap().clients.insert(id, inbox);
+        self.active.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // The keys the connection read stay in the table until they change, when
+    // sending to it is skipped
+    fn remove(&self, id: u64) {
+        if self.tables.lock().unwrap().clients.remove(&id).is_some() {
+            self.active.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Inbox {
+    fn send(&self, keys: Vec<String>) {
+        if self.tx.try_send(keys).is_err() {
+            self.overflowed.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+// Tracking as one connection sees it
+pub struct Tracker {
+    id: u64,
+    tracking: Arc<Tracking>,
+    inbox: Arc<Inbox>,
+    rx: mpsc::Receiver<Vec<String>>,
+}
+
+impl Tracker {
+    pub fn start(tracking: Arc<Tracking>) -> Self {
+        let (tx, rx) = mpsc::channel(TRACKING_QUEUE);
+        let id = tracking.next_id.fetch_add(1, Ordering::Relaxed);
+        let inbox = Arc::new(Inbox { tx, overflowed: AtomicBool::new(false) });
+        tracking.add(id, inbox.clone());
+        Tracker { id, tracking, inbox, rx }
+    }
+
+    // Remember keys the connection read
+    pub fn remember(&self, keys: Vec<String>) {
+        if !keys.is_empty() {
+            self.tracking.remember(self.id, keys);
+        }
+    }
+
+    // Wait for the next invalidated keys; empty means every key
+    pub async fn recv(&mut self) -> Option<Vec<String>> {
+        if self.inbox.overflowed.swap(false, Ordering::Relaxed) {
+            while self.rx.try_recv().is_ok() {}
+            return Some(Vec::new());
+        }
+        self.rx.recv().await
+    }
+}
+
+impl Drop for Tracker {
+    fn drop(&mut self) {
+        self.tracking.remove(self.id);
+    }
+}
+
+// Wait for the next invalidation of a connection; never resolves on
+// connections without tracking
+pub async fn next_invalidation(tracker: &mut Option<Tracker>) -> Option<Vec<String>> {
+    match tracker {
+        Some(tracker) => tracker.recv().await,
+        None => std::future::pending().await,
+    }
+}
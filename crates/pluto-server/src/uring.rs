@@ -0,0 +1,204 @@
+use std::io;
+use std::net::Shutdown;
+use std::rc::Rc;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use bytes::BytesMut;
+use log::{debug, error};
+use socket2::SockRef;
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use tokio_uring::net::TcpStream;
+use crate::api::{Connection, serve_connection};
+use crate::batch::ResponseBatch;
+use crate::buffer::{READ_BUFFER_SIZE, SCRATCH_BUFFER_SIZE};
+use crate::network::{self, ListenerKind};
+use crate::state::ServerState;
+
+// The io_uring backend for the data port, with io_uring_threads set and the
+// io-uring feature built in. Each thread runs an io_uring runtime of its own
+// and accepts on every data listener, so the kernel spreads new connections
+// over the threads. Reads and writes of a connection are submitted to the
+// ring of its thread, which batches the syscalls of all its connections.
+// Connections are still accepted through tokio: tokio-uring can't take over
+// a listener bound with our TCP options.
+
+// Serve the data listeners on `threads` io_uring threads until shutdown
+pub async fn serve(listeners: Vec<std::net::TcpListener>, state: Arc<RwLock<ServerState>>, threads: usize) {
+    let mut workers = Vec::new();
+    for id in 0..threads {
+        let listeners: io::Result<Vec<_>> = listeners.iter().map(|listener| listener.try_clone()).collect();
+        let listeners = match listeners {
+            Ok(listeners) => listeners,
+            Err(e) => {
+                error!("Failed to share the data listeners with io_uring thread {}: {}", id, e);
+                continue;
+            }
+        };
+        let state = state.clone();
+        let worker = thread::Builder::new()
+            .name(format!("io-uring-{}", id))
+            .spawn(move || tokio_uring::start(run(listeners, state)));
+        match worker {
+            Ok(worker) => workers.push(worker),
+            Err(e) => error!("Failed to start io_uring thread {}: {}", id, e),
+        }
+    }
+    for worker in workers {
+        let _ = tokio::task::spawn_blocking(move || worker.join()).await;
+    }
+}
+
+// One io_uring thread: accept on every listener, then wait for the
+// connections to finish, as they do on shutdown
+async fn run(listeners: Vec<std::net::TcpListener>, state: Arc<RwLock<ServerState>>) {
+    let mut accepting = JoinSet::new();
+    for listener in listeners {
+        accepting.spawn_local(accept(listener, state.clone()));
+    }
+    while accepting.join_next().await.is_some() {}
+}
+
+async fn accept(listener: std::net::TcpListener, state: Arc<RwLock<ServerState>>) {
+    let listener = match tokio::net::TcpListener::from_std(listener) {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Failed to accept on the io_uring backend: {}", e);
+            return;
+        }
+    };
+    let (clients, conf, shutdown, health) = {
+        let state = state.read().unwrap();
+        (state.clients.clone(), state.config.clone(), state.shutdown.clone(), state.health.clone())
+    };
+
+    health.listener_started();
+    let mut connections = JoinSet::new();
+    loop {
+        let (socket, addr) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    eprintln!("Failed to accept connection: {}", e);
+                    continue;
+                }
+            },
+            _ = shutdown.wait() => {
+                debug!("Accept loop on {:?} stopping for shutdown", listener.local_addr());
+                break;
+            }
+        };
+        debug!("Accepted connection from: {} (active: {})", addr, clients.len());
+        if let Err(e) = network::tune_client_socket(&SockRef::from(&socket), &conf) {
+            debug!("Failed to apply TCP options for {}: {}", addr, e);
+        }
+        // io_uring waits for the socket itself, which it does for blocking ones
+        let socket = match socket.into_std().and_then(|socket| socket.set_nonblocking(false).map(|_| socket)) {
+            Ok(socket) => TcpStream::from_std(socket),
+            Err(e) => {
+                debug!("Failed to hand {} over to io_uring: {}", addr, e);
+                continue;
+            }
+        };
+        let state = state.clone();
+        connections.spawn_local(async move {
+            serve_connection(UringConnection::new(socket), addr, state, ListenerKind::Data).await;
+            debug!("Client handler task completed for {}", addr);
+        });
+        while connections.try_join_next().is_some() {}
+    }
+    health.listener_stopped();
+    while connections.join_next().await.is_some() {}
+}
+
+// A connection whose socket is read and written through io_uring. An
+// io_uring read can't be dropped halfway without losing what it read, so a
+// task of its own reads the socket, one chunk ahead of the connection loop.
+struct UringConnection {
+    socket: Rc<TcpStream>,
+    input: mpsc::Receiver<io::Result<Vec<u8>>>, // chunks read, empty once the client hung up
+    unread: Option<io::Result<Vec<u8>>>, // a chunk taken off `input` while watching for a hang-up
+}
+
+impl UringConnection {
+    fn new(socket: TcpStream) -> Self {
+        let socket = Rc::new(socket);
+        let (sender, input) = mpsc::channel(1);
+        let reader = socket.clone();
+        tokio_uring::spawn(async move {
+            let mut buf = Vec::with_capacity(READ_BUFFER_SIZE);
+            loop {
+                let (result, mut read) = reader.read(buf).await;
+                let chunk = result.map(|n| read[..n].to_vec());
+                let done = !matches!(&chunk, Ok(chunk) if !chunk.is_empty());
+                if sender.send(chunk).await.is_err() || done {
+                    break;
+                }
+                // Reads that fill the buffer grow it, as a large command
+                // would take many small ones; a short read shrinks it back
+                let size = if read.len() == read.capacity() {
+                    (read.capacity() * 2).min(SCRATCH_BUFFER_SIZE)
+                } else {
+                    READ_BUFFER_SIZE
+                };
+                if size != read.capacity() {
+                    read = Vec::with_capacity(size);
+                }
+                read.clear();
+                buf = read;
+            }
+        });
+        UringConnection { socket, input, unread: None }
+    }
+}
+
+impl Connection for UringConnection {
+    async fn read_buf(&mut self, buf: &mut BytesMut) -> io::Result<usize> {
+        let chunk = match self.unread.take() {
+            Some(chunk) => chunk,
+            None => self.input.recv().await.unwrap_or(Ok(Vec::new())),
+        }?;
+        buf.extend_from_slice(&chunk);
+        Ok(chunk.len())
+    }
+
+    async fn write_batch(&mut self, batch: &mut ResponseBatch) -> io::Result<()> {
+        loop {
+            let chunks = batch.next_chunks();
+            if chunks.is_empty() {
+                return Ok(());
+            }
+            let (written, _) = self.socket.writev(chunks).await;
+            batch.consume(written?)?;
+        }
+    }
+
+    async fn closed(&mut self) {
+        if self.unread.is_none() {
+            self.unread = self.input.recv().await;
+        }
+        // More input, or the EOF of a half-close, leaves the client waiting
+        if let Some(Ok(_)) = &self.unread {
+            std::future::pending().await
+        }
+    }
+}
+
+impl Drop for UringConnection {
+    // Wakes the reader task out of its read, letting the socket close
+    fn drop(&mut self) {
+        let _ = self.socket.shutdown(Shutdown::Both);
+    }
+}
+
+// The data listeners out of `listeners`, as std listeners for the io_uring
+// threads to share, leaving the rest to tokio
+pub fn take_data_listeners(
+    listeners: &mut Vec<(tokio::net::TcpListener, ListenerKind)>,
+) -> io::Result<Vec<std::net::TcpListener>> {
+    let (data, rest): (Vec<_>, Vec<_>) = std::mem::take(listeners).into_iter()
+        .partition(|(_, kind)| *kind == ListenerKind::Data);
+    *listeners = rest;
+    data.into_iter().map(|(listener, _)| listener.into_std()).collect()
+}
+
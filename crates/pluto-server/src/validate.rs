@@ -0,0 +1,99 @@
+use std::collections::HashSet;
+use pluto_core::cache::ServerError;
+use pluto_core::cluster::TOTAL_SLOTS;
+use pluto_core::protocol::Command;
+use crate::environment::FluxConfig;
+use crate::middleware::{Call, Middleware};
+
+// Checks a command's arguments before it runs: sizes against the configured
+// limits, lists that may not be empty and values that only make sense in a
+// range. Deserializing a command already checked its shape; what passes here
+// is something the handlers can run as given.
+
+pub struct Validate {
+    max_key_bytes: usize, // 0 means unlimited
+    max_value_bytes: usize, // 0 means unlimited
+}
+
+impl Validate {
+    pub fn new(config: &FluxConfig) -> Self {
+        Validate { max_key_bytes: config.max_key_bytes, max_value_bytes: config.max_value_bytes }
+    }
+
+    fn check(&self, cmd: &Command) -> Result<(), String> {
+        for key in cmd.keys() {
+            if self.max_key_bytes > 0 && key.len() > self.max_key_bytes {
+                return Err(format!("Key of {} bytes is longer than max_key_bytes ({})", key.len(), self.max_key_bytes));
+            }
+        }
+        match cmd {
+            Command::SET { value, .. } | Command::GETSET { value, .. } => self.value(value.len()),
+            Command::RESTORE { payload, .. } => self.value(payload.len()),
+            Command::PUBLISH { message, .. } => self.value(message.len()),
+            Command::MSETNX { entries } => {
+                non_empty("entries", entries)?;
+                let mut seen = HashSet::new();
+                for (key, value) in entries {
+                    if !seen.insert(key) {
+                        return Err(format!("{} is set twice", key));
+                    }
+                    self.value(value.len())?;
+                }
+                Ok(())
+            }
+            Command::DEL { keys } | Command::UNLINK { keys } | Command::EXISTS_MANY { keys } | Command::REPAIR { keys } => {
+                non_empty("keys", keys)
+            }
+            Command::ORSET_ADD { members, .. } | Command::ORSET_REM { members, .. } => non_empty("members", members),
+            Command::SUBSCRIBE { channels } => non_empty("channels", channels),
+            Command::KEYEVENTS_SUBSCRIBE { patterns } => non_empty("patterns", patterns),
+            Command::EXPIRE { jitter_pct: Some(pct), .. } | Command::PEXPIRE { jitter_pct: Some(pct), .. } if *pct > 100 => {
+                Err(format!("jitter_pct must be at most 100, got {}", pct))
+            }
+            Command::SET_MISSING { milliseconds: 0, .. } => Err("milliseconds must be positive".to_string()),
+            Command::GET_OR_LOCK { lock_ms: 0, .. } => Err("lock_ms must be positive".to_string()),
+            Command::KEYS { chunk: Some(0), .. } | Command::CLUSTER_KEYS { chunk: Some(0), .. } => {
+                Err("chunk must be positive".to_string())
+            }
+            Command::MIGRATE_SLOTS { slots, .. } | Command::CLUSTER_SETSLOT { slots, .. } => {
+                non_empty("slots", slots)?;
+                slots.iter().try_for_each(|slot| in_range(*slot))
+            }
+            Command::DIGEST { slot } => in_range(*slot),
+            Command::QUORUM { replicas: 0, .. } => Err("replicas must be positive".to_string()),
+            Command::QUORUM { command, .. } => match **command {
+                Command::QUORUM { .. } => Err("QUORUM can't be nested".to_string()),
+                ref command => self.check(command),
+            },
+            _ => Ok(()),
+        }
+    }
+
+    fn value(&self, len: usize) -> Result<(), String> {
+        if self.max_value_bytes > 0 && len > self.max_value_bytes {
+            return Err(format!("Value of {} bytes is larger than max_value_bytes ({})", len, self.max_value_bytes));
+        }
+        Ok(())
+    }
+}
+
+impl Middleware for Validate {
+    fn before(&self, call: &Call) -> Result<(), ServerError> {
+        self.check(call.command)
+            .map_err(|reason| ServerError::InvalidCommand(format!("{}: {}", call.command.name(), reason)))
+    }
+}
+
+fn non_empty<T>(name: &str, list: &[T]) -> Result<(), String> {
+    match list.is_empty() {
+        true => Err(format!("{} may not be empty", name)),
+        false => Ok(()),
+    }
+}
+
+fn in_range(slot: usize) -> Result<(), String> {
+    match slot < TOTAL_SLOTS {
+        true => Ok(()),
+        false => Err(format!("Slot {} out of range", slot)),
+    }
+}
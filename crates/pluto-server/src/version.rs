@@ -0,0 +1,43 @@
+use pluto_core::codec::PROTOCOL_VERSION;
+use crate::allocator;
+
+// What this binary is and was built from, for INFO server and VERSION, so a
+// bug report or a fleet audit can tell exactly what runs
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+pub const GIT_SHA: &str = env!("PLUTO_GIT_SHA"); // ends in -dirty when built with uncommitted changes
+pub const BUILD_DATE: &str = env!("PLUTO_BUILD_DATE");
+
+// Cargo features the server was built with
+pub fn features() -> Vec<&'static str> {
+    let mut features = Vec::new();
+    if cfg!(feature = "grpc") {
+        features.push("grpc");
+    }
+    if cfg!(feature = "jemalloc") {
+        features.push("jemalloc");
+    }
+    if cfg!(feature = "mimalloc") {
+        features.push("mimalloc");
+    }
+    if cfg!(all(feature = "io-uring", target_os = "linux")) {
+        features.push("io-uring");
+    }
+    if cfg!(feature = "otel") {
+        features.push("otel");
+    }
+    features
+}
+
+pub fn build_lines() -> Vec<(&'static str, String)> {
+    vec![
+        ("version", VERSION.to_string()),
+        ("git_sha", GIT_SHA.to_string()),
+        ("build_date", BUILD_DATE.to_string()),
+        ("features", features().join(",")),
+        ("allocator", allocator::NAME.to_string()),
+        ("protocol_version", PROTOCOL_VERSION.to_string()),
+        ("os", std::env::consts::OS.to_string()),
+        ("arch", std::env::consts::ARCH.to_string()),
+    ]
+}
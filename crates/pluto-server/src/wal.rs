@@ -0,0 +1,677 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use pluto_core::cache::{ServerError, WriteStamp, dump_entry, now_ms, restore_entry};
+use pluto_core::persistence::save_snapshot;
+use pluto_core::protocol::Command;
+use pluto_core::encryption::Keyring;
+use pluto_core::wal::{WalRecord, encode_record, list_segments, read_segment, segment_header, segment_name};
+use crate::api::stamped_write;
+use crate::backup::ObjectStore;
+use crate::environment::FluxConfig;
+use crate::state::ServerState;
+
+// Every write streamed to the replicas is also appended to the current
+// segment in `wal_dir`. Full segments are copied to `wal_archive_dir` and/or
+// handed to `wal_archive_command`, and dropped locally past
+// `wal_keep_segments` once archived. At startup the writes made after the
+// snapshot are replayed from the log, so a crash loses no more than the fsync
+// policy allows. A node given `wal_restore_from` replays that archive first,
+// bootstrapping it from another node's history.
+
+// When the log is synced to disk
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum WalFsync {
+    // After every write, before it is acknowledged. Writes made while
+    // another is being synced share the next fsync (group commit).
+    Always,
+    // Once a second, losing at most a second of writes in a crash
+    #[default]
+    Everysec,
+    // Whenever the OS flushes it
+    No,
+}
+
+// How often the log is flushed and full segments are archived
+const TICK: Duration = Duration::from_secs(1);
+
+// Holds an empty file per archived segment
+const ARCHIVED_DIR: &str = "archived";
+
+// Holds the position of the last write in the snapshot and the time it was
+// saved at
+const CHECKPOINT_FILE: &str = "checkpoint";
+
+// Holds the time in unix milliseconds the next replay stops at
+const RECOVERY_TARGET_FILE: &str = "recovery_target";
+
+struct Segment {
+    file: BufWriter<File>,
+    first: u64, // position of its first write
+    len: u64,
+}
+
+// The log being written, with the progress of archiving for INFO wal
+pub struct Wal {
+    dir: PathBuf,
+    segment_bytes: u64,
+    pub fsync: WalFsync,
+    segment: Mutex<Segment>,
+    position: AtomicU64, // of the last write appended
+    pub replayed: u64,   // writes replayed at startup
+    pub archived: AtomicU64,
+    pub archive_failures: AtomicU64,
+    pub last_archived: Mutex<String>,
+    keys: Keyring, // new segments are encrypted with
+    synced: AtomicU64, // position of the last write known to be on disk
+    syncing: tokio::sync::Mutex<()>, // held by the writer syncing for the others
+    fsync_window: Duration, // how long a sync waits for more writes to join it
+    pub group_commits: AtomicU64, // syncs writers waited on
+    pub group_committed: AtomicU64, // writes those syncs made durable
+    pub write_errors: AtomicU64, // appends and flushes that failed
+    pub last_error: Mutex<String>, // of the last failure, empty once a flush succeeds after it
+    pub last_synced_at: AtomicU64, // unix time in milliseconds of the last sync, 0 before
+}
+
+impl Wal {
+    // Start a new segment after the last write replayed at `position`
+    pub fn open(config: &FluxConfig, position: u64, replayed: u64, keys: Keyring) -> io::Result<Self> {
+        let dir = PathBuf::from(&config.wal_dir);
+        fs::create_dir_all(dir.join(ARCHIVED_DIR))?;
+        Ok(Wal {
+            segment: Mutex::new(start_segment(&dir, position + 1, &keys)?),
+            dir,
+            segment_bytes: config.wal_segment_bytes.max(1),
+            fsync: config.wal_fsync,
+            position: AtomicU64::new(position),
+            replayed,
+            archived: AtomicU64::new(0),
+            archive_failures: AtomicU64::new(0),
+            last_archived: Mutex::new(String::new()),
+            keys,
+            synced: AtomicU64::new(position),
+            syncing: tokio::sync::Mutex::new(()),
+            fsync_window: Duration::from_micros(config.wal_fsync_window_us),
+            group_commits: AtomicU64::new(0),
+            group_committed: AtomicU64::new(0),
+            write_errors: AtomicU64::new(0),
+            last_error: Mutex::new(String::new()),
+            last_synced_at: AtomicU64::new(0),
+        })
+    }
+
+    pub fn position(&self) -> u64 {
+        self.position.load(Ordering::Relaxed)
+    }
+
+    // Name and size of the segment being written
+    pub fn current_segment(&self) -> (String, u64) {
+        let segment = self.segment.lock().unwrap();
+        (segment_name(segment.first), segment.len)
+    }
+
+    // Record a write. Called with the state's write lock held, so the log
+    // has writes in the order they were applied. With `always` the write is
+    // synced by `sync_to`, once the lock is released.
+    pub fn append(&self, command: &Command, stamp: Option<WriteStamp>, local_only: bool) {
+        let mut segment = self.segment.lock().unwrap();
+        let position = self.position() + 1;
+        let written = encode_record(position, now_ms(), command, stamp, local_only, &self.keys).and_then(|record| {
+            segment.file.write_all(&record)?;
+            segment.len += record.len() as u64;
+            Ok(())
+        });
+        if let Err(e) = written {
+            error!("Failed to append to the write-ahead log: {}", e);
+            self.failed(&e);
+            return;
+        }
+        self.position.store(position, Ordering::Relaxed);
+        if segment.len >= self.segment_bytes {
+            match finish_segment(&mut segment).and_then(|_| start_segment(&self.dir, position + 1, &self.keys)) {
+                Ok(next) => {
+                    *segment = next;
+                    self.synced.fetch_max(position, Ordering::Relaxed);
+                }
+                Err(e) => {
+                    error!("Failed to start a new write-ahead log segment: {}", e);
+                    self.failed(&e);
+                }
+            }
+        }
+    }
+
+    // Write out buffered records, syncing them unless the OS is left to
+    pub fn flush(&self) -> io::Result<()> {
+        let result = (|| {
+            let (file, position) = {
+                let mut segment = self.segment.lock().unwrap();
+                segment.file.flush()?;
+                (segment.file.get_ref().try_clone()?, self.position())
+            };
+            if self.fsync != WalFsync::No {
+                file.sync_data()?;
+                self.synced.fetch_max(position, Ordering::Relaxed);
+                self.last_synced_at.store(now_ms(), Ordering::Relaxed);
+            }
+            Ok(())
+        })();
+        match &result {
+            Ok(()) => self.last_error.lock().unwrap().clear(),
+            Err(e) => self.failed(e),
+        }
+        result
+    }
+
+    fn failed(&self, e: &io::Error) {
+        self.write_errors.fetch_add(1, Ordering::Relaxed);
+        *self.last_error.lock().unwrap() = e.to_string();
+    }
+
+    // Wait until the writes up to `position` are on disk. One writer syncs
+    // at a time; the others wait for it, then find their writes synced
+    // along with its own or sync for all of those that waited with them.
+    pub async fn sync_to(self: &Arc<Self>, position: u64) -> io::Result<()> {
+        if self.synced.load(Ordering::Relaxed) >= position {
+            return Ok(());
+        }
+        let _syncing = self.syncing.lock().await;
+        let before = self.synced.load(Ordering::Relaxed);
+        if before >= position {
+            return Ok(());
+        }
+        let wal = self.clone();
+        tokio::task::spawn_blocking(move || {
+            std::thread::sleep(wal.fsync_window);
+            wal.flush()
+        })
+        .await
+        .map_err(io::Error::other)??;
+        self.group_commits.fetch_add(1, Ordering::Relaxed);
+        self.group_committed.fetch_add(self.synced.load(Ordering::Relaxed).saturating_sub(before), Ordering::Relaxed);
+        Ok(())
+    }
+
+    // Flush the log on shutdown
+    pub fn close(&self) -> io::Result<()> {
+        finish_segment(&mut self.segment.lock().unwrap())
+    }
+
+    // Record that the snapshot just saved holds every write up to
+    // `position`, made until `at`
+    pub fn checkpoint(&self, position: u64, at: u64) -> io::Result<()> {
+        write_checkpoint(&self.dir, position, at)
+    }
+}
+
+fn start_segment(dir: &Path, first: u64, keys: &Keyring) -> io::Result<Segment> {
+    // A segment by that name can only hold a torn first write
+    let mut file = BufWriter::new(File::create(dir.join(segment_name(first)))?);
+    let header = segment_header(keys);
+    file.write_all(&header)?;
+    Ok(Segment { file, first, len: header.len() as u64 })
+}
+
+fn finish_segment(segment: &mut Segment) -> io::Result<()> {
+    segment.file.flush()?;
+    segment.file.get_ref().sync_all()
+}
+
+// Record that the snapshot holds the writes up to `position`, made until `at`
+pub fn write_checkpoint(dir: &Path, position: u64, at: u64) -> io::Result<()> {
+    let path = dir.join(CHECKPOINT_FILE);
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, format!("{} {}", position, at))?;
+    fs::rename(&tmp_path, &path)
+}
+
+// Position and time of the checkpoint; the time is 0 when it isn't known
+pub(crate) fn read_checkpoint(dir: &Path) -> (u64, u64) {
+    let text = fs::read_to_string(dir.join(CHECKPOINT_FILE)).unwrap_or_default();
+    let mut fields = text.split_whitespace().map(|field| field.parse().unwrap_or(0));
+    (fields.next().unwrap_or(0), fields.next().unwrap_or(0))
+}
+
+// Have the next replay stop after the last write made at or before `at`
+pub fn set_recovery_target(dir: &Path, at: u64) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    fs::write(dir.join(RECOVERY_TARGET_FILE), at.to_string())
+}
+
+pub(crate) fn read_recovery_target(dir: &Path) -> Option<u64> {
+    fs::read_to_string(dir.join(RECOVERY_TARGET_FILE)).ok()?.trim().parse().ok()
+}
+
+// Segments of `wal_restore_from`, the archive and `wal_dir`, the local copy winning
+fn all_segments(config: &FluxConfig) -> io::Result<BTreeMap<u64, PathBuf>> {
+    let mut segments = BTreeMap::new();
+    for dir in [&config.wal_restore_from, &config.wal_archive_dir, &config.wal_dir] {
+        if !dir.is_empty() && Path::new(dir).is_dir() {
+            segments.extend(list_segments(Path::new(dir))?);
+        }
+    }
+    Ok(segments)
+}
+
+// Prepare restoring the local data to how it was at `target` on the next
+// start. The snapshot is kept if it was saved before then, and set aside
+// otherwise, replaying the log from its first write.
+pub fn prepare_recovery(config: &FluxConfig, target: u64) -> io::Result<()> {
+    if !config.wal_enabled && config.wal_restore_from.is_empty() {
+        return Err(io::Error::other("wal_enabled is off, so there is no log to recover with"));
+    }
+    let dir = Path::new(&config.wal_dir);
+    let snapshot = Path::new(&config.snapshot_file);
+    let (position, saved_at) = read_checkpoint(dir);
+    let keep_snapshot = snapshot.exists() && saved_at != 0 && saved_at <= target;
+    let from = if keep_snapshot { position } else { 0 };
+    let segments = all_segments(config)?;
+    if let Some(first) = segments.keys().next()
+        && *first > from + 1 {
+        return Err(io::Error::other(format!(
+            "The write-ahead log starts at position {}, but recovering to that time needs it from {}",
+            first, from + 1
+        )));
+    }
+    if snapshot.exists() && !keep_snapshot {
+        let aside = format!("{}.before-restore", config.snapshot_file);
+        fs::rename(snapshot, &aside)?;
+        println!("Moved {} to {}, as it was saved after that time", config.snapshot_file, aside);
+    }
+    set_recovery_target(dir, target)?;
+    println!("The next start replays the write-ahead log from position {} up to that time", from + 1);
+    Ok(())
+}
+
+// Replay the log into a keyspace just loaded from the snapshot, returning the
+// position of the last write and the number of writes replayed. The writes
+// the snapshot already holds are skipped.
+//
+// With a recovery target set, replay stops at the first write made after it.
+// The writes from there on are abandoned: the keyspace is saved as the
+// snapshot, checkpointed past them, and the log goes on after them.
+pub fn replay(state: &mut ServerState, snapshot_loaded: bool) -> io::Result<(u64, u64)> {
+    let config = state.config.clone();
+    let local = PathBuf::from(&config.wal_dir);
+    let mut position = if snapshot_loaded { read_checkpoint(&local).0 } else { 0 };
+    let target = read_recovery_target(&local);
+    let segments = all_segments(&config)?;
+    let firsts: Vec<u64> = segments.keys().copied().collect();
+    let mut replayed = 0;
+    'segments: for (i, (first, path)) in segments.iter().enumerate() {
+        // Skip segments the snapshot holds all of without reading them
+        if firsts.get(i + 1).is_some_and(|next| next - 1 <= position) {
+            continue;
+        }
+        if *first > position + 1 {
+            warn!("Write-ahead log has no record of writes {} to {}", position + 1, first - 1);
+        }
+        let (records, torn) = read_segment(path, &state.keys)?;
+        for record in records {
+            if record.position <= position {
+                continue;
+            }
+            if target.is_some_and(|target| record.at > target) {
+                break 'segments;
+            }
+            position = record.position;
+            let (stamp, local_only) = (record.stamp, record.local_only);
+            if let Err(e) = stamped_write(state, replayed_command(record), stamp, local_only) {
+                debug!("Replayed write at position {} failed: {}", position, e);
+            }
+            replayed += 1;
+        }
+        if torn {
+            warn!("{} ends in a torn write; replayed up to position {}", path.display(), position);
+        }
+    }
+    if replayed > 0 {
+        info!("Replayed {} writes from the write-ahead log, up to position {}", replayed, position);
+    }
+    if let Some(target) = target {
+        let last = match segments.values().next_back() {
+            Some(path) => read_segment(path, &state.keys)?.0.last().map_or(0, |record| record.position),
+            None => 0,
+        };
+        let abandoned = last.saturating_sub(position);
+        position = position.max(last);
+        fs::create_dir_all(&local)?;
+        save_snapshot(&state.cache.view(), &config.snapshot_file, &state.keys)?;
+        write_checkpoint(&local, position, target)?;
+        fs::remove_file(local.join(RECOVERY_TARGET_FILE))?;
+        let time = chrono::DateTime::from_timestamp_millis(target as i64).unwrap_or_default();
+        info!("Recovered to {}, abandoning the {} writes made after it", time.to_rfc3339(), abandoned);
+    }
+    // Never reuse the name of a segment that is still there
+    position = position.max(segments.keys().next_back().map_or(0, |last| last - 1));
+    Ok((position, replayed))
+}
+
+// Position of the last write in the log, for a node whose keyspace already
+// holds every write in it
+pub fn last_position(state: &ServerState) -> io::Result<u64> {
+    let segments = all_segments(&state.config)?;
+    let Some((first, path)) = segments.iter().next_back() else {
+        return Ok(0);
+    };
+    let last = read_segment(path, &state.keys)?.0.last().map_or(0, |record| record.position);
+    Ok(last.max(first - 1))
+}
+
+// A recorded write as it applies now: expiries stay as far from the time of
+// the write as they were then
+fn replayed_command(record: WalRecord) -> Command {
+    let now = now_ms();
+    match record.command {
+        Command::EXPIRE { key, seconds, .. } => {
+            let remaining = (record.at + seconds.saturating_mul(1000)).saturating_sub(now);
+            if remaining == 0 {
+                Command::DEL { keys: vec![key] }
+            } else {
+                Command::EXPIRE { key, seconds: remaining.div_ceil(1000), jitter_pct: None }
+            }
+        }
+        Command::PEXPIRE { key, milliseconds, .. } => {
+            let remaining = (record.at + milliseconds).saturating_sub(now);
+            if remaining == 0 {
+                Command::DEL { keys: vec![key] }
+            } else {
+                Command::PEXPIRE { key, milliseconds: remaining, jitter_pct: None }
+            }
+        }
+        Command::SET_MISSING { key, milliseconds } => {
+            let remaining = (record.at + milliseconds).saturating_sub(now);
+            if remaining == 0 {
+                Command::DEL { keys: vec![key] }
+            } else {
+                Command::SET_MISSING { key, milliseconds: remaining }
+            }
+        }
+        Command::SOFT_EXPIRE { key, seconds } => {
+            let remaining = (record.at + seconds.saturating_mul(1000)).saturating_sub(now);
+            Command::SOFT_EXPIRE { key, seconds: remaining.div_ceil(1000) }
+        }
+        Command::RESTORE { key, payload, replace } => match restore_entry(&payload, record.at) {
+            Ok(entry) if entry.is_expired(now) => Command::DEL { keys: vec![key] },
+            Ok(entry) => Command::RESTORE { key, payload: dump_entry(&entry, now).to_vec(), replace },
+            Err(_) => Command::RESTORE { key, payload, replace },
+        },
+        command => command,
+    }
+}
+
+// Flush the log and archive full segments until shutdown
+pub async fn run(state: Arc<RwLock<ServerState>>) {
+    let (wal, config, shutdown) = {
+        let state = state.read().unwrap();
+        (state.replication.wal(), state.config.clone(), state.shutdown.clone())
+    };
+    let Some(wal) = wal else {
+        return;
+    };
+    let mut tick = tokio::time::interval(TICK);
+    loop {
+        tokio::select! {
+            _ = tick.tick() => {}
+            _ = shutdown.wait() => break,
+        }
+        // With `always` this syncs the writes no client waits for, as those
+        // a replica or an expiry makes
+        if let Err(e) = wal.flush() {
+            error!("Failed to flush the write-ahead log: {}", e);
+        }
+        if let Err(e) = archive_full_segments(&wal, &config).await {
+            warn!("Archiving write-ahead log segments failed: {}", e);
+        }
+    }
+}
+
+// Whether full segments are copied anywhere before they are dropped
+pub fn archiving(config: &FluxConfig) -> bool {
+    !config.wal_archive_dir.is_empty()
+        || !config.wal_archive_command.is_empty()
+        || (!config.backup_url.is_empty() && config.backup_wal)
+}
+
+// Archive the segments no longer written to, oldest first, then drop the
+// local copies past `wal_keep_segments`
+async fn archive_full_segments(wal: &Wal, config: &FluxConfig) -> Result<(), ServerError> {
+    let current = wal.segment.lock().unwrap().first;
+    let full: Vec<(u64, PathBuf)> = list_segments(&wal.dir)?.into_iter().filter(|(first, _)| *first < current).collect();
+    let archiving = archiving(config);
+    let archived_dir = wal.dir.join(ARCHIVED_DIR);
+    if archiving {
+        for (first, path) in &full {
+            let name = segment_name(*first);
+            let marker = archived_dir.join(&name);
+            if marker.exists() {
+                continue;
+            }
+            if let Err(e) = archive_segment(path, &name, config).await {
+                wal.archive_failures.fetch_add(1, Ordering::Relaxed);
+                // Later segments wait, so the archive never has gaps
+                return Err(e);
+            }
+            fs::write(&marker, b"")?;
+            wal.archived.fetch_add(1, Ordering::Relaxed);
+            *wal.last_archived.lock().unwrap() = name.clone();
+            info!("Archived write-ahead log segment {}", name);
+        }
+    }
+
+    if config.wal_keep_segments == 0 || full.len() <= config.wal_keep_segments {
+        return Ok(());
+    }
+    // Without an archive, a segment goes once the snapshot holds all its writes
+    let (checkpoint, _) = read_checkpoint(&wal.dir);
+    let droppable = full.len() - config.wal_keep_segments;
+    for (i, (first, path)) in full.iter().take(droppable).enumerate() {
+        let name = segment_name(*first);
+        let marker = archived_dir.join(&name);
+        let covered = full.get(i + 1).map_or(current, |(next, _)| *next) - 1 <= checkpoint;
+        if (archiving && marker.exists()) || (!archiving && covered) {
+            fs::remove_file(path)?;
+            let _ = fs::remove_file(&marker);
+        }
+    }
+    Ok(())
+}
+
+// Copy a segment into the archive directory, run the archive command on it
+// and upload it to the backup bucket
+async fn archive_segment(path: &Path, name: &str, config: &FluxConfig) -> Result<(), ServerError> {
+    if !config.wal_archive_dir.is_empty() {
+        let dir = Path::new(&config.wal_archive_dir);
+        tokio::fs::create_dir_all(dir).await?;
+        let target = dir.join(name);
+        let tmp_path = target.with_extension("tmp");
+        tokio::fs::copy(path, &tmp_path).await?;
+        tokio::fs::File::open(&tmp_path).await?.sync_all().await?;
+        tokio::fs::rename(&tmp_path, &target).await?;
+    }
+    if !config.wal_archive_command.is_empty() {
+        // PLUTO_WAL_SEGMENT is the segment's path and PLUTO_WAL_NAME the name
+        // to archive it under
+        let status = tokio::process::Command::new("sh")
+            .arg("-c")
+            .arg(&config.wal_archive_command)
+            .env("PLUTO_WAL_SEGMENT", path)
+            .env("PLUTO_WAL_NAME", name)
+            .status()
+            .await?;
+        if !status.success() {
+            return Err(ServerError::InvalidArgument(format!("Archive command exited with {} for {}", status, name)));
+        }
+    }
+    if !config.backup_url.is_empty() && config.backup_wal {
+        let store = ObjectStore::new(config, &config.backup_url)?;
+        store.put(&format!("wal/{}", name), tokio::fs::read(path).await?).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A log directory in the temp directory, removed once dropped
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("pluto-wal-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            TempDir(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn open(dir: &TempDir, window_us: u64, segment_bytes: u64) -> Arc<Wal> {
+        let config = FluxConfig {
+            wal_dir: dir.0.to_string_lossy().into_owned(),
+            wal_fsync: WalFsync::Always,
+            wal_fsync_window_us: window_us,
+            wal_segment_bytes: segment_bytes,
+            ..Default::default()
+        };
+        Arc::new(Wal::open(&config, 0, 0, Keyring::default()).unwrap())
+    }
+
+    fn set(key: &str) -> Command {
+        Command::SET { key: key.to_string(), value: b"value".to_vec(), visible_at: None }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn writers_waiting_together_share_a_sync() {
+        let dir = TempDir::new("group");
+        let wal = open(&dir, 50_000, 1 << 20);
+        let mut waits = tokio::task::JoinSet::new();
+        for i in 0..16 {
+            wal.append(&set(&format!("key{}", i)), None, false);
+            let (wal, position) = (wal.clone(), wal.position());
+            waits.spawn(async move { wal.sync_to(position).await });
+        }
+        for result in waits.join_all().await {
+            result.unwrap();
+        }
+        let commits = wal.group_commits.load(Ordering::Relaxed);
+        assert!((1..16).contains(&commits), "{} syncs for 16 writes", commits);
+        assert_eq!(wal.group_committed.load(Ordering::Relaxed), 16);
+        assert!(wal.last_synced_at.load(Ordering::Relaxed) > 0);
+
+        // The synced writes are on disk, in the order they were appended
+        let (_, path) = list_segments(&dir.0).unwrap().pop().unwrap();
+        let (records, torn) = read_segment(&path, &Keyring::default()).unwrap();
+        assert!(!torn);
+        assert_eq!(records.iter().map(|record| record.position).collect::<Vec<_>>(), (1..=16).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn writes_already_synced_dont_sync_again() {
+        let dir = TempDir::new("synced");
+        let wal = open(&dir, 0, 1 << 20);
+        wal.append(&set("a"), None, false);
+        wal.sync_to(wal.position()).await.unwrap();
+        wal.sync_to(wal.position()).await.unwrap();
+        assert_eq!(wal.group_commits.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn a_finished_segment_counts_as_synced() {
+        let dir = TempDir::new("rolled");
+        // Every record fills a segment, which is synced as it is finished
+        let wal = open(&dir, 0, 1);
+        wal.append(&set("a"), None, false);
+        wal.append(&set("b"), None, false);
+        wal.sync_to(wal.position()).await.unwrap();
+        assert_eq!(wal.group_commits.load(Ordering::Relaxed), 0);
+        assert_eq!(list_segments(&dir.0).unwrap().len(), 3);
+    }
+
+    // Config for recovering the snapshot and log kept in `dir`
+    fn recovery_config(dir: &TempDir) -> FluxConfig {
+        FluxConfig {
+            wal_enabled: true,
+            wal_dir: dir.0.join("wal").to_string_lossy().into_owned(),
+            snapshot_file: dir.0.join("dump.snapshot").to_string_lossy().into_owned(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn recovery_keeps_a_snapshot_saved_before_the_target() {
+        let dir = TempDir::new("keep");
+        let config = recovery_config(&dir);
+        let wal_dir = Path::new(&config.wal_dir);
+        fs::create_dir_all(wal_dir).unwrap();
+        fs::write(wal_dir.join(segment_name(1)), b"").unwrap();
+        fs::write(&config.snapshot_file, b"snapshot").unwrap();
+        write_checkpoint(wal_dir, 3, 100).unwrap();
+
+        prepare_recovery(&config, 200).unwrap();
+        assert!(Path::new(&config.snapshot_file).exists());
+        assert_eq!(read_recovery_target(wal_dir), Some(200));
+
+        // Saved after the target, it is set aside and the log replayed from its start
+        prepare_recovery(&config, 50).unwrap();
+        assert!(!Path::new(&config.snapshot_file).exists());
+        assert!(Path::new(&format!("{}.before-restore", config.snapshot_file)).exists());
+        assert_eq!(read_recovery_target(wal_dir), Some(50));
+    }
+
+    #[test]
+    fn recovery_needs_the_log_from_the_snapshot_on() {
+        let dir = TempDir::new("gap");
+        let config = recovery_config(&dir);
+        let wal_dir = Path::new(&config.wal_dir);
+        fs::create_dir_all(wal_dir).unwrap();
+        fs::write(wal_dir.join(segment_name(10)), b"").unwrap();
+        fs::write(&config.snapshot_file, b"snapshot").unwrap();
+        write_checkpoint(wal_dir, 3, 100).unwrap();
+        assert!(prepare_recovery(&config, 50).is_err());
+        assert!(prepare_recovery(&config, 200).is_err());
+        assert!(Path::new(&config.snapshot_file).exists());
+        assert_eq!(read_recovery_target(wal_dir), None);
+
+        let off = FluxConfig { wal_enabled: false, ..config };
+        assert!(prepare_recovery(&off, 200).is_err());
+    }
+
+    #[tokio::test]
+    async fn replay_stops_at_the_recovery_target() {
+        let dir = TempDir::new("target");
+        let config = recovery_config(&dir);
+        let wal = Arc::new(Wal::open(&config, 0, 0, Keyring::default()).unwrap());
+        wal.append(&set("before"), None, false);
+        // Records are stamped to the millisecond
+        std::thread::sleep(Duration::from_millis(2));
+        let target = now_ms();
+        std::thread::sleep(Duration::from_millis(2));
+        wal.append(&set("after"), None, false);
+        wal.sync_to(wal.position()).await.unwrap();
+        drop(wal);
+        let wal_dir = Path::new(&config.wal_dir);
+        set_recovery_target(wal_dir, target).unwrap();
+
+        let mut state = ServerState::new("127.0.0.1:7000".to_string(), FluxConfig { wal_enabled: false, ..config.clone() });
+        assert_eq!(replay(&mut state, false).unwrap(), (2, 1));
+        assert!(state.cache.get("before").is_some());
+        assert!(state.cache.get("after").is_none());
+        // The keyspace recovered is saved, past the writes abandoned
+        assert!(Path::new(&config.snapshot_file).exists());
+        assert_eq!(read_checkpoint(wal_dir), (2, target));
+        assert_eq!(read_recovery_target(wal_dir), None);
+    }
+}
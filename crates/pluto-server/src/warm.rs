@@ -0,0 +1,113 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use log::{info, warn};
+use pluto_core::cache::ServerError;
+use pluto_core::protocol::{Command, Response};
+use crate::api::apply_write;
+use crate::environment::FluxConfig;
+use crate::peer::PeerConnection;
+use crate::state::ServerState;
+
+// Before a restarted node reports ready, the keys listed by `warm_keys` and
+// `warm_manifest` are copied from the node at `warm_from`, so it doesn't open
+// with every read a miss. Keys written here meanwhile are kept.
+
+// Keys copied per round trip
+const WARM_BATCH: usize = 100;
+
+// Copy the keys, then report ready whether or not it worked
+pub async fn run(state: Arc<RwLock<ServerState>>) {
+    let (config, health) = {
+        let state = state.read().unwrap();
+        (state.config.clone(), state.health.clone())
+    };
+    if config.warm_from.is_empty() {
+        return;
+    }
+    let started = Instant::now();
+    match tokio::time::timeout(Duration::from_secs(config.warm_timeout_secs), warm(&state, &config)).await {
+        Ok(Ok(copied)) => {
+            info!("Warmed {} keys from {} in {} ms", copied, config.warm_from, started.elapsed().as_millis());
+        }
+        Ok(Err(e)) => warn!("Warming from {} failed: {}", config.warm_from, e),
+        Err(_) => warn!(
+            "Warming from {} took over {} seconds; reporting ready without the rest",
+            config.warm_from, config.warm_timeout_secs
+        ),
+    }
+    health.set_warming(false);
+}
+
+async fn warm(state: &Arc<RwLock<ServerState>>, config: &FluxConfig) -> Result<usize, ServerError> {
+    let patterns = patterns(config)?;
+    if patterns.is_empty() {
+        warn!("warm_from is set, but warm_keys and warm_manifest list no keys");
+        return Ok(0);
+    }
+    let mut peer = PeerConnection::connect(&config.warm_from, &config.peer_credentials()).await?;
+    let mut keys = BTreeSet::new();
+    for pattern in patterns {
+        if !pattern.contains(['*', '?', '[']) {
+            keys.insert(pattern);
+            continue;
+        }
+        match peer.send(&[Command::KEYS { pattern, chunk: None }]).await?.pop() {
+            Some(Response::Keys(found)) => keys.extend(found),
+            other => return Err(unexpected(&config.warm_from, other)),
+        }
+    }
+    // Keys another member serves aren't this node's to warm
+    let keys: Vec<String> = {
+        let state = state.read().unwrap();
+        keys.into_iter()
+            .filter(|key| {
+                !state.cluster_enabled
+                    || state.cluster.slot_owner(state.acl.key_slot(key)).is_some_and(|node| node.address == state.cluster.self_addr)
+            })
+            .collect()
+    };
+
+    let mut copied = 0;
+    for batch in keys.chunks(WARM_BATCH) {
+        let dumps: Vec<Command> = batch.iter().map(|key| Command::DUMP { key: key.clone() }).collect();
+        let responses = peer.send(&dumps).await?;
+        let mut state = state.write().unwrap();
+        for (key, response) in batch.iter().zip(responses) {
+            // Missing on the peer, or gone since it was listed
+            let Response::Data(payload) = response else {
+                continue;
+            };
+            let restore = Command::RESTORE { key: key.clone(), payload: payload.to_vec(), replace: false };
+            if apply_write(&mut state, restore).is_ok() {
+                copied += 1;
+            }
+        }
+    }
+    Ok(copied)
+}
+
+// Keys and patterns from warm_keys and the manifest, one per line, skipping
+// blank lines and # comments
+fn patterns(config: &FluxConfig) -> io::Result<Vec<String>> {
+    let mut patterns = config.warm_keys.clone();
+    if !config.warm_manifest.is_empty() {
+        let manifest = fs::read_to_string(&config.warm_manifest)?;
+        patterns.extend(
+            manifest.lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(str::to_string),
+        );
+    }
+    Ok(patterns)
+}
+
+fn unexpected(address: &str, response: Option<Response>) -> ServerError {
+    match response {
+        Some(Response::Error(e)) => ServerError::InvalidArgument(format!("{} refused: {}", address, e)),
+        other => ServerError::InvalidArgument(format!("Unexpected response from {}: {:?}", address, other)),
+    }
+}
@@ -0,0 +1,446 @@
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use std::net::SocketAddr;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time;
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use log::{debug, error, info, warn};
+use pluto_core::cluster::{ClusterData, MetaCommand};
+use crate::api::{auth_required, authenticate};
+use crate::heartbeat;
+use crate::network;
+use crate::peer::PeerCredentials;
+use crate::relay;
+use crate::raft::{self, LogEntry};
+use crate::state::ServerState;
+
+// Longest a whisper exchange with another node may take
+const WHISPER_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Largest whisper message accepted, enough for a full AppendEntries batch
+const MAX_WHISPER_MESSAGE: usize = 16 * 1024 * 1024;
+
+// Nodes whisper on their client port plus this
+pub const WHISPER_PORT_OFFSET: u16 = 10000;
+
+// Whisper protocol messages for inter-node communication
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum WhisperMessage {
+    // Cluster state, sent in reply to ClusterRequest
+    Gossip { cluster_data: ClusterData },
+    // Request cluster state from another node
+    ClusterRequest,
+    // Raft: a candidate asking for a vote
+    RequestVote { cluster_id: String, term: u64, candidate: String, last_log_index: u64, last_log_term: u64 },
+    // Raft: answer to RequestVote
+    Vote { term: u64, granted: bool },
+    // Raft: the leader replicating metadata log entries, also its heartbeat
+    AppendEntries {
+        cluster_id: String,
+        term: u64,
+        leader: String,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<LogEntry>,
+        leader_commit: u64,
+    },
+    // Raft: answer to AppendEntries with the last index known to match
+    AppendResult { term: u64, success: bool, match_index: u64 },
+    // A membership change forwarded to the leader
+    Propose { command: MetaCommand },
+    // Ask a node to take the metadata log of another cluster
+    Invite { cluster_id: String },
+    // Heartbeat carrying the members the sender stopped hearing from
+    Ping { from: String, suspects: Vec<String> },
+    // Answer to Ping with the receiver's own suspects
+    Pong { suspects: Vec<String> },
+    // The channels owned by the receiver that the sender has subscribers of
+    Subscriptions { from: String, channels: Vec<String> },
+    // A message for a channel, sent to its owner, or by the owner to a member
+    // following it once `relayed`
+    Publish { channel: String, message: Vec<u8>, relayed: bool },
+    // Sent first on a connection to a node that requires a password: the
+    // requirepass, or the password of an admin user outside any tenant
+    Auth { username: Option<String>, password: String },
+    // Answer to Publish with the receiver's subscribers that got the message
+    Published { receivers: usize },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WhisperResponse {
+    pub success: bool,
+    pub message: Option<String>,
+    pub data: Option<WhisperMessage>,
+}
+
+pub struct WhisperServer {
+    pub port: u16,
+    pub state: Arc<RwLock<ServerState>>,
+}
+
+impl WhisperServer {
+    pub fn new(port: u16, state: Arc<RwLock<ServerState>>) -> Self {
+        WhisperServer { port, state }
+    }
+
+    // Start the whisper server on the whisper port of every bind address
+    pub async fn start(&self, bind_addrs: Vec<SocketAddr>) -> std::io::Result<()> {
+        let mut listeners = Vec::new();
+        for bind_addr in bind_addrs {
+            let whisper_addr = SocketAddr::new(bind_addr.ip(), self.port);
+            listeners.push(TcpListener::bind(whisper_addr).await?);
+            info!("Whisper protocol listening on {}", whisper_addr);
+        }
+
+        // Accept whisper connections on every listener
+        let mut accept_loops = tokio::task::JoinSet::new();
+        for listener in listeners {
+            accept_loops.spawn(WhisperServer::accept_loop(listener, self.state.clone()));
+        }
+        while accept_loops.join_next().await.is_some() {}
+        Ok(())
+    }
+
+    // Accept whisper connections from one listener
+    async fn accept_loop(listener: TcpListener, state: Arc<RwLock<ServerState>>) {
+        loop {
+            match tokio::time::timeout(Duration::from_secs(5), listener.accept()).await {
+                Ok(Ok((socket, addr))) => {
+                    // Other nodes are held to protected mode like clients are
+                    if network::protected_refuses(&state.read().unwrap().config, addr) {
+                        warn!("Refused a whisper connection from {} in protected mode", addr);
+                        continue;
+                    }
+                    debug!("Whisper connection from: {}", addr);
+                    let state = state.clone();
+                    tokio::spawn(async move {
+                        WhisperServer::handle_whisper_client(socket, state).await;
+                    });
+                }
+                Ok(Err(e)) => {
+                    error!("Failed to accept whisper connection: {}", e);
+                }
+                Err(_) => {
+                    // Timeout occurred, continue
+                    debug!("Whisper accept timed out");
+                }
+            }
+        }
+    }
+
+    // Handle incoming whisper connections
+    async fn handle_whisper_client(mut socket: TcpStream, state: Arc<RwLock<ServerState>>) {
+        let mut authenticated = !auth_required(&state.read().unwrap());
+        loop {
+            let message = match read_json::<WhisperMessage>(&mut socket).await {
+                Ok(Some(message)) => message,
+                Ok(None) => {
+                    debug!("Whisper client disconnected");
+                    break;
+                }
+                Err(e) => {
+                    error!("Failed to parse whisper message: {}", e);
+                    let error_response = WhisperResponse {
+                        success: false,
+                        message: Some(format!("Invalid message: {}", e)),
+                        data: None,
+                    };
+                    if let Ok(data) = serde_json::to_vec(&error_response) {
+                        let _ = socket.write_all(&data).await;
+                    }
+                    break;
+                }
+            };
+            let response = match message {
+                WhisperMessage::Auth { username, password } => {
+                    let response = WhisperServer::authenticate_peer(&state, username.as_deref(), &password);
+                    authenticated = response.success;
+                    response
+                }
+                _ if !authenticated => WhisperResponse {
+                    success: false,
+                    message: Some("NOAUTH this node requires the other nodes to authenticate".to_string()),
+                    data: None,
+                },
+                message => {
+                    debug!("Received whisper message: {:?}", message);
+                    WhisperServer::process_whisper_message(message, &state).await
+                }
+            };
+
+            match serde_json::to_vec(&response) {
+                Ok(data) => {
+                    if let Err(e) = socket.write_all(&data).await {
+                        error!("Failed to write whisper response: {}", e);
+                        break;
+                    }
+                }
+                Err(e) => {
+                    error!("Failed to serialize whisper response: {}", e);
+                    break;
+                }
+            }
+        }
+    }
+
+    // Check the credentials another node sent. Whispers change the slot map
+    // and the metadata log, so only requirepass or an admin user outside any
+    // tenant, as cluster_user must be, may send them.
+    fn authenticate_peer(state: &Arc<RwLock<ServerState>>, username: Option<&str>, password: &str) -> WhisperResponse {
+        let refused = |message: String| WhisperResponse { success: false, message: Some(message), data: None };
+        match authenticate(&state.read().unwrap(), username, password) {
+            Ok(Some(user)) if !user.rule.admin || user.tenant.is_some() => {
+                refused(format!("{} may not whisper: it is not an admin outside any tenant", user.rule.name))
+            }
+            Ok(_) => WhisperResponse { success: true, message: None, data: None },
+            Err(e) => refused(e.to_string()),
+        }
+    }
+
+    // Process whisper protocol messages
+    async fn process_whisper_message(
+        message: WhisperMessage,
+        state: &Arc<RwLock<ServerState>>
+    ) -> WhisperResponse {
+        let reply = |data: WhisperMessage| WhisperResponse { success: true, message: None, data: Some(data) };
+        match message {
+            WhisperMessage::ClusterRequest => {
+                let state_guard = state.read().unwrap();
+                let cluster_data = state_guard.cluster.get_cluster_data();
+                
+                WhisperResponse {
+                    success: true,
+                    message: Some("Cluster data sent".to_string()),
+                    data: Some(WhisperMessage::Gossip { cluster_data }),
+                }
+            }
+            WhisperMessage::RequestVote { cluster_id, term, candidate, last_log_index, last_log_term } => {
+                let (vote, store) = {
+                    let mut state = state.write().unwrap();
+                    let vote = raft::handle_request_vote(&mut state, cluster_id, term, candidate, last_log_index, last_log_term);
+                    (vote, state.raft.store())
+                };
+                // A vote or stored entries only count once they survive a restart
+                raft::sync(store).await;
+                reply(vote)
+            }
+            WhisperMessage::AppendEntries { cluster_id, term, leader, prev_log_index, prev_log_term, entries, leader_commit } => {
+                let (result, store) = {
+                    let mut state = state.write().unwrap();
+                    let result = raft::handle_append_entries(
+                        &mut state, cluster_id, term, leader, prev_log_index, prev_log_term, entries, leader_commit,
+                    );
+                    (result, state.raft.store())
+                };
+                raft::sync(store).await;
+                reply(result)
+            }
+            WhisperMessage::Propose { command } => {
+                // Only the leader takes proposals, so they are never forwarded twice
+                match raft::propose(state, command, false).await {
+                    Ok(()) => WhisperResponse { success: true, message: None, data: None },
+                    Err(e) => WhisperResponse { success: false, message: Some(e.to_string()), data: None },
+                }
+            }
+            WhisperMessage::Invite { cluster_id } => {
+                let mut state = state.write().unwrap();
+                match raft::handle_invite(&mut state, cluster_id) {
+                    Ok(()) => WhisperResponse { success: true, message: None, data: None },
+                    Err(e) => WhisperResponse { success: false, message: Some(e), data: None },
+                }
+            }
+            WhisperMessage::Ping { from, suspects } => {
+                let mut state = state.write().unwrap();
+                reply(heartbeat::handle_ping(&mut state, from, suspects))
+            }
+            WhisperMessage::Subscriptions { from, channels } => {
+                state.read().unwrap().relay.record_interest(from, channels);
+                WhisperResponse { success: true, message: None, data: None }
+            }
+            WhisperMessage::Publish { channel, message, relayed } => {
+                // A message is relayed at most once, even should the members
+                // disagree on who owns the channel
+                let state = state.read().unwrap();
+                let receivers = if relayed {
+                    state.pubsub.publish(&channel, Bytes::from(message))
+                } else {
+                    relay::deliver(&state, &channel, Bytes::from(message))
+                };
+                reply(WhisperMessage::Published { receivers })
+            }
+            other => WhisperResponse {
+                success: false,
+                message: Some(format!("Unexpected message: {:?}", other)),
+                data: None,
+            },
+        }
+    }
+
+    // Whisper port of a node serving clients on `port`, None when it would
+    // be past 65535
+    pub fn whisper_port_for(port: u16) -> Option<u16> {
+        port.checked_add(WHISPER_PORT_OFFSET)
+    }
+
+    // Whisper port of the node serving clients at `address`
+    pub fn whisper_port_of(address: &str) -> Option<u16> {
+        let port = address.rsplit(':').next()
+            .and_then(|port| port.parse::<u16>().ok())
+            .unwrap_or(6124);
+        Self::whisper_port_for(port)
+    }
+
+    // Send whisper message to another node, authenticating first when
+    // `credentials` hold a password
+    pub async fn send_whisper_message(
+        target_addr: &str,
+        credentials: &PeerCredentials,
+        message: WhisperMessage
+    ) -> Result<WhisperResponse, Box<dyn std::error::Error + Send + Sync>> {
+        let whisper_port = Self::whisper_port_of(target_addr)
+            .ok_or_else(|| format!("{} has no whisper port: its port + {} is past 65535", target_addr, WHISPER_PORT_OFFSET))?;
+        let whisper_addr = format!("{}:{}", 
+            target_addr.split(':').next().unwrap_or(target_addr), 
+            whisper_port
+        );
+        
+        let mut stream = time::timeout(WHISPER_TIMEOUT, TcpStream::connect(whisper_addr)).await??;
+        if !credentials.password.is_empty() {
+            let auth = WhisperMessage::Auth { username: credentials.username.clone(), password: credentials.password.clone() };
+            let response = exchange(&mut stream, &auth).await?;
+            if !response.success {
+                return Err(format!("{} refused our credentials: {}", target_addr, response.message.unwrap_or_default()).into());
+            }
+        }
+        exchange(&mut stream, &message).await
+    }
+}
+
+// Send one message and read the response to it
+async fn exchange(
+    stream: &mut TcpStream,
+    message: &WhisperMessage,
+) -> Result<WhisperResponse, Box<dyn std::error::Error + Send + Sync>> {
+    let data = serde_json::to_vec(message)?;
+    stream.write_all(&data).await?;
+    match time::timeout(WHISPER_TIMEOUT, read_json::<WhisperResponse>(stream)).await?? {
+        Some(response) => Ok(response),
+        None => Err("connection closed before a response".into()),
+    }
+}
+
+// Read one JSON value from the socket, which may arrive over several reads.
+// Returns None when the peer closes the connection before sending anything.
+async fn read_json<T: serde::de::DeserializeOwned>(
+    stream: &mut TcpStream,
+) -> Result<Option<T>, Box<dyn std::error::Error + Send + Sync>> {
+    let mut buf = Vec::with_capacity(4096);
+    loop {
+        if stream.read_buf(&mut buf).await? == 0 {
+            if buf.is_empty() {
+                return Ok(None);
+            }
+            return Err("connection closed mid-message".into());
+        }
+        if buf.len() > MAX_WHISPER_MESSAGE {
+            return Err("whisper message too large".into());
+        }
+        match serde_json::from_slice::<T>(&buf) {
+            Ok(value) => return Ok(Some(value)),
+            Err(e) if e.is_eof() => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::acl::AclUser;
+    use crate::environment::FluxConfig;
+
+    fn user(name: &str, admin: bool, tenant: &str) -> AclUser {
+        AclUser {
+            name: name.to_string(),
+            password: format!("{}-password", name),
+            keys: vec!["*".to_string()],
+            admin,
+            max_keys: 0,
+            max_bytes: 0,
+            tenant: tenant.to_string(),
+        }
+    }
+
+    fn state_with(config: FluxConfig) -> Arc<RwLock<ServerState>> {
+        Arc::new(RwLock::new(ServerState::new("127.0.0.1:7000".to_string(), config)))
+    }
+
+    // A whisper connection to a node with the given state
+    async fn connect(state: Arc<RwLock<ServerState>>) -> TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            WhisperServer::handle_whisper_client(socket, state).await;
+        });
+        TcpStream::connect(address).await.unwrap()
+    }
+
+    fn ping() -> WhisperMessage {
+        WhisperMessage::Ping { from: "127.0.0.1:7001".to_string(), suspects: Vec::new() }
+    }
+
+    fn auth(username: Option<&str>, password: &str) -> WhisperMessage {
+        WhisperMessage::Auth { username: username.map(String::from), password: password.to_string() }
+    }
+
+    #[tokio::test]
+    async fn whispers_need_no_auth_without_a_password() {
+        let mut stream = connect(state_with(FluxConfig::default())).await;
+        let response = exchange(&mut stream, &ping()).await.unwrap();
+        assert!(matches!(response.data, Some(WhisperMessage::Pong { .. })));
+    }
+
+    #[tokio::test]
+    async fn whispers_are_refused_until_the_sender_authenticates() {
+        let config = FluxConfig { requirepass: "secret".to_string(), ..Default::default() };
+        let mut stream = connect(state_with(config)).await;
+        let response = exchange(&mut stream, &ping()).await.unwrap();
+        assert!(!response.success);
+        assert!(response.message.unwrap().starts_with("NOAUTH"));
+        assert!(!exchange(&mut stream, &auth(None, "wrong")).await.unwrap().success);
+        assert!(!exchange(&mut stream, &ping()).await.unwrap().success);
+
+        assert!(exchange(&mut stream, &auth(None, "secret")).await.unwrap().success);
+        let response = exchange(&mut stream, &ping()).await.unwrap();
+        assert!(matches!(response.data, Some(WhisperMessage::Pong { .. })));
+    }
+
+    #[test]
+    fn only_admins_outside_tenants_may_whisper() {
+        let config = FluxConfig {
+            users: vec![user("nodes", true, ""), user("app", false, ""), user("tenant-admin", true, "acme")],
+            tenants: vec![crate::acl::TenantRule { name: "acme".to_string(), max_keys: 0, max_bytes: 0 }],
+            ..Default::default()
+        };
+        let state = state_with(config);
+        assert!(WhisperServer::authenticate_peer(&state, Some("nodes"), "nodes-password").success);
+        assert!(!WhisperServer::authenticate_peer(&state, Some("nodes"), "app-password").success);
+        assert!(!WhisperServer::authenticate_peer(&state, Some("app"), "app-password").success);
+        assert!(!WhisperServer::authenticate_peer(&state, Some("tenant-admin"), "tenant-admin-password").success);
+    }
+
+    #[test]
+    fn whisper_port_is_the_client_port_plus_the_offset() {
+        assert_eq!(WhisperServer::whisper_port_of("10.0.0.1:7379"), Some(17379));
+        assert_eq!(WhisperServer::whisper_port_of("[::1]:55535"), Some(65535));
+    }
+
+    #[test]
+    fn whisper_port_past_65535_is_refused() {
+        assert_eq!(WhisperServer::whisper_port_for(55536), None);
+        assert_eq!(WhisperServer::whisper_port_of("10.0.0.1:65535"), None);
+    }
+}